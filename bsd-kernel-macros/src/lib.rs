@@ -0,0 +1,678 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Procedural macros for `bsd-kernel` modules, re-exported from there
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use std::ffi::CString;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{
+    Data, DeriveInput, Fields, ImplItem, ItemImpl, LitByte, LitCStr, LitInt,
+    LitStr, parse_macro_input,
+};
+
+/// Generate the boilerplate of a module from the struct holding its state
+///
+/// ```rust,ignore
+/// #[kernel_module(name = "hello", version = 1, depend("rustfifo", 1))]
+/// #[derive(Debug, Default)]
+/// pub struct Hello { /* ... */ }
+///
+/// impl ModuleEvents for Hello { /* ... */ }
+/// ```
+///
+/// The struct must implement `Default` and `ModuleEvents`. Next to it
+/// this defines `MODULE`, a `Lazy<SharedModule<_>>` built with `Default`,
+/// and the module's `moduledata_t` as the C symbol `module_data`, whose
+/// event handler loads, quiesces and unloads `MODULE`, refusing kernels
+/// that don't fit `ModuleEvents::ABI`. The crate also gets
+/// `KernelAllocator` as its global allocator, allocating from a
+/// `malloc(9)` type named after the module for `vmstat -m`, and a panic
+/// handler that hands the panic to `bsd_kernel::panic::handle`.
+///
+/// The module is declared as `DECLARE_MODULE` would, in the
+/// `subsystem` and at the `order` given, `SI_SUB_DRIVERS` and
+/// `SI_ORDER_MIDDLE` by default. `version = N` is `MODULE_VERSION`, and
+/// each `depend("name", min, pref, max)` a `MODULE_DEPEND`, with
+/// `depend("name", v)` standing for `v, v, v`. With `declare = false` the
+/// module is left for C to declare instead:
+///
+/// ```c,ignore
+/// extern moduledata_t module_data;
+/// DECLARE_MODULE(hello, module_data, SI_SUB_DRIVERS, SI_ORDER_MIDDLE);
+/// ```
+#[proc_macro_attribute]
+pub fn kernel_module(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name: Option<LitStr> = None;
+    let mut version: Option<LitInt> = None;
+    let mut depends: Vec<(LitStr, [LitInt; 3])> = Vec::new();
+    let mut subsystem = format_ident!("SI_SUB_DRIVERS");
+    let mut order = format_ident!("SI_ORDER_MIDDLE");
+    let mut declare = true;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("version") {
+            version = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("depend") {
+            let content;
+            syn::parenthesized!(content in meta.input);
+            let module: LitStr = content.parse()?;
+            content.parse::<syn::Token![,]>()?;
+            let vers: Vec<LitInt> =
+                Punctuated::<LitInt, syn::Token![,]>::parse_terminated(
+                    &content,
+                )?
+                .into_iter()
+                .collect();
+            let vers = match vers.as_slice() {
+                [v] => [v.clone(), v.clone(), v.clone()],
+                [min, pref, max] => [min.clone(), pref.clone(), max.clone()],
+                _ => {
+                    let msg = "expected `depend(\"...\", v)` or \
+                               `depend(\"...\", min, pref, max)`";
+                    return Err(meta.error(msg));
+                }
+            };
+            depends.push((module, vers));
+        } else if meta.path.is_ident("subsystem") {
+            let id: syn::Ident = meta.value()?.parse()?;
+            if !id.to_string().starts_with("SI_SUB_") {
+                return Err(syn::Error::new_spanned(id, "expected `SI_SUB_*`"));
+            }
+            subsystem = id;
+        } else if meta.path.is_ident("order") {
+            let id: syn::Ident = meta.value()?.parse()?;
+            if !id.to_string().starts_with("SI_ORDER_") {
+                let msg = "expected `SI_ORDER_*`";
+                return Err(syn::Error::new_spanned(id, msg));
+            }
+            order = id;
+        } else if meta.path.is_ident("declare") {
+            declare = meta.value()?.parse::<syn::LitBool>()?.value;
+        } else {
+            return Err(meta.error(
+                "expected `name`, `version`, `depend`, `subsystem`, \
+                 `order` or `declare`",
+            ));
+        }
+        Ok(())
+    });
+    parse_macro_input!(attr with parser);
+    let input = parse_macro_input!(item as DeriveInput);
+    let Some(name) = name else {
+        let msg = "#[kernel_module] needs `name = \"...\"`";
+        return syn::Error::new_spanned(&input.ident, msg)
+            .to_compile_error()
+            .into();
+    };
+    if !input.generics.params.is_empty() {
+        let msg = "#[kernel_module] structs can't be generic";
+        return syn::Error::new_spanned(&input.generics, msg)
+            .to_compile_error()
+            .into();
+    }
+    let cstr = |lit: &LitStr| match CString::new(lit.value()) {
+        Ok(c) => Ok(LitCStr::new(&c, lit.span())),
+        Err(_) => Err(syn::Error::new_spanned(
+            lit,
+            "module names can't contain NULs",
+        )),
+    };
+    let cname = match cstr(&name) {
+        Ok(c) => c,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let ident = &input.ident;
+
+    let declaration = if declare {
+        let mut depend_items = Vec::new();
+        for (i, (module, [min, pref, max])) in depends.iter().enumerate() {
+            let cmodule = match cstr(module) {
+                Ok(c) => c,
+                Err(e) => return e.to_compile_error().into(),
+            };
+            let dep = format_ident!("__DEPEND_{}", i);
+            let md = format_ident!("__MD_DEPEND_{}", i);
+            let entry = format_ident!("__MD_DEPEND_{}_ENTRY", i);
+            depend_items.push(quote! {
+                static #dep: ModuleDepend = ModuleDepend::new(#min, #pref, #max);
+                static #md: ModuleMetadata =
+                    ModuleMetadata::depend(&#dep, #cmodule);
+                #[used]
+                #[unsafe(link_section = "set_modmetadata_set")]
+                static #entry: LinkerSetEntry<ModuleMetadata> =
+                    LinkerSetEntry::new(&#md);
+            });
+        }
+        let version_item = version.as_ref().map(|v| {
+            quote! {
+                static __VERSION: ModuleVersion = ModuleVersion::new(#v);
+                static __MD_VERSION: ModuleMetadata =
+                    ModuleMetadata::version(&__VERSION, #cname);
+                #[used]
+                #[unsafe(link_section = "set_modmetadata_set")]
+                static __MD_VERSION_ENTRY: LinkerSetEntry<ModuleMetadata> =
+                    LinkerSetEntry::new(&__MD_VERSION);
+            }
+        });
+        let subsystem = format_ident!("sysinit_sub_id_{}", subsystem);
+        let order = format_ident!("sysinit_elem_order_{}", order);
+        quote! {
+            const _: () = {
+                use ::bsd_kernel::module::{
+                    LinkerSetEntry, ModuleDepend, ModuleMetadata,
+                    ModuleVersion, SysInit,
+                };
+
+                static __SYSINIT: SysInit = SysInit::module(
+                    &module_data,
+                    ::bsd_kernel::kernel_sys::#subsystem,
+                    ::bsd_kernel::kernel_sys::#order,
+                );
+                #[used]
+                #[unsafe(link_section = "set_sysinit_set")]
+                static __SYSINIT_ENTRY: LinkerSetEntry<SysInit> =
+                    LinkerSetEntry::new(&__SYSINIT);
+
+                static __MD_MODULE: ModuleMetadata =
+                    ModuleMetadata::module(&module_data, #cname);
+                #[used]
+                #[unsafe(link_section = "set_modmetadata_set")]
+                static __MD_MODULE_ENTRY: LinkerSetEntry<ModuleMetadata> =
+                    LinkerSetEntry::new(&__MD_MODULE);
+
+                static __KERNEL_DEPEND: ModuleDepend = ModuleDepend::kernel();
+                static __MD_KERNEL: ModuleMetadata =
+                    ModuleMetadata::depend(&__KERNEL_DEPEND, c"kernel");
+                #[used]
+                #[unsafe(link_section = "set_modmetadata_set")]
+                static __MD_KERNEL_ENTRY: LinkerSetEntry<ModuleMetadata> =
+                    LinkerSetEntry::new(&__MD_KERNEL);
+
+                #version_item
+                #(#depend_items)*
+            };
+        }
+    } else {
+        quote! {}
+    };
+
+    quote! {
+        #input
+
+        pub static MODULE: ::bsd_kernel::sync::Lazy<
+            ::bsd_kernel::module::SharedModule<#ident>,
+        > = ::bsd_kernel::sync::Lazy::new(|| {
+            ::bsd_kernel::module::SharedModule::new(
+                <#ident as ::core::default::Default>::default(),
+            )
+        });
+
+        #[unsafe(no_mangle)]
+        pub static module_data: ::bsd_kernel::module::ModuleData =
+            ::bsd_kernel::module::ModuleData::new(#cname, __module_event);
+
+        #declaration
+
+        unsafe extern "C" fn __module_event(
+            _module: ::bsd_kernel::Module,
+            event: ::bsd_kernel::libc::c_int,
+            _arg: *mut ::bsd_kernel::libc::c_void,
+        ) -> ::bsd_kernel::libc::c_int {
+            use ::bsd_kernel::module::ModuleEventType;
+            match ModuleEventType::from_i32(event) {
+                Some(ModuleEventType::Load) => match MODULE.load() {
+                    Ok(()) => 0,
+                    Err(e) => e.as_raw(),
+                },
+                Some(ModuleEventType::Quiesce) => match MODULE.quiesce() {
+                    Ok(()) => 0,
+                    Err(e) => e.as_raw(),
+                },
+                Some(ModuleEventType::Unload) => {
+                    MODULE.unload();
+                    MODULE.cleanup();
+                    0
+                }
+                _ => 0,
+            }
+        }
+
+        ::bsd_kernel::malloc_type!(static __M_MODULE = #cname);
+
+        #[global_allocator]
+        static __ALLOCATOR: ::bsd_kernel::allocator::KernelAllocator =
+            ::bsd_kernel::allocator::KernelAllocator::with_type(&__M_MODULE);
+
+        #[panic_handler]
+        fn __panic_handler(info: &::core::panic::PanicInfo) -> ! {
+            ::bsd_kernel::panic::handle(info)
+        }
+    }
+    .into()
+}
+
+/// Implement `bsd_kernel::ioctl::IoctlEnum` for an enum of commands, see
+/// the `bsd_kernel::ioctl` docs
+#[proc_macro_derive(IoctlEnum, attributes(ioctl))]
+pub fn derive_ioctl_enum(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match ioctl_enum(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// The `_IO*` macro a variant is declared with
+#[derive(Copy, Clone, PartialEq)]
+enum Dir {
+    Io,
+    Ior,
+    Iow,
+    Iowr,
+}
+
+fn ioctl_enum(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        let msg = "IoctlEnum can only be derived for enums";
+        return Err(syn::Error::new_spanned(&input.ident, msg));
+    };
+    let mut group: Option<LitByte> = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("ioctl")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("group") {
+                group = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `group = b'...'`"))
+            }
+        })?;
+    }
+    let Some(group) = group else {
+        let msg = "IoctlEnum needs #[ioctl(group = b'...')]";
+        return Err(syn::Error::new_spanned(&input.ident, msg));
+    };
+
+    let ioctl = quote!(::bsd_kernel::ioctl);
+    let ulong = quote!(::bsd_kernel::libc::c_ulong);
+    let mut consts = Vec::new();
+    let mut decode = Vec::new();
+    let mut cmd = Vec::new();
+    let mut encode = Vec::new();
+    let mut decode32 = Vec::new();
+    let mut cmd32 = Vec::new();
+    let mut encode32 = Vec::new();
+    for variant in &data.variants {
+        let mut dir = None;
+        let mut compat32 = false;
+        for attr in variant.attrs.iter().filter(|a| a.path().is_ident("ioctl"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("compat32") {
+                    compat32 = true;
+                    return Ok(());
+                }
+                let d = [
+                    ("io", Dir::Io),
+                    ("ior", Dir::Ior),
+                    ("iow", Dir::Iow),
+                    ("iowr", Dir::Iowr),
+                ]
+                .into_iter()
+                .find(|(name, _)| meta.path.is_ident(name));
+                let Some((_, d)) = d else {
+                    return Err(meta.error(
+                        "expected `io`, `ior`, `iow`, `iowr` or `compat32`",
+                    ));
+                };
+                let num: LitInt = meta.value()?.parse()?;
+                dir = Some((d, num));
+                Ok(())
+            })?;
+        }
+        let ident = &variant.ident;
+        let Some((dir, num)) = dir else {
+            let msg = "variant needs #[ioctl(io = N)], or ior, iow or iowr";
+            return Err(syn::Error::new_spanned(ident, msg));
+        };
+        let payload = match &variant.fields {
+            Fields::Unit => None,
+            Fields::Unnamed(f) if f.unnamed.len() == 1 => {
+                Some(&f.unnamed[0].ty)
+            }
+            _ => {
+                let msg = "variant must be a unit or have one unnamed field";
+                return Err(syn::Error::new_spanned(ident, msg));
+            }
+        };
+        let name = syn::Ident::new(&screaming_snake(ident), ident.span());
+        let name32 = format_ident!("{}32", name);
+        if compat32 && payload.is_none() {
+            let msg = "`compat32` commands need an argument";
+            return Err(syn::Error::new_spanned(ident, msg));
+        }
+        let value = match (dir, payload) {
+            (Dir::Io, None) => quote!(#ioctl::io(#group, #num)),
+            (Dir::Io, Some(ty)) => {
+                let msg = "`io` commands have no argument";
+                return Err(syn::Error::new_spanned(ty, msg));
+            }
+            (_, None) => {
+                let msg = "`ior`, `iow` and `iowr` commands need an argument";
+                return Err(syn::Error::new_spanned(ident, msg));
+            }
+            (d, Some(ty)) => {
+                let f = match d {
+                    Dir::Ior => quote!(ior),
+                    Dir::Iow => quote!(iow),
+                    _ => quote!(iowr),
+                };
+                if compat32 {
+                    let abi32 = quote!(<#ty as #ioctl::Compat32>::Abi32);
+                    consts.push(quote! {
+                        pub const #name32: #ulong = #ioctl::#f(
+                            #group,
+                            #num,
+                            ::core::mem::size_of::<#abi32>(),
+                        );
+                    });
+                }
+                quote!(#ioctl::#f(#group, #num, ::core::mem::size_of::<#ty>()))
+            }
+        };
+        consts.push(quote! {
+            pub const #name: #ulong = #value;
+        });
+        match payload {
+            None => {
+                decode.push(quote! {
+                    if cmd == Self::#name {
+                        return Ok(Self::#ident);
+                    }
+                });
+                cmd.push(quote!(Self::#ident => Self::#name,));
+            }
+            Some(ty) => {
+                decode.push(quote! {
+                    if cmd == Self::#name {
+                        let arg = #ioctl::read_payload::<#ty>(data)?;
+                        return Ok(Self::#ident(arg));
+                    }
+                });
+                cmd.push(quote!(Self::#ident(_) => Self::#name,));
+                if dir != Dir::Iow {
+                    encode.push(quote! {
+                        Self::#ident(arg) => #ioctl::write_payload(data, arg),
+                    });
+                }
+            }
+        }
+        match payload {
+            Some(ty) if compat32 => {
+                let abi32 = quote!(<#ty as #ioctl::Compat32>::Abi32);
+                decode32.push(quote! {
+                    if cmd == Self::#name32 {
+                        let arg = #ioctl::read_payload::<#abi32>(data)?;
+                        return Ok(Self::#ident(
+                            #ioctl::Compat32::from_abi32(arg),
+                        ));
+                    }
+                });
+                cmd32.push(quote!(Self::#ident(_) => Self::#name32,));
+                if dir != Dir::Iow {
+                    encode32.push(quote! {
+                        Self::#ident(arg) => #ioctl::write_payload(
+                            data,
+                            &#ioctl::Compat32::to_abi32(arg),
+                        ),
+                    });
+                }
+            }
+            _ => decode32.extend(decode.last().cloned()),
+        }
+    }
+    cmd32.push(quote!(_ => self.cmd(),));
+    encode32.push(quote!(_ => self.encode(data),));
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) =
+        input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #ident #ty_generics #where_clause {
+            #(#consts)*
+        }
+
+        impl #impl_generics #ioctl::IoctlEnum for #ident #ty_generics
+            #where_clause
+        {
+            fn decode(
+                cmd: #ulong,
+                data: &[u8],
+            ) -> ::core::result::Result<Self, ::bsd_kernel::errno::Errno> {
+                #(#decode)*
+                Err(::bsd_kernel::errno::Errno::NotTy)
+            }
+
+            fn cmd(&self) -> #ulong {
+                match self {
+                    #(#cmd)*
+                }
+            }
+
+            #[allow(unused_variables)]
+            fn encode(&self, data: &mut [u8]) {
+                #[allow(unreachable_patterns)]
+                match self {
+                    #(#encode)*
+                    _ => (),
+                }
+            }
+
+            fn decode32(
+                cmd: #ulong,
+                data: &[u8],
+            ) -> ::core::result::Result<Self, ::bsd_kernel::errno::Errno> {
+                #(#decode32)*
+                Err(::bsd_kernel::errno::Errno::NotTy)
+            }
+
+            fn cmd32(&self) -> #ulong {
+                #[allow(unreachable_patterns)]
+                match self {
+                    #(#cmd32)*
+                }
+            }
+
+            fn encode32(&self, data: &mut [u8]) {
+                #[allow(unreachable_patterns)]
+                match self {
+                    #(#encode32)*
+                }
+            }
+        }
+    })
+}
+
+/// Implement `bsd_kernel::ioctl::Compat32` for a `#[repr(C)]` struct of
+/// `Compat32` fields, defining the 32-bit layout as the struct suffixed
+/// `32`, with the same fields in their `Compat32::Abi32` types
+#[proc_macro_derive(Compat32)]
+pub fn derive_compat32(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match compat32(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn compat32(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let msg = "Compat32 can only be derived for structs with named fields";
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(&input.ident, msg));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(&input.ident, msg));
+    };
+    if !input.generics.params.is_empty() {
+        let msg = "Compat32 can't be derived for generic structs";
+        return Err(syn::Error::new_spanned(&input.generics, msg));
+    }
+    let repr_c = input.attrs.iter().any(|a| {
+        a.path().is_ident("repr")
+            && a.parse_nested_meta(|meta| {
+                if meta.path.is_ident("C") {
+                    Ok(())
+                } else {
+                    Err(meta.error("not C"))
+                }
+            })
+            .is_ok()
+    });
+    if !repr_c {
+        let msg = "Compat32 needs #[repr(C)]";
+        return Err(syn::Error::new_spanned(&input.ident, msg));
+    }
+
+    let ioctl = quote!(::bsd_kernel::ioctl);
+    let vis = &input.vis;
+    let ident = &input.ident;
+    let ident32 = format_ident!("{}32", ident);
+    let names: Vec<_> = fields.named.iter().map(|f| &f.ident).collect();
+    let types = fields.named.iter().map(|f| &f.ty);
+    let doc = format!("`{}` as a 32-bit process lays it out", ident);
+    Ok(quote! {
+        #[doc = #doc]
+        #[repr(C)]
+        #[derive(Copy, Clone)]
+        #vis struct #ident32 {
+            #(#vis #names: <#types as #ioctl::Compat32>::Abi32,)*
+        }
+
+        unsafe impl #ioctl::Payload for #ident32 {}
+
+        impl #ioctl::Compat32 for #ident {
+            type Abi32 = #ident32;
+
+            fn from_abi32(arg: #ident32) -> Self {
+                #ident {
+                    #(#names: #ioctl::Compat32::from_abi32(arg.#names),)*
+                }
+            }
+
+            fn to_abi32(&self) -> #ident32 {
+                #ident32 {
+                    #(#names: #ioctl::Compat32::to_abi32(&self.#names),)*
+                }
+            }
+        }
+    })
+}
+
+/// Build the kobj method table of a type from an impl block, see the
+/// `bsd_kernel::kobj` docs. Each `extern "C"` function tagged
+/// `#[method(name)]` implements `name` of its interface, and must have the
+/// type of `name_t`; the table is the type's `kobj::Methods::METHODS`
+#[proc_macro_attribute]
+pub fn kobj_methods(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let msg = "#[kobj::methods] takes no arguments";
+        return syn::Error::new(proc_macro2::Span::call_site(), msg)
+            .to_compile_error()
+            .into();
+    }
+    let mut input = parse_macro_input!(item as ItemImpl);
+    match kobj_methods_impl(&mut input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn kobj_methods_impl(
+    input: &mut ItemImpl,
+) -> syn::Result<proc_macro2::TokenStream> {
+    if let Some((_, path, _)) = &input.trait_ {
+        let msg = "#[kobj::methods] goes on an inherent impl block";
+        return Err(syn::Error::new_spanned(path, msg));
+    }
+    if !input.generics.params.is_empty() {
+        let msg = "kobj classes can't be generic";
+        return Err(syn::Error::new_spanned(&input.generics, msg));
+    }
+    let ty = &input.self_ty;
+    let mut entries = Vec::new();
+    for item in &mut input.items {
+        let ImplItem::Fn(f) = item else { continue };
+        let Some(i) = f.attrs.iter().position(|a| a.path().is_ident("method"))
+        else {
+            continue;
+        };
+        let method: syn::Ident = f.attrs.remove(i).parse_args()?;
+        let is_c = f.sig.abi.as_ref().is_some_and(|abi| {
+            abi.name.as_ref().is_some_and(|name| name.value() == "C")
+        });
+        if !is_c {
+            let msg = "kobj methods must be `extern \"C\"`";
+            return Err(syn::Error::new_spanned(&f.sig, msg));
+        }
+        let name = &f.sig.ident;
+        let desc = format_ident!("{}_desc", method);
+        let ty_t = format_ident!("{}_t", method);
+        // Type errors point at the function
+        entries.push(quote_spanned! {f.sig.span()=>
+            unsafe {
+                ::bsd_kernel::kobj::Method::new(
+                    &raw mut ::bsd_kernel::kernel_sys::#desc,
+                    ::core::mem::transmute::<
+                        ::bsd_kernel::kernel_sys::#ty_t,
+                        ::bsd_kernel::kernel_sys::kobjop_t,
+                    >(::core::option::Option::Some(<#ty>::#name)),
+                )
+            }
+        });
+    }
+    Ok(quote! {
+        #input
+
+        unsafe impl ::bsd_kernel::kobj::Methods for #ty {
+            const METHODS: &'static [::bsd_kernel::kobj::Method] = &[
+                #(#entries,)*
+                ::bsd_kernel::kobj::Method::END,
+            ];
+        }
+    })
+}
+
+/// `NRead` to `N_READ`
+fn screaming_snake(ident: &syn::Ident) -> String {
+    let mut out = String::new();
+    for (i, c) in ident.to_string().chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.extend(c.to_uppercase());
+    }
+    out
+}
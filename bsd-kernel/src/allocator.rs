@@ -23,29 +23,614 @@
 //
 // Based on public domain code by Johannes Lundberg
 
-use core::alloc::{GlobalAlloc, Layout};
+//! The global allocator modules allocate through, and fallible boxes,
+//! vectors and strings for code that mustn't sleep
+//!
+//! `KernelAllocator` hands out `malloc(9)` memory, accounted to a
+//! `MallocType` that `vmstat -m` lists, from `M_DEVBUF` by default. It
+//! sleeps for memory unless made to fail with `M_NOWAIT` instead:
+//!
+//! ```rust,ignore
+//! bsd_kernel::malloc_type!(static M_RMD = c"rmd");
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: KernelAllocator = KernelAllocator::with_type(&M_RMD);
+//! ```
+//!
+//! With the global allocator sleeping, a GEOM `start` routine, interrupt
+//! filter or other code holding a mutex allocates with `KBox::try_new`,
+//! `KVec::try_with_capacity` and `KString::try_from_str`, which fail with
+//! `AllocError` rather than sleep; `?` turns it into `Errno::NoMem`. The
+//! `_with` constructors take the `KernelAllocator` to use instead, for
+//! another malloc type or flags:
+//!
+//! ```rust,ignore
+//! let alloc = KernelAllocator::with_type(&M_RMD).wait(Wait::NoWait);
+//! let mut name = KString::new_with(alloc);
+//! write!(name, "rmd{}", unit)?;
+//! let mut sectors = KVec::try_with_capacity_with(count, alloc)?;
+//! ```
+//!
+//! An allocation that fails where it can't be handled panics the kernel,
+//! saying how much was asked for.
 
-pub struct KernelAllocator;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::str;
 
-unsafe impl GlobalAlloc for KernelAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        unsafe {
-            kernel_sys::malloc(
-                layout.size(),
-                &mut kernel_sys::M_DEVBUF[0],
-                kernel_sys::M_WAITOK,
-            ) as *mut u8
+/// A `malloc(9)` type, which allocations are accounted to, as
+/// `MALLOC_DEFINE` declares. The kernel must know of it before it is
+/// allocated from, which `malloc_type!` sees to
+#[repr(transparent)]
+pub struct MallocType(UnsafeCell<kernel_sys::malloc_type>);
+
+// Its statistics are updated by malloc(9), under its own locks
+unsafe impl Sync for MallocType {}
+
+impl MallocType {
+    /// A type `vmstat -m` shows as `name`
+    pub const fn new(name: &'static CStr) -> Self {
+        // Zeroed for the statistics kernels keep inline
+        let mut ty: kernel_sys::malloc_type = unsafe { core::mem::zeroed() };
+        ty.ks_version = kernel_sys::M_VERSION as _;
+        ty.ks_shortdesc = name.as_ptr();
+        MallocType(UnsafeCell::new(ty))
+    }
+
+    /// The name `vmstat -m` shows
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.0.get()).ks_shortdesc) }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::malloc_type {
+        self.0.get()
+    }
+}
+
+impl fmt::Debug for MallocType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MallocType {{ name: {:?} }}", self.name())
+    }
+}
+
+/// Declare a `MallocType` static, which the kernel knows of from when the
+/// module is loaded until it is unloaded, as `MALLOC_DEFINE` does
+///
+/// ```rust,ignore
+/// bsd_kernel::malloc_type!(pub static M_RMD = c"rmd");
+/// ```
+///
+/// Unloading reports memory still allocated from the type as leaked.
+#[cfg(not(feature = "mock"))]
+#[macro_export]
+macro_rules! malloc_type {
+    ($(#[$attr:meta])* $vis:vis static $name:ident = $desc:expr $(;)?) => {
+        $(#[$attr])*
+        $vis static $name: $crate::allocator::MallocType =
+            $crate::allocator::MallocType::new($desc);
+
+        const _: () = {
+            use $crate::module::{LinkerSetEntry, SysInit};
+
+            static INIT: SysInit = SysInit::malloc_init(&$name);
+            #[used]
+            #[unsafe(link_section = "set_sysinit_set")]
+            static INIT_ENTRY: LinkerSetEntry<SysInit> =
+                LinkerSetEntry::new(&INIT);
+
+            static UNINIT: SysInit = SysInit::malloc_uninit(&$name);
+            #[used]
+            #[unsafe(link_section = "set_sysuninit_set")]
+            static UNINIT_ENTRY: LinkerSetEntry<SysInit> =
+                LinkerSetEntry::new(&UNINIT);
+        };
+    };
+}
+
+/// Whether an allocation may sleep until memory is free
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Wait {
+    /// `M_WAITOK`: sleep for memory, never failing
+    WaitOk,
+    /// `M_NOWAIT`: fail rather than sleep, for code that holds a mutex or
+    /// otherwise mustn't sleep
+    NoWait,
+}
+
+impl Wait {
+    fn flags(self) -> i32 {
+        match self {
+            Wait::WaitOk => kernel_sys::M_WAITOK,
+            Wait::NoWait => kernel_sys::M_NOWAIT,
+        }
+    }
+}
+
+/// `malloc(9)` as an allocator, global or for `KBox` and `KVec`
+#[derive(Copy, Clone)]
+pub struct KernelAllocator {
+    // M_DEVBUF if None
+    ty: Option<&'static MallocType>,
+    wait: Wait,
+}
+
+impl KernelAllocator {
+    /// Allocate from `M_DEVBUF`, sleeping for memory
+    pub const fn new() -> Self {
+        KernelAllocator {
+            ty: None,
+            wait: Wait::WaitOk,
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+    /// Allocate from `ty`, sleeping for memory
+    pub const fn with_type(ty: &'static MallocType) -> Self {
+        KernelAllocator {
+            ty: Some(ty),
+            wait: Wait::WaitOk,
+        }
+    }
+
+    /// This allocator, sleeping for memory or not as `wait` says
+    pub const fn wait(self, wait: Wait) -> Self {
+        KernelAllocator { wait, ..self }
+    }
+
+    /// The type allocations are accounted to
+    pub fn malloc_type(&self) -> *mut kernel_sys::malloc_type {
+        match self.ty {
+            Some(ty) => ty.as_ptr(),
+            None => unsafe { &raw mut kernel_sys::M_DEVBUF[0] },
+        }
+    }
+
+    /// Allocate memory for `layout`, sleeping for it or not as `wait`
+    /// says whatever this allocator's own choice
+    pub fn try_alloc(
+        &self,
+        layout: Layout,
+        wait: Wait,
+    ) -> Result<NonNull<u8>, AllocError> {
+        self.malloc(layout, wait.flags())
+    }
+
+    /// Like `try_alloc`, but zero filled
+    pub fn try_alloc_zeroed(
+        &self,
+        layout: Layout,
+        wait: Wait,
+    ) -> Result<NonNull<u8>, AllocError> {
+        self.malloc(layout, wait.flags() | kernel_sys::M_ZERO)
+    }
+
+    /// Free memory from `try_alloc`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by an allocator of the same type,
+    /// and not be used again
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
         unsafe {
             kernel_sys::free(
-                ptr as *mut libc::c_void,
-                &mut kernel_sys::M_DEVBUF[0],
+                ptr.as_ptr() as *mut libc::c_void,
+                self.malloc_type(),
             );
         }
     }
+
+    fn malloc(
+        &self,
+        layout: Layout,
+        flags: i32,
+    ) -> Result<NonNull<u8>, AllocError> {
+        let ptr = unsafe {
+            kernel_sys::malloc(layout.size(), self.malloc_type(), flags)
+        };
+        NonNull::new(ptr as *mut u8).ok_or(AllocError)
+    }
+}
+
+impl Default for KernelAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for KernelAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.ty {
+            Some(ty) => ty.name(),
+            None => c"devbuf",
+        };
+        write!(
+            f,
+            "KernelAllocator {{ type: {:?}, wait: {:?} }}",
+            name, self.wait
+        )
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout, self.wait)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc_zeroed(layout, self.wait)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            unsafe { self.free(ptr) };
+        }
+    }
+}
+
+unsafe impl Allocator for KernelAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.try_alloc(layout, self.wait)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    fn allocate_zeroed(
+        &self,
+        layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.try_alloc_zeroed(layout, self.wait)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        unsafe { self.free(ptr) };
+    }
+}
+
+const NOWAIT: KernelAllocator = KernelAllocator::new().wait(Wait::NoWait);
+
+/// A `Box` allocated without sleeping
+pub struct KBox<T: ?Sized>(Box<T, KernelAllocator>);
+
+impl<T> KBox<T> {
+    /// Box `value` in `M_DEVBUF`, failing rather than sleeping for memory
+    pub fn try_new(value: T) -> Result<Self, AllocError> {
+        Self::try_new_with(value, NOWAIT)
+    }
+
+    /// Box `value` in `ty`, failing rather than sleeping for memory
+    pub fn try_new_in(
+        value: T,
+        ty: &'static MallocType,
+    ) -> Result<Self, AllocError> {
+        let alloc = KernelAllocator::with_type(ty).wait(Wait::NoWait);
+        Self::try_new_with(value, alloc)
+    }
+
+    /// Box `value` with `alloc`, which fails only if it may not sleep
+    pub fn try_new_with(
+        value: T,
+        alloc: KernelAllocator,
+    ) -> Result<Self, AllocError> {
+        Box::try_new_in(value, alloc).map(KBox)
+    }
+
+    /// The boxed value, freeing the box
+    pub fn into_inner(b: Self) -> T {
+        *b.0
+    }
+}
+
+impl<T: ?Sized> KBox<T> {
+    /// The box, allocating from the same type without sleeping
+    pub fn into_box(b: Self) -> Box<T, KernelAllocator> {
+        b.0
+    }
+}
+
+impl<T: ?Sized> Deref for KBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: ?Sized> DerefMut for KBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for KBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A `Vec` that grows without sleeping
+///
+/// It derefs to a slice, and grows only through `try_push`, `try_insert`,
+/// `try_extend_from_slice` and `try_reserve`, which fail rather than
+/// panic when memory runs out.
+pub struct KVec<T>(Vec<T, KernelAllocator>);
+
+impl<T> KVec<T> {
+    /// An empty vector of `M_DEVBUF` memory, not yet allocated
+    pub const fn new() -> Self {
+        KVec(Vec::new_in(NOWAIT))
+    }
+
+    /// A vector of `M_DEVBUF` memory with room for `capacity` elements,
+    /// failing rather than sleeping for it
+    pub fn try_with_capacity(capacity: usize) -> Result<Self, AllocError> {
+        Self::try_with_capacity_with(capacity, NOWAIT)
+    }
+
+    /// A vector of `ty` memory with room for `capacity` elements, failing
+    /// rather than sleeping for it
+    pub fn try_with_capacity_in(
+        capacity: usize,
+        ty: &'static MallocType,
+    ) -> Result<Self, AllocError> {
+        let alloc = KernelAllocator::with_type(ty).wait(Wait::NoWait);
+        Self::try_with_capacity_with(capacity, alloc)
+    }
+
+    /// An empty vector growing with `alloc`, not yet allocated
+    pub const fn new_with(alloc: KernelAllocator) -> Self {
+        KVec(Vec::new_in(alloc))
+    }
+
+    /// A vector with room for `capacity` elements from `alloc`, which
+    /// fails only if it may not sleep
+    pub fn try_with_capacity_with(
+        capacity: usize,
+        alloc: KernelAllocator,
+    ) -> Result<Self, AllocError> {
+        Vec::try_with_capacity_in(capacity, alloc)
+            .map(KVec)
+            .map_err(|_| AllocError)
+    }
+
+    /// Make room for at least `additional` more elements
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        self.0.try_reserve(additional).map_err(|_| AllocError)
+    }
+
+    /// Append `value`, or hand it back if there is no memory for it
+    pub fn try_push(&mut self, value: T) -> Result<(), T> {
+        if self.0.try_reserve(1).is_err() {
+            return Err(value);
+        }
+        self.0.push(value);
+        Ok(())
+    }
+
+    /// Insert `value` at `index`, shifting those after it, or hand it back
+    /// if there is no memory for it
+    ///
+    /// ## Panics
+    /// Panics if `index` is past the end
+    pub fn try_insert(&mut self, index: usize, value: T) -> Result<(), T> {
+        if self.0.try_reserve(1).is_err() {
+            return Err(value);
+        }
+        self.0.insert(index, value);
+        Ok(())
+    }
+
+    /// Append a copy of `other`, or nothing if there is no memory for it
+    pub fn try_extend_from_slice(
+        &mut self,
+        other: &[T],
+    ) -> Result<(), AllocError>
+    where
+        T: Clone,
+    {
+        self.try_reserve(other.len())?;
+        self.0.extend_from_slice(other);
+        Ok(())
+    }
+
+    /// The elements
+    pub fn as_slice(&self) -> &[T] {
+        &self.0
+    }
+
+    /// The elements, mutably
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.0
+    }
+
+    /// The number of elements it holds without growing
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    /// Remove and return the last element
+    pub fn pop(&mut self) -> Option<T> {
+        self.0.pop()
+    }
+
+    /// Remove and return the element at `index`, shifting those after it
+    ///
+    /// ## Panics
+    /// Panics if `index` is out of bounds
+    pub fn remove(&mut self, index: usize) -> T {
+        self.0.remove(index)
+    }
+
+    /// Remove and return the element at `index`, moving the last element
+    /// into its place
+    ///
+    /// ## Panics
+    /// Panics if `index` is out of bounds
+    pub fn swap_remove(&mut self, index: usize) -> T {
+        self.0.swap_remove(index)
+    }
+
+    /// Keep only the first `len` elements, dropping the rest
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
+
+    /// Keep only the elements `f` returns true for
+    pub fn retain(&mut self, f: impl FnMut(&T) -> bool) {
+        self.0.retain(f);
+    }
+
+    /// Drop every element, keeping the memory
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// The vector, allocating from the same type without sleeping. Its
+    /// `push` and `extend` panic when memory runs out
+    pub fn into_vec(self) -> Vec<T, KernelAllocator> {
+        self.0
+    }
+}
+
+impl<T> Default for KVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deref for KVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for KVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.0
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for KVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A `String` that grows without sleeping
+///
+/// It derefs to a `str`, and is formatted into with `write!`, which fails
+/// with `fmt::Error` once memory runs out, keeping what was written before.
+pub struct KString(KVec<u8>);
+
+impl KString {
+    /// An empty string of `M_DEVBUF` memory, not yet allocated
+    pub const fn new() -> Self {
+        KString(KVec::new())
+    }
+
+    /// An empty string growing with `alloc`, not yet allocated
+    pub const fn new_with(alloc: KernelAllocator) -> Self {
+        KString(KVec::new_with(alloc))
+    }
+
+    /// A string of `M_DEVBUF` memory with room for `capacity` bytes,
+    /// failing rather than sleeping for it
+    pub fn try_with_capacity(capacity: usize) -> Result<Self, AllocError> {
+        KVec::try_with_capacity(capacity).map(KString)
+    }
+
+    /// A string with room for `capacity` bytes from `alloc`, which fails
+    /// only if it may not sleep
+    pub fn try_with_capacity_with(
+        capacity: usize,
+        alloc: KernelAllocator,
+    ) -> Result<Self, AllocError> {
+        KVec::try_with_capacity_with(capacity, alloc).map(KString)
+    }
+
+    /// A copy of `s` in `M_DEVBUF` memory, failing rather than sleeping
+    /// for it
+    pub fn try_from_str(s: &str) -> Result<Self, AllocError> {
+        let mut string = Self::try_with_capacity(s.len())?;
+        string.try_push_str(s)?;
+        Ok(string)
+    }
+
+    /// Append `s`, or nothing if there is no memory for it
+    pub fn try_push_str(&mut self, s: &str) -> Result<(), AllocError> {
+        self.0.try_extend_from_slice(s.as_bytes())
+    }
+
+    /// Append `c`, or nothing if there is no memory for it
+    pub fn try_push(&mut self, c: char) -> Result<(), AllocError> {
+        self.try_push_str(c.encode_utf8(&mut [0; 4]))
+    }
+
+    /// Make room for at least `additional` more bytes
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        self.0.try_reserve(additional)
+    }
+
+    /// The string
+    pub fn as_str(&self) -> &str {
+        // Only ever appended to from strs
+        unsafe { str::from_utf8_unchecked(&self.0) }
+    }
+
+    /// The number of bytes it holds without growing
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    /// Empty the string, keeping its memory
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// The string's bytes
+    pub fn into_bytes(self) -> KVec<u8> {
+        self.0
+    }
+}
+
+impl Default for KString {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for KString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Write for KString {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.try_push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Debug for KString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for KString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
 }
 
 /// from `sys/malloc.h`
@@ -63,6 +648,16 @@ unsafe impl GlobalAlloc for KernelAllocator {
 /// ```
 
 #[alloc_error_handler]
-fn oom(_layout: Layout) -> ! {
-    panic!("Out of memory!");
+#[cfg(not(feature = "mock"))]
+fn oom(layout: Layout) -> ! {
+    // Only M_NOWAIT allocations fail, and one that did where it couldn't
+    // be handled leaves nothing sane to carry on with. They are mostly
+    // made where sleeping isn't allowed, so the module's panic handler
+    // couldn't park the thread anyway: straight to panic(9)
+    unsafe {
+        kernel_sys::panic(
+            c"rust: out of memory allocating %zu bytes".as_ptr(),
+            layout.size(),
+        )
+    }
 }
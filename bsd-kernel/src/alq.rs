@@ -0,0 +1,199 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! Asynchronous logging queues, `alq(9)`
+//!
+//! An `Alq` streams records to a file through a buffer the kernel's
+//! `ALQ` daemon writes out, so tracing from a hot path costs a copy into
+//! memory rather than the I/O. Queues hold variable-length records in a
+//! buffer of a given size, or a given count of records of one size:
+//! ```ignore
+//! let trace = Alq::open(c"/var/log/rusttrace", 64 * 1024)?;
+//! // From the I/O path, dropping the record if the buffer is full
+//! let _ = trace.try_write(&record.to_ne_bytes());
+//! ```
+//! The module needs `MODULE_DEPEND(<module>, alq, 1, 1, 1);` in its C
+//! file, so that `alq.ko` is loaded first.
+
+use crate::errno::Errno;
+use core::ffi::CStr;
+use core::{fmt, ptr, slice};
+use libc::c_int;
+
+/// An open logging queue, flushed and closed when dropped
+pub struct Alq {
+    alq: ptr::NonNull<kernel_sys::alq>,
+    /// The record size of a fixed-length queue
+    fixed: Option<usize>,
+    /// The longest record that fits
+    max: usize,
+}
+
+// alq serializes writers with its own lock
+unsafe impl Send for Alq {}
+unsafe impl Sync for Alq {}
+
+impl Alq {
+    /// Open `path` for variable-length records, with `size` bytes of
+    /// buffer. The file is created with mode 0600 and the credentials of
+    /// the calling thread, such as `kldload`'s. Sleeps
+    pub fn open(path: &CStr, size: usize) -> Result<Self, Errno> {
+        let len = c_int::try_from(size).map_err(|_| Errno::Inval)?;
+        let mut alq = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::alq_open_flags(
+                &mut alq,
+                path.as_ptr(),
+                (*crate::arch::curthread()).td_ucred,
+                kernel_sys::ALQ_DEFAULT_CMODE,
+                len,
+                // Sleeping writers go in turn rather than racing for space
+                kernel_sys::ALQ_ORDERED,
+            )
+        })?;
+        Ok(Alq {
+            alq: ptr::NonNull::new(alq).ok_or(Errno::NoMem)?,
+            fixed: None,
+            max: size,
+        })
+    }
+
+    /// Open `path` for `count` records of `size` bytes each, as
+    /// `open` does
+    pub fn open_fixed(
+        path: &CStr,
+        size: usize,
+        count: usize,
+    ) -> Result<Self, Errno> {
+        let len = c_int::try_from(size).map_err(|_| Errno::Inval)?;
+        let count = c_int::try_from(count).map_err(|_| Errno::Inval)?;
+        if len == 0 || count == 0 {
+            return Err(Errno::Inval);
+        }
+        let mut alq = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::alq_open(
+                &mut alq,
+                path.as_ptr(),
+                (*crate::arch::curthread()).td_ucred,
+                kernel_sys::ALQ_DEFAULT_CMODE,
+                len,
+                count,
+            )
+        })?;
+        Ok(Alq {
+            alq: ptr::NonNull::new(alq).ok_or(Errno::NoMem)?,
+            fixed: Some(size),
+            max: size,
+        })
+    }
+
+    /// The record size, for fixed-length queues
+    pub fn record_size(&self) -> Option<usize> {
+        self.fixed
+    }
+
+    /// Queue a copy of `record`, sleeping until there is room. Records
+    /// of a fixed-length queue must be exactly its size, and those of a
+    /// variable-length one no longer than its buffer
+    pub fn write(&self, record: &[u8]) -> Result<(), Errno> {
+        self.writen(record, kernel_sys::ALQ_WAITOK)
+    }
+
+    /// Queue a copy of `record` without sleeping, failing with
+    /// `Errno::Again` if the buffer is full
+    pub fn try_write(&self, record: &[u8]) -> Result<(), Errno> {
+        self.writen(record, kernel_sys::ALQ_NOWAIT)
+    }
+
+    fn writen(&self, record: &[u8], flags: i32) -> Result<(), Errno> {
+        self.check(record.len())?;
+        Errno::result(unsafe {
+            kernel_sys::alq_writen(
+                self.as_ptr(),
+                record.as_ptr() as *mut libc::c_void,
+                record.len() as c_int,
+                flags,
+            )
+        })
+    }
+
+    /// Fill a `len` byte record in place with `f` and queue it, without
+    /// copying, `alq_getn()` and `alq_post()`. `f` runs under the queue's
+    /// spin lock, so it must be short and must not sleep. Without
+    /// `wait`, returns `None` if the buffer is full; with it, sleeps
+    /// until there is room
+    pub fn write_with<R>(
+        &self,
+        len: usize,
+        wait: bool,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> Option<R> {
+        self.check(len).ok()?;
+        let flags = match wait {
+            true => kernel_sys::ALQ_WAITOK,
+            false => kernel_sys::ALQ_NOWAIT,
+        };
+        let ale =
+            unsafe { kernel_sys::alq_getn(self.as_ptr(), len as c_int, flags) };
+        if ale.is_null() {
+            return None;
+        }
+        let buf = unsafe {
+            slice::from_raw_parts_mut((*ale).ae_data as *mut u8, len)
+        };
+        let result = f(buf);
+        unsafe { kernel_sys::alq_post_flags(self.as_ptr(), ale, 0) };
+        Some(result)
+    }
+
+    fn check(&self, len: usize) -> Result<(), Errno> {
+        match self.fixed {
+            Some(size) if len != size => Err(Errno::Inval),
+            _ if len == 0 || len > self.max => Err(Errno::Inval),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write out what is queued now, rather than when the daemon next
+    /// gets to it. Sleeps
+    pub fn flush(&self) {
+        unsafe { kernel_sys::alq_flush(self.as_ptr()) };
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::alq {
+        self.alq.as_ptr()
+    }
+}
+
+impl Drop for Alq {
+    fn drop(&mut self) {
+        // Writes out what is left and closes the file, which sleeps
+        unsafe { kernel_sys::alq_close(self.as_ptr()) };
+    }
+}
+
+impl fmt::Debug for Alq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Alq {{ alq: {:?}, fixed: {:?} }}", self.alq, self.fixed)
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! riscv64: `tp` holds the pcpu pointer
+
+use core::mem;
+use kernel_sys::{bus_size_t, bus_space_handle_t, bus_space_tag_t};
+
+/// `curcpu`
+pub(crate) fn curcpu() -> u32 {
+    let cpuid: u32;
+    unsafe {
+        core::arch::asm!(
+            "lwu {0}, {off}(tp)",
+            out(reg) cpuid,
+            off = const mem::offset_of!(kernel_sys::pcpu, pc_cpuid),
+            options(nostack, preserves_flags, readonly),
+        );
+    }
+    cpuid
+}
+
+/// `curthread`
+pub(crate) fn curthread() -> *mut kernel_sys::thread {
+    let td: *mut kernel_sys::thread;
+    unsafe {
+        core::arch::asm!(
+            "ld {0}, {off}(tp)",
+            out(reg) td,
+            off = const mem::offset_of!(kernel_sys::pcpu, pc_curthread),
+            options(nostack, preserves_flags, readonly),
+        );
+    }
+    td
+}
+
+macro_rules! bus_space {
+    ($read:ident, $r:ident, $write:ident, $w:ident, $t:ty) => {
+        /// ## Safety
+        /// `h + o` must be in a resource `t` maps
+        pub(crate) unsafe fn $read(
+            t: bus_space_tag_t,
+            h: bus_space_handle_t,
+            o: bus_size_t,
+        ) -> $t {
+            unsafe { ((*t).$r.unwrap())((*t).bs_cookie, h, o) }
+        }
+
+        /// ## Safety
+        /// `h + o` must be in a resource `t` maps
+        pub(crate) unsafe fn $write(
+            t: bus_space_tag_t,
+            h: bus_space_handle_t,
+            o: bus_size_t,
+            v: $t,
+        ) {
+            unsafe { ((*t).$w.unwrap())((*t).bs_cookie, h, o, v) }
+        }
+    };
+}
+
+bus_space!(bus_read_1, bs_r_1, bus_write_1, bs_w_1, u8);
+bus_space!(bus_read_2, bs_r_2, bus_write_2, bs_w_2, u16);
+bus_space!(bus_read_4, bs_r_4, bus_write_4, bs_w_4, u32);
+bus_space!(bus_read_8, bs_r_8, bus_write_8, bs_w_8, u64);
+
+/// `bus_space_barrier()`, through the bus's own method
+pub(crate) unsafe fn bus_barrier(
+    t: bus_space_tag_t,
+    h: bus_space_handle_t,
+    o: bus_size_t,
+    len: bus_size_t,
+    flags: i32,
+) {
+    unsafe { ((*t).bs_barrier.unwrap())((*t).bs_cookie, h, o, len, flags) }
+}
+
+/// `PMC_FAKE_TRAPFRAME()`: a kernel mode trapframe for the caller's pc
+/// and frame, for hwpmc to take a callchain from
+#[inline(always)]
+pub(crate) fn fake_trapframe(tf: &mut kernel_sys::trapframe) {
+    unsafe {
+        core::arch::asm!(
+            "auipc {pc}, 0",
+            "mv {fp}, s0",
+            "mv {sp}, sp",
+            pc = out(reg) tf.tf_sepc,
+            fp = out(reg) tf.tf_s[0],
+            sp = out(reg) tf.tf_sp,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    // SSTATUS_SPP, trapped from supervisor mode
+    tf.tf_sstatus = 1 << 8;
+}
+
+/// `cpu_spinwait()`, which does nothing on riscv64
+#[inline(always)]
+pub(crate) fn cpu_spinwait() {}
+
+/// `get_cyclecount()`: `rdcycle`
+#[inline(always)]
+pub(crate) fn get_cyclecount() -> u64 {
+    let count: u64;
+    unsafe {
+        core::arch::asm!(
+            "rdcycle {0}",
+            out(reg) count,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    count
+}
+
+/// The cycle counter runs at the core's clock, which the kernel doesn't
+/// record
+pub(crate) fn cyclecount_frequency() -> u64 {
+    0
+}
+
+/// The prefetch instructions are an extension the kernel doesn't assume
+#[inline(always)]
+pub(crate) fn prefetch_read(_p: *const u8) {}
+
+#[inline(always)]
+pub(crate) fn prefetch_write(_p: *const u8) {}
@@ -0,0 +1,225 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! amd64: the pcpu is addressed through `%gs`
+
+use core::sync::atomic::{Ordering, compiler_fence};
+use core::{mem, ptr};
+use kernel_sys::{bus_size_t, bus_space_handle_t, bus_space_tag_t};
+
+/// `X86_BUS_SPACE_IO`; the one other tag is memory
+const BUS_SPACE_IO: bus_space_tag_t = 0;
+
+/// `curcpu`
+pub(crate) fn curcpu() -> u32 {
+    let cpuid: u32;
+    unsafe {
+        core::arch::asm!(
+            "mov {0:e}, dword ptr gs:[{off}]",
+            out(reg) cpuid,
+            off = const mem::offset_of!(kernel_sys::pcpu, pc_cpuid),
+            options(nostack, preserves_flags, readonly),
+        );
+    }
+    cpuid
+}
+
+/// `curthread`
+pub(crate) fn curthread() -> *mut kernel_sys::thread {
+    let td: *mut kernel_sys::thread;
+    unsafe {
+        core::arch::asm!(
+            "mov {0}, qword ptr gs:[{off}]",
+            out(reg) td,
+            off = const mem::offset_of!(kernel_sys::pcpu, pc_curthread),
+            options(nostack, preserves_flags, readonly),
+        );
+    }
+    td
+}
+
+macro_rules! bus_space {
+    ($read:ident, $write:ident, $t:ty, $reg:tt) => {
+        /// ## Safety
+        /// `h + o` must be in a resource `t` maps
+        pub(crate) unsafe fn $read(
+            t: bus_space_tag_t,
+            h: bus_space_handle_t,
+            o: bus_size_t,
+        ) -> $t {
+            if t == BUS_SPACE_IO {
+                let v: $t;
+                unsafe {
+                    core::arch::asm!(
+                        concat!("in ", $reg, ", dx"),
+                        out($reg) v,
+                        in("dx") (h + o) as u16,
+                        options(nomem, nostack, preserves_flags),
+                    )
+                };
+                v
+            } else {
+                unsafe { ptr::read_volatile((h + o) as *const $t) }
+            }
+        }
+
+        /// ## Safety
+        /// `h + o` must be in a resource `t` maps
+        pub(crate) unsafe fn $write(
+            t: bus_space_tag_t,
+            h: bus_space_handle_t,
+            o: bus_size_t,
+            v: $t,
+        ) {
+            if t == BUS_SPACE_IO {
+                unsafe {
+                    core::arch::asm!(
+                        concat!("out dx, ", $reg),
+                        in($reg) v,
+                        in("dx") (h + o) as u16,
+                        options(nomem, nostack, preserves_flags),
+                    )
+                };
+            } else {
+                unsafe { ptr::write_volatile((h + o) as *mut $t, v) }
+            }
+        }
+    };
+}
+
+// The port instructions take the value in the accumulator
+bus_space!(bus_read_1, bus_write_1, u8, "al");
+bus_space!(bus_read_2, bus_write_2, u16, "ax");
+bus_space!(bus_read_4, bus_write_4, u32, "eax");
+
+/// `BUS_SPACE_INVALID_DATA`, what 8-byte reads of ports return
+const BUS_SPACE_INVALID_DATA: u64 = !0;
+
+/// ## Safety
+/// `h + o` must be in a resource `t` maps
+pub(crate) unsafe fn bus_read_8(
+    t: bus_space_tag_t,
+    h: bus_space_handle_t,
+    o: bus_size_t,
+) -> u64 {
+    // There are no 8-byte port instructions
+    if t == BUS_SPACE_IO {
+        return BUS_SPACE_INVALID_DATA;
+    }
+    unsafe { ptr::read_volatile((h + o) as *const u64) }
+}
+
+/// ## Safety
+/// `h + o` must be in a resource `t` maps
+pub(crate) unsafe fn bus_write_8(
+    t: bus_space_tag_t,
+    h: bus_space_handle_t,
+    o: bus_size_t,
+    v: u64,
+) {
+    if t != BUS_SPACE_IO {
+        unsafe { ptr::write_volatile((h + o) as *mut u64, v) }
+    }
+}
+
+/// `bus_space_barrier()`: x86 keeps device accesses in order, so only
+/// the compiler needs holding back
+pub(crate) unsafe fn bus_barrier(
+    _t: bus_space_tag_t,
+    _h: bus_space_handle_t,
+    _o: bus_size_t,
+    _len: bus_size_t,
+    _flags: i32,
+) {
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// `PMC_FAKE_TRAPFRAME()`: a kernel mode trapframe for the caller's pc
+/// and frame, for hwpmc to take a callchain from
+#[inline(always)]
+pub(crate) fn fake_trapframe(tf: &mut kernel_sys::trapframe) {
+    unsafe {
+        core::arch::asm!(
+            "lea {pc}, [rip]",
+            "mov {fp}, rbp",
+            "mov {sp}, rsp",
+            pc = out(reg) tf.tf_rip,
+            fp = out(reg) tf.tf_rbp,
+            sp = out(reg) tf.tf_rsp,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    // SEL_KPL
+    tf.tf_cs = 0;
+}
+
+/// `cpu_spinwait()`
+#[inline(always)]
+pub(crate) fn cpu_spinwait() {
+    unsafe {
+        core::arch::asm!("pause", options(nomem, nostack, preserves_flags))
+    };
+}
+
+/// `rdtsc()`, which `get_cyclecount()` is on amd64
+#[inline(always)]
+pub(crate) fn get_cyclecount() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe {
+        core::arch::asm!(
+            "rdtsc",
+            out("eax") lo,
+            out("edx") hi,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    u64::from(hi) << 32 | u64::from(lo)
+}
+
+/// `tsc_freq`, zero until the TSC is calibrated
+pub(crate) fn cyclecount_frequency() -> u64 {
+    unsafe { ptr::read_volatile(&raw const kernel_sys::tsc_freq) }
+}
+
+#[inline(always)]
+pub(crate) fn prefetch_read(p: *const u8) {
+    unsafe {
+        core::arch::asm!(
+            "prefetcht0 [{0}]",
+            in(reg) p,
+            options(readonly, nostack, preserves_flags),
+        );
+    }
+}
+
+/// `prefetchw`, a no-op on CPUs without it
+#[inline(always)]
+pub(crate) fn prefetch_write(p: *const u8) {
+    unsafe {
+        core::arch::asm!(
+            "prefetchw [{0}]",
+            in(reg) p,
+            options(readonly, nostack, preserves_flags),
+        );
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Typed multi-producer, single-consumer ring wrapping `buf_ring(9)`
+//!
+//! The enqueue and dequeue paths of `buf_ring` are inline functions in
+//! `sys/buf_ring.h`, so they are reimplemented here on top of the ring
+//! allocated by `buf_ring_alloc`, following the same protocol.
+
+use crate::errno::Errno;
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::{fmt, hint};
+use libc::c_void;
+
+/// A bounded lock-free queue of boxed items. Any number of producers may
+/// enqueue concurrently, from any context that may not sleep (including
+/// interrupt filters); one consumer at a time dequeues through a
+/// `Consumer` handle
+pub struct BufRing<T: Send> {
+    br: NonNull<kernel_sys::buf_ring>,
+    consumer: AtomicBool,
+    _marker: PhantomData<Box<T>>,
+}
+
+unsafe impl<T: Send> Send for BufRing<T> {}
+unsafe impl<T: Send> Sync for BufRing<T> {}
+
+impl<T: Send> BufRing<T> {
+    /// Allocate a ring with room for `count - 1` items. `count` must be a
+    /// power of two
+    pub fn new(count: usize) -> Result<Self, Errno> {
+        if !count.is_power_of_two() || count < 2 {
+            return Err(Errno::Inval);
+        }
+        let count = i32::try_from(count).map_err(|_| Errno::Inval)?;
+        let br = unsafe {
+            kernel_sys::buf_ring_alloc(
+                count,
+                &mut kernel_sys::M_DEVBUF[0],
+                kernel_sys::M_WAITOK,
+                ptr::null_mut(),
+            )
+        };
+        Ok(BufRing {
+            br: NonNull::new(br).ok_or(Errno::NoMem)?,
+            consumer: AtomicBool::new(false),
+            _marker: PhantomData,
+        })
+    }
+
+    fn prod_head(&self) -> &AtomicU32 {
+        unsafe {
+            AtomicU32::from_ptr(&raw mut (*self.br.as_ptr()).br_prod_head)
+        }
+    }
+
+    fn prod_tail(&self) -> &AtomicU32 {
+        unsafe {
+            AtomicU32::from_ptr(&raw mut (*self.br.as_ptr()).br_prod_tail)
+        }
+    }
+
+    fn cons_head(&self) -> &AtomicU32 {
+        unsafe {
+            AtomicU32::from_ptr(&raw mut (*self.br.as_ptr()).br_cons_head)
+        }
+    }
+
+    fn cons_tail(&self) -> &AtomicU32 {
+        unsafe {
+            AtomicU32::from_ptr(&raw mut (*self.br.as_ptr()).br_cons_tail)
+        }
+    }
+
+    fn prod_mask(&self) -> u32 {
+        unsafe { (*self.br.as_ptr()).br_prod_mask as u32 }
+    }
+
+    fn cons_mask(&self) -> u32 {
+        unsafe { (*self.br.as_ptr()).br_cons_mask as u32 }
+    }
+
+    fn slot(&self, idx: u32) -> *mut *mut c_void {
+        unsafe { (*self.br.as_ptr()).br_ring.as_mut_ptr().add(idx as usize) }
+    }
+
+    /// Add an item to the ring. If the ring is full the drop counter is
+    /// bumped and the item is handed back
+    pub fn enqueue(&self, item: Box<T>) -> Result<(), Box<T>> {
+        let buf = Box::into_raw(item);
+        let mask = self.prod_mask();
+        // Stay on this CPU while we own a slot, so a preempted producer
+        // can't stall the others spinning on br_prod_tail below
+        unsafe { kernel_sys::critical_enter_KBI() };
+        let prod_head = loop {
+            let prod_head = self.prod_head().load(Ordering::Relaxed);
+            let prod_next = (prod_head + 1) & mask;
+            let cons_tail = self.cons_tail().load(Ordering::Acquire);
+            if prod_next == cons_tail {
+                if prod_head == self.prod_head().load(Ordering::Acquire)
+                    && cons_tail == self.cons_tail().load(Ordering::Acquire)
+                {
+                    unsafe {
+                        (*self.br.as_ptr()).br_drops += 1;
+                        kernel_sys::critical_exit_KBI();
+                        return Err(Box::from_raw(buf));
+                    }
+                }
+                continue;
+            }
+            if self
+                .prod_head()
+                .compare_exchange(
+                    prod_head,
+                    prod_next,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                break prod_head;
+            }
+        };
+        unsafe {
+            ptr::write_volatile(self.slot(prod_head), buf as *mut c_void)
+        };
+        // Wait for enqueues that claimed earlier slots to publish theirs
+        while self.prod_tail().load(Ordering::Relaxed) != prod_head {
+            hint::spin_loop();
+        }
+        self.prod_tail()
+            .store((prod_head + 1) & mask, Ordering::Release);
+        unsafe { kernel_sys::critical_exit_KBI() };
+        Ok(())
+    }
+
+    /// Claim the consumer side of the ring. Returns `None` if another
+    /// `Consumer` for this ring is still alive
+    pub fn consumer(&self) -> Option<Consumer<'_, T>> {
+        self.consumer
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Consumer { ring: self })
+    }
+
+    /// The number of items currently in the ring
+    pub fn len(&self) -> usize {
+        let prod_tail = self.prod_tail().load(Ordering::Acquire);
+        let cons_tail = self.cons_tail().load(Ordering::Acquire);
+        (prod_tail.wrapping_sub(cons_tail) & self.prod_mask()) as usize
+    }
+
+    /// Check whether the ring is empty
+    pub fn is_empty(&self) -> bool {
+        self.cons_head().load(Ordering::Acquire)
+            == self.prod_tail().load(Ordering::Acquire)
+    }
+
+    /// Check whether the ring is full
+    pub fn is_full(&self) -> bool {
+        let prod_head = self.prod_head().load(Ordering::Acquire);
+        (prod_head + 1) & self.prod_mask()
+            == self.cons_tail().load(Ordering::Acquire)
+    }
+
+    /// The number of items that could not be enqueued because the ring was
+    /// full
+    pub fn drops(&self) -> u64 {
+        unsafe { ptr::read_volatile(&raw const (*self.br.as_ptr()).br_drops) }
+    }
+
+    fn dequeue_sc(&self) -> Option<Box<T>> {
+        let cons_head = self.cons_head().load(Ordering::Relaxed);
+        let prod_tail = self.prod_tail().load(Ordering::Acquire);
+        if cons_head == prod_tail {
+            return None;
+        }
+        let cons_next = (cons_head + 1) & self.cons_mask();
+        self.cons_head().store(cons_next, Ordering::Relaxed);
+        let buf = unsafe { ptr::read_volatile(self.slot(cons_head)) };
+        self.cons_tail().store(cons_next, Ordering::Release);
+        Some(unsafe { Box::from_raw(buf as *mut T) })
+    }
+
+    fn peek_sc(&self) -> Option<&T> {
+        let cons_head = self.cons_head().load(Ordering::Relaxed);
+        if cons_head == self.prod_tail().load(Ordering::Acquire) {
+            return None;
+        }
+        let buf = unsafe { ptr::read_volatile(self.slot(cons_head)) };
+        Some(unsafe { &*(buf as *const T) })
+    }
+}
+
+impl<T: Send> fmt::Debug for BufRing<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BufRing {{ br: {:?}, len: {} }}", self.br, self.len())
+    }
+}
+
+impl<T: Send> Drop for BufRing<T> {
+    fn drop(&mut self) {
+        while self.dequeue_sc().is_some() {}
+        unsafe {
+            kernel_sys::buf_ring_free(
+                self.br.as_ptr(),
+                &mut kernel_sys::M_DEVBUF[0],
+            );
+        }
+    }
+}
+
+/// The single consumer of a `BufRing`
+pub struct Consumer<'a, T: Send> {
+    ring: &'a BufRing<T>,
+}
+
+impl<T: Send> Consumer<'_, T> {
+    /// Remove the oldest item from the ring
+    pub fn dequeue(&mut self) -> Option<Box<T>> {
+        self.ring.dequeue_sc()
+    }
+
+    /// Look at the oldest item without removing it
+    pub fn peek(&mut self) -> Option<&T> {
+        self.ring.peek_sc()
+    }
+}
+
+impl<T: Send> Iterator for Consumer<'_, T> {
+    type Item = Box<T>;
+
+    fn next(&mut self) -> Option<Box<T>> {
+        self.dequeue()
+    }
+}
+
+impl<T: Send> fmt::Debug for Consumer<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Consumer {{ ring: {:?} }}", self.ring)
+    }
+}
+
+impl<T: Send> Drop for Consumer<'_, T> {
+    fn drop(&mut self) {
+        self.ring.consumer.store(false, Ordering::Release);
+    }
+}
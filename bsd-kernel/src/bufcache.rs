@@ -0,0 +1,160 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Per-CPU caches of scratch buffers
+//!
+//! A `BufCache` hands out buffers of one size for short-lived use on hot
+//! paths, such as staging a transfer, without going to the allocator each
+//! time. Each CPU keeps its own few free buffers, taken and returned in a
+//! critical section, so CPUs never contend with each other; only when a
+//! CPU has none to give, or no room for one coming back, does `get` or
+//! dropping a `Buf` fall back to `malloc(9)` and `free(9)`.
+//!
+//! The critical sections only protect against other threads, so a cache
+//! must not be used from interrupt filters.
+
+use crate::sched::critical;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+/// Free buffers kept by one CPU
+struct Stack {
+    bufs: Vec<Box<[u8]>>,
+}
+
+/// A cache of `SIZE` byte buffers, keeping up to `DEPTH` free on each CPU
+pub struct BufCache<const SIZE: usize, const DEPTH: usize = 4> {
+    /// Indexed by CPU ID, and only touched from that CPU in a critical
+    /// section
+    cpus: Box<[UnsafeCell<Stack>]>,
+}
+
+unsafe impl<const SIZE: usize, const DEPTH: usize> Sync
+    for BufCache<SIZE, DEPTH>
+{
+}
+
+impl<const SIZE: usize, const DEPTH: usize> BufCache<SIZE, DEPTH> {
+    /// An empty cache, which fills as buffers are returned to it
+    pub fn new() -> Self {
+        let cpus = (0..=crate::smp::max_cpu_id())
+            .map(|_| {
+                UnsafeCell::new(Stack {
+                    bufs: Vec::with_capacity(DEPTH),
+                })
+            })
+            .collect();
+        BufCache { cpus }
+    }
+
+    /// The calling CPU's stack
+    ///
+    /// ## Safety
+    /// Must be called in a critical section, which the reference must not
+    /// outlive
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn local(&self) -> &mut Stack {
+        let cpu = crate::smp::current_cpu();
+        unsafe { &mut *self.cpus[cpu].get() }
+    }
+
+    /// A buffer, cached by this CPU if it has one. Its contents are left
+    /// over from its last use
+    pub fn get(&self) -> Buf<'_, SIZE, DEPTH> {
+        let cached = critical(|| unsafe { self.local() }.bufs.pop());
+        // Allocated outside the critical section, as malloc may sleep
+        let buf = cached.unwrap_or_else(|| vec![0; SIZE].into_boxed_slice());
+        Buf {
+            cache: self,
+            buf: Some(buf),
+        }
+    }
+
+    /// Give `buf` back to this CPU, or free it if already holding `DEPTH`
+    fn put(&self, buf: Box<[u8]>) {
+        let spare = critical(|| {
+            let stack = unsafe { self.local() };
+            if stack.bufs.len() < DEPTH {
+                stack.bufs.push(buf);
+                None
+            } else {
+                Some(buf)
+            }
+        });
+        drop(spare);
+    }
+}
+
+impl<const SIZE: usize, const DEPTH: usize> Default for BufCache<SIZE, DEPTH> {
+    fn default() -> Self {
+        BufCache::new()
+    }
+}
+
+impl<const SIZE: usize, const DEPTH: usize> fmt::Debug
+    for BufCache<SIZE, DEPTH>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BufCache {{ size: {}, depth: {} }}", SIZE, DEPTH)
+    }
+}
+
+/// A buffer from a `BufCache`, given back to the CPU it is dropped on
+pub struct Buf<'a, const SIZE: usize, const DEPTH: usize> {
+    cache: &'a BufCache<SIZE, DEPTH>,
+    buf: Option<Box<[u8]>>,
+}
+
+impl<const SIZE: usize, const DEPTH: usize> Deref for Buf<'_, SIZE, DEPTH> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.as_deref().unwrap()
+    }
+}
+
+impl<const SIZE: usize, const DEPTH: usize> DerefMut for Buf<'_, SIZE, DEPTH> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf.as_deref_mut().unwrap()
+    }
+}
+
+impl<const SIZE: usize, const DEPTH: usize> Drop for Buf<'_, SIZE, DEPTH> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.cache.put(buf);
+        }
+    }
+}
+
+impl<const SIZE: usize, const DEPTH: usize> fmt::Debug
+    for Buf<'_, SIZE, DEPTH>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Buf {{ size: {} }}", SIZE)
+    }
+}
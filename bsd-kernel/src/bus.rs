@@ -0,0 +1,802 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! newbus device drivers, see `driver(9)`
+//!
+//! `DRIVER_MODULE` stays in C, and the method table can too, written with
+//! the `DEVMETHOD` macros; the C file declares the driver with a softc the
+//! size of a pointer and points its `device_probe`, `device_attach` and
+//! `device_detach` methods at functions `newbus_driver!` exports:
+//! ```c,ignore
+//! static device_method_t rusthid_methods[] = {
+//!     DEVMETHOD(device_probe,  rusthid_probe),
+//!     DEVMETHOD(device_attach, rusthid_attach),
+//!     DEVMETHOD(device_detach, rusthid_detach),
+//!     DEVMETHOD_END
+//! };
+//! DEFINE_CLASS_0(rusthid, rusthid_driver, rusthid_methods, sizeof(void *));
+//! DRIVER_MODULE(rusthid, hidbus, rusthid_driver, module_event, NULL);
+//! ```
+//! The softc then holds the driver's state, boxed by `attach`. A driver
+//! that only implements `device_if` needs no C at all: `driver_module!`
+//! builds its method table and declares it on a bus, as `DRIVER_MODULE`
+//! does:
+//! ```rust,ignore
+//! bsd_kernel::driver_module!(rustuart, acpi, Uart);
+//! ```
+//! A driver implementing more interfaces can build the whole table in Rust
+//! with `kobj::Class`.
+
+use crate::arch;
+use crate::errno::Errno;
+use crate::kobj;
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::{fmt, mem, ptr};
+use libc::c_int;
+
+/// `BUS_PROBE_*` priorities for `Driver::probe`, from `sys/bus.h`; the
+/// highest bidder gets the device
+pub mod priority {
+    /// Only this driver can handle the device
+    pub const SPECIFIC: i32 = 0;
+    /// A vendor's own driver
+    pub const VENDOR: i32 = -10;
+    /// The base OS's driver
+    pub const DEFAULT: i32 = -20;
+    /// An older, less favoured driver
+    pub const LOW_PRIORITY: i32 = -40;
+    /// A driver for a whole class of devices
+    pub const GENERIC: i32 = -100;
+    /// Takes anything nobody else wants
+    pub const HOOVER: i32 = -1_000_000;
+}
+
+/// A `device_t`, valid for as long as the driver is attached to it
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Device {
+    dev: ptr::NonNull<kernel_sys::_device>,
+}
+
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
+
+impl Device {
+    /// ## Safety
+    /// `dev` must be a device the caller's driver is probing or attached to
+    pub unsafe fn from_raw(dev: kernel_sys::device_t) -> Option<Self> {
+        ptr::NonNull::new(dev).map(|dev| Device { dev })
+    }
+
+    pub fn as_ptr(&self) -> kernel_sys::device_t {
+        self.dev.as_ptr()
+    }
+
+    /// The name and unit, as in `hms0`
+    pub fn nameunit(&self) -> &CStr {
+        unsafe {
+            CStr::from_ptr(kernel_sys::device_get_nameunit(self.as_ptr()))
+        }
+    }
+
+    /// The name of the driver's devices, as in `hms`
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(kernel_sys::device_get_name(self.as_ptr())) }
+    }
+
+    pub fn unit(&self) -> i32 {
+        unsafe { kernel_sys::device_get_unit(self.as_ptr()) }
+    }
+
+    /// The bus device this one hangs off
+    pub fn parent(&self) -> Option<Device> {
+        unsafe {
+            Device::from_raw(kernel_sys::device_get_parent(self.as_ptr()))
+        }
+    }
+
+    /// Set the description shown when the device attaches
+    pub fn set_desc(&self, desc: &'static CStr) {
+        unsafe { kernel_sys::device_set_desc(self.as_ptr(), desc.as_ptr()) };
+    }
+
+    /// Set a description made at run time, which the kernel copies
+    pub fn set_desc_copy(&self, desc: &CStr) {
+        unsafe {
+            kernel_sys::device_set_desc_copy(self.as_ptr(), desc.as_ptr())
+        };
+    }
+
+    /// Read instance variable `index` the parent bus keeps for this
+    /// device, `BUS_READ_IVAR()`
+    pub fn read_ivar(&self, index: i32) -> Result<usize, Errno> {
+        let parent = self.parent().ok_or(Errno::NxIo)?;
+        let f: kernel_sys::bus_read_ivar_t = unsafe {
+            mem::transmute(method(
+                parent,
+                &raw mut kernel_sys::bus_read_ivar_desc,
+            ))
+        };
+        let mut value = 0;
+        Errno::result(unsafe {
+            f.unwrap()(parent.as_ptr(), self.as_ptr(), index, &mut value)
+        })?;
+        Ok(value)
+    }
+
+    /// Set instance variable `index` the parent bus keeps for this
+    /// device, `BUS_WRITE_IVAR()`
+    pub fn write_ivar(&self, index: i32, value: usize) -> Result<(), Errno> {
+        let parent = self.parent().ok_or(Errno::NxIo)?;
+        let f: kernel_sys::bus_write_ivar_t = unsafe {
+            mem::transmute(method(
+                parent,
+                &raw mut kernel_sys::bus_write_ivar_desc,
+            ))
+        };
+        Errno::result(unsafe {
+            f.unwrap()(parent.as_ptr(), self.as_ptr(), index, value)
+        })
+    }
+
+    fn softc(&self) -> *mut *mut libc::c_void {
+        unsafe { kernel_sys::device_get_softc(self.as_ptr()) as *mut _ }
+    }
+}
+
+/// Look up `desc` in `dev`'s driver's method table, for calling
+/// interfaces a parent bus implements, see `kobj::lookup`
+///
+/// ## Safety
+/// The result must be transmuted to the method's `*_t` type.
+pub(crate) unsafe fn method(
+    dev: Device,
+    desc: kernel_sys::kobjop_desc_t,
+) -> kernel_sys::kobjop_t {
+    unsafe { kobj::lookup(dev.as_ptr().cast(), desc) }
+}
+
+impl fmt::Debug for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Device {{ dev: {:?} }}", self.dev.as_ptr())
+    }
+}
+
+/// `BUS_SPACE_BARRIER_*`, which accesses `Resource::barrier` and
+/// `MmioRegion::barrier` order
+pub mod barrier {
+    pub const READ: i32 = 0x01;
+    pub const WRITE: i32 = 0x02;
+}
+
+/// `SYS_RES_*`
+pub(crate) const SYS_RES_IRQ: c_int = 1;
+const SYS_RES_IOPORT: c_int = 4;
+const SYS_RES_MEMORY: c_int = 3;
+
+/// `RF_ACTIVE`: map the resource as it is allocated
+pub(crate) const RF_ACTIVE: u32 = 0x0002;
+
+/// `RF_SHAREABLE`: let other devices allocate it too
+pub(crate) const RF_SHAREABLE: u32 = 0x0004;
+
+/// A resource of a device as `bus_alloc_resource_any()` allocates it,
+/// released when dropped
+///
+/// Owning the rid the bus chose along with the resource is what keeps a
+/// detach path from releasing one twice, or another's.
+pub(crate) struct Allocation {
+    dev: Device,
+    kind: c_int,
+    rid: c_int,
+    res: ptr::NonNull<kernel_sys::resource>,
+}
+
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+impl Allocation {
+    pub(crate) fn new(
+        dev: Device,
+        kind: c_int,
+        rid: c_int,
+        flags: u32,
+    ) -> Result<Self, Errno> {
+        let mut r = rid;
+        let res = unsafe {
+            kernel_sys::bus_alloc_resource(
+                dev.as_ptr(),
+                kind,
+                &mut r,
+                0,
+                !0,
+                1,
+                flags,
+            )
+        };
+        let res = ptr::NonNull::new(res).ok_or(Errno::NxIo)?;
+        Ok(Allocation {
+            dev,
+            kind,
+            rid: r,
+            res,
+        })
+    }
+
+    pub(crate) fn device(&self) -> Device {
+        self.dev
+    }
+
+    pub(crate) fn rid(&self) -> c_int {
+        self.rid
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut kernel_sys::resource {
+        self.res.as_ptr()
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::bus_release_resource(
+                self.dev.as_ptr(),
+                self.kind,
+                self.rid,
+                self.res.as_ptr(),
+            )
+        };
+    }
+}
+
+/// A device's register window, allocated with `bus_alloc_resource(9)`
+/// and accessed as `bus_space(9)` does on each architecture
+///
+/// Offsets are checked against the window's size. The window is active,
+/// so mapped, for as long as it is held, and released when dropped.
+pub struct Resource {
+    alloc: Allocation,
+    tag: kernel_sys::bus_space_tag_t,
+    handle: kernel_sys::bus_space_handle_t,
+    size: usize,
+}
+
+unsafe impl Send for Resource {}
+unsafe impl Sync for Resource {}
+
+macro_rules! access {
+    ($read:ident, $bus_read:ident, $write:ident, $bus_write:ident, $t:ty) => {
+        pub fn $read(&self, off: usize) -> $t {
+            let o = self.check::<$t>(off);
+            unsafe { arch::$bus_read(self.tag, self.handle, o as _) }
+        }
+
+        pub fn $write(&self, off: usize, v: $t) {
+            let o = self.check::<$t>(off);
+            unsafe { arch::$bus_write(self.tag, self.handle, o as _, v) }
+        }
+    };
+}
+
+impl Resource {
+    fn alloc(dev: Device, kind: c_int, rid: c_int) -> Result<Self, Errno> {
+        let alloc = Allocation::new(dev, kind, rid, RF_ACTIVE)?;
+        let res = alloc.as_ptr();
+        Ok(Resource {
+            alloc,
+            tag: unsafe { kernel_sys::rman_get_bustag(res) },
+            handle: unsafe { kernel_sys::rman_get_bushandle(res) },
+            size: unsafe { kernel_sys::rman_get_size(res) } as usize,
+        })
+    }
+
+    /// Memory-mapped registers `rid`, typically 0 for the first window
+    /// or a PCI BAR's config offset
+    pub fn memory(dev: Device, rid: i32) -> Result<Self, Errno> {
+        Resource::alloc(dev, SYS_RES_MEMORY, rid)
+    }
+
+    /// I/O ports `rid`, amd64 only in practice
+    pub fn io_ports(dev: Device, rid: i32) -> Result<Self, Errno> {
+        Resource::alloc(dev, SYS_RES_IOPORT, rid)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The rid the bus allocated the window as
+    pub fn rid(&self) -> i32 {
+        self.alloc.rid()
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::resource {
+        self.alloc.as_ptr()
+    }
+
+    fn check<T>(&self, off: usize) -> usize {
+        assert!(
+            off.checked_add(size_of::<T>())
+                .is_some_and(|end| end <= self.size),
+            "register offset {off:#x} outside resource"
+        );
+        off
+    }
+
+    access!(read_1, bus_read_1, write_1, bus_write_1, u8);
+    access!(read_2, bus_read_2, write_2, bus_write_2, u16);
+    access!(read_4, bus_read_4, write_4, bus_write_4, u32);
+    access!(read_8, bus_read_8, write_8, bus_write_8, u64);
+
+    /// Order the accesses in `barrier::*` to `len` bytes from `off`
+    /// before those after
+    pub fn barrier(&self, off: usize, len: usize, flags: i32) {
+        unsafe {
+            arch::bus_barrier(self.tag, self.handle, off as _, len as _, flags)
+        }
+    }
+
+    /// The registers `len` bytes from `off`, as `bus_space_subregion()`
+    /// would give, to hand a part of the device its own window
+    ///
+    /// ## Panics
+    /// Panics if they aren't all in the resource
+    pub fn region(&self, off: usize, len: usize) -> MmioRegion<'_> {
+        assert!(
+            off.checked_add(len).is_some_and(|end| end <= self.size),
+            "region {off:#x}+{len:#x} outside resource"
+        );
+        MmioRegion {
+            tag: self.tag,
+            handle: self.handle,
+            base: off,
+            size: len,
+            _res: PhantomData,
+        }
+    }
+
+    /// All of the resource's registers
+    pub fn as_region(&self) -> MmioRegion<'_> {
+        self.region(0, self.size)
+    }
+}
+
+impl fmt::Debug for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Resource {{ rid: {}, size: {:#x} }}",
+            self.alloc.rid(),
+            self.size
+        )
+    }
+}
+
+/// A window on the registers of the `Resource` it borrows
+///
+/// Accesses are volatile, in the device's byte order, and checked against
+/// the window's size, with offsets from its start. 8-byte reads of ports
+/// on amd64 return all ones, and writes are dropped, as in C.
+#[derive(Copy, Clone)]
+pub struct MmioRegion<'r> {
+    tag: kernel_sys::bus_space_tag_t,
+    handle: kernel_sys::bus_space_handle_t,
+    base: usize,
+    size: usize,
+    _res: PhantomData<&'r Resource>,
+}
+
+unsafe impl Send for MmioRegion<'_> {}
+unsafe impl Sync for MmioRegion<'_> {}
+
+impl<'r> MmioRegion<'r> {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn check<T>(&self, off: usize) -> usize {
+        assert!(
+            off.checked_add(size_of::<T>())
+                .is_some_and(|end| end <= self.size),
+            "register offset {off:#x} outside region"
+        );
+        self.base + off
+    }
+
+    access!(read_1, bus_read_1, write_1, bus_write_1, u8);
+    access!(read_2, bus_read_2, write_2, bus_write_2, u16);
+    access!(read_4, bus_read_4, write_4, bus_write_4, u32);
+    access!(read_8, bus_read_8, write_8, bus_write_8, u64);
+
+    /// Order the accesses in `barrier::*` to `len` bytes from `off`
+    /// before those after
+    pub fn barrier(&self, off: usize, len: usize, flags: i32) {
+        assert!(
+            off.checked_add(len).is_some_and(|end| end <= self.size),
+            "barrier {off:#x}+{len:#x} outside region"
+        );
+        let o = self.base + off;
+        unsafe {
+            arch::bus_barrier(self.tag, self.handle, o as _, len as _, flags)
+        }
+    }
+
+    /// The part of this window `len` bytes from `off`
+    ///
+    /// ## Panics
+    /// Panics if it isn't all in the window
+    pub fn subregion(&self, off: usize, len: usize) -> MmioRegion<'r> {
+        assert!(
+            off.checked_add(len).is_some_and(|end| end <= self.size),
+            "region {off:#x}+{len:#x} outside region"
+        );
+        MmioRegion {
+            base: self.base + off,
+            size: len,
+            ..*self
+        }
+    }
+}
+
+impl fmt::Debug for MmioRegion<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MmioRegion {{ base: {:#x}, size: {:#x} }}",
+            self.base, self.size
+        )
+    }
+}
+
+/// A driver's state for one device
+///
+/// Newbus serialises probe, attach and detach, but interrupt handlers and
+/// other entry points can run alongside each other, hence `Sync`.
+pub trait Driver: Sized + Send + Sync + 'static {
+    /// Bid for `dev` with one of the `priority` values, or refuse it,
+    /// typically with `Errno::NxIo`
+    fn probe(dev: Device) -> Result<i32, Errno>;
+
+    /// Set up the driver's state for `dev`
+    fn attach(dev: Device) -> Result<Self, Errno>;
+
+    /// Start the device, such as its interrupts, once the state is at its
+    /// final address. Failing detaches the driver again
+    fn start(&self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Stop the device before the state is dropped. Failing, with
+    /// `Errno::Busy` say, keeps the driver attached
+    fn detach(&self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Quiesce the device before the system sleeps. Failing keeps the
+    /// system awake
+    fn suspend(&self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Bring the device back after the system wakes
+    fn resume(&self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Leave the device idle as the system halts or reboots
+    fn shutdown(&self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// The state `T` attached to `dev`, for entry points other than the
+/// `Driver` methods
+///
+/// ## Safety
+/// `dev` must be attached to `T`'s driver, and stay attached while the
+/// reference is used.
+pub unsafe fn state<'a, T: Driver>(dev: Device) -> Option<&'a T> {
+    let p = unsafe { *dev.softc() } as *const T;
+    unsafe { p.as_ref() }
+}
+
+/// `device_probe` for `T`, for `newbus_driver!`
+///
+/// ## Safety
+/// `dev` must be a device of `T`'s driver, with a pointer-sized softc
+pub unsafe fn probe<T: Driver>(dev: kernel_sys::device_t) -> c_int {
+    let Some(dev) = (unsafe { Device::from_raw(dev) }) else {
+        return Errno::NxIo.as_raw();
+    };
+    catch_in_module(|| T::probe(dev))
+        .and_then(|r| r)
+        .unwrap_or_else(|e| e.as_raw())
+}
+
+/// `device_attach` for `T`, for `newbus_driver!`
+///
+/// ## Safety
+/// `dev` must be a device of `T`'s driver, with a pointer-sized softc
+pub unsafe fn attach<T: Driver>(dev: kernel_sys::device_t) -> c_int {
+    let Some(dev) = (unsafe { Device::from_raw(dev) }) else {
+        return Errno::NxIo.as_raw();
+    };
+    let res = catch_in_module(|| {
+        let state = Box::into_raw(Box::new(T::attach(dev)?));
+        unsafe { *dev.softc() = state as *mut libc::c_void };
+        let res = unsafe { (*state).start(dev) };
+        if res.is_err() {
+            unsafe { *dev.softc() = ptr::null_mut() };
+            drop(unsafe { Box::from_raw(state) });
+        }
+        res
+    });
+    match res.and_then(|r| r) {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
+
+/// `device_detach` for `T`, for `newbus_driver!`
+///
+/// ## Safety
+/// `dev` must be a device of `T`'s driver, with a pointer-sized softc
+pub unsafe fn detach<T: Driver>(dev: kernel_sys::device_t) -> c_int {
+    let Some(dev) = (unsafe { Device::from_raw(dev) }) else {
+        return 0;
+    };
+    let state = unsafe { *dev.softc() } as *mut T;
+    if state.is_null() {
+        return 0;
+    }
+    // A poisoned module's state is left alone rather than used
+    let res = catch_in_module(|| unsafe { (*state).detach(dev) });
+    match res.and_then(|r| r) {
+        Ok(()) => {
+            unsafe { *dev.softc() = ptr::null_mut() };
+            drop(unsafe { Box::from_raw(state) });
+            0
+        }
+        Err(e) => e.as_raw(),
+    }
+}
+
+/// Run `f` on the state attached to `dev`, for the `Driver` methods
+/// called while attached. Succeeds if there is none
+unsafe fn with_state<T: Driver>(
+    dev: kernel_sys::device_t,
+    f: impl FnOnce(&T, Device) -> Result<(), Errno>,
+) -> c_int {
+    let Some(dev) = (unsafe { Device::from_raw(dev) }) else {
+        return 0;
+    };
+    let Some(state) = (unsafe { state::<T>(dev) }) else {
+        return 0;
+    };
+    match catch_in_module(|| f(state, dev)).and_then(|r| r) {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
+
+/// `device_suspend` for `T`
+///
+/// ## Safety
+/// `dev` must be a device of `T`'s driver, with a pointer-sized softc
+pub unsafe fn suspend<T: Driver>(dev: kernel_sys::device_t) -> c_int {
+    unsafe { with_state::<T>(dev, T::suspend) }
+}
+
+/// `device_resume` for `T`
+///
+/// ## Safety
+/// `dev` must be a device of `T`'s driver, with a pointer-sized softc
+pub unsafe fn resume<T: Driver>(dev: kernel_sys::device_t) -> c_int {
+    unsafe { with_state::<T>(dev, T::resume) }
+}
+
+/// `device_shutdown` for `T`
+///
+/// ## Safety
+/// `dev` must be a device of `T`'s driver, with a pointer-sized softc
+pub unsafe fn shutdown<T: Driver>(dev: kernel_sys::device_t) -> c_int {
+    unsafe { with_state::<T>(dev, T::shutdown) }
+}
+
+macro_rules! device_methods {
+    ($($method:ident($desc:ident, $t:ident) => $f:ident),* $(,)?) => {
+        $(
+            unsafe extern "C" fn $method<T: Driver>(
+                dev: kernel_sys::device_t,
+            ) -> c_int {
+                unsafe { $f::<T>(dev) }
+            }
+        )*
+
+        unsafe impl<T: Driver> kobj::Methods for DriverMethods<T> {
+            const METHODS: &'static [kobj::Method] = &[
+                $(
+                    unsafe {
+                        kobj::Method::new(
+                            &raw mut kernel_sys::$desc,
+                            mem::transmute::<
+                                kernel_sys::$t,
+                                kernel_sys::kobjop_t,
+                            >(Some($method::<T>)),
+                        )
+                    },
+                )*
+                kobj::Method::END,
+            ];
+        }
+    };
+}
+
+/// The `device_if` methods of `T`'s driver, for a `kobj::Class` of its
+/// own, as `driver_module!` makes
+pub struct DriverMethods<T>(PhantomData<T>);
+
+device_methods! {
+    device_probe(device_probe_desc, device_probe_t) => probe,
+    device_attach(device_attach_desc, device_attach_t) => attach,
+    device_detach(device_detach_desc, device_detach_t) => detach,
+    device_suspend(device_suspend_desc, device_suspend_t) => suspend,
+    device_resume(device_resume_desc, device_resume_t) => resume,
+    device_shutdown(device_shutdown_desc, device_shutdown_t) => shutdown,
+}
+
+/// `BUS_PASS_DEFAULT`: attach in the last pass, with most drivers
+const BUS_PASS_DEFAULT: c_int = c_int::MAX;
+
+/// A `struct driver_module_data`, the argument `DRIVER_MODULE` gives
+/// `driver_module_handler` to add a driver to a bus
+#[repr(transparent)]
+pub struct DriverModuleData(UnsafeCell<kernel_sys::driver_module_data>);
+
+// Only read, by the kernel's module registration
+unsafe impl Sync for DriverModuleData {}
+
+impl DriverModuleData {
+    /// `driver` on buses named `bus`
+    pub const fn new(driver: &'static kobj::Class, bus: &'static CStr) -> Self {
+        // Zeroed for the fields some kernels have, and no chained handler
+        let mut dmd: kernel_sys::driver_module_data = unsafe { mem::zeroed() };
+        dmd.dmd_busname = bus.as_ptr();
+        dmd.dmd_driver = driver.as_ptr();
+        dmd.dmd_pass = BUS_PASS_DEFAULT;
+        DriverModuleData(UnsafeCell::new(dmd))
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::driver_module_data {
+        self.0.get()
+    }
+}
+
+impl fmt::Debug for DriverModuleData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bus = unsafe { CStr::from_ptr((*self.as_ptr()).dmd_busname) };
+        write!(f, "DriverModuleData {{ bus: {:?} }}", bus)
+    }
+}
+
+/// Declare driver `$ty` as `$name` on buses named `$bus`, as
+/// `DEFINE_CLASS_0` and `DRIVER_MODULE` do, with a pointer-sized softc
+/// for its state. The module is named `$bus/$name`
+///
+/// ```rust,ignore
+/// bsd_kernel::driver_module!(rustuart, acpi, Uart);
+/// ```
+#[macro_export]
+macro_rules! driver_module {
+    ($name:ident, $bus:ident, $ty:ty) => {
+        const _: () = {
+            use $crate::bus::{DriverMethods, DriverModuleData};
+            use $crate::kobj::Class;
+            use $crate::module::{
+                LinkerSetEntry, ModuleData, ModuleDepend, ModuleMetadata,
+                SysInit,
+            };
+
+            static DRIVER: Class = Class::new::<DriverMethods<$ty>>(
+                $crate::cstr!(stringify!($name)),
+                ::core::mem::size_of::<usize>(),
+            );
+            static DMD: DriverModuleData =
+                DriverModuleData::new(&DRIVER, $crate::cstr!(stringify!($bus)));
+            static DATA: ModuleData = ModuleData::driver(
+                $crate::cstr!(concat!(
+                    stringify!($bus),
+                    "/",
+                    stringify!($name)
+                )),
+                &DMD,
+            );
+
+            static INIT: SysInit = SysInit::module(
+                &DATA,
+                $crate::kernel_sys::sysinit_sub_id_SI_SUB_DRIVERS,
+                $crate::kernel_sys::sysinit_elem_order_SI_ORDER_MIDDLE,
+            );
+            #[used]
+            #[unsafe(link_section = "set_sysinit_set")]
+            static INIT_ENTRY: LinkerSetEntry<SysInit> =
+                LinkerSetEntry::new(&INIT);
+
+            static MD_MODULE: ModuleMetadata = ModuleMetadata::module(
+                &DATA,
+                $crate::cstr!(concat!(
+                    stringify!($name),
+                    "_",
+                    stringify!($bus)
+                )),
+            );
+            #[used]
+            #[unsafe(link_section = "set_modmetadata_set")]
+            static MD_MODULE_ENTRY: LinkerSetEntry<ModuleMetadata> =
+                LinkerSetEntry::new(&MD_MODULE);
+
+            static KERNEL_DEPEND: ModuleDepend = ModuleDepend::kernel();
+            static MD_KERNEL: ModuleMetadata =
+                ModuleMetadata::depend(&KERNEL_DEPEND, c"kernel");
+            #[used]
+            #[unsafe(link_section = "set_modmetadata_set")]
+            static MD_KERNEL_ENTRY: LinkerSetEntry<ModuleMetadata> =
+                LinkerSetEntry::new(&MD_KERNEL);
+        };
+    };
+}
+
+/// Export `device_probe`, `device_attach` and `device_detach` for driver
+/// `$ty` under the names the C method table uses
+///
+/// ```rust,ignore
+/// bsd_kernel::newbus_driver!(Mouse, rusthid_probe, rusthid_attach, rusthid_detach);
+/// ```
+#[macro_export]
+macro_rules! newbus_driver {
+    ($ty:ty, $probe:ident, $attach:ident, $detach:ident) => {
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $probe(
+            dev: $crate::kernel_sys::device_t,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::bus::probe::<$ty>(dev) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $attach(
+            dev: $crate::kernel_sys::device_t,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::bus::attach::<$ty>(dev) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $detach(
+            dev: $crate::kernel_sys::device_t,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::bus::detach::<$ty>(dev) }
+        }
+    };
+}
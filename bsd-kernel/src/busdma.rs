@@ -0,0 +1,589 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! DMA through `bus_dma(9)`
+//!
+//! A `DmaTag` describes what a device's DMA engine can reach: how far up
+//! it can address, the alignment and boundaries it needs, and how many
+//! segments of what size it takes per transfer. On platforms with an
+//! IOMMU, or devices that can't reach all of memory, the kernel remaps or
+//! bounces buffers to fit.
+//!
+//! Descriptor rings and other memory the driver and device share for as
+//! long as it's attached are `DmaBuffer`s, loaded once. Data buffers are
+//! loaded into a `DmaMap` for each transfer, which gives a `Loaded`
+//! holding the buffer, and its segments for the descriptors, until the
+//! transfer is done:
+//! ```rust,ignore
+//! let within = DmaConstraints::new().below(0xffff_ffff);
+//! let tag = DmaTag::new(dev, &within, MCLBYTES, 1)?;
+//! let mut map = tag.create_map()?;
+//!
+//! let loaded = map.load_mbuf(m).map_err(|(e, _)| e)?;
+//! loaded.sync_for_device(Direction::ToDevice);
+//! for seg in loaded.segments() {
+//!     ring.post(seg.addr, seg.len);
+//! }
+//! // Once the device is done with it
+//! loaded.sync_for_cpu(Direction::ToDevice);
+//! let m = loaded.unload();
+//! ```
+//! Loads don't wait for bounce pages: they fail with `Errno::NoMem` when
+//! there are none, rather than call back later.
+
+use crate::allocator::Wait;
+use crate::bus::Device;
+use crate::errno::Errno;
+use crate::net::Mbuf;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::{fmt, mem, ptr, slice};
+use libc::{c_int, c_void};
+
+/// What a DMA engine can reach, for `DmaTag::new`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DmaConstraints {
+    high: kernel_sys::bus_addr_t,
+    alignment: kernel_sys::bus_size_t,
+    boundary: kernel_sys::bus_addr_t,
+}
+
+impl DmaConstraints {
+    /// Any bus address, byte aligned
+    pub const fn new() -> Self {
+        DmaConstraints {
+            high: kernel_sys::bus_addr_t::MAX,
+            alignment: 1,
+            boundary: 0,
+        }
+    }
+
+    /// At or below bus address `high`, as for a device that only drives
+    /// 32 address lines
+    pub const fn below(self, high: kernel_sys::bus_addr_t) -> Self {
+        DmaConstraints { high, ..self }
+    }
+
+    /// Starting at a multiple of `alignment`, a power of two
+    pub const fn align(self, alignment: kernel_sys::bus_size_t) -> Self {
+        DmaConstraints { alignment, ..self }
+    }
+
+    /// Segments not crossing a multiple of `boundary`, a power of two,
+    /// or 0 for no such limit
+    pub const fn boundary(self, boundary: kernel_sys::bus_addr_t) -> Self {
+        DmaConstraints { boundary, ..self }
+    }
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A device's DMA constraints, `bus_dma_tag_t`, derived from its bus's
+pub struct DmaTag {
+    tag: kernel_sys::bus_dma_tag_t,
+    segments: usize,
+}
+
+unsafe impl Send for DmaTag {}
+unsafe impl Sync for DmaTag {}
+
+impl DmaTag {
+    /// A tag for `dev` within `constraints`, for transfers of up to
+    /// `max_size` bytes in up to `segments` segments
+    pub fn new(
+        dev: Device,
+        constraints: &DmaConstraints,
+        max_size: usize,
+        segments: usize,
+    ) -> Result<Self, Errno> {
+        if segments == 0 {
+            return Err(Errno::Inval);
+        }
+        let mut tag = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::bus_dma_tag_create(
+                kernel_sys::bus_get_dma_tag(dev.as_ptr()),
+                constraints.alignment,
+                constraints.boundary,
+                constraints.high,
+                kernel_sys::bus_addr_t::MAX,
+                None,
+                ptr::null_mut(),
+                max_size as kernel_sys::bus_size_t,
+                segments as c_int,
+                max_size as kernel_sys::bus_size_t,
+                0,
+                // Loads never defer, so the callbacks need no lock
+                None,
+                ptr::null_mut(),
+                &mut tag,
+            )
+        })?;
+        Ok(DmaTag { tag, segments })
+    }
+
+    /// A map to load buffers for transfers into
+    pub fn create_map(&self) -> Result<DmaMap<'_>, Errno> {
+        let mut map = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::bus_dmamap_create(self.tag, 0, &mut map)
+        })?;
+        // Zeroed, as the segment type has no constructor
+        let segs = (0..self.segments).map(|_| unsafe { mem::zeroed() });
+        Ok(DmaMap {
+            tag: self,
+            map,
+            segs: segs.collect(),
+        })
+    }
+
+    pub fn as_ptr(&self) -> kernel_sys::bus_dma_tag_t {
+        self.tag
+    }
+}
+
+impl Drop for DmaTag {
+    fn drop(&mut self) {
+        // Fails only while maps of the tag exist, which borrow it
+        unsafe { kernel_sys::bus_dma_tag_destroy(self.tag) };
+    }
+}
+
+impl fmt::Debug for DmaTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DmaTag {{ tag: {:p}, segments: {} }}",
+            self.tag, self.segments
+        )
+    }
+}
+
+/// Which way a transfer moves data, for syncing around it
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// The device reads what the CPU wrote, as for a packet sent
+    ToDevice,
+    /// The CPU reads what the device wrote, as for a packet received
+    FromDevice,
+    /// Both, as for a descriptor ring
+    Bidirectional,
+}
+
+impl Direction {
+    fn pre(self) -> kernel_sys::bus_dmasync_op_t {
+        (match self {
+            Direction::ToDevice => kernel_sys::BUS_DMASYNC_PREWRITE,
+            Direction::FromDevice => kernel_sys::BUS_DMASYNC_PREREAD,
+            Direction::Bidirectional => {
+                kernel_sys::BUS_DMASYNC_PREREAD
+                    | kernel_sys::BUS_DMASYNC_PREWRITE
+            }
+        }) as _
+    }
+
+    fn post(self) -> kernel_sys::bus_dmasync_op_t {
+        (match self {
+            Direction::ToDevice => kernel_sys::BUS_DMASYNC_POSTWRITE,
+            Direction::FromDevice => kernel_sys::BUS_DMASYNC_POSTREAD,
+            Direction::Bidirectional => {
+                kernel_sys::BUS_DMASYNC_POSTREAD
+                    | kernel_sys::BUS_DMASYNC_POSTWRITE
+            }
+        }) as _
+    }
+}
+
+/// A run of bus addresses a buffer was loaded at
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub addr: kernel_sys::bus_addr_t,
+    pub len: usize,
+}
+
+/// A `bus_dmamap_t`, which buffers are loaded into one at a time
+pub struct DmaMap<'t> {
+    tag: &'t DmaTag,
+    map: kernel_sys::bus_dmamap_t,
+    segs: Vec<kernel_sys::bus_dma_segment_t>,
+}
+
+unsafe impl Send for DmaMap<'_> {}
+unsafe impl Sync for DmaMap<'_> {}
+
+struct LoadArg<'a> {
+    segs: &'a mut [kernel_sys::bus_dma_segment_t],
+    nsegs: usize,
+    error: c_int,
+}
+
+unsafe extern "C" fn load_callback(
+    arg: *mut c_void,
+    segs: *mut kernel_sys::bus_dma_segment_t,
+    nseg: c_int,
+    error: c_int,
+) {
+    let arg = unsafe { &mut *(arg as *mut LoadArg) };
+    arg.error = error;
+    if error == 0 {
+        let nseg = nseg as usize;
+        let segs = unsafe { slice::from_raw_parts(segs, nseg) };
+        arg.segs[..nseg].copy_from_slice(segs);
+        arg.nsegs = nseg;
+    }
+}
+
+unsafe extern "C" fn load_callback2(
+    arg: *mut c_void,
+    segs: *mut kernel_sys::bus_dma_segment_t,
+    nseg: c_int,
+    _mapsize: kernel_sys::bus_size_t,
+    error: c_int,
+) {
+    unsafe { load_callback(arg, segs, nseg, error) };
+}
+
+impl<'t> DmaMap<'t> {
+    /// Load `buf`, for the device to read or write
+    pub fn load<'b>(
+        &mut self,
+        buf: &'b mut [u8],
+    ) -> Result<Loaded<'_, 't, &'b mut [u8]>, Errno> {
+        let mut arg = LoadArg {
+            segs: &mut self.segs,
+            nsegs: 0,
+            error: 0,
+        };
+        Errno::result(unsafe {
+            kernel_sys::bus_dmamap_load(
+                self.tag.tag,
+                self.map,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as kernel_sys::bus_size_t,
+                Some(load_callback),
+                &raw mut arg as *mut c_void,
+                kernel_sys::BUS_DMA_NOWAIT,
+            )
+        })?;
+        Errno::result(arg.error)?;
+        let nsegs = arg.nsegs;
+        Ok(Loaded {
+            map: self,
+            nsegs,
+            buf,
+        })
+    }
+
+    /// Load the packet `m`, or hand it back with why it couldn't be.
+    /// A chain with more segments than the tag allows fails with
+    /// `Errno::FBig`, and may be `m_collapse(9)`d to try again
+    pub fn load_mbuf(
+        &mut self,
+        m: Mbuf,
+    ) -> Result<Loaded<'_, 't, Mbuf>, (Errno, Mbuf)> {
+        let mut nsegs = 0;
+        let error = unsafe {
+            kernel_sys::bus_dmamap_load_mbuf_sg(
+                self.tag.tag,
+                self.map,
+                m.as_ptr(),
+                self.segs.as_mut_ptr(),
+                &mut nsegs,
+                kernel_sys::BUS_DMA_NOWAIT,
+            )
+        };
+        match Errno::result(error) {
+            Ok(()) => Ok(Loaded {
+                map: self,
+                nsegs: nsegs as usize,
+                buf: m,
+            }),
+            Err(e) => Err((e, m)),
+        }
+    }
+
+    /// Load the buffers `uio` describes, in the kernel or, from the
+    /// thread that owns them, userland
+    ///
+    /// ## Safety
+    /// The buffers must stay valid, and userland ones wired, until the
+    /// map is unloaded.
+    pub unsafe fn load_uio(
+        &mut self,
+        uio: *mut kernel_sys::uio,
+    ) -> Result<Loaded<'_, 't, ()>, Errno> {
+        let mut arg = LoadArg {
+            segs: &mut self.segs,
+            nsegs: 0,
+            error: 0,
+        };
+        Errno::result(unsafe {
+            kernel_sys::bus_dmamap_load_uio(
+                self.tag.tag,
+                self.map,
+                uio,
+                Some(load_callback2),
+                &raw mut arg as *mut c_void,
+                kernel_sys::BUS_DMA_NOWAIT,
+            )
+        })?;
+        Errno::result(arg.error)?;
+        let nsegs = arg.nsegs;
+        Ok(Loaded {
+            map: self,
+            nsegs,
+            buf: (),
+        })
+    }
+
+    pub fn as_ptr(&self) -> kernel_sys::bus_dmamap_t {
+        self.map
+    }
+}
+
+impl Drop for DmaMap<'_> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::bus_dmamap_destroy(self.tag.tag, self.map) };
+    }
+}
+
+impl fmt::Debug for DmaMap<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DmaMap {{ map: {:p} }}", self.map)
+    }
+}
+
+/// A buffer `B` loaded into a map, unloaded when dropped or with
+/// `unload`, which hands the buffer back
+pub struct Loaded<'m, 't, B> {
+    map: &'m mut DmaMap<'t>,
+    nsegs: usize,
+    buf: B,
+}
+
+impl<B> Loaded<'_, '_, B> {
+    /// The bus addresses the buffer was loaded at, for the device
+    pub fn segments(&self) -> impl ExactSizeIterator<Item = Segment> + '_ {
+        self.map.segs[..self.nsegs].iter().map(|s| Segment {
+            addr: s.ds_addr,
+            len: s.ds_len as usize,
+        })
+    }
+
+    /// Make the CPU's writes visible to the device, or the device's to
+    /// come visible to the CPU, before starting a transfer
+    pub fn sync_for_device(&self, dir: Direction) {
+        unsafe {
+            kernel_sys::bus_dmamap_sync(
+                self.map.tag.tag,
+                self.map.map,
+                dir.pre(),
+            )
+        };
+    }
+
+    /// Make the device's writes visible to the CPU once a transfer is
+    /// done
+    pub fn sync_for_cpu(&self, dir: Direction) {
+        unsafe {
+            kernel_sys::bus_dmamap_sync(
+                self.map.tag.tag,
+                self.map.map,
+                dir.post(),
+            )
+        };
+    }
+
+    /// Unload the map, handing back the buffer
+    pub fn unload(self) -> B {
+        let this = mem::ManuallyDrop::new(self);
+        unsafe {
+            kernel_sys::bus_dmamap_unload(this.map.tag.tag, this.map.map);
+            ptr::read(&this.buf)
+        }
+    }
+}
+
+impl<B> Drop for Loaded<'_, '_, B> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::bus_dmamap_unload(self.map.tag.tag, self.map.map)
+        };
+    }
+}
+
+impl<B> fmt::Debug for Loaded<'_, '_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.segments()).finish()
+    }
+}
+
+/// A `T` in memory from `bus_dmamem_alloc(9)`, loaded for the device for
+/// as long as it lives, as for a descriptor ring
+///
+/// What the device writes should be read through `as_ptr` with
+/// `read_volatile`, as references assume only Rust changes the memory,
+/// after `sync_for_cpu`.
+pub struct DmaBuffer<T> {
+    tag: DmaTag,
+    map: kernel_sys::bus_dmamap_t,
+    ptr: ptr::NonNull<T>,
+    addr: kernel_sys::bus_addr_t,
+    _value: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for DmaBuffer<T> {}
+unsafe impl<T: Sync> Sync for DmaBuffer<T> {}
+
+impl<T> DmaBuffer<T> {
+    /// Move `value` into memory `dev` can reach within `constraints`,
+    /// in one segment, sleeping for it or not as `wait` says. The memory
+    /// is coherent with the device where the platform can make it so
+    pub fn new(
+        dev: Device,
+        constraints: &DmaConstraints,
+        value: T,
+        wait: Wait,
+    ) -> Result<Self, Errno> {
+        let align = mem::align_of::<T>() as kernel_sys::bus_size_t;
+        let constraints = constraints.align(constraints.alignment.max(align));
+        let tag = DmaTag::new(dev, &constraints, Self::size(), 1)?;
+        let flags = match wait {
+            Wait::WaitOk => kernel_sys::BUS_DMA_WAITOK,
+            Wait::NoWait => kernel_sys::BUS_DMA_NOWAIT,
+        };
+        let mut vaddr = ptr::null_mut();
+        let mut map = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::bus_dmamem_alloc(
+                tag.tag,
+                &mut vaddr,
+                flags | kernel_sys::BUS_DMA_ZERO | kernel_sys::BUS_DMA_COHERENT,
+                &mut map,
+            )
+        })?;
+        let ptr = ptr::NonNull::new(vaddr as *mut T).ok_or(Errno::NoMem)?;
+        let mut segs = [unsafe { mem::zeroed() }];
+        let mut arg = LoadArg {
+            segs: &mut segs,
+            nsegs: 0,
+            error: 0,
+        };
+        let error = unsafe {
+            kernel_sys::bus_dmamap_load(
+                tag.tag,
+                map,
+                vaddr,
+                Self::size() as kernel_sys::bus_size_t,
+                Some(load_callback),
+                &raw mut arg as *mut c_void,
+                kernel_sys::BUS_DMA_NOWAIT,
+            )
+        };
+        if let Err(e) = Errno::result(error).and(Errno::result(arg.error)) {
+            unsafe { kernel_sys::bus_dmamem_free(tag.tag, vaddr, map) };
+            return Err(e);
+        }
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(DmaBuffer {
+            addr: segs[0].ds_addr,
+            tag,
+            map,
+            ptr,
+            _value: PhantomData,
+        })
+    }
+
+    fn size() -> usize {
+        mem::size_of::<T>().max(1)
+    }
+
+    /// The bus address the buffer starts at, for the device
+    pub fn bus_addr(&self) -> kernel_sys::bus_addr_t {
+        self.addr
+    }
+
+    /// The kernel's mapping of the buffer
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    /// As `Loaded::sync_for_device`
+    pub fn sync_for_device(&self, dir: Direction) {
+        unsafe {
+            kernel_sys::bus_dmamap_sync(self.tag.tag, self.map, dir.pre())
+        };
+    }
+
+    /// As `Loaded::sync_for_cpu`
+    pub fn sync_for_cpu(&self, dir: Direction) {
+        unsafe {
+            kernel_sys::bus_dmamap_sync(self.tag.tag, self.map, dir.post())
+        };
+    }
+}
+
+impl<T> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            kernel_sys::bus_dmamap_unload(self.tag.tag, self.map);
+            kernel_sys::bus_dmamem_free(
+                self.tag.tag,
+                self.ptr.as_ptr() as *mut c_void,
+                self.map,
+            );
+        }
+    }
+}
+
+impl<T> fmt::Debug for DmaBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DmaBuffer {{ addr: {:p}, bus_addr: {:#x}, len: {} }}",
+            self.ptr,
+            self.addr,
+            mem::size_of::<T>()
+        )
+    }
+}
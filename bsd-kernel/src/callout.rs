@@ -0,0 +1,199 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! Timers running closures, see `callout(9)`
+//!
+//! A `Callout` owns its closure, and whatever the closure captured, and
+//! drains itself when dropped: once the drop returns the closure is
+//! neither pending nor running on another CPU, so the state it uses can't
+//! be freed from under it. Callouts run from a software interrupt, so
+//! their closures may take default mutexes but must not sleep.
+//!
+//! ```rust,ignore
+//! let stats = Arc::new(Mutex::new(c"rustfifo stats", Stats::default()));
+//! let tick = Callout::with_mutex(stats.clone(), |s| s.sample());
+//! tick.reset_periodic(Duration::from_secs(1));
+//! // kept in the module state, and drained along with it on unload
+//! ```
+
+use crate::panic::catch_in_module;
+use crate::sync::Mutex;
+use crate::time::duration_to_sbt;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicI64, Ordering};
+use core::time::Duration;
+use core::{fmt, mem};
+use libc::c_void;
+
+struct Inner {
+    c: UnsafeCell<kernel_sys::callout>,
+    f: Box<dyn Fn() + Send + Sync>,
+    // Re-armed with this after each run if not 0
+    period: AtomicI64,
+}
+
+/// A one-shot or periodic timer running a closure
+pub struct Callout {
+    // Boxed as the callout wheel holds a pointer to it
+    inner: Box<Inner>,
+    // The lock the callout runs with, kept alive until it is drained
+    _lock: Option<Arc<dyn Send + Sync>>,
+}
+
+unsafe impl Send for Callout {}
+unsafe impl Sync for Callout {}
+
+impl Callout {
+    fn alloc(f: Box<dyn Fn() + Send + Sync>) -> Box<Inner> {
+        Box::new(Inner {
+            c: UnsafeCell::new(unsafe { mem::zeroed() }),
+            f,
+            period: AtomicI64::new(0),
+        })
+    }
+
+    /// A callout running `f` without a lock, which `f` takes itself if
+    /// it needs one
+    pub fn new<F: Fn() + Send + Sync + 'static>(f: F) -> Self {
+        let inner = Self::alloc(Box::new(f));
+        unsafe { kernel_sys::callout_init(inner.c.get(), 1) };
+        Callout { inner, _lock: None }
+    }
+
+    /// A callout running `f` with `mutex` held, see `callout_init_mtx(9)`.
+    /// Stopping or resetting it with the mutex held then can't race with
+    /// a run in progress
+    pub fn with_mutex<T, F>(mutex: Arc<Mutex<T>>, f: F) -> Self
+    where
+        T: Send + 'static,
+        F: Fn(&mut T) + Send + Sync + 'static,
+    {
+        let lock = mutex.clone();
+        let inner = Self::alloc(Box::new(move || {
+            // The callout wheel has taken the mutex for us
+            f(unsafe { &mut *lock.data_ptr() })
+        }));
+        unsafe {
+            kernel_sys::_callout_init_lock(
+                inner.c.get(),
+                mutex.lock_object(),
+                0,
+            );
+        }
+        Callout {
+            inner,
+            _lock: Some(mutex),
+        }
+    }
+
+    fn arm(&self, sbt: kernel_sys::sbintime_t) {
+        unsafe {
+            kernel_sys::callout_reset_sbt_on(
+                self.inner.c.get(),
+                sbt,
+                0,
+                Some(callout_fire),
+                &*self.inner as *const Inner as *mut c_void,
+                -1,
+                0,
+            );
+        }
+    }
+
+    /// Run the closure once, after `delay`, replacing any earlier request
+    pub fn reset(&self, delay: Duration) {
+        self.inner.period.store(0, Ordering::Release);
+        self.arm(duration_to_sbt(delay));
+    }
+
+    /// Run the closure every `period`, starting one period from now,
+    /// until stopped or reset
+    pub fn reset_periodic(&self, period: Duration) {
+        let sbt = duration_to_sbt(period).max(1);
+        self.inner.period.store(sbt, Ordering::Release);
+        self.arm(sbt);
+    }
+
+    /// Cancel the pending run, returning whether there was one. A run in
+    /// progress of a periodic callout without a mutex may still re-arm it
+    /// once; `drain` waits that out
+    pub fn stop(&self) -> bool {
+        self.inner.period.store(0, Ordering::Release);
+        let ret =
+            unsafe { kernel_sys::_callout_stop_safe(self.inner.c.get(), 0) };
+        ret > 0
+    }
+
+    /// Cancel the pending run and wait for one in progress to finish. The
+    /// callout's mutex, if any, must not be held
+    pub fn drain(&self) {
+        self.inner.period.store(0, Ordering::Release);
+        unsafe {
+            kernel_sys::_callout_stop_safe(
+                self.inner.c.get(),
+                kernel_sys::CS_DRAIN,
+            );
+        }
+    }
+
+    /// Whether the closure is due to run
+    pub fn is_pending(&self) -> bool {
+        let iflags = unsafe { (*self.inner.c.get()).c_iflags } as i32;
+        iflags & kernel_sys::CALLOUT_PENDING != 0
+    }
+}
+
+impl Drop for Callout {
+    fn drop(&mut self) {
+        self.drain();
+    }
+}
+
+impl fmt::Debug for Callout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Callout {{ pending: {} }}", self.is_pending())
+    }
+}
+
+unsafe extern "C" fn callout_fire(arg: *mut c_void) {
+    let inner = unsafe { &*(arg as *const Inner) };
+    if catch_in_module(|| (inner.f)()).is_err() {
+        return;
+    }
+    let period = inner.period.load(Ordering::Acquire);
+    if period != 0 {
+        // Not re-armed while being drained, which callout(9) ensures
+        unsafe {
+            kernel_sys::callout_reset_sbt_on(
+                inner.c.get(),
+                period,
+                0,
+                Some(callout_fire),
+                arg,
+                -1,
+                0,
+            );
+        }
+    }
+}
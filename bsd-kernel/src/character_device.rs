@@ -23,13 +23,33 @@
 //
 // Based on public domain code by Johannes Lundberg
 
+use crate::counter::Counter;
+use crate::cred::CurThread;
 use crate::cstr_ref;
+#[cfg(not(feature = "mock"))]
+use crate::devstat::{DevStat, Transfer};
 //use crate::debugln;
+use crate::errno::Errno;
+use crate::ioctl::{self, IoctlRequest};
+#[cfg(not(feature = "mock"))]
+use crate::kqueue::{KnList, Knote};
 use crate::module::SharedModule;
-use crate::uio::{UioReader, UioWriter};
+use crate::panic::{Poison, catch_at_boundary};
+use crate::selinfo::SelInfo;
+use crate::sync::{Condvar, Mutex};
+#[cfg(not(feature = "mock"))]
+use crate::sysctl;
+use crate::uio::{Offsets, UioReader, UioWriter};
+#[cfg(not(feature = "mock"))]
+use crate::vm::{Prot, SharedBuffer};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::ffi::CStr;
 use core::prelude::v1::*;
-use core::{fmt, mem, ptr};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::{fmt, mem, ops, ptr};
 use libc::c_int;
 
 /// ```c,ignore
@@ -67,11 +87,353 @@ use libc::c_int;
 /// };
 /// ```
 
+/// Flags of a device's `cdevsw`, which change how the kernel calls it
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceFlags(u32);
+
+impl DeviceFlags {
+    pub const NONE: Self = DeviceFlags(0);
+    /// Call `close` on every close of a descriptor for the device, rather
+    /// than only once the last one is closed
+    pub const TRACK_CLOSE: Self = DeviceFlags(kernel_sys::D_TRACKCLOSE as u32);
+    /// `mmap(2)` of the device gives anonymous memory, as `/dev/zero` does
+    pub const MMAP_ANON: Self = DeviceFlags(kernel_sys::D_MMAP_ANON as u32);
+    /// Run every operation under Giant, for code that still relies on it.
+    /// Devices are otherwise called without it, concurrently
+    pub const NEED_GIANT: Self = DeviceFlags(kernel_sys::D_NEEDGIANT as u32);
+
+    /// Both sets of flags, usable in `CharacterDevice::FLAGS`
+    pub const fn union(self, other: Self) -> Self {
+        DeviceFlags(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags as `d_flags`
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl ops::BitOr for DeviceFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
 pub trait CharacterDevice {
-    fn open(&mut self);
-    fn close(&mut self);
-    fn read(&mut self, uio: &mut UioWriter);
-    fn write(&mut self, uio: &mut UioReader);
+    /// How the `UioReader` and `UioWriter` passed to `write` and `read`
+    /// treat the transfer offset
+    const OFFSETS: Offsets = Offsets::Seekable;
+
+    /// Flags for the device's `cdevsw`. `d_version` is always the
+    /// `D_VERSION` the bindings were generated with
+    const FLAGS: DeviceFlags = DeviceFlags::NONE;
+
+    /// Called as the device is opened by `td`. An error, such as
+    /// `Errno::Busy` for a device only one process may have open, or
+    /// `Errno::Perm` from a failed `td.priv_check`, fails the `open(2)`
+    fn open(&mut self, td: &CurThread) -> Result<(), Errno>;
+
+    /// Called once the last descriptor for the device is closed, or on
+    /// every close with `DeviceFlags::TRACK_CLOSE`. An error is returned
+    /// from `close(2)`, though the descriptor is closed regardless
+    fn close(&mut self) -> Result<(), Errno>;
+
+    /// Copy data out to `uio`, for the reading thread `td`. An error is
+    /// returned to the reader
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        td: &CurThread,
+    ) -> Result<(), Errno>;
+
+    /// Take data in from `uio`, from the writing thread `td`. An error is
+    /// returned to the writer, such as `Errno::NoSpc` when the device is
+    /// out of room
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        td: &CurThread,
+    ) -> Result<(), Errno>;
+
+    /// Handle an `ioctl(2)`, typically by decoding it with
+    /// `IoctlRequest::decode` into a `#[derive(IoctlEnum)]` enum. Unknown
+    /// commands fail with `Errno::NotTy`, as the default does for all.
+    /// Commands that change the device's state typically check `td`'s
+    /// privileges first
+    fn ioctl(
+        &mut self,
+        _req: &mut IoctlRequest,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Err(Errno::NotTy)
+    }
+
+    /// Check whether `read` has data to return. A device with nothing to
+    /// read returns `Poll::Pending` after storing the waker from `cx`, and
+    /// wakes it once data arrives. The glue then puts blocking readers to
+    /// sleep (interruptibly, so signals yield `EINTR`/`ERESTART`), returns
+    /// `EAGAIN` to non-blocking readers and reports readiness to `poll(2)`.
+    ///
+    /// The default implementation is always ready, which gives the plain
+    /// non-blocking read behaviour.
+    fn poll_read_ready(&mut self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    /// Check whether `write` has room to accept data, the counterpart of
+    /// `poll_read_ready`, which `kevent(2)`'s `EVFILT_READ` and
+    /// `EVFILT_WRITE` report on too. While data is left in the request, the glue calls
+    /// `write` again each time the device is ready, so a blocking writer
+    /// only returns once everything is written.
+    fn poll_write_ready(&mut self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    /// Send reads and writes through `physio(9)` and on to `strategy`,
+    /// moving data directly between user memory and the device in place
+    /// of calling `read` and `write`
+    #[cfg(not(feature = "mock"))]
+    const RAW: bool = false;
+
+    /// Register the device with `devstat(9)`, for devices doing enough I/O
+    /// to be worth watching with `iostat(8)`. Reads, writes and ioctls are
+    /// then recorded as transactions
+    #[cfg(not(feature = "mock"))]
+    const DEVSTAT: bool = false;
+
+    /// Start a transfer for a `RAW` device. The caller sleeps until the
+    /// bio is completed with `RawBio::done`, which may happen later from
+    /// another thread; this is called with the device locked, so it must
+    /// not wait for the transfer itself
+    #[cfg(not(feature = "mock"))]
+    fn strategy(&mut self, bio: RawBio) {
+        bio.done(Err(Errno::NoDev));
+    }
+
+    /// Back an `mmap(2)` of `size` bytes of the device at `offset` with
+    /// a `SharedBuffer`, returning it and the offset into it the mapping
+    /// starts at. The default refuses with `Errno::NoDev`, as does the
+    /// kernel for devices without `DeviceFlags::MMAP_ANON`
+    #[cfg(not(feature = "mock"))]
+    fn mmap(
+        &mut self,
+        _offset: u64,
+        _size: usize,
+        _prot: Prot,
+    ) -> Result<(&SharedBuffer, u64), Errno> {
+        Err(Errno::NoDev)
+    }
+}
+
+/// A transfer passed to `CharacterDevice::strategy` by `physio(9)`
+///
+/// Its data is mapped into the kernel for the duration of the transfer
+/// and holds `length()` bytes. Dropping one leaves the caller waiting
+/// forever.
+#[cfg(not(feature = "mock"))]
+#[must_use = "a bio must be completed"]
+pub struct RawBio {
+    bp: ptr::NonNull<kernel_sys::bio>,
+}
+
+#[cfg(not(feature = "mock"))]
+unsafe impl Send for RawBio {}
+
+#[cfg(not(feature = "mock"))]
+impl RawBio {
+    fn raw(&self) -> &kernel_sys::bio {
+        unsafe { self.bp.as_ref() }
+    }
+
+    /// Whether data goes to the device, rather than coming from it
+    pub fn is_write(&self) -> bool {
+        i32::from(self.raw().bio_cmd) == kernel_sys::BIO_WRITE
+    }
+
+    /// Byte offset on the device
+    pub fn offset(&self) -> i64 {
+        self.raw().bio_offset
+    }
+
+    /// Number of bytes to transfer
+    pub fn length(&self) -> usize {
+        self.raw().bio_length as usize
+    }
+
+    /// The data, read from for writes and written to for reads
+    pub fn data(&self) -> *mut u8 {
+        self.raw().bio_data as *mut u8
+    }
+
+    /// Complete the transfer, see `biodone(9)`. `Ok` carries the number of
+    /// bytes moved, which is less than `length()` at the end of the device
+    pub fn done(self, result: Result<usize, Errno>) {
+        let bp = self.bp.as_ptr();
+        unsafe {
+            match result {
+                Ok(n) => {
+                    (*bp).bio_resid =
+                        (*bp).bio_length - n.min(self.length()) as i64;
+                }
+                Err(e) => {
+                    (*bp).bio_error = e.as_raw();
+                    (*bp).bio_flags |= kernel_sys::BIO_ERROR as u16;
+                    (*bp).bio_resid = (*bp).bio_length;
+                }
+            }
+            kernel_sys::biodone(bp);
+        }
+    }
+}
+
+#[cfg(not(feature = "mock"))]
+impl fmt::Debug for RawBio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "RawBio {{ write: {:?}, offset: {}, length: {} }}",
+            self.is_write(),
+            self.offset(),
+            self.length()
+        )
+    }
+}
+
+/// Shared between a `CDev` and the `Waker` it hands to `poll_read_ready`
+/// or `poll_write_ready`, which may outlive it
+struct Wait {
+    generation: AtomicU64,
+    gone: AtomicBool,
+    lock: Mutex<()>,
+    cv: Condvar,
+    sel: SelInfo,
+    /// `kevent(2)` registrations waiting with `EVFILT_READ` or
+    /// `EVFILT_WRITE`
+    #[cfg(not(feature = "mock"))]
+    knotes: KnList,
+}
+
+impl Wait {
+    fn new(name: &'static CStr) -> Arc<Self> {
+        Arc::new(Wait {
+            generation: AtomicU64::new(0),
+            gone: AtomicBool::new(false),
+            lock: Mutex::new(name, ()),
+            cv: Condvar::new(name),
+            sel: SelInfo::new(),
+            #[cfg(not(feature = "mock"))]
+            knotes: KnList::new(),
+        })
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Sleep until woken after `generation`
+    fn sleep(&self, generation: u64) -> Result<(), Errno> {
+        let mut guard = self.lock.lock();
+        while self.generation() == generation {
+            if self.gone.load(Ordering::Acquire) {
+                return Err(Errno::NxIo);
+            }
+            let (g, res) = self.cv.wait_sig(guard);
+            guard = g;
+            res?;
+        }
+        Ok(())
+    }
+}
+
+impl Wake for Wait {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        {
+            let _guard = self.lock.lock();
+            self.cv.notify_all();
+        }
+        self.sel.wakeup();
+        #[cfg(not(feature = "mock"))]
+        self.knotes.notify(KNOTE_WAKE);
+    }
+}
+
+/// Operation counters every `CDev` keeps, also shown under
+/// `hw.rustmod.<module>.dev.<name>` for modules with a sysctl node
+#[derive(Debug, Default)]
+pub struct DeviceStats {
+    pub opens: Counter,
+    pub reads: Counter,
+    pub writes: Counter,
+    pub ioctls: Counter,
+    /// Bytes moved to readers
+    pub bytes_read: Counter,
+    /// Bytes taken from writers
+    pub bytes_written: Counter,
+    /// Operations that failed
+    pub errors: Counter,
+    /// Operations under way, a gauge rather than a total
+    pub in_flight: Counter,
+}
+
+impl DeviceStats {
+    /// Count `f`, an operation returning an errno, while it runs and if it
+    /// fails
+    fn track(&self, op: &Counter, f: impl FnOnce() -> c_int) -> c_int {
+        op.increment();
+        self.in_flight.increment();
+        let ret = f();
+        self.in_flight.subtract(1);
+        // Non-blocking callers being told to wait is no failure
+        if ret != 0 && ret != Errno::Again.as_raw() {
+            self.errors.increment();
+        }
+        ret
+    }
+
+    /// Add the counters under `hw.rustmod.<module>.dev.<name>`, in a
+    /// context of their own so they go with the device
+    #[cfg(not(feature = "mock"))]
+    fn add_sysctls(self: &Arc<Self>, name: &str) -> Option<sysctl::Context> {
+        let module = crate::module::sysctl_name()?;
+        let name = alloc::ffi::CString::new(name).ok()?;
+        let mut ctx = sysctl::Context::new();
+        let res = (|| {
+            let root = crate::module::module_node(&mut ctx, module)?;
+            let devs = ctx.add_node(root, c"dev", c"Character devices")?;
+            let node = ctx.add_node(devs, &name, c"Device statistics")?;
+            type Get = fn(&DeviceStats) -> &Counter;
+            let oids: [(&CStr, &CStr, Get); 8] = [
+                (c"opens", c"Opens", |s| &s.opens),
+                (c"reads", c"Reads", |s| &s.reads),
+                (c"writes", c"Writes", |s| &s.writes),
+                (c"ioctls", c"Ioctls", |s| &s.ioctls),
+                (c"bytes_read", c"Bytes read", |s| &s.bytes_read),
+                (c"bytes_written", c"Bytes written", |s| &s.bytes_written),
+                (c"errors", c"Failed operations", |s| &s.errors),
+                (c"in_flight", c"Operations under way", |s| &s.in_flight),
+            ];
+            for (oid, descr, counter) in oids {
+                let stats = self.clone();
+                ctx.add_u64(node, oid, descr, move || counter(&stats).fetch())?;
+            }
+            Ok::<(), Errno>(())
+        })();
+        if let Err(e) = res {
+            crate::println!("sysctl for {:?}: {}", name, e);
+        }
+        Some(ctx)
+    }
 }
 
 pub struct CDev<T>
@@ -80,6 +442,17 @@ where
 {
     cdev: ptr::NonNull<kernel_sys::cdev>,
     delegate: SharedModule<T>,
+    poison: Poison,
+    stats: Arc<DeviceStats>,
+    /// Removes the stats' oids before they go
+    #[cfg(not(feature = "mock"))]
+    _oids: Option<sysctl::Context>,
+    #[cfg(not(feature = "mock"))]
+    devstat: Option<DevStat>,
+    read_wait: Arc<Wait>,
+    read_waker: Waker,
+    write_wait: Arc<Wait>,
+    write_waker: Waker,
 }
 
 impl<T> CDev<T>
@@ -96,8 +469,23 @@ where
             c.d_close = Some(cdev_close::<T>);
             c.d_read = Some(cdev_read::<T>);
             c.d_write = Some(cdev_write::<T>);
+            c.d_ioctl = Some(cdev_ioctl::<T>);
+            c.d_poll = Some(cdev_poll::<T>);
+            #[cfg(not(feature = "mock"))]
+            {
+                c.d_kqfilter = Some(cdev_kqfilter::<T>);
+            }
+            #[cfg(not(feature = "mock"))]
+            if T::RAW {
+                c.d_strategy = Some(cdev_strategy::<T>);
+            }
+            #[cfg(not(feature = "mock"))]
+            {
+                c.d_mmap_single = Some(cdev_mmap_single::<T>);
+            }
             c.d_version = kernel_sys::D_VERSION as i32;
-            c.d_name = cstr_ref!(name).as_ptr() as *mut i8;
+            c.d_flags = T::FLAGS.bits();
+            c.d_name = cstr_ref!(name).as_ptr() as *mut libc::c_char;
             Box::into_raw(Box::new(c))
         };
 
@@ -120,7 +508,7 @@ where
             kernel_sys::make_dev_s(
                 &raw mut args,
                 cdev_raw.as_mut_ptr(),
-                cstr_ref!(name).as_ptr() as *mut i8,
+                cstr_ref!(name).as_ptr() as *mut libc::c_char,
             )
         };
         if res != 0 {
@@ -129,13 +517,112 @@ where
             return None;
         }
         let cdev_raw = unsafe { cdev_raw.assume_init() };
+        #[cfg(not(feature = "mock"))]
+        if T::RAW {
+            // physio() splits transfers into pieces no larger than this
+            unsafe { (*cdev_raw).si_iosize_max = kernel_sys::maxphys as _ };
+        }
+        let read_wait = Wait::new(c"cdevread");
+        let write_wait = Wait::new(c"cdevwrite");
+        let stats = Arc::new(DeviceStats::default());
         let cdev = Box::new(CDev {
             cdev: ptr::NonNull::new(cdev_raw).unwrap(),
             delegate,
+            poison: Poison::new(),
+            #[cfg(not(feature = "mock"))]
+            _oids: stats.add_sysctls(name),
+            #[cfg(not(feature = "mock"))]
+            devstat: T::DEVSTAT.then(|| {
+                let name = cstr_ref!(name);
+                DevStat::new(
+                    CStr::from_bytes_with_nul(name.as_bytes()).unwrap(),
+                    0,
+                )
+            }),
+            stats,
+            read_waker: Waker::from(read_wait.clone()),
+            read_wait,
+            write_waker: Waker::from(write_wait.clone()),
+            write_wait,
         });
         unsafe { (*cdev_raw).si_drv1 = &raw const *cdev as *mut libc::c_void };
         Some(cdev)
     }
+
+    /// Whether the device has panicked, after which every operation on it
+    /// fails with `Errno::NxIo`
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    /// What has been done with the device so far
+    pub fn stats(&self) -> &DeviceStats {
+        &self.stats
+    }
+
+    /// Raw pointer to the underlying cdev
+    pub fn as_ptr(&self) -> *mut kernel_sys::cdev {
+        self.cdev.as_ptr()
+    }
+}
+
+/// What `set_cdevpriv` attaches to a descriptor. `repr(C)` so the type
+/// is found at the start whatever `P` is
+#[cfg(not(feature = "mock"))]
+#[repr(C)]
+struct CdevPriv<P> {
+    type_id: core::any::TypeId,
+    state: P,
+}
+
+/// Give the file descriptor being opened state of its own, dropped once
+/// the descriptor's last reference is closed; see `devfs_cdevpriv(9)`.
+/// Call from `CharacterDevice::open`, where every open of the device
+/// otherwise shares the device's state. Fails with `Errno::Busy` if
+/// the descriptor already has some
+#[cfg(not(feature = "mock"))]
+pub fn set_cdevpriv<P>(state: P) -> Result<(), Errno>
+where
+    P: Send + Sync + 'static,
+{
+    let data = Box::into_raw(Box::new(CdevPriv {
+        type_id: core::any::TypeId::of::<P>(),
+        state,
+    }));
+    let res = Errno::result(unsafe {
+        kernel_sys::devfs_set_cdevpriv(
+            data as *mut libc::c_void,
+            Some(cdevpriv_dtr::<P>),
+        )
+    });
+    if res.is_err() {
+        drop(unsafe { Box::from_raw(data) });
+    }
+    res
+}
+
+/// Run `f` on the state `set_cdevpriv` gave the descriptor the current
+/// operation came in on. Fails with `Errno::NoEnt` if it has none, and
+/// `Errno::Inval` if it is not a `P`. Descriptors can be shared, so `f`
+/// may run on several threads at once
+#[cfg(not(feature = "mock"))]
+pub fn with_cdevpriv<P, R>(f: impl FnOnce(&P) -> R) -> Result<R, Errno>
+where
+    P: Send + Sync + 'static,
+{
+    let mut data = ptr::null_mut();
+    Errno::result(unsafe { kernel_sys::devfs_get_cdevpriv(&mut data) })?;
+    // The descriptor is held for the operation, so its state stays
+    let type_id = unsafe { (*(data as *const CdevPriv<()>)).type_id };
+    if type_id != core::any::TypeId::of::<P>() {
+        return Err(Errno::Inval);
+    }
+    Ok(f(unsafe { &(*(data as *const CdevPriv<P>)).state }))
+}
+
+#[cfg(not(feature = "mock"))]
+unsafe extern "C" fn cdevpriv_dtr<P>(data: *mut libc::c_void) {
+    drop(unsafe { Box::from_raw(data as *mut CdevPriv<P>) });
 }
 
 impl<T> fmt::Debug for CDev<T>
@@ -161,8 +648,21 @@ where
         let cdevsw: Box<kernel_sys::cdevsw> =
             unsafe { Box::from_raw((*dev).si_devsw) };
 
+        // destroy_dev waits for threads to leave the driver, so kick out
+        // any readers sleeping for data and writers sleeping for room
+        self.read_wait.gone.store(true, Ordering::Release);
+        self.read_waker.wake_by_ref();
+        self.write_wait.gone.store(true, Ordering::Release);
+        self.write_waker.wake_by_ref();
+
         // debugln!("[kernel.rs] CDev::drop calling destroy_dev. ptr={:?}", dev.as_ptr());
         unsafe { kernel_sys::destroy_dev(dev) };
+        // The knotes point at the device, but the waits may outlive it
+        #[cfg(not(feature = "mock"))]
+        {
+            self.read_wait.knotes.clear();
+            self.write_wait.knotes.clear();
+        }
 
         drop(cdevsw);
     }
@@ -173,17 +673,21 @@ extern "C" fn cdev_open<T>(
     dev: *mut kernel_sys::cdev,
     _oflags: c_int,
     _devtype: c_int,
-    _td: *mut kernel_sys::thread,
+    td: *mut kernel_sys::thread,
 ) -> c_int
 where
     T: CharacterDevice,
 {
     // debugln!("cdev_open");
     let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
-    if let Some(mut m) = cdev.delegate.lock() {
-        m.open();
-    }
-    0
+    let td = unsafe { CurThread::from_raw(td) };
+    cdev.stats.track(&cdev.stats.opens, || {
+        catch_at_boundary(&cdev.poison, || match cdev.delegate.lock() {
+            Some(mut m) => m.open(&td).map_or_else(Errno::as_raw, |()| 0),
+            None => 0,
+        })
+        .unwrap_or_else(Errno::as_raw)
+    })
 }
 
 #[allow(unused)]
@@ -208,40 +712,372 @@ where
 {
     // debugln!("cdev_close");
     let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
-    if let Some(mut m) = cdev.delegate.lock() {
-        m.close();
-    }
-    0
+    catch_at_boundary(&cdev.poison, || match cdev.delegate.lock() {
+        Some(mut m) => m.close().map_or_else(Errno::as_raw, |()| 0),
+        None => 0,
+    })
+    .unwrap_or_else(Errno::as_raw)
 }
 
 extern "C" fn cdev_read<T>(
     dev: *mut kernel_sys::cdev,
     uio: *mut kernel_sys::uio,
-    _ioflag: c_int,
+    ioflag: c_int,
 ) -> c_int
 where
     T: CharacterDevice,
 {
     // debugln!("cdev_read");
     let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
-    if let Some(mut m) = cdev.delegate.lock() {
-        m.read(&mut UioWriter::new(uio));
+    let resid = unsafe { (*uio).uio_resid };
+    // devfs reads and writes on the caller's thread
+    let td = unsafe { CurThread::from_raw((*uio).uio_td) };
+    #[cfg(not(feature = "mock"))]
+    let tx = cdev.devstat.as_ref().map(DevStat::start);
+    let ret = cdev.stats.track(&cdev.stats.reads, || {
+        #[cfg(not(feature = "mock"))]
+        if T::RAW {
+            return unsafe { kernel_sys::physio(dev, uio, ioflag) };
+        }
+        catch_at_boundary(&cdev.poison, || {
+            let mut cx = Context::from_waker(&cdev.read_waker);
+            loop {
+                let generation = cdev.read_wait.generation();
+                match cdev.delegate.lock() {
+                    Some(mut m) => {
+                        if m.poll_read_ready(&mut cx).is_ready() {
+                            let mut writer =
+                                UioWriter::with_offsets(uio, T::OFFSETS);
+                            return match m.read(&mut writer, &td) {
+                                Ok(()) => 0,
+                                Err(e) => e.as_raw(),
+                            };
+                        }
+                    }
+                    None => return 0,
+                }
+                if ioflag & kernel_sys::O_NONBLOCK != 0 {
+                    return Errno::Again.as_raw();
+                }
+                if let Err(e) = cdev.read_wait.sleep(generation) {
+                    return e.as_raw();
+                }
+            }
+        })
+        .unwrap_or_else(Errno::as_raw)
+    });
+    let moved = resid - unsafe { (*uio).uio_resid };
+    cdev.stats.bytes_read.add(moved.max(0) as u64);
+    #[cfg(not(feature = "mock"))]
+    if let Some(tx) = tx {
+        tx.end(moved.max(0) as usize, Transfer::Read);
     }
-    0
+    ret
+}
+
+extern "C" fn cdev_ioctl<T>(
+    dev: *mut kernel_sys::cdev,
+    cmd: libc::c_ulong,
+    data: kernel_sys::caddr_t,
+    _fflag: c_int,
+    td: *mut kernel_sys::thread,
+) -> c_int
+where
+    T: CharacterDevice,
+{
+    // debugln!("cdev_ioctl");
+    let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
+    // The kernel has copied the argument in and copies it out after
+    let len = ioctl::param_len(cmd);
+    let data: &mut [u8] = if len == 0 || data.is_null() {
+        &mut []
+    } else {
+        unsafe { core::slice::from_raw_parts_mut(data as *mut u8, len) }
+    };
+    let mut req = if ioctl::is_compat32(td) {
+        IoctlRequest::new_compat32(cmd, data)
+    } else {
+        IoctlRequest::new(cmd, data)
+    };
+    let td = unsafe { CurThread::from_raw(td) };
+    #[cfg(not(feature = "mock"))]
+    let _tx = cdev.devstat.as_ref().map(DevStat::start);
+    cdev.stats.track(&cdev.stats.ioctls, || {
+        catch_at_boundary(&cdev.poison, || match cdev.delegate.lock() {
+            Some(mut m) => match m.ioctl(&mut req, &td) {
+                Ok(()) => 0,
+                Err(e) => e.as_raw(),
+            },
+            None => Errno::NxIo.as_raw(),
+        })
+        .unwrap_or_else(Errno::as_raw)
+    })
+}
+
+extern "C" fn cdev_poll<T>(
+    dev: *mut kernel_sys::cdev,
+    events: c_int,
+    td: *mut kernel_sys::thread,
+) -> c_int
+where
+    T: CharacterDevice,
+{
+    // debugln!("cdev_poll");
+    let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
+    let readable = kernel_sys::POLLIN | kernel_sys::POLLRDNORM;
+    let writable = kernel_sys::POLLOUT | kernel_sys::POLLWRNORM;
+    // A poisoned device reports ready, so read and write see the error
+    catch_at_boundary(&cdev.poison, || {
+        let mut revents = 0;
+        if events & readable != 0 {
+            let generation = cdev.read_wait.generation();
+            let mut cx = Context::from_waker(&cdev.read_waker);
+            let ready = match cdev.delegate.lock() {
+                Some(mut m) => m.poll_read_ready(&mut cx).is_ready(),
+                None => true,
+            };
+            if ready {
+                revents |= events & readable;
+            } else {
+                unsafe { cdev.read_wait.sel.record(td) };
+                // A wakeup between the check and selrecord would be missed
+                if cdev.read_wait.generation() != generation {
+                    revents |= events & readable;
+                }
+            }
+        }
+        if events & writable != 0 {
+            let generation = cdev.write_wait.generation();
+            let mut cx = Context::from_waker(&cdev.write_waker);
+            let ready = match cdev.delegate.lock() {
+                Some(mut m) => m.poll_write_ready(&mut cx).is_ready(),
+                None => true,
+            };
+            if ready {
+                revents |= events & writable;
+            } else {
+                unsafe { cdev.write_wait.sel.record(td) };
+                if cdev.write_wait.generation() != generation {
+                    revents |= events & writable;
+                }
+            }
+        }
+        revents
+    })
+    .unwrap_or(events & (readable | writable))
 }
 
 extern "C" fn cdev_write<T>(
     dev: *mut kernel_sys::cdev,
     uio: *mut kernel_sys::uio,
-    _ioflag: c_int,
+    ioflag: c_int,
 ) -> c_int
 where
     T: CharacterDevice,
 {
     // debugln!("cdev_write");
     let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
-    if let Some(mut m) = cdev.delegate.lock() {
-        m.write(&mut UioReader::new(uio));
+    let resid = unsafe { (*uio).uio_resid };
+    let td = unsafe { CurThread::from_raw((*uio).uio_td) };
+    #[cfg(not(feature = "mock"))]
+    let tx = cdev.devstat.as_ref().map(DevStat::start);
+    let ret = cdev.stats.track(&cdev.stats.writes, || {
+        #[cfg(not(feature = "mock"))]
+        if T::RAW {
+            return unsafe { kernel_sys::physio(dev, uio, ioflag) };
+        }
+        catch_at_boundary(&cdev.poison, || {
+            let mut cx = Context::from_waker(&cdev.write_waker);
+            let mut uio = UioReader::with_offsets(uio, T::OFFSETS);
+            let mut wrote = false;
+            loop {
+                let generation = cdev.write_wait.generation();
+                match cdev.delegate.lock() {
+                    Some(mut m) => {
+                        if m.poll_write_ready(&mut cx).is_ready() {
+                            let resid = uio.residual();
+                            if let Err(e) = m.write(&mut uio, &td) {
+                                return e.as_raw();
+                            }
+                            // Done, or the device took nothing despite being ready
+                            if uio.residual() == 0 || uio.residual() == resid {
+                                return 0;
+                            }
+                            wrote = true;
+                            continue;
+                        }
+                    }
+                    None => return 0,
+                }
+                // A partial write is reported as such rather than as an error
+                if ioflag & kernel_sys::O_NONBLOCK != 0 {
+                    return if wrote { 0 } else { Errno::Again.as_raw() };
+                }
+                if let Err(e) = cdev.write_wait.sleep(generation) {
+                    return if wrote { 0 } else { e.as_raw() };
+                }
+            }
+        })
+        .unwrap_or_else(Errno::as_raw)
+    });
+    let moved = resid - unsafe { (*uio).uio_resid };
+    cdev.stats.bytes_written.add(moved.max(0) as u64);
+    #[cfg(not(feature = "mock"))]
+    if let Some(tx) = tx {
+        tx.end(moved.max(0) as usize, Transfer::Write);
     }
+    ret
+}
+
+#[cfg(not(feature = "mock"))]
+extern "C" fn cdev_strategy<T>(bp: *mut kernel_sys::bio)
+where
+    T: CharacterDevice,
+{
+    // debugln!("cdev_strategy");
+    let dev = unsafe { (*bp).bio_dev };
+    let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
+    let bp = ptr::NonNull::new(bp).unwrap();
+    let result = catch_at_boundary(&cdev.poison, || {
+        let bio = RawBio { bp };
+        match cdev.delegate.lock() {
+            Some(mut m) => m.strategy(bio),
+            None => bio.done(Err(Errno::NxIo)),
+        }
+    });
+    // A bio whose handler panicked may already have been completed, so
+    // only one refused outright is still ours to finish
+    if result == Err(Errno::NxIo) {
+        RawBio { bp }.done(Err(Errno::NxIo));
+    }
+}
+
+#[cfg(not(feature = "mock"))]
+extern "C" fn cdev_mmap_single<T>(
+    dev: *mut kernel_sys::cdev,
+    offset: *mut kernel_sys::vm_ooffset_t,
+    size: kernel_sys::vm_size_t,
+    object: *mut *mut kernel_sys::vm_object,
+    nprot: c_int,
+) -> c_int
+where
+    T: CharacterDevice,
+{
+    let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
+    let Ok(off) = u64::try_from(unsafe { *offset }) else {
+        return Errno::Inval.as_raw();
+    };
+    let prot = Prot::from_bits(nprot as kernel_sys::vm_prot_t);
+    catch_at_boundary(&cdev.poison, || match cdev.delegate.lock() {
+        Some(mut m) => {
+            let (buf, start) = m.mmap(off, size, prot)?;
+            let obj = buf.map(start, size)?;
+            unsafe {
+                *offset = start as kernel_sys::vm_ooffset_t;
+                *object = obj;
+            }
+            Ok(())
+        }
+        None => Err(Errno::NxIo),
+    })
+    .and_then(|r| r)
+    .map_or_else(Errno::as_raw, |()| 0)
+}
+
+/// The `KnList::notify` hint from a `Wait`'s waker, as opposed to 0 when
+/// userland collects events
+#[cfg(not(feature = "mock"))]
+const KNOTE_WAKE: i64 = 1;
+
+#[cfg(not(feature = "mock"))]
+struct KqFilter<T>(core::marker::PhantomData<T>);
+
+#[cfg(not(feature = "mock"))]
+impl<T: CharacterDevice> KqFilter<T> {
+    const OPS: kernel_sys::filterops = {
+        let mut ops: kernel_sys::filterops = unsafe { mem::zeroed() };
+        ops.f_isfd = 1;
+        ops.f_detach = Some(cdev_kqdetach::<T>);
+        ops.f_event = Some(cdev_kqevent::<T>);
+        ops
+    };
+
+    /// The wait a knote of `filter` is on
+    fn wait(cdev: &CDev<T>, filter: i16) -> Option<&Wait> {
+        match i32::from(filter) {
+            kernel_sys::EVFILT_READ => Some(&cdev.read_wait),
+            kernel_sys::EVFILT_WRITE => Some(&cdev.write_wait),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(feature = "mock"))]
+extern "C" fn cdev_kqfilter<T>(
+    dev: *mut kernel_sys::cdev,
+    kn: *mut kernel_sys::knote,
+) -> c_int
+where
+    T: CharacterDevice,
+{
+    let cdev: &CDev<T> = unsafe { &*((*dev).si_drv1 as *const CDev<T>) };
+    let kn = unsafe { Knote::from_raw(kn) };
+    let Some(wait) = KqFilter::wait(cdev, kn.filter()) else {
+        return Errno::Inval.as_raw();
+    };
+    unsafe {
+        (*kn.as_ptr()).kn_fop =
+            &KqFilter::<T>::OPS as *const _ as *mut kernel_sys::filterops
+    };
+    kn.set_hook(cdev as *const CDev<T> as *mut libc::c_void);
+    wait.knotes.add(&kn);
     0
 }
+
+#[cfg(not(feature = "mock"))]
+unsafe extern "C" fn cdev_kqdetach<T>(kn: *mut kernel_sys::knote)
+where
+    T: CharacterDevice,
+{
+    let kn = unsafe { Knote::from_raw(kn) };
+    let cdev: &CDev<T> = unsafe { &*(kn.hook() as *const CDev<T>) };
+    if let Some(wait) = KqFilter::wait(cdev, kn.filter()) {
+        wait.knotes.remove(&kn);
+    }
+}
+
+/// Runs with the knote list locked. A wakeup may come from the device
+/// with its own lock held, so only fires the knote; the readiness check
+/// when events are collected then only tries the lock, and reports ready
+/// if it is busy, as a read or write then sees for itself
+#[cfg(not(feature = "mock"))]
+unsafe extern "C" fn cdev_kqevent<T>(
+    kn: *mut kernel_sys::knote,
+    hint: libc::c_long,
+) -> c_int
+where
+    T: CharacterDevice,
+{
+    if hint == KNOTE_WAKE {
+        return 1;
+    }
+    let kn = unsafe { Knote::from_raw(kn) };
+    let cdev: &CDev<T> = unsafe { &*(kn.hook() as *const CDev<T>) };
+    let write = i32::from(kn.filter()) == kernel_sys::EVFILT_WRITE;
+    catch_at_boundary(&cdev.poison, || {
+        let Some(mut m) = cdev.delegate.try_lock() else {
+            return true;
+        };
+        match write {
+            false => {
+                let mut cx = Context::from_waker(&cdev.read_waker);
+                m.poll_read_ready(&mut cx).is_ready()
+            }
+            true => {
+                let mut cx = Context::from_waker(&cdev.write_waker);
+                m.poll_write_ready(&mut cx).is_ready()
+            }
+        }
+    })
+    .unwrap_or(true) as c_int
+}
@@ -0,0 +1,243 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Checksums for metadata and wire formats
+//!
+//! CRC32C uses the kernel's `calculate_crc32c()`, which switches to the
+//! SSE4.2 `crc32` instruction on CPUs that have it. The Fletcher sums
+//! are those of ZFS, whose own copies live in `zfs.ko`, so they are
+//! computed here.
+
+use libc::c_uint;
+
+/// CRC32C (Castagnoli), as used by iSCSI, SCTP and many on-disk formats
+#[derive(Copy, Clone, Debug)]
+pub struct Crc32c {
+    crc: u32,
+}
+
+impl Crc32c {
+    pub const fn new() -> Self {
+        Crc32c { crc: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        // The kernel takes an unsigned int length
+        for chunk in data.chunks(c_uint::MAX as usize) {
+            self.crc = unsafe {
+                kernel_sys::calculate_crc32c(
+                    self.crc,
+                    chunk.as_ptr(),
+                    chunk.len() as c_uint,
+                )
+            };
+        }
+    }
+
+    /// The checksum of everything passed to `update`
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Crc32c::new()
+    }
+}
+
+/// CRC32C of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// The bytes of a word split across `update` calls, carried to the next
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Partial<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Partial<N> {
+    const fn new() -> Self {
+        Partial {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Complete the carried word from the front of `data`, returning the
+    /// word once whole and what is left of `data`
+    fn fill<'a>(&mut self, data: &'a [u8]) -> (Option<[u8; N]>, &'a [u8]) {
+        if self.len == 0 {
+            return (None, data);
+        }
+        let n = (N - self.len).min(data.len());
+        self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        if self.len < N {
+            return (None, &data[n..]);
+        }
+        self.len = 0;
+        (Some(self.buf), &data[n..])
+    }
+
+    /// Carry the tail of `data` past its last whole word
+    fn keep(&mut self, tail: &[u8]) {
+        self.buf[..tail.len()].copy_from_slice(tail);
+        self.len = tail.len();
+    }
+
+    /// The carried word, zero padded, if any
+    fn padded(&self) -> Option<[u8; N]> {
+        let mut word = [0; N];
+        word[..self.len].copy_from_slice(&self.buf[..self.len]);
+        (self.len != 0).then_some(word)
+    }
+}
+
+impl<const N: usize> Default for Partial<N> {
+    fn default() -> Self {
+        Partial::new()
+    }
+}
+
+/// ZFS's Fletcher-2: two pairs of running sums over native-endian 64-bit
+/// words, taken alternately
+///
+/// Data may be passed to `update` in pieces of any length; the sum is that
+/// of the pieces joined. A length that isn't a multiple of 16 bytes is
+/// summed as if padded with zeros, though ZFS only checksums whole blocks.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Fletcher2 {
+    a: [u64; 2],
+    b: [u64; 2],
+    partial: Partial<16>,
+}
+
+impl Fletcher2 {
+    pub const fn new() -> Self {
+        Fletcher2 {
+            a: [0; 2],
+            b: [0; 2],
+            partial: Partial::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let (pair, data) = self.partial.fill(data);
+        if let Some(pair) = pair {
+            self.add(&pair);
+        }
+        let pairs = data.chunks_exact(16);
+        let tail = pairs.remainder();
+        for pair in pairs {
+            self.add(pair);
+        }
+        self.partial.keep(tail);
+    }
+
+    fn add(&mut self, pair: &[u8]) {
+        for i in 0..2 {
+            let word = &pair[i * 8..i * 8 + 8];
+            let word = u64::from_ne_bytes(word.try_into().unwrap());
+            self.a[i] = self.a[i].wrapping_add(word);
+            self.b[i] = self.b[i].wrapping_add(self.a[i]);
+        }
+    }
+
+    /// The four words of the checksum, in ZFS's `zio_cksum_t` order
+    pub fn finish(&self) -> [u64; 4] {
+        let mut sum = *self;
+        if let Some(pair) = self.partial.padded() {
+            sum.add(&pair);
+        }
+        [sum.a[0], sum.a[1], sum.b[0], sum.b[1]]
+    }
+}
+
+/// ZFS's Fletcher-4: four cascaded running sums over native-endian
+/// 32-bit words
+///
+/// As with `Fletcher2`, `update` takes pieces of any length, and a length
+/// that isn't a multiple of 4 bytes is summed as if padded with zeros.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Fletcher4 {
+    sums: [u64; 4],
+    partial: Partial<4>,
+}
+
+impl Fletcher4 {
+    pub const fn new() -> Self {
+        Fletcher4 {
+            sums: [0; 4],
+            partial: Partial::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let (word, data) = self.partial.fill(data);
+        if let Some(word) = word {
+            self.add(&word);
+        }
+        let words = data.chunks_exact(4);
+        let tail = words.remainder();
+        for word in words {
+            self.add(word.try_into().unwrap());
+        }
+        self.partial.keep(tail);
+    }
+
+    fn add(&mut self, word: &[u8; 4]) {
+        let [a, b, c, d] = &mut self.sums;
+        *a = a.wrapping_add(u64::from(u32::from_ne_bytes(*word)));
+        *b = b.wrapping_add(*a);
+        *c = c.wrapping_add(*b);
+        *d = d.wrapping_add(*c);
+    }
+
+    /// The four words of the checksum, in ZFS's `zio_cksum_t` order
+    pub fn finish(&self) -> [u64; 4] {
+        let mut sum = *self;
+        if let Some(word) = self.partial.padded() {
+            sum.add(&word);
+        }
+        sum.sums
+    }
+}
+
+/// Fletcher-2 of `data`
+pub fn fletcher2(data: &[u8]) -> [u64; 4] {
+    let mut sum = Fletcher2::new();
+    sum.update(data);
+    sum.finish()
+}
+
+/// Fletcher-4 of `data`
+pub fn fletcher4(data: &[u8]) -> [u64; 4] {
+    let mut sum = Fletcher4::new();
+    sum.update(data);
+    sum.finish()
+}
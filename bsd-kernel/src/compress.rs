@@ -0,0 +1,476 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Streaming compression with the kernel's zlib and zstd
+//!
+//! zlib lives in the `zlib` module, which users must depend on in their C
+//! shim: `MODULE_DEPEND(<name>, zlib, 1, 1, 1);`. zstd is built into
+//! kernels with `options ZSTDIO` and is behind this crate's `zstd`
+//! feature; it allocates with `M_WAITOK`, so its streams may only be used
+//! where sleeping is allowed.
+//!
+//! Each call consumes what input it can and fills what output it can,
+//! returning how far it got, so data can be pushed through fixed size
+//! buffers. `compress_all` and `decompress_all` handle the common case of
+//! a whole stream in one buffer.
+
+use crate::errno::Errno;
+use alloc::boxed::Box;
+use core::{fmt, mem, ptr};
+use libc::{c_int, c_uint, c_void};
+
+/// A compression format
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    /// Deflate in the zlib wrapper, as `compress(3)` and `mkuzip(8)` write
+    Zlib,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// How far a call got
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Progress {
+    /// Input bytes used up
+    pub consumed: usize,
+    /// Output bytes written
+    pub produced: usize,
+    /// The end of the stream was reached: written out in full when
+    /// compressing, or read when decompressing
+    pub finished: bool,
+}
+
+// zlib streams may be used where sleeping isn't allowed
+unsafe extern "C" fn zalloc(
+    _opaque: *mut c_void,
+    items: c_uint,
+    size: c_uint,
+) -> *mut c_void {
+    let Some(len) = (items as usize).checked_mul(size as usize) else {
+        return ptr::null_mut();
+    };
+    unsafe {
+        kernel_sys::malloc(
+            len,
+            &raw mut kernel_sys::M_DEVBUF[0],
+            kernel_sys::M_NOWAIT,
+        )
+    }
+}
+
+unsafe extern "C" fn zfree(_opaque: *mut c_void, address: *mut c_void) {
+    unsafe { kernel_sys::free(address, &raw mut kernel_sys::M_DEVBUF[0]) };
+}
+
+// zlib's internal state points back at the stream, so it's boxed
+fn zlib_stream() -> Box<kernel_sys::z_stream> {
+    let mut zs: Box<kernel_sys::z_stream> = Box::new(unsafe { mem::zeroed() });
+    zs.zalloc = Some(zalloc);
+    zs.zfree = Some(zfree);
+    zs
+}
+
+fn zlib_result(ret: c_int) -> Result<c_int, Errno> {
+    match ret {
+        kernel_sys::Z_MEM_ERROR => Err(Errno::NoMem),
+        kernel_sys::Z_DATA_ERROR | kernel_sys::Z_NEED_DICT => Err(Errno::IlSeq),
+        // No progress possible, which the caller sees in the counts
+        kernel_sys::Z_BUF_ERROR => Ok(kernel_sys::Z_OK),
+        r if r < 0 => Err(Errno::Inval),
+        r => Ok(r),
+    }
+}
+
+/// Point `zs` at the buffers for one call of `f`
+fn zlib_step(
+    zs: &mut kernel_sys::z_stream,
+    input: &[u8],
+    output: &mut [u8],
+    f: impl FnOnce(&mut kernel_sys::z_stream) -> c_int,
+) -> Result<Progress, Errno> {
+    // Longer buffers are taken a piece at a time
+    let avail_in = c_uint::try_from(input.len()).unwrap_or(c_uint::MAX);
+    let avail_out = c_uint::try_from(output.len()).unwrap_or(c_uint::MAX);
+    zs.next_in = input.as_ptr().cast_mut();
+    zs.avail_in = avail_in;
+    zs.next_out = output.as_mut_ptr();
+    zs.avail_out = avail_out;
+    let ret = zlib_result(f(zs));
+    let progress = Progress {
+        consumed: (avail_in - zs.avail_in) as usize,
+        produced: (avail_out - zs.avail_out) as usize,
+        finished: ret == Ok(kernel_sys::Z_STREAM_END),
+    };
+    // Don't leave the stream pointing into borrowed buffers
+    zs.next_in = ptr::null_mut();
+    zs.next_out = ptr::null_mut();
+    ret.map(|_| progress)
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_result(ret: usize, error: Errno) -> Result<usize, Errno> {
+    if unsafe { kernel_sys::ZSTD_isError(ret) } == 0 {
+        return Ok(ret);
+    }
+    match unsafe { kernel_sys::ZSTD_getErrorCode(ret) } {
+        kernel_sys::ZSTD_ErrorCode_ZSTD_error_memory_allocation => {
+            Err(Errno::NoMem)
+        }
+        _ => Err(error),
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_buffers(
+    input: &[u8],
+    output: &mut [u8],
+) -> (kernel_sys::ZSTD_inBuffer, kernel_sys::ZSTD_outBuffer) {
+    let inb = kernel_sys::ZSTD_inBuffer {
+        src: input.as_ptr().cast(),
+        size: input.len(),
+        pos: 0,
+    };
+    let outb = kernel_sys::ZSTD_outBuffer {
+        dst: output.as_mut_ptr().cast(),
+        size: output.len(),
+        pos: 0,
+    };
+    (inb, outb)
+}
+
+/// Drive `step` until the stream finishes, failing with `Errno::NoSpc` if
+/// `output` fills up first and `Errno::IlSeq` if `input` runs out
+fn run_all(
+    input: &[u8],
+    output: &mut [u8],
+    mut step: impl FnMut(&[u8], &mut [u8]) -> Result<Progress, Errno>,
+) -> Result<usize, Errno> {
+    let (mut read, mut written) = (0, 0);
+    loop {
+        let p = step(&input[read..], &mut output[written..])?;
+        read += p.consumed;
+        written += p.produced;
+        if p.finished {
+            return Ok(written);
+        }
+        if p.consumed == 0 && p.produced == 0 {
+            return Err(if written == output.len() {
+                Errno::NoSpc
+            } else {
+                Errno::IlSeq
+            });
+        }
+    }
+}
+
+enum Encoder {
+    Zlib(Box<kernel_sys::z_stream>),
+    #[cfg(feature = "zstd")]
+    Zstd(ptr::NonNull<kernel_sys::ZSTD_CCtx>),
+}
+
+/// A compression stream
+pub struct Compressor {
+    algorithm: Algorithm,
+    encoder: Encoder,
+}
+
+unsafe impl Send for Compressor {}
+
+impl Compressor {
+    /// Start a stream at the given level: 0-9 for zlib, or -1 for its
+    /// default of 6, and 1-19 for zstd
+    pub fn new(algorithm: Algorithm, level: i32) -> Result<Self, Errno> {
+        let encoder = match algorithm {
+            Algorithm::Zlib => {
+                let mut zs = zlib_stream();
+                zlib_result(unsafe {
+                    kernel_sys::deflateInit_(
+                        &mut *zs,
+                        level,
+                        kernel_sys::ZLIB_VERSION.as_ptr().cast(),
+                        mem::size_of::<kernel_sys::z_stream>() as c_int,
+                    )
+                })?;
+                Encoder::Zlib(zs)
+            }
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd => {
+                let cctx = unsafe { kernel_sys::ZSTD_createCCtx() };
+                let cctx = ptr::NonNull::new(cctx).ok_or(Errno::NoMem)?;
+                // Freed by drop if the level is refused
+                let compressor = Compressor {
+                    algorithm,
+                    encoder: Encoder::Zstd(cctx),
+                };
+                zstd_result(
+                    unsafe {
+                        kernel_sys::ZSTD_CCtx_setParameter(
+                            cctx.as_ptr(),
+                            kernel_sys::ZSTD_cParameter_ZSTD_c_compressionLevel,
+                            level,
+                        )
+                    },
+                    Errno::Inval,
+                )?;
+                return Ok(compressor);
+            }
+        };
+        Ok(Compressor { algorithm, encoder })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Compress from `input` into `output`. Once `finish` is passed, with
+    /// the last of the input, keep calling with it until `finished` is
+    /// reported; output may be held back until then
+    pub fn compress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        finish: bool,
+    ) -> Result<Progress, Errno> {
+        match &mut self.encoder {
+            Encoder::Zlib(zs) => {
+                let flush = if finish {
+                    kernel_sys::Z_FINISH
+                } else {
+                    kernel_sys::Z_NO_FLUSH
+                };
+                zlib_step(zs, input, output, |zs| unsafe {
+                    kernel_sys::deflate(zs, flush)
+                })
+            }
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(cctx) => {
+                let (mut inb, mut outb) = zstd_buffers(input, output);
+                let op = if finish {
+                    kernel_sys::ZSTD_EndDirective_ZSTD_e_end
+                } else {
+                    kernel_sys::ZSTD_EndDirective_ZSTD_e_continue
+                };
+                let left = zstd_result(
+                    unsafe {
+                        kernel_sys::ZSTD_compressStream2(
+                            cctx.as_ptr(),
+                            &mut outb,
+                            &mut inb,
+                            op,
+                        )
+                    },
+                    Errno::Inval,
+                )?;
+                Ok(Progress {
+                    consumed: inb.pos,
+                    produced: outb.pos,
+                    finished: finish && left == 0,
+                })
+            }
+        }
+    }
+
+    /// Compress the whole of `input` into `output` as one stream,
+    /// returning the compressed length. Fails with `Errno::NoSpc` if it
+    /// doesn't fit
+    pub fn compress_all(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, Errno> {
+        self.reset()?;
+        run_all(input, output, |i, o| self.compress(i, o, true))
+    }
+
+    /// Abandon the current stream and start another with the same settings
+    pub fn reset(&mut self) -> Result<(), Errno> {
+        match &mut self.encoder {
+            Encoder::Zlib(zs) => {
+                zlib_result(unsafe { kernel_sys::deflateReset(&mut **zs) })?;
+            }
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(cctx) => {
+                zstd_result(
+                    unsafe {
+                        kernel_sys::ZSTD_CCtx_reset(
+                            cctx.as_ptr(),
+                            kernel_sys::ZSTD_ResetDirective_ZSTD_reset_session_only,
+                        )
+                    },
+                    Errno::Inval,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Compressor {
+    fn drop(&mut self) {
+        match &mut self.encoder {
+            Encoder::Zlib(zs) => unsafe {
+                kernel_sys::deflateEnd(&mut **zs);
+            },
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(cctx) => unsafe {
+                kernel_sys::ZSTD_freeCCtx(cctx.as_ptr());
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Compressor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Compressor {{ algorithm: {:?} }}", self.algorithm)
+    }
+}
+
+enum Decoder {
+    Zlib(Box<kernel_sys::z_stream>),
+    #[cfg(feature = "zstd")]
+    Zstd(ptr::NonNull<kernel_sys::ZSTD_DCtx>),
+}
+
+/// A decompression stream
+pub struct Decompressor {
+    algorithm: Algorithm,
+    decoder: Decoder,
+}
+
+unsafe impl Send for Decompressor {}
+
+impl Decompressor {
+    pub fn new(algorithm: Algorithm) -> Result<Self, Errno> {
+        let decoder = match algorithm {
+            Algorithm::Zlib => {
+                let mut zs = zlib_stream();
+                zlib_result(unsafe {
+                    kernel_sys::inflateInit_(
+                        &mut *zs,
+                        kernel_sys::ZLIB_VERSION.as_ptr().cast(),
+                        mem::size_of::<kernel_sys::z_stream>() as c_int,
+                    )
+                })?;
+                Decoder::Zlib(zs)
+            }
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd => {
+                let dctx = unsafe { kernel_sys::ZSTD_createDCtx() };
+                Decoder::Zstd(ptr::NonNull::new(dctx).ok_or(Errno::NoMem)?)
+            }
+        };
+        Ok(Decompressor { algorithm, decoder })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Decompress from `input` into `output`, failing with `Errno::IlSeq`
+    /// on corrupt data. A zstd stream is finished at the end of each frame
+    pub fn decompress(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<Progress, Errno> {
+        match &mut self.decoder {
+            Decoder::Zlib(zs) => zlib_step(zs, input, output, |zs| unsafe {
+                kernel_sys::inflate(zs, kernel_sys::Z_NO_FLUSH)
+            }),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(dctx) => {
+                let (mut inb, mut outb) = zstd_buffers(input, output);
+                let hint = zstd_result(
+                    unsafe {
+                        kernel_sys::ZSTD_decompressStream(
+                            dctx.as_ptr(),
+                            &mut outb,
+                            &mut inb,
+                        )
+                    },
+                    Errno::IlSeq,
+                )?;
+                Ok(Progress {
+                    consumed: inb.pos,
+                    produced: outb.pos,
+                    finished: hint == 0,
+                })
+            }
+        }
+    }
+
+    /// Decompress the single stream in `input` into `output`, returning
+    /// the decompressed length. Fails with `Errno::NoSpc` if it doesn't
+    /// fit, and `Errno::IlSeq` if the data is corrupt or cut short
+    pub fn decompress_all(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, Errno> {
+        self.reset()?;
+        run_all(input, output, |i, o| self.decompress(i, o))
+    }
+
+    /// Abandon the current stream and expect a new one
+    pub fn reset(&mut self) -> Result<(), Errno> {
+        match &mut self.decoder {
+            Decoder::Zlib(zs) => {
+                zlib_result(unsafe { kernel_sys::inflateReset(&mut **zs) })?;
+            }
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(dctx) => {
+                zstd_result(
+                    unsafe {
+                        kernel_sys::ZSTD_DCtx_reset(
+                            dctx.as_ptr(),
+                            kernel_sys::ZSTD_ResetDirective_ZSTD_reset_session_only,
+                        )
+                    },
+                    Errno::Inval,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Decompressor {
+    fn drop(&mut self) {
+        match &mut self.decoder {
+            Decoder::Zlib(zs) => unsafe {
+                kernel_sys::inflateEnd(&mut **zs);
+            },
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(dctx) => unsafe {
+                kernel_sys::ZSTD_freeDCtx(dctx.as_ptr());
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Decompressor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Decompressor {{ algorithm: {:?} }}", self.algorithm)
+    }
+}
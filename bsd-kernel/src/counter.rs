@@ -0,0 +1,143 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Per-CPU statistics counters, see `counter(9)`
+//!
+//! Updates touch only the current CPU's slot, so they need no locking and
+//! don't bounce cache lines; reading sums every CPU's slot. That makes
+//! them the thing for bytes and errors counted on every bio. Shared in an
+//! `Arc`, a counter is exported with `sysctl::Context::add_counter`, or a
+//! `counter` entry of `sysctl!`:
+//!
+//! ```rust,ignore
+//! let errors = Arc::new(Counter::new());
+//! ctx.add_counter(node, c"errors", c"Failed requests", errors.clone(), true)?;
+//! // In the I/O path
+//! errors.increment();
+//! ```
+
+#[cfg(all(not(feature = "mock"), target_arch = "x86_64"))]
+use core::arch::asm;
+use core::fmt;
+#[cfg(any(feature = "mock", not(target_arch = "x86_64")))]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A 64-bit counter with a slot per CPU
+pub struct Counter {
+    c: kernel_sys::counter_u64_t,
+}
+
+unsafe impl Send for Counter {}
+unsafe impl Sync for Counter {}
+
+impl Counter {
+    /// Allocate a zeroed counter, sleeping for memory if needed
+    pub fn new() -> Self {
+        let c = unsafe { kernel_sys::counter_u64_alloc(kernel_sys::M_WAITOK) };
+        Counter { c }
+    }
+
+    /// Add `inc` to the current CPU's slot
+    #[inline]
+    #[cfg(all(not(feature = "mock"), target_arch = "x86_64"))]
+    pub fn add(&self, inc: u64) {
+        // counter_u64_add() is inline in C: the counter is an offset into
+        // the per-CPU area, which %gs points at
+        unsafe {
+            asm!(
+                "add qword ptr gs:[{c}], {inc}",
+                c = in(reg) self.c,
+                inc = in(reg) inc,
+                options(nostack),
+            );
+        }
+    }
+
+    /// Add `inc` to the current CPU's slot
+    #[inline]
+    #[cfg(all(not(feature = "mock"), not(target_arch = "x86_64")))]
+    pub fn add(&self, inc: u64) {
+        // As arm64's counter_u64_add(): the slots are a page apart, and
+        // the add is atomic in case the thread moves to another CPU
+        // between finding its slot and updating it
+        let cpu = crate::arch::curcpu() as usize;
+        let slot = self
+            .c
+            .wrapping_byte_add(kernel_sys::PAGE_SIZE as usize * cpu);
+        unsafe { AtomicU64::from_ptr(slot) }.fetch_add(inc, Ordering::Relaxed);
+    }
+
+    /// Add `inc` to the mock's only slot
+    #[cfg(feature = "mock")]
+    pub fn add(&self, inc: u64) {
+        unsafe { AtomicU64::from_ptr(self.c) }
+            .fetch_add(inc, Ordering::Relaxed);
+    }
+
+    /// Take `dec` from the current CPU's slot, for gauges such as requests
+    /// in flight. Slots wrap, but their sum comes out right
+    #[inline]
+    pub fn subtract(&self, dec: u64) {
+        self.add(dec.wrapping_neg());
+    }
+
+    /// Add one to the current CPU's slot
+    #[inline]
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// Sum of all CPUs' slots. Concurrent updates may or may not be
+    /// included
+    pub fn fetch(&self) -> u64 {
+        unsafe { kernel_sys::counter_u64_fetch(self.c) }
+    }
+
+    /// Reset every CPU's slot to zero
+    pub fn zero(&self) {
+        unsafe { kernel_sys::counter_u64_zero(self.c) };
+    }
+
+    /// Where the `counter_u64_t` is kept, for `sysctl_handle_counter_u64`
+    pub(crate) fn as_ptr(&self) -> *const kernel_sys::counter_u64_t {
+        &self.c
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Counter::new()
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::counter_u64_free(self.c) };
+    }
+}
+
+impl fmt::Debug for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Counter {{ value: {} }}", self.fetch())
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! CPU hints and cycle counters
+//!
+//! `spinwait` belongs in the body of every busy-wait loop: on SMT systems
+//! it lets the core's other thread run while this one polls. `cyclecount`
+//! reads the
+//! CPU's free-running counter without a system call's or timecounter's
+//! cost, for timing short stretches of code:
+//! ```ignore
+//! let start = cpu::cyclecount();
+//! work();
+//! let took = cpu::cycles_to_duration(cpu::cyclecount() - start);
+//! ```
+//! Counters on different CPUs may disagree, so both readings should come
+//! from the same one, as inside a `sched::CriticalSection` or on a
+//! `sched::Pinned` thread.
+
+use crate::arch;
+use core::time::Duration;
+
+/// `cpu_spinwait()`: a hint that the caller is spinning on a condition
+#[inline(always)]
+pub fn spinwait() {
+    arch::cpu_spinwait();
+}
+
+/// `get_cyclecount()`: the TSC on amd64, the generic timer's virtual
+/// count on arm64 and the cycle CSR on riscv64
+#[inline(always)]
+pub fn cyclecount() -> u64 {
+    arch::get_cyclecount()
+}
+
+/// `rdtsc()`
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    arch::get_cyclecount()
+}
+
+/// How fast `cyclecount` counts, in Hz, if the kernel knows
+pub fn cyclecount_frequency() -> Option<u64> {
+    Some(arch::cyclecount_frequency()).filter(|&f| f != 0)
+}
+
+/// How long `cycles` of `cyclecount` take, if the kernel knows its rate
+pub fn cycles_to_duration(cycles: u64) -> Option<Duration> {
+    let freq = u128::from(cyclecount_frequency()?);
+    let ns = u128::from(cycles) * 1_000_000_000 / freq;
+    Some(Duration::from_nanos(u64::try_from(ns).unwrap_or(u64::MAX)))
+}
+
+/// Hint that the cache line holding `p` is about to be read. Never
+/// faults, whatever `p` is
+#[inline(always)]
+pub fn prefetch<T>(p: *const T) {
+    arch::prefetch_read(p.cast());
+}
+
+/// Hint that the cache line holding `p` is about to be written
+#[inline(always)]
+pub fn prefetch_write<T>(p: *const T) {
+    arch::prefetch_write(p.cast());
+}
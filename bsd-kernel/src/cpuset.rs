@@ -0,0 +1,380 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! CPU sets and CPU affinity for kernel threads and interrupts
+//!
+//! Sets combine with `|`, `&` and `-`, and read and print in the list
+//! form of `cpuset -l`, so a sysctl string can carry one:
+//! ```ignore
+//! let cpus: CpuSet = "0-3,6".parse()?;
+//! set_irq_affinity(irq, &(cpus & CpuSet::all()))?;
+//! for cpu in &cpus { /* ... */ }
+//! ```
+
+use crate::errno::Errno;
+use core::ops::{
+    BitAnd, BitAndAssign, BitOr, BitOrAssign, Not, Sub, SubAssign,
+};
+use core::str::FromStr;
+use core::{fmt, mem};
+use libc::{c_int, c_long, c_void};
+
+const BITS_PER_WORD: usize = mem::size_of::<c_long>() * 8;
+
+/// A set of CPUs, wrapping the kernel's `cpuset_t`
+#[derive(Copy, Clone)]
+pub struct CpuSet {
+    set: kernel_sys::cpuset_t,
+}
+
+impl CpuSet {
+    /// The number of CPUs a set can hold (`CPU_SETSIZE`)
+    pub const CAPACITY: usize = mem::size_of::<kernel_sys::cpuset_t>() * 8;
+
+    /// Create an empty set
+    pub fn new() -> Self {
+        CpuSet {
+            set: unsafe { mem::zeroed() },
+        }
+    }
+
+    /// Create a set containing only `cpu`
+    pub fn single(cpu: usize) -> Self {
+        let mut set = CpuSet::new();
+        set.set(cpu);
+        set
+    }
+
+    /// The set of all CPUs present in the system (`all_cpus`)
+    pub fn all() -> Self {
+        CpuSet {
+            set: unsafe { kernel_sys::all_cpus },
+        }
+    }
+
+    /// The CPUs belonging to memory domain `domain`, or `None` if the
+    /// domain does not exist or has no CPUs
+    pub fn domain(domain: usize) -> Option<Self> {
+        if domain >= kernel_sys::MAXMEMDOM as usize {
+            return None;
+        }
+        let set = CpuSet {
+            set: unsafe { kernel_sys::cpuset_domain[domain] },
+        };
+        if set.is_empty() { None } else { Some(set) }
+    }
+
+    /// Add `cpu` to the set
+    ///
+    /// ## Panics
+    /// Panics if `cpu` is not less than `CpuSet::CAPACITY`
+    pub fn set(&mut self, cpu: usize) {
+        assert!(cpu < Self::CAPACITY, "cpu {} out of range", cpu);
+        self.set.__bits[cpu / BITS_PER_WORD] |= 1 << (cpu % BITS_PER_WORD);
+    }
+
+    /// Remove `cpu` from the set
+    pub fn clear(&mut self, cpu: usize) {
+        if cpu < Self::CAPACITY {
+            self.set.__bits[cpu / BITS_PER_WORD] &=
+                !(1 << (cpu % BITS_PER_WORD));
+        }
+    }
+
+    /// Check whether `cpu` is in the set
+    pub fn is_set(&self, cpu: usize) -> bool {
+        cpu < Self::CAPACITY
+            && self.set.__bits[cpu / BITS_PER_WORD]
+                & (1 << (cpu % BITS_PER_WORD))
+                != 0
+    }
+
+    /// The number of CPUs in the set
+    pub fn count(&self) -> usize {
+        self.set
+            .__bits
+            .iter()
+            .map(|w| w.count_ones() as usize)
+            .sum()
+    }
+
+    /// Check whether the set has no CPUs in it
+    pub fn is_empty(&self) -> bool {
+        self.set.__bits.iter().all(|&w| w == 0)
+    }
+
+    /// Check whether the two sets have any CPUs in common
+    pub fn intersects(&self, other: &CpuSet) -> bool {
+        self.set
+            .__bits
+            .iter()
+            .zip(other.set.__bits.iter())
+            .any(|(a, b)| a & b != 0)
+    }
+
+    /// Check whether every CPU in the set is also in `other`
+    pub fn is_subset(&self, other: &CpuSet) -> bool {
+        (*self - *other).is_empty()
+    }
+
+    /// The lowest numbered CPU in the set
+    pub fn first(&self) -> Option<usize> {
+        self.iter().next()
+    }
+
+    /// The CPUs in the set, lowest first
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            set: self,
+            word: 0,
+            bits: self.set.__bits[0],
+        }
+    }
+
+    /// Parse a CPU list in the form of `cpuset -l`, such as `0-3,6`, or
+    /// `all`. Trailing whitespace and NULs, as a string written to a
+    /// sysctl may end in, are ignored
+    pub fn parse(s: &str) -> Result<Self, Errno> {
+        let s = s.trim_end_matches(|c: char| c == '\0' || c.is_whitespace());
+        if s == "all" {
+            return Ok(CpuSet::all());
+        }
+        let cpu = |s: &str| match s.trim().parse::<usize>() {
+            Ok(cpu) if cpu < Self::CAPACITY => Ok(cpu),
+            _ => Err(Errno::Inval),
+        };
+        let mut set = CpuSet::new();
+        for range in s.split(',') {
+            let (first, last) = match range.split_once('-') {
+                Some((first, last)) => (cpu(first)?, cpu(last)?),
+                None => (cpu(range)?, cpu(range)?),
+            };
+            if first > last {
+                return Err(Errno::Inval);
+            }
+            (first..=last).for_each(|cpu| set.set(cpu));
+        }
+        Ok(set)
+    }
+
+    fn zip_with(
+        mut self,
+        other: CpuSet,
+        f: impl Fn(c_long, c_long) -> c_long,
+    ) -> Self {
+        for (a, b) in self.set.__bits.iter_mut().zip(other.set.__bits) {
+            *a = f(*a, b);
+        }
+        self
+    }
+
+    /// Raw pointer to the underlying `cpuset_t`
+    pub fn as_ptr(&self) -> *const kernel_sys::cpuset_t {
+        &raw const self.set
+    }
+
+    /// Mutable raw pointer to the underlying `cpuset_t`
+    pub fn as_mut_ptr(&mut self) -> *mut kernel_sys::cpuset_t {
+        &raw mut self.set
+    }
+}
+
+impl Default for CpuSet {
+    fn default() -> Self {
+        CpuSet::new()
+    }
+}
+
+impl PartialEq for CpuSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.set.__bits == other.set.__bits
+    }
+}
+
+impl Eq for CpuSet {}
+
+impl BitOr for CpuSet {
+    type Output = CpuSet;
+
+    /// The CPUs in either set (`CPU_OR`)
+    fn bitor(self, other: CpuSet) -> CpuSet {
+        self.zip_with(other, |a, b| a | b)
+    }
+}
+
+impl BitAnd for CpuSet {
+    type Output = CpuSet;
+
+    /// The CPUs in both sets (`CPU_AND`)
+    fn bitand(self, other: CpuSet) -> CpuSet {
+        self.zip_with(other, |a, b| a & b)
+    }
+}
+
+impl Sub for CpuSet {
+    type Output = CpuSet;
+
+    /// The CPUs in `self` but not `other` (`CPU_ANDNOT`)
+    fn sub(self, other: CpuSet) -> CpuSet {
+        self.zip_with(other, |a, b| a & !b)
+    }
+}
+
+impl Not for CpuSet {
+    type Output = CpuSet;
+
+    /// The CPUs present in the system that are not in the set
+    fn not(self) -> CpuSet {
+        CpuSet::all() - self
+    }
+}
+
+impl BitOrAssign for CpuSet {
+    fn bitor_assign(&mut self, other: CpuSet) {
+        *self = *self | other;
+    }
+}
+
+impl BitAndAssign for CpuSet {
+    fn bitand_assign(&mut self, other: CpuSet) {
+        *self = *self & other;
+    }
+}
+
+impl SubAssign for CpuSet {
+    fn sub_assign(&mut self, other: CpuSet) {
+        *self = *self - other;
+    }
+}
+
+impl FromIterator<usize> for CpuSet {
+    /// ## Panics
+    /// Panics if a CPU is not less than `CpuSet::CAPACITY`
+    fn from_iter<I: IntoIterator<Item = usize>>(cpus: I) -> Self {
+        let mut set = CpuSet::new();
+        cpus.into_iter().for_each(|cpu| set.set(cpu));
+        set
+    }
+}
+
+impl<'a> IntoIterator for &'a CpuSet {
+    type Item = usize;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = Errno;
+
+    fn from_str(s: &str) -> Result<Self, Errno> {
+        CpuSet::parse(s)
+    }
+}
+
+/// The list form `CpuSet::parse` reads, such as `0-3,6`
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut cpus = self.iter().peekable();
+        let mut sep = "";
+        while let Some(first) = cpus.next() {
+            let mut last = first;
+            while cpus.next_if_eq(&(last + 1)).is_some() {
+                last += 1;
+            }
+            match last == first {
+                true => write!(f, "{}{}", sep, first)?,
+                false => write!(f, "{}{}-{}", sep, first, last)?,
+            }
+            sep = ",";
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Iterator over the CPUs in a `CpuSet`, lowest first
+pub struct Iter<'a> {
+    set: &'a CpuSet,
+    word: usize,
+    /// What is left of the current word
+    bits: c_long,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let words = &self.set.set.__bits;
+        while self.bits == 0 {
+            self.word += 1;
+            self.bits = *words.get(self.word)?;
+        }
+        let bit = self.bits.trailing_zeros() as usize;
+        self.bits &= self.bits.wrapping_sub(1);
+        Some(self.word * BITS_PER_WORD + bit)
+    }
+}
+
+/// Restrict the kernel thread with thread ID `tid` to run only on the CPUs
+/// in `set`. Threads created with `kthread_add` can be pinned this way
+/// once they exist, using the `td_tid` of the new thread
+pub fn set_thread_affinity(
+    tid: kernel_sys::lwpid_t,
+    set: &CpuSet,
+) -> Result<(), Errno> {
+    let mut mask = *set;
+    Errno::result(unsafe {
+        kernel_sys::cpuset_setthread(tid, mask.as_mut_ptr())
+    })
+}
+
+/// Deliver interrupt `irq` only to the CPUs in `set`
+pub fn set_irq_affinity(irq: u32, set: &CpuSet) -> Result<(), Errno> {
+    let irq = c_int::try_from(irq).map_err(|_| Errno::Inval)?;
+    let mut mask = *set;
+    Errno::result(unsafe {
+        kernel_sys::intr_setaffinity(
+            irq,
+            kernel_sys::CPU_WHICH_IRQ,
+            mask.as_mut_ptr() as *mut c_void,
+        )
+    })
+}
+
+/// Bind the interrupt thread with thread ID `tid` to a single `cpu`; this
+/// is how an ithread follows its interrupt to a new CPU
+pub fn set_ithread_cpu(
+    tid: kernel_sys::lwpid_t,
+    cpu: usize,
+) -> Result<(), Errno> {
+    let cpu = c_int::try_from(cpu).map_err(|_| Errno::Inval)?;
+    Errno::result(unsafe { kernel_sys::cpuset_setithread(tid, cpu) })
+}
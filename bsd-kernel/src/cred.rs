@@ -0,0 +1,253 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The calling thread and its credentials, see `ucred(9)` and `priv(9)`
+//!
+//! Device methods are passed the `CurThread` they're called on, whose
+//! credentials decide what the caller may do:
+//!
+//! ```rust,ignore
+//! fn ioctl(
+//!     &mut self,
+//!     req: &mut IoctlRequest,
+//!     td: &CurThread,
+//! ) -> Result<(), Errno> {
+//!     match req.decode()? {
+//!         Cmd::GetStats(stats) => *stats = self.stats,
+//!         // Resetting the counters is for root, outside of jails
+//!         Cmd::Reset => {
+//!             td.priv_check(Privilege::DRIVER)?;
+//!             self.stats = Stats::default();
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+//!
+//! `priv_check` is preferred over testing for uid 0, as it takes jails and
+//! MAC policies into account and is logged by `security.bsd.*` knobs.
+
+use crate::errno::Errno;
+use core::ffi::c_int;
+use core::fmt;
+use core::marker::PhantomData;
+
+/// A privilege of `priv(9)`, which `priv_check` grants or denies
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Privilege(c_int);
+
+impl Privilege {
+    /// `PRIV_IO`: access to I/O ports
+    pub const IO: Self = Privilege(kernel_sys::PRIV_IO as c_int);
+    /// `PRIV_DRIVER`: the catch-all for privileged driver operations
+    pub const DRIVER: Self = Privilege(kernel_sys::PRIV_DRIVER as c_int);
+    /// `PRIV_SYSCTL_WRITE`: setting sysctls
+    pub const SYSCTL_WRITE: Self =
+        Privilege(kernel_sys::PRIV_SYSCTL_WRITE as c_int);
+    /// `PRIV_NET_SETIFMTU`: changing an interface's MTU
+    pub const NET_SETIFMTU: Self =
+        Privilege(kernel_sys::PRIV_NET_SETIFMTU as c_int);
+    /// `PRIV_NET_SETIFFLAGS`: changing an interface's flags
+    pub const NET_SETIFFLAGS: Self =
+        Privilege(kernel_sys::PRIV_NET_SETIFFLAGS as c_int);
+    /// `PRIV_NET_IFCREATE`: creating cloned interfaces
+    pub const NET_IFCREATE: Self =
+        Privilege(kernel_sys::PRIV_NET_IFCREATE as c_int);
+
+    /// Another `PRIV_*` of `sys/priv.h`
+    pub const fn from_raw(priv_: c_int) -> Self {
+        Privilege(priv_)
+    }
+
+    pub const fn as_raw(self) -> c_int {
+        self.0
+    }
+}
+
+/// A thread's credentials, `struct ucred`
+#[repr(transparent)]
+pub struct Credential(kernel_sys::ucred);
+
+impl Credential {
+    /// Borrow the credentials at `cred`
+    ///
+    /// ## Safety
+    ///
+    /// `cred` must be valid, and hold a reference for `'a`
+    pub unsafe fn from_raw<'a>(cred: *mut kernel_sys::ucred) -> &'a Self {
+        unsafe { &*cred.cast() }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::ucred {
+        &self.0 as *const _ as *mut _
+    }
+
+    /// The effective user ID, which permissions are checked against
+    pub fn uid(&self) -> u32 {
+        self.0.cr_uid
+    }
+
+    /// The real user ID, that of the user who ran the process
+    pub fn ruid(&self) -> u32 {
+        self.0.cr_ruid
+    }
+
+    /// The effective group ID
+    pub fn gid(&self) -> u32 {
+        self.0.cr_gid
+    }
+
+    /// The real group ID
+    pub fn rgid(&self) -> u32 {
+        self.0.cr_rgid
+    }
+
+    /// The saved user ID, which a set-user-ID program started as
+    pub fn svuid(&self) -> u32 {
+        self.0.cr_svuid
+    }
+
+    /// The saved group ID
+    pub fn svgid(&self) -> u32 {
+        self.0.cr_svgid
+    }
+
+    /// The supplementary groups, `cr_groups`
+    pub fn groups(&self) -> &[u32] {
+        match usize::try_from(self.0.cr_ngroups) {
+            Ok(n) if n > 0 => unsafe {
+                core::slice::from_raw_parts(self.0.cr_groups, n)
+            },
+            _ => &[],
+        }
+    }
+
+    /// Whether the credentials are a member of the group `gid`, be it the
+    /// effective group or a supplementary one
+    pub fn in_group(&self, gid: u32) -> bool {
+        unsafe { kernel_sys::groupmember(gid, self.as_ptr()) }
+    }
+
+    /// Whether the effective user is root, in whichever jail it is. Jailed
+    /// root is denied most privileges, so `priv_check` is what decides
+    /// whether it may do something
+    pub fn is_root(&self) -> bool {
+        self.uid() == 0
+    }
+
+    /// Whether the credentials belong to a jail, rather than the host
+    pub fn is_jailed(&self) -> bool {
+        unsafe { kernel_sys::jailed(self.as_ptr()) != 0 }
+    }
+
+    /// `priv_check_cred`: check that the credentials hold `privilege`,
+    /// failing with `Errno::Perm` if not
+    pub fn priv_check(&self, privilege: Privilege) -> Result<(), Errno> {
+        match unsafe {
+            kernel_sys::priv_check_cred(self.as_ptr(), privilege.as_raw())
+        } {
+            0 => Ok(()),
+            e => Err(Errno::from_raw(e).unwrap_or(Errno::Perm)),
+        }
+    }
+}
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Credential {{ uid: {}, gid: {}, jailed: {} }}",
+            self.uid(),
+            self.gid(),
+            self.is_jailed()
+        )
+    }
+}
+
+/// The thread running the code that has one, `curthread`, which is passed
+/// to device methods as the thread of the process calling them. Neither
+/// `Send` nor `Sync`, as it is only the current thread on this one
+pub struct CurThread {
+    td: *mut kernel_sys::thread,
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl CurThread {
+    /// The current thread
+    #[cfg(not(feature = "mock"))]
+    pub fn get() -> Self {
+        CurThread {
+            td: crate::arch::curthread(),
+            _not_send: PhantomData,
+        }
+    }
+
+    /// ## Safety
+    ///
+    /// `td` must be the current thread
+    pub(crate) unsafe fn from_raw(td: *mut kernel_sys::thread) -> Self {
+        CurThread {
+            td,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::thread {
+        self.td
+    }
+
+    /// The thread's ID
+    pub fn tid(&self) -> i32 {
+        unsafe { (*self.td).td_tid }
+    }
+
+    /// The ID of the thread's process
+    pub fn pid(&self) -> i32 {
+        unsafe { (*(*self.td).td_proc).p_pid }
+    }
+
+    /// The credentials the thread acts with
+    pub fn cred(&self) -> &Credential {
+        unsafe { Credential::from_raw((*self.td).td_ucred) }
+    }
+
+    /// `priv_check`: check that the thread holds `privilege`, failing with
+    /// `Errno::Perm` if not
+    pub fn priv_check(&self, privilege: Privilege) -> Result<(), Errno> {
+        match unsafe { kernel_sys::priv_check(self.td, privilege.as_raw()) } {
+            0 => Ok(()),
+            e => Err(Errno::from_raw(e).unwrap_or(Errno::Perm)),
+        }
+    }
+}
+
+impl fmt::Debug for CurThread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CurThread {{ tid: {}, cred: {:?} }}",
+            self.tid(),
+            self.cred()
+        )
+    }
+}
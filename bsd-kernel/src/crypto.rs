@@ -0,0 +1,472 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Sessions with the kernel's crypto framework, see `crypto(9)`
+//!
+//! Unlike `digest`, which hashes on the calling thread, a `CryptoSession`
+//! hands requests to whichever driver the framework picked for it: an
+//! accelerator such as `qat(4)` or `ccp(4)`, the CPU's AES instructions
+//! through `aesni(4)` or `armv8crypto(4)`, or the software `cryptosoft`.
+//! A GEOM transform encrypts each sector the way `geli(8)` does:
+//!
+//! ```rust,ignore
+//! let session = CryptoSession::new(
+//!     &SessionParams::cipher(Cipher::AesXts, &key),
+//!     Drivers::Any,
+//! )?;
+//!
+//! // In `start`, which mustn't sleep
+//! let mut req = Request::new(Op::ENCRYPT, buf);
+//! req.set_iv(&(offset / 512).to_le_bytes());
+//! session.dispatch(req, move |result, buf| {
+//!     // Write `buf` out, or fail the bio with `result`'s error
+//! });
+//! ```
+//!
+//! `run` waits for a request instead, where sleeping is allowed. Modules
+//! using this must depend on the framework in their C shim:
+//! `MODULE_DEPEND(<name>, crypto, 1, 1, 1);`
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::sync::{Condvar, Mutex};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::marker::PhantomData;
+use core::ops::Range;
+use core::{fmt, ptr};
+use libc::{c_int, c_void};
+
+/// A cipher a session encrypts and decrypts with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Cipher {
+    /// AES-XTS, for disk sectors, with a 256 or 512 bit key: two AES keys
+    /// back to back. Its 8 byte IV is typically the sector number
+    AesXts,
+    /// AES-GCM, with a 128, 192 or 256 bit key and a 12 byte IV, which
+    /// also authenticates the payload and AAD with a 16 byte tag
+    AesGcm,
+}
+
+impl Cipher {
+    fn alg(self) -> c_int {
+        (match self {
+            Cipher::AesXts => kernel_sys::CRYPTO_AES_XTS,
+            Cipher::AesGcm => kernel_sys::CRYPTO_AES_NIST_GCM_16,
+        }) as c_int
+    }
+
+    /// Length of the cipher's IVs in bytes
+    pub fn iv_len(self) -> usize {
+        match self {
+            Cipher::AesXts => 8,
+            Cipher::AesGcm => 12,
+        }
+    }
+}
+
+/// A keyed hash a session authenticates with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Auth {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl Auth {
+    fn alg(self) -> c_int {
+        (match self {
+            Auth::HmacSha256 => kernel_sys::CRYPTO_SHA2_256_HMAC,
+            Auth::HmacSha384 => kernel_sys::CRYPTO_SHA2_384_HMAC,
+            Auth::HmacSha512 => kernel_sys::CRYPTO_SHA2_512_HMAC,
+        }) as c_int
+    }
+}
+
+/// What a session does, and with which keys, `struct
+/// crypto_session_params`. The keys are copied as the session is made
+pub struct SessionParams<'k> {
+    csp: kernel_sys::crypto_session_params,
+    _keys: PhantomData<&'k [u8]>,
+}
+
+impl<'k> SessionParams<'k> {
+    fn new(mode: u32) -> Self {
+        let mut csp: kernel_sys::crypto_session_params =
+            unsafe { core::mem::zeroed() };
+        csp.csp_mode = mode as c_int;
+        SessionParams {
+            csp,
+            _keys: PhantomData,
+        }
+    }
+
+    fn set_cipher(&mut self, cipher: Cipher, key: &'k [u8]) {
+        self.csp.csp_cipher_alg = cipher.alg();
+        self.csp.csp_cipher_key = key.as_ptr() as *const c_void;
+        self.csp.csp_cipher_klen = key.len() as c_int;
+        self.csp.csp_ivlen = cipher.iv_len() as c_int;
+    }
+
+    fn set_auth(&mut self, auth: Auth, key: &'k [u8]) {
+        self.csp.csp_auth_alg = auth.alg();
+        self.csp.csp_auth_key = key.as_ptr() as *const c_void;
+        self.csp.csp_auth_klen = key.len() as c_int;
+    }
+
+    /// Encrypt or decrypt with `cipher`. `AesGcm` is an AEAD mode, which
+    /// computes or verifies a tag as well
+    pub fn cipher(cipher: Cipher, key: &'k [u8]) -> Self {
+        let mode = match cipher {
+            Cipher::AesGcm => kernel_sys::CSP_MODE_AEAD,
+            Cipher::AesXts => kernel_sys::CSP_MODE_CIPHER,
+        };
+        let mut params = Self::new(mode);
+        params.set_cipher(cipher, key);
+        params
+    }
+
+    /// Compute or verify a digest with `auth`
+    pub fn digest(auth: Auth, key: &'k [u8]) -> Self {
+        let mut params = Self::new(kernel_sys::CSP_MODE_DIGEST);
+        params.set_auth(auth, key);
+        params
+    }
+
+    /// Encrypt with `cipher`, then authenticate the ciphertext with
+    /// `auth`, as `geli(8)` does with `-a`; or verify, then decrypt
+    pub fn encrypt_then_auth(
+        cipher: Cipher,
+        cipher_key: &'k [u8],
+        auth: Auth,
+        auth_key: &'k [u8],
+    ) -> Self {
+        let mut params = Self::new(kernel_sys::CSP_MODE_ETA);
+        params.set_cipher(cipher, cipher_key);
+        params.set_auth(auth, auth_key);
+        params
+    }
+
+    /// Truncate digests to `len` bytes, rather than the algorithm's full
+    /// length
+    pub fn digest_len(mut self, len: usize) -> Self {
+        self.csp.csp_auth_mlen = len as c_int;
+        self
+    }
+}
+
+impl fmt::Debug for SessionParams<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SessionParams {{ mode: {}, cipher: {}, auth: {} }}",
+            self.csp.csp_mode, self.csp.csp_cipher_alg, self.csp.csp_auth_alg
+        )
+    }
+}
+
+/// Which drivers a session may be given to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Drivers {
+    /// Hardware if there is a driver for it, else software
+    Any,
+    /// Accelerators and CPU instructions only, failing otherwise
+    Hardware,
+    /// The software implementation only
+    Software,
+}
+
+impl Drivers {
+    fn crid(self) -> c_int {
+        (match self {
+            Drivers::Any => {
+                kernel_sys::CRYPTOCAP_F_HARDWARE
+                    | kernel_sys::CRYPTOCAP_F_SOFTWARE
+            }
+            Drivers::Hardware => kernel_sys::CRYPTOCAP_F_HARDWARE,
+            Drivers::Software => kernel_sys::CRYPTOCAP_F_SOFTWARE,
+        }) as c_int
+    }
+}
+
+/// Operations of a request, combined with `|` for sessions that both
+/// encrypt and authenticate
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Op(c_int);
+
+impl Op {
+    pub const ENCRYPT: Self = Op(kernel_sys::CRYPTO_OP_ENCRYPT as c_int);
+    pub const DECRYPT: Self = Op(kernel_sys::CRYPTO_OP_DECRYPT as c_int);
+    /// Write the digest at `Request::set_digest_start`
+    pub const COMPUTE_DIGEST: Self =
+        Op(kernel_sys::CRYPTO_OP_COMPUTE_DIGEST as c_int);
+    /// Compare the digest at `Request::set_digest_start`, failing with
+    /// `Errno::BadMsg` on a mismatch
+    pub const VERIFY_DIGEST: Self =
+        Op(kernel_sys::CRYPTO_OP_VERIFY_DIGEST as c_int);
+}
+
+impl core::ops::BitOr for Op {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Op(self.0 | other.0)
+    }
+}
+
+/// A request over a buffer of `B`, transformed in place. By default the
+/// whole buffer is payload, with no AAD or digest
+pub struct Request<B> {
+    op: Op,
+    buf: B,
+    payload: Option<Range<usize>>,
+    aad: Range<usize>,
+    digest_start: usize,
+    iv: [u8; kernel_sys::EALG_MAX_BLOCK_LEN as usize],
+    iv_len: usize,
+}
+
+impl<B: AsMut<[u8]> + Send + 'static> Request<B> {
+    pub fn new(op: Op, buf: B) -> Self {
+        Request {
+            op,
+            buf,
+            payload: None,
+            aad: 0..0,
+            digest_start: 0,
+            iv: [0; kernel_sys::EALG_MAX_BLOCK_LEN as usize],
+            iv_len: 0,
+        }
+    }
+
+    /// The bytes to encrypt, decrypt or hash
+    pub fn set_payload(&mut self, range: Range<usize>) -> &mut Self {
+        self.payload = Some(range);
+        self
+    }
+
+    /// Additional data authenticated along with the payload, for AEAD and
+    /// ETA sessions
+    pub fn set_aad(&mut self, range: Range<usize>) -> &mut Self {
+        self.aad = range;
+        self
+    }
+
+    /// Where the digest or tag is written or read
+    pub fn set_digest_start(&mut self, offset: usize) -> &mut Self {
+        self.digest_start = offset;
+        self
+    }
+
+    /// The request's IV, `Cipher::iv_len` bytes long
+    ///
+    /// ## Panics
+    ///
+    /// If longer than a block
+    pub fn set_iv(&mut self, iv: &[u8]) -> &mut Self {
+        self.iv[..iv.len()].copy_from_slice(iv);
+        self.iv_len = iv.len();
+        self
+    }
+
+    /// Fill in the `cryptop` from `crypto_getreq`
+    fn prepare(&mut self, crp: *mut kernel_sys::cryptop) {
+        let buf = self.buf.as_mut();
+        let payload = self.payload.clone().unwrap_or(0..buf.len());
+        unsafe {
+            let cb = &mut (*crp).crp_buf;
+            cb.cb_type = kernel_sys::crypto_buffer_type_CRYPTO_BUF_CONTIG;
+            cb.__bindgen_anon_1.__bindgen_anon_1.cb_buf =
+                buf.as_mut_ptr().cast();
+            cb.__bindgen_anon_1.__bindgen_anon_1.cb_buf_len =
+                buf.len() as c_int;
+            (*crp).crp_op = self.op.0;
+            (*crp).crp_payload_start = payload.start as c_int;
+            (*crp).crp_payload_length = payload.len() as c_int;
+            (*crp).crp_aad_start = self.aad.start as c_int;
+            (*crp).crp_aad_length = self.aad.len() as c_int;
+            (*crp).crp_digest_start = self.digest_start as c_int;
+            if self.iv_len != 0 {
+                (*crp).crp_flags |= kernel_sys::CRYPTO_F_IV_SEPARATE as c_int;
+                (*crp).crp_iv[..self.iv_len]
+                    .copy_from_slice(&self.iv[..self.iv_len]);
+            }
+        }
+    }
+}
+
+impl<B> fmt::Debug for Request<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Request {{ op: {:?}, payload: {:?}, aad: {:?} }}",
+            self.op, self.payload, self.aad
+        )
+    }
+}
+
+/// The session itself, freed once the session and every request
+/// dispatched on it are dropped
+struct Session(kernel_sys::crypto_session_t);
+
+unsafe impl Send for Session {}
+unsafe impl Sync for Session {}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::crypto_freesession(self.0) };
+    }
+}
+
+/// A session with the crypto framework, bound to a driver able to do what
+/// its `SessionParams` ask
+pub struct CryptoSession {
+    session: Arc<Session>,
+}
+
+impl CryptoSession {
+    /// Make a session. Fails with `Errno::Inval` for parameters no driver
+    /// of `drivers` supports, such as a key of the wrong length
+    pub fn new(
+        params: &SessionParams,
+        drivers: Drivers,
+    ) -> Result<Self, Errno> {
+        let mut cses: kernel_sys::crypto_session_t = ptr::null_mut();
+        let error = unsafe {
+            kernel_sys::crypto_newsession(
+                &mut cses,
+                &params.csp,
+                drivers.crid(),
+            )
+        };
+        match error {
+            0 => Ok(CryptoSession {
+                session: Arc::new(Session(cses)),
+            }),
+            e => Err(Errno::from_raw(e).unwrap_or(Errno::Inval)),
+        }
+    }
+
+    /// Hand `req` to the session's driver, calling `done` with the result
+    /// and the buffer once it is finished. `done` is called exactly once,
+    /// perhaps from the driver's interrupt handler or the framework's
+    /// thread, perhaps before `dispatch` returns, and mustn't sleep. Never
+    /// sleeps itself: a request the framework can't allocate fails with
+    /// `Errno::NoMem`
+    pub fn dispatch<B, F>(&self, req: Request<B>, done: F)
+    where
+        B: AsMut<[u8]> + Send + 'static,
+        F: FnOnce(Result<(), Errno>, B) + Send + 'static,
+    {
+        self.submit(req, kernel_sys::M_NOWAIT, done);
+    }
+
+    /// Run `req`, sleeping until it is finished, and return the result
+    /// and the buffer
+    pub fn run<B>(&self, req: Request<B>) -> (Result<(), Errno>, B)
+    where
+        B: AsMut<[u8]> + Send + 'static,
+    {
+        let wait = Arc::new((
+            Mutex::new(c"cryptorun", None),
+            Condvar::new(c"cryptorun"),
+        ));
+        let w = wait.clone();
+        self.submit(req, kernel_sys::M_WAITOK, move |result, buf| {
+            *w.0.lock() = Some((result, buf));
+            w.1.notify_one();
+        });
+        let mut finished = wait.0.lock();
+        loop {
+            if let Some(done) = finished.take() {
+                return done;
+            }
+            finished = wait.1.wait(finished);
+        }
+    }
+
+    fn submit<B, F>(&self, mut req: Request<B>, how: c_int, done: F)
+    where
+        B: AsMut<[u8]> + Send + 'static,
+        F: FnOnce(Result<(), Errno>, B) + Send + 'static,
+    {
+        let crp = unsafe { kernel_sys::crypto_getreq(self.session.0, how) };
+        if crp.is_null() {
+            return done(Err(Errno::NoMem), req.buf);
+        }
+        req.prepare(crp);
+        let pending = Box::new(Pending {
+            buf: req.buf,
+            done,
+            _session: self.session.clone(),
+        });
+        unsafe {
+            (*crp).crp_opaque = Box::into_raw(pending).cast();
+            (*crp).crp_callback = Some(complete::<B, F>);
+        }
+        // Errors are reported to the callback
+        unsafe { kernel_sys::crypto_dispatch(crp) };
+    }
+}
+
+impl fmt::Debug for CryptoSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CryptoSession {{ {:p} }}", self.session.0)
+    }
+}
+
+/// A dispatched request's buffer and completion, which keeps the session
+/// alive until it is finished
+struct Pending<B, F> {
+    buf: B,
+    done: F,
+    _session: Arc<Session>,
+}
+
+/// The `crp_callback` of requests, run once the driver is done
+unsafe extern "C" fn complete<B, F>(crp: *mut kernel_sys::cryptop) -> c_int
+where
+    B: AsMut<[u8]> + Send + 'static,
+    F: FnOnce(Result<(), Errno>, B) + Send + 'static,
+{
+    let etype = unsafe { (*crp).crp_etype };
+    // The driver asked for the request to be tried again
+    if etype == kernel_sys::EAGAIN {
+        unsafe {
+            (*crp).crp_etype = 0;
+            (*crp).crp_flags &= !(kernel_sys::CRYPTO_F_DONE as c_int);
+            kernel_sys::crypto_dispatch(crp);
+        }
+        return 0;
+    }
+    let pending =
+        unsafe { Box::from_raw((*crp).crp_opaque as *mut Pending<B, F>) };
+    unsafe { kernel_sys::crypto_freereq(crp) };
+    let result = match etype {
+        0 => Ok(()),
+        e => Err(Errno::from_raw(e).unwrap_or(Errno::Io)),
+    };
+    let Pending { buf, done, .. } = *pending;
+    let _ = catch_in_module(move || done(result, buf));
+    0
+}
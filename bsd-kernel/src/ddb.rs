@@ -0,0 +1,231 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Commands for the in-kernel debugger, see `ddb(4)`
+//!
+//! `db_command!` adds a command to one of ddb's tables for as long as
+//! the module is loaded, as `DB_COMMAND` and `DB_SHOW_COMMAND` do:
+//!
+//! ```rust,ignore
+//! bsd_kernel::db_command!(show rustmd, |args, out| {
+//!     let _ = writeln!(out, "queued: {}", STATE.queued());
+//! });
+//! ```
+//!
+//! Commands run with the machine stopped, possibly interrupting any code
+//! at all. They must not sleep, take locks or allocate, and should only
+//! read state that can be read while half-updated. Their output goes
+//! through `Output`, which writes with `db_printf` and stops once the
+//! user quits the pager.
+//!
+//! Needs a kernel built with `options DDB`, behind the `ddb` feature.
+
+use crate::panic::catch_in_module;
+use core::cell::UnsafeCell;
+use core::ffi::{CStr, c_char};
+use core::marker::PhantomData;
+use core::{fmt, mem, ptr};
+use libc::c_int;
+
+/// The table a command is added to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Table {
+    /// Top-level commands, `db_cmd_table`
+    Command,
+    /// `show` commands, `db_show_table`
+    Show,
+    /// `show all` commands, `db_show_all_table`
+    ShowAll,
+}
+
+impl Table {
+    fn as_ptr(self) -> *mut kernel_sys::db_command_table {
+        match self {
+            Table::Command => &raw mut kernel_sys::db_cmd_table,
+            Table::Show => &raw mut kernel_sys::db_show_table,
+            Table::ShowAll => &raw mut kernel_sys::db_show_all_table,
+        }
+    }
+}
+
+/// What a command was given: `show rustmd/v 0x1000,4` has modifiers
+/// `v`, address `0x1000` and count 4
+#[derive(Debug)]
+pub struct Args<'a> {
+    pub addr: Option<kernel_sys::db_expr_t>,
+    pub count: Option<kernel_sys::db_expr_t>,
+    pub modifiers: &'a CStr,
+}
+
+/// ddb's console, through the pager
+pub struct Output {
+    // Only while stopped in the debugger
+    _ddb: PhantomData<*mut ()>,
+}
+
+impl Output {
+    /// Whether the user quit the pager, after which writes fail so
+    /// formatting stops. Commands that loop check it to stop early
+    pub fn is_quit(&self) -> bool {
+        unsafe { ptr::read_volatile(&raw const kernel_sys::db_pager_quit) != 0 }
+    }
+}
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.is_quit() {
+            return Err(fmt::Error);
+        }
+        for chunk in s.as_bytes().chunks(c_int::MAX as usize) {
+            unsafe {
+                kernel_sys::db_printf(
+                    c"%.*s".as_ptr(),
+                    chunk.len() as c_int,
+                    chunk.as_ptr() as *const c_char,
+                )
+            };
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output")
+    }
+}
+
+/// A command's entry in a table, made by `db_command!`
+pub struct Command(UnsafeCell<kernel_sys::db_command>);
+
+// Only ddb and its registration, under the kernel linker's lock, use it
+unsafe impl Sync for Command {}
+
+impl Command {
+    pub const fn new(
+        name: &'static CStr,
+        fcn: unsafe extern "C" fn(
+            kernel_sys::db_expr_t,
+            bool,
+            kernel_sys::db_expr_t,
+            *mut c_char,
+        ),
+    ) -> Self {
+        let mut cmd: kernel_sys::db_command = unsafe { mem::zeroed() };
+        cmd.name = name.as_ptr() as *mut c_char;
+        cmd.fcn = Some(fcn);
+        Command(UnsafeCell::new(cmd))
+    }
+
+    /// Add the command to `table`, as the module loads
+    pub fn register(&'static self, table: Table) {
+        unsafe {
+            kernel_sys::db_command_register(table.as_ptr(), self.0.get())
+        };
+    }
+
+    /// Remove the command from `table`, as the module unloads
+    pub fn unregister(&'static self, table: Table) {
+        unsafe {
+            kernel_sys::db_command_unregister(table.as_ptr(), self.0.get())
+        };
+    }
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = unsafe { CStr::from_ptr((*self.0.get()).name) };
+        write!(f, "Command {{ name: {:?} }}", name)
+    }
+}
+
+/// Run a command's closure for `db_command!`
+#[doc(hidden)]
+pub unsafe fn run(
+    addr: kernel_sys::db_expr_t,
+    have_addr: bool,
+    count: kernel_sys::db_expr_t,
+    modif: *mut c_char,
+    f: impl Fn(&Args, &mut Output),
+) {
+    let args = Args {
+        addr: have_addr.then_some(addr),
+        // ddb passes -1 when no count was given
+        count: (count != -1).then_some(count),
+        modifiers: unsafe { CStr::from_ptr(modif) },
+    };
+    let mut out = Output { _ddb: PhantomData };
+    let _ = catch_in_module(|| f(&args, &mut out));
+}
+
+/// Add a command to ddb while the module is loaded, as `DB_COMMAND`,
+/// `DB_SHOW_COMMAND` and `DB_SHOW_ALL_COMMAND` do
+///
+/// `f` is a closure or function taking `&Args` and `&mut Output`.
+///
+/// ```rust,ignore
+/// db_command!(rustmd_reset, reset);
+/// db_command!(show rustmd, show);
+/// db_command!(show all rustmds, show_all);
+/// ```
+#[macro_export]
+macro_rules! db_command {
+    (@table $table:ident, $name:ident, $f:expr) => {
+        const _: () = {
+            use $crate::ddb::{Command, Table};
+            use $crate::module::{Order, Subsystem};
+
+            unsafe extern "C" fn fcn(
+                addr: $crate::kernel_sys::db_expr_t,
+                have_addr: bool,
+                count: $crate::kernel_sys::db_expr_t,
+                modif: *mut ::core::ffi::c_char,
+            ) {
+                unsafe { $crate::ddb::run(addr, have_addr, count, modif, $f) };
+            }
+
+            static COMMAND: Command =
+                Command::new($crate::cstr!(stringify!($name)), fcn);
+
+            fn add() {
+                COMMAND.register(Table::$table);
+            }
+
+            fn del() {
+                COMMAND.unregister(Table::$table);
+            }
+
+            $crate::sysinit!(Subsystem::Kld, Order::Any, add);
+            $crate::sysuninit!(Subsystem::Kld, Order::Any, del);
+        };
+    };
+    (show all $name:ident, $f:expr $(,)?) => {
+        $crate::db_command!(@table ShowAll, $name, $f);
+    };
+    (show $name:ident, $f:expr $(,)?) => {
+        $crate::db_command!(@table Show, $name, $f);
+    };
+    ($name:ident, $f:expr $(,)?) => {
+        $crate::db_command!(@table Command, $name, $f);
+    };
+}
@@ -0,0 +1,218 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Events for `devd(8)`, see `devctl_notify(9)`
+//!
+//! An event names a system, subsystem and type for devd rules to match,
+//! and carries `key=value` pairs that the rule's action can use:
+//! ```text,ignore
+//! notify 0 {
+//!     match "system"    "RUST";
+//!     match "subsystem" "fifo";
+//!     match "type"      "OVERFLOW";
+//!     action "logger -t fifo dropped $bytes bytes on $cdev";
+//! };
+//! ```
+//!
+//! `Event` allocates as it's built, so code that mustn't sleep, such as a
+//! GEOM class's `start`, builds an `EventBuf` on the stack instead.
+
+use crate::io::FmtBuf;
+use alloc::ffi::CString;
+use alloc::string::String;
+use core::ffi::CStr;
+use core::fmt::{self, Write};
+use core::ptr;
+
+/// An event under construction
+#[derive(Clone, Debug)]
+pub struct Event {
+    system: CString,
+    subsystem: CString,
+    kind: CString,
+    data: String,
+}
+
+/// Drop NULs, which can't be passed on
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
+
+impl Event {
+    /// Start an event. Systems are conventionally upper case, e.g. the
+    /// kernel's own `GEOM`, `IFNET` or `ZFS`
+    pub fn new(system: &str, subsystem: &str, kind: &str) -> Self {
+        Event {
+            system: c_string(system),
+            subsystem: c_string(subsystem),
+            kind: c_string(kind),
+            data: String::new(),
+        }
+    }
+
+    /// Add a `key=value` pair. The value is quoted if it's empty or has
+    /// spaces, quotes or backslashes in it, the way devd unquotes
+    pub fn with(mut self, key: &str, value: impl fmt::Display) -> Self {
+        let first = self.data.is_empty();
+        let _ = push_pair(&mut self.data, first, key, value);
+        self
+    }
+
+    /// Queue the event for devd. It's dropped if devd isn't listening
+    pub fn notify(&self) {
+        let data = (!self.data.is_empty()).then(|| c_string(&self.data));
+        notify(&self.system, &self.subsystem, &self.kind, data.as_deref());
+    }
+}
+
+/// An event formatted into an `N` byte buffer rather than allocated,
+/// for code that mustn't sleep
+pub struct EventBuf<const N: usize = 256> {
+    system: &'static CStr,
+    subsystem: &'static CStr,
+    kind: &'static CStr,
+    data: FmtBuf<N>,
+}
+
+impl<const N: usize> EventBuf<N> {
+    pub const fn new(
+        system: &'static CStr,
+        subsystem: &'static CStr,
+        kind: &'static CStr,
+    ) -> Self {
+        EventBuf {
+            system,
+            subsystem,
+            kind,
+            data: FmtBuf::new(),
+        }
+    }
+
+    /// Add a `key=value` pair, quoted as `Event::with` does. A pair that
+    /// doesn't fit in what's left of the buffer is left out whole
+    pub fn with(mut self, key: &str, value: impl fmt::Display) -> Self {
+        let len = self.data.len();
+        if push_pair(&mut self.data, len == 0, key, value).is_err() {
+            self.data.truncate(len);
+        }
+        self
+    }
+
+    /// Queue the event for devd. It's dropped if devd isn't listening
+    pub fn notify(&self) {
+        let data = (!self.data.is_empty()).then(|| self.data.as_cstr());
+        notify(self.system, self.subsystem, self.kind, data);
+    }
+}
+
+impl<const N: usize> fmt::Debug for EventBuf<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "EventBuf {{ system: {:?}, subsystem: {:?}, kind: {:?}, data: {:?} }}",
+            self.system,
+            self.subsystem,
+            self.kind,
+            self.data.as_str()
+        )
+    }
+}
+
+/// Finds whether a value must be quoted, without keeping it
+struct NeedsQuotes {
+    empty: bool,
+    special: bool,
+}
+
+impl Write for NeedsQuotes {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.empty &= s.is_empty();
+        self.special |=
+            s.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\');
+        Ok(())
+    }
+}
+
+/// Backslash-escapes quotes and backslashes on the way to `W`
+struct Escape<'a, W>(&'a mut W);
+
+impl<W: Write> Write for Escape<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == '"' || c == '\\' {
+                self.0.write_char('\\')?;
+            }
+            self.0.write_char(c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Append `key=value` to `data`, formatting `value` twice: once to see
+/// whether it needs quotes, and once to write it
+fn push_pair<W: Write>(
+    data: &mut W,
+    first: bool,
+    key: &str,
+    value: impl fmt::Display,
+) -> fmt::Result {
+    let mut scan = NeedsQuotes {
+        empty: true,
+        special: false,
+    };
+    let _ = write!(scan, "{}", value);
+    if !first {
+        data.write_char(' ')?;
+    }
+    write!(data, "{}=", key)?;
+    if scan.empty || scan.special {
+        data.write_char('"')?;
+        write!(Escape(data), "{}", value)?;
+        data.write_char('"')
+    } else {
+        write!(data, "{}", value)
+    }
+}
+
+/// Whether devd is listening, so events would be read. Saves building
+/// one that would be dropped
+pub fn is_listening() -> bool {
+    unsafe { kernel_sys::devctl_process_running() }
+}
+
+/// Send an event with preformatted data, if any
+pub fn notify(
+    system: &CStr,
+    subsystem: &CStr,
+    kind: &CStr,
+    data: Option<&CStr>,
+) {
+    unsafe {
+        kernel_sys::devctl_notify(
+            system.as_ptr(),
+            subsystem.as_ptr(),
+            kind.as_ptr(),
+            data.map_or(ptr::null(), CStr::as_ptr),
+        )
+    };
+}
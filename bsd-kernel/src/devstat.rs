@@ -0,0 +1,154 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Device statistics for `iostat(8)` and `systat(1)`, see `devstat(9)`
+
+use crate::sync::Mutex;
+use core::ffi::CStr;
+use core::{fmt, ptr};
+
+/// What a finished transaction did
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Transfer {
+    /// Moved no data, as for most ioctls
+    NoData,
+    /// Moved data from the device
+    Read,
+    /// Moved data to the device
+    Write,
+}
+
+impl Transfer {
+    fn flags(self) -> kernel_sys::devstat_trans_flags {
+        match self {
+            Transfer::NoData => kernel_sys::devstat_trans_flags_DEVSTAT_NO_DATA,
+            Transfer::Read => kernel_sys::devstat_trans_flags_DEVSTAT_READ,
+            Transfer::Write => kernel_sys::devstat_trans_flags_DEVSTAT_WRITE,
+        }
+    }
+}
+
+/// A device's entry in the kernel's statistics list, removed on drop
+pub struct DevStat {
+    ds: ptr::NonNull<kernel_sys::devstat>,
+    /// devstat(9) leaves serialising updates to the driver
+    lock: Mutex<()>,
+}
+
+unsafe impl Send for DevStat {}
+unsafe impl Sync for DevStat {}
+
+impl DevStat {
+    /// Register `name` and `unit`, shown together as e.g. `rustfifo0`, for
+    /// a device without a block size
+    pub fn new(name: &CStr, unit: i32) -> Self {
+        // The name is copied into the entry
+        let ds = unsafe {
+            kernel_sys::devstat_new_entry(
+                name.as_ptr() as *const libc::c_void,
+                unit,
+                0,
+                kernel_sys::devstat_support_flags_DEVSTAT_NO_BLOCKSIZE,
+                kernel_sys::devstat_type_flags_DEVSTAT_TYPE_DIRECT
+                    | kernel_sys::devstat_type_flags_DEVSTAT_TYPE_IF_OTHER,
+                kernel_sys::devstat_priority_DEVSTAT_PRIORITY_OTHER,
+            )
+        };
+        DevStat {
+            ds: ptr::NonNull::new(ds).unwrap(),
+            lock: Mutex::new(c"devstat", ()),
+        }
+    }
+
+    /// Count a transaction as started, until the returned one is ended
+    pub fn start(&self) -> Transaction<'_> {
+        let mut start = kernel_sys::bintime::default();
+        unsafe { kernel_sys::binuptime(&mut start) };
+        let _guard = self.lock.lock();
+        unsafe {
+            kernel_sys::devstat_start_transaction(self.ds.as_ptr(), &start)
+        };
+        Transaction { stat: self, start }
+    }
+}
+
+impl Drop for DevStat {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::devstat_remove_entry(self.ds.as_ptr()) };
+    }
+}
+
+impl fmt::Debug for DevStat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DevStat {{ ds: {:?} }}", self.ds.as_ptr())
+    }
+}
+
+/// A transaction started with `DevStat::start`
+///
+/// Dropping one without calling `end` ends it as moving no data, so the
+/// device isn't left looking busy.
+#[must_use = "a transaction is counted as busy until ended"]
+pub struct Transaction<'a> {
+    stat: &'a DevStat,
+    start: kernel_sys::bintime,
+}
+
+impl Transaction<'_> {
+    /// Count the transaction as done, having moved `bytes` as `transfer`
+    pub fn end(self, bytes: usize, transfer: Transfer) {
+        self.finish(bytes, transfer);
+        core::mem::forget(self);
+    }
+
+    fn finish(&self, bytes: usize, transfer: Transfer) {
+        let flags = if bytes == 0 {
+            Transfer::NoData.flags()
+        } else {
+            transfer.flags()
+        };
+        let _guard = self.stat.lock.lock();
+        unsafe {
+            kernel_sys::devstat_end_transaction(
+                self.stat.ds.as_ptr(),
+                bytes.min(u32::MAX as usize) as u32,
+                kernel_sys::devstat_tag_type_DEVSTAT_TAG_SIMPLE,
+                flags,
+                ptr::null(),
+                &self.start,
+            )
+        };
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.finish(0, Transfer::NoData);
+    }
+}
+
+impl fmt::Debug for Transaction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transaction {{ stat: {:?} }}", self.stat)
+    }
+}
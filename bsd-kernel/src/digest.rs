@@ -0,0 +1,210 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Message digests from the kernel's crypto framework, see `crypto(9)`
+//!
+//! Hashing runs the framework's software transforms directly on the
+//! calling thread, with no session or driver involved. Modules using this
+//! must depend on it in their C shim: `MODULE_DEPEND(<name>, crypto, 1, 1,
+//! 1);`
+
+use crate::errno::Errno;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use libc::{c_uint, c_void};
+
+/// A digest algorithm
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+    /// BLAKE2b with a 64 byte digest, optionally keyed
+    Blake2b,
+    /// BLAKE2s with a 32 byte digest, optionally keyed
+    Blake2s,
+}
+
+impl Algorithm {
+    fn xform(self) -> &'static kernel_sys::auth_hash {
+        unsafe {
+            match self {
+                Algorithm::Sha256 => &kernel_sys::auth_hash_sha2_256,
+                Algorithm::Sha384 => &kernel_sys::auth_hash_sha2_384,
+                Algorithm::Sha512 => &kernel_sys::auth_hash_sha2_512,
+                Algorithm::Blake2b => &kernel_sys::auth_hash_blake2b,
+                Algorithm::Blake2s => &kernel_sys::auth_hash_blake2s,
+            }
+        }
+    }
+
+    /// Length of the algorithm's digests in bytes
+    pub fn digest_len(self) -> usize {
+        usize::from(self.xform().hashsize)
+    }
+}
+
+/// Longest digest of any algorithm
+const MAX_DIGEST_LEN: usize = 64;
+
+/// A finished digest
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Digest {
+    bytes: [u8; MAX_DIGEST_LEN],
+    len: usize,
+}
+
+impl Digest {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Compare against an expected digest in constant time, so a mismatch
+    /// doesn't reveal how much of it was right
+    pub fn verify(&self, expected: &[u8]) -> bool {
+        expected.len() == self.len
+            && unsafe {
+                kernel_sys::timingsafe_bcmp(
+                    self.bytes.as_ptr() as *const c_void,
+                    expected.as_ptr() as *const c_void,
+                    self.len,
+                )
+            } == 0
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Digest {{ ")?;
+        for b in self.as_bytes() {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, " }}")
+    }
+}
+
+/// An incremental hash of data fed to it piece by piece
+pub struct Hasher {
+    algorithm: Algorithm,
+    // The transform's context, in u64s to keep its alignment
+    ctx: Vec<u64>,
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        let mut hasher = Hasher::alloc(algorithm);
+        hasher.init();
+        hasher
+    }
+
+    /// Start a keyed hash (a MAC), for the algorithms that take a key,
+    /// BLAKE2b and BLAKE2s. Fails with `Errno::Inval` for others or a
+    /// key that's too long
+    pub fn with_key(algorithm: Algorithm, key: &[u8]) -> Result<Self, Errno> {
+        let xform = algorithm.xform();
+        let setkey = xform.Setkey.ok_or(Errno::Inval)?;
+        if key.len() > usize::from(xform.keysize) {
+            return Err(Errno::Inval);
+        }
+        let mut hasher = Hasher::alloc(algorithm);
+        unsafe {
+            setkey(hasher.ctx_ptr(), key.as_ptr(), key.len() as c_uint);
+        }
+        hasher.init();
+        Ok(hasher)
+    }
+
+    fn alloc(algorithm: Algorithm) -> Self {
+        let ctxsize = usize::from(algorithm.xform().ctxsize);
+        Hasher {
+            algorithm,
+            ctx: vec![0; ctxsize.div_ceil(8)],
+        }
+    }
+
+    fn ctx_ptr(&mut self) -> *mut c_void {
+        self.ctx.as_mut_ptr() as *mut c_void
+    }
+
+    fn init(&mut self) {
+        let init = self.algorithm.xform().Init.unwrap();
+        unsafe { init(self.ctx_ptr()) };
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let update = self.algorithm.xform().Update.unwrap();
+        // The transforms take an unsigned int length
+        for chunk in data.chunks(c_uint::MAX as usize) {
+            unsafe {
+                update(
+                    self.ctx_ptr(),
+                    chunk.as_ptr() as *const c_void,
+                    chunk.len() as c_uint,
+                )
+            };
+        }
+    }
+
+    /// The digest of everything passed to `update`
+    pub fn finish(mut self) -> Digest {
+        let last = self.algorithm.xform().Final.unwrap();
+        let mut digest = Digest {
+            bytes: [0; MAX_DIGEST_LEN],
+            len: self.algorithm.digest_len(),
+        };
+        unsafe { last(digest.bytes.as_mut_ptr(), self.ctx_ptr()) };
+        digest
+    }
+}
+
+impl Drop for Hasher {
+    fn drop(&mut self) {
+        // The context may hold a key or what's been hashed
+        let len = self.ctx.len() * 8;
+        unsafe { kernel_sys::explicit_bzero(self.ctx_ptr(), len) };
+    }
+}
+
+impl fmt::Debug for Hasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hasher {{ algorithm: {:?} }}", self.algorithm)
+    }
+}
+
+/// Hash `data` in one go
+pub fn digest(algorithm: Algorithm, data: &[u8]) -> Digest {
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(data);
+    hasher.finish()
+}
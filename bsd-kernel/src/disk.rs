@@ -0,0 +1,460 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Disks for block drivers below GEOM, see `disk(9)`
+//!
+//! A driver that only moves blocks to and from its hardware implements
+//! `DiskDriver` and describes the media with a `Builder`, rather than
+//! writing a whole `geom::GeomClass`. GEOM's `DISK` class then publishes
+//! a provider named for the driver and unit, such as `rd0`, takes care
+//! of opens, attributes and statistics, and hands each request to
+//! `DiskDriver::strategy`:
+//!
+//! ```rust,ignore
+//! let disk = disk::Builder::new(c"rd", 0)
+//!     .sectorsize(512)
+//!     .mediasize(size)
+//!     .flags(DiskFlags::CANDELETE | DiskFlags::CANFLUSHCACHE)
+//!     .create(RamDisk::new(size)?);
+//! ```
+
+use crate::cred::CurThread;
+use crate::dump::DumpContext;
+use crate::errno::Errno;
+use crate::geom::BioCmd;
+use crate::ioctl::{self, IoctlRequest};
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::ffi::CStr;
+use core::{fmt, ops, ptr, slice};
+use libc::{c_int, c_void};
+
+/// Flags of a `struct disk`, telling GEOM what the driver can do
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DiskFlags(u32);
+
+impl DiskFlags {
+    pub const NONE: Self = DiskFlags(0);
+    /// `strategy` takes `BioCmd::Delete` requests, to trim or unmap
+    pub const CANDELETE: Self = DiskFlags(kernel_sys::DISKFLAG_CANDELETE);
+    /// `strategy` takes `BioCmd::Flush` requests, to write back a cache
+    pub const CANFLUSHCACHE: Self =
+        DiskFlags(kernel_sys::DISKFLAG_CANFLUSHCACHE);
+    /// `strategy` takes requests whose data isn't mapped into the kernel,
+    /// for drivers that only hand pages to DMA
+    pub const UNMAPPED_BIO: Self = DiskFlags(kernel_sys::DISKFLAG_UNMAPPED_BIO);
+    /// Bios may be completed from any context, without the `g_up` thread
+    pub const DIRECT_COMPLETION: Self =
+        DiskFlags(kernel_sys::DISKFLAG_DIRECT_COMPLETION);
+    /// The media may not be opened for writing
+    pub const WRITE_PROTECT: Self =
+        DiskFlags(kernel_sys::DISKFLAG_WRITE_PROTECT);
+
+    pub const fn union(self, other: Self) -> Self {
+        DiskFlags(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags as `d_flags`
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl ops::BitOr for DiskFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// A disk driver's entry points
+///
+/// GEOM calls them concurrently, from its `g_down` thread or straight
+/// from the thread issuing I/O, so a driver keeps its state behind its
+/// own locks.
+pub trait DiskDriver: Send + Sync + 'static {
+    /// Offer the disk as a dump device to `dumpon(8)`, writing through
+    /// `dump` and `dump_flush`
+    const DUMP: bool = false;
+
+    /// Start a request, completing it with `DiskBio::done` once the
+    /// hardware has finished, which may happen later from another thread
+    /// such as an interrupt handler. Sleeping is not allowed
+    fn strategy(&self, bio: DiskBio);
+
+    /// Handle an `ioctl(2)` on the disk that GEOM didn't handle itself.
+    /// Unknown commands fail with `Errno::NotTy`, as the default does for
+    /// all
+    fn ioctl(
+        &self,
+        _req: &mut IoctlRequest,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Err(Errno::NotTy)
+    }
+
+    /// Write `data`, a whole number of sectors, at byte `offset` of the
+    /// media while dumping after a panic, see `dump::Dumper::write`
+    fn dump(
+        &self,
+        _ctx: &DumpContext,
+        _offset: u64,
+        _data: &[u8],
+    ) -> Result<(), Errno> {
+        Err(Errno::NoDev)
+    }
+
+    /// The dump is complete; flush any write cache
+    fn dump_flush(&self, _ctx: &DumpContext) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// A request passed to `DiskDriver::strategy`
+///
+/// GEOM has already checked it against the media: reads and writes are
+/// whole sectors, within the media and no longer than the disk's
+/// `maxsize`. Dropping one leaves its issuer waiting forever.
+#[must_use = "a bio must be completed"]
+pub struct DiskBio {
+    bp: ptr::NonNull<kernel_sys::bio>,
+}
+
+unsafe impl Send for DiskBio {}
+
+impl DiskBio {
+    fn raw(&self) -> &kernel_sys::bio {
+        unsafe { self.bp.as_ref() }
+    }
+
+    /// Raw pointer to the underlying bio
+    pub fn as_ptr(&self) -> *mut kernel_sys::bio {
+        self.bp.as_ptr()
+    }
+
+    /// The requested operation
+    pub fn cmd(&self) -> BioCmd {
+        BioCmd::from_raw(self.raw().bio_cmd)
+    }
+
+    /// Byte offset of the request on the media
+    pub fn offset(&self) -> i64 {
+        self.raw().bio_offset
+    }
+
+    /// Number of bytes to transfer, or to delete
+    pub fn length(&self) -> usize {
+        self.raw().bio_length as usize
+    }
+
+    /// Whether the request must not start until everything sent before
+    /// it has completed, as `BioCmd::Flush` requests sent by `g_io_flush`
+    pub fn is_ordered(&self) -> bool {
+        i32::from(self.raw().bio_flags) & kernel_sys::BIO_ORDERED != 0
+    }
+
+    /// The data, read from for writes and written to for reads, `None`
+    /// for requests without any or, under `DiskFlags::UNMAPPED_BIO`,
+    /// whose pages aren't mapped
+    pub fn data(&self) -> Option<*mut u8> {
+        let unmapped =
+            i32::from(self.raw().bio_flags) & kernel_sys::BIO_UNMAPPED != 0;
+        let data = self.raw().bio_data as *mut u8;
+        (!unmapped && !data.is_null()).then_some(data)
+    }
+
+    /// Complete the request, see `biodone(9)`. `Ok` carries the number of
+    /// bytes moved, which is less than `length()` for a short transfer
+    pub fn done(self, result: Result<usize, Errno>) {
+        let bp = self.bp.as_ptr();
+        unsafe {
+            match result {
+                Ok(n) => {
+                    (*bp).bio_resid =
+                        (*bp).bio_length - n.min(self.length()) as i64;
+                }
+                Err(e) => {
+                    (*bp).bio_error = e.as_raw();
+                    (*bp).bio_flags |= kernel_sys::BIO_ERROR as u16;
+                    (*bp).bio_resid = (*bp).bio_length;
+                }
+            }
+            kernel_sys::biodone(bp);
+        }
+    }
+}
+
+impl fmt::Debug for DiskBio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DiskBio {{ cmd: {:?}, offset: {}, length: {} }}",
+            self.cmd(),
+            self.offset(),
+            self.length()
+        )
+    }
+}
+
+/// The media a new disk presents
+#[derive(Debug)]
+pub struct Builder {
+    name: &'static CStr,
+    unit: u32,
+    sectorsize: u32,
+    mediasize: u64,
+    maxsize: u32,
+    stripesize: u64,
+    stripeoffset: u64,
+    flags: DiskFlags,
+    ident: Option<&'static CStr>,
+}
+
+impl Builder {
+    /// Unit `unit` of the disks named `name`, of 512-byte sectors and no
+    /// size yet
+    pub fn new(name: &'static CStr, unit: u32) -> Self {
+        Builder {
+            name,
+            unit,
+            sectorsize: 512,
+            mediasize: 0,
+            maxsize: kernel_sys::DFLTPHYS,
+            stripesize: 0,
+            stripeoffset: 0,
+            flags: DiskFlags::NONE,
+            ident: None,
+        }
+    }
+
+    /// The size of a sector, the smallest unit of I/O, in bytes
+    pub fn sectorsize(mut self, sectorsize: u32) -> Self {
+        self.sectorsize = sectorsize;
+        self
+    }
+
+    /// The size of the media in bytes, a multiple of the sector size
+    pub fn mediasize(mut self, mediasize: u64) -> Self {
+        self.mediasize = mediasize;
+        self
+    }
+
+    /// The longest request `strategy` takes, in bytes. GEOM splits longer
+    /// ones. Defaults to `DFLTPHYS`
+    pub fn maxsize(mut self, maxsize: u32) -> Self {
+        self.maxsize = maxsize;
+        self
+    }
+
+    /// The media's preferred I/O size and where the first such stripe
+    /// starts, such as the physical sectors of a drive emulating smaller
+    /// ones, for filesystems to align to
+    pub fn stripe(mut self, size: u64, offset: u64) -> Self {
+        self.stripesize = size;
+        self.stripeoffset = offset;
+        self
+    }
+
+    pub fn flags(mut self, flags: DiskFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// A serial number or other identity that survives renumbering,
+    /// which GEOM publishes as the provider's `ident`
+    pub fn ident(mut self, ident: &'static CStr) -> Self {
+        self.ident = Some(ident);
+        self
+    }
+
+    /// Publish the disk, with `driver` serving its I/O. Sleeps, so is
+    /// called from attach or the module event handler
+    pub fn create<T: DiskDriver>(self, driver: T) -> Disk<T> {
+        let driver = Box::new(driver);
+        unsafe {
+            let dp = kernel_sys::disk_alloc();
+            (*dp).d_name = self.name.as_ptr();
+            (*dp).d_unit = self.unit as c_int;
+            (*dp).d_sectorsize = self.sectorsize;
+            (*dp).d_mediasize = self.mediasize as kernel_sys::off_t;
+            (*dp).d_maxsize = self.maxsize;
+            (*dp).d_stripesize = self.stripesize as kernel_sys::off_t;
+            (*dp).d_stripeoffset = self.stripeoffset as kernel_sys::off_t;
+            (*dp).d_flags = self.flags.bits();
+            if let Some(ident) = self.ident {
+                // Truncated to fit, leaving the terminating NUL
+                let ident = ident.to_bytes();
+                let n = ident.len().min((*dp).d_ident.len() - 1);
+                for (d, s) in (*dp).d_ident.iter_mut().zip(&ident[..n]) {
+                    *d = *s as libc::c_char;
+                }
+            }
+            (*dp).d_strategy = Some(disk_strategy::<T>);
+            (*dp).d_ioctl = Some(disk_ioctl::<T>);
+            if T::DUMP {
+                (*dp).d_dump = Some(disk_dump::<T>);
+            }
+            (*dp).d_drv1 = &*driver as *const T as *mut c_void;
+            kernel_sys::disk_create(dp, kernel_sys::DISK_VERSION as c_int);
+            Disk {
+                dp: ptr::NonNull::new(dp).unwrap(),
+                driver,
+            }
+        }
+    }
+}
+
+/// A disk published to GEOM, destroyed when dropped
+///
+/// Requests already passed to `strategy` must have been completed before
+/// it's dropped, as the driver goes with it.
+pub struct Disk<T: DiskDriver> {
+    dp: ptr::NonNull<kernel_sys::disk>,
+    driver: Box<T>,
+}
+
+unsafe impl<T: DiskDriver> Send for Disk<T> {}
+unsafe impl<T: DiskDriver> Sync for Disk<T> {}
+
+impl<T: DiskDriver> Disk<T> {
+    pub fn driver(&self) -> &T {
+        &self.driver
+    }
+
+    /// Raw pointer to the underlying `struct disk`
+    pub fn as_ptr(&self) -> *mut kernel_sys::disk {
+        self.dp.as_ptr()
+    }
+
+    /// Tell GEOM the media is now `mediasize` bytes, as after a volume
+    /// behind the disk was grown, see `disk_resize(9)`
+    pub fn resize(&self, mediasize: u64) -> Result<(), Errno> {
+        let dp = self.dp.as_ptr();
+        unsafe {
+            (*dp).d_mediasize = mediasize as kernel_sys::off_t;
+            Errno::result(kernel_sys::disk_resize(dp, kernel_sys::M_WAITOK))
+        }
+    }
+
+    /// Tell GEOM new media was inserted, so it retastes the disk
+    pub fn media_changed(&self) {
+        unsafe {
+            kernel_sys::disk_media_changed(
+                self.dp.as_ptr(),
+                kernel_sys::M_WAITOK,
+            )
+        };
+    }
+
+    /// Tell GEOM the media was removed, so consumers see it go
+    pub fn media_gone(&self) {
+        unsafe {
+            kernel_sys::disk_media_gone(self.dp.as_ptr(), kernel_sys::M_WAITOK)
+        };
+    }
+}
+
+impl<T: DiskDriver> Drop for Disk<T> {
+    fn drop(&mut self) {
+        // Refuses new requests at once; GEOM frees the disk later
+        unsafe { kernel_sys::disk_destroy(self.dp.as_ptr()) };
+    }
+}
+
+impl<T: DiskDriver> fmt::Debug for Disk<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, unit) = unsafe {
+            let dp = self.dp.as_ref();
+            (CStr::from_ptr(dp.d_name), dp.d_unit)
+        };
+        write!(f, "Disk {{ name: {:?}, unit: {} }}", name, unit)
+    }
+}
+
+unsafe fn driver<'a, T: DiskDriver>(dp: *mut kernel_sys::disk) -> &'a T {
+    unsafe { &*((*dp).d_drv1 as *const T) }
+}
+
+unsafe extern "C" fn disk_strategy<T: DiskDriver>(bp: *mut kernel_sys::bio) {
+    let t = unsafe { driver::<T>((*bp).bio_disk) };
+    let bio = DiskBio {
+        bp: ptr::NonNull::new(bp).unwrap(),
+    };
+    // A bio whose handler panicked may or may not have been completed, so
+    // it's left as it is
+    let _ = catch_in_module(|| t.strategy(bio));
+}
+
+unsafe extern "C" fn disk_ioctl<T: DiskDriver>(
+    dp: *mut kernel_sys::disk,
+    cmd: libc::c_ulong,
+    data: *mut c_void,
+    _fflag: c_int,
+    td: *mut kernel_sys::thread,
+) -> c_int {
+    let t = unsafe { driver::<T>(dp) };
+    // The kernel has copied the argument in and copies it out after
+    let len = ioctl::param_len(cmd);
+    let data: &mut [u8] = if len == 0 || data.is_null() {
+        &mut []
+    } else {
+        unsafe { slice::from_raw_parts_mut(data as *mut u8, len) }
+    };
+    let mut req = if ioctl::is_compat32(td) {
+        IoctlRequest::new_compat32(cmd, data)
+    } else {
+        IoctlRequest::new(cmd, data)
+    };
+    let td = unsafe { CurThread::from_raw(td) };
+    catch_in_module(|| t.ioctl(&mut req, &td))
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+/// `dumper_t`, whose argument is the disk: a zero length write ends the
+/// dump
+unsafe extern "C" fn disk_dump<T: DiskDriver>(
+    arg: *mut c_void,
+    virtual_: *mut c_void,
+    offset: kernel_sys::off_t,
+    length: usize,
+) -> c_int {
+    let t = unsafe { driver::<T>(arg as *mut kernel_sys::disk) };
+    let ctx = unsafe { DumpContext::new() };
+    catch_in_module(|| {
+        if length == 0 {
+            t.dump_flush(&ctx)
+        } else {
+            let data =
+                unsafe { slice::from_raw_parts(virtual_ as *const u8, length) };
+            t.dump(&ctx, offset as u64, data)
+        }
+    })
+    .and_then(|r| r)
+    .map_or_else(|e| e.as_raw(), |()| 0)
+}
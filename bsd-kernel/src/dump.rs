@@ -0,0 +1,165 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Crash dump devices, see `dumpon(8)`
+//!
+//! A storage driver offers itself as a place to write kernel dumps with
+//! `DumpDevice::register`, and the kernel calls its `Dumper` after a
+//! panic to write the dump out. By then the scheduler is stopped and
+//! only the panicking CPU runs, with interrupts off: nothing may sleep,
+//! take a lock, allocate, or wait for an interrupt. The driver polls its
+//! hardware instead, pausing with `DumpContext::delay`, which `Dumper`'s
+//! methods get as a reminder of where they run.
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use core::marker::PhantomData;
+use core::{fmt, mem, ptr, slice};
+use libc::{c_int, c_void};
+
+/// Proof of running in the dump path, with the machine stopped
+pub struct DumpContext {
+    _cpu: PhantomData<*mut ()>,
+}
+
+impl DumpContext {
+    /// ## Safety
+    /// Only for dump callbacks, which the kernel calls once stopped
+    pub(crate) unsafe fn new() -> Self {
+        DumpContext { _cpu: PhantomData }
+    }
+
+    /// Spin for `us` microseconds, `DELAY(9)`
+    pub fn delay(&self, us: u32) {
+        unsafe { kernel_sys::DELAY(us.min(c_int::MAX as u32) as c_int) };
+    }
+}
+
+impl fmt::Debug for DumpContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DumpContext")
+    }
+}
+
+/// A dump target's write path
+pub trait Dumper: Send + Sync + 'static {
+    /// Write `data`, a whole number of blocks, at byte `offset` of the
+    /// media, and return only once it's there
+    fn write(
+        &self,
+        ctx: &DumpContext,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), Errno>;
+
+    /// The dump is complete; flush any write cache
+    fn flush(&self, _ctx: &DumpContext) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// The geometry of the media a dump goes to
+#[derive(Copy, Clone, Debug)]
+pub struct Media {
+    /// The block size writes are made in
+    pub blocksize: u32,
+    /// The most bytes one write may carry
+    pub maxiosize: u32,
+    /// Where the space for dumps starts, in bytes
+    pub offset: u64,
+    /// The space for dumps, in bytes
+    pub size: u64,
+}
+
+/// A registered dump device, the target `dumpon(8)` lists as `name`.
+/// Removed when dropped
+pub struct DumpDevice<T: Dumper> {
+    dumper: Box<T>,
+    name: CString,
+}
+
+impl<T: Dumper> DumpDevice<T> {
+    /// Make `dumper` the first choice of dump device, ahead of those
+    /// already configured
+    pub fn register(
+        name: &str,
+        media: Media,
+        dumper: T,
+    ) -> Result<Self, Errno> {
+        let name = CString::new(name).map_err(|_| Errno::Inval)?;
+        let dumper = Box::new(dumper);
+        let mut di: kernel_sys::dumperinfo = unsafe { mem::zeroed() };
+        di.dumper = Some(dump::<T>);
+        di.priv_ = &*dumper as *const T as *mut c_void;
+        di.blocksize = media.blocksize;
+        di.maxiosize = media.maxiosize;
+        di.mediaoffset = media.offset as kernel_sys::off_t;
+        di.mediasize = media.size as kernel_sys::off_t;
+        // Index 0, no compression or encryption
+        let kda: kernel_sys::diocskerneldump_arg = unsafe { mem::zeroed() };
+        Errno::result(unsafe {
+            kernel_sys::dumper_insert(&di, name.as_ptr(), &kda)
+        })?;
+        Ok(DumpDevice { dumper, name })
+    }
+
+    pub fn dumper(&self) -> &T {
+        &self.dumper
+    }
+}
+
+impl<T: Dumper> Drop for DumpDevice<T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::dumper_remove(self.name.as_ptr(), ptr::null()) };
+    }
+}
+
+impl<T: Dumper> fmt::Debug for DumpDevice<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DumpDevice {{ name: {:?} }}", self.name)
+    }
+}
+
+/// `dumper_t`: a zero length write ends the dump
+unsafe extern "C" fn dump<T: Dumper>(
+    priv_: *mut c_void,
+    virtual_: *mut c_void,
+    offset: kernel_sys::off_t,
+    length: usize,
+) -> c_int {
+    let t = unsafe { &*(priv_ as *const T) };
+    let ctx = unsafe { DumpContext::new() };
+    catch_in_module(|| {
+        if length == 0 {
+            t.flush(&ctx)
+        } else {
+            let data =
+                unsafe { slice::from_raw_parts(virtual_ as *const u8, length) };
+            t.write(&ctx, offset as u64, data)
+        }
+    })
+    .and_then(|r| r)
+    .map_or_else(|e| e.as_raw(), |()| 0)
+}
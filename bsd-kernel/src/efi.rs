@@ -0,0 +1,364 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! EFI runtime services, see `efirt(9)`
+//!
+//! On machines booted through UEFI, the firmware's runtime services read
+//! and write its variables and its real time clock. The `efirt` module
+//! maps them into the kernel and switches in and out of the firmware's
+//! address space around each call, so modules using this should declare
+//! `MODULE_DEPEND(<module>, efirt, 1, 1, 1)` in their C file. Calls sleep
+//! on the lock serializing firmware access, so they can't be made from
+//! interrupt handlers or with non-sleepable locks held.
+//!
+//! Variable names are UTF-16 in the firmware and `&str` here.
+//!
+//! ```ignore
+//! let (_, data) = efi::read_variable("BootCurrent", &efi::GLOBAL_VARIABLE)?;
+//! let time = efi::get_time()?;
+//! ```
+
+use crate::errno::Errno;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{fmt, mem, ptr};
+
+/// `EFI_VARIABLE_*` attributes
+pub mod attr {
+    pub const NON_VOLATILE: u32 = 0x1;
+    pub const BOOTSERVICE_ACCESS: u32 = 0x2;
+    pub const RUNTIME_ACCESS: u32 = 0x4;
+    pub const HARDWARE_ERROR_RECORD: u32 = 0x8;
+    pub const TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+    pub const APPEND_WRITE: u32 = 0x40;
+}
+
+/// An EFI GUID, laid out as `struct uuid`
+#[repr(C)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    pub const fn new(
+        data1: u32,
+        data2: u16,
+        data3: u16,
+        data4: [u8; 8],
+    ) -> Self {
+        Guid {
+            data1,
+            data2,
+            data3,
+            data4,
+        }
+    }
+
+    fn as_ptr(&self) -> *mut kernel_sys::uuid {
+        self as *const Guid as *mut kernel_sys::uuid
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.data4;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            self.data1, self.data2, self.data3, d[0], d[1]
+        )?;
+        d[2..].iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+/// `EFI_GLOBAL_VARIABLE`, the vendor of the variables the UEFI
+/// specification defines, such as `BootOrder`
+pub const GLOBAL_VARIABLE: Guid = Guid::new(
+    0x8be4df61,
+    0x93ca,
+    0x11d2,
+    [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+);
+
+/// The firmware clock's time, `struct efi_tm`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Time {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+    /// Minutes from UTC, or `UNSPECIFIED_TIMEZONE` for local time
+    pub timezone: i16,
+    /// `EFI_TIME_*` daylight saving bits
+    pub daylight: u8,
+}
+
+/// The `timezone` of a clock kept in local time
+pub const UNSPECIFIED_TIMEZONE: i16 = 0x07ff;
+
+impl Time {
+    fn from_raw(tm: &kernel_sys::efi_tm) -> Self {
+        Time {
+            year: tm.tm_year,
+            month: tm.tm_mon,
+            day: tm.tm_mday,
+            hour: tm.tm_hour,
+            minute: tm.tm_min,
+            second: tm.tm_sec,
+            nanosecond: tm.tm_nsec,
+            timezone: tm.tm_tz,
+            daylight: tm.tm_dst,
+        }
+    }
+
+    fn to_raw(self) -> kernel_sys::efi_tm {
+        let mut tm: kernel_sys::efi_tm = unsafe { mem::zeroed() };
+        tm.tm_year = self.year;
+        tm.tm_mon = self.month;
+        tm.tm_mday = self.day;
+        tm.tm_hour = self.hour;
+        tm.tm_min = self.minute;
+        tm.tm_sec = self.second;
+        tm.tm_nsec = self.nanosecond;
+        tm.tm_tz = self.timezone;
+        tm.tm_dst = self.daylight;
+        tm
+    }
+}
+
+/// What the firmware clock can do, `struct efi_tm_cap`
+#[derive(Copy, Clone, Debug)]
+pub struct TimeCapabilities {
+    /// Resolution in counts per second
+    pub resolution: u32,
+    /// Accuracy in parts per million
+    pub accuracy: u32,
+    /// Whether setting the time clears the sub-second count
+    pub sets_to_zero: bool,
+}
+
+fn ops() -> &'static kernel_sys::efi_ops {
+    unsafe { &*kernel_sys::active_efi_ops }
+}
+
+/// Whether runtime services are usable: the machine booted through UEFI,
+/// `efirt` is loaded and `efi.rt.disabled` isn't set
+pub fn is_available() -> bool {
+    ops().rt_ok.is_some_and(|f| unsafe { f() } == 0)
+}
+
+pub fn get_time() -> Result<Time, Errno> {
+    let f = ops().get_time.ok_or(Errno::NxIo)?;
+    let mut tm: kernel_sys::efi_tm = unsafe { mem::zeroed() };
+    Errno::result(unsafe { f(&mut tm) })?;
+    Ok(Time::from_raw(&tm))
+}
+
+pub fn time_capabilities() -> Result<TimeCapabilities, Errno> {
+    let f = ops().get_time_capabilities.ok_or(Errno::NxIo)?;
+    let mut cap: kernel_sys::efi_tm_cap = unsafe { mem::zeroed() };
+    Errno::result(unsafe { f(&mut cap) })?;
+    Ok(TimeCapabilities {
+        resolution: cap.tc_res,
+        accuracy: cap.tc_prec,
+        sets_to_zero: cap.tc_stz != 0,
+    })
+}
+
+pub fn set_time(time: &Time) -> Result<(), Errno> {
+    let f = ops().set_time.ok_or(Errno::NxIo)?;
+    let mut tm = time.to_raw();
+    Errno::result(unsafe { f(&mut tm) })
+}
+
+fn encode(name: &str) -> Vec<u16> {
+    name.encode_utf16().chain(Some(0)).collect()
+}
+
+/// Read variable `name` of `vendor` into `buf`, returning its attributes
+/// and length. Fails with `Errno::Overflow` if `buf` is too short, and
+/// `Errno::NoEnt` if there's no such variable
+pub fn get_variable(
+    name: &str,
+    vendor: &Guid,
+    buf: &mut [u8],
+) -> Result<(u32, usize), Errno> {
+    let (attrs, len, error) = var_get(&mut encode(name), vendor, buf);
+    Errno::result(error).map(|()| (attrs, len))
+}
+
+/// Read all of variable `name` of `vendor`, returning its attributes and
+/// data
+pub fn read_variable(
+    name: &str,
+    vendor: &Guid,
+) -> Result<(u32, Vec<u8>), Errno> {
+    let mut name = encode(name);
+    let mut data = Vec::new();
+    loop {
+        match var_get(&mut name, vendor, &mut data) {
+            (attrs, len, 0) => {
+                data.truncate(len);
+                return Ok((attrs, data));
+            }
+            // The variable may grow between calls
+            (_, len, kernel_sys::EOVERFLOW) if len > data.len() => {
+                data.resize(len, 0);
+            }
+            (_, _, error) => {
+                return Err(Errno::from_raw(error).unwrap_or(Errno::Io));
+            }
+        }
+    }
+}
+
+fn var_get(
+    name: &mut [u16],
+    vendor: &Guid,
+    buf: &mut [u8],
+) -> (u32, usize, libc::c_int) {
+    let Some(f) = ops().var_get else {
+        return (0, 0, kernel_sys::ENXIO);
+    };
+    let mut attrs = 0;
+    let mut len = buf.len();
+    let data = if buf.is_empty() {
+        ptr::null_mut()
+    } else {
+        buf.as_mut_ptr().cast()
+    };
+    let error = unsafe {
+        f(
+            name.as_mut_ptr(),
+            vendor.as_ptr(),
+            &mut attrs,
+            &mut len,
+            data,
+        )
+    };
+    (attrs, len, error)
+}
+
+/// Create or replace variable `name` of `vendor`, or with
+/// `attr::APPEND_WRITE`, append to it. Empty `data` deletes the variable
+pub fn set_variable(
+    name: &str,
+    vendor: &Guid,
+    attrs: u32,
+    data: &[u8],
+) -> Result<(), Errno> {
+    let f = ops().var_set.ok_or(Errno::NxIo)?;
+    let mut name = encode(name);
+    Errno::result(unsafe {
+        f(
+            name.as_mut_ptr(),
+            vendor.as_ptr(),
+            attrs,
+            data.len(),
+            data.as_ptr() as *mut libc::c_void,
+        )
+    })
+}
+
+pub fn delete_variable(name: &str, vendor: &Guid) -> Result<(), Errno> {
+    set_variable(name, vendor, 0, &[])
+}
+
+/// Iterate over the names and vendors of all variables visible at runtime
+pub fn variables() -> Variables {
+    Variables {
+        name: vec![0; 64],
+        vendor: Guid::new(0, 0, 0, [0; 8]),
+        done: false,
+    }
+}
+
+/// The iterator `variables` returns, yielding errors the firmware raises
+/// part way and then stopping
+pub struct Variables {
+    name: Vec<u16>,
+    vendor: Guid,
+    done: bool,
+}
+
+impl Iterator for Variables {
+    type Item = Result<(String, Guid), Errno>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let Some(f) = ops().var_nextname else {
+            self.done = true;
+            return Some(Err(Errno::NxIo));
+        };
+        loop {
+            let mut size = self.name.len() * 2;
+            let error = unsafe {
+                f(&mut size, self.name.as_mut_ptr(), self.vendor.as_ptr())
+            };
+            match error {
+                0 => break,
+                kernel_sys::EOVERFLOW if size > self.name.len() * 2 => {
+                    // The name so far must stay in place to resume from
+                    self.name.resize(size.div_ceil(2), 0);
+                }
+                kernel_sys::ENOENT => {
+                    self.done = true;
+                    return None;
+                }
+                error => {
+                    self.done = true;
+                    return Some(Err(
+                        Errno::from_raw(error).unwrap_or(Errno::Io)
+                    ));
+                }
+            }
+        }
+        let len = self.name.iter().position(|&c| c == 0);
+        let name = &self.name[..len.unwrap_or(self.name.len())];
+        Some(Ok((String::from_utf16_lossy(name), self.vendor)))
+    }
+}
+
+impl fmt::Debug for Variables {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Variables {{ done: {} }}", self.done)
+    }
+}
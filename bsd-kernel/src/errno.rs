@@ -0,0 +1,489 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Kernel error numbers
+
+use core::alloc::AllocError;
+use core::fmt;
+use libc::c_int;
+
+/// Error numbers from `sys/errno.h`, including the kernel-only pseudo
+/// errors used internally (`ERESTART` and friends)
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[repr(i32)]
+pub enum Errno {
+    /// Operation not permitted
+    Perm = kernel_sys::EPERM,
+    /// No such file or directory
+    NoEnt = kernel_sys::ENOENT,
+    /// No such process
+    Srch = kernel_sys::ESRCH,
+    /// Interrupted system call
+    Intr = kernel_sys::EINTR,
+    /// Input/output error
+    Io = kernel_sys::EIO,
+    /// Device not configured
+    NxIo = kernel_sys::ENXIO,
+    /// Argument list too long
+    TooBig = kernel_sys::E2BIG,
+    /// Exec format error
+    NoExec = kernel_sys::ENOEXEC,
+    /// Bad file descriptor
+    BadF = kernel_sys::EBADF,
+    /// No child processes
+    Child = kernel_sys::ECHILD,
+    /// Resource deadlock avoided
+    DeadLk = kernel_sys::EDEADLK,
+    /// Cannot allocate memory
+    NoMem = kernel_sys::ENOMEM,
+    /// Permission denied
+    Acces = kernel_sys::EACCES,
+    /// Bad address
+    Fault = kernel_sys::EFAULT,
+    /// Block device required
+    NotBlk = kernel_sys::ENOTBLK,
+    /// Device busy
+    Busy = kernel_sys::EBUSY,
+    /// File exists
+    Exist = kernel_sys::EEXIST,
+    /// Cross-device link
+    XDev = kernel_sys::EXDEV,
+    /// Operation not supported by device
+    NoDev = kernel_sys::ENODEV,
+    /// Not a directory
+    NotDir = kernel_sys::ENOTDIR,
+    /// Is a directory
+    IsDir = kernel_sys::EISDIR,
+    /// Invalid argument
+    Inval = kernel_sys::EINVAL,
+    /// Too many open files in system
+    NFile = kernel_sys::ENFILE,
+    /// Too many open files
+    MFile = kernel_sys::EMFILE,
+    /// Inappropriate ioctl for device
+    NotTy = kernel_sys::ENOTTY,
+    /// Text file busy
+    TxtBsy = kernel_sys::ETXTBSY,
+    /// File too large
+    FBig = kernel_sys::EFBIG,
+    /// No space left on device
+    NoSpc = kernel_sys::ENOSPC,
+    /// Illegal seek
+    SPipe = kernel_sys::ESPIPE,
+    /// Read-only file system
+    RoFs = kernel_sys::EROFS,
+    /// Too many links
+    MLink = kernel_sys::EMLINK,
+    /// Broken pipe
+    Pipe = kernel_sys::EPIPE,
+    /// Numerical argument out of domain
+    Dom = kernel_sys::EDOM,
+    /// Result too large
+    Range = kernel_sys::ERANGE,
+    /// Resource temporarily unavailable
+    Again = kernel_sys::EAGAIN,
+    /// Operation now in progress
+    InProgress = kernel_sys::EINPROGRESS,
+    /// Operation already in progress
+    Already = kernel_sys::EALREADY,
+    /// Socket operation on non-socket
+    NotSock = kernel_sys::ENOTSOCK,
+    /// Destination address required
+    DestAddrReq = kernel_sys::EDESTADDRREQ,
+    /// Message too long
+    MsgSize = kernel_sys::EMSGSIZE,
+    /// Protocol wrong type for socket
+    Prototype = kernel_sys::EPROTOTYPE,
+    /// Protocol not available
+    NoProtoOpt = kernel_sys::ENOPROTOOPT,
+    /// Protocol not supported
+    ProtoNoSupport = kernel_sys::EPROTONOSUPPORT,
+    /// Socket type not supported
+    SocktNoSupport = kernel_sys::ESOCKTNOSUPPORT,
+    /// Operation not supported
+    OpNotSupp = kernel_sys::EOPNOTSUPP,
+    /// Protocol family not supported
+    PfNoSupport = kernel_sys::EPFNOSUPPORT,
+    /// Address family not supported by protocol family
+    AfNoSupport = kernel_sys::EAFNOSUPPORT,
+    /// Address already in use
+    AddrInUse = kernel_sys::EADDRINUSE,
+    /// Can't assign requested address
+    AddrNotAvail = kernel_sys::EADDRNOTAVAIL,
+    /// Network is down
+    NetDown = kernel_sys::ENETDOWN,
+    /// Network is unreachable
+    NetUnreach = kernel_sys::ENETUNREACH,
+    /// Network dropped connection on reset
+    NetReset = kernel_sys::ENETRESET,
+    /// Software caused connection abort
+    ConnAborted = kernel_sys::ECONNABORTED,
+    /// Connection reset by peer
+    ConnReset = kernel_sys::ECONNRESET,
+    /// No buffer space available
+    NoBufs = kernel_sys::ENOBUFS,
+    /// Socket is already connected
+    IsConn = kernel_sys::EISCONN,
+    /// Socket is not connected
+    NotConn = kernel_sys::ENOTCONN,
+    /// Can't send after socket shutdown
+    Shutdown = kernel_sys::ESHUTDOWN,
+    /// Too many references: can't splice
+    TooManyRefs = kernel_sys::ETOOMANYREFS,
+    /// Operation timed out
+    TimedOut = kernel_sys::ETIMEDOUT,
+    /// Connection refused
+    ConnRefused = kernel_sys::ECONNREFUSED,
+    /// Too many levels of symbolic links
+    Loop = kernel_sys::ELOOP,
+    /// File name too long
+    NameTooLong = kernel_sys::ENAMETOOLONG,
+    /// Host is down
+    HostDown = kernel_sys::EHOSTDOWN,
+    /// No route to host
+    HostUnreach = kernel_sys::EHOSTUNREACH,
+    /// Directory not empty
+    NotEmpty = kernel_sys::ENOTEMPTY,
+    /// Too many processes
+    ProcLim = kernel_sys::EPROCLIM,
+    /// Too many users
+    Users = kernel_sys::EUSERS,
+    /// Disc quota exceeded
+    DQuot = kernel_sys::EDQUOT,
+    /// Stale NFS file handle
+    Stale = kernel_sys::ESTALE,
+    /// Too many levels of remote in path
+    Remote = kernel_sys::EREMOTE,
+    /// RPC struct is bad
+    BadRpc = kernel_sys::EBADRPC,
+    /// RPC version wrong
+    RpcMismatch = kernel_sys::ERPCMISMATCH,
+    /// RPC prog. not avail
+    ProgUnavail = kernel_sys::EPROGUNAVAIL,
+    /// Program version wrong
+    ProgMismatch = kernel_sys::EPROGMISMATCH,
+    /// Bad procedure for program
+    ProcUnavail = kernel_sys::EPROCUNAVAIL,
+    /// No locks available
+    NoLck = kernel_sys::ENOLCK,
+    /// Function not implemented
+    NoSys = kernel_sys::ENOSYS,
+    /// Inappropriate file type or format
+    FType = kernel_sys::EFTYPE,
+    /// Authentication error
+    Auth = kernel_sys::EAUTH,
+    /// Need authenticator
+    NeedAuth = kernel_sys::ENEEDAUTH,
+    /// Identifier removed
+    IdRm = kernel_sys::EIDRM,
+    /// No message of desired type
+    NoMsg = kernel_sys::ENOMSG,
+    /// Value too large to be stored in data type
+    Overflow = kernel_sys::EOVERFLOW,
+    /// Operation canceled
+    Canceled = kernel_sys::ECANCELED,
+    /// Illegal byte sequence
+    IlSeq = kernel_sys::EILSEQ,
+    /// Attribute not found
+    NoAttr = kernel_sys::ENOATTR,
+    /// Programming error
+    Doofus = kernel_sys::EDOOFUS,
+    /// Bad message
+    BadMsg = kernel_sys::EBADMSG,
+    /// Multihop attempted
+    MultiHop = kernel_sys::EMULTIHOP,
+    /// Link has been severed
+    NoLink = kernel_sys::ENOLINK,
+    /// Protocol error
+    Proto = kernel_sys::EPROTO,
+    /// Capabilities insufficient
+    NotCapable = kernel_sys::ENOTCAPABLE,
+    /// Not permitted in capability mode
+    CapMode = kernel_sys::ECAPMODE,
+    /// State not recoverable
+    NotRecoverable = kernel_sys::ENOTRECOVERABLE,
+    /// Previous owner died
+    OwnerDead = kernel_sys::EOWNERDEAD,
+    /// Integrity check failed
+    Integrity = kernel_sys::EINTEGRITY,
+    /// Restart syscall
+    Restart = kernel_sys::ERESTART,
+    /// Don't modify regs, just return
+    JustReturn = kernel_sys::EJUSTRETURN,
+    /// ioctl not handled by this layer
+    NoIoctl = kernel_sys::ENOIOCTL,
+    /// Do direct ioctl in GEOM
+    DirIoctl = kernel_sys::EDIRIOCTL,
+    /// Retry the directory lookup
+    ReLookup = kernel_sys::ERELOOKUP,
+}
+
+impl Errno {
+    /// Look up the `Errno` for a raw error number. Returns `None` for zero
+    /// and for values that are not defined in `sys/errno.h`
+    pub fn from_raw(n: c_int) -> Option<Errno> {
+        use Errno::*;
+        let e = match n {
+            kernel_sys::EPERM => Perm,
+            kernel_sys::ENOENT => NoEnt,
+            kernel_sys::ESRCH => Srch,
+            kernel_sys::EINTR => Intr,
+            kernel_sys::EIO => Io,
+            kernel_sys::ENXIO => NxIo,
+            kernel_sys::E2BIG => TooBig,
+            kernel_sys::ENOEXEC => NoExec,
+            kernel_sys::EBADF => BadF,
+            kernel_sys::ECHILD => Child,
+            kernel_sys::EDEADLK => DeadLk,
+            kernel_sys::ENOMEM => NoMem,
+            kernel_sys::EACCES => Acces,
+            kernel_sys::EFAULT => Fault,
+            kernel_sys::ENOTBLK => NotBlk,
+            kernel_sys::EBUSY => Busy,
+            kernel_sys::EEXIST => Exist,
+            kernel_sys::EXDEV => XDev,
+            kernel_sys::ENODEV => NoDev,
+            kernel_sys::ENOTDIR => NotDir,
+            kernel_sys::EISDIR => IsDir,
+            kernel_sys::EINVAL => Inval,
+            kernel_sys::ENFILE => NFile,
+            kernel_sys::EMFILE => MFile,
+            kernel_sys::ENOTTY => NotTy,
+            kernel_sys::ETXTBSY => TxtBsy,
+            kernel_sys::EFBIG => FBig,
+            kernel_sys::ENOSPC => NoSpc,
+            kernel_sys::ESPIPE => SPipe,
+            kernel_sys::EROFS => RoFs,
+            kernel_sys::EMLINK => MLink,
+            kernel_sys::EPIPE => Pipe,
+            kernel_sys::EDOM => Dom,
+            kernel_sys::ERANGE => Range,
+            kernel_sys::EAGAIN => Again,
+            kernel_sys::EINPROGRESS => InProgress,
+            kernel_sys::EALREADY => Already,
+            kernel_sys::ENOTSOCK => NotSock,
+            kernel_sys::EDESTADDRREQ => DestAddrReq,
+            kernel_sys::EMSGSIZE => MsgSize,
+            kernel_sys::EPROTOTYPE => Prototype,
+            kernel_sys::ENOPROTOOPT => NoProtoOpt,
+            kernel_sys::EPROTONOSUPPORT => ProtoNoSupport,
+            kernel_sys::ESOCKTNOSUPPORT => SocktNoSupport,
+            kernel_sys::EOPNOTSUPP => OpNotSupp,
+            kernel_sys::EPFNOSUPPORT => PfNoSupport,
+            kernel_sys::EAFNOSUPPORT => AfNoSupport,
+            kernel_sys::EADDRINUSE => AddrInUse,
+            kernel_sys::EADDRNOTAVAIL => AddrNotAvail,
+            kernel_sys::ENETDOWN => NetDown,
+            kernel_sys::ENETUNREACH => NetUnreach,
+            kernel_sys::ENETRESET => NetReset,
+            kernel_sys::ECONNABORTED => ConnAborted,
+            kernel_sys::ECONNRESET => ConnReset,
+            kernel_sys::ENOBUFS => NoBufs,
+            kernel_sys::EISCONN => IsConn,
+            kernel_sys::ENOTCONN => NotConn,
+            kernel_sys::ESHUTDOWN => Shutdown,
+            kernel_sys::ETOOMANYREFS => TooManyRefs,
+            kernel_sys::ETIMEDOUT => TimedOut,
+            kernel_sys::ECONNREFUSED => ConnRefused,
+            kernel_sys::ELOOP => Loop,
+            kernel_sys::ENAMETOOLONG => NameTooLong,
+            kernel_sys::EHOSTDOWN => HostDown,
+            kernel_sys::EHOSTUNREACH => HostUnreach,
+            kernel_sys::ENOTEMPTY => NotEmpty,
+            kernel_sys::EPROCLIM => ProcLim,
+            kernel_sys::EUSERS => Users,
+            kernel_sys::EDQUOT => DQuot,
+            kernel_sys::ESTALE => Stale,
+            kernel_sys::EREMOTE => Remote,
+            kernel_sys::EBADRPC => BadRpc,
+            kernel_sys::ERPCMISMATCH => RpcMismatch,
+            kernel_sys::EPROGUNAVAIL => ProgUnavail,
+            kernel_sys::EPROGMISMATCH => ProgMismatch,
+            kernel_sys::EPROCUNAVAIL => ProcUnavail,
+            kernel_sys::ENOLCK => NoLck,
+            kernel_sys::ENOSYS => NoSys,
+            kernel_sys::EFTYPE => FType,
+            kernel_sys::EAUTH => Auth,
+            kernel_sys::ENEEDAUTH => NeedAuth,
+            kernel_sys::EIDRM => IdRm,
+            kernel_sys::ENOMSG => NoMsg,
+            kernel_sys::EOVERFLOW => Overflow,
+            kernel_sys::ECANCELED => Canceled,
+            kernel_sys::EILSEQ => IlSeq,
+            kernel_sys::ENOATTR => NoAttr,
+            kernel_sys::EDOOFUS => Doofus,
+            kernel_sys::EBADMSG => BadMsg,
+            kernel_sys::EMULTIHOP => MultiHop,
+            kernel_sys::ENOLINK => NoLink,
+            kernel_sys::EPROTO => Proto,
+            kernel_sys::ENOTCAPABLE => NotCapable,
+            kernel_sys::ECAPMODE => CapMode,
+            kernel_sys::ENOTRECOVERABLE => NotRecoverable,
+            kernel_sys::EOWNERDEAD => OwnerDead,
+            kernel_sys::EINTEGRITY => Integrity,
+            kernel_sys::ERESTART => Restart,
+            kernel_sys::EJUSTRETURN => JustReturn,
+            kernel_sys::ENOIOCTL => NoIoctl,
+            kernel_sys::EDIRIOCTL => DirIoctl,
+            kernel_sys::ERELOOKUP => ReLookup,
+            _ => return None,
+        };
+        Some(e)
+    }
+
+    /// The raw error number, suitable for returning from a kernel callback
+    pub fn as_raw(self) -> c_int {
+        self as c_int
+    }
+
+    /// Convert the return code of a kernel function into a `Result`.
+    /// Unknown non-zero return codes are reported as `Errno::Io`
+    pub fn result(ret: c_int) -> Result<(), Errno> {
+        match ret {
+            0 => Ok(()),
+            n => Err(Errno::from_raw(n).unwrap_or(Errno::Io)),
+        }
+    }
+
+    /// Short description of the error, as printed by `strerror(3)`
+    pub fn description(self) -> &'static str {
+        use Errno::*;
+        match self {
+            Perm => "Operation not permitted",
+            NoEnt => "No such file or directory",
+            Srch => "No such process",
+            Intr => "Interrupted system call",
+            Io => "Input/output error",
+            NxIo => "Device not configured",
+            TooBig => "Argument list too long",
+            NoExec => "Exec format error",
+            BadF => "Bad file descriptor",
+            Child => "No child processes",
+            DeadLk => "Resource deadlock avoided",
+            NoMem => "Cannot allocate memory",
+            Acces => "Permission denied",
+            Fault => "Bad address",
+            NotBlk => "Block device required",
+            Busy => "Device busy",
+            Exist => "File exists",
+            XDev => "Cross-device link",
+            NoDev => "Operation not supported by device",
+            NotDir => "Not a directory",
+            IsDir => "Is a directory",
+            Inval => "Invalid argument",
+            NFile => "Too many open files in system",
+            MFile => "Too many open files",
+            NotTy => "Inappropriate ioctl for device",
+            TxtBsy => "Text file busy",
+            FBig => "File too large",
+            NoSpc => "No space left on device",
+            SPipe => "Illegal seek",
+            RoFs => "Read-only file system",
+            MLink => "Too many links",
+            Pipe => "Broken pipe",
+            Dom => "Numerical argument out of domain",
+            Range => "Result too large",
+            Again => "Resource temporarily unavailable",
+            InProgress => "Operation now in progress",
+            Already => "Operation already in progress",
+            NotSock => "Socket operation on non-socket",
+            DestAddrReq => "Destination address required",
+            MsgSize => "Message too long",
+            Prototype => "Protocol wrong type for socket",
+            NoProtoOpt => "Protocol not available",
+            ProtoNoSupport => "Protocol not supported",
+            SocktNoSupport => "Socket type not supported",
+            OpNotSupp => "Operation not supported",
+            PfNoSupport => "Protocol family not supported",
+            AfNoSupport => "Address family not supported by protocol family",
+            AddrInUse => "Address already in use",
+            AddrNotAvail => "Can't assign requested address",
+            NetDown => "Network is down",
+            NetUnreach => "Network is unreachable",
+            NetReset => "Network dropped connection on reset",
+            ConnAborted => "Software caused connection abort",
+            ConnReset => "Connection reset by peer",
+            NoBufs => "No buffer space available",
+            IsConn => "Socket is already connected",
+            NotConn => "Socket is not connected",
+            Shutdown => "Can't send after socket shutdown",
+            TooManyRefs => "Too many references: can't splice",
+            TimedOut => "Operation timed out",
+            ConnRefused => "Connection refused",
+            Loop => "Too many levels of symbolic links",
+            NameTooLong => "File name too long",
+            HostDown => "Host is down",
+            HostUnreach => "No route to host",
+            NotEmpty => "Directory not empty",
+            ProcLim => "Too many processes",
+            Users => "Too many users",
+            DQuot => "Disc quota exceeded",
+            Stale => "Stale NFS file handle",
+            Remote => "Too many levels of remote in path",
+            BadRpc => "RPC struct is bad",
+            RpcMismatch => "RPC version wrong",
+            ProgUnavail => "RPC prog. not avail",
+            ProgMismatch => "Program version wrong",
+            ProcUnavail => "Bad procedure for program",
+            NoLck => "No locks available",
+            NoSys => "Function not implemented",
+            FType => "Inappropriate file type or format",
+            Auth => "Authentication error",
+            NeedAuth => "Need authenticator",
+            IdRm => "Identifier removed",
+            NoMsg => "No message of desired type",
+            Overflow => "Value too large to be stored in data type",
+            Canceled => "Operation canceled",
+            IlSeq => "Illegal byte sequence",
+            NoAttr => "Attribute not found",
+            Doofus => "Programming error",
+            BadMsg => "Bad message",
+            MultiHop => "Multihop attempted",
+            NoLink => "Link has been severed",
+            Proto => "Protocol error",
+            NotCapable => "Capabilities insufficient",
+            CapMode => "Not permitted in capability mode",
+            NotRecoverable => "State not recoverable",
+            OwnerDead => "Previous owner died",
+            Integrity => "Integrity check failed",
+            Restart => "Restart syscall",
+            JustReturn => "Don't modify regs, just return",
+            NoIoctl => "ioctl not handled by this layer",
+            DirIoctl => "Do direct ioctl in GEOM",
+            ReLookup => "Retry the directory lookup",
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.description(), self.as_raw())
+    }
+}
+
+/// A failed `M_NOWAIT` allocation, from `KBox`, `KVec` or `KString`
+impl From<AllocError> for Errno {
+    fn from(_: AllocError) -> Self {
+        Errno::NoMem
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Kernel event hooks, see `EVENTHANDLER(9)`
+//!
+//! A registration owns its closure and deregisters when dropped, which
+//! waits for a running invocation to return first.
+
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::ffi::CStr;
+use core::fmt;
+use libc::{c_int, c_void};
+
+// From `vm/vm_pageout.h`, which can't be included on its own
+const VM_LOW_KMEM: c_int = 0x01;
+const VM_LOW_PAGES: c_int = 0x02;
+
+/// Why `vm_lowmem` fired
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Free pages are short and the page daemon is reclaiming. Caches
+    /// should trim what they can rebuild
+    Pages,
+    /// A kernel memory allocation failed for want of address space, so
+    /// anything held in kernel memory should be given back
+    Kmem,
+}
+
+impl Severity {
+    fn from_flags(flags: c_int) -> Self {
+        if flags & VM_LOW_KMEM != 0 {
+            Severity::Kmem
+        } else {
+            debug_assert!(flags & VM_LOW_PAGES != 0);
+            Severity::Pages
+        }
+    }
+}
+
+type LowMemFn = Box<dyn Fn(Severity) + Send + Sync>;
+
+const VM_LOWMEM: &CStr = c"vm_lowmem";
+
+/// A `vm_lowmem` hook, for modules that cache memory they could give back
+///
+/// The closure runs from the page daemon, or from a thread whose
+/// allocation failed, so it must not wait for memory itself; free
+/// without sleeping and return. Hooks run before UMA drains its own
+/// caches, so memory freed here can be reclaimed in the same pass.
+pub struct LowMem {
+    tag: kernel_sys::eventhandler_tag,
+    // The kernel holds a pointer to the inner box until deregistered
+    _f: Box<LowMemFn>,
+}
+
+unsafe impl Send for LowMem {}
+unsafe impl Sync for LowMem {}
+
+impl LowMem {
+    /// Call `f` whenever the system is low on memory. May sleep
+    pub fn register<F>(f: F) -> Self
+    where
+        F: Fn(Severity) + Send + Sync + 'static,
+    {
+        let f: Box<LowMemFn> = Box::new(Box::new(f));
+        let tag = unsafe {
+            kernel_sys::eventhandler_register(
+                core::ptr::null_mut(),
+                VM_LOWMEM.as_ptr(),
+                lowmem_handler as *mut c_void,
+                &raw const *f as *mut c_void,
+                kernel_sys::EVENTHANDLER_PRI_FIRST,
+            )
+        };
+        LowMem { tag, _f: f }
+    }
+}
+
+impl Drop for LowMem {
+    fn drop(&mut self) {
+        unsafe {
+            let list = kernel_sys::eventhandler_find_list(VM_LOWMEM.as_ptr());
+            // The list was created by registering, and lists are never freed
+            debug_assert!(!list.is_null());
+            if !list.is_null() {
+                // Waits for the handler, after which `f` can go
+                kernel_sys::eventhandler_deregister(list, self.tag);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for LowMem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LowMem {{ tag: {:?} }}", self.tag)
+    }
+}
+
+unsafe extern "C" fn lowmem_handler(arg: *mut c_void, flags: c_int) {
+    let f = unsafe { &*(arg as *const LowMemFn) };
+    let _ = catch_in_module(|| f(Severity::from_flags(flags)));
+}
+
+type CloneFn =
+    Box<dyn Fn(&CStr) -> Option<*mut kernel_sys::cdev> + Send + Sync>;
+
+const DEV_CLONE: &CStr = c"dev_clone";
+
+/// A `dev_clone` hook, which creates devices the first time their names
+/// are looked up under `/dev`, see `dev_clone(9)`
+///
+/// The closure is given the name, and returns the `CDev::as_ptr` of a
+/// device it made for it, keeping the `CDev` itself, or `None` for names
+/// that aren't its own. It may sleep.
+pub struct DevClone {
+    tag: kernel_sys::eventhandler_tag,
+    // The kernel holds a pointer to the inner box until deregistered
+    _f: Box<CloneFn>,
+}
+
+unsafe impl Send for DevClone {}
+unsafe impl Sync for DevClone {}
+
+impl DevClone {
+    pub fn register<F>(f: F) -> Self
+    where
+        F: Fn(&CStr) -> Option<*mut kernel_sys::cdev> + Send + Sync + 'static,
+    {
+        let f: Box<CloneFn> = Box::new(Box::new(f));
+        let tag = unsafe {
+            kernel_sys::eventhandler_register(
+                core::ptr::null_mut(),
+                DEV_CLONE.as_ptr(),
+                clone_handler as *mut c_void,
+                &raw const *f as *mut c_void,
+                // Ahead of the default, as drivers register it
+                1000,
+            )
+        };
+        DevClone { tag, _f: f }
+    }
+}
+
+impl Drop for DevClone {
+    fn drop(&mut self) {
+        unsafe {
+            let list = kernel_sys::eventhandler_find_list(DEV_CLONE.as_ptr());
+            debug_assert!(!list.is_null());
+            if !list.is_null() {
+                kernel_sys::eventhandler_deregister(list, self.tag);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for DevClone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DevClone {{ tag: {:?} }}", self.tag)
+    }
+}
+
+unsafe extern "C" fn clone_handler(
+    arg: *mut c_void,
+    _cred: *mut kernel_sys::ucred,
+    name: *mut libc::c_char,
+    namelen: c_int,
+    dev: *mut *mut kernel_sys::cdev,
+) {
+    // Another handler has already made it
+    if unsafe { !(*dev).is_null() } {
+        return;
+    }
+    let f = unsafe { &*(arg as *const CloneFn) };
+    let name = unsafe {
+        core::slice::from_raw_parts(name as *const u8, namelen as usize + 1)
+    };
+    let Ok(name) = CStr::from_bytes_with_nul(name) else {
+        return;
+    };
+    if let Ok(Some(cdev)) = catch_in_module(|| f(name)) {
+        // devfs releases the reference once it has the node
+        unsafe {
+            kernel_sys::dev_ref(cdev);
+            *dev = cdev;
+        }
+    }
+}
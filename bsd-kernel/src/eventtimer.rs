@@ -0,0 +1,213 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Event timers, see `eventtimers(4)`
+//!
+//! An event timer is hardware that interrupts after a programmed time,
+//! once or periodically, on which the kernel runs its clock: hardclock,
+//! statclock and callouts. A driver describes its timer with `Info`,
+//! registers it with `Timer::register`, and from its interrupt handler
+//! calls `Timer::fire` each time the timer expires. The kernel picks the
+//! timer of best quality, tunable with `kern.eventtimer.timer`, and
+//! programs it through the driver's `EventTimer` methods.
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::time::sbt_to_duration;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::ptr::NonNull;
+use core::time::Duration;
+use core::{fmt, mem};
+use libc::c_int;
+
+/// `ET_FLAGS_*` capabilities of a timer
+pub mod flags {
+    pub const PERIODIC: i32 = 1;
+    pub const ONESHOT: i32 = 2;
+    /// One timer per CPU, each interrupting its own CPU
+    pub const PERCPU: i32 = 4;
+    /// Stops in C3 sleep
+    pub const C3STOP: i32 = 8;
+    /// Periods must be a power of two times the base period
+    pub const POW2DIV: i32 = 16;
+}
+
+/// How a timer presents itself
+#[derive(Copy, Clone, Debug)]
+pub struct Info {
+    pub name: &'static CStr,
+    /// `flags` bits
+    pub flags: i32,
+    /// Higher is preferred; the LAPIC timer's is 600 and the HPET's 450
+    pub quality: i32,
+    /// Counting frequency in Hz, 0 if the timer isn't counter based
+    pub frequency: u64,
+    /// The shortest and longest times that can be programmed
+    pub min_period: Duration,
+    pub max_period: Duration,
+}
+
+/// The hardware side of a timer. `start` and `stop` apply to the CPU
+/// they're called on for a `PERCPU` timer, and run in a spinlock section
+pub trait EventTimer: Send + Sync + 'static {
+    /// Interrupt after `first`, then every `period` if set, or every
+    /// `period` from now if `first` is zero
+    fn start(
+        &self,
+        first: Duration,
+        period: Option<Duration>,
+    ) -> Result<(), Errno>;
+
+    fn stop(&self) -> Result<(), Errno>;
+}
+
+#[repr(C)]
+struct Inner<T> {
+    et: UnsafeCell<kernel_sys::eventtimer>,
+    timer: T,
+}
+
+/// A registered timer. Deregistered when dropped, or left registered,
+/// and leaked, if the kernel refuses because it's in use
+pub struct Timer<T: EventTimer> {
+    inner: NonNull<Inner<T>>,
+}
+
+unsafe impl<T: EventTimer> Send for Timer<T> {}
+unsafe impl<T: EventTimer> Sync for Timer<T> {}
+
+impl<T: EventTimer> Timer<T> {
+    pub fn register(info: Info, timer: T) -> Result<Self, Errno> {
+        let mut et: kernel_sys::eventtimer = unsafe { mem::zeroed() };
+        et.et_name = info.name.as_ptr() as *mut libc::c_char;
+        et.et_flags = info.flags;
+        et.et_quality = info.quality;
+        et.et_frequency = info.frequency;
+        et.et_min_period = crate::time::duration_to_sbt(info.min_period);
+        et.et_max_period = crate::time::duration_to_sbt(info.max_period);
+        et.et_start = Some(start::<T>);
+        et.et_stop = Some(stop::<T>);
+        let inner = Box::into_raw(Box::new(Inner {
+            et: UnsafeCell::new(et),
+            timer,
+        }));
+        let error = unsafe {
+            (*(*inner).et.get()).et_priv = inner.cast();
+            kernel_sys::et_register((*inner).et.get())
+        };
+        if let Err(e) = Errno::result(error) {
+            drop(unsafe { Box::from_raw(inner) });
+            return Err(e);
+        }
+        Ok(Timer {
+            inner: NonNull::new(inner).unwrap(),
+        })
+    }
+
+    /// Deregister the timer, failing with `Errno::Busy` while the kernel
+    /// runs its clock on it
+    pub fn deregister(self) -> Result<(), (Errno, Self)> {
+        match Errno::result(unsafe { kernel_sys::et_deregister(self.as_ptr()) })
+        {
+            Ok(()) => {
+                drop(unsafe { Box::from_raw(self.inner.as_ptr()) });
+                mem::forget(self);
+                Ok(())
+            }
+            Err(e) => Err((e, self)),
+        }
+    }
+
+    /// Report that the timer expired, from its interrupt handler on the
+    /// CPU it interrupted
+    pub fn fire(&self) {
+        let et = self.as_ptr();
+        unsafe {
+            if (*et).et_active != 0 {
+                if let Some(cb) = (*et).et_event_cb {
+                    cb(et, (*et).et_arg);
+                }
+            }
+        }
+    }
+
+    /// Whether the kernel is using the timer
+    pub fn is_active(&self) -> bool {
+        unsafe { (*self.as_ptr()).et_active != 0 }
+    }
+
+    pub fn timer(&self) -> &T {
+        unsafe { &self.inner.as_ref().timer }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::eventtimer {
+        unsafe { self.inner.as_ref().et.get() }
+    }
+}
+
+impl<T: EventTimer> Drop for Timer<T> {
+    fn drop(&mut self) {
+        if unsafe { kernel_sys::et_deregister(self.as_ptr()) } == 0 {
+            drop(unsafe { Box::from_raw(self.inner.as_ptr()) });
+        }
+    }
+}
+
+impl<T: EventTimer> fmt::Debug for Timer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = unsafe { CStr::from_ptr((*self.as_ptr()).et_name) };
+        write!(
+            f,
+            "Timer {{ name: {:?}, active: {} }}",
+            name,
+            self.is_active()
+        )
+    }
+}
+
+unsafe fn timer<'a, T>(et: *mut kernel_sys::eventtimer) -> &'a T {
+    unsafe { &(*((*et).et_priv as *const Inner<T>)).timer }
+}
+
+unsafe extern "C" fn start<T: EventTimer>(
+    et: *mut kernel_sys::eventtimer,
+    first: kernel_sys::sbintime_t,
+    period: kernel_sys::sbintime_t,
+) -> c_int {
+    let t = unsafe { timer::<T>(et) };
+    let period = (period != 0).then(|| sbt_to_duration(period));
+    catch_in_module(|| t.start(sbt_to_duration(first), period))
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+unsafe extern "C" fn stop<T: EventTimer>(
+    et: *mut kernel_sys::eventtimer,
+) -> c_int {
+    let t = unsafe { timer::<T>(et) };
+    catch_in_module(|| t.stop())
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
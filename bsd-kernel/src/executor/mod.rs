@@ -0,0 +1,465 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! A small async runtime for kernel code
+//!
+//! An `Executor` polls `'static` futures on the threads of a
+//! `taskqueue(9)`. Each spawned future owns a kernel `struct task`, and
+//! waking the future enqueues that task, so an idle future costs nothing
+//! until something wakes it. Wakers may be stored anywhere and invoked from
+//! any context that may acquire a default mutex, e.g. callouts or
+//! interrupt threads; `AtomicWaker` provides a lock-free slot for them.
+//!
+//! `block_on` runs a single future to completion on the calling thread,
+//! sleeping on a condition variable whenever the future is pending.
+//!
+//! `sleep` and `timeout` provide delays and deadlines backed by callouts,
+//! so timed waits don't tie up a thread, and `Notify` stands in for a
+//! `Condvar`, letting a future wait for a completion signalled from
+//! elsewhere:
+//!
+//! ```rust,ignore
+//! // In `GeomClass::done`, on the geom's I/O path
+//! *sc.finished.lock() = Some(bio);
+//! sc.done.notify_one();
+//!
+//! // In the worker future
+//! let cbp = sc.done.wait_while(&sc.finished, |f| f.is_none()).await.take();
+//! ```
+
+pub use self::notify::{Notified, Notify};
+pub use self::timer::{Elapsed, Sleep, Timeout, sleep, timeout};
+pub use self::waker::AtomicWaker;
+
+use crate::errno::Errno;
+use crate::panic::module_poisoned;
+use crate::sync::{Condvar, Mutex};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::future::Future;
+use core::pin::{Pin, pin};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::{fmt, mem, ptr};
+use libc::{c_int, c_void};
+
+mod notify;
+mod timer;
+mod waker;
+
+struct Inner {
+    // The taskqueue's enqueue function is handed a pointer to this field,
+    // so it must not move once the queue is created
+    tq: UnsafeCell<*mut kernel_sys::taskqueue>,
+    owned: bool,
+    live: Mutex<usize>,
+    idle: Condvar,
+}
+
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+impl Inner {
+    fn new(owned: bool) -> Arc<Self> {
+        Arc::new(Inner {
+            tq: UnsafeCell::new(ptr::null_mut()),
+            owned,
+            live: Mutex::new(c"executor", 0),
+            idle: Condvar::new(c"execidle"),
+        })
+    }
+
+    fn taskqueue(&self) -> *mut kernel_sys::taskqueue {
+        unsafe { *self.tq.get() }
+    }
+
+    fn task_done(&self) {
+        let mut live = self.live.lock();
+        *live -= 1;
+        if *live == 0 {
+            self.idle.notify_all();
+        }
+    }
+}
+
+/// Runs futures on the threads of a taskqueue
+///
+/// Dropping the executor waits for every future spawned on it to finish
+/// (or be aborted through its `JoinHandle`), so a module can keep its
+/// executor in its module state and rely on unload draining it.
+pub struct Executor {
+    inner: Arc<Inner>,
+}
+
+impl Executor {
+    /// Create an executor with its own taskqueue, served by `threads`
+    /// kernel threads named after `name`
+    pub fn new(name: &'static CStr, threads: usize) -> Result<Self, Errno> {
+        let threads =
+            c_int::try_from(threads.max(1)).map_err(|_| Errno::Inval)?;
+        let inner = Inner::new(true);
+        let tqp = inner.tq.get();
+        unsafe {
+            let tq = kernel_sys::taskqueue_create(
+                name.as_ptr(),
+                kernel_sys::M_WAITOK,
+                Some(kernel_sys::taskqueue_thread_enqueue),
+                tqp as *mut c_void,
+            );
+            if tq.is_null() {
+                return Err(Errno::NoMem);
+            }
+            *tqp = tq;
+            let ret = kernel_sys::taskqueue_start_threads(
+                tqp,
+                threads,
+                kernel_sys::PWAIT,
+                c"%s".as_ptr(),
+                name.as_ptr(),
+            );
+            if let Err(e) = Errno::result(ret) {
+                kernel_sys::taskqueue_free(tq);
+                return Err(e);
+            }
+        }
+        Ok(Executor { inner })
+    }
+
+    /// An executor that runs futures on the shared system taskqueue
+    /// (`taskqueue_thread`). Futures run this way should not block for
+    /// long, as they hold up every other user of the queue
+    pub fn system() -> Self {
+        let inner = Inner::new(false);
+        unsafe { *inner.tq.get() = kernel_sys::taskqueue_thread };
+        Executor { inner }
+    }
+
+    /// Start running `future` in the background, returning a handle that
+    /// can be awaited or joined for its output
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        *self.inner.live.lock() += 1;
+        let join = Arc::new(Join::new());
+        let task = Arc::new(Task {
+            ktask: UnsafeCell::new(unsafe { mem::zeroed() }),
+            state: AtomicU8::new(IDLE),
+            cancelled: AtomicBool::new(false),
+            future: UnsafeCell::new(Some(future)),
+            join: join.clone(),
+            exec: self.inner.clone(),
+        });
+        unsafe {
+            // TASK_INIT(&task->ktask, 0, run_task, task)
+            let kt = &mut *task.ktask.get();
+            kt.ta_pending = 0;
+            kt.ta_priority = 0;
+            kt.ta_func = Some(run_task::<F>);
+            kt.ta_context = Arc::as_ptr(&task) as *mut c_void;
+        }
+        task.clone().wake();
+        JoinHandle { join, task }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        let live = self.inner.live.lock();
+        drop(self.inner.idle.wait_while(live, |n| *n > 0));
+        // The last task is still in run_task after it counted itself done.
+        // Freeing the queue waits for it; the shared queue has to be
+        // drained, or unloading could pull the code from under it
+        if self.inner.owned {
+            unsafe { kernel_sys::taskqueue_free(self.inner.taskqueue()) };
+        } else {
+            unsafe { kernel_sys::taskqueue_drain_all(self.inner.taskqueue()) };
+        }
+    }
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Executor {{ tq: {:?} }}", self.inner.taskqueue())
+    }
+}
+
+// Task states. A task is enqueued on the taskqueue only on the transition
+// to SCHEDULED, so it is never queued twice or polled concurrently
+const IDLE: u8 = 0;
+const SCHEDULED: u8 = 1;
+const RUNNING: u8 = 2;
+const NOTIFIED: u8 = 3;
+const DONE: u8 = 4;
+
+struct Task<F: Future> {
+    ktask: UnsafeCell<kernel_sys::task>,
+    state: AtomicU8,
+    cancelled: AtomicBool,
+    // Only touched by run_task, which the state machine serializes
+    future: UnsafeCell<Option<F>>,
+    join: Arc<Join<F::Output>>,
+    exec: Arc<Inner>,
+}
+
+unsafe impl<F: Future + Send> Send for Task<F> {}
+unsafe impl<F: Future + Send> Sync for Task<F> {}
+
+impl<F: Future> Task<F> {
+    fn enqueue(self: &Arc<Self>) {
+        // The queued task owns a reference, released by run_task
+        mem::forget(self.clone());
+        unsafe {
+            kernel_sys::taskqueue_enqueue(
+                self.exec.taskqueue(),
+                self.ktask.get(),
+            );
+        }
+    }
+
+    fn finish(&self, output: Option<F::Output>) {
+        unsafe { *self.future.get() = None };
+        self.state.store(DONE, Ordering::Release);
+        self.join.complete(output);
+        self.exec.task_done();
+    }
+}
+
+impl<F> Wake for Task<F>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            let next = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                _ => return,
+            };
+            match self.state.compare_exchange_weak(
+                state,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) if next == SCHEDULED => return self.enqueue(),
+                Ok(_) => return,
+                Err(s) => state = s,
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn run_task<F>(context: *mut c_void, _pending: c_int)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let task = unsafe { Arc::from_raw(context as *const Task<F>) };
+    task.state.store(RUNNING, Ordering::Release);
+    // Nothing more of a module that has panicked is run
+    if task.cancelled.load(Ordering::Acquire) || module_poisoned() {
+        task.finish(None);
+        return;
+    }
+    let waker = Waker::from(task.clone());
+    let mut cx = Context::from_waker(&waker);
+    let poll = unsafe {
+        match &mut *task.future.get() {
+            // The future lives inside the Arc and is never moved
+            Some(f) => Pin::new_unchecked(f).poll(&mut cx),
+            None => return,
+        }
+    };
+    match poll {
+        Poll::Ready(output) => task.finish(Some(output)),
+        Poll::Pending => {
+            if task
+                .state
+                .compare_exchange(
+                    RUNNING,
+                    IDLE,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_err()
+            {
+                // Woken while being polled
+                task.state.store(SCHEDULED, Ordering::Release);
+                task.enqueue();
+            }
+        }
+    }
+}
+
+trait Abort: Send + Sync {
+    fn abort(self: Arc<Self>);
+}
+
+impl<F> Abort for Task<F>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn abort(self: Arc<Self>) {
+        self.cancelled.store(true, Ordering::Release);
+        self.wake();
+    }
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    finished: bool,
+    waker: Option<Waker>,
+}
+
+struct Join<T> {
+    state: Mutex<JoinState<T>>,
+    done: Condvar,
+}
+
+impl<T> Join<T> {
+    fn new() -> Self {
+        Join {
+            state: Mutex::new(
+                c"join",
+                JoinState {
+                    output: None,
+                    finished: false,
+                    waker: None,
+                },
+            ),
+            done: Condvar::new(c"join"),
+        }
+    }
+
+    fn complete(&self, output: Option<T>) {
+        let mut state = self.state.lock();
+        state.output = output;
+        state.finished = true;
+        if let Some(w) = state.waker.take() {
+            w.wake();
+        }
+        self.done.notify_all();
+    }
+}
+
+/// The future was aborted before it completed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Cancelled;
+
+/// Handle to a spawned future. Awaiting it (or calling `join`) yields the
+/// future's output; dropping it lets the future keep running detached
+pub struct JoinHandle<T> {
+    join: Arc<Join<T>>,
+    task: Arc<dyn Abort>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Ask for the future to be dropped without being polled again. Has no
+    /// effect if it already completed
+    pub fn abort(&self) {
+        self.task.clone().abort();
+    }
+
+    /// Check whether the future has completed or been aborted
+    pub fn is_finished(&self) -> bool {
+        self.join.state.lock().finished
+    }
+
+    /// Sleep until the future completes, returning its output
+    pub fn join(self) -> Result<T, Cancelled> {
+        let state = self.join.state.lock();
+        let mut state = self.join.done.wait_while(state, |s| !s.finished);
+        state.output.take().ok_or(Cancelled)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Cancelled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.join.state.lock();
+        if state.finished {
+            return Poll::Ready(state.output.take().ok_or(Cancelled));
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JoinHandle {{ finished: {} }}", self.is_finished())
+    }
+}
+
+struct Parker {
+    notified: Mutex<bool>,
+    cv: Condvar,
+}
+
+impl Wake for Parker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        *self.notified.lock() = true;
+        self.cv.notify_one();
+    }
+}
+
+/// Run `future` to completion on the calling thread, sleeping whenever it
+/// is pending until its waker is invoked
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let parker = Arc::new(Parker {
+        notified: Mutex::new(c"blockon", false),
+        cv: Condvar::new(c"blockon"),
+    });
+    let waker = Waker::from(parker.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        let notified = parker.notified.lock();
+        let mut notified = parker.cv.wait_while(notified, |n| !*n);
+        *notified = false;
+    }
+}
+
+/// A boxed, type-erased future, for storing futures of different types
+/// (e.g. per-request state machines) in one collection
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + 'static>>;
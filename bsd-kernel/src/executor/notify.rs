@@ -0,0 +1,178 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! An async condition variable
+
+use super::AtomicWaker;
+use crate::sync::{Mutex, MutexGuard};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::ffi::CStr;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use core::{fmt, mem};
+
+struct Slot {
+    notified: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Slot {
+    fn notify(&self) {
+        self.notified.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+/// The counterpart of `sync::Condvar` for futures, which wait on it
+/// without holding up a thread
+///
+/// Notifying never sleeps, so it may be done from `bio` completion,
+/// callouts or interrupt threads. As with a `Condvar`, notifications sent
+/// while nobody waits are lost, so futures wait for a condition on data
+/// behind a `Mutex` with `wait_while`.
+pub struct Notify {
+    waiters: Mutex<VecDeque<Arc<Slot>>>,
+}
+
+impl Notify {
+    /// Create a `Notify` whose lock is named `name`
+    pub fn new(name: &'static CStr) -> Self {
+        Notify {
+            waiters: Mutex::new(name, VecDeque::new()),
+        }
+    }
+
+    /// A future completing on the next `notify_one` or `notify_all`. It
+    /// waits from when it is created rather than first polled, so a
+    /// notification between checking a condition and awaiting isn't missed
+    pub fn notified(&self) -> Notified<'_> {
+        let slot = Arc::new(Slot {
+            notified: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        self.waiters.lock().push_back(slot.clone());
+        Notified {
+            notify: self,
+            slot,
+            done: false,
+        }
+    }
+
+    /// Wait until `condition` returns false for the data behind `mutex`,
+    /// as `Condvar::wait_while`, returning with the lock held
+    pub async fn wait_while<'a, T, F>(
+        &self,
+        mutex: &'a Mutex<T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        loop {
+            let notified = self.notified();
+            {
+                let mut guard = mutex.lock();
+                if !condition(&mut *guard) {
+                    return guard;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Wake the longest waiting future, if any
+    pub fn notify_one(&self) {
+        let slot = self.waiters.lock().pop_front();
+        if let Some(slot) = slot {
+            slot.notify();
+        }
+    }
+
+    /// Wake every waiting future
+    pub fn notify_all(&self) {
+        let waiters = mem::take(&mut *self.waiters.lock());
+        for slot in waiters {
+            slot.notify();
+        }
+    }
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Notify {{ waiters: {} }}", self.waiters.lock().len())
+    }
+}
+
+/// Future returned by `Notify::notified`
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    slot: Arc<Slot>,
+    /// Returned `Ready`, taking the notification
+    done: bool,
+}
+
+impl Notified<'_> {
+    fn is_notified(&self) -> bool {
+        self.slot.notified.load(Ordering::Acquire)
+    }
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_notified() {
+            self.slot.waker.register(cx.waker());
+            if !self.is_notified() {
+                return Poll::Pending;
+            }
+        }
+        self.done = true;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.notify.waiters.lock();
+        let queued = waiters.len();
+        waiters.retain(|s| !Arc::ptr_eq(s, &self.slot));
+        // Dropped after a notify_one picked it, without acting on it: pass
+        // the notification on rather than lose it
+        if queued == waiters.len() && self.is_notified() && !self.done {
+            if let Some(next) = waiters.pop_front() {
+                drop(waiters);
+                next.notify();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Notified<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Notified {{ notified: {} }}", self.is_notified())
+    }
+}
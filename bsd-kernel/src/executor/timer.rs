@@ -0,0 +1,175 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Timer futures backed by `callout(9)`
+
+use super::AtomicWaker;
+use crate::time::duration_to_sbt;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use core::{fmt, mem};
+use libc::c_void;
+
+struct Timer {
+    callout: UnsafeCell<kernel_sys::callout>,
+    fired: AtomicBool,
+    waker: AtomicWaker,
+}
+
+unsafe impl Send for Timer {}
+unsafe impl Sync for Timer {}
+
+unsafe extern "C" fn timer_fire(arg: *mut c_void) {
+    // Takes back the reference handed to the callout when it was armed
+    let timer = unsafe { Arc::from_raw(arg as *const Timer) };
+    timer.fired.store(true, Ordering::Release);
+    timer.waker.wake();
+}
+
+/// Future returned by `sleep`, which completes once its duration has
+/// elapsed
+pub struct Sleep {
+    timer: Arc<Timer>,
+}
+
+/// Wait for `duration` to elapse. The callout is armed immediately, so the
+/// time spent before first polling the future counts towards the delay
+pub fn sleep(duration: Duration) -> Sleep {
+    let timer = Arc::new(Timer {
+        callout: UnsafeCell::new(unsafe { mem::zeroed() }),
+        fired: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    unsafe {
+        kernel_sys::callout_init(timer.callout.get(), 1);
+        kernel_sys::callout_reset_sbt_on(
+            timer.callout.get(),
+            duration_to_sbt(duration),
+            0,
+            Some(timer_fire),
+            Arc::into_raw(timer.clone()) as *mut c_void,
+            -1,
+            0,
+        );
+    }
+    Sleep { timer }
+}
+
+impl Sleep {
+    /// Check whether the timer has fired
+    pub fn is_elapsed(&self) -> bool {
+        self.timer.fired.load(Ordering::Acquire)
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_elapsed() {
+            return Poll::Ready(());
+        }
+        self.timer.waker.register(cx.waker());
+        if self.is_elapsed() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // Drain rather than stop, so the callout function is not still
+        // running on another CPU once we return
+        let stopped = unsafe {
+            kernel_sys::_callout_stop_safe(
+                self.timer.callout.get(),
+                kernel_sys::CS_DRAIN,
+            )
+        };
+        if stopped > 0 {
+            // Cancelled before it ran; release the callout's reference
+            unsafe { Arc::decrement_strong_count(Arc::as_ptr(&self.timer)) };
+        }
+    }
+}
+
+impl fmt::Debug for Sleep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sleep {{ elapsed: {} }}", self.is_elapsed())
+    }
+}
+
+/// The deadline of a `timeout` passed before its future completed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+/// Future returned by `timeout`
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Run `future`, giving up once `duration` has elapsed. The future is
+/// dropped without completing if the deadline passes first
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The inner future is structurally pinned; Sleep is Unpin
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(v) = future.poll(cx) {
+            return Poll::Ready(Ok(v));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F> fmt::Debug for Timeout<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timeout {{ sleep: {:?} }}", self.sleep)
+    }
+}
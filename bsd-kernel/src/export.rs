@@ -0,0 +1,146 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Versioned C ABIs exported to other kernel modules
+//!
+//! A module offers an API as one `#[repr(C)]` table of `extern "C"` entry
+//! points that starts with an `ApiHeader`, published under a fixed symbol
+//! with `export_api!`. To make the symbol reachable from other modules:
+//!
+//! * list it in the module's Makefile as `EXPORT_SYMS= <symbol>`, as
+//!   `bsd.kmod.mk` otherwise makes every symbol local;
+//! * declare `MODULE_VERSION(<module>, <major>)` in the module's C file,
+//!   bumping the major version whenever the table changes incompatibly.
+//!
+//! Consumers, C or Rust, then declare `MODULE_DEPEND(<consumer>, <module>,
+//! <major>, <major>, <major>)` so the kernel loads the provider first and
+//! refuses mismatched majors, reference the symbol directly, and check the
+//! header with `ApiHeader::supports` before calling anything added in a
+//! later minor version. Rust consumers can do both with `import`, or find
+//! the table at runtime with `linker::with_api` if the provider is
+//! optional.
+//!
+//! Entry points are calls into the module like any other, so they should
+//! run their Rust side through `panic::catch_in_module`.
+
+use crate::errno::Errno;
+use core::fmt;
+
+/// The start of every exported table
+#[repr(C)]
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct ApiHeader {
+    /// Bumped, along with `MODULE_VERSION`, by incompatible changes
+    pub major: u16,
+    /// Bumped when entry points are appended to the table
+    pub minor: u16,
+    /// Size of the whole table in bytes
+    pub size: u32,
+}
+
+impl ApiHeader {
+    /// The header of a table of type `T`
+    pub const fn new<T>(major: u16, minor: u16) -> Self {
+        ApiHeader {
+            major,
+            minor,
+            size: size_of::<T>() as u32,
+        }
+    }
+
+    /// Whether the table is compatible with `major` and has everything
+    /// introduced up to `minor`
+    pub fn supports(&self, major: u16, minor: u16) -> bool {
+        self.major == major && self.minor >= minor
+    }
+}
+
+impl fmt::Debug for ApiHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ApiHeader {{ version: {}.{}, size: {} }}",
+            self.major, self.minor, self.size
+        )
+    }
+}
+
+/// A table of entry points exported with `export_api!`
+///
+/// # Safety
+/// The type must be `#[repr(C)]` with an `ApiHeader` as its first field,
+/// and entry points may only ever be appended to it.
+pub unsafe trait Api: Sync + 'static {
+    /// The major version this definition of the table has
+    const MAJOR: u16;
+    /// The minor version this definition of the table has
+    const MINOR: u16;
+
+    /// The header every table starts with
+    fn header(&self) -> &ApiHeader {
+        unsafe { &*(self as *const Self as *const ApiHeader) }
+    }
+}
+
+/// Check that `table`, another module's export, provides everything this
+/// module's definition of `T` has, and return it
+///
+/// Fails with `Errno::ProgMismatch` if the major versions differ, and with
+/// `Errno::ProcUnavail` if the table is older than `T::MINOR`.
+pub fn import<T: Api>(table: &T) -> Result<&T, Errno> {
+    let header = table.header();
+    if header.major != T::MAJOR {
+        return Err(Errno::ProgMismatch);
+    }
+    if !header.supports(T::MAJOR, T::MINOR)
+        || (header.size as usize) < size_of::<T>()
+    {
+        return Err(Errno::ProcUnavail);
+    }
+    Ok(table)
+}
+
+/// Publish `$value`, the table of an `Api`, under the unmangled symbol
+/// `$sym` for other modules to link against
+///
+/// ```rust,ignore
+/// bsd_kernel::export_api! {
+///     /// Version 1.0 of the fifo API
+///     rustfifo_api: FifoApi = FifoApi {
+///         header: ApiHeader::new::<FifoApi>(1, 0),
+///         buffered,
+///     };
+/// }
+/// ```
+#[macro_export]
+macro_rules! export_api {
+    ($(#[$attr:meta])* $sym:ident: $ty:ty = $value:expr;) => {
+        $(#[$attr])*
+        #[unsafe(no_mangle)]
+        pub static $sym: $ty = {
+            const fn is_api<T: $crate::export::Api>() {}
+            is_api::<$ty>();
+            $value
+        };
+    };
+}
@@ -0,0 +1,170 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::{Bio, BioCmd};
+use crate::errno::Errno;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt;
+use libc::{c_int, c_void};
+
+/// The value of an attribute, borrowed from the softc where it isn't a
+/// plain number
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Attr<'a> {
+    Int(i32),
+    Off(i64),
+    U16(u16),
+    /// A string, copied out if it fits the request
+    Str(&'a CStr),
+    /// Raw bytes, which the request must be exactly the length of
+    Bytes(&'a [u8]),
+}
+
+type Getter<S> = Box<dyn Fn(&S) -> Attr<'_> + Send + Sync>;
+
+enum Value<S> {
+    Get(Getter<S>),
+    Str(CString),
+}
+
+/// A table of `BIO_GETATTR` answers, by attribute name
+///
+/// Built once, such as when the geom is created, and consulted from
+/// `start`:
+/// ```rust,ignore
+/// let Some(bio) = sc.attrs.handle(sc, bio) else {
+///     return; // answered
+/// };
+/// ```
+pub struct Attributes<S> {
+    getters: Vec<(&'static CStr, Value<S>)>,
+}
+
+impl<S> Attributes<S> {
+    pub fn new() -> Self {
+        Attributes {
+            getters: Vec::new(),
+        }
+    }
+
+    /// Answer `name`, e.g. `c"GEOM::candelete"`, with what `f` returns
+    pub fn with<F>(mut self, name: &'static CStr, f: F) -> Self
+    where
+        F: Fn(&S) -> Attr<'_> + Send + Sync + 'static,
+    {
+        self.getters.push((name, Value::Get(Box::new(f))));
+        self
+    }
+
+    /// Answer `name` with a fixed string
+    pub fn with_str(mut self, name: &'static CStr, value: CString) -> Self {
+        self.getters.push((name, Value::Str(value)));
+        self
+    }
+
+    /// Answer `GEOM::ident`, the serial number `/dev/diskid` names and
+    /// `zpool` device labels are made from. It should survive reboots and
+    /// moving the disk elsewhere
+    pub fn ident(self, ident: CString) -> Self {
+        self.with_str(c"GEOM::ident", ident)
+    }
+
+    /// Answer `GEOM::physpath`, where the disk sits, such as its enclosure
+    /// slot. ZFS records it so a replacement disk in the same slot can take
+    /// over automatically. Use `Provider::attr_changed` if it moves
+    pub fn physpath(self, physpath: CString) -> Self {
+        self.with_str(c"GEOM::physpath", physpath)
+    }
+
+    /// Answer `GEOM::lunid`, the logical unit's world wide name
+    pub fn lunid(self, lunid: CString) -> Self {
+        self.with_str(c"GEOM::lunid", lunid)
+    }
+
+    /// Deliver `bio` if it asks for an attribute in the table, failing
+    /// it with `EFAULT` if its length doesn't fit the value, the way
+    /// `g_handleattr(9)` does. Any other bio is handed back
+    pub fn handle(&self, softc: &S, bio: Bio) -> Option<Bio> {
+        if bio.cmd() != BioCmd::GetAttr {
+            return Some(bio);
+        }
+        let Some(attr) = bio.attribute() else {
+            return Some(bio);
+        };
+        let Some((name, v)) = self.getters.iter().find(|(n, _)| *n == attr)
+        else {
+            return Some(bio);
+        };
+        let value = match v {
+            Value::Get(f) => f(softc),
+            Value::Str(s) => Attr::Str(s),
+        };
+        let (int, off, short);
+        // A length of 0 asks for string handling
+        let (ptr, len): (*const c_void, usize) = match value {
+            Attr::Int(v) => {
+                int = v;
+                (&raw const int as _, size_of::<i32>())
+            }
+            Attr::Off(v) => {
+                off = v;
+                (&raw const off as _, size_of::<i64>())
+            }
+            Attr::U16(v) => {
+                short = v;
+                (&raw const short as _, size_of::<u16>())
+            }
+            Attr::Str(s) => (s.as_ptr() as _, 0),
+            Attr::Bytes(b) => (b.as_ptr() as _, b.len()),
+        };
+        let Ok(len) = c_int::try_from(len) else {
+            bio.deliver(Err(Errno::Fault));
+            return None;
+        };
+        let handled = unsafe {
+            kernel_sys::g_handleattr(bio.as_ptr(), name.as_ptr(), ptr, len)
+        };
+        if handled != 0 {
+            let _ = bio.into_raw();
+            None
+        } else {
+            Some(bio)
+        }
+    }
+}
+
+impl<S> Default for Attributes<S> {
+    fn default() -> Self {
+        Attributes::new()
+    }
+}
+
+impl<S> fmt::Debug for Attributes<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = self.getters.iter().map(|(n, _)| *n).collect();
+        write!(f, "Attributes {{ names: {:?} }}", names)
+    }
+}
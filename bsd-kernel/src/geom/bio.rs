@@ -0,0 +1,437 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::errno::Errno;
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicI32, AtomicI64, AtomicU32, Ordering};
+use core::{fmt, ptr};
+use libc::c_void;
+
+/// The operation a bio requests
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BioCmd {
+    Read,
+    Write,
+    Delete,
+    GetAttr,
+    Flush,
+    Zone,
+    Speedup,
+    /// Anything else, such as the driver-private `BIO_CMD0`-`BIO_CMD2`
+    Other(u16),
+}
+
+impl BioCmd {
+    pub(crate) fn from_raw(cmd: u16) -> Self {
+        match i32::from(cmd) {
+            kernel_sys::BIO_READ => BioCmd::Read,
+            kernel_sys::BIO_WRITE => BioCmd::Write,
+            kernel_sys::BIO_DELETE => BioCmd::Delete,
+            kernel_sys::BIO_GETATTR => BioCmd::GetAttr,
+            kernel_sys::BIO_FLUSH => BioCmd::Flush,
+            kernel_sys::BIO_ZONE => BioCmd::Zone,
+            kernel_sys::BIO_SPEEDUP => BioCmd::Speedup,
+            _ => BioCmd::Other(cmd),
+        }
+    }
+
+    fn as_raw(self) -> u16 {
+        let cmd = match self {
+            BioCmd::Read => kernel_sys::BIO_READ,
+            BioCmd::Write => kernel_sys::BIO_WRITE,
+            BioCmd::Delete => kernel_sys::BIO_DELETE,
+            BioCmd::GetAttr => kernel_sys::BIO_GETATTR,
+            BioCmd::Flush => kernel_sys::BIO_FLUSH,
+            BioCmd::Zone => kernel_sys::BIO_ZONE,
+            BioCmd::Speedup => kernel_sys::BIO_SPEEDUP,
+            BioCmd::Other(cmd) => return cmd,
+        };
+        cmd as u16
+    }
+}
+
+/// What a `BioCmd::Speedup` request asks to hurry along
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Speedup {
+    /// Writes already sent, such as those held in a write cache
+    pub writes: bool,
+    /// Deletes already sent, so the space they free becomes usable
+    pub trims: bool,
+}
+
+/// An I/O request travelling through GEOM, see `g_bio(9)`
+///
+/// A `Bio` is consumed by passing it down with `Geom::request` or
+/// completing it with `deliver`. Dropping one leaves its issuer waiting
+/// forever.
+#[must_use = "a bio must be passed on or delivered"]
+pub struct Bio {
+    bp: ptr::NonNull<kernel_sys::bio>,
+}
+
+unsafe impl Send for Bio {}
+
+impl Bio {
+    /// Allocate a blank request for a geom to issue on its own account,
+    /// sleeping until memory is available, so not from `start` or `done`
+    pub fn alloc(cmd: BioCmd) -> Bio {
+        let bp = unsafe { kernel_sys::g_alloc_bio() };
+        let mut bio = Bio {
+            bp: ptr::NonNull::new(bp).unwrap(),
+        };
+        bio.raw_mut().bio_cmd = cmd.as_raw();
+        bio
+    }
+
+    /// Take charge of a raw bio
+    ///
+    /// ## Safety
+    /// `bp` must be a live bio that nothing else will complete or free
+    pub unsafe fn from_raw(bp: *mut kernel_sys::bio) -> Self {
+        Bio {
+            bp: ptr::NonNull::new(bp).unwrap(),
+        }
+    }
+
+    /// Give up the bio without completing it
+    pub fn into_raw(self) -> *mut kernel_sys::bio {
+        self.bp.as_ptr()
+    }
+
+    /// Raw pointer to the underlying bio
+    pub fn as_ptr(&self) -> *mut kernel_sys::bio {
+        self.bp.as_ptr()
+    }
+
+    fn raw(&self) -> &kernel_sys::bio {
+        unsafe { self.bp.as_ref() }
+    }
+
+    fn raw_mut(&mut self) -> &mut kernel_sys::bio {
+        unsafe { self.bp.as_mut() }
+    }
+
+    /// The requested operation
+    pub fn cmd(&self) -> BioCmd {
+        BioCmd::from_raw(self.raw().bio_cmd)
+    }
+
+    /// Byte offset of the request in the provider
+    pub fn offset(&self) -> i64 {
+        self.raw().bio_offset
+    }
+
+    pub fn set_offset(&mut self, offset: i64) {
+        self.raw_mut().bio_offset = offset;
+    }
+
+    /// Requested length in bytes
+    pub fn length(&self) -> i64 {
+        self.raw().bio_length
+    }
+
+    pub fn set_length(&mut self, length: i64) {
+        self.raw_mut().bio_length = length;
+    }
+
+    /// Bytes transferred so far
+    pub fn completed(&self) -> i64 {
+        self.raw().bio_completed
+    }
+
+    pub fn set_completed(&mut self, completed: i64) {
+        self.raw_mut().bio_completed = completed;
+    }
+
+    /// What a `BioCmd::Speedup` request covers. Its `length()` is the
+    /// number of bytes the issuer would like to see freed
+    pub fn speedup(&self) -> Speedup {
+        let flags = i32::from(self.raw().bio_flags);
+        Speedup {
+            writes: flags & kernel_sys::BIO_SPEEDUP_WRITE != 0,
+            trims: flags & kernel_sys::BIO_SPEEDUP_TRIM != 0,
+        }
+    }
+
+    /// Whether the data buffer has no kernel mapping
+    pub fn is_unmapped(&self) -> bool {
+        i32::from(self.raw().bio_flags) & kernel_sys::BIO_UNMAPPED != 0
+    }
+
+    /// Whether the request is a barrier: it may not start until everything
+    /// sent before it has completed, and nothing sent after it may start
+    /// until it has. `g_io_flush(9)` sends ordered `BioCmd::Flush` requests
+    pub fn is_ordered(&self) -> bool {
+        i32::from(self.raw().bio_flags) & kernel_sys::BIO_ORDERED != 0
+    }
+
+    pub fn set_ordered(&mut self, ordered: bool) {
+        let flag = kernel_sys::BIO_ORDERED as u16;
+        if ordered {
+            self.raw_mut().bio_flags |= flag;
+        } else {
+            self.raw_mut().bio_flags &= !flag;
+        }
+    }
+
+    /// The data buffer, `length()` bytes long, or `None` for unmapped bios
+    pub fn data(&self) -> Option<*mut u8> {
+        if self.is_unmapped() || self.raw().bio_data.is_null() {
+            None
+        } else {
+            Some(self.raw().bio_data as *mut u8)
+        }
+    }
+
+    /// Point the bio at a different buffer, such as part of the parent's
+    /// when splitting a request
+    pub fn set_data(&mut self, data: *mut u8) {
+        self.raw_mut().bio_data = data as kernel_sys::caddr_t;
+    }
+
+    /// The attribute queried by a `BioCmd::GetAttr` request
+    pub fn attribute(&self) -> Option<&CStr> {
+        let attr = self.raw().bio_attribute;
+        if attr.is_null() {
+            None
+        } else {
+            Some(unsafe { CStr::from_ptr(attr) })
+        }
+    }
+
+    /// The error recorded by the lower layer, if any
+    pub fn error(&self) -> Option<Errno> {
+        match self.raw().bio_error {
+            0 => None,
+            e => Some(Errno::from_raw(e).unwrap_or(Errno::Io)),
+        }
+    }
+
+    /// Replace the recorded error, e.g. to hide a failure that was
+    /// recovered from before finishing with `std_done`
+    pub fn set_error(&mut self, error: Option<Errno>) {
+        self.raw_mut().bio_error = error.map_or(0, Errno::as_raw);
+    }
+
+    /// Field for the issuer's private use
+    pub fn caller1(&self) -> *mut c_void {
+        self.raw().bio_caller1
+    }
+
+    pub fn set_caller1(&mut self, value: *mut c_void) {
+        self.raw_mut().bio_caller1 = value;
+    }
+
+    /// Second field for the issuer's private use
+    pub fn caller2(&self) -> *mut c_void {
+        self.raw().bio_caller2
+    }
+
+    pub fn set_caller2(&mut self, value: *mut c_void) {
+        self.raw_mut().bio_caller2 = value;
+    }
+
+    /// Create a child request with the same command, range and data,
+    /// whose completion is accounted to this one by `std_done`, and which
+    /// is ordered or unmapped if this one is. Returns `None` when out of
+    /// memory, which the caller usually reports by delivering this bio
+    /// with `Errno::NoMem`
+    pub fn clone_bio(&self) -> Option<Bio> {
+        let cbp = unsafe { kernel_sys::g_clone_bio(self.as_ptr()) };
+        ptr::NonNull::new(cbp).map(|bp| Bio { bp })
+    }
+
+    /// Create an independent copy with the same command, range and data,
+    /// see `g_duplicate_bio(9)`. Completing it is up to the caller; it is
+    /// not accounted to this one. Sleeps until memory is available, so not
+    /// from `start` or `done`
+    pub fn duplicate(&self) -> Bio {
+        let bp = unsafe { kernel_sys::g_duplicate_bio(self.as_ptr()) };
+        Bio {
+            bp: ptr::NonNull::new(bp).unwrap(),
+        }
+    }
+
+    /// Cut a clone in two: this one keeps the first `offset` bytes, and
+    /// the rest is returned as a new clone of the same parent, so the
+    /// parent is delivered by `std_done` only once both are in. Like
+    /// `clone_bio`, all the splitting must be done before any piece is
+    /// sent. Returns `None` when out of memory, leaving this one intact
+    ///
+    /// ## Panics
+    /// If this bio is not a clone, is unmapped, or `offset` is not
+    /// strictly between zero and `length()`
+    pub fn split_at(&mut self, offset: i64) -> Option<Bio> {
+        let parent = self.raw().bio_parent;
+        assert!(!parent.is_null(), "only clones can be split");
+        assert!(!self.is_unmapped(), "unmapped bios can't be split");
+        assert!(offset > 0 && offset < self.length(), "split out of range");
+        let bp = unsafe { kernel_sys::g_clone_bio(parent) };
+        let mut rest = Bio {
+            bp: ptr::NonNull::new(bp)?,
+        };
+        rest.set_offset(self.offset() + offset);
+        rest.set_length(self.length() - offset);
+        if let Some(data) = self.data() {
+            rest.set_data(unsafe { data.add(offset as usize) });
+        }
+        rest.set_caller1(self.caller1());
+        rest.set_caller2(self.caller2());
+        self.set_length(offset);
+        Some(rest)
+    }
+
+    /// Free a bio that was never sent, such as a clone left over when
+    /// splitting a request fails part way
+    pub fn destroy(self) {
+        unsafe { kernel_sys::g_destroy_bio(self.into_raw()) };
+    }
+
+    /// Complete the request towards its issuer, see `g_io_deliver(9)`
+    pub fn deliver(self, result: Result<(), Errno>) {
+        let error = match result {
+            Ok(()) => 0,
+            Err(e) => e.as_raw(),
+        };
+        unsafe { kernel_sys::g_io_deliver(self.into_raw(), error) };
+    }
+
+    /// Finish a child created by `clone_bio`: record its error and byte
+    /// count in the parent, free it, and deliver the parent once all its
+    /// children are in. Unlike `g_std_done(9)`, siblings may finish at the
+    /// same time on different threads, as they do under `DirectDispatch`
+    pub fn std_done(self) {
+        let bp = self.into_raw();
+        unsafe {
+            let parent = (*bp).bio_parent;
+            if (*bp).bio_error != 0 {
+                let error = AtomicI32::from_ptr(&raw mut (*parent).bio_error);
+                let _ = error.compare_exchange(
+                    0,
+                    (*bp).bio_error,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+            AtomicI64::from_ptr(&raw mut (*parent).bio_completed)
+                .fetch_add((*bp).bio_completed, Ordering::Relaxed);
+            let inbed = AtomicU32::from_ptr(&raw mut (*parent).bio_inbed)
+                .fetch_add(1, Ordering::AcqRel);
+            kernel_sys::g_destroy_bio(bp);
+            if inbed + 1 == (*parent).bio_children {
+                kernel_sys::g_io_deliver(parent, (*parent).bio_error);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Bio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Bio {{ cmd: {:?}, offset: {}, length: {} }}",
+            self.cmd(),
+            self.offset(),
+            self.length()
+        )
+    }
+}
+
+/// Bios waiting to be sent, linked through their queue entry so building
+/// the pieces of a split request doesn't allocate
+///
+/// Bios still on the list when it is dropped are destroyed.
+pub struct BioList {
+    head: Option<Bio>,
+    tail: *mut kernel_sys::bio,
+    len: usize,
+}
+
+unsafe impl Send for BioList {}
+
+impl BioList {
+    pub fn new() -> Self {
+        BioList {
+            head: None,
+            tail: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add `bio` at the end
+    pub fn push_back(&mut self, bio: Bio) {
+        let bp = bio.into_raw();
+        unsafe { (*bp).bio_queue.tqe_next = ptr::null_mut() };
+        if self.head.is_none() {
+            self.head = Some(unsafe { Bio::from_raw(bp) });
+        } else {
+            unsafe { (*self.tail).bio_queue.tqe_next = bp };
+        }
+        self.tail = bp;
+        self.len += 1;
+    }
+
+    /// The first bio, left on the list
+    pub fn front(&self) -> Option<&Bio> {
+        self.head.as_ref()
+    }
+
+    /// Take the first bio
+    pub fn pop_front(&mut self) -> Option<Bio> {
+        let bio = self.head.take()?;
+        let next = unsafe { (*bio.as_ptr()).bio_queue.tqe_next };
+        if !next.is_null() {
+            self.head = Some(unsafe { Bio::from_raw(next) });
+        }
+        self.len -= 1;
+        Some(bio)
+    }
+}
+
+impl Default for BioList {
+    fn default() -> Self {
+        BioList::new()
+    }
+}
+
+impl Drop for BioList {
+    fn drop(&mut self) {
+        while let Some(bio) = self.pop_front() {
+            bio.destroy();
+        }
+    }
+}
+
+impl fmt::Debug for BioList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BioList {{ len: {} }}", self.len)
+    }
+}
@@ -0,0 +1,673 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::{Bio, BioCmd, Conf, ConfPart, Consumer, CtlReq, Provider};
+use crate::Module;
+use crate::errno::Errno;
+use crate::panic::{Poison, catch_at_boundary};
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::{fmt, mem, ptr};
+use libc::{c_int, c_void};
+
+/// The behaviour of a GEOM class
+///
+/// Apart from `start` and `done`, every callback runs on the GEOM event
+/// thread with the topology lock held.
+pub trait GeomClass: Sized + Send + Sync + 'static {
+    /// Class name, as listed by `geom -t`
+    const NAME: &'static CStr;
+
+    /// Per-geom state, reachable from every callback through `Geom::softc`.
+    /// The class is never called for a geom without one
+    type Softc: Send + Sync;
+
+    /// Called once the class is registered, before any provider is
+    /// tasted. May create geoms that need nothing below them, such as
+    /// memory disks
+    fn init(_class: &Class<Self>) {}
+
+    /// Offered each new provider, including when the class is loaded. May
+    /// create a geom stacked on `pp` with `Class::new_geom`
+    fn taste(_class: &Class<Self>, _pp: Provider) -> Option<Geom<Self>> {
+        None
+    }
+
+    /// Handle a control request from userland, such as `create` or
+    /// `destroy` verbs for classes configured through `geom(8)`. Failure is
+    /// reported with `CtlReq::error`
+    fn ctlreq(_class: &Class<Self>, req: &mut CtlReq, _verb: &CStr) {
+        req.error("Unknown verb.");
+    }
+
+    /// Handle a request sent to one of `gp`'s providers, other than
+    /// `BioCmd::Speedup`. Must not sleep
+    fn start(&self, gp: Geom<Self>, bio: Bio);
+
+    /// Asked by a layer above, usually a filesystem short of space, to
+    /// hurry along the writes or deletes given by `Bio::speedup`, see
+    /// `g_io_speedup(9)`. Must not sleep. By default refused with
+    /// `Errno::OpNotSupp`, which issuers take as nothing to be done
+    fn speedup(&self, _gp: Geom<Self>, bio: Bio) {
+        bio.deliver(Err(Errno::OpNotSupp));
+    }
+
+    /// Called when a bio passed down with `Geom::request` completes. Must
+    /// not sleep
+    fn done(&self, _gp: Geom<Self>, bio: Bio) {
+        bio.std_done();
+    }
+
+    /// Adjust the access counts of one of `gp`'s providers by the given
+    /// deltas. By default these are forwarded to the geom's consumer
+    fn access(
+        &self,
+        gp: Geom<Self>,
+        _pp: Provider,
+        dr: i32,
+        dw: i32,
+        de: i32,
+    ) -> Result<(), Errno> {
+        match gp.consumer() {
+            Some(cp) => cp.access(dr, dw, de),
+            None => Err(Errno::NxIo),
+        }
+    }
+
+    /// The provider below `cp` has gone away. By default the geom withers
+    fn orphan(&self, gp: Geom<Self>, cp: Consumer) {
+        let error = cp.provider().and_then(|pp| pp.error());
+        gp.wither(error.unwrap_or(Errno::NxIo));
+    }
+
+    /// Describe `gp`, or one of its consumers or providers, for
+    /// `geom list` and `kern.geom.confxml`. Must not sleep
+    fn dumpconf(&self, _gp: Geom<Self>, _conf: &mut Conf, _part: ConfPart) {}
+
+    /// Asked to destroy `gp`, such as when the class is unloaded. By
+    /// default refuses while any provider is open
+    fn destroy(&self, gp: Geom<Self>) -> Result<(), Errno> {
+        if gp.providers().any(|pp| pp.is_open()) {
+            return Err(Errno::Busy);
+        }
+        gp.wither(Errno::NxIo);
+        Ok(())
+    }
+}
+
+/// A class whose `start` and `done` may be called directly by the geoms
+/// above and below it, instead of only from the `g_down` and `g_up`
+/// threads. Enables `Geom::new_direct_provider` and
+/// `Geom::new_direct_consumer`
+///
+/// ## Safety
+///
+/// `start` and `done` must be safe to run on any thread that issues or
+/// completes a bio, which may already hold non-sleepable locks: they must
+/// never sleep, and must not hold a lock of their own across
+/// `Geom::request`, `Bio::deliver` or `Bio::std_done`, since those may
+/// recurse straight into a neighbouring geom and back
+pub unsafe trait DirectDispatch: GeomClass {}
+
+/// A GEOM class backed by a `GeomClass` implementation
+///
+/// The class must stay at the same address while it is loaded, so it is
+/// only ever handed out boxed.
+#[repr(C)]
+pub struct Class<T: GeomClass> {
+    // First, so callbacks can get from the g_class back to the Class
+    class: UnsafeCell<kernel_sys::g_class>,
+    inner: T,
+    poison: Poison,
+}
+
+unsafe impl<T: GeomClass> Send for Class<T> {}
+unsafe impl<T: GeomClass> Sync for Class<T> {}
+
+impl<T: GeomClass> Class<T> {
+    /// Create the class, not yet known to GEOM
+    pub fn new(inner: T) -> Box<Self> {
+        let mut class: kernel_sys::g_class = unsafe { mem::zeroed() };
+        class.name = T::NAME.as_ptr();
+        class.version = kernel_sys::G_VERSION;
+        class.init = Some(init::<T>);
+        class.taste = Some(taste::<T>);
+        class.ctlreq = Some(ctlreq::<T>);
+        class.destroy_geom = Some(destroy_geom::<T>);
+        class.start = Some(start::<T>);
+        class.spoiled = Some(kernel_sys::g_std_spoiled);
+        class.access = Some(access::<T>);
+        class.orphan = Some(orphan::<T>);
+        class.dumpconf = Some(dumpconf::<T>);
+        class.providergone = Some(providergone::<T>);
+        Box::new(Class {
+            class: UnsafeCell::new(class),
+            inner,
+            poison: Poison::new(),
+        })
+    }
+
+    /// Whether a callback has panicked, after which GEOM's calls into the
+    /// class are refused, failing I/O with `Errno::NxIo`
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    /// Raw pointer to the underlying g_class
+    pub fn as_ptr(&self) -> *mut kernel_sys::g_class {
+        self.class.get()
+    }
+
+    /// Recover the class from its g_class
+    ///
+    /// ## Safety
+    /// `mp` must point into a live `Class<T>`
+    unsafe fn from_raw<'a>(mp: *mut kernel_sys::g_class) -> &'a Self {
+        unsafe { &*(mp as *const Self) }
+    }
+
+    fn modevent(&self, module: Module, what: u32) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::g_modevent(
+                module,
+                what as c_int,
+                self.as_ptr() as *mut c_void,
+            )
+        })
+    }
+
+    /// Register the class with GEOM, which then tastes every existing
+    /// provider. Call from the module's load event, without the topology
+    /// lock held
+    pub fn load(&self, module: Module) -> Result<(), Errno> {
+        self.modevent(module, kernel_sys::modeventtype_MOD_LOAD)
+    }
+
+    /// Destroy every geom of the class and unregister it. Fails, leaving
+    /// the class loaded, if a geom refuses to go
+    pub fn unload(&self, module: Module) -> Result<(), Errno> {
+        self.modevent(module, kernel_sys::modeventtype_MOD_UNLOAD)
+    }
+
+    /// Create a geom of this class named `name`, owning `softc`
+    ///
+    /// Needs the topology lock, as held during `taste`.
+    pub fn new_geom(&self, name: &CStr, softc: T::Softc) -> Geom<T> {
+        let gp = self.alloc_geom(name);
+        unsafe { gp.set_softc(softc) };
+        gp
+    }
+
+    /// Like `new_geom`, but builds the softc from the new geom so it can
+    /// hold the geom's consumers and providers. The geom has no softc
+    /// until this returns. If `f` fails, the geom is withered
+    pub fn new_geom_with<F>(&self, name: &CStr, f: F) -> Result<Geom<T>, Errno>
+    where
+        F: FnOnce(Geom<T>) -> Result<T::Softc, Errno>,
+    {
+        let gp = self.alloc_geom(name);
+        match f(gp) {
+            Ok(softc) => {
+                unsafe { gp.set_softc(softc) };
+                Ok(gp)
+            }
+            Err(e) => {
+                gp.wither(e);
+                Err(e)
+            }
+        }
+    }
+
+    fn alloc_geom(&self, name: &CStr) -> Geom<T> {
+        let gp = unsafe {
+            kernel_sys::g_new_geomf(
+                self.as_ptr(),
+                c"%s".as_ptr(),
+                name.as_ptr(),
+            )
+        };
+        unsafe { Geom::from_raw(gp) }
+    }
+
+    /// Iterate over the class's geoms. Needs the topology lock
+    pub fn geoms(&self) -> impl Iterator<Item = Geom<T>> + '_ {
+        let mut gp = unsafe { (*self.as_ptr()).geom.lh_first };
+        core::iter::from_fn(move || {
+            if gp.is_null() {
+                return None;
+            }
+            let next = unsafe { Geom::from_raw(gp) };
+            gp = unsafe { (*gp).geom.le_next };
+            Some(next)
+        })
+    }
+}
+
+impl<T: GeomClass> Deref for Class<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: GeomClass + fmt::Debug> fmt::Debug for Class<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Class {{ name: {:?}, inner: {:?} }}",
+            T::NAME,
+            self.inner
+        )
+    }
+}
+
+/// A geom of class `T`, owned by the GEOM framework
+pub struct Geom<T: GeomClass> {
+    gp: ptr::NonNull<kernel_sys::g_geom>,
+    _class: PhantomData<fn() -> T>,
+}
+
+unsafe impl<T: GeomClass> Send for Geom<T> {}
+unsafe impl<T: GeomClass> Sync for Geom<T> {}
+
+impl<T: GeomClass> Clone for Geom<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: GeomClass> Copy for Geom<T> {}
+
+impl<T: GeomClass> Geom<T> {
+    /// ## Safety
+    /// `gp` must be a geom created by `Class::<T>::new_geom`
+    unsafe fn from_raw(gp: *mut kernel_sys::g_geom) -> Self {
+        Geom {
+            gp: ptr::NonNull::new(gp).unwrap(),
+            _class: PhantomData,
+        }
+    }
+
+    /// Raw pointer to the underlying g_geom
+    pub fn as_ptr(&self) -> *mut kernel_sys::g_geom {
+        self.gp.as_ptr()
+    }
+
+    fn raw(&self) -> &kernel_sys::g_geom {
+        unsafe { self.gp.as_ref() }
+    }
+
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.raw().name) }
+    }
+
+    /// The class this geom belongs to
+    pub fn class(&self) -> &Class<T> {
+        unsafe { Class::from_raw(self.raw().class) }
+    }
+
+    /// The state passed to `Class::new_geom`. It lives until the geom has
+    /// neither providers nor consumers left, so nothing can call into the
+    /// class for it any more
+    ///
+    /// ## Safety
+    /// The geom must have its softc: it doesn't yet inside the closure
+    /// passed to `Class::new_geom_with`, nor any more once withered and
+    /// reaped. It always does in the `GeomClass` callbacks for it
+    pub unsafe fn softc(&self) -> &T::Softc {
+        unsafe { &*(self.raw().softc as *const T::Softc) }
+    }
+
+    /// Whether the geom has its softc, which the class is only called for
+    pub fn has_softc(&self) -> bool {
+        !self.raw().softc.is_null()
+    }
+
+    /// ## Safety
+    /// The geom must not have a softc yet
+    unsafe fn set_softc(&self, softc: T::Softc) {
+        let softc = Box::into_raw(Box::new(softc)) as *mut c_void;
+        unsafe { (*self.as_ptr()).softc = softc };
+    }
+
+    /// Whether the geom is being torn down
+    pub fn is_withering(&self) -> bool {
+        self.raw().flags & kernel_sys::G_GEOM_WITHER != 0
+    }
+
+    /// Create a provider named `name` that others can attach to. It stays
+    /// hidden until the current event, such as `taste`, returns
+    pub fn new_provider(&self, name: &CStr) -> Provider {
+        unsafe {
+            Provider::from_raw(kernel_sys::g_new_providerf(
+                self.as_ptr(),
+                c"%s".as_ptr(),
+                name.as_ptr(),
+            ))
+        }
+    }
+
+    /// Create a consumer, to be attached to a provider below this geom
+    pub fn new_consumer(&self) -> Consumer {
+        unsafe { Consumer::from_raw(kernel_sys::g_new_consumer(self.as_ptr())) }
+    }
+
+    /// The first of the geom's consumers
+    pub fn consumer(&self) -> Option<Consumer> {
+        let cp = self.raw().consumer.lh_first;
+        (!cp.is_null()).then(|| unsafe { Consumer::from_raw(cp) })
+    }
+
+    /// The first of the geom's providers
+    pub fn provider(&self) -> Option<Provider> {
+        self.providers().next()
+    }
+
+    /// Iterate over the geom's providers
+    pub fn providers(&self) -> impl Iterator<Item = Provider> + use<T> {
+        let mut pp = self.raw().provider.lh_first;
+        core::iter::from_fn(move || {
+            if pp.is_null() {
+                return None;
+            }
+            let next = unsafe { Provider::from_raw(pp) };
+            pp = unsafe { (*pp).provider.le_next };
+            Some(next)
+        })
+    }
+
+    /// Pass `bio` down through `cp`, calling `GeomClass::done` when it
+    /// completes. Usually `bio` is a clone of the request being started
+    pub fn request(&self, bio: Bio, cp: Consumer) {
+        let bp = bio.into_raw();
+        unsafe {
+            (*bp).bio_done = Some(done::<T>);
+            kernel_sys::g_io_request(bp, cp.as_ptr());
+        }
+    }
+
+    /// Start tearing the geom down: its providers are orphaned with
+    /// `error`, and the geom is destroyed once they are all closed. The
+    /// softc is freed later, from its own event, so it may still be used
+    /// until the caller returns
+    pub fn wither(&self, error: Errno) {
+        unsafe {
+            kernel_sys::g_wither_geom(self.as_ptr(), error.as_raw());
+            reap_later::<T>(self.as_ptr());
+        }
+    }
+}
+
+impl<T: DirectDispatch> Geom<T> {
+    /// Like `new_provider`, but requests may call `start` directly from the
+    /// issuing thread, and completions are delivered directly to consumers
+    /// that accept them
+    pub fn new_direct_provider(&self, name: &CStr) -> Provider {
+        let pp = self.new_provider(name);
+        let flags =
+            kernel_sys::G_PF_DIRECT_SEND | kernel_sys::G_PF_DIRECT_RECEIVE;
+        unsafe { (*pp.as_ptr()).flags |= flags as u32 };
+        pp
+    }
+
+    /// Like `new_consumer`, but requests are passed directly to providers
+    /// that accept them, and completions may call `done` directly from the
+    /// completing thread
+    pub fn new_direct_consumer(&self) -> Consumer {
+        let cp = self.new_consumer();
+        let flags =
+            kernel_sys::G_CF_DIRECT_SEND | kernel_sys::G_CF_DIRECT_RECEIVE;
+        unsafe { (*cp.as_ptr()).flags |= flags };
+        cp
+    }
+}
+
+impl<T: GeomClass> fmt::Debug for Geom<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Geom {{ name: {:?} }}", self.name())
+    }
+}
+
+unsafe fn free_softc<T: GeomClass>(gp: *mut kernel_sys::g_geom) {
+    let softc = unsafe { mem::replace(&mut (*gp).softc, ptr::null_mut()) };
+    if !softc.is_null() {
+        drop(unsafe { Box::from_raw(softc as *mut T::Softc) });
+    }
+}
+
+/// Queue `reap` for a withering geom with no providers left, so that its
+/// softc is freed once its consumers are gone too. Needs the topology lock
+unsafe fn reap_later<T: GeomClass>(gp: *mut kernel_sys::g_geom) {
+    let withering = unsafe { (*gp).flags } & kernel_sys::G_GEOM_WITHER != 0;
+    if !withering
+        || unsafe { (*gp).softc.is_null() }
+        || !unsafe { (*gp).provider.lh_first.is_null() }
+    {
+        return;
+    }
+    // Naming the geom has the event cancelled when the geom is destroyed
+    unsafe {
+        kernel_sys::g_post_event(
+            Some(reap::<T>),
+            gp as *mut c_void,
+            kernel_sys::M_WAITOK,
+            gp as *mut c_void,
+            ptr::null_mut::<c_void>(),
+        );
+    }
+}
+
+/// Free the softc of a withering geom once nothing can call into the class
+/// for it: destroy its consumers as the washer would, unless one is still
+/// open, in which case a later `reap_later` tries again. If the washer got
+/// there first, the geom's destruction cancels the event, and the softc is
+/// freed then
+unsafe extern "C" fn reap<T: GeomClass>(arg: *mut c_void, flag: c_int) {
+    let gp = arg as *mut kernel_sys::g_geom;
+    if flag != kernel_sys::EV_CANCEL as c_int {
+        if !unsafe { (*gp).provider.lh_first.is_null() } {
+            return;
+        }
+        let mut cp = unsafe { (*gp).consumer.lh_first };
+        while !cp.is_null() {
+            let c = unsafe { &*cp };
+            if c.acr != 0 || c.acw != 0 || c.ace != 0 {
+                return;
+            }
+            cp = c.consumer.le_next;
+        }
+        loop {
+            let cp = unsafe { (*gp).consumer.lh_first };
+            if cp.is_null() {
+                break;
+            }
+            unsafe {
+                if !(*cp).provider.is_null() {
+                    kernel_sys::g_detach(cp);
+                }
+                kernel_sys::g_destroy_consumer(cp);
+            }
+        }
+    }
+    unsafe { free_softc::<T>(gp) };
+}
+
+unsafe extern "C" fn init<T: GeomClass>(mp: *mut kernel_sys::g_class) {
+    let class = unsafe { Class::<T>::from_raw(mp) };
+    let _ = catch_at_boundary(&class.poison, || T::init(class));
+}
+
+unsafe extern "C" fn taste<T: GeomClass>(
+    mp: *mut kernel_sys::g_class,
+    pp: *mut kernel_sys::g_provider,
+    _flags: c_int,
+) -> *mut kernel_sys::g_geom {
+    // Never stack on our own providers
+    if unsafe { (*(*pp).geom).class } == mp {
+        return ptr::null_mut();
+    }
+    let class = unsafe { Class::<T>::from_raw(mp) };
+    let pp = unsafe { Provider::from_raw(pp) };
+    catch_at_boundary(&class.poison, || T::taste(class, pp))
+        .ok()
+        .flatten()
+        .map_or(ptr::null_mut(), |gp| gp.as_ptr())
+}
+
+unsafe extern "C" fn ctlreq<T: GeomClass>(
+    req: *mut kernel_sys::gctl_req,
+    mp: *mut kernel_sys::g_class,
+    verb: *const libc::c_char,
+) {
+    let class = unsafe { Class::<T>::from_raw(mp) };
+    let mut req = unsafe { CtlReq::from_raw(req) };
+    let verb = unsafe { CStr::from_ptr(verb) };
+    if let Err(e) =
+        catch_at_boundary(&class.poison, || T::ctlreq(class, &mut req, verb))
+    {
+        req.error(e.description());
+    }
+}
+
+unsafe extern "C" fn destroy_geom<T: GeomClass>(
+    _req: *mut kernel_sys::gctl_req,
+    mp: *mut kernel_sys::g_class,
+    gp: *mut kernel_sys::g_geom,
+) -> c_int {
+    let class = unsafe { Class::<T>::from_raw(mp) };
+    let gp = unsafe { Geom::from_raw(gp) };
+    // Either failed to build or already reaped, and withered either way
+    if !gp.has_softc() {
+        return 0;
+    }
+    match catch_at_boundary(&class.poison, || class.destroy(gp)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn start<T: GeomClass>(bp: *mut kernel_sys::bio) {
+    let gp = unsafe { Geom::<T>::from_raw((*(*bp).bio_to).geom) };
+    if !gp.has_softc() {
+        return unsafe { Bio::from_raw(bp) }.deliver(Err(Errno::NxIo));
+    }
+    let result = catch_at_boundary(&gp.class().poison, || {
+        let bio = unsafe { Bio::from_raw(bp) };
+        match bio.cmd() {
+            BioCmd::Speedup => gp.class().speedup(gp, bio),
+            _ => gp.class().start(gp, bio),
+        }
+    });
+    // A bio whose handler panicked may already have been delivered, so
+    // only one refused outright is still ours to finish
+    if result == Err(Errno::NxIo) {
+        unsafe { Bio::from_raw(bp) }.deliver(Err(Errno::NxIo));
+    }
+}
+
+unsafe extern "C" fn done<T: GeomClass>(bp: *mut kernel_sys::bio) {
+    let gp = unsafe { Geom::<T>::from_raw((*(*bp).bio_from).geom) };
+    if !gp.has_softc() {
+        return unsafe { Bio::from_raw(bp) }.std_done();
+    }
+    let result = catch_at_boundary(&gp.class().poison, || {
+        gp.class().done(gp, unsafe { Bio::from_raw(bp) })
+    });
+    if result == Err(Errno::NxIo) {
+        unsafe { Bio::from_raw(bp) }.std_done();
+    }
+}
+
+unsafe extern "C" fn access<T: GeomClass>(
+    pp: *mut kernel_sys::g_provider,
+    dr: c_int,
+    dw: c_int,
+    de: c_int,
+) -> c_int {
+    let gp = unsafe { Geom::<T>::from_raw((*pp).geom) };
+    let pp = unsafe { Provider::from_raw(pp) };
+    let class = gp.class();
+    // Closing must still work on a poisoned class, or it can't be unloaded,
+    // and on a geom without its softc, or it can't be reaped
+    let closing = dr <= 0 && dw <= 0 && de <= 0;
+    if (class.is_poisoned() || !gp.has_softc()) && closing {
+        return 0;
+    }
+    if !gp.has_softc() {
+        return Errno::NxIo.as_raw();
+    }
+    match catch_at_boundary(&class.poison, || class.access(gp, pp, dr, dw, de))
+    {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn orphan<T: GeomClass>(cp: *mut kernel_sys::g_consumer) {
+    let gp = unsafe { Geom::<T>::from_raw((*cp).geom) };
+    let cp = unsafe { Consumer::from_raw(cp) };
+    // The geom must still go away once what it was stacked on has
+    if !gp.has_softc()
+        || catch_at_boundary(&gp.class().poison, || gp.class().orphan(gp, cp))
+            .is_err()
+    {
+        gp.wither(Errno::NxIo);
+    }
+    // The last consumer may have been closed after withering
+    unsafe { reap_later::<T>(gp.as_ptr()) };
+}
+
+unsafe extern "C" fn dumpconf<T: GeomClass>(
+    sb: *mut kernel_sys::sbuf,
+    indent: *const libc::c_char,
+    gp: *mut kernel_sys::g_geom,
+    cp: *mut kernel_sys::g_consumer,
+    pp: *mut kernel_sys::g_provider,
+) {
+    // Also called for geoms that are still being set up
+    let gp = unsafe { Geom::<T>::from_raw(gp) };
+    if !gp.has_softc() {
+        return;
+    }
+    let mut conf = unsafe { Conf::from_raw(sb, indent) };
+    let part = match (cp.is_null(), pp.is_null()) {
+        (false, _) => ConfPart::Consumer(unsafe { Consumer::from_raw(cp) }),
+        (_, false) => ConfPart::Provider(unsafe { Provider::from_raw(pp) }),
+        _ => ConfPart::Geom,
+    };
+    let _ = catch_at_boundary(&gp.class().poison, || {
+        gp.class().dumpconf(gp, &mut conf, part)
+    });
+}
+
+unsafe extern "C" fn providergone<T: GeomClass>(
+    pp: *mut kernel_sys::g_provider,
+) {
+    // The provider is already off the geom's list, and the softc goes
+    // with the last one once the consumers are gone too
+    unsafe { reap_later::<T>((*pp).geom) };
+}
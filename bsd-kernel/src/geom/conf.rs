@@ -0,0 +1,105 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Per-geom configuration shown by `geom <class> list` and
+//! `kern.geom.confxml`
+//!
+//! `GeomClass::dumpconf` is called once for the geom itself and once for
+//! each of its consumers and providers, and adds elements to each:
+//! ```ignore
+//! fn dumpconf(&self, gp: Geom<Self>, conf: &mut Conf, part: ConfPart) {
+//!     if let ConfPart::Geom = part {
+//!         conf.element(c"Delay", unsafe { gp.softc() }.delay_ms());
+//!     }
+//! }
+//! ```
+
+use super::{Consumer, Provider};
+use crate::sbuf::SbufRef;
+use core::ffi::CStr;
+use core::fmt::{self, Write};
+
+/// What `GeomClass::dumpconf` is describing
+#[derive(Debug)]
+pub enum ConfPart {
+    /// The geom itself
+    Geom,
+    Consumer(Consumer),
+    Provider(Provider),
+}
+
+/// The XML configuration being built, which elements are added to
+pub struct Conf<'a> {
+    sb: SbufRef<'a>,
+    indent: &'a CStr,
+}
+
+impl<'c> Conf<'c> {
+    /// ## Safety
+    /// `sb` and `indent` must be those passed to the current `dumpconf`
+    pub unsafe fn from_raw<'a>(
+        sb: *mut kernel_sys::sbuf,
+        indent: *const libc::c_char,
+    ) -> Conf<'a> {
+        Conf {
+            sb: unsafe { SbufRef::from_raw(sb) },
+            indent: unsafe { CStr::from_ptr(indent) },
+        }
+    }
+
+    /// Add `<name>value</name>`, escaping `value` for XML. `geom list`
+    /// prints it as `name: value`
+    pub fn element(&mut self, name: &CStr, value: impl fmt::Display) {
+        let name = name.to_bytes();
+        // Overflow is recorded in the sbuf, and GEOM retries with more room
+        let _ = self.sb.cat(self.indent.to_bytes());
+        let _ = self.sb.push(b'<');
+        let _ = self.sb.cat(name);
+        let _ = self.sb.push(b'>');
+        let _ = write!(self.sb.xml(), "{}", value);
+        let _ = self.sb.cat(b"</");
+        let _ = self.sb.cat(name);
+        let _ = self.sb.cat(b">\n");
+    }
+
+    /// The indentation elements at this level start with
+    pub fn indent(&self) -> &CStr {
+        self.indent
+    }
+
+    /// The sbuf itself, for what `element` doesn't cover
+    pub fn sbuf(&mut self) -> &mut SbufRef<'c> {
+        &mut self.sb
+    }
+
+    /// Raw pointer to the underlying sbuf
+    pub fn as_ptr(&self) -> *mut kernel_sys::sbuf {
+        self.sb.as_ptr()
+    }
+}
+
+impl fmt::Debug for Conf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Conf {{ sb: {:?} }}", self.sb.as_ptr())
+    }
+}
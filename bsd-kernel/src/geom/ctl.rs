@@ -0,0 +1,149 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::Provider;
+use alloc::ffi::CString;
+use alloc::format;
+use core::ffi::CStr;
+use core::{fmt, ptr, slice};
+use libc::c_int;
+
+/// A verb's handler for `CtlReq::dispatch`
+pub type VerbHandler<C> = fn(&C, &mut CtlReq);
+
+/// A control request from userland, see `geom(8)` and `libgeom(3)`
+///
+/// Parameters are looked up by name; `geom(8)` passes its positional
+/// arguments as the strings `arg0`, `arg1`, ... with their count in the
+/// int `nargs`, and numeric options as `intmax_t`.
+pub struct CtlReq {
+    req: ptr::NonNull<kernel_sys::gctl_req>,
+}
+
+impl CtlReq {
+    /// ## Safety
+    /// `req` must be a request being handled by the current `ctlreq`
+    pub unsafe fn from_raw(req: *mut kernel_sys::gctl_req) -> Self {
+        CtlReq {
+            req: ptr::NonNull::new(req).unwrap(),
+        }
+    }
+
+    /// Raw pointer to the underlying gctl_req
+    pub fn as_ptr(&self) -> *mut kernel_sys::gctl_req {
+        self.req.as_ptr()
+    }
+
+    /// The raw bytes of parameter `name`
+    pub fn param(&self, name: &CStr) -> Option<&[u8]> {
+        let mut len: c_int = 0;
+        let p = unsafe {
+            kernel_sys::gctl_get_param(self.as_ptr(), name.as_ptr(), &mut len)
+        };
+        if p.is_null() {
+            None
+        } else {
+            Some(unsafe { slice::from_raw_parts(p as *const u8, len as usize) })
+        }
+    }
+
+    /// Parameter `name` as a string
+    pub fn param_str(&self, name: &CStr) -> Option<&CStr> {
+        let p = unsafe {
+            kernel_sys::gctl_get_asciiparam(self.as_ptr(), name.as_ptr())
+        };
+        if p.is_null() {
+            None
+        } else {
+            Some(unsafe { CStr::from_ptr(p) })
+        }
+    }
+
+    /// Parameter `name` as an `int`, such as `nargs`
+    pub fn param_int(&self, name: &CStr) -> Option<i32> {
+        let bytes = self.param(name)?.try_into().ok()?;
+        Some(i32::from_ne_bytes(bytes))
+    }
+
+    /// Parameter `name` as an `intmax_t`, as numeric options are passed
+    pub fn param_i64(&self, name: &CStr) -> Option<i64> {
+        let bytes = self.param(name)?.try_into().ok()?;
+        Some(i64::from_ne_bytes(bytes))
+    }
+
+    /// The positional arguments `arg0` onwards, as strings
+    pub fn args(&self) -> impl Iterator<Item = &CStr> + '_ {
+        let nargs = self.param_int(c"nargs").unwrap_or(0);
+        (0..nargs).map_while(|i| {
+            let name = CString::new(format!("arg{}", i)).unwrap();
+            self.param_str(&name)
+        })
+    }
+
+    /// Run the handler `verbs` has for `verb`, passing it `ctx`, or fail
+    /// the request if there is none:
+    /// ```rust,ignore
+    /// let verbs: &[(&CStr, VerbHandler<_>)] =
+    ///     &[(c"create", create), (c"destroy", destroy)];
+    /// req.dispatch(class, verb, verbs);
+    /// ```
+    pub fn dispatch<C>(
+        &mut self,
+        ctx: &C,
+        verb: &CStr,
+        verbs: &[(&CStr, VerbHandler<C>)],
+    ) {
+        match verbs.iter().find(|(name, _)| *name == verb) {
+            Some((_, handler)) => handler(ctx, self),
+            None => self.error("Unknown verb."),
+        }
+    }
+
+    /// Look up the provider named by string parameter `name`. On failure
+    /// the request has already been failed with a message
+    pub fn provider(&mut self, name: &CStr) -> Option<Provider> {
+        let pp = unsafe {
+            kernel_sys::gctl_get_provider(self.as_ptr(), name.as_ptr())
+        };
+        (!pp.is_null()).then(|| unsafe { Provider::from_raw(pp) })
+    }
+
+    /// Fail the request, reporting `msg` to userland. Only the first
+    /// error of a request is kept
+    pub fn error(&mut self, msg: &str) {
+        unsafe {
+            kernel_sys::gctl_error(
+                self.as_ptr(),
+                c"%.*s".as_ptr(),
+                msg.len() as c_int,
+                msg.as_ptr(),
+            );
+        }
+    }
+}
+
+impl fmt::Debug for CtlReq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CtlReq {{ req: {:?} }}", self.req.as_ptr())
+    }
+}
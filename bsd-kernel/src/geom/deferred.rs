@@ -0,0 +1,200 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::{Bio, BioList};
+use crate::errno::Errno;
+use crate::executor::AtomicWaker;
+use crate::sync::{Condvar, Mutex};
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::{Future, poll_fn};
+use core::ops::{Deref, DerefMut};
+use core::task::Poll;
+
+struct State {
+    queue: BioList,
+    /// Bios pushed and not yet delivered, queued or not
+    inflight: usize,
+    closed: bool,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    waker: AtomicWaker,
+    idle: Condvar,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // The queue's bios belong to their issuers, so fail rather than
+        // destroy them
+        let queue = &mut self.state.get_mut().queue;
+        while let Some(bio) = queue.pop_front() {
+            bio.deliver(Err(Errno::NxIo));
+        }
+    }
+}
+
+/// Bios handed off by `start` to be finished later, on an `Executor`
+///
+/// `start` may not sleep, so work that does, such as reading through a
+/// consumer and waiting for the result, is left to a worker future that
+/// takes the bios `push`ed here with `next`. Each bio counts as in flight
+/// until its `Deferred` is delivered or dropped, so `drain` can wait for
+/// all of them before the geom goes away.
+#[derive(Clone)]
+pub struct BioQueue {
+    inner: Arc<Inner>,
+}
+
+impl BioQueue {
+    pub fn new() -> Self {
+        BioQueue {
+            inner: Arc::new(Inner {
+                state: Mutex::new(
+                    c"bioqueue",
+                    State {
+                        queue: BioList::new(),
+                        inflight: 0,
+                        closed: false,
+                    },
+                ),
+                waker: AtomicWaker::new(),
+                idle: Condvar::new(c"bioidle"),
+            }),
+        }
+    }
+
+    /// Queue `bio` for the worker and wake it. Never sleeps. Once the
+    /// queue is closed, `bio` is failed with `Errno::NxIo` instead
+    pub fn push(&self, bio: Bio) {
+        {
+            let mut state = self.inner.state.lock();
+            if state.closed {
+                drop(state);
+                return bio.deliver(Err(Errno::NxIo));
+            }
+            state.queue.push_back(bio);
+            state.inflight += 1;
+        }
+        self.inner.waker.wake();
+    }
+
+    /// Wait for the next bio. Resolves to `None` once the queue is closed
+    /// and everything pushed before has been taken
+    pub fn next(&self) -> impl Future<Output = Option<Deferred>> + '_ {
+        poll_fn(move |cx| {
+            self.inner.waker.register(cx.waker());
+            let mut state = self.inner.state.lock();
+            match state.queue.pop_front() {
+                Some(bio) => Poll::Ready(Some(Deferred {
+                    bio: Some(bio),
+                    inner: self.inner.clone(),
+                })),
+                None if state.closed => Poll::Ready(None),
+                None => Poll::Pending,
+            }
+        })
+    }
+
+    /// Refuse further bios, and let the worker's `next` finish once the
+    /// queue is empty
+    pub fn close(&self) {
+        self.inner.state.lock().closed = true;
+        self.inner.waker.wake();
+    }
+
+    /// Bios pushed and not yet delivered
+    pub fn in_flight(&self) -> usize {
+        self.inner.state.lock().inflight
+    }
+
+    /// Sleep until every bio pushed so far has been delivered, usually
+    /// after `close`
+    pub fn drain(&self) {
+        let state = self.inner.state.lock();
+        drop(self.inner.idle.wait_while(state, |s| s.inflight > 0));
+    }
+}
+
+impl Default for BioQueue {
+    fn default() -> Self {
+        BioQueue::new()
+    }
+}
+
+impl fmt::Debug for BioQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BioQueue {{ in_flight: {} }}", self.in_flight())
+    }
+}
+
+/// A bio taken from a `BioQueue`, counted in flight until it is
+/// delivered. Dropping it delivers the bio with `Errno::Io`
+#[must_use = "a bio must be passed on or delivered"]
+pub struct Deferred {
+    bio: Option<Bio>,
+    inner: Arc<Inner>,
+}
+
+impl Deferred {
+    /// Complete the request towards its issuer, see `Bio::deliver`
+    pub fn deliver(mut self, result: Result<(), Errno>) {
+        if let Some(bio) = self.bio.take() {
+            bio.deliver(result);
+        }
+    }
+}
+
+impl Deref for Deferred {
+    type Target = Bio;
+
+    fn deref(&self) -> &Bio {
+        self.bio.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Deferred {
+    fn deref_mut(&mut self) -> &mut Bio {
+        self.bio.as_mut().unwrap()
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        if let Some(bio) = self.bio.take() {
+            bio.deliver(Err(Errno::Io));
+        }
+        let mut state = self.inner.state.lock();
+        state.inflight -= 1;
+        if state.inflight == 0 {
+            self.inner.idle.notify_all();
+        }
+    }
+}
+
+impl fmt::Debug for Deferred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Deferred {{ bio: {:?} }}", self.bio)
+    }
+}
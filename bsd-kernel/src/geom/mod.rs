@@ -0,0 +1,102 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Safe layer over the GEOM storage framework, see `geom(4)`
+//!
+//! A class is described by implementing `GeomClass` and registered with
+//! `Class::load` from the module event handler. GEOM calls the class back
+//! from its event thread, with the topology lock held, to taste providers
+//! and manage geoms; `start` and `done` run on the I/O path, where
+//! sleeping is not allowed. What `dumpconf` adds to a geom's `Conf` is
+//! listed by `geom <class> list`.
+//!
+//! `Geom`, `Provider` and `Consumer` are unowned handles to objects the
+//! framework keeps alive; a `Bio` is a request that must be passed on or
+//! delivered exactly once. Requests that need sleeping work can be handed
+//! to an `Executor` through a `BioQueue`, and classes that reorder or pace
+//! requests queue them on a `Scheduler`.
+
+pub use self::attr::{Attr, Attributes};
+pub use self::bio::{Bio, BioCmd, BioList, Speedup};
+pub use self::class::{Class, DirectDispatch, Geom, GeomClass};
+pub use self::conf::{Conf, ConfPart};
+pub use self::ctl::{CtlReq, VerbHandler};
+pub use self::deferred::{BioQueue, Deferred};
+pub use self::provider::{Consumer, Provider};
+pub use self::sched::{Deadline, Policy, RateLimit, Scheduler};
+
+use crate::sysctl::Node;
+
+mod attr;
+mod bio;
+mod class;
+mod conf;
+mod ctl;
+mod deferred;
+mod provider;
+pub mod sched;
+
+/// Holds the GEOM topology lock, see `g_topology_lock(9)`
+#[must_use]
+pub struct TopologyGuard {
+    _private: (),
+}
+
+/// Take the topology lock, sleeping until it is available. Class
+/// callbacks other than `start` and `done` already run with it held
+pub fn topology_lock() -> TopologyGuard {
+    unsafe {
+        kernel_sys::_sx_xlock(
+            &raw mut kernel_sys::topology_lock,
+            0,
+            core::ptr::null(),
+            0,
+        );
+    }
+    TopologyGuard { _private: () }
+}
+
+impl Drop for TopologyGuard {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::_sx_xunlock(
+                &raw mut kernel_sys::topology_lock,
+                core::ptr::null(),
+                0,
+            );
+        }
+    }
+}
+
+/// Run `f` with the topology lock, which the caller holds, released
+fn without_topology_lock<R>(f: impl FnOnce() -> R) -> R {
+    drop(TopologyGuard { _private: () });
+    let result = f();
+    core::mem::forget(topology_lock());
+    result
+}
+
+/// The `kern.geom` sysctl tree, where classes hang their knobs
+pub fn sysctl_node() -> Node {
+    unsafe { Node::from_raw(&raw mut kernel_sys::sysctl__kern_geom_children) }
+}
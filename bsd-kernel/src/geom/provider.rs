@@ -0,0 +1,320 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::errno::Errno;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::{fmt, ptr, slice};
+use libc::c_void;
+
+/// A provider, the node a geom exposes for others to attach to
+///
+/// The setters are meant for a geom's own providers, before the creating
+/// event returns.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Provider {
+    pp: ptr::NonNull<kernel_sys::g_provider>,
+}
+
+unsafe impl Send for Provider {}
+unsafe impl Sync for Provider {}
+
+impl Provider {
+    /// ## Safety
+    /// `pp` must be a live provider
+    pub unsafe fn from_raw(pp: *mut kernel_sys::g_provider) -> Self {
+        Provider {
+            pp: ptr::NonNull::new(pp).unwrap(),
+        }
+    }
+
+    /// Look up a provider by name, with or without the `/dev/` prefix.
+    /// Needs the topology lock
+    pub fn by_name(name: &CStr) -> Option<Self> {
+        let pp = unsafe { kernel_sys::g_provider_by_name(name.as_ptr()) };
+        (!pp.is_null()).then(|| unsafe { Provider::from_raw(pp) })
+    }
+
+    /// Raw pointer to the underlying g_provider
+    pub fn as_ptr(&self) -> *mut kernel_sys::g_provider {
+        self.pp.as_ptr()
+    }
+
+    fn raw(&self) -> &kernel_sys::g_provider {
+        unsafe { self.pp.as_ref() }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn raw_mut(&self) -> &mut kernel_sys::g_provider {
+        unsafe { &mut *self.pp.as_ptr() }
+    }
+
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.raw().name) }
+    }
+
+    /// Publish the provider's device under `name` as well, such as an
+    /// older naming scheme kept for existing `fstab(5)` entries, see
+    /// `g_provider_add_alias(9)`. Only takes effect if added before the
+    /// provider is announced, i.e. while the creating event runs
+    pub fn add_alias(&self, name: &CStr) {
+        unsafe {
+            kernel_sys::g_provider_add_alias(
+                self.as_ptr(),
+                c"%s".as_ptr(),
+                name.as_ptr(),
+            );
+        }
+    }
+
+    /// Size in bytes
+    pub fn mediasize(&self) -> i64 {
+        self.raw().mediasize
+    }
+
+    pub fn set_mediasize(&self, mediasize: i64) {
+        self.raw_mut().mediasize = mediasize;
+    }
+
+    pub fn sectorsize(&self) -> u32 {
+        self.raw().sectorsize
+    }
+
+    pub fn set_sectorsize(&self, sectorsize: u32) {
+        self.raw_mut().sectorsize = sectorsize;
+    }
+
+    pub fn stripesize(&self) -> i64 {
+        self.raw().stripesize
+    }
+
+    pub fn set_stripesize(&self, stripesize: i64) {
+        self.raw_mut().stripesize = stripesize;
+    }
+
+    pub fn stripeoffset(&self) -> i64 {
+        self.raw().stripeoffset
+    }
+
+    pub fn set_stripeoffset(&self, stripeoffset: i64) {
+        self.raw_mut().stripeoffset = stripeoffset;
+    }
+
+    /// Whether bios without a kernel mapping may be sent here
+    pub fn accepts_unmapped(&self) -> bool {
+        self.raw().flags & kernel_sys::G_PF_ACCEPT_UNMAPPED as u32 != 0
+    }
+
+    pub fn set_accepts_unmapped(&self, accept: bool) {
+        let flag = kernel_sys::G_PF_ACCEPT_UNMAPPED as u32;
+        if accept {
+            self.raw_mut().flags |= flag;
+        } else {
+            self.raw_mut().flags &= !flag;
+        }
+    }
+
+    /// The error new requests fail with, if any
+    pub fn error(&self) -> Option<Errno> {
+        match self.raw().error {
+            0 => None,
+            e => Some(Errno::from_raw(e).unwrap_or(Errno::Io)),
+        }
+    }
+
+    /// Set or clear the error, see `g_error_provider(9)`. A new provider
+    /// starts out failing with `Errno::NxIo` until the creating event
+    /// returns, at which point it is cleared
+    pub fn set_error(&self, error: Option<Errno>) {
+        let error = error.map_or(0, Errno::as_raw);
+        unsafe { kernel_sys::g_error_provider(self.as_ptr(), error) };
+    }
+
+    /// Tell the geoms above that attribute `attr`, such as
+    /// `GEOM::physpath`, has a new value to be queried, see
+    /// `g_attr_changed(9)`. Never sleeps
+    pub fn attr_changed(&self, attr: &CStr) -> Result<(), Errno> {
+        let error = unsafe {
+            kernel_sys::g_attr_changed(
+                self.as_ptr(),
+                attr.as_ptr(),
+                kernel_sys::M_NOWAIT,
+            )
+        };
+        match error {
+            0 => Ok(()),
+            e => Err(Errno::from_raw(e).unwrap_or(Errno::NoMem)),
+        }
+    }
+
+    /// Read, write and exclusive access counts
+    pub fn access_counts(&self) -> (i32, i32, i32) {
+        (self.raw().acr, self.raw().acw, self.raw().ace)
+    }
+
+    /// Whether anyone has the provider open
+    pub fn is_open(&self) -> bool {
+        self.access_counts() != (0, 0, 0)
+    }
+}
+
+impl fmt::Debug for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Provider {{ name: {:?} }}", self.name())
+    }
+}
+
+/// A consumer, through which a geom uses a provider below it
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Consumer {
+    cp: ptr::NonNull<kernel_sys::g_consumer>,
+}
+
+unsafe impl Send for Consumer {}
+unsafe impl Sync for Consumer {}
+
+impl Consumer {
+    /// ## Safety
+    /// `cp` must be a live consumer
+    pub unsafe fn from_raw(cp: *mut kernel_sys::g_consumer) -> Self {
+        Consumer {
+            cp: ptr::NonNull::new(cp).unwrap(),
+        }
+    }
+
+    /// Raw pointer to the underlying g_consumer
+    pub fn as_ptr(&self) -> *mut kernel_sys::g_consumer {
+        self.cp.as_ptr()
+    }
+
+    fn raw(&self) -> &kernel_sys::g_consumer {
+        unsafe { self.cp.as_ref() }
+    }
+
+    /// Attach to `pp`. Needs the topology lock
+    pub fn attach(&self, pp: Provider) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::g_attach(self.as_ptr(), pp.as_ptr())
+        })
+    }
+
+    /// Detach from the provider, which must no longer be open through this
+    /// consumer. Needs the topology lock
+    pub fn detach(&self) {
+        unsafe { kernel_sys::g_detach(self.as_ptr()) };
+    }
+
+    /// Free a detached consumer. Needs the topology lock
+    pub fn destroy(self) {
+        unsafe { kernel_sys::g_destroy_consumer(self.as_ptr()) };
+    }
+
+    /// The provider this consumer is attached to
+    pub fn provider(&self) -> Option<Provider> {
+        let pp = self.raw().provider;
+        (!pp.is_null()).then(|| unsafe { Provider::from_raw(pp) })
+    }
+
+    /// Change the read, write and exclusive counts by the given deltas,
+    /// see `g_access(9)`. Needs the topology lock
+    pub fn access(&self, dr: i32, dw: i32, de: i32) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::g_access(self.as_ptr(), dr, dw, de)
+        })
+    }
+
+    /// Read, write and exclusive access counts
+    pub fn access_counts(&self) -> (i32, i32, i32) {
+        (self.raw().acr, self.raw().acw, self.raw().ace)
+    }
+
+    /// Read `length` bytes at `offset`, both whole sectors, sleeping for
+    /// the I/O, see `g_read_data(9)`. The consumer must be open for
+    /// reading. Called with the topology lock held, as from `taste` or a
+    /// control request, and drops it while reading
+    pub fn read_data(
+        &self,
+        offset: i64,
+        length: usize,
+    ) -> Result<Vec<u8>, Errno> {
+        let pp = self.provider().ok_or(Errno::NxIo)?;
+        let sectorsize = pp.sectorsize() as usize;
+        // At most maxphys bytes per call
+        let chunk = unsafe { kernel_sys::maxphys } as usize;
+        let chunk = chunk / sectorsize * sectorsize;
+        let mut data = Vec::with_capacity(length);
+        super::without_topology_lock(|| {
+            while data.len() < length {
+                let len = chunk.min(length - data.len());
+                let mut error = 0;
+                let buf = unsafe {
+                    kernel_sys::g_read_data(
+                        self.as_ptr(),
+                        offset + data.len() as i64,
+                        len as i64,
+                        &mut error,
+                    )
+                };
+                if buf.is_null() {
+                    return Err(Errno::from_raw(error).unwrap_or(Errno::Io));
+                }
+                let bytes =
+                    unsafe { slice::from_raw_parts(buf as *const u8, len) };
+                data.extend_from_slice(bytes);
+                unsafe { kernel_sys::g_free(buf) };
+            }
+            Ok(data)
+        })
+    }
+
+    /// Write `data`, whole sectors, at `offset`, sleeping for the I/O,
+    /// see `g_write_data(9)`. The consumer must be open for writing, and
+    /// the topology lock held as for `read_data`
+    pub fn write_data(&self, offset: i64, data: &[u8]) -> Result<(), Errno> {
+        let pp = self.provider().ok_or(Errno::NxIo)?;
+        let sectorsize = pp.sectorsize() as usize;
+        let chunk = unsafe { kernel_sys::maxphys } as usize;
+        let chunk = chunk / sectorsize * sectorsize;
+        super::without_topology_lock(|| {
+            let mut done = 0;
+            for part in data.chunks(chunk) {
+                Errno::result(unsafe {
+                    kernel_sys::g_write_data(
+                        self.as_ptr(),
+                        offset + done as i64,
+                        part.as_ptr() as *mut c_void,
+                        part.len() as i64,
+                    )
+                })?;
+                done += part.len();
+            }
+            Ok(())
+        })
+    }
+}
+
+impl fmt::Debug for Consumer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Consumer {{ provider: {:?} }}", self.provider())
+    }
+}
@@ -0,0 +1,544 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! I/O scheduling for classes stacked between a provider and its users,
+//! in the manner of `gsched(8)`
+//!
+//! A scheduling class passes each bio its `start` receives to
+//! `Scheduler::enqueue`, which queues a clone of it on one of `FLOWS`
+//! flows, by default one per issuing consumer. The class's `Policy` then
+//! picks which flow goes next, or says to wait, and the scheduler sends
+//! the clones it picks down with the function given to `Scheduler::new`,
+//! usually `Geom::request` through the geom's consumer. The class's
+//! `done` reports each clone back with `Scheduler::completed` before
+//! `Bio::std_done`:
+//!
+//! ```ignore
+//! fn start(&self, gp: Geom<Self>, bio: Bio) {
+//!     unsafe { gp.softc() }.sched.enqueue(bio);
+//! }
+//!
+//! fn done(&self, gp: Geom<Self>, bio: Bio) {
+//!     unsafe { gp.softc() }.sched.completed(&bio);
+//!     bio.std_done();
+//! }
+//! ```
+//!
+//! The scheduler keeps the flows' queues and the counts policies base
+//! their choices on, and retries on a callout when the policy asks it to
+//! wait. Its clones' `caller1` and `caller2` are its own.
+//! `Deadline` and `RateLimit` are policies ready to use.
+
+use super::{Bio, BioCmd, BioList};
+use crate::errno::Errno;
+use crate::sync::Mutex;
+use crate::time::{duration_to_sbt, sbinuptime, sbt_to_duration};
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::time::Duration;
+use core::{array, fmt, mem};
+use libc::c_void;
+
+/// Number of flows. Issuers are hashed onto them, so with more issuers
+/// than flows some share one
+pub const FLOWS: usize = 32;
+
+/// One flow's queue and accounting, plus the policy's state for it
+pub struct Flow<F> {
+    queue: BioList,
+    queued_bytes: u64,
+    in_flight: usize,
+    dispatched: u64,
+    dispatched_bytes: u64,
+    /// The policy's own state for the flow
+    pub state: F,
+}
+
+impl<F: Default> Flow<F> {
+    fn new() -> Self {
+        Flow {
+            queue: BioList::new(),
+            queued_bytes: 0,
+            in_flight: 0,
+            dispatched: 0,
+            dispatched_bytes: 0,
+            state: F::default(),
+        }
+    }
+}
+
+impl<F> Flow<F> {
+    /// Bios waiting to be dispatched
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn queued_bytes(&self) -> u64 {
+        self.queued_bytes
+    }
+
+    /// Bios dispatched and not yet completed
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Bios dispatched so far
+    pub fn dispatched(&self) -> u64 {
+        self.dispatched
+    }
+
+    pub fn dispatched_bytes(&self) -> u64 {
+        self.dispatched_bytes
+    }
+
+    /// The bio that goes next if the flow is picked
+    pub fn head(&self) -> Option<&Bio> {
+        self.queue.front()
+    }
+
+    /// When the head arrived, in uptime
+    pub fn head_arrival(&self) -> Option<Duration> {
+        self.head().map(arrival)
+    }
+}
+
+impl<F: fmt::Debug> fmt::Debug for Flow<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Flow {{ queued: {}, in_flight: {}, dispatched: {}, state: {:?} }}",
+            self.queued(),
+            self.in_flight,
+            self.dispatched,
+            self.state
+        )
+    }
+}
+
+/// What a policy wants done next
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Select {
+    /// Dispatch the head of this flow
+    Flow(usize),
+    /// Nothing may go yet; ask again after this long
+    After(Duration),
+    /// Nothing to do until more bios arrive or complete
+    Idle,
+}
+
+/// How a `Scheduler` orders and paces the bios queued on it
+///
+/// Called with the scheduler's lock held, from `start` and `done` or the
+/// scheduler's callout, so it must not sleep.
+pub trait Policy: Send + 'static {
+    /// Per-flow state
+    type Flow: Default + Send;
+
+    /// The flow `bio`, the one passed to `start`, joins, as any number
+    /// that is then hashed onto the `FLOWS`: by default the consumer
+    /// above that issued it
+    fn classify(&self, bio: &Bio) -> usize {
+        unsafe { (*bio.as_ptr()).bio_from as usize }
+    }
+
+    /// Pick the flow to dispatch from, among those with bios queued
+    fn select(
+        &mut self,
+        flows: &mut [Flow<Self::Flow>; FLOWS],
+        now: Duration,
+    ) -> Select;
+
+    /// `bio` was taken off the head of `flow` to be dispatched
+    fn dispatched(
+        &mut self,
+        _flow: &mut Flow<Self::Flow>,
+        _bio: &Bio,
+        _now: Duration,
+    ) {
+    }
+
+    /// `bio`, dispatched from `flow`, completed
+    fn completed(
+        &mut self,
+        _flow: &mut Flow<Self::Flow>,
+        _bio: &Bio,
+        _now: Duration,
+    ) {
+    }
+}
+
+fn arrival(bio: &Bio) -> Duration {
+    sbt_to_duration(bio.caller2() as kernel_sys::sbintime_t)
+}
+
+fn now() -> Duration {
+    sbt_to_duration(sbinuptime())
+}
+
+fn hash(key: usize) -> usize {
+    // Fibonacci hashing, as the keys are mostly aligned pointers
+    (key as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) as usize >> 32
+        & (FLOWS - 1)
+}
+
+struct State<P: Policy> {
+    policy: P,
+    flows: [Flow<P::Flow>; FLOWS],
+    in_flight: usize,
+    closed: bool,
+}
+
+struct Inner<P: Policy> {
+    state: Mutex<State<P>>,
+    max_in_flight: usize,
+    dispatch: Box<dyn Fn(Bio) + Send + Sync>,
+    callout: UnsafeCell<kernel_sys::callout>,
+}
+
+/// Queues bios on flows and dispatches them as policy `P` decides
+pub struct Scheduler<P: Policy> {
+    inner: Box<Inner<P>>,
+}
+
+unsafe impl<P: Policy> Send for Scheduler<P> {}
+unsafe impl<P: Policy> Sync for Scheduler<P> {}
+
+impl<P: Policy> Scheduler<P> {
+    /// Create a scheduler keeping at most `max_in_flight` bios below it at
+    /// once, which passes those it dispatches to `dispatch`. `dispatch` is
+    /// called without the scheduler's lock and must not sleep
+    pub fn new<D>(policy: P, max_in_flight: usize, dispatch: D) -> Self
+    where
+        D: Fn(Bio) + Send + Sync + 'static,
+    {
+        let inner = Box::new(Inner {
+            state: Mutex::new(
+                c"gsched",
+                State {
+                    policy,
+                    flows: array::from_fn(|_| Flow::new()),
+                    in_flight: 0,
+                    closed: false,
+                },
+            ),
+            max_in_flight: max_in_flight.max(1),
+            dispatch: Box::new(dispatch),
+            callout: UnsafeCell::new(unsafe { mem::zeroed() }),
+        });
+        unsafe { kernel_sys::callout_init(inner.callout.get(), 1) };
+        Scheduler { inner }
+    }
+
+    /// Queue a clone of `bio`, a request from `start`, and dispatch what
+    /// the policy allows. `bio` is delivered once its clone is in, or
+    /// failed with `Errno::NoMem` if it can't be cloned. Requests other
+    /// than reads, writes and deletes, such as flushes and attribute
+    /// queries, skip the queues
+    pub fn enqueue(&self, bio: Bio) {
+        let queued =
+            matches!(bio.cmd(), BioCmd::Read | BioCmd::Write | BioCmd::Delete);
+        let mut state = self.inner.state.lock();
+        if state.closed {
+            drop(state);
+            return bio.deliver(Err(Errno::NxIo));
+        }
+        let Some(mut cbp) = bio.clone_bio() else {
+            drop(state);
+            return bio.deliver(Err(Errno::NoMem));
+        };
+        if !queued {
+            cbp.set_caller1(FLOWS as *mut c_void);
+            state.in_flight += 1;
+            drop(state);
+            return (self.inner.dispatch)(cbp);
+        }
+        let i = hash(state.policy.classify(&bio));
+        cbp.set_caller1(i as *mut c_void);
+        cbp.set_caller2(sbinuptime() as *mut c_void);
+        let flow = &mut state.flows[i];
+        flow.queued_bytes += cbp.length() as u64;
+        flow.queue.push_back(cbp);
+        drop(state);
+        self.run();
+    }
+
+    /// Account for `bio`, a clone passed to the dispatch function, having
+    /// completed, and dispatch what that allows
+    pub fn completed(&self, bio: &Bio) {
+        {
+            let mut state = self.inner.state.lock();
+            state.in_flight -= 1;
+            let i = bio.caller1() as usize;
+            if i < FLOWS {
+                let State { policy, flows, .. } = &mut *state;
+                flows[i].in_flight -= 1;
+                policy.completed(&mut flows[i], bio, now());
+            }
+        }
+        self.run();
+    }
+
+    /// Bios dispatched and not yet completed
+    pub fn in_flight(&self) -> usize {
+        self.inner.state.lock().in_flight
+    }
+
+    /// Bios waiting in all flows
+    pub fn queued(&self) -> usize {
+        let state = self.inner.state.lock();
+        state.flows.iter().map(Flow::queued).sum()
+    }
+
+    /// Run `f` on the policy, such as to change its settings from a
+    /// `ctlreq`, then dispatch what that allows
+    pub fn with_policy<R>(&self, f: impl FnOnce(&mut P) -> R) -> R {
+        let result = f(&mut self.inner.state.lock().policy);
+        self.run();
+        result
+    }
+
+    /// Dispatch what the policy allows now
+    fn run(&self) {
+        run(&self.inner);
+    }
+}
+
+/// Take the bios the policy picks off their flows, and dispatch them once
+/// the lock is released
+fn run<P: Policy>(inner: &Inner<P>) {
+    let mut ready = BioList::new();
+    {
+        let mut state = inner.state.lock();
+        let now = now();
+        let State {
+            policy,
+            flows,
+            in_flight,
+            closed,
+        } = &mut *state;
+        while !*closed && *in_flight < inner.max_in_flight {
+            match policy.select(flows, now) {
+                Select::Flow(i) => {
+                    let Some(bio) =
+                        flows.get_mut(i).and_then(|f| f.queue.pop_front())
+                    else {
+                        break;
+                    };
+                    let flow = &mut flows[i];
+                    let len = bio.length() as u64;
+                    flow.queued_bytes -= len;
+                    flow.in_flight += 1;
+                    flow.dispatched += 1;
+                    flow.dispatched_bytes += len;
+                    policy.dispatched(flow, &bio, now);
+                    *in_flight += 1;
+                    ready.push_back(bio);
+                }
+                Select::After(delay) => {
+                    unsafe {
+                        kernel_sys::callout_reset_sbt_on(
+                            inner.callout.get(),
+                            duration_to_sbt(delay),
+                            0,
+                            Some(retry::<P>),
+                            inner as *const Inner<P> as *mut c_void,
+                            -1,
+                            0,
+                        )
+                    };
+                    break;
+                }
+                Select::Idle => break,
+            }
+        }
+    }
+    while let Some(bio) = ready.pop_front() {
+        (inner.dispatch)(bio);
+    }
+}
+
+unsafe extern "C" fn retry<P: Policy>(arg: *mut c_void) {
+    let inner = unsafe { &*(arg as *const Inner<P>) };
+    let _ = crate::panic::catch_in_module(|| run(inner));
+}
+
+impl<P: Policy> Drop for Scheduler<P> {
+    fn drop(&mut self) {
+        self.inner.state.lock().closed = true;
+        // Drain rather than stop, so the retry isn't still running on
+        // another CPU once we return
+        unsafe {
+            kernel_sys::_callout_stop_safe(
+                self.inner.callout.get(),
+                kernel_sys::CS_DRAIN,
+            )
+        };
+        // The clones' parents belong to their issuers, so fail them
+        // rather than leave them hanging
+        let state = self.inner.state.get_mut();
+        for flow in &mut state.flows {
+            while let Some(mut bio) = flow.queue.pop_front() {
+                bio.set_error(Some(Errno::NxIo));
+                bio.std_done();
+            }
+        }
+    }
+}
+
+impl<P: Policy> fmt::Debug for Scheduler<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Scheduler {{ queued: {}, in_flight: {} }}",
+            self.queued(),
+            self.in_flight()
+        )
+    }
+}
+
+/// Dispatch the flow whose head's deadline comes first, reads being due
+/// `read` after they arrive and writes and deletes `write` after: with
+/// writes given longer, a stream of them can't starve reads
+#[derive(Copy, Clone, Debug)]
+pub struct Deadline {
+    pub read: Duration,
+    pub write: Duration,
+}
+
+impl Default for Deadline {
+    fn default() -> Self {
+        Deadline {
+            read: Duration::from_millis(50),
+            write: Duration::from_millis(500),
+        }
+    }
+}
+
+impl Deadline {
+    fn due(&self, bio: &Bio) -> Duration {
+        let expiry = match bio.cmd() {
+            BioCmd::Read => self.read,
+            _ => self.write,
+        };
+        arrival(bio) + expiry
+    }
+}
+
+impl Policy for Deadline {
+    type Flow = ();
+
+    fn select(
+        &mut self,
+        flows: &mut [Flow<()>; FLOWS],
+        _now: Duration,
+    ) -> Select {
+        flows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, f)| Some((i, self.due(f.head()?))))
+            .min_by_key(|&(_, due)| due)
+            .map_or(Select::Idle, |(i, _)| Select::Flow(i))
+    }
+}
+
+/// Token bucket state of a `RateLimit` flow
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Bucket {
+    tokens: u64,
+    refilled: Duration,
+}
+
+/// Limit each flow to `bytes_per_sec`, allowing bursts of up to `burst`
+/// bytes, and take turns among flows within their limits. A bio larger
+/// than `burst` goes once the bucket is full
+#[derive(Copy, Clone, Debug)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    pub burst: u64,
+    next: usize,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64, burst: u64) -> Self {
+        RateLimit {
+            bytes_per_sec: bytes_per_sec.max(1),
+            burst: burst.max(1),
+            next: 0,
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Duration) {
+        let elapsed = now.saturating_sub(bucket.refilled);
+        let earned =
+            elapsed.as_nanos() * self.bytes_per_sec as u128 / 1_000_000_000;
+        let tokens = (bucket.tokens as u128 + earned).min(self.burst as u128);
+        bucket.tokens = tokens as u64;
+        bucket.refilled = now;
+    }
+
+    /// How long until `bucket` holds enough for `len` bytes
+    fn wait(&self, bucket: &Bucket, len: u64) -> Duration {
+        let short = len.min(self.burst) - bucket.tokens.min(len);
+        Duration::from_nanos(
+            (short as u128 * 1_000_000_000 / self.bytes_per_sec as u128).max(1)
+                as u64,
+        )
+    }
+}
+
+impl Policy for RateLimit {
+    type Flow = Bucket;
+
+    fn select(
+        &mut self,
+        flows: &mut [Flow<Bucket>; FLOWS],
+        now: Duration,
+    ) -> Select {
+        let mut soonest: Option<Duration> = None;
+        for k in 0..FLOWS {
+            let i = (self.next + k) % FLOWS;
+            let flow = &mut flows[i];
+            let Some(len) = flow.head().map(|bio| bio.length() as u64) else {
+                continue;
+            };
+            self.refill(&mut flow.state, now);
+            if flow.state.tokens >= len.min(self.burst) {
+                self.next = (i + 1) % FLOWS;
+                return Select::Flow(i);
+            }
+            let wait = self.wait(&flow.state, len);
+            soonest = Some(soonest.map_or(wait, |s| s.min(wait)));
+        }
+        soonest.map_or(Select::Idle, Select::After)
+    }
+
+    fn dispatched(
+        &mut self,
+        flow: &mut Flow<Bucket>,
+        bio: &Bio,
+        _now: Duration,
+    ) {
+        let len = bio.length() as u64;
+        flow.state.tokens -= len.min(flow.state.tokens);
+    }
+}
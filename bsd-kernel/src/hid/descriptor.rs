@@ -0,0 +1,417 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! HID report descriptor parsing
+//!
+//! A report descriptor is a sequence of short items that describe, field
+//! by field, the reports a device sends and accepts. `ReportDescriptor`
+//! walks it once and keeps the resulting `Field`s, each of which knows
+//! where its value lives in a report, much as `hid_locate(9)` does in C.
+//! Long items are skipped and collections are flattened away.
+
+use crate::errno::Errno;
+use alloc::vec::Vec;
+
+/// A usage, which says what a field means
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Usage {
+    pub page: u16,
+    pub id: u16,
+}
+
+impl Usage {
+    pub const fn new(page: u16, id: u16) -> Self {
+        Usage { page, id }
+    }
+
+    /// A 32-bit usage as in `HID_USAGE2()`, page in the high half
+    pub const fn from_u32(usage: u32) -> Self {
+        Usage::new((usage >> 16) as u16, usage as u16)
+    }
+
+    pub const fn as_u32(self) -> u32 {
+        (self.page as u32) << 16 | self.id as u32
+    }
+
+    /// The `n`th button, from 1
+    pub const fn button(n: u16) -> Self {
+        Usage::new(page::BUTTON, n)
+    }
+
+    pub const POINTER: Usage = Usage::new(page::GENERIC_DESKTOP, 0x01);
+    pub const MOUSE: Usage = Usage::new(page::GENERIC_DESKTOP, 0x02);
+    pub const JOYSTICK: Usage = Usage::new(page::GENERIC_DESKTOP, 0x04);
+    pub const GAME_PAD: Usage = Usage::new(page::GENERIC_DESKTOP, 0x05);
+    pub const KEYBOARD: Usage = Usage::new(page::GENERIC_DESKTOP, 0x06);
+    pub const X: Usage = Usage::new(page::GENERIC_DESKTOP, 0x30);
+    pub const Y: Usage = Usage::new(page::GENERIC_DESKTOP, 0x31);
+    pub const Z: Usage = Usage::new(page::GENERIC_DESKTOP, 0x32);
+    pub const WHEEL: Usage = Usage::new(page::GENERIC_DESKTOP, 0x38);
+}
+
+/// Usage pages, from `dev/hid/hid.h`
+pub mod page {
+    pub const GENERIC_DESKTOP: u16 = 0x01;
+    pub const SIMULATION: u16 = 0x02;
+    pub const KEYBOARD: u16 = 0x07;
+    pub const LED: u16 = 0x08;
+    pub const BUTTON: u16 = 0x09;
+    pub const CONSUMER: u16 = 0x0c;
+    pub const DIGITIZERS: u16 = 0x0d;
+}
+
+/// Which reports a field is in
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// Sent by the device, normally over the interrupt pipe
+    Input,
+    /// Sent to the device
+    Output,
+    /// Configuration, read and written on request
+    Feature,
+}
+
+impl Kind {
+    /// The report type `hid_get_report()` and `hid_set_report()` take
+    pub const fn report_type(self) -> u8 {
+        match self {
+            Kind::Input => 1,
+            Kind::Output => 2,
+            Kind::Feature => 3,
+        }
+    }
+}
+
+/// Main item flags, `HIO_*` in `dev/hid/hid.h`
+pub mod flags {
+    pub const CONST: u32 = 0x001;
+    pub const VARIABLE: u32 = 0x002;
+    pub const RELATIVE: u32 = 0x004;
+    pub const WRAP: u32 = 0x008;
+    pub const NONLINEAR: u32 = 0x010;
+    pub const NOPREF: u32 = 0x020;
+    pub const NULLSTATE: u32 = 0x040;
+    pub const VOLATILE: u32 = 0x080;
+    pub const BUFBYTES: u32 = 0x100;
+}
+
+/// One value in a report
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Field {
+    pub kind: Kind,
+    /// For an array field, the first usage its values index from
+    pub usage: Usage,
+    /// 0 if the device doesn't number its reports
+    pub report_id: u8,
+    /// Offset in bits from the start of the report, after any ID byte
+    pub pos: u32,
+    /// Width in bits, at most 32
+    pub size: u32,
+    pub logical_min: i32,
+    pub logical_max: i32,
+    /// `flags::*`
+    pub flags: u32,
+}
+
+impl Field {
+    pub fn is_variable(&self) -> bool {
+        self.flags & flags::VARIABLE != 0
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.flags & flags::RELATIVE != 0
+    }
+
+    /// Whether the value can be negative, judging by its logical range
+    pub fn is_signed(&self) -> bool {
+        self.logical_min < 0
+    }
+
+    /// The field's data in `report`, or `None` when `report` is some other
+    /// report or too short. `report` starts with the ID byte if there is one
+    fn data<'a>(&self, report: &'a [u8]) -> Option<&'a [u8]> {
+        let data = if self.report_id != 0 {
+            let (&id, rest) = report.split_first()?;
+            if id != self.report_id {
+                return None;
+            }
+            rest
+        } else {
+            report
+        };
+        let end = (self.pos + self.size).div_ceil(8) as usize;
+        (end <= data.len()).then_some(data)
+    }
+
+    /// The raw value, as `hid_get_udata()`
+    pub fn get(&self, report: &[u8]) -> Option<u32> {
+        let data = self.data(report)?;
+        let mut v = 0u64;
+        let first = (self.pos / 8) as usize;
+        let last = ((self.pos + self.size).div_ceil(8)) as usize;
+        for (i, b) in data[first..last].iter().enumerate() {
+            v |= (*b as u64) << (8 * i);
+        }
+        v >>= self.pos % 8;
+        Some((v & ((1u64 << self.size) - 1)) as u32)
+    }
+
+    /// The value sign-extended, as `hid_get_data()`
+    pub fn get_signed(&self, report: &[u8]) -> Option<i32> {
+        let v = self.get(report)?;
+        let shift = 32 - self.size;
+        Some(((v << shift) as i32) >> shift)
+    }
+
+    /// The value as the logical range says to read it
+    pub fn value(&self, report: &[u8]) -> Option<i32> {
+        if self.is_signed() {
+            self.get_signed(report)
+        } else {
+            self.get(report).map(|v| v as i32)
+        }
+    }
+
+    /// Store `value` in `report`, as `hid_put_udata()`. Fails if `report`
+    /// is some other report or too short
+    pub fn set(&self, report: &mut [u8], value: u32) -> Result<(), Errno> {
+        let off = (self.report_id != 0) as usize;
+        self.data(report).ok_or(Errno::Inval)?;
+        let data = &mut report[off..];
+        for bit in 0..self.size {
+            let at = (self.pos + bit) as usize;
+            let mask = 1 << (at % 8);
+            if value >> bit & 1 != 0 {
+                data[at / 8] |= mask;
+            } else {
+                data[at / 8] &= !mask;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Global items, saved and restored by push and pop
+#[derive(Copy, Clone, Default)]
+struct Globals {
+    page: u16,
+    logical_min: i32,
+    logical_max: i32,
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+/// Pushes allowed, as in `dev/hid/hid.c`
+const MAXPUSH: usize = 4;
+
+/// Fields kept per main item; more are in the report but not listed
+const MAXUSAGE: u32 = 64;
+
+/// The fields of a parsed report descriptor
+#[derive(Clone, Debug, Default)]
+pub struct ReportDescriptor {
+    fields: Vec<Field>,
+    /// Report sizes in bits, by kind and ID
+    sizes: Vec<(Kind, u8, u32)>,
+}
+
+impl ReportDescriptor {
+    /// Parse `desc`, failing with `Errno::Inval` if it is malformed
+    pub fn parse(desc: &[u8]) -> Result<Self, Errno> {
+        let mut rd = ReportDescriptor::default();
+        let mut g = Globals::default();
+        let mut stack: Vec<Globals> = Vec::new();
+        let mut usages: Vec<Usage> = Vec::new();
+        let mut usage_min: Option<Usage> = None;
+        let mut usage_max: Option<Usage> = None;
+
+        let mut rest = desc;
+        while let Some((&prefix, tail)) = rest.split_first() {
+            if prefix == 0xfe {
+                // Long item: size, tag, data
+                let len = *tail.first().ok_or(Errno::Inval)? as usize;
+                rest = tail.get(2 + len..).ok_or(Errno::Inval)?;
+                continue;
+            }
+            let len = match prefix & 3 {
+                3 => 4,
+                n => n as usize,
+            };
+            let data = tail.get(..len).ok_or(Errno::Inval)?;
+            rest = &tail[len..];
+            let mut u = 0u32;
+            for (i, b) in data.iter().enumerate() {
+                u |= (*b as u32) << (8 * i);
+            }
+            // Sign-extended, for the logical range
+            let s = match len {
+                0 => 0,
+                _ => {
+                    let shift = 32 - 8 * len as u32;
+                    ((u << shift) as i32) >> shift
+                }
+            };
+            // A local usage's page is the current one unless it's given
+            let local = |u: u32| match len {
+                4 => Usage::from_u32(u),
+                _ => Usage::new(g.page, u as u16),
+            };
+
+            match (prefix >> 2 & 3, prefix >> 4) {
+                // Main items
+                (0, tag @ (0x8 | 0x9 | 0xb)) => {
+                    let kind = match tag {
+                        0x8 => Kind::Input,
+                        0x9 => Kind::Output,
+                        _ => Kind::Feature,
+                    };
+                    let pos = rd.size_mut(kind, g.report_id);
+                    let start = *pos;
+                    let bits = g.report_size.saturating_mul(g.report_count);
+                    *pos = pos.checked_add(bits).ok_or(Errno::Inval)?;
+
+                    let usage_at = |i: u32| -> Option<Usage> {
+                        if let (Some(min), Some(max)) = (usage_min, usage_max) {
+                            let id = (min.id as u32 + i).min(max.id as u32);
+                            return Some(Usage::new(min.page, id as u16));
+                        }
+                        usages.get(i as usize).or(usages.last()).copied()
+                    };
+                    let keep = u & flags::CONST == 0
+                        && (1..=32).contains(&g.report_size);
+                    for i in 0..g.report_count.min(MAXUSAGE) {
+                        let usage = match u & flags::VARIABLE {
+                            0 => usage_at(0),
+                            _ => usage_at(i),
+                        };
+                        if let (true, Some(usage)) = (keep, usage) {
+                            rd.fields.push(Field {
+                                kind,
+                                usage,
+                                report_id: g.report_id,
+                                pos: start + i * g.report_size,
+                                size: g.report_size,
+                                logical_min: g.logical_min,
+                                logical_max: g.logical_max,
+                                flags: u,
+                            });
+                        }
+                    }
+                    usages.clear();
+                    usage_min = None;
+                    usage_max = None;
+                }
+                // Collection and End Collection
+                (0, 0xa | 0xc) => {
+                    usages.clear();
+                    usage_min = None;
+                    usage_max = None;
+                }
+                (0, _) => return Err(Errno::Inval),
+                // Global items
+                (1, 0x0) => g.page = u as u16,
+                (1, 0x1) => g.logical_min = s,
+                (1, 0x2) => {
+                    // Unsigned unless the minimum says otherwise
+                    g.logical_max = if g.logical_min < 0 { s } else { u as i32 }
+                }
+                (1, 0x7) => g.report_size = u,
+                (1, 0x8) => {
+                    if u == 0 || u > 0xff {
+                        return Err(Errno::Inval);
+                    }
+                    g.report_id = u as u8;
+                }
+                (1, 0x9) => g.report_count = u,
+                (1, 0xa) => {
+                    if stack.len() == MAXPUSH {
+                        return Err(Errno::Inval);
+                    }
+                    stack.push(g);
+                }
+                (1, 0xb) => g = stack.pop().ok_or(Errno::Inval)?,
+                (1, _) => (),
+                // Local items
+                (2, 0x0) => usages.push(local(u)),
+                (2, 0x1) => usage_min = Some(local(u)),
+                (2, 0x2) => usage_max = Some(local(u)),
+                (2, _) => (),
+                _ => return Err(Errno::Inval),
+            }
+        }
+        Ok(rd)
+    }
+
+    fn size_mut(&mut self, kind: Kind, id: u8) -> &mut u32 {
+        let i = match self.sizes.iter().position(|s| s.0 == kind && s.1 == id) {
+            Some(i) => i,
+            None => {
+                self.sizes.push((kind, id, 0));
+                self.sizes.len() - 1
+            }
+        };
+        &mut self.sizes[i].2
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// The `index`th field of `kind` with `usage`, as `hid_locate()`
+    pub fn locate(
+        &self,
+        usage: Usage,
+        kind: Kind,
+        index: usize,
+    ) -> Option<&Field> {
+        self.fields
+            .iter()
+            .filter(|f| f.kind == kind && f.usage == usage)
+            .nth(index)
+    }
+
+    /// Whether reports start with an ID byte
+    pub fn has_report_ids(&self) -> bool {
+        self.sizes.iter().any(|s| s.1 != 0)
+    }
+
+    /// Length in bytes of report `id` of `kind`, counting the ID byte, as
+    /// `hid_report_size()`. 0 if there's no such report
+    pub fn report_len(&self, kind: Kind, id: u8) -> usize {
+        self.sizes
+            .iter()
+            .find(|s| s.0 == kind && s.1 == id)
+            .map_or(0, |s| s.2.div_ceil(8) as usize + (id != 0) as usize)
+    }
+
+    /// The longest report of `kind`, for sizing buffers
+    pub fn max_report_len(&self, kind: Kind) -> usize {
+        self.sizes
+            .iter()
+            .filter(|s| s.0 == kind)
+            .map(|s| self.report_len(kind, s.1))
+            .max()
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! HID device drivers on `hidbus(4)`
+//!
+//! Drivers for the top-level collections of USB, I2C and Bluetooth HID
+//! devices attach to `hidbus`, whatever the transport. A driver is a
+//! `bus::Driver` whose C method table is declared for `hidbus`, and whose
+//! C probe matches the collection with `HIDBUS_LOOKUP_DRIVER_INFO()`
+//! before calling the Rust one. `HidDevice` then fetches and parses the
+//! report descriptor, and reads and writes reports, and a `HidInterrupt`
+//! receives the input reports from the interrupt pipe.
+
+pub mod descriptor;
+
+#[cfg(not(feature = "mock"))]
+use descriptor::{Kind, ReportDescriptor};
+#[cfg(not(feature = "mock"))]
+use crate::bus::Device;
+#[cfg(not(feature = "mock"))]
+use crate::errno::Errno;
+#[cfg(not(feature = "mock"))]
+use crate::panic::catch_in_module;
+#[cfg(not(feature = "mock"))]
+use core::{ptr, slice};
+
+/// Handles input reports as they arrive
+///
+/// Called from the transport's interrupt path with the `hidbus` lock
+/// held, so it must not sleep. `log!` and `Counter`s are fine.
+#[cfg(not(feature = "mock"))]
+pub trait HidInterrupt: Sync {
+    /// `report` starts with the ID byte if the device numbers reports
+    fn interrupt(&self, report: &[u8]);
+}
+
+/// A `hidbus` child, the top-level collection a driver attached to
+#[cfg(not(feature = "mock"))]
+#[derive(Copy, Clone, Debug)]
+pub struct HidDevice {
+    dev: Device,
+}
+
+#[cfg(not(feature = "mock"))]
+unsafe extern "C" fn intr<T: HidInterrupt>(
+    context: *mut libc::c_void,
+    data: *mut libc::c_void,
+    len: kernel_sys::hid_size_t,
+) {
+    let handler = unsafe { &*(context as *const T) };
+    let report = match data.is_null() {
+        true => &[][..],
+        false => unsafe {
+            slice::from_raw_parts(data as *const u8, len as usize)
+        },
+    };
+    let _ = catch_in_module(|| handler.interrupt(report));
+}
+
+#[cfg(not(feature = "mock"))]
+impl HidDevice {
+    pub fn new(dev: Device) -> Self {
+        HidDevice { dev }
+    }
+
+    pub fn device(&self) -> Device {
+        self.dev
+    }
+
+    /// The raw report descriptor, owned by `hidbus`
+    pub fn report_descriptor(&self) -> Result<&[u8], Errno> {
+        let mut data = ptr::null_mut();
+        let mut len = 0;
+        Errno::result(unsafe {
+            kernel_sys::hid_get_report_descr(
+                self.dev.as_ptr(),
+                &mut data,
+                &mut len,
+            )
+        })?;
+        if data.is_null() {
+            return Err(Errno::NxIo);
+        }
+        Ok(unsafe { slice::from_raw_parts(data as *const u8, len as usize) })
+    }
+
+    /// The report descriptor, parsed
+    pub fn descriptor(&self) -> Result<ReportDescriptor, Errno> {
+        ReportDescriptor::parse(self.report_descriptor()?)
+    }
+
+    /// Read an input report from the interrupt pipe, polling if the
+    /// transport can't do otherwise. Sleeps
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut actlen = 0;
+        Errno::result(unsafe {
+            kernel_sys::hid_read(
+                self.dev.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as kernel_sys::hid_size_t,
+                &mut actlen,
+            )
+        })?;
+        Ok(actlen as usize)
+    }
+
+    /// Send an output report over the interrupt pipe. Sleeps
+    pub fn write(&self, report: &[u8]) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::hid_write(
+                self.dev.as_ptr(),
+                report.as_ptr() as *const _,
+                report.len() as kernel_sys::hid_size_t,
+            )
+        })
+    }
+
+    /// Fetch report `id` of `kind` over the control pipe, `GET_REPORT`.
+    /// Sleeps
+    pub fn get_report(
+        &self,
+        kind: Kind,
+        id: u8,
+        buf: &mut [u8],
+    ) -> Result<usize, Errno> {
+        let mut actlen = 0;
+        Errno::result(unsafe {
+            kernel_sys::hid_get_report(
+                self.dev.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as kernel_sys::hid_size_t,
+                &mut actlen,
+                kind.report_type(),
+                id,
+            )
+        })?;
+        Ok(actlen as usize)
+    }
+
+    /// Send report `id` of `kind` over the control pipe, `SET_REPORT`.
+    /// Sleeps
+    pub fn set_report(
+        &self,
+        kind: Kind,
+        id: u8,
+        report: &[u8],
+    ) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::hid_set_report(
+                self.dev.as_ptr(),
+                report.as_ptr() as *const _,
+                report.len() as kernel_sys::hid_size_t,
+                kind.report_type(),
+                id,
+            )
+        })
+    }
+
+    /// Have `handler` receive input reports once `start_interrupts`
+    /// is called
+    ///
+    /// ## Safety
+    /// `handler` must stay where it is until `stop_interrupts` returns,
+    /// as the driver state does between `Driver::start` and
+    /// `Driver::detach`.
+    pub unsafe fn set_interrupt<T: HidInterrupt>(&self, handler: &T) {
+        unsafe {
+            kernel_sys::hidbus_set_intr(
+                self.dev.as_ptr(),
+                Some(intr::<T>),
+                handler as *const T as *mut libc::c_void,
+            )
+        };
+    }
+
+    pub fn start_interrupts(&self) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::hidbus_intr_start(self.dev.as_ptr())
+        })
+    }
+
+    /// Stop input reports; none are being handled once this returns
+    pub fn stop_interrupts(&self) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::hidbus_intr_stop(self.dev.as_ptr())
+        })
+    }
+}
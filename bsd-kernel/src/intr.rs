@@ -0,0 +1,363 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Hardware interrupt handlers, see `bus_setup_intr(9)`
+//!
+//! A device's interrupt is handled in up to two stages. The filter runs
+//! first, in the interrupt's own context on whatever thread it
+//! interrupted: it must not sleep or take default mutexes, only spin
+//! ones, and typically acknowledges the device and defers the rest. The
+//! ithread handler then runs in the interrupt's thread, where it may take
+//! default mutexes, though it still must not sleep:
+//! ```rust,ignore
+//! let irq = Irq::new(dev, 0, true)?;
+//! let regs = shared.clone();
+//! let intr = Interrupt::new(
+//!     irq,
+//!     IntrType::Net,
+//!     move |_: &FilterContext| match regs.read_4(ISR) {
+//!         0 => FilterResult::Stray,
+//!         _ => FilterResult::ScheduleThread,
+//!     },
+//!     move || shared.rx_eof(),
+//! )?;
+//! ```
+//! The filter is handed a `FilterContext`, which only exists while a
+//! filter runs, so what may only be done from one can ask for it.
+//!
+//! An `Interrupt` owns its `Irq` and its handlers, and tears the handlers
+//! down before dropping either, so a driver that keeps it in its state
+//! can't have them run on state already dropped at detach.
+
+use crate::bus::{Allocation, Device, RF_ACTIVE, RF_SHAREABLE, SYS_RES_IRQ};
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::{fmt, ptr};
+use libc::{c_int, c_void};
+
+/// A device's interrupt line or message, from `bus_alloc_resource(9)`
+pub struct Irq {
+    alloc: Allocation,
+}
+
+impl Irq {
+    /// Interrupt `rid` of `dev`: 0 for a line, or one of `Msi::rids`.
+    /// Lines may be `shareable` with other devices, messages never are
+    pub fn new(dev: Device, rid: i32, shareable: bool) -> Result<Self, Errno> {
+        let flags = match shareable {
+            true => RF_ACTIVE | RF_SHAREABLE,
+            false => RF_ACTIVE,
+        };
+        let alloc = Allocation::new(dev, SYS_RES_IRQ, rid, flags)?;
+        Ok(Irq { alloc })
+    }
+
+    pub fn rid(&self) -> i32 {
+        self.alloc.rid()
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::resource {
+        self.alloc.as_ptr()
+    }
+
+    fn device(&self) -> Device {
+        self.alloc.device()
+    }
+}
+
+impl fmt::Debug for Irq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Irq {{ rid: {} }}", self.rid())
+    }
+}
+
+/// The kind of device interrupting, `INTR_TYPE_*`, which sets the
+/// priority of its interrupt thread
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IntrType {
+    Tty,
+    Bio,
+    Net,
+    Cam,
+    Misc,
+    Clk,
+    Av,
+}
+
+impl IntrType {
+    fn flags(self) -> c_int {
+        (match self {
+            IntrType::Tty => kernel_sys::intr_type_INTR_TYPE_TTY,
+            IntrType::Bio => kernel_sys::intr_type_INTR_TYPE_BIO,
+            IntrType::Net => kernel_sys::intr_type_INTR_TYPE_NET,
+            IntrType::Cam => kernel_sys::intr_type_INTR_TYPE_CAM,
+            IntrType::Misc => kernel_sys::intr_type_INTR_TYPE_MISC,
+            IntrType::Clk => kernel_sys::intr_type_INTR_TYPE_CLK,
+            IntrType::Av => kernel_sys::intr_type_INTR_TYPE_AV,
+        } | kernel_sys::intr_type_INTR_MPSAFE) as c_int
+    }
+}
+
+/// What a filter did with an interrupt, `FILTER_*`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FilterResult {
+    /// Not the device's, as on a shared line
+    Stray,
+    /// Dealt with entirely
+    Handled,
+    /// Acknowledged, with the rest for the ithread handler. The line is
+    /// masked until it has run
+    ScheduleThread,
+}
+
+impl FilterResult {
+    fn as_raw(self) -> c_int {
+        match self {
+            FilterResult::Stray => kernel_sys::FILTER_STRAY,
+            FilterResult::Handled => kernel_sys::FILTER_HANDLED,
+            FilterResult::ScheduleThread => kernel_sys::FILTER_SCHEDULE_THREAD,
+        }
+    }
+}
+
+/// Proof of running in an interrupt filter, where sleeping and default
+/// mutexes are forbidden
+pub struct FilterContext {
+    // Only lent to the filter, on the thread it interrupted
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl FilterContext {
+    /// The CPU the filter is running on, which can't change while it runs
+    pub fn cpu(&self) -> usize {
+        crate::smp::current_cpu()
+    }
+}
+
+impl fmt::Debug for FilterContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FilterContext {{ cpu: {} }}", self.cpu())
+    }
+}
+
+/// The filter of an `Interrupt` with only an ithread handler
+pub type NoFilter = fn(&FilterContext) -> FilterResult;
+
+/// The ithread handler of an `Interrupt` with only a filter
+pub type NoThread = fn();
+
+struct Handlers<F, H> {
+    filter: Option<F>,
+    ithread: Option<H>,
+}
+
+unsafe extern "C" fn filter<F, H>(arg: *mut c_void) -> c_int
+where
+    F: Fn(&FilterContext) -> FilterResult,
+{
+    let handlers = unsafe { &*(arg as *const Handlers<F, H>) };
+    let ctx = FilterContext {
+        _not_send: PhantomData,
+    };
+    let f = handlers.filter.as_ref().unwrap();
+    catch_in_module(|| f(&ctx))
+        .unwrap_or(FilterResult::Stray)
+        .as_raw()
+}
+
+unsafe extern "C" fn ithread<F, H: Fn()>(arg: *mut c_void) {
+    let handlers = unsafe { &*(arg as *const Handlers<F, H>) };
+    let _ = catch_in_module(handlers.ithread.as_ref().unwrap());
+}
+
+/// Handlers set up on an `Irq`, torn down when dropped, after waiting for
+/// any running
+pub struct Interrupt<F, H>
+where
+    F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+    H: Fn() + Send + Sync + 'static,
+{
+    irq: Irq,
+    cookie: *mut c_void,
+    // Boxed as the kernel holds a pointer to it
+    handlers: Box<Handlers<F, H>>,
+}
+
+unsafe impl<F, H> Send for Interrupt<F, H>
+where
+    F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+    H: Fn() + Send + Sync + 'static,
+{
+}
+unsafe impl<F, H> Sync for Interrupt<F, H>
+where
+    F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+    H: Fn() + Send + Sync + 'static,
+{
+}
+
+impl<F, H> Interrupt<F, H>
+where
+    F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+    H: Fn() + Send + Sync + 'static,
+{
+    /// Handle `irq` with `filter`, which schedules `ithread`
+    pub fn new(
+        irq: Irq,
+        kind: IntrType,
+        filter: F,
+        ithread: H,
+    ) -> Result<Self, Errno> {
+        Self::setup(irq, kind, Some(filter), Some(ithread))
+    }
+
+    fn setup(
+        irq: Irq,
+        kind: IntrType,
+        f: Option<F>,
+        h: Option<H>,
+    ) -> Result<Self, Errno> {
+        let filter_fn = f.is_some().then_some(filter::<F, H> as _);
+        let ithread_fn = h.is_some().then_some(ithread::<F, H> as _);
+        let handlers = Box::new(Handlers {
+            filter: f,
+            ithread: h,
+        });
+        let mut cookie = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::bus_setup_intr(
+                irq.device().as_ptr(),
+                irq.as_ptr(),
+                kind.flags(),
+                filter_fn,
+                ithread_fn,
+                &*handlers as *const Handlers<F, H> as *mut c_void,
+                &mut cookie,
+            )
+        })?;
+        Ok(Interrupt {
+            irq,
+            cookie,
+            handlers,
+        })
+    }
+
+    /// Name the handler in `vmstat -i` and `ps(1)`, after the device's
+    /// name, as in `irq16: em0:rx`
+    pub fn describe(&self, name: &CStr) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::bus_describe_intr(
+                self.irq.device().as_ptr(),
+                self.irq.as_ptr(),
+                self.cookie,
+                c"%s".as_ptr(),
+                name.as_ptr(),
+            )
+        })
+    }
+
+    /// Have the interrupt delivered to `cpu`, which may not be possible
+    pub fn bind(&self, cpu: usize) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::bus_bind_intr(
+                self.irq.device().as_ptr(),
+                self.irq.as_ptr(),
+                cpu as c_int,
+            )
+        })
+    }
+
+    /// Tear the handlers down and hand back the `Irq`
+    pub fn into_irq(self) -> Irq {
+        let mut this = core::mem::ManuallyDrop::new(self);
+        this.teardown();
+        unsafe {
+            drop(ptr::read(&this.handlers));
+            ptr::read(&this.irq)
+        }
+    }
+
+    fn teardown(&mut self) {
+        let error = unsafe {
+            kernel_sys::bus_teardown_intr(
+                self.irq.device().as_ptr(),
+                self.irq.as_ptr(),
+                self.cookie,
+            )
+        };
+        // Dropping the handlers while the kernel can still run them would
+        // leave it calling freed memory
+        assert_eq!(error, 0, "bus_teardown_intr failed");
+    }
+}
+
+impl<F> Interrupt<F, NoThread>
+where
+    F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+{
+    /// Handle `irq` with `filter` alone
+    pub fn filter(irq: Irq, kind: IntrType, filter: F) -> Result<Self, Errno> {
+        Self::setup(irq, kind, Some(filter), None)
+    }
+}
+
+impl<H> Interrupt<NoFilter, H>
+where
+    H: Fn() + Send + Sync + 'static,
+{
+    /// Handle `irq` with `ithread` alone, in the interrupt thread
+    pub fn ithread(
+        irq: Irq,
+        kind: IntrType,
+        ithread: H,
+    ) -> Result<Self, Errno> {
+        Self::setup(irq, kind, None, Some(ithread))
+    }
+}
+
+impl<F, H> Drop for Interrupt<F, H>
+where
+    F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+    H: Fn() + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.teardown();
+    }
+}
+
+impl<F, H> fmt::Debug for Interrupt<F, H>
+where
+    F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+    H: Fn() + Send + Sync + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Interrupt {{ irq: {:?}, cookie: {:p} }}",
+            self.irq, self.cookie
+        )
+    }
+}
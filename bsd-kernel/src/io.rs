@@ -24,10 +24,13 @@
 // Based on public domain code by Johannes Lundberg
 
 //use crate::debugln;
+use crate::errno::Errno;
 use alloc::string::String;
 use alloc::vec::Vec;
-use core::{fmt, ptr};
-use libc::{c_char, c_void};
+use core::ffi::CStr;
+use core::ops::{Deref, DerefMut};
+use core::{fmt, mem, slice};
+use libc::c_char;
 
 /// Empty structure that uses libcore's `fmt::Write` trait to provide
 /// support for writing formatted arguments lists (as generated by the
@@ -36,47 +39,201 @@ pub struct KernelDebugWriter {}
 
 impl fmt::Write for KernelDebugWriter {
     fn write_str(&mut self, message: &str) -> fmt::Result {
-        unsafe {
-            let ptr = kernel_sys::malloc(
-                message.len() + 1,
-                &mut kernel_sys::M_DEVBUF[0],
-                kernel_sys::M_WAITOK,
-            ) as *mut c_char;
-            ptr::copy(message.as_ptr(), ptr as *mut u8, message.len());
-            ptr::write(ptr.add(message.len()), 0);
-            kernel_sys::uprintf(ptr);
-            kernel_sys::free(ptr as *mut c_void, &mut kernel_sys::M_DEVBUF[0]);
+        // Printed through a stack buffer, so that printing from a panic
+        // can't recurse into a broken allocator
+        let mut buf = FmtBuf::<128>::new();
+        let mut rest = message;
+        while !rest.is_empty() {
+            buf.clear();
+            let _ = buf.write_str(rest);
+            if buf.is_empty() {
+                break;
+            }
+            rest = &rest[buf.len()..];
+            unsafe { kernel_sys::uprintf(c"%s".as_ptr(), buf.as_ptr()) };
         }
         Ok(())
     }
 }
 
+/// A fixed-capacity string to format into without allocating, such as
+/// on panic, when the allocator may be what failed
+///
+/// Holds up to `N - 1` bytes, so it is always NUL-terminated for C. What
+/// doesn't fit is dropped at a `char` boundary, and the write that
+/// overflowed returns `fmt::Error`.
+pub struct FmtBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FmtBuf<N> {
+    pub const fn new() -> Self {
+        const { assert!(N > 0, "no room for the NUL") };
+        FmtBuf {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole `str`s and `char`s are ever copied in
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+
+    /// The contents as a C string, cut short at any NUL they contain
+    pub fn as_cstr(&self) -> &CStr {
+        CStr::from_bytes_until_nul(&self.buf[..=self.len]).unwrap()
+    }
+
+    pub fn as_ptr(&self) -> *const c_char {
+        self.buf.as_ptr() as *const c_char
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.buf[0] = 0;
+    }
+
+    /// Shorten to `len` bytes, such as back to a length seen before a
+    /// write that overflowed
+    ///
+    /// ## Panics
+    /// If `len` is not at a `char` boundary
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            assert!(self.as_str().is_char_boundary(len));
+            self.len = len;
+            self.buf[len] = 0;
+        }
+    }
+}
+
+impl<const N: usize> fmt::Write for FmtBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(N - 1 - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        self.buf[self.len] = 0;
+        if n < s.len() { Err(fmt::Error) } else { Ok(()) }
+    }
+}
+
+impl<const N: usize> Default for FmtBuf<N> {
+    fn default() -> Self {
+        FmtBuf::new()
+    }
+}
+
+impl<const N: usize> fmt::Display for FmtBuf<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for FmtBuf<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
 pub type Result<T> = core::result::Result<T, Error>;
 
-#[derive(Debug)]
+/// An I/O error: what kind it is, a static description of what failed,
+/// and the errno the kernel returned, if it was the kernel that failed.
+/// Nothing is formatted until it is displayed, so making one can't fail
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Error {
     kind: ErrorKind,
-    error: String,
+    message: &'static str,
+    errno: Option<Errno>,
 }
 
 impl Error {
-    pub fn new<E>(kind: ErrorKind, error: E) -> Error
-    where
-        E: Into<String>,
-    {
+    /// Create an error of `kind` described by `message`
+    pub const fn new(kind: ErrorKind, message: &'static str) -> Error {
         Error {
-            error: error.into(),
             kind,
+            message,
+            errno: None,
+        }
+    }
+
+    /// Create an error for `errno`, returned by the kernel call `message`
+    /// describes
+    pub fn from_errno(errno: Errno, message: &'static str) -> Error {
+        Error {
+            kind: ErrorKind::from(errno),
+            message,
+            errno: Some(errno),
         }
     }
+
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
+
+    pub fn message(&self) -> &'static str {
+        self.message
+    }
+
+    /// The errno the kernel returned, if the error came from the kernel
+    pub fn errno(&self) -> Option<Errno> {
+        self.errno
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "{:?}: {}", self.kind, self.error)
+        write!(fmt, "{:?}: {}", self.kind, self.message)?;
+        match self.errno {
+            Some(errno) => write!(fmt, ": {}", errno),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The errno to return for an I/O error: the kernel's own, or one that
+/// fits `kind`
+impl From<Error> for Errno {
+    fn from(e: Error) -> Errno {
+        e.errno.unwrap_or(match e.kind {
+            ErrorKind::NotFound => Errno::NoEnt,
+            ErrorKind::PermissionDenied => Errno::Perm,
+            ErrorKind::ConnectionRefused => Errno::ConnRefused,
+            ErrorKind::ConnectionReset => Errno::ConnReset,
+            ErrorKind::ConnectionAborted => Errno::ConnAborted,
+            ErrorKind::NotConnected => Errno::NotConn,
+            ErrorKind::AddrInUse => Errno::AddrInUse,
+            ErrorKind::AddrNotAvailable => Errno::AddrNotAvail,
+            ErrorKind::BrokenPipe => Errno::Pipe,
+            ErrorKind::AlreadyExists => Errno::Exist,
+            ErrorKind::WouldBlock => Errno::Again,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => Errno::Inval,
+            ErrorKind::TimedOut => Errno::TimedOut,
+            ErrorKind::Interrupted => Errno::Intr,
+            ErrorKind::WriteZero
+            | ErrorKind::Other
+            | ErrorKind::UnexpectedEof => Errno::Io,
+        })
+    }
+}
+
+/// An I/O error for an errno, described as `strerror(3)` would
+impl From<Errno> for Error {
+    fn from(errno: Errno) -> Error {
+        Error::from_errno(errno, errno.description())
     }
 }
 
@@ -102,6 +259,28 @@ pub enum ErrorKind {
     UnexpectedEof,
 }
 
+impl From<Errno> for ErrorKind {
+    fn from(errno: Errno) -> ErrorKind {
+        match errno {
+            Errno::NoEnt => ErrorKind::NotFound,
+            Errno::Perm | Errno::Acces => ErrorKind::PermissionDenied,
+            Errno::ConnRefused => ErrorKind::ConnectionRefused,
+            Errno::ConnReset => ErrorKind::ConnectionReset,
+            Errno::ConnAborted => ErrorKind::ConnectionAborted,
+            Errno::NotConn => ErrorKind::NotConnected,
+            Errno::AddrInUse => ErrorKind::AddrInUse,
+            Errno::AddrNotAvail => ErrorKind::AddrNotAvailable,
+            Errno::Pipe => ErrorKind::BrokenPipe,
+            Errno::Exist => ErrorKind::AlreadyExists,
+            Errno::Again => ErrorKind::WouldBlock,
+            Errno::Inval => ErrorKind::InvalidInput,
+            Errno::TimedOut => ErrorKind::TimedOut,
+            Errno::Intr => ErrorKind::Interrupted,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
 // This uses an adaptive system to extend the vector when it fills. We want to
 // avoid paying to allocate and zero a huge chunk of memory if the reader only
 // has 4 bytes while still making large reads if the reader does have a ton
@@ -185,6 +364,14 @@ fn read_to_end<R: Read + ?Sized>(
 
 pub trait Read {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+    /// Read into each of `bufs` in turn; the default just fills the first
+    /// that isn't empty
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize> {
+        match bufs.iter_mut().find(|b| !b.is_empty()) {
+            Some(buf) => self.read(buf),
+            None => Ok(0),
+        }
+    }
     fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
         read_to_end(self, buf)
     }
@@ -231,6 +418,14 @@ pub trait Read {
 
 pub trait Write {
     fn write(&mut self, buf: &[u8]) -> Result<usize>;
+    /// Write each of `bufs` in turn; the default just writes the first
+    /// that isn't empty
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        match bufs.iter().find(|b| !b.is_empty()) {
+            Some(buf) => self.write(buf),
+            None => Ok(0),
+        }
+    }
     fn flush(&mut self) -> Result<()>;
     fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
         /* FIXME: uiomove_frombuf expects to see the whole buffer */
@@ -292,3 +487,102 @@ pub trait Write {
         self
     }
 }
+
+/// Where `Seek::seek` moves to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+pub trait Seek {
+    /// Move to `pos`, returning the new offset from the start
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
+    fn stream_position(&mut self) -> Result<u64> {
+        self.seek(SeekFrom::Current(0))
+    }
+}
+
+/// A buffer to write from, one of several for `Write::write_vectored`
+#[derive(Copy, Clone, Debug)]
+pub struct IoSlice<'a>(&'a [u8]);
+
+impl<'a> IoSlice<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        IoSlice(buf)
+    }
+}
+
+impl Deref for IoSlice<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+/// A buffer to read into, one of several for `Read::read_vectored`
+#[derive(Debug)]
+pub struct IoSliceMut<'a>(&'a mut [u8]);
+
+impl<'a> IoSliceMut<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        IoSliceMut(buf)
+    }
+}
+
+impl Deref for IoSliceMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl DerefMut for IoSliceMut<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0
+    }
+}
+
+/// Types any bytes are a valid value of, so can be read in from
+/// userland or hardware as they are
+///
+/// # Safety
+///
+/// Every bit pattern of `size_of::<Self>()` bytes must be a valid `Self`
+pub unsafe trait FromBytes: Copy {}
+
+/// Types that are all bytes, with no padding, so can be sent out without
+/// leaking whatever the padding held
+///
+/// # Safety
+///
+/// `Self` must have no padding or other uninitialized bytes
+pub unsafe trait AsBytes {
+    fn as_bytes(&self) -> &[u8]
+    where
+        Self: Sized,
+    {
+        let p = self as *const Self as *const u8;
+        unsafe { slice::from_raw_parts(p, mem::size_of::<Self>()) }
+    }
+}
+
+macro_rules! plain {
+    ($($t:ty),*) => {
+        $(
+            unsafe impl FromBytes for $t {}
+            unsafe impl AsBytes for $t {}
+        )*
+    };
+}
+
+plain!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+plain!(f32, f64);
+
+unsafe impl<T: FromBytes, const N: usize> FromBytes for [T; N] {}
+unsafe impl<T: AsBytes, const N: usize> AsBytes for [T; N] {}
@@ -0,0 +1,321 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `ioctl(2)` commands for character devices
+//!
+//! A command number encodes its argument's size and whether it is copied
+//! in, out or both, as the `_IO*` macros in `sys/ioccom.h` do; the kernel
+//! does the copying around `d_ioctl`, so the device only sees a kernel
+//! buffer. `#[derive(IoctlEnum)]` turns an enum into such commands:
+//! ```rust,ignore
+//! #[derive(IoctlEnum)]
+//! #[ioctl(group = b'f')]
+//! enum FifoIoctl {
+//!     #[ioctl(ior = 127)]
+//!     NRead(c_int),
+//! }
+//! ```
+//! Each variant names the macro it would use in C and its number, and
+//! carries its argument, if any, as a single `Payload` field. The derive
+//! adds an associated constant per command, named after the variant
+//! (`FifoIoctl::N_READ`), and implements `IoctlEnum` so that
+//! `IoctlRequest::decode` and `IoctlRequest::reply` do the matching and
+//! copying for `CharacterDevice::ioctl`.
+//!
+//! 32-bit processes on a 64-bit kernel lay out arguments holding `long`s,
+//! `size_t`s or 64-bit integers differently, so such commands get a
+//! different number and argument for them. Marking a variant
+//! `#[ioctl(iowr = 3, compat32)]` adds the 32-bit command, suffixed `32`
+//! (`Stats(Stats)` gets `STATS32` next to `STATS`), converting its
+//! argument through `Compat32`, which `#[derive(Compat32)]` implements for
+//! `#[repr(C)]` structs by defining their 32-bit twin (`Stats32`):
+//! ```rust,ignore
+//! #[repr(C)]
+//! #[derive(Copy, Clone, Compat32)]
+//! struct Stats {
+//!     bytes: u64,
+//!     len: usize,
+//! }
+//! unsafe impl Payload for Stats {}
+//! ```
+//! `IoctlRequest` knows which kind of process called, so `decode` and
+//! `reply` pick the right command and layout. Other commands are taken to
+//! look the same to both.
+
+use crate::errno::Errno;
+use core::{fmt, mem, ptr};
+use libc::c_ulong;
+
+pub use bsd_kernel_macros::{Compat32, IoctlEnum};
+
+// From `sys/ioccom.h`
+const IOCPARM_SHIFT: u32 = 13;
+const IOCPARM_MASK: c_ulong = (1 << IOCPARM_SHIFT) - 1;
+const IOC_VOID: c_ulong = 0x2000_0000;
+const IOC_OUT: c_ulong = 0x4000_0000;
+const IOC_IN: c_ulong = 0x8000_0000;
+
+/// Largest argument a command can have
+pub const IOCPARM_MAX: usize = 1 << IOCPARM_SHIFT;
+
+const fn ioc(inout: c_ulong, group: u8, num: u8, len: usize) -> c_ulong {
+    assert!(len <= IOCPARM_MAX, "ioctl argument too large");
+    inout
+        | ((len as c_ulong & IOCPARM_MASK) << 16)
+        | ((group as c_ulong) << 8)
+        | num as c_ulong
+}
+
+/// `_IO`: a command without an argument
+pub const fn io(group: u8, num: u8) -> c_ulong {
+    ioc(IOC_VOID, group, num, 0)
+}
+
+/// `_IOR`: a command whose `len`-byte argument is copied out
+pub const fn ior(group: u8, num: u8, len: usize) -> c_ulong {
+    ioc(IOC_OUT, group, num, len)
+}
+
+/// `_IOW`: a command whose `len`-byte argument is copied in
+pub const fn iow(group: u8, num: u8, len: usize) -> c_ulong {
+    ioc(IOC_IN, group, num, len)
+}
+
+/// `_IOWR`: a command whose `len`-byte argument is copied in and out
+pub const fn iowr(group: u8, num: u8, len: usize) -> c_ulong {
+    ioc(IOC_IN | IOC_OUT, group, num, len)
+}
+
+/// `IOCPARM_LEN`: the size of `cmd`'s argument
+pub const fn param_len(cmd: c_ulong) -> usize {
+    ((cmd >> 16) & IOCPARM_MASK) as usize
+}
+
+/// Plain data that can be an ioctl argument
+///
+/// ## Safety
+/// The type must have no pointers or references, and every bit pattern
+/// must be a valid value, as the argument comes from userland as is
+pub unsafe trait Payload: Copy + 'static {}
+
+macro_rules! payload {
+    ($($t:ty),*) => { $(unsafe impl Payload for $t {})* };
+}
+
+payload!(u8, u16, u32, u64, i8, i16, i32, i64, usize, isize);
+
+unsafe impl<T: Payload, const N: usize> Payload for [T; N] {}
+
+/// An argument as laid out by 32-bit processes, whose 64-bit integers are
+/// only 4-byte aligned
+#[repr(C, packed(4))]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Align4<T: Copy>(pub T);
+
+unsafe impl<T: Payload> Payload for Align4<T> {}
+
+/// An argument with a different layout for 32-bit processes
+pub trait Compat32: Payload {
+    /// The argument as a 32-bit process lays it out
+    type Abi32: Payload;
+
+    fn from_abi32(arg: Self::Abi32) -> Self;
+
+    /// Truncates what doesn't fit, as a C cast would
+    fn to_abi32(&self) -> Self::Abi32;
+}
+
+macro_rules! compat32 {
+    ($($t:ty => $abi32:ty, $wrap:expr, $unwrap:expr;)*) => {$(
+        impl Compat32 for $t {
+            type Abi32 = $abi32;
+
+            fn from_abi32(arg: $abi32) -> Self {
+                $unwrap(arg)
+            }
+
+            fn to_abi32(&self) -> $abi32 {
+                $wrap(*self)
+            }
+        }
+    )*};
+}
+
+compat32! {
+    u8 => u8, |v| v, |v| v;
+    u16 => u16, |v| v, |v| v;
+    u32 => u32, |v| v, |v| v;
+    i8 => i8, |v| v, |v| v;
+    i16 => i16, |v| v, |v| v;
+    i32 => i32, |v| v, |v| v;
+    u64 => Align4<u64>, Align4, |v: Align4<u64>| v.0;
+    i64 => Align4<i64>, Align4, |v: Align4<i64>| v.0;
+    usize => u32, |v| v as u32, |v| v as usize;
+    isize => i32, |v| v as i32, |v| v as isize;
+}
+
+impl<T: Compat32, const N: usize> Compat32 for [T; N] {
+    type Abi32 = [T::Abi32; N];
+
+    fn from_abi32(arg: Self::Abi32) -> Self {
+        arg.map(T::from_abi32)
+    }
+
+    fn to_abi32(&self) -> Self::Abi32 {
+        self.each_ref().map(T::to_abi32)
+    }
+}
+
+/// Whether `td` belongs to a 32-bit process on a 64-bit kernel
+pub(crate) fn is_compat32(td: *mut kernel_sys::thread) -> bool {
+    if cfg!(target_pointer_width = "32") || td.is_null() {
+        return false;
+    }
+    unsafe {
+        let p = (*td).td_proc;
+        !p.is_null()
+            && !(*p).p_sysent.is_null()
+            && (*(*p).p_sysent).sv_flags & kernel_sys::SV_ILP32 as u32 != 0
+    }
+}
+
+/// A set of commands, as implemented by `#[derive(IoctlEnum)]`
+pub trait IoctlEnum: Sized {
+    /// Decode `cmd`, taking its argument from `data`. Fails with
+    /// `Errno::NotTy` for commands not in the set
+    fn decode(cmd: c_ulong, data: &[u8]) -> Result<Self, Errno>;
+
+    /// The command number
+    fn cmd(&self) -> c_ulong;
+
+    /// Store the argument in `data`, if the command copies it out
+    fn encode(&self, data: &mut [u8]);
+
+    /// `decode` for a 32-bit process; the same unless commands are marked
+    /// `compat32`
+    fn decode32(cmd: c_ulong, data: &[u8]) -> Result<Self, Errno> {
+        Self::decode(cmd, data)
+    }
+
+    /// `cmd` for a 32-bit process
+    fn cmd32(&self) -> c_ulong {
+        self.cmd()
+    }
+
+    /// `encode` for a 32-bit process
+    fn encode32(&self, data: &mut [u8]) {
+        self.encode(data)
+    }
+}
+
+/// Read a `T` from the start of `data`
+pub fn read_payload<T: Payload>(data: &[u8]) -> Result<T, Errno> {
+    if data.len() < mem::size_of::<T>() {
+        return Err(Errno::Inval);
+    }
+    Ok(unsafe { ptr::read_unaligned(data.as_ptr() as *const T) })
+}
+
+/// Write `value` to the start of `data`
+///
+/// ## Panics
+/// Panics if `data` is too short
+pub fn write_payload<T: Payload>(data: &mut [u8], value: &T) {
+    assert!(data.len() >= mem::size_of::<T>());
+    unsafe { ptr::write_unaligned(data.as_mut_ptr() as *mut T, *value) };
+}
+
+/// An `ioctl(2)` call, with its argument already copied in
+pub struct IoctlRequest<'a> {
+    cmd: c_ulong,
+    data: &'a mut [u8],
+    compat32: bool,
+}
+
+impl<'a> IoctlRequest<'a> {
+    /// Wrap a call to `cmd` whose argument is in `data`
+    pub fn new(cmd: c_ulong, data: &'a mut [u8]) -> Self {
+        IoctlRequest {
+            cmd,
+            data,
+            compat32: false,
+        }
+    }
+
+    /// Wrap a call from a 32-bit process on a 64-bit kernel
+    pub fn new_compat32(cmd: c_ulong, data: &'a mut [u8]) -> Self {
+        IoctlRequest {
+            cmd,
+            data,
+            compat32: true,
+        }
+    }
+
+    /// Whether a 32-bit process on a 64-bit kernel made the call
+    pub fn is_compat32(&self) -> bool {
+        self.compat32
+    }
+
+    /// The command number
+    pub fn cmd(&self) -> c_ulong {
+        self.cmd
+    }
+
+    /// The kernel copy of the argument, `param_len(cmd)` bytes long
+    pub fn data(&mut self) -> &mut [u8] {
+        self.data
+    }
+
+    /// Decode the call as one of `T`'s commands
+    pub fn decode<T: IoctlEnum>(&self) -> Result<T, Errno> {
+        if self.compat32 {
+            T::decode32(self.cmd, self.data)
+        } else {
+            T::decode(self.cmd, self.data)
+        }
+    }
+
+    /// Store the result of a decoded command, to be copied out when the
+    /// handler returns `Ok`
+    pub fn reply<T: IoctlEnum>(&mut self, cmd: &T) {
+        if self.compat32 {
+            debug_assert_eq!(cmd.cmd32(), self.cmd);
+            cmd.encode32(self.data);
+        } else {
+            debug_assert_eq!(cmd.cmd(), self.cmd);
+            cmd.encode(self.data);
+        }
+    }
+}
+
+impl fmt::Debug for IoctlRequest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "IoctlRequest {{ cmd: {:#x}, len: {}, compat32: {} }}",
+            self.cmd,
+            self.data.len(),
+            self.compat32
+        )
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Jails, `struct prison`, and state kept for each of them
+//!
+//! A device node exposed into jails by `devfs.rules(5)` is called by jailed
+//! processes as well as the host's. The caller's credentials say which
+//! prison it is in, and whether it may see what others did:
+//!
+//! ```rust,ignore
+//! fn open(&mut self, td: &CurThread) -> Result<(), Errno> {
+//!     let prison = td.cred().prison();
+//!     if !prison.is_host() {
+//!         let (id, name) = (prison.id(), prison.name());
+//!         bsd_kernel::log!("opened from jail {} ({:?})", id, name);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+//!
+//! A `JailState` keeps a value for each jail, in the jail's `osd(9)` slot,
+//! which is dropped along with the jail, or with the `JailState`:
+//!
+//! ```rust,ignore
+//! struct Module {
+//!     quotas: JailState<Quota>,
+//! }
+//!
+//! let quota = self
+//!     .quotas
+//!     .get_or_insert_with(td.cred().prison(), Quota::default)?;
+//! ```
+
+use crate::cred::Credential;
+use crate::errno::Errno;
+use alloc::sync::Arc;
+use core::ffi::{CStr, c_void};
+use core::marker::PhantomData;
+use core::{fmt, ptr};
+
+/// A jail, or the host's `prison0`, which jails are made under
+#[repr(transparent)]
+pub struct Prison(kernel_sys::prison);
+
+impl Prison {
+    /// `prison0`, the host itself
+    pub fn host() -> &'static Prison {
+        unsafe { Prison::from_raw(&raw mut kernel_sys::prison0) }
+    }
+
+    /// Borrow the prison at `pr`
+    ///
+    /// ## Safety
+    ///
+    /// `pr` must be valid, and have a reference held for `'a`, as the
+    /// credentials referring to it do
+    pub unsafe fn from_raw<'a>(pr: *mut kernel_sys::prison) -> &'a Self {
+        unsafe { &*pr.cast() }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::prison {
+        &self.0 as *const _ as *mut _
+    }
+
+    /// The jail ID, `jid`, 0 for the host
+    pub fn id(&self) -> i32 {
+        self.0.pr_id
+    }
+
+    /// The jail's name, its full dotted path below the host. The host's
+    /// is `0`
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.0.pr_name.as_ptr()) }
+    }
+
+    /// Whether this is the host rather than a jail
+    pub fn is_host(&self) -> bool {
+        ptr::eq(self, Prison::host())
+    }
+
+    /// The prison the jail was made in, `None` for the host
+    pub fn parent(&self) -> Option<&Prison> {
+        let pr = self.0.pr_parent;
+        (!pr.is_null()).then(|| unsafe { Prison::from_raw(pr) })
+    }
+
+    /// Whether `other` is a jail made below this one, at any depth
+    pub fn is_ancestor_of(&self, other: &Prison) -> bool {
+        unsafe {
+            kernel_sys::prison_ischild(self.as_ptr(), other.as_ptr()) != 0
+        }
+    }
+
+    /// Run `f` with the prison's mutex, `pr_mtx`, held
+    fn locked<R>(&self, f: impl FnOnce() -> R) -> R {
+        let mtx = unsafe { &raw mut (*self.as_ptr()).pr_mtx.mtx_lock };
+        unsafe { kernel_sys::_mtx_lock_flags(mtx, 0, ptr::null(), 0) };
+        let r = f();
+        unsafe { kernel_sys::_mtx_unlock_flags(mtx, 0, ptr::null(), 0) };
+        r
+    }
+
+    fn osd(&self) -> *mut kernel_sys::osd {
+        unsafe { &raw mut (*self.as_ptr()).pr_osd }
+    }
+}
+
+impl fmt::Debug for Prison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Prison {{ id: {}, name: {:?} }}", self.id(), self.name())
+    }
+}
+
+impl Credential {
+    /// The prison the credentials belong to, `Prison::host()` outside of
+    /// jails
+    pub fn prison(&self) -> &Prison {
+        unsafe { Prison::from_raw((*self.as_ptr()).cr_prison) }
+    }
+
+    /// `prison_check`: whether the credentials may see what `other` does,
+    /// which they may if it is in their prison or one below it. Fails
+    /// with `Errno::Srch`, as for a process that isn't there, if not
+    pub fn can_see(&self, other: &Credential) -> Result<(), Errno> {
+        match unsafe { kernel_sys::prison_check(self.as_ptr(), other.as_ptr()) }
+        {
+            0 => Ok(()),
+            e => Err(Errno::from_raw(e).unwrap_or(Errno::Srch)),
+        }
+    }
+}
+
+/// A value of `T` kept for each jail, in an `osd(9)` slot of its own
+///
+/// Values are shared as `Arc`s, so one can be used after the jail is gone.
+/// The slot's reference is dropped as the jail is removed, or for every
+/// jail when the `JailState` is dropped, which must be before the module
+/// is unloaded.
+pub struct JailState<T> {
+    slot: u32,
+    _value: PhantomData<Arc<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for JailState<T> {}
+unsafe impl<T: Send + Sync> Sync for JailState<T> {}
+
+impl<T: Send + Sync> JailState<T> {
+    /// Register a slot, with no values in it yet
+    pub fn new() -> Self {
+        let slot = unsafe {
+            kernel_sys::osd_register(
+                kernel_sys::OSD_JAIL as _,
+                Some(destroy::<T>),
+                ptr::null(),
+            )
+        };
+        JailState {
+            slot,
+            _value: PhantomData,
+        }
+    }
+
+    /// The value kept for `prison`, if there is one
+    pub fn get(&self, prison: &Prison) -> Option<Arc<T>> {
+        // Locked so it can't be removed before the reference is taken
+        prison.locked(|| {
+            let p = self.raw(prison);
+            (!p.is_null()).then(|| unsafe {
+                Arc::increment_strong_count(p);
+                Arc::from_raw(p)
+            })
+        })
+    }
+
+    /// Keep `value` for `prison`, returning the value it replaces. Fails
+    /// with `Errno::NoMem` if the jail's slots can't be grown
+    pub fn set(
+        &self,
+        prison: &Prison,
+        value: T,
+    ) -> Result<Option<Arc<T>>, Errno> {
+        self.replace(prison, Arc::into_raw(Arc::new(value)))
+    }
+
+    /// The value kept for `prison`, keeping the one `f` makes first if
+    /// there is none
+    pub fn get_or_insert_with(
+        &self,
+        prison: &Prison,
+        f: impl FnOnce() -> T,
+    ) -> Result<Arc<T>, Errno> {
+        if let Some(value) = self.get(prison) {
+            return Ok(value);
+        }
+        // Made unlocked, as it may sleep to allocate
+        let kept = Arc::into_raw(Arc::new(f()));
+        let found = prison.locked(|| {
+            let mut p = self.raw(prison);
+            // Unless another thread kept one meanwhile
+            if p.is_null() {
+                self.store(prison, kept)?;
+                p = kept;
+            }
+            unsafe { Arc::increment_strong_count(p) };
+            Ok(p)
+        });
+        if found != Ok(kept) {
+            drop(unsafe { Arc::from_raw(kept) });
+        }
+        found.map(|p| unsafe { Arc::from_raw(p) })
+    }
+
+    /// Stop keeping a value for `prison`, returning it
+    pub fn remove(&self, prison: &Prison) -> Option<Arc<T>> {
+        self.replace(prison, ptr::null()).ok().flatten()
+    }
+
+    /// Swap the slot's pointer for `prison` for `new`, taking its
+    /// reference, and giving the old one's back
+    fn replace(
+        &self,
+        prison: &Prison,
+        new: *const T,
+    ) -> Result<Option<Arc<T>>, Errno> {
+        let old = prison.locked(|| {
+            let old = self.raw(prison);
+            self.store(prison, new).map(|()| old)
+        });
+        match old {
+            Ok(old) => {
+                Ok((!old.is_null()).then(|| unsafe { Arc::from_raw(old) }))
+            }
+            Err(e) => {
+                if !new.is_null() {
+                    drop(unsafe { Arc::from_raw(new) });
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// The slot's pointer for `prison`, with `pr_mtx` held
+    fn raw(&self, prison: &Prison) -> *const T {
+        unsafe {
+            kernel_sys::osd_get(
+                kernel_sys::OSD_JAIL as _,
+                prison.osd(),
+                self.slot,
+            )
+            .cast()
+        }
+    }
+
+    /// Set the slot's pointer for `prison`, with `pr_mtx` held
+    fn store(&self, prison: &Prison, p: *const T) -> Result<(), Errno> {
+        match unsafe {
+            kernel_sys::osd_set(
+                kernel_sys::OSD_JAIL as _,
+                prison.osd(),
+                self.slot,
+                p as *mut c_void,
+            )
+        } {
+            0 => Ok(()),
+            e => Err(Errno::from_raw(e).unwrap_or(Errno::NoMem)),
+        }
+    }
+}
+
+impl<T: Send + Sync> Default for JailState<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for JailState<T> {
+    fn drop(&mut self) {
+        // Calls `destroy` on every jail's value
+        unsafe {
+            kernel_sys::osd_deregister(kernel_sys::OSD_JAIL as _, self.slot)
+        };
+    }
+}
+
+impl<T> fmt::Debug for JailState<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JailState {{ slot: {} }}", self.slot)
+    }
+}
+
+/// The slot's `osd_destructor_t`, dropping the slot's reference as the
+/// jail is removed or the slot deregistered
+unsafe extern "C" fn destroy<T>(value: *mut c_void) {
+    if !value.is_null() {
+        drop(unsafe { Arc::from_raw(value as *const T) });
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Kernel environment lookups, see `kenv(2)` and `getenv(9)`
+//!
+//! Loader tunables are read with `fetch`, typically while loading, in the
+//! way C modules use `TUNABLE_INT_FETCH` and friends:
+//!
+//! ```rust,ignore
+//! let mut debug = false;
+//! kenv::fetch(c"hw.rustfifo.debug", &mut debug);
+//! let size = kenv::get::<u64>(c"hw.rustfifo.size").unwrap_or(64 * 1024);
+//! ```
+//!
+//! Integers take the `k`, `m`, `g` and `t` suffixes of `loader.conf(5)`,
+//! and bools `0`, `1`, `true` and `false`. Other variables, such as the
+//! `smbios.*` ones the loader sets, are read as a `Value`, and the whole
+//! environment is walked with `vars`:
+//!
+//! ```rust,ignore
+//! if let Some(product) = kenv::get::<kenv::Value>(c"smbios.system.product") {
+//!     println!("running on {:?}", product.as_c_str());
+//! }
+//! for (name, value) in kenv::vars().iter() {
+//!     if name.starts_with(b"hint.rustfifo.") {
+//!         // ...
+//!     }
+//! }
+//! ```
+
+use crate::errno::Errno;
+use alloc::string::String;
+use core::ffi::{CStr, c_char, c_int, c_long, c_uint, c_ulong};
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::{fmt, ptr};
+
+/// Look up `name` in the kernel environment, which holds loader
+/// tunables and variables set with `kenv(1)`
+pub fn getenv(name: &CStr) -> Option<String> {
+    Value::get(name).map(|v| v.to_string_lossy().into_owned())
+}
+
+/// A variable's value as `kern_getenv` copied it, which is given back with
+/// `freeenv` when dropped
+pub struct Value {
+    p: *mut c_char,
+}
+
+unsafe impl Send for Value {}
+unsafe impl Sync for Value {}
+
+impl Value {
+    pub fn as_c_str(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.p) }
+    }
+}
+
+impl Deref for Value {
+    type Target = CStr;
+
+    fn deref(&self) -> &CStr {
+        self.as_c_str()
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::freeenv(self.p) };
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Value {{ {:?} }}", self.as_c_str())
+    }
+}
+
+/// Set the variable `name` to `value`, as `kenv(1)` does, for code that
+/// reads it later. May sleep.
+///
+/// Fails with `Errno::Inval` before the environment can be changed, early
+/// in boot, or if either is too long.
+pub fn set(name: &CStr, value: &CStr) -> Result<(), Errno> {
+    match unsafe { kernel_sys::kern_setenv(name.as_ptr(), value.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(Errno::Inval),
+    }
+}
+
+/// Remove the variable `name`. Fails with `Errno::NoEnt` if it isn't set
+pub fn unset(name: &CStr) -> Result<(), Errno> {
+    match unsafe { kernel_sys::kern_unsetenv(name.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(Errno::NoEnt),
+    }
+}
+
+/// The whole environment, locked for reading through `iter` until it is
+/// dropped. Don't sleep while holding it: it holds `kenv_lock`, a mutex.
+pub fn vars() -> Vars {
+    // Until the environment is copied to the heap during boot, it is the
+    // static one the loader passed, which nothing changes
+    let dynamic = unsafe { kernel_sys::dynamic_kenv };
+    if dynamic {
+        unsafe {
+            kernel_sys::_mtx_lock_flags(
+                &raw mut kernel_sys::kenv_lock.mtx_lock,
+                0,
+                ptr::null(),
+                0,
+            )
+        };
+    }
+    Vars {
+        dynamic,
+        _not_send: PhantomData,
+    }
+}
+
+/// The locked kernel environment, see `vars`
+pub struct Vars {
+    dynamic: bool,
+    // Unlocked on the thread that locked it
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl Vars {
+    /// Each variable's name and value, in no particular order
+    pub fn iter(&self) -> VarsIter<'_> {
+        VarsIter {
+            dynamic: self.dynamic,
+            next: 0,
+            envp: unsafe { kernel_sys::kern_envp },
+            _vars: PhantomData,
+        }
+    }
+}
+
+impl Drop for Vars {
+    fn drop(&mut self) {
+        if self.dynamic {
+            unsafe {
+                kernel_sys::_mtx_unlock_flags(
+                    &raw mut kernel_sys::kenv_lock.mtx_lock,
+                    0,
+                    ptr::null(),
+                    0,
+                )
+            };
+        }
+    }
+}
+
+impl fmt::Debug for Vars {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Vars {{ dynamic: {} }}", self.dynamic)
+    }
+}
+
+/// Iterator over `Vars`, yielding each name and value
+pub struct VarsIter<'a> {
+    dynamic: bool,
+    // Index into `kenvp`, for the dynamic environment
+    next: usize,
+    // The next `name=value` string of the static one
+    envp: *const c_char,
+    _vars: PhantomData<&'a Vars>,
+}
+
+impl<'a> Iterator for VarsIter<'a> {
+    type Item = (&'a [u8], &'a CStr);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = if self.dynamic {
+            // `kenvp` is a NULL terminated array of `name=value` strings
+            let p = unsafe { *kernel_sys::kenvp.add(self.next) };
+            if p.is_null() {
+                return None;
+            }
+            self.next += 1;
+            unsafe { CStr::from_ptr(p) }
+        } else {
+            // The static environment is a run of NUL terminated strings,
+            // ended by an empty one
+            if self.envp.is_null() {
+                return None;
+            }
+            let entry = unsafe { CStr::from_ptr(self.envp) };
+            if entry.is_empty() {
+                return None;
+            }
+            self.envp = unsafe { self.envp.add(entry.count_bytes() + 1) };
+            entry
+        };
+        let bytes = entry.to_bytes_with_nul();
+        Some(match bytes.iter().position(|&b| b == b'=') {
+            Some(eq) => {
+                let value = &bytes[eq + 1..];
+                (&bytes[..eq], CStr::from_bytes_with_nul(value).unwrap())
+            }
+            None => (entry.to_bytes(), c""),
+        })
+    }
+}
+
+impl fmt::Debug for VarsIter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VarsIter {{ dynamic: {} }}", self.dynamic)
+    }
+}
+
+/// A type a tunable can be read as, see `get`
+pub trait Tunable: Sized {
+    /// Parse the tunable `name`, if set and valid
+    fn get(name: &CStr) -> Option<Self>;
+}
+
+macro_rules! tunable_int {
+    ($t:ty, $c:ty, $getenv:ident) => {
+        impl Tunable for $t {
+            fn get(name: &CStr) -> Option<Self> {
+                let mut value: $c = 0;
+                let found =
+                    unsafe { kernel_sys::$getenv(name.as_ptr(), &mut value) };
+                (found != 0).then_some(value as $t)
+            }
+        }
+    };
+}
+
+tunable_int!(i32, c_int, getenv_int);
+tunable_int!(u32, c_uint, getenv_uint);
+tunable_int!(isize, c_long, getenv_long);
+tunable_int!(usize, c_ulong, getenv_ulong);
+tunable_int!(i64, i64, getenv_quad);
+tunable_int!(u64, u64, getenv_uquad);
+
+impl Tunable for bool {
+    fn get(name: &CStr) -> Option<Self> {
+        let mut value = false;
+        unsafe { kernel_sys::getenv_bool(name.as_ptr(), &mut value) }
+            .then_some(value)
+    }
+}
+
+impl Tunable for String {
+    fn get(name: &CStr) -> Option<Self> {
+        getenv(name)
+    }
+}
+
+impl Tunable for Value {
+    fn get(name: &CStr) -> Option<Self> {
+        let p = unsafe { kernel_sys::kern_getenv(name.as_ptr()) };
+        (!p.is_null()).then_some(Value { p })
+    }
+}
+
+/// Read the tunable `name` as a `T`. `None` if it isn't set, or doesn't
+/// parse as one
+pub fn get<T: Tunable>(name: &CStr) -> Option<T> {
+    T::get(name)
+}
+
+/// Like `TUNABLE_*_FETCH`: overwrite `value` with the tunable `name` if it
+/// is set, leaving the default in place otherwise. Returns whether it was
+pub fn fetch<T: Tunable>(name: &CStr, value: &mut T) -> bool {
+    match T::get(name) {
+        Some(v) => {
+            *value = v;
+            true
+        }
+        None => false,
+    }
+}
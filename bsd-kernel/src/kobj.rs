@@ -0,0 +1,237 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! kobj(9) classes implemented in Rust
+//!
+//! kobj is the object system under newbus and the other kernel
+//! interfaces described by `*_if.m` files: a class is a table of methods
+//! keyed by the interfaces' method descriptors, and an object is a struct
+//! whose first field points at its class's compiled method cache.
+//! `#[kobj::methods]` builds a type's table from an impl block, each
+//! `extern "C"` function tagged with the interface method it implements
+//! and checked against that method's `*_t` type:
+//! ```ignore
+//! struct Mouse;
+//!
+//! #[kobj::methods]
+//! impl Mouse {
+//!     #[method(device_probe)]
+//!     extern "C" fn probe(dev: kernel_sys::device_t) -> c_int { ... }
+//!
+//!     #[method(device_attach)]
+//!     extern "C" fn attach(dev: kernel_sys::device_t) -> c_int { ... }
+//! }
+//!
+//! #[unsafe(no_mangle)]
+//! pub static rusthid_driver: kobj::Class =
+//!     kobj::Class::new::<Mouse>(c"rusthid", mem::size_of::<usize>());
+//! ```
+//! A class made this way is a `driver_t` to C, so the module's C file only
+//! declares it `extern` for `DRIVER_MODULE`. Methods are called straight
+//! from the kernel, so they catch panics themselves, as with
+//! `panic::catch_in_module`. Interfaces kernel-sys doesn't bind yet go in
+//! the `INTERFACES` list of its `build.rs`.
+//!
+//! Objects of any class can also be made from Rust with `Object`, and the
+//! methods of an object called through `lookup`.
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::ops::Deref;
+use core::{fmt, ptr};
+
+/// One entry of a method table, `KOBJMETHOD()`
+#[repr(transparent)]
+pub struct Method(kernel_sys::kobj_method_t);
+
+unsafe impl Send for Method {}
+unsafe impl Sync for Method {}
+
+impl Method {
+    /// The end of a table, `KOBJMETHOD_END`
+    pub const END: Method = Method(kernel_sys::kobj_method_t {
+        desc: ptr::null_mut(),
+        func: None,
+    });
+
+    /// ## Safety
+    /// `func` must be the method `desc` describes, transmuted from the
+    /// method's `*_t` type.
+    pub const unsafe fn new(
+        desc: kernel_sys::kobjop_desc_t,
+        func: kernel_sys::kobjop_t,
+    ) -> Self {
+        Method(kernel_sys::kobj_method_t { desc, func })
+    }
+}
+
+impl fmt::Debug for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Method {{ desc: {:?} }}", self.0.desc)
+    }
+}
+
+pub use bsd_kernel_macros::kobj_methods as methods;
+
+/// A type's method table, as `#[kobj::methods]` implements it
+///
+/// ## Safety
+/// Every entry must be made with `Method::new` under its contract, and
+/// the table must end with `Method::END`.
+pub unsafe trait Methods {
+    const METHODS: &'static [Method];
+}
+
+/// A kobj class, `DEFINE_CLASS_0()`, which newbus also takes as a
+/// `driver_t`
+///
+/// The kernel compiles the class's method cache into it on first use, so
+/// it is kept in a `static`.
+#[repr(transparent)]
+pub struct Class(UnsafeCell<kernel_sys::kobj_class>);
+
+unsafe impl Send for Class {}
+unsafe impl Sync for Class {}
+
+impl Class {
+    /// The class named `name` with `T`'s methods, whose objects take
+    /// `size` bytes. For drivers that is the size of the softc
+    pub const fn new<T: Methods>(name: &'static CStr, size: usize) -> Self {
+        let methods = T::METHODS;
+        Class(UnsafeCell::new(kernel_sys::kobj_class {
+            name: name.as_ptr(),
+            methods: methods.as_ptr() as *mut kernel_sys::kobj_method_t,
+            size,
+            baseclasses: ptr::null_mut(),
+            refs: 0,
+            ops: ptr::null_mut(),
+        }))
+    }
+
+    pub fn as_ptr(&self) -> kernel_sys::kobj_class_t {
+        self.0.get()
+    }
+
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.as_ptr()).name) }
+    }
+}
+
+impl fmt::Debug for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Class {{ name: {:?} }}", self.name())
+    }
+}
+
+/// Look up `desc` in `obj`'s class, as the kobj method wrappers generated
+/// into `*_if.h` do with `KOBJOPLOOKUP()`. Classes lacking the method get
+/// the interface's default, so the result is only null for methods
+/// without one
+///
+/// ## Safety
+/// `obj` must be a live kobj, such as a `device_t`, and the result must
+/// be transmuted to the method's `*_t` type.
+pub unsafe fn lookup(
+    obj: kernel_sys::kobj_t,
+    desc: kernel_sys::kobjop_desc_t,
+) -> kernel_sys::kobjop_t {
+    unsafe {
+        let ops = (*obj).ops;
+        let slot =
+            (*desc).id as usize & (kernel_sys::KOBJ_CACHE_SIZE as usize - 1);
+        let cep = &raw mut (*ops).cache[slot];
+        let mut ce = *cep;
+        if (*ce).desc != desc {
+            ce = kernel_sys::kobj_lookup_method((*ops).cls, cep, desc);
+        }
+        (*ce).func
+    }
+}
+
+#[repr(C)]
+struct Repr<T> {
+    kobj: kernel_sys::kobj,
+    state: T,
+}
+
+/// An object of a kobj class, holding a `T` after the kobj header, for
+/// interfaces whose consumers are handed a `kobj_t` to call
+pub struct Object<T> {
+    ptr: ptr::NonNull<Repr<T>>,
+}
+
+unsafe impl<T: Send> Send for Object<T> {}
+unsafe impl<T: Sync> Sync for Object<T> {}
+
+impl<T> Object<T> {
+    /// Make an object of `class` holding `state`, `kobj_init()`. The
+    /// class is compiled on first use, which may sleep
+    pub fn new(class: &'static Class, state: T) -> Self {
+        let repr = Box::new(Repr {
+            kobj: kernel_sys::kobj {
+                ops: ptr::null_mut(),
+            },
+            state,
+        });
+        let ptr = ptr::NonNull::from(Box::leak(repr));
+        unsafe {
+            kernel_sys::kobj_init(ptr.as_ptr().cast(), class.as_ptr());
+        }
+        Object { ptr }
+    }
+
+    pub fn as_ptr(&self) -> kernel_sys::kobj_t {
+        self.ptr.as_ptr().cast()
+    }
+
+    /// The state of the object `obj`, as in a method called on it
+    ///
+    /// ## Safety
+    /// `obj` must come from `Object::<T>::as_ptr` on an object that
+    /// outlives `'a`.
+    pub unsafe fn state<'a>(obj: kernel_sys::kobj_t) -> &'a T {
+        unsafe { &(*obj.cast::<Repr<T>>()).state }
+    }
+}
+
+impl<T> Deref for Object<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &self.ptr.as_ref().state }
+    }
+}
+
+impl<T> Drop for Object<T> {
+    fn drop(&mut self) {
+        // Drops the class's reference, freeing its cache with the last one
+        unsafe { kernel_sys::kobj_delete(self.as_ptr(), ptr::null_mut()) };
+        drop(unsafe { Box::from_raw(self.ptr.as_ptr()) });
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Object<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Object {{ state: {:?} }}", &**self)
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! kqueue(2) filters, see `kqueue(9)`
+//!
+//! An object that userland waits on with `kevent(2)` keeps the knotes
+//! attached to it in a `KnList`, and calls `KnList::notify` when its
+//! state changes; the filter then decides, in `Filter::event`, whether
+//! each knote fires. Filters of the base system's types are set per file
+//! or device, but a module can also provide a whole filter type of its
+//! own, with its own meaning for the event's ident and flags:
+//! ```ignore
+//! static RUSTMOD_FILTER: FilterOps = FilterOps::new::<RustModEvents>();
+//!
+//! // in the module's load and unload events
+//! RUSTMOD_FILTER.register()?;
+//! RUSTMOD_FILTER.unregister()?;
+//! ```
+//! The kernel only has room for the `EVFILT_*` numbers in
+//! `sys/event.h`, and takes filters for those it leaves to modules, such
+//! as `EVFILT_LIO` while `aio(4)` isn't loaded.
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use core::{fmt, mem};
+use libc::{c_int, c_long, c_void};
+
+/// A knote, one kevent registration on an object
+pub struct Knote {
+    kn: NonNull<kernel_sys::knote>,
+}
+
+impl Knote {
+    /// ## Safety
+    /// `kn` must be a live knote for as long as the wrapper is used
+    pub unsafe fn from_raw(kn: *mut kernel_sys::knote) -> Self {
+        Knote {
+            kn: NonNull::new(kn).unwrap(),
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::knote {
+        self.kn.as_ptr()
+    }
+
+    /// The kevent's ident, for a filter of type `IS_FD` a descriptor
+    pub fn ident(&self) -> usize {
+        unsafe { (*self.as_ptr()).kn_kevent.ident }
+    }
+
+    /// The kevent's `EVFILT_*` filter
+    pub fn filter(&self) -> i16 {
+        unsafe { (*self.as_ptr()).kn_kevent.filter }
+    }
+
+    /// The `fflags` userland registered the kevent with
+    pub fn sfflags(&self) -> u32 {
+        unsafe { (*self.as_ptr()).kn_sfflags as u32 }
+    }
+
+    /// The `data` userland registered the kevent with
+    pub fn sdata(&self) -> i64 {
+        unsafe { (*self.as_ptr()).kn_sdata }
+    }
+
+    /// Set the `fflags` userland will see when the knote fires
+    pub fn set_fflags(&self, fflags: u32) {
+        unsafe { (*self.as_ptr()).kn_kevent.fflags = fflags };
+    }
+
+    /// Set the `data` userland will see when the knote fires
+    pub fn set_data(&self, data: i64) {
+        unsafe { (*self.as_ptr()).kn_kevent.data = data };
+    }
+
+    /// The filter's own pointer, such as to the object watched
+    pub fn hook(&self) -> *mut c_void {
+        unsafe { (*self.as_ptr()).kn_hook }
+    }
+
+    pub fn set_hook(&self, hook: *mut c_void) {
+        unsafe { (*self.as_ptr()).kn_hook = hook };
+    }
+}
+
+impl fmt::Debug for Knote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Knote {{ ident: {}, sfflags: {:#x} }}",
+            self.ident(),
+            self.sfflags()
+        )
+    }
+}
+
+/// The knotes attached to an object, see `knlist_add(9)`. Cleared when
+/// dropped, which ends each kevent with `EV_EOF`
+pub struct KnList {
+    // Boxed so the knlist keeps a stable address when the owner moves
+    knl: Box<UnsafeCell<kernel_sys::knlist>>,
+}
+
+unsafe impl Send for KnList {}
+unsafe impl Sync for KnList {}
+
+impl KnList {
+    /// Create an empty list, locked by the kernel's shared knlist lock
+    pub fn new() -> Self {
+        let knl = Box::new(UnsafeCell::new(unsafe { mem::zeroed() }));
+        unsafe {
+            kernel_sys::knlist_init_mtx(knl.get(), core::ptr::null_mut())
+        };
+        KnList { knl }
+    }
+
+    /// Attach `kn`, usually from `Filter::attach`
+    pub fn add(&self, kn: &Knote) {
+        unsafe { kernel_sys::knlist_add(self.as_ptr(), kn.as_ptr(), 0) };
+    }
+
+    /// Detach `kn`, usually from `Filter::detach`
+    pub fn remove(&self, kn: &Knote) {
+        unsafe { kernel_sys::knlist_remove(self.as_ptr(), kn.as_ptr(), 0) };
+    }
+
+    /// Run the filter's `event` on each knote, with `hint` saying what
+    /// changed, and activate those that fire, as `KNOTE_UNLOCKED`
+    pub fn notify(&self, hint: i64) {
+        unsafe { kernel_sys::knote(self.as_ptr(), hint, 0) };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        unsafe { kernel_sys::knlist_empty(self.as_ptr()) != 0 }
+    }
+
+    /// Detach every knote, ending each kevent with `EV_EOF`, for when
+    /// the object is going away before the list is
+    pub fn clear(&self) {
+        // knlist_clear(), a macro
+        unsafe {
+            kernel_sys::knlist_cleardel(
+                self.as_ptr(),
+                core::ptr::null_mut(),
+                0,
+                0,
+            )
+        };
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::knlist {
+        self.knl.get()
+    }
+}
+
+impl Default for KnList {
+    fn default() -> Self {
+        KnList::new()
+    }
+}
+
+impl Drop for KnList {
+    fn drop(&mut self) {
+        self.clear();
+        unsafe { kernel_sys::knlist_destroy(self.as_ptr()) };
+    }
+}
+
+impl fmt::Debug for KnList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KnList {{ empty: {} }}", self.is_empty())
+    }
+}
+
+/// A filter type of a module's own. There is one of each, so its
+/// methods take no `self`; per-knote state goes in `Knote::hook`
+pub trait Filter: 'static {
+    /// The `EVFILT_*` number, negative, that the filter is registered as
+    const EVFILT: i16;
+    /// Whether idents are file descriptors, held while the kevent is
+    const IS_FD: bool = false;
+
+    /// Set up a new kevent, usually adding `kn` to a `KnList`, or refuse
+    /// it
+    fn attach(kn: &Knote) -> Result<(), Errno>;
+
+    /// Undo `attach`
+    fn detach(kn: &Knote);
+
+    /// Whether `kn` fires, after `KnList::notify` with `hint` or when
+    /// userland collects events, with `hint` 0
+    fn event(kn: &Knote, hint: i64) -> bool;
+}
+
+/// A filter's `struct filterops`, for the kernel to find it by
+pub struct FilterOps {
+    ops: kernel_sys::filterops,
+    evfilt: i16,
+}
+
+// The kernel only reads the filterops
+unsafe impl Sync for FilterOps {}
+
+impl FilterOps {
+    pub const fn new<T: Filter>() -> Self {
+        let mut ops: kernel_sys::filterops = unsafe { mem::zeroed() };
+        ops.f_isfd = T::IS_FD as c_int;
+        ops.f_attach = Some(attach::<T>);
+        ops.f_detach = Some(detach::<T>);
+        ops.f_event = Some(event::<T>);
+        FilterOps {
+            ops,
+            evfilt: T::EVFILT,
+        }
+    }
+
+    /// Make the filter type available to `kevent(2)`, failing with
+    /// `Errno::Exist` if another filter has it, for the module's load
+    /// event
+    pub fn register(&'static self) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::kqueue_add_filteropts(self.evfilt as c_int, &self.ops)
+        })
+    }
+
+    /// Withdraw the filter type, failing with `Errno::Busy` while kevents
+    /// of it remain
+    pub fn unregister(&'static self) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::kqueue_del_filteropts(self.evfilt as c_int)
+        })
+    }
+}
+
+impl fmt::Debug for FilterOps {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FilterOps {{ evfilt: {} }}", self.evfilt)
+    }
+}
+
+unsafe extern "C" fn attach<T: Filter>(kn: *mut kernel_sys::knote) -> c_int {
+    let kn = unsafe { Knote::from_raw(kn) };
+    catch_in_module(|| T::attach(&kn))
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+unsafe extern "C" fn detach<T: Filter>(kn: *mut kernel_sys::knote) {
+    let kn = unsafe { Knote::from_raw(kn) };
+    let _ = catch_in_module(|| T::detach(&kn));
+}
+
+unsafe extern "C" fn event<T: Filter>(
+    kn: *mut kernel_sys::knote,
+    hint: c_long,
+) -> c_int {
+    let kn = unsafe { Knote::from_raw(kn) };
+    catch_in_module(|| T::event(&kn, hint)).unwrap_or(false) as c_int
+}
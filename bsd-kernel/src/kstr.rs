@@ -0,0 +1,164 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Borrowed NUL-terminated strings for kernel interfaces
+//!
+//! `KernelStr` is a `CStr` that can also be printed and copied into the
+//! fixed-size name fields of kernel structures. Rust's `c"..."` literals
+//! convert to it for free:
+//! ```rust,ignore
+//! const NAME: &KernelStr = KernelStr::from_cstr(c"rustmodule");
+//! let mut buf = [0; 16];
+//! NAME.copy_to(&mut buf)?;
+//! println!("registered {}", NAME);
+//! ```
+
+use crate::errno::Errno;
+use core::ffi::CStr;
+use core::fmt;
+use core::ops::Deref;
+use core::str::Utf8Error;
+use libc::c_char;
+
+/// A borrowed, NUL-terminated string, with the same layout as `CStr`
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[repr(transparent)]
+pub struct KernelStr(CStr);
+
+impl KernelStr {
+    pub const fn from_cstr(s: &CStr) -> &KernelStr {
+        // `KernelStr` is a transparent wrapper, so the pointer casts
+        // between the two unsized types
+        unsafe { &*(s as *const CStr as *const KernelStr) }
+    }
+
+    /// Borrow a string ending in exactly one NUL, which must be its last
+    /// byte
+    pub fn from_bytes_with_nul(bytes: &[u8]) -> Result<&KernelStr, Errno> {
+        CStr::from_bytes_with_nul(bytes)
+            .map(KernelStr::from_cstr)
+            .map_err(|_| Errno::Inval)
+    }
+
+    /// Borrow the string in a fixed-size C field, up to its first NUL.
+    /// Returns `None` if the field is not terminated
+    pub fn from_buf(buf: &[c_char]) -> Option<&KernelStr> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len())
+        };
+        CStr::from_bytes_until_nul(bytes)
+            .ok()
+            .map(KernelStr::from_cstr)
+    }
+
+    /// Borrow a string from a raw pointer
+    ///
+    /// ## Safety
+    /// `ptr` must point to a NUL-terminated string that stays valid and
+    /// unchanged for `'a`
+    pub unsafe fn from_ptr<'a>(ptr: *const c_char) -> &'a KernelStr {
+        KernelStr::from_cstr(unsafe { CStr::from_ptr(ptr) })
+    }
+
+    pub const fn as_cstr(&self) -> &CStr {
+        &self.0
+    }
+
+    /// The string's bytes, without the NUL
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.to_bytes()
+    }
+
+    /// Length in bytes, without the NUL
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_bytes().is_empty()
+    }
+
+    pub fn to_str(&self) -> Result<&str, Utf8Error> {
+        self.0.to_str()
+    }
+
+    /// Copy the string, with its NUL, into a fixed-size C field such as a
+    /// 16-byte name, zero filling the rest. Fails with
+    /// `Errno::NameTooLong`, leaving `buf` untouched, if it doesn't fit
+    pub fn copy_to(&self, buf: &mut [c_char]) -> Result<(), Errno> {
+        let bytes = self.as_bytes();
+        if bytes.len() >= buf.len() {
+            return Err(Errno::NameTooLong);
+        }
+        for (dst, &src) in buf.iter_mut().zip(bytes) {
+            *dst = src as c_char;
+        }
+        buf[bytes.len()..].fill(0);
+        Ok(())
+    }
+}
+
+impl Deref for KernelStr {
+    type Target = CStr;
+
+    fn deref(&self) -> &CStr {
+        &self.0
+    }
+}
+
+impl AsRef<CStr> for KernelStr {
+    fn as_ref(&self) -> &CStr {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a CStr> for &'a KernelStr {
+    fn from(s: &'a CStr) -> Self {
+        KernelStr::from_cstr(s)
+    }
+}
+
+impl<'a> From<&'a KernelStr> for &'a CStr {
+    fn from(s: &'a KernelStr) -> Self {
+        &s.0
+    }
+}
+
+/// Prints the string as UTF-8, with invalid sequences replaced by U+FFFD
+impl fmt::Display for KernelStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.as_bytes().utf8_chunks() {
+            f.write_str(chunk.valid())?;
+            if !chunk.invalid().is_empty() {
+                f.write_str("\u{fffd}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for KernelStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
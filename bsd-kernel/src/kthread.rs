@@ -0,0 +1,274 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! Kernel threads running closures, see `kthread(9)` and `kproc(9)`
+//!
+//! `spawn` starts a thread of `proc0`, the kernel process, like
+//! `std::thread::spawn`; `Builder` names a stack size, or starts a
+//! process of its own instead, which `ps(1)` then lists by the name. The
+//! closure is handed a `Stop`, which its owner uses to ask it to return:
+//!
+//! ```rust,ignore
+//! let flusher = kthread::spawn(c"rmdflush", move |stop| {
+//!     while !stop.sleep(Duration::from_secs(5)) {
+//!         disk.flush();
+//!     }
+//! })?;
+//! // on unload
+//! drop(flusher); // stops and joins
+//! ```
+//!
+//! A `JoinHandle` stops and joins its thread when dropped, so module state
+//! holding it can't be unloaded with the thread still running its code.
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::sync::{Condvar, Mutex};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::ffi::CStr;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use libc::c_void;
+
+/// The stop request of a thread, which its closure checks or sleeps on
+pub struct Stop {
+    requested: AtomicBool,
+    lock: Mutex<()>,
+    cv: Condvar,
+}
+
+impl Stop {
+    fn new() -> Self {
+        Stop {
+            requested: AtomicBool::new(false),
+            lock: Mutex::new(c"kthread stop", ()),
+            cv: Condvar::new(c"kthstop"),
+        }
+    }
+
+    /// Whether the thread has been asked to return
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Sleep for up to `timeout`, returning early with `true` once the
+    /// thread is asked to return
+    pub fn sleep(&self, timeout: Duration) -> bool {
+        let guard = self.lock.lock();
+        if self.requested() {
+            return true;
+        }
+        drop(self.cv.wait_timeout(guard, timeout));
+        self.requested()
+    }
+
+    fn request(&self) {
+        let _guard = self.lock.lock();
+        self.requested.store(true, Ordering::Release);
+        self.cv.notify_all();
+    }
+}
+
+impl fmt::Debug for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stop {{ requested: {} }}", self.requested())
+    }
+}
+
+struct Packet<T> {
+    stop: Stop,
+    // Set once the closure has returned, or panicked with None
+    result: Mutex<Option<Option<T>>>,
+    done: Condvar,
+}
+
+struct Start<F, T> {
+    f: F,
+    // Kept alive by the JoinHandle, which waits for the result before
+    // letting go of it
+    packet: *const Packet<T>,
+}
+
+/// Options for a new thread
+#[derive(Debug)]
+pub struct Builder<'a> {
+    name: &'a CStr,
+    pages: i32,
+    process: bool,
+}
+
+impl<'a> Builder<'a> {
+    /// A thread of `proc0` named `name`, with the default stack
+    pub fn new(name: &'a CStr) -> Self {
+        Builder {
+            name,
+            pages: 0,
+            process: false,
+        }
+    }
+
+    /// Give the thread a stack of `pages` pages rather than the default
+    pub fn stack_pages(mut self, pages: usize) -> Self {
+        self.pages = i32::try_from(pages).unwrap_or(i32::MAX);
+        self
+    }
+
+    /// Start a kernel process of its own, see `kproc_create(9)`, rather
+    /// than a thread of `proc0`
+    pub fn process(mut self) -> Self {
+        self.process = true;
+        self
+    }
+
+    /// Start the thread running `f`
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, Errno>
+    where
+        F: FnOnce(&Stop) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet = Arc::new(Packet {
+            stop: Stop::new(),
+            result: Mutex::new(c"kthread join", None),
+            done: Condvar::new(c"kthjoin"),
+        });
+        let start = Box::into_raw(Box::new(Start {
+            f,
+            packet: Arc::as_ptr(&packet),
+        }));
+        let arg = start as *mut c_void;
+        let func = Some(run::<F, T> as unsafe extern "C" fn(*mut c_void));
+        let ret = unsafe {
+            if self.process {
+                kernel_sys::kproc_create(
+                    func,
+                    arg,
+                    ptr::null_mut(),
+                    0,
+                    self.pages,
+                    c"%s".as_ptr(),
+                    self.name.as_ptr(),
+                )
+            } else {
+                kernel_sys::kthread_add(
+                    func,
+                    arg,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    0,
+                    self.pages,
+                    c"%s".as_ptr(),
+                    self.name.as_ptr(),
+                )
+            }
+        };
+        if let Err(e) = Errno::result(ret) {
+            // Never started, so never taken
+            drop(unsafe { Box::from_raw(start) });
+            return Err(e);
+        }
+        Ok(JoinHandle {
+            packet: Some(packet),
+        })
+    }
+}
+
+/// Start a thread of `proc0` named `name` running `f`
+pub fn spawn<F, T>(name: &CStr, f: F) -> Result<JoinHandle<T>, Errno>
+where
+    F: FnOnce(&Stop) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new(name).spawn(f)
+}
+
+unsafe extern "C" fn run<F, T>(arg: *mut c_void)
+where
+    F: FnOnce(&Stop) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let Start { f, packet } =
+        *unsafe { Box::from_raw(arg as *mut Start<F, T>) };
+    let packet = unsafe { &*packet };
+    let result = catch_in_module(|| f(&packet.stop)).ok();
+    let mut slot = packet.result.lock();
+    *slot = Some(result);
+    packet.done.notify_all();
+    // Once unlocked, the joiner may free the packet and unload the module,
+    // so only the unlock and kthread_exit are left, as in C
+    drop(slot);
+    // Never returns, and wakes anyone in kthread_shutdown(9) too
+    unsafe { kernel_sys::kthread_exit() };
+}
+
+/// An owned thread, which is stopped and joined when dropped
+pub struct JoinHandle<T> {
+    // Taken by join
+    packet: Option<Arc<Packet<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    fn packet(&self) -> &Packet<T> {
+        self.packet.as_ref().unwrap()
+    }
+
+    /// Ask the thread to return, without waiting for it
+    pub fn stop(&self) {
+        self.packet().stop.request();
+    }
+
+    /// Whether the closure has returned
+    pub fn is_finished(&self) -> bool {
+        self.packet().result.lock().is_some()
+    }
+
+    /// Wait for the closure to return, with what it returned. In the mock
+    /// a closure that panicked gives `Errno::Io`; in the kernel its
+    /// thread parks, and this waits for good
+    pub fn join(mut self) -> Result<T, Errno> {
+        let packet = self.packet.take().unwrap();
+        Self::wait(&packet)
+    }
+
+    fn wait(packet: &Packet<T>) -> Result<T, Errno> {
+        let slot = packet.result.lock();
+        let mut slot = packet.done.wait_while(slot, |r| r.is_none());
+        slot.take().flatten().ok_or(Errno::Io)
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if let Some(packet) = self.packet.take() {
+            packet.stop.request();
+            let _ = Self::wait(&packet);
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JoinHandle {{ finished: {} }}", self.is_finished())
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `ktr(4)` trace points
+//!
+//! `ktr!` records a format string and up to six integer or pointer
+//! arguments in the kernel's trace buffer, as `CTR0` to `CTR6` do. Nothing
+//! is formatted until `ktrdump(8)` or ddb's `show ktr` prints the buffer,
+//! so trace points are cheap enough for interrupt handlers and bio paths,
+//! and work before the console does:
+//!
+//! ```rust,ignore
+//! ktr!(KTR_GEOM, "rmd_start: bp %p offset %jd", bio.as_ptr(), bio.offset());
+//! ```
+//!
+//! Trace points are only built with the `ktr` feature, for kernels with
+//! `options KTR`, and then only for the classes in `KTR_COMPILE`; the rest
+//! compile to nothing. Those built record when their class is also in
+//! `sysctl debug.ktr.mask`.
+//!
+//! The buffer keeps pointers to the format and to `%s` arguments rather
+//! than copies, which is why strings must be `'static`. Entries left by a
+//! module that was unloaded don't print, as for C modules.
+
+use core::ffi::CStr;
+use libc::c_ulong;
+
+pub const KTR_GEN: u64 = 0x00000001;
+pub const KTR_NET: u64 = 0x00000002;
+pub const KTR_DEV: u64 = 0x00000004;
+pub const KTR_LOCK: u64 = 0x00000008;
+pub const KTR_SMP: u64 = 0x00000010;
+pub const KTR_SUBSYS: u64 = 0x00000020;
+pub const KTR_PMAP: u64 = 0x00000040;
+pub const KTR_MALLOC: u64 = 0x00000080;
+pub const KTR_TRAP: u64 = 0x00000100;
+pub const KTR_INTR: u64 = 0x00000200;
+pub const KTR_SIG: u64 = 0x00000400;
+pub const KTR_PROC: u64 = 0x00001000;
+pub const KTR_SYSC: u64 = 0x00002000;
+pub const KTR_INIT: u64 = 0x00004000;
+pub const KTR_EVH: u64 = 0x00020000;
+pub const KTR_VFS: u64 = 0x00040000;
+pub const KTR_VOP: u64 = 0x00080000;
+pub const KTR_VM: u64 = 0x00100000;
+pub const KTR_INET: u64 = 0x00200000;
+pub const KTR_RUNQ: u64 = 0x00400000;
+pub const KTR_UMA: u64 = 0x01000000;
+pub const KTR_CALLOUT: u64 = 0x02000000;
+pub const KTR_GEOM: u64 = 0x04000000;
+pub const KTR_BUSDMA: u64 = 0x08000000;
+pub const KTR_INET6: u64 = 0x10000000;
+pub const KTR_SCHED: u64 = 0x20000000;
+pub const KTR_BUF: u64 = 0x40000000;
+pub const KTR_PTRACE: u64 = 0x80000000;
+pub const KTR_ALL: u64 = 0xffffffff;
+
+/// Classes whose trace points are built: none without the `ktr` feature,
+/// otherwise those in `KTR_COMPILE` in the build environment, as the
+/// kernel option of that name, and all of them if it isn't set
+pub const KTR_COMPILE: u64 =
+    if cfg!(all(feature = "ktr", not(feature = "mock"))) {
+        match option_env!("KTR_COMPILE") {
+            Some(mask) => parse_mask(mask.as_bytes()),
+            None => KTR_ALL,
+        }
+    } else {
+        0
+    };
+
+/// A mask in C notation, hexadecimal with a `0x` prefix or decimal
+const fn parse_mask(s: &[u8]) -> u64 {
+    let (radix, mut i) = match s {
+        [b'0', b'x' | b'X', ..] => (16, 2),
+        _ => (10, 0),
+    };
+    assert!(i < s.len(), "KTR_COMPILE: empty mask");
+    let mut mask: u64 = 0;
+    while i < s.len() {
+        let digit = match s[i] {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'a'..=b'f' if radix == 16 => c - b'a' + 10,
+            c @ b'A'..=b'F' if radix == 16 => c - b'A' + 10,
+            _ => panic!("KTR_COMPILE: not a number"),
+        };
+        mask = mask * radix + digit as u64;
+        i += 1;
+    }
+    mask
+}
+
+/// What a trace point argument is stored as, a `u_long`
+pub trait Arg {
+    fn into_raw(self) -> c_ulong;
+}
+
+macro_rules! int_arg {
+    ($($t:ty),*) => {
+        $(impl Arg for $t {
+            fn into_raw(self) -> c_ulong {
+                self as c_ulong
+            }
+        })*
+    };
+}
+
+int_arg!(
+    u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, bool, char
+);
+
+impl<T> Arg for *const T {
+    fn into_raw(self) -> c_ulong {
+        self as usize as c_ulong
+    }
+}
+
+impl<T> Arg for *mut T {
+    fn into_raw(self) -> c_ulong {
+        self as usize as c_ulong
+    }
+}
+
+/// For `%s`
+impl Arg for &'static CStr {
+    fn into_raw(self) -> c_ulong {
+        self.as_ptr() as usize as c_ulong
+    }
+}
+
+/// Record a trace point of class `class` with a `printf(9)` format and up
+/// to six arguments, if the class is built and enabled
+#[macro_export]
+macro_rules! ktr {
+    ($class:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {
+        if $crate::ktr::KTR_COMPILE & ($class) != 0 {
+            $crate::ktr::tracepoint(
+                $class,
+                $crate::cstr!(file!()),
+                line!(),
+                $crate::cstr!($fmt),
+                [$($crate::ktr::Arg::into_raw($arg)),*],
+            );
+        }
+    };
+}
+
+/// Record a trace point, for `ktr!`
+#[inline]
+pub fn tracepoint<const N: usize>(
+    class: u64,
+    file: &'static CStr,
+    line: u32,
+    fmt: &'static CStr,
+    args: [c_ulong; N],
+) {
+    const { assert!(N <= 6, "ktr! takes up to six arguments") };
+    #[cfg(all(feature = "ktr", not(feature = "mock")))]
+    {
+        // As ktr_tracepoint would, but without the call
+        let mask = unsafe {
+            core::ptr::read_volatile(&raw const kernel_sys::ktr_mask)
+        };
+        if mask & class == 0 {
+            return;
+        }
+        let mut a = [0; 6];
+        a[..N].copy_from_slice(&args);
+        unsafe {
+            kernel_sys::ktr_tracepoint(
+                class,
+                file.as_ptr(),
+                line as libc::c_int,
+                fmt.as_ptr(),
+                a[0],
+                a[1],
+                a[2],
+                a[3],
+                a[4],
+                a[5],
+            )
+        };
+    }
+    #[cfg(not(all(feature = "ktr", not(feature = "mock"))))]
+    let _ = (class, file, line, fmt, args);
+}
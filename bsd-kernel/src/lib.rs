@@ -25,6 +25,7 @@
 
 #![no_std]
 #![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 
 // Re-export libc and kernel_sys so that the printing macros work
 pub use kernel_sys;
@@ -32,20 +33,152 @@ pub use libc;
 
 pub use kernel_sys::module_t as Module;
 
+pub use bsd_kernel_macros::kernel_module;
+
 extern crate alloc;
+#[cfg(feature = "mock")]
+extern crate std;
 
+#[cfg(not(feature = "mock"))]
+pub mod alq;
 pub mod allocator;
+#[cfg(not(feature = "mock"))]
+mod arch;
+#[cfg(not(feature = "mock"))]
+pub mod bus;
+#[cfg(not(feature = "mock"))]
+pub mod bufcache;
+#[cfg(not(feature = "mock"))]
+pub mod buf_ring;
+#[cfg(not(feature = "mock"))]
+pub mod busdma;
+#[cfg(not(feature = "mock"))]
+pub mod callout;
 pub mod character_device;
+pub mod checksum;
+#[cfg(not(feature = "mock"))]
+pub mod compress;
+pub mod counter;
+#[cfg(not(feature = "mock"))]
+pub mod cpu;
+#[cfg(not(feature = "mock"))]
+pub mod cpuset;
+pub mod cred;
+#[cfg(not(feature = "mock"))]
+pub mod crypto;
+#[cfg(all(feature = "ddb", not(feature = "mock")))]
+pub mod ddb;
+pub mod devctl;
+#[cfg(not(feature = "mock"))]
+pub mod devstat;
+#[cfg(not(feature = "mock"))]
+pub mod digest;
+#[cfg(not(feature = "mock"))]
+pub mod disk;
+#[cfg(not(feature = "mock"))]
+pub mod dump;
+#[cfg(not(feature = "mock"))]
+pub mod efi;
+pub mod errno;
 pub mod error;
+#[cfg(not(feature = "mock"))]
+pub mod eventhandler;
+#[cfg(not(feature = "mock"))]
+pub mod eventtimer;
+#[cfg(not(feature = "mock"))]
+pub mod executor;
+pub mod export;
+#[cfg(not(feature = "mock"))]
+pub mod geom;
+pub mod hid;
+#[cfg(not(feature = "mock"))]
+pub mod intr;
 pub mod io;
+pub mod ioctl;
+#[cfg(not(feature = "mock"))]
+pub mod jail;
+#[cfg(not(feature = "mock"))]
+pub mod kenv;
+#[cfg(not(feature = "mock"))]
+pub mod kobj;
+pub mod kstr;
+#[cfg(not(feature = "mock"))]
+pub mod kqueue;
+#[cfg(not(feature = "mock"))]
+pub mod kthread;
+pub mod ktr;
+#[cfg(not(feature = "mock"))]
+pub mod linker;
+pub mod log;
+#[cfg(not(feature = "mock"))]
+pub mod mmc;
 pub mod module;
+#[cfg(not(feature = "mock"))]
+pub mod net;
+pub mod panic;
+#[cfg(not(feature = "mock"))]
+pub mod pci;
+#[cfg(not(feature = "mock"))]
+pub mod percpu;
+#[cfg(not(feature = "mock"))]
+pub mod pmc;
+#[cfg(not(feature = "mock"))]
+pub mod proc;
+#[cfg(not(feature = "mock"))]
+pub mod pseudofs;
+pub mod random;
+pub mod refcount;
+pub mod sbuf;
+#[cfg(not(feature = "mock"))]
+pub mod sched;
+pub mod selinfo;
+#[cfg(not(feature = "mock"))]
+pub mod smbus;
+#[cfg(not(feature = "mock"))]
+pub mod smp;
+#[cfg(not(feature = "mock"))]
+pub mod stack;
+#[cfg(not(feature = "mock"))]
+pub mod superio;
+#[cfg(not(feature = "mock"))]
+pub mod swi;
+pub mod sync;
+pub mod sysctl;
+pub mod syslog;
+#[cfg(not(feature = "mock"))]
+pub mod taskqueue;
+pub mod time;
+#[cfg(not(feature = "mock"))]
+pub mod timecounter;
+#[cfg(not(feature = "mock"))]
+pub mod tty;
 pub mod uio;
+pub mod uma;
+pub mod unr;
+pub mod usb;
+pub mod user;
+#[cfg(not(feature = "mock"))]
+pub mod vfs;
+#[cfg(not(feature = "mock"))]
+pub mod vm;
+#[cfg(not(feature = "mock"))]
+pub mod vnode;
+#[cfg(not(feature = "mock"))]
+pub mod watchdog;
 
-/// Create a null-terminated constant string at compile time
+/// Create a `&'static CStr` from a string literal at compile time, failing
+/// to build if it contains a NUL. New code can use `c"..."` literals
 #[macro_export]
 macro_rules! cstr {
     ($arg:expr) => {
-        concat!($arg, '\x00')
+        const {
+            match ::core::ffi::CStr::from_bytes_with_nul(
+                concat!($arg, '\x00').as_bytes(),
+            ) {
+                Ok(s) => s,
+                Err(_) => panic!("string literal contains a NUL"),
+            }
+        }
     };
 }
 
@@ -63,9 +196,8 @@ macro_rules! print {
 	// Static (zero-allocation) implementation that uses compile-time `concat!()` only
 	($fmt:expr) => ({
 		let msg = $crate::cstr!($fmt);
-		let ptr = msg.as_ptr() as *const $crate::libc::c_char;
 		unsafe {
-			$crate::kernel_sys::uprintf(ptr);
+			$crate::kernel_sys::uprintf(msg.as_ptr());
 		}
 	});
 
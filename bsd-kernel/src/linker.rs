@@ -0,0 +1,194 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Finding symbols and linker sets in loaded kernel files at runtime
+//!
+//! For optional dependencies, where `MODULE_DEPEND` would make the other
+//! module mandatory. Everything here runs its callback with the kernel
+//! linker's lock held, so the file a symbol came from can't be unloaded
+//! while the callback uses it; pointers must not be kept past it. Loading
+//! or unloading files from a callback deadlocks, and as the lock may
+//! sleep, none of this may be called with a mutex held.
+//!
+//! Files the loader preloaded as data, rather than as modules, such as
+//! memory disk or filesystem images, are found with `preloaded`.
+
+use crate::errno::Errno;
+use crate::export::{self, Api};
+use core::ffi::{CStr, c_void};
+use core::marker::PhantomData;
+use core::ops::ControlFlow;
+use core::ptr::NonNull;
+use core::slice;
+
+/// A loaded kernel file: the kernel itself or a module
+pub struct LinkerFile<'a> {
+    lf: NonNull<kernel_sys::linker_file>,
+    _lock: PhantomData<&'a ()>,
+}
+
+impl LinkerFile<'_> {
+    /// The name it was loaded as, such as `kernel` or `fifo.ko`
+    pub fn filename(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.lf.as_ref().filename) }
+    }
+
+    /// The address of the global symbol `name` defined in the file
+    pub fn symbol(&self, name: &CStr) -> Option<NonNull<c_void>> {
+        let p = unsafe {
+            kernel_sys::linker_file_lookup_symbol(
+                self.lf.as_ptr(),
+                name.as_ptr(),
+                0,
+            )
+        };
+        NonNull::new(p as *mut c_void)
+    }
+
+    /// The entries of the file's linker set `name`, as declared with
+    /// `DATA_SET(name, ...)` in C
+    ///
+    /// # Safety
+    /// Every entry of the set must point to a `T`.
+    pub unsafe fn set<T>(&self, name: &CStr) -> &[&T] {
+        let mut start: *mut *const T = core::ptr::null_mut();
+        let mut stop: *mut *const T = core::ptr::null_mut();
+        let mut count = 0;
+        let error = unsafe {
+            kernel_sys::linker_file_lookup_set(
+                self.lf.as_ptr(),
+                name.as_ptr(),
+                &raw mut start as *mut c_void,
+                &raw mut stop as *mut c_void,
+                &mut count,
+            )
+        };
+        if error != 0 || start.is_null() || count <= 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(start as *const &T, count as usize) }
+    }
+}
+
+struct Visit<'f, B> {
+    f: &'f mut dyn FnMut(&LinkerFile) -> ControlFlow<B>,
+    result: Option<B>,
+}
+
+unsafe extern "C" fn visit<B>(
+    lf: kernel_sys::linker_file_t,
+    arg: *mut c_void,
+) -> libc::c_int {
+    let v = unsafe { &mut *(arg as *mut Visit<B>) };
+    let Some(lf) = NonNull::new(lf) else {
+        return 0;
+    };
+    let file = LinkerFile {
+        lf,
+        _lock: PhantomData,
+    };
+    match (v.f)(&file) {
+        ControlFlow::Continue(()) => 0,
+        ControlFlow::Break(b) => {
+            v.result = Some(b);
+            1
+        }
+    }
+}
+
+/// Call `f` on each loaded file until it breaks, returning what it broke
+/// with
+pub fn for_each_file<B>(
+    mut f: impl FnMut(&LinkerFile) -> ControlFlow<B>,
+) -> Option<B> {
+    let mut v = Visit {
+        f: &mut f,
+        result: None,
+    };
+    unsafe {
+        kernel_sys::linker_file_foreach(
+            Some(visit::<B>),
+            &raw mut v as *mut c_void,
+        );
+    }
+    v.result
+}
+
+/// Call `f` with the address of the first global symbol `name` found in
+/// any loaded file, or return `None` if none defines it
+pub fn with_symbol<R>(
+    name: &CStr,
+    f: impl FnOnce(NonNull<c_void>) -> R,
+) -> Option<R> {
+    let mut f = Some(f);
+    for_each_file(|file| match file.symbol(name) {
+        Some(p) => ControlFlow::Break(f.take().unwrap()(p)),
+        None => ControlFlow::Continue(()),
+    })
+}
+
+/// Call `f` with the API table another module exports as `name`
+///
+/// Fails with `Errno::NoEnt` if no loaded file exports it, or as
+/// `export::import` does if its version doesn't match `T`.
+pub fn with_api<T: Api, R>(
+    name: &CStr,
+    f: impl FnOnce(&T) -> R,
+) -> Result<R, Errno> {
+    with_symbol(name, |p| {
+        let table = unsafe { &*(p.as_ptr() as *const T) };
+        export::import(table).map(f)
+    })
+    .unwrap_or(Err(Errno::NoEnt))
+}
+
+/// Call `f` on every entry of linker set `name` in every loaded file, such
+/// as plugins other modules register
+///
+/// # Safety
+/// Every entry of every set by that name must point to a `T`.
+pub unsafe fn for_each_in_set<T>(name: &CStr, mut f: impl FnMut(&T)) {
+    for_each_file::<()>(|file| {
+        for entry in unsafe { file.set::<T>(name) } {
+            f(entry);
+        }
+        ControlFlow::Continue(())
+    });
+}
+
+/// The contents of a file the loader read in before the kernel started,
+/// by the name it was loaded as, such as `/boot/initfs.tar` for
+/// `initfs_load="YES"` and `initfs_name="/boot/initfs.tar"` in
+/// `loader.conf(5)`. Such files are never unloaded
+pub fn preloaded(name: &CStr) -> Option<&'static [u8]> {
+    let file = unsafe { kernel_sys::preload_search_by_name(name.as_ptr()) };
+    if file.is_null() {
+        return None;
+    }
+    let addr = unsafe { kernel_sys::preload_fetch_addr(file) };
+    let size = unsafe { kernel_sys::preload_fetch_size(file) };
+    if addr.is_null() {
+        return None;
+    }
+    Some(unsafe { slice::from_raw_parts(addr as *const u8, size) })
+}
@@ -0,0 +1,276 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Diagnostic log
+//!
+//! Each module keeps the last `LINES` lines written with `log!` in a ring.
+//! Writing never blocks, sleeps or allocates, so it works from any context,
+//! including interrupt filters and with spin locks held, and unlike
+//! `println!` it doesn't serialize on the console. Once full, the ring
+//! overwrites its oldest lines.
+//!
+//! The log is read with a `Reader`, from userland through a `LogDevice`
+//! or, in the kernel, a sysctl handled by `sysctl_dump`.
+
+use crate::character_device::CharacterDevice;
+use crate::cred::CurThread;
+use crate::errno::Errno;
+use crate::io::Write;
+use crate::uio::{Offsets, UioReader, UioWriter};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering, fence};
+
+use crate::sysctl::Request;
+
+/// Lines kept
+pub const LINES: usize = 256;
+
+/// Longest line kept, in bytes. Longer ones are cut short
+pub const LINE_MAX: usize = 120;
+
+/// A line. `seq` is `2 * n + 1` while line `n` is being written, and
+/// `2 * n + 2` once it is complete
+struct Slot {
+    seq: AtomicUsize,
+    len: AtomicUsize,
+    bytes: [AtomicU8; LINE_MAX],
+}
+
+struct Ring {
+    /// Number of the next line to be written
+    head: AtomicUsize,
+    slots: [Slot; LINES],
+}
+
+static RING: Ring = Ring {
+    head: AtomicUsize::new(0),
+    slots: [const {
+        Slot {
+            seq: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            bytes: [const { AtomicU8::new(0) }; LINE_MAX],
+        }
+    }; LINES],
+};
+
+/// Append a line to the module's diagnostic log, formatted like
+/// `println!` but without the newline
+///
+/// Given a priority first, as in `log!(LOG_WARNING, "{} failed", name)`,
+/// the line goes to `log(9)` instead; see `syslog`.
+#[macro_export]
+macro_rules! log {
+    ($pri:ident, $($arg:tt)+) => ({
+        $crate::syslog::log($crate::syslog::$pri, format_args!($($arg)+));
+    });
+    ($($arg:tt)*) => ({
+        use ::core::fmt::Write;
+        let mut line =
+            $crate::io::FmtBuf::<{ $crate::log::LINE_MAX + 1 }>::new();
+        let _ = write!(line, $($arg)*);
+        $crate::log::write_line(line.as_str().as_bytes());
+    });
+}
+
+/// Append `line`, cut to `LINE_MAX` bytes, to the log. Dropped if the ring
+/// has come round to a slot another writer is still filling
+pub fn write_line(line: &[u8]) {
+    let n = RING.head.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING.slots[n % LINES];
+    let mut seq = slot.seq.load(Ordering::Relaxed);
+    loop {
+        // Still being written, or already taken by a later line
+        if seq % 2 == 1 || seq > 2 * n {
+            return;
+        }
+        match slot.seq.compare_exchange_weak(
+            seq,
+            2 * n + 1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => break,
+            Err(s) => seq = s,
+        }
+    }
+    // Readers seeing any of the new bytes then see the odd sequence
+    fence(Ordering::Release);
+    let len = line.len().min(LINE_MAX);
+    for (b, &c) in slot.bytes.iter().zip(&line[..len]) {
+        b.store(c, Ordering::Relaxed);
+    }
+    slot.len.store(len, Ordering::Relaxed);
+    slot.seq.store(2 * n + 2, Ordering::Release);
+}
+
+/// A cursor over the log, which readers don't share
+#[derive(Debug)]
+pub struct Reader {
+    next: usize,
+    dropped: usize,
+}
+
+impl Reader {
+    /// Start at the oldest line still kept
+    pub fn oldest() -> Self {
+        let head = RING.head.load(Ordering::Acquire);
+        Reader {
+            next: head.saturating_sub(LINES),
+            dropped: 0,
+        }
+    }
+
+    /// Copy the next line into `buf`, returning its length, or `None` once
+    /// caught up with the writers. A line still being written holds up
+    /// the ones after it until it is complete or overwritten
+    pub fn next_line(&mut self, buf: &mut [u8; LINE_MAX]) -> Option<usize> {
+        loop {
+            let head = RING.head.load(Ordering::Acquire);
+            if self.next >= head {
+                return None;
+            }
+            if head - self.next > LINES {
+                self.dropped += head - LINES - self.next;
+                self.next = head - LINES;
+            }
+            let slot = &RING.slots[self.next % LINES];
+            let want = 2 * self.next + 2;
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq < want {
+                return None;
+            }
+            if seq == want {
+                let len = slot.len.load(Ordering::Relaxed);
+                for (c, b) in buf.iter_mut().zip(&slot.bytes[..len]) {
+                    *c = b.load(Ordering::Relaxed);
+                }
+                fence(Ordering::Acquire);
+                if slot.seq.load(Ordering::Relaxed) == want {
+                    self.next += 1;
+                    return Some(len);
+                }
+            }
+            // Overwritten before it could be read
+            self.dropped += 1;
+            self.next += 1;
+        }
+    }
+
+    /// Lines overwritten before this reader got to them
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// Write the lines kept out to a `Format::Text` sysctl, one per line, as
+/// the handler of `proc(text, rd)` in `sysctl!`
+pub fn sysctl_dump(req: &mut Request) -> Result<(), Errno> {
+    let mut reader = Reader::oldest();
+    let mut line = [0u8; LINE_MAX];
+    while let Some(len) = reader.next_line(&mut line) {
+        req.write_old(&line[..len])?;
+        req.write_old(b"\n")?;
+    }
+    req.write_old(b"\0")
+}
+
+/// A read-only character device serving the log, one line per line. Each
+/// open starts again from the oldest line kept, and reading returns end of
+/// file once caught up, so `cat(1)` of the device prints the log so far
+pub struct LogDevice {
+    reader: Reader,
+    /// The line being read out, with its newline
+    line: [u8; LINE_MAX + 1],
+    len: usize,
+    off: usize,
+}
+
+impl LogDevice {
+    pub fn new() -> Self {
+        LogDevice {
+            reader: Reader::oldest(),
+            line: [0; LINE_MAX + 1],
+            len: 0,
+            off: 0,
+        }
+    }
+}
+
+impl Default for LogDevice {
+    fn default() -> Self {
+        LogDevice::new()
+    }
+}
+
+impl fmt::Debug for LogDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LogDevice {{ reader: {:?} }}", self.reader)
+    }
+}
+
+impl CharacterDevice for LogDevice {
+    const OFFSETS: Offsets = Offsets::Stream;
+
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        *self = LogDevice::new();
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        while uio.residual() > 0 {
+            if self.off == self.len {
+                let buf = self.line.first_chunk_mut().unwrap();
+                let Some(len) = self.reader.next_line(buf) else {
+                    break;
+                };
+                self.line[len] = b'\n';
+                self.len = len + 1;
+                self.off = 0;
+            }
+            let n = uio
+                .write(&self.line[self.off..self.len])
+                .map_err(|_| Errno::Fault)?;
+            if n == 0 {
+                break;
+            }
+            self.off += n;
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        _uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Err(Errno::NoDev)
+    }
+}
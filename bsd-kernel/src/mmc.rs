@@ -0,0 +1,680 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! MMC and SD host controllers, the `mmcbr` bridge interface
+//!
+//! A host controller driver sits between its parent bus and `mmc(4)`,
+//! which enumerates the cards and issues their commands through the
+//! bridge methods in `dev/mmc/mmcbr_if.m`. In Rust, the controller is a
+//! `bus::Driver` that also implements `HostController`; `mmc_host!`
+//! exports the bridge methods for the C method table to list beside the
+//! device ones:
+//! ```c,ignore
+//! DEVMETHOD(bus_read_ivar,     rustsd_read_ivar),
+//! DEVMETHOD(bus_write_ivar,    rustsd_write_ivar),
+//! DEVMETHOD(mmcbr_update_ios,  rustsd_update_ios),
+//! DEVMETHOD(mmcbr_request,     rustsd_request),
+//! DEVMETHOD(mmcbr_get_ro,      rustsd_get_ro),
+//! DEVMETHOD(mmcbr_acquire_host, rustsd_acquire_host),
+//! DEVMETHOD(mmcbr_release_host, rustsd_release_host),
+//! ```
+//! with `MODULE_DEPEND(rustsd, mmc, 1, 1, 1)` and
+//! `DRIVER_MODULE(mmc, rustsd, mmc_driver, NULL, NULL)` so that `mmc`
+//! attaches below it. The driver adds the `mmc` child with
+//! `Host::attach_bus` once it's ready for requests.
+//!
+//! `mmc` owns the host, by `acquire_host`, across each sequence of ivar
+//! writes, `update_ios` calls and requests, so those never overlap.
+//! A request completes asynchronously, usually from the controller's
+//! interrupt handler, by `Request::done`.
+
+use crate::bus::{Device, Driver, state};
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::sync::{Condvar, Mutex};
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use core::{fmt, mem, slice};
+use libc::c_int;
+
+/// `MMC_CAP_*` host capabilities, from `dev/mmc/bridge.h`
+pub mod caps {
+    pub const BUS_WIDTH_4: u32 = 1 << 0;
+    pub const BUS_WIDTH_8: u32 = 1 << 1;
+    pub const HSPEED: u32 = 1 << 2;
+    pub const BOOT_NOACC: u32 = 1 << 4;
+    pub const WAIT_WHILE_BUSY: u32 = 1 << 5;
+    pub const UHS_SDR12: u32 = 1 << 6;
+    pub const UHS_SDR25: u32 = 1 << 7;
+    pub const UHS_SDR50: u32 = 1 << 8;
+    pub const UHS_SDR104: u32 = 1 << 9;
+    pub const UHS_DDR50: u32 = 1 << 10;
+    pub const MMC_DDR52: u32 = 1 << 13;
+    pub const SIGNALING_180: u32 = 1 << 16;
+}
+
+/// `MMC_OCR_*` voltage windows for `Host::new`
+pub mod ocr {
+    pub const V_165_195: u32 = 1 << 7;
+    pub const V_290_300: u32 = 1 << 17;
+    pub const V_300_310: u32 = 1 << 18;
+    pub const V_310_320: u32 = 1 << 19;
+    pub const V_320_330: u32 = 1 << 20;
+    pub const V_330_340: u32 = 1 << 21;
+    /// The usual 3.3V
+    pub const V_320_340: u32 = V_320_330 | V_330_340;
+}
+
+/// `MMC_RSP_*` bits of `Command::flags`, describing the response
+pub mod rsp {
+    pub const PRESENT: u32 = 1 << 0;
+    /// The response is 136 bits long, as for R2
+    pub const R136: u32 = 1 << 1;
+    pub const CRC: u32 = 1 << 2;
+    /// The card holds DAT0 low while busy
+    pub const BUSY: u32 = 1 << 3;
+    pub const OPCODE: u32 = 1 << 4;
+    pub const MASK: u32 = 0x1f;
+}
+
+/// Bus widths `Ios::bus_width` reports
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BusWidth {
+    One,
+    Four,
+    Eight,
+}
+
+/// Power states `Ios::power` reports
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Power {
+    Off,
+    /// Applying power, before the clock starts
+    Up,
+    On,
+}
+
+/// How a command failed, `MMC_ERR_*`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MmcError {
+    Timeout = 1,
+    BadCrc = 2,
+    Fifo = 3,
+    Failed = 4,
+    Invalid = 5,
+    NoMemory = 6,
+}
+
+/// The bus settings `mmc` asks for, `struct mmc_ios`
+#[derive(Copy, Clone)]
+pub struct Ios {
+    ios: kernel_sys::mmc_ios,
+}
+
+impl Ios {
+    /// Card clock in Hz, 0 for stopped
+    pub fn clock(&self) -> u32 {
+        self.ios.clock
+    }
+
+    pub fn bus_width(&self) -> BusWidth {
+        match self.ios.bus_width {
+            kernel_sys::mmc_bus_width_bus_width_4 => BusWidth::Four,
+            kernel_sys::mmc_bus_width_bus_width_8 => BusWidth::Eight,
+            _ => BusWidth::One,
+        }
+    }
+
+    pub fn power(&self) -> Power {
+        match self.ios.power_mode {
+            kernel_sys::mmc_power_mode_power_up => Power::Up,
+            kernel_sys::mmc_power_mode_power_on => Power::On,
+            _ => Power::Off,
+        }
+    }
+
+    /// The supply, as the bit number of its `ocr` window
+    pub fn vdd(&self) -> u32 {
+        self.ios.vdd
+    }
+
+    /// Whether signalling is at 1.8V rather than 3.3V
+    pub fn is_vccq_180(&self) -> bool {
+        self.ios.vccq == kernel_sys::mmc_vccq_vccq_180
+    }
+
+    /// `bus_timing_*`, the timing mode such as high speed
+    pub fn timing(&self) -> u32 {
+        self.ios.timing
+    }
+
+    /// Whether CMD is open drain, as during card identification
+    pub fn is_open_drain(&self) -> bool {
+        self.ios.bus_mode == kernel_sys::mmc_bus_mode_opendrain
+    }
+}
+
+impl fmt::Debug for Ios {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Ios {{ clock: {}, bus_width: {:?}, power: {:?} }}",
+            self.clock(),
+            self.bus_width(),
+            self.power()
+        )
+    }
+}
+
+/// The host's side of the bridge: what `mmc` reads and sets through ivars,
+/// and who owns the bus
+pub struct Host {
+    host: UnsafeCell<kernel_sys::mmc_host>,
+    max_data: u32,
+    busy: Mutex<bool>,
+    idle: Condvar,
+}
+
+// The ivars are only touched by `mmc`, holding the host
+unsafe impl Send for Host {}
+unsafe impl Sync for Host {}
+
+impl Host {
+    /// A host clocking cards between `f_min` and `f_max` Hz, at the
+    /// voltages in `ocr`, with `caps`. `max_data` is the most blocks a
+    /// request may move
+    pub fn new(
+        f_min: u32,
+        f_max: u32,
+        ocr: u32,
+        caps: u32,
+        max_data: u32,
+    ) -> Self {
+        let mut host: kernel_sys::mmc_host = unsafe { mem::zeroed() };
+        host.f_min = f_min as _;
+        host.f_max = f_max as _;
+        host.host_ocr = ocr;
+        host.caps = caps;
+        Host {
+            host: UnsafeCell::new(host),
+            max_data: max_data.max(1),
+            busy: Mutex::new(c"mmchost", false),
+            idle: Condvar::new(c"mmchost"),
+        }
+    }
+
+    /// The settings `update_ios` is to apply
+    pub fn ios(&self) -> Ios {
+        Ios {
+            ios: unsafe { (*self.host.get()).ios },
+        }
+    }
+
+    /// Add and attach the `mmc` bus below `dev`, which then probes for
+    /// cards
+    pub fn attach_bus(&self, dev: Device) -> Result<(), Errno> {
+        let child = unsafe {
+            kernel_sys::device_add_child(dev.as_ptr(), c"mmc".as_ptr(), -1)
+        };
+        if child.is_null() {
+            return Err(Errno::NxIo);
+        }
+        Errno::result(unsafe { kernel_sys::bus_generic_attach(dev.as_ptr()) })
+    }
+
+    /// Detach and delete the `mmc` bus, for `Driver::detach`
+    pub fn detach_bus(&self, dev: Device) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::device_delete_children(dev.as_ptr())
+        })
+    }
+
+    fn read_ivar(&self, which: c_int) -> Option<usize> {
+        let h = unsafe { &*self.host.get() };
+        let v = match which as u32 {
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_BUS_MODE => {
+                h.ios.bus_mode as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_BUS_WIDTH => {
+                h.ios.bus_width as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_CHIP_SELECT => {
+                h.ios.chip_select as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_CLOCK => {
+                h.ios.clock as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_F_MIN => h.f_min as usize,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_F_MAX => h.f_max as usize,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_HOST_OCR => {
+                h.host_ocr as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_MODE => h.mode as usize,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_OCR => h.ocr as usize,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_POWER_MODE => {
+                h.ios.power_mode as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_RETUNE_REQ => 0,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_VDD => h.ios.vdd as usize,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_VCCQ => {
+                h.ios.vccq as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_CAPS => h.caps as usize,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_TIMING => {
+                h.ios.timing as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_MAX_DATA => {
+                self.max_data as usize
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_MAX_BUSY_TIMEOUT => {
+                1_000_000
+            }
+            _ => return None,
+        };
+        Some(v)
+    }
+
+    fn write_ivar(&self, which: c_int, v: usize) -> Result<(), Errno> {
+        let h = unsafe { &mut *self.host.get() };
+        match which as u32 {
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_BUS_MODE => {
+                h.ios.bus_mode = v as _
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_BUS_WIDTH => {
+                h.ios.bus_width = v as _
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_CHIP_SELECT => {
+                h.ios.chip_select = v as _
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_CLOCK => {
+                h.ios.clock = v as _
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_MODE => h.mode = v as _,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_OCR => h.ocr = v as _,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_POWER_MODE => {
+                h.ios.power_mode = v as _
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_VDD => h.ios.vdd = v as _,
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_VCCQ => {
+                h.ios.vccq = v as _
+            }
+            kernel_sys::mmcbr_device_ivars_MMCBR_IVAR_TIMING => {
+                h.ios.timing = v as _
+            }
+            _ => return Err(Errno::Inval),
+        }
+        Ok(())
+    }
+
+    fn acquire(&self) {
+        let mut busy = self.idle.wait_while(self.busy.lock(), |busy| *busy);
+        *busy = true;
+    }
+
+    fn release(&self) {
+        *self.busy.lock() = false;
+        self.idle.notify_one();
+    }
+}
+
+impl fmt::Debug for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Host {{ ios: {:?}, max_data: {} }}",
+            self.ios(),
+            self.max_data
+        )
+    }
+}
+
+/// A command, `struct mmc_command`
+pub struct Command<'a> {
+    cmd: &'a mut kernel_sys::mmc_command,
+}
+
+impl Command<'_> {
+    pub fn opcode(&self) -> u32 {
+        self.cmd.opcode
+    }
+
+    pub fn arg(&self) -> u32 {
+        self.cmd.arg
+    }
+
+    /// `rsp::*` and the command type
+    pub fn flags(&self) -> u32 {
+        self.cmd.flags
+    }
+
+    /// Record the response, in the order the bits are sent for R2
+    pub fn set_response(&mut self, resp: [u32; 4]) {
+        self.cmd.resp = resp;
+    }
+
+    pub fn set_error(&mut self, err: MmcError) {
+        self.cmd.error = err as _;
+    }
+
+    /// The data phase, for commands that have one
+    pub fn data(&mut self) -> Option<Data<'_>> {
+        unsafe { self.cmd.data.as_mut() }.map(|data| Data { data })
+    }
+}
+
+impl fmt::Debug for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Command {{ opcode: {}, arg: {:#x} }}",
+            self.opcode(),
+            self.arg()
+        )
+    }
+}
+
+/// A command's data, `struct mmc_data`
+pub struct Data<'a> {
+    data: &'a mut kernel_sys::mmc_data,
+}
+
+impl Data<'_> {
+    /// Whether the card sends the data
+    pub fn is_read(&self) -> bool {
+        self.data.flags as u32 & kernel_sys::MMC_DATA_READ as u32 != 0
+    }
+
+    /// Whether this is a multiple block transfer, ended by the stop command
+    pub fn is_multi(&self) -> bool {
+        self.data.flags as u32 & kernel_sys::MMC_DATA_MULTI as u32 != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.len == 0
+    }
+
+    /// The buffer to fill for a read, or send for a write, in kernel
+    /// memory
+    pub fn buf(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.data.data as *mut u8, self.data.len)
+        }
+    }
+}
+
+/// A request from `mmc`: a command, any data, and a command to stop a
+/// multiple block transfer. `mmc` waits until it is `done`
+#[must_use = "mmc waits for the request to be done"]
+pub struct Request {
+    mrq: NonNull<kernel_sys::mmc_request>,
+}
+
+unsafe impl Send for Request {}
+
+impl Request {
+    pub fn command(&mut self) -> Command<'_> {
+        Command {
+            cmd: unsafe { &mut *self.mrq.as_ref().cmd },
+        }
+    }
+
+    /// The command to send once the data is moved
+    pub fn stop(&mut self) -> Option<Command<'_>> {
+        unsafe { self.mrq.as_ref().stop.as_mut() }.map(|cmd| Command { cmd })
+    }
+
+    /// Hand the request back to `mmc`, errors and responses recorded
+    pub fn done(self) {
+        let mrq = self.mrq.as_ptr();
+        if let Some(done) = unsafe { (*mrq).done } {
+            unsafe { done(mrq) };
+        }
+    }
+}
+
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request {{ mrq: {:?} }}", self.mrq.as_ptr())
+    }
+}
+
+/// A host controller driver's bridge methods
+pub trait HostController: Driver {
+    fn host(&self) -> &Host;
+
+    /// Apply `Host::ios`' clock, bus width, power and timing
+    fn update_ios(&self, dev: Device, ios: Ios) -> Result<(), Errno>;
+
+    /// Start `req`. Once this returns `Ok`, the controller owes it
+    /// `Request::done`; an `Err` is returned to `mmc` without it
+    fn request(&self, dev: Device, req: Request) -> Result<(), Errno>;
+
+    /// Whether the card is write protected
+    fn get_ro(&self, _dev: Device) -> bool {
+        false
+    }
+}
+
+/// The state `T` for `dev`, if something attached it
+fn host_state<'a, T: HostController>(
+    dev: kernel_sys::device_t,
+) -> Option<(Device, &'a T)> {
+    let dev = unsafe { Device::from_raw(dev) }?;
+    Some((dev, unsafe { state::<T>(dev) }?))
+}
+
+/// `bus_read_ivar` for `T`, for `mmc_host!`
+///
+/// ## Safety
+/// `bus` must be a device of `T`'s driver
+pub unsafe fn read_ivar<T: HostController>(
+    bus: kernel_sys::device_t,
+    which: c_int,
+    result: *mut usize,
+) -> c_int {
+    let Some((_, t)) = host_state::<T>(bus) else {
+        return Errno::NxIo.as_raw();
+    };
+    match t.host().read_ivar(which) {
+        Some(v) => {
+            unsafe { *result = v };
+            0
+        }
+        None => Errno::Inval.as_raw(),
+    }
+}
+
+/// `bus_write_ivar` for `T`, for `mmc_host!`
+///
+/// ## Safety
+/// `bus` must be a device of `T`'s driver
+pub unsafe fn write_ivar<T: HostController>(
+    bus: kernel_sys::device_t,
+    which: c_int,
+    value: usize,
+) -> c_int {
+    let Some((_, t)) = host_state::<T>(bus) else {
+        return Errno::NxIo.as_raw();
+    };
+    match t.host().write_ivar(which, value) {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
+
+/// `mmcbr_update_ios` for `T`, for `mmc_host!`
+///
+/// ## Safety
+/// `brdev` must be a device of `T`'s driver
+pub unsafe fn update_ios<T: HostController>(
+    brdev: kernel_sys::device_t,
+) -> c_int {
+    let Some((dev, t)) = host_state::<T>(brdev) else {
+        return Errno::NxIo.as_raw();
+    };
+    catch_in_module(|| t.update_ios(dev, t.host().ios()))
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+/// `mmcbr_request` for `T`, for `mmc_host!`
+///
+/// ## Safety
+/// `brdev` must be a device of `T`'s driver and `req` a request for it
+pub unsafe fn request<T: HostController>(
+    brdev: kernel_sys::device_t,
+    req: *mut kernel_sys::mmc_request,
+) -> c_int {
+    let (Some((dev, t)), Some(mrq)) =
+        (host_state::<T>(brdev), NonNull::new(req))
+    else {
+        return Errno::NxIo.as_raw();
+    };
+    catch_in_module(|| t.request(dev, Request { mrq }))
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+/// `mmcbr_get_ro` for `T`, for `mmc_host!`
+///
+/// ## Safety
+/// `brdev` must be a device of `T`'s driver
+pub unsafe fn get_ro<T: HostController>(brdev: kernel_sys::device_t) -> c_int {
+    let Some((dev, t)) = host_state::<T>(brdev) else {
+        return 0;
+    };
+    catch_in_module(|| t.get_ro(dev)).unwrap_or(true) as c_int
+}
+
+/// `mmcbr_acquire_host` for `T`, for `mmc_host!`. Sleeps until the host
+/// is free
+///
+/// ## Safety
+/// `brdev` must be a device of `T`'s driver
+pub unsafe fn acquire_host<T: HostController>(
+    brdev: kernel_sys::device_t,
+) -> c_int {
+    let Some((_, t)) = host_state::<T>(brdev) else {
+        return Errno::NxIo.as_raw();
+    };
+    t.host().acquire();
+    0
+}
+
+/// `mmcbr_release_host` for `T`, for `mmc_host!`
+///
+/// ## Safety
+/// `brdev` must be a device of `T`'s driver
+pub unsafe fn release_host<T: HostController>(
+    brdev: kernel_sys::device_t,
+) -> c_int {
+    let Some((_, t)) = host_state::<T>(brdev) else {
+        return Errno::NxIo.as_raw();
+    };
+    t.host().release();
+    0
+}
+
+/// Export the bridge methods and ivar accessors of host controller `$ty`,
+/// named as the C method table lists them
+///
+/// ```rust,ignore
+/// bsd_kernel::mmc_host!(Sd,
+///     read_ivar = rustsd_read_ivar, write_ivar = rustsd_write_ivar,
+///     update_ios = rustsd_update_ios, request = rustsd_request,
+///     get_ro = rustsd_get_ro, acquire_host = rustsd_acquire_host,
+///     release_host = rustsd_release_host);
+/// ```
+#[macro_export]
+macro_rules! mmc_host {
+    ($ty:ty,
+     read_ivar = $read_ivar:ident, write_ivar = $write_ivar:ident,
+     update_ios = $update_ios:ident, request = $request:ident,
+     get_ro = $get_ro:ident, acquire_host = $acquire:ident,
+     release_host = $release:ident $(,)?) => {
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $read_ivar(
+            bus: $crate::kernel_sys::device_t,
+            _child: $crate::kernel_sys::device_t,
+            which: $crate::libc::c_int,
+            result: *mut usize,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::mmc::read_ivar::<$ty>(bus, which, result) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $write_ivar(
+            bus: $crate::kernel_sys::device_t,
+            _child: $crate::kernel_sys::device_t,
+            which: $crate::libc::c_int,
+            value: usize,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::mmc::write_ivar::<$ty>(bus, which, value) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $update_ios(
+            brdev: $crate::kernel_sys::device_t,
+            _reqdev: $crate::kernel_sys::device_t,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::mmc::update_ios::<$ty>(brdev) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $request(
+            brdev: $crate::kernel_sys::device_t,
+            _reqdev: $crate::kernel_sys::device_t,
+            req: *mut $crate::kernel_sys::mmc_request,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::mmc::request::<$ty>(brdev, req) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $get_ro(
+            brdev: $crate::kernel_sys::device_t,
+            _reqdev: $crate::kernel_sys::device_t,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::mmc::get_ro::<$ty>(brdev) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $acquire(
+            brdev: $crate::kernel_sys::device_t,
+            _reqdev: $crate::kernel_sys::device_t,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::mmc::acquire_host::<$ty>(brdev) }
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn $release(
+            brdev: $crate::kernel_sys::device_t,
+            _reqdev: $crate::kernel_sys::device_t,
+        ) -> $crate::libc::c_int {
+            unsafe { $crate::mmc::release_host::<$ty>(brdev) }
+        }
+    };
+}
@@ -25,16 +25,31 @@
 
 //! Traits and interfaces for modules
 
+#[cfg(not(feature = "mock"))]
+use crate::allocator::MallocType;
+use crate::errno::Errno;
 use crate::error::Error;
+#[cfg(not(feature = "mock"))]
+use crate::sysctl::{Context, Node};
 use alloc::sync::Arc;
+#[cfg(not(feature = "mock"))]
+use core::cell::UnsafeCell;
 use core::convert::{TryFrom, TryInto};
+#[cfg(not(feature = "mock"))]
+use core::ffi::{CStr, c_char};
 use core::ops::{Deref, DerefMut};
 use core::prelude::v1::*;
+#[cfg(not(feature = "mock"))]
+use core::sync::atomic::{AtomicPtr, Ordering};
 use core::{fmt, ptr};
 use kernel_sys::{
     modeventtype_MOD_LOAD, modeventtype_MOD_QUIESCE, modeventtype_MOD_SHUTDOWN,
     modeventtype_MOD_UNLOAD,
 };
+#[cfg(not(feature = "mock"))]
+use kernel_sys::{
+    sysinit_elem_order_SI_ORDER_ANY, sysinit_elem_order_SI_ORDER_THIRD,
+};
 use spin::{Mutex, MutexGuard};
 
 /// The module event types
@@ -77,14 +92,504 @@ impl ModuleEventType {
     }
 }
 
+/// The `__FreeBSD_version` the bindings were generated against
+pub const BUILT_FOR: i32 = kernel_sys::__FreeBSD_version;
+
+/// Which kernels a module agrees to load into, by their
+/// `__FreeBSD_version`. The bindings bake in the layout of kernel
+/// structures, which may change between versions without any other sign
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Abi {
+    /// Only the version the bindings were generated against
+    Exact,
+    /// Any version of the same stable branch, whose KBI doesn't change,
+    /// from the one the bindings were generated against on
+    Branch,
+    /// Any version in the inclusive range
+    Range(i32, i32),
+}
+
+impl Abi {
+    /// Whether a kernel of version `running` fits
+    pub fn accepts(self, running: i32) -> bool {
+        match self {
+            Abi::Exact => running == BUILT_FOR,
+            Abi::Branch => {
+                running / 100_000 == BUILT_FOR / 100_000 && running >= BUILT_FOR
+            }
+            Abi::Range(min, max) => (min..=max).contains(&running),
+        }
+    }
+}
+
+/// Check the running kernel against `abi`, failing with `Errno::NoExec`
+/// and saying why on the console if it doesn't fit
+pub fn check_abi(abi: Abi) -> Result<(), Errno> {
+    let running = unsafe { kernel_sys::osreldate };
+    if abi.accepts(running) {
+        return Ok(());
+    }
+    crate::println!(
+        "module built for __FreeBSD_version {} ({:?}), kernel is {}",
+        BUILT_FOR,
+        abi,
+        running
+    );
+    Err(Errno::NoExec)
+}
+
+/// A module's `moduledata_t`, registered by a `SysInit::module`, or by a
+/// `DECLARE_MODULE` in C. The `kernel_module` attribute defines one, and
+/// declares it unless told not to
+#[cfg(not(feature = "mock"))]
+#[repr(transparent)]
+pub struct ModuleData(kernel_sys::moduledata_t);
+
+// Only read, by the kernel's module registration
+#[cfg(not(feature = "mock"))]
+unsafe impl Sync for ModuleData {}
+
+#[cfg(not(feature = "mock"))]
+impl ModuleData {
+    pub const fn new(
+        name: &'static CStr,
+        evhand: unsafe extern "C" fn(
+            kernel_sys::module_t,
+            libc::c_int,
+            *mut libc::c_void,
+        ) -> libc::c_int,
+    ) -> Self {
+        ModuleData(kernel_sys::moduledata_t {
+            name: name.as_ptr(),
+            evhand: Some(evhand),
+            priv_: ptr::null_mut(),
+        })
+    }
+
+    /// `DRIVER_MODULE`'s module, which adds a driver to a bus with the
+    /// kernel's `driver_module_handler`
+    pub const fn driver(
+        name: &'static CStr,
+        dmd: &'static crate::bus::DriverModuleData,
+    ) -> Self {
+        ModuleData(kernel_sys::moduledata_t {
+            name: name.as_ptr(),
+            evhand: Some(kernel_sys::driver_module_handler),
+            priv_: dmd as *const _ as _,
+        })
+    }
+}
+
+#[cfg(not(feature = "mock"))]
+impl fmt::Debug for ModuleData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = unsafe { CStr::from_ptr(self.0.name) };
+        write!(f, "ModuleData {{ name: {:?} }}", name)
+    }
+}
+
+/// The `MODULE_KERNEL_MAXVER` of `sys/module.h`: the last kernel version of
+/// the branch the module is built for
+pub const KERNEL_MAXVER: i32 = (BUILT_FOR + 99999) / 100000 * 100000 - 1;
+
+/// A `struct mod_depend`, the versions of a module another one depends on
+#[cfg(not(feature = "mock"))]
+#[repr(transparent)]
+pub struct ModuleDepend(kernel_sys::mod_depend);
+
+#[cfg(not(feature = "mock"))]
+impl ModuleDepend {
+    pub const fn new(minimum: i32, preferred: i32, maximum: i32) -> Self {
+        ModuleDepend(kernel_sys::mod_depend {
+            md_ver_minimum: minimum,
+            md_ver_preferred: preferred,
+            md_ver_maximum: maximum,
+        })
+    }
+
+    /// The dependency on the kernel that `DECLARE_MODULE` records
+    pub const fn kernel() -> Self {
+        Self::new(BUILT_FOR, BUILT_FOR, KERNEL_MAXVER)
+    }
+}
+
+/// A `struct mod_version`, the version a module provides
+#[cfg(not(feature = "mock"))]
+#[repr(transparent)]
+pub struct ModuleVersion(kernel_sys::mod_version);
+
+#[cfg(not(feature = "mock"))]
+impl ModuleVersion {
+    pub const fn new(version: i32) -> Self {
+        ModuleVersion(kernel_sys::mod_version {
+            mv_version: version,
+        })
+    }
+}
+
+/// A `struct mod_metadata`, which the kernel linker reads from a file's
+/// `modmetadata_set` to find the modules in it, their versions and what
+/// they depend on
+#[cfg(not(feature = "mock"))]
+#[repr(transparent)]
+pub struct ModuleMetadata(kernel_sys::mod_metadata);
+
+// Only read, by the kernel linker
+#[cfg(not(feature = "mock"))]
+unsafe impl Sync for ModuleMetadata {}
+
+#[cfg(not(feature = "mock"))]
+impl ModuleMetadata {
+    const fn new(
+        kind: i32,
+        data: *const libc::c_void,
+        name: &'static CStr,
+    ) -> Self {
+        ModuleMetadata(kernel_sys::mod_metadata {
+            md_version: kernel_sys::MDT_STRUCT_VERSION,
+            md_type: kind,
+            md_data: data,
+            md_cval: name.as_ptr(),
+        })
+    }
+
+    /// `DECLARE_MODULE`'s entry for the module `name`
+    pub const fn module(
+        data: &'static ModuleData,
+        name: &'static CStr,
+    ) -> Self {
+        Self::new(kernel_sys::MDT_MODULE, data as *const _ as _, name)
+    }
+
+    /// `MODULE_DEPEND`'s entry, on the module `name`
+    pub const fn depend(
+        depend: &'static ModuleDepend,
+        name: &'static CStr,
+    ) -> Self {
+        Self::new(kernel_sys::MDT_DEPEND, depend as *const _ as _, name)
+    }
+
+    /// `MODULE_VERSION`'s entry, for the module `name`
+    pub const fn version(
+        version: &'static ModuleVersion,
+        name: &'static CStr,
+    ) -> Self {
+        Self::new(kernel_sys::MDT_VERSION, version as *const _ as _, name)
+    }
+}
+
+/// The `SI_SUB_*` subsystems sysinits run in, earliest first. A module's
+/// own sysinits run when it is loaded, in the same order as at boot
+#[cfg(not(feature = "mock"))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Subsystem {
+    /// `SI_SUB_TUNABLES`: loader tunables are read
+    Tunables,
+    /// `SI_SUB_KMEM`: malloc types are registered
+    Kmem,
+    /// `SI_SUB_LOCK`: global locks are initialized
+    Lock,
+    /// `SI_SUB_EVENTHANDLER`: event handler lists are made
+    EventHandler,
+    /// `SI_SUB_KLD`: the kernel linker starts
+    Kld,
+    /// `SI_SUB_INTRINSIC`: proc 0 and other intrinsic state
+    Intrinsic,
+    /// `SI_SUB_DRIVERS`: drivers are registered, and modules loaded by
+    /// default
+    Drivers,
+    /// `SI_SUB_CONFIGURE`: devices are probed and attached
+    Configure,
+    /// `SI_SUB_VFS`: the virtual file system
+    Vfs,
+    /// `SI_SUB_CLOCKS`: clocks are started
+    Clocks,
+    /// `SI_SUB_PSEUDO`: pseudo devices
+    Pseudo,
+    /// `SI_SUB_PROTO_BEGIN`: network protocols begin
+    ProtoBegin,
+    /// `SI_SUB_VNET`: the vnet subsystem, before any per-vnet sysinits
+    Vnet,
+    /// `SI_SUB_PROTO_DOMAIN`: protocol domains are added
+    ProtoDomain,
+    /// `SI_SUB_PROTO_FIREWALL`: firewalls hook into the stack
+    ProtoFirewall,
+    /// `SI_SUB_PROTO_END`: network protocols end
+    ProtoEnd,
+    /// `SI_SUB_ROOT_CONF`: the root file system is found
+    RootConf,
+    /// `SI_SUB_SYSCALLS`: system calls are registered
+    Syscalls,
+    /// `SI_SUB_VNET_DONE`: the last of the per-vnet sysinits
+    VnetDone,
+    /// `SI_SUB_KTHREAD_INIT`: init is started
+    KthreadInit,
+    /// `SI_SUB_SMP`: the other CPUs are started
+    Smp,
+    /// `SI_SUB_LAST`: after everything else
+    Last,
+}
+
+#[cfg(not(feature = "mock"))]
+impl Subsystem {
+    pub const fn as_raw(self) -> kernel_sys::sysinit_sub_id {
+        use Subsystem::*;
+        match self {
+            Tunables => kernel_sys::sysinit_sub_id_SI_SUB_TUNABLES,
+            Kmem => kernel_sys::sysinit_sub_id_SI_SUB_KMEM,
+            Lock => kernel_sys::sysinit_sub_id_SI_SUB_LOCK,
+            EventHandler => kernel_sys::sysinit_sub_id_SI_SUB_EVENTHANDLER,
+            Kld => kernel_sys::sysinit_sub_id_SI_SUB_KLD,
+            Intrinsic => kernel_sys::sysinit_sub_id_SI_SUB_INTRINSIC,
+            Drivers => kernel_sys::sysinit_sub_id_SI_SUB_DRIVERS,
+            Configure => kernel_sys::sysinit_sub_id_SI_SUB_CONFIGURE,
+            Vfs => kernel_sys::sysinit_sub_id_SI_SUB_VFS,
+            Clocks => kernel_sys::sysinit_sub_id_SI_SUB_CLOCKS,
+            Pseudo => kernel_sys::sysinit_sub_id_SI_SUB_PSEUDO,
+            ProtoBegin => kernel_sys::sysinit_sub_id_SI_SUB_PROTO_BEGIN,
+            Vnet => kernel_sys::sysinit_sub_id_SI_SUB_VNET,
+            ProtoDomain => kernel_sys::sysinit_sub_id_SI_SUB_PROTO_DOMAIN,
+            ProtoFirewall => kernel_sys::sysinit_sub_id_SI_SUB_PROTO_FIREWALL,
+            ProtoEnd => kernel_sys::sysinit_sub_id_SI_SUB_PROTO_END,
+            RootConf => kernel_sys::sysinit_sub_id_SI_SUB_ROOT_CONF,
+            Syscalls => kernel_sys::sysinit_sub_id_SI_SUB_SYSCALLS,
+            VnetDone => kernel_sys::sysinit_sub_id_SI_SUB_VNET_DONE,
+            KthreadInit => kernel_sys::sysinit_sub_id_SI_SUB_KTHREAD_INIT,
+            Smp => kernel_sys::sysinit_sub_id_SI_SUB_SMP,
+            Last => kernel_sys::sysinit_sub_id_SI_SUB_LAST,
+        }
+    }
+}
+
+/// The `SI_ORDER_*` order of a sysinit within its subsystem, earliest
+/// first. Sysinits of the same subsystem and order run in no set order
+#[cfg(not(feature = "mock"))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Order {
+    First,
+    Second,
+    Third,
+    Fourth,
+    Fifth,
+    Sixth,
+    Seventh,
+    Eighth,
+    /// Where `DECLARE_MODULE` registers modules by default
+    Middle,
+    Any,
+}
+
+#[cfg(not(feature = "mock"))]
+impl Order {
+    pub const fn as_raw(self) -> kernel_sys::sysinit_elem_order {
+        use Order::*;
+        match self {
+            First => kernel_sys::sysinit_elem_order_SI_ORDER_FIRST,
+            Second => kernel_sys::sysinit_elem_order_SI_ORDER_SECOND,
+            Third => kernel_sys::sysinit_elem_order_SI_ORDER_THIRD,
+            Fourth => kernel_sys::sysinit_elem_order_SI_ORDER_FOURTH,
+            Fifth => kernel_sys::sysinit_elem_order_SI_ORDER_FIFTH,
+            Sixth => kernel_sys::sysinit_elem_order_SI_ORDER_SIXTH,
+            Seventh => kernel_sys::sysinit_elem_order_SI_ORDER_SEVENTH,
+            Eighth => kernel_sys::sysinit_elem_order_SI_ORDER_EIGHTH,
+            Middle => kernel_sys::sysinit_elem_order_SI_ORDER_MIDDLE,
+            Any => kernel_sys::sysinit_elem_order_SI_ORDER_ANY,
+        }
+    }
+}
+
+/// A `struct sysinit`, run by the kernel at boot or when the file holding
+/// it is loaded, in order of subsystem then order within it
+#[cfg(not(feature = "mock"))]
+#[repr(transparent)]
+pub struct SysInit(UnsafeCell<kernel_sys::sysinit>);
+
+// Written only by the kernel, while it sorts sysinits
+#[cfg(not(feature = "mock"))]
+unsafe impl Sync for SysInit {}
+
+#[cfg(not(feature = "mock"))]
+impl SysInit {
+    /// `DECLARE_MODULE`'s sysinit, which registers the module
+    pub const fn module(
+        data: &'static ModuleData,
+        subsystem: kernel_sys::sysinit_sub_id,
+        order: kernel_sys::sysinit_elem_order,
+    ) -> Self {
+        // Zeroed for the list linkage some kernels have
+        let mut si: kernel_sys::sysinit = unsafe { core::mem::zeroed() };
+        si.subsystem = subsystem;
+        si.order = order;
+        si.func = Some(kernel_sys::module_register_init);
+        si.udata = data as *const _ as _;
+        SysInit(UnsafeCell::new(si))
+    }
+
+    /// `SYSINIT`'s or `SYSUNINIT`'s sysinit, which calls `f`. Declared
+    /// with `sysinit!` or `sysuninit!`
+    pub const fn call(subsystem: Subsystem, order: Order, f: fn()) -> Self {
+        let mut si: kernel_sys::sysinit = unsafe { core::mem::zeroed() };
+        si.subsystem = subsystem.as_raw();
+        si.order = order.as_raw();
+        si.func = Some(run);
+        si.udata = f as *const libc::c_void;
+        SysInit(UnsafeCell::new(si))
+    }
+
+    /// `MALLOC_DEFINE`'s sysinit, which tells the kernel of `ty`
+    pub const fn malloc_init(ty: &'static MallocType) -> Self {
+        Self::malloc(
+            ty,
+            kernel_sys::malloc_init,
+            sysinit_elem_order_SI_ORDER_THIRD,
+        )
+    }
+
+    /// `MALLOC_DEFINE`'s sysuninit, for the `sysuninit_set`, which has the
+    /// kernel forget `ty`
+    pub const fn malloc_uninit(ty: &'static MallocType) -> Self {
+        Self::malloc(
+            ty,
+            kernel_sys::malloc_uninit,
+            sysinit_elem_order_SI_ORDER_ANY,
+        )
+    }
+
+    const fn malloc(
+        ty: &'static MallocType,
+        func: unsafe extern "C" fn(*mut libc::c_void),
+        order: kernel_sys::sysinit_elem_order,
+    ) -> Self {
+        let mut si: kernel_sys::sysinit = unsafe { core::mem::zeroed() };
+        si.subsystem = kernel_sys::sysinit_sub_id_SI_SUB_KMEM;
+        si.order = order;
+        // As SYSINIT casts it, the argument being the C API's void *
+        si.func = Some(unsafe {
+            core::mem::transmute::<
+                unsafe extern "C" fn(*mut libc::c_void),
+                unsafe extern "C" fn(*const libc::c_void),
+            >(func)
+        });
+        si.udata = ty as *const _ as _;
+        SysInit(UnsafeCell::new(si))
+    }
+}
+
+/// The `sysinit_cfunc_t` of a `SysInit::call`, given its function. A
+/// sysinit can't fail, so a panic is only logged
+#[cfg(not(feature = "mock"))]
+unsafe extern "C" fn run(arg: *const libc::c_void) {
+    let f: fn() = unsafe { core::mem::transmute(arg) };
+    let _ = crate::panic::catch_in_module(f);
+}
+
+/// An entry of a linker set, such as `modmetadata_set` or `sysinit_set`,
+/// for a static placed in the set's `set_<name>` section. Writable, as
+/// the kernel linker sorts `sysinit_set` in place
+#[cfg(not(feature = "mock"))]
+#[repr(transparent)]
+pub struct LinkerSetEntry<T: 'static>(UnsafeCell<*const T>);
+
+#[cfg(not(feature = "mock"))]
+unsafe impl<T: Sync> Sync for LinkerSetEntry<T> {}
+
+#[cfg(not(feature = "mock"))]
+impl<T: Sync> LinkerSetEntry<T> {
+    pub const fn new(item: &'static T) -> Self {
+        LinkerSetEntry(UnsafeCell::new(item))
+    }
+}
+
+/// Call a function as the module is loaded, at a point of the kernel's
+/// `SYSINIT` order, as `SYSINIT` does
+///
+/// ```rust,ignore
+/// use bsd_kernel::module::{Order, Subsystem};
+///
+/// fn tables() { /* ... */ }
+/// fn hooks() { /* ... */ }
+/// fn drop_tables() { /* ... */ }
+///
+/// bsd_kernel::sysinit!(Subsystem::Drivers, Order::First, tables);
+/// bsd_kernel::sysinit!(Subsystem::ProtoFirewall, Order::Any, hooks);
+/// bsd_kernel::sysuninit!(Subsystem::Drivers, Order::First, drop_tables);
+/// ```
+///
+/// Steps run earliest first, with the module's load event at
+/// `Subsystem::Drivers`, `Order::Middle` unless `kernel_module` is told
+/// otherwise. When the module is compiled into the kernel, they run at
+/// boot, as soon as their subsystem is reached.
+#[cfg(not(feature = "mock"))]
+#[macro_export]
+macro_rules! sysinit {
+    ($subsystem:expr, $order:expr, $f:expr $(,)?) => {
+        const _: () = {
+            use $crate::module::{LinkerSetEntry, SysInit};
+
+            static INIT: SysInit = SysInit::call($subsystem, $order, $f);
+            #[used]
+            #[unsafe(link_section = "set_sysinit_set")]
+            static INIT_ENTRY: LinkerSetEntry<SysInit> =
+                LinkerSetEntry::new(&INIT);
+        };
+    };
+}
+
+/// Call a function as the module is unloaded, as `SYSUNINIT` does
+///
+/// Steps run in the reverse of the `sysinit!` order, latest first, and
+/// after the module's unload event, once the kernel linker has committed
+/// to unloading the file.
+#[cfg(not(feature = "mock"))]
+#[macro_export]
+macro_rules! sysuninit {
+    ($subsystem:expr, $order:expr, $f:expr $(,)?) => {
+        const _: () = {
+            use $crate::module::{LinkerSetEntry, SysInit};
+
+            static UNINIT: SysInit = SysInit::call($subsystem, $order, $f);
+            #[used]
+            #[unsafe(link_section = "set_sysuninit_set")]
+            static UNINIT_ENTRY: LinkerSetEntry<SysInit> =
+                LinkerSetEntry::new(&UNINIT);
+        };
+    };
+}
+
 /// Functions to handle each type of module event
 ///
-/// TODO: functions for SHUTDOWN and QUIESCE with default implementations
+/// TODO: a function for SHUTDOWN with a default implementation
 pub trait ModuleEvents {
+    /// Kernels `SharedModule::load` agrees to load into
+    const ABI: Abi = Abi::Exact;
+
+    /// Name of the module's `hw.rustmod.<name>` sysctl node, created by
+    /// `SharedModule::load` for `sysctl` to add children to
+    #[cfg(not(feature = "mock"))]
+    const SYSCTL_NAME: Option<&'static CStr> = None;
+
     /// Function called when the module is loaded
     fn load(&mut self);
-    /// Function called when the module is unloaded
+    /// Function called when the module is unloaded, unless it has
+    /// panicked
     fn unload(&mut self);
+    /// Function called before `unload`, unless unloading is forced. An
+    /// error, such as `Errno::Busy` while the module is in use, keeps the
+    /// module loaded
+    fn quiesce(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Add the module's oids under `root`, before `load`. Everything added
+    /// through `sysctl` is removed along with `root` after `unload`
+    #[cfg(not(feature = "mock"))]
+    fn sysctl(
+        &mut self,
+        _root: Node,
+        _sysctl: &mut Context,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
 }
 
 pub struct LockedModule<'a, T: Sized + 'a> {
@@ -123,11 +628,16 @@ impl<'a, T> core::fmt::Debug for LockedModule<'a, T> {
 #[derive(Debug, Default)]
 pub struct SharedModule<T> {
     inner: Arc<Mutex<Option<T>>>,
+    /// The module's sysctl node and its children, while loaded
+    #[cfg(not(feature = "mock"))]
+    sysctl: Arc<Mutex<Option<Context>>>,
 }
 impl<T> SharedModule<T> {
     pub fn new(data: T) -> Self {
         SharedModule {
             inner: Arc::new(Mutex::new(Some(data))),
+            #[cfg(not(feature = "mock"))]
+            sysctl: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -144,7 +654,23 @@ impl<T> SharedModule<T> {
         }
     }
 
+    /// Like `lock`, but `None` rather than waiting if the module is
+    /// locked elsewhere
+    pub fn try_lock(&self) -> Option<LockedModule<T>> {
+        let guard = self.inner.try_lock()?;
+        if guard.is_some() {
+            Some(LockedModule { guard })
+        } else {
+            None
+        }
+    }
+
     pub fn cleanup(&self) {
+        #[cfg(not(feature = "mock"))]
+        {
+            // Before the module goes, as the oids' handlers may use it
+            let _ = self.sysctl.lock().take();
+        }
         {
             let _ = self.inner.lock().take();
         }
@@ -155,14 +681,102 @@ impl<T> SharedModule<T> {
                 as *const Arc<Mutex<Option<T>>>
                 as *mut Arc<Mutex<Option<T>>>;
             ptr::drop_in_place(ptr);
+            #[cfg(not(feature = "mock"))]
+            {
+                let ptr = &self.sysctl as *const Arc<Mutex<Option<Context>>>
+                    as *mut Arc<Mutex<Option<Context>>>;
+                ptr::drop_in_place(ptr);
+            }
+        }
+    }
+}
+
+impl<T: ModuleEvents> SharedModule<T> {
+    /// Handle `MOD_LOAD`: check the kernel against `T::ABI`, create the
+    /// module's sysctl node if it names one, then call `load`. An error
+    /// is returned to the kernel, which then doesn't load the module
+    pub fn load(&self) -> Result<(), Errno> {
+        check_abi(T::ABI)?;
+        let Some(mut m) = self.lock() else {
+            return Ok(());
+        };
+        #[cfg(not(feature = "mock"))]
+        if let Some(name) = T::SYSCTL_NAME {
+            SYSCTL_NAME.store(name.as_ptr() as *mut c_char, Ordering::Release);
+            let mut ctx = Context::new();
+            let res = module_node(&mut ctx, name)
+                .and_then(|root| m.sysctl(root, &mut ctx));
+            if let Err(e) = res {
+                let name = crate::kstr::KernelStr::from_cstr(name);
+                crate::println!("sysctl hw.rustmod.{}: {}", name, e);
+            }
+            *self.sysctl.lock() = Some(ctx);
         }
+        m.load();
+        Ok(())
     }
+
+    /// Handle `MOD_QUIESCE`: call `quiesce`, unless the module has panicked,
+    /// for whatever it returns to refuse the unload. Refused with
+    /// `Errno::Busy` while a thread that panicked is parked in the module
+    pub fn quiesce(&self) -> Result<(), Errno> {
+        if crate::panic::parked() > 0 {
+            return Err(Errno::Busy);
+        }
+        if crate::panic::module_poisoned() {
+            return Ok(());
+        }
+        match self.lock() {
+            Some(mut m) => m.quiesce(),
+            None => Ok(()),
+        }
+    }
+
+    /// Handle `MOD_UNLOAD`: call `unload`, unless the module has panicked,
+    /// then remove the module's sysctl node and its children
+    pub fn unload(&self) {
+        if crate::panic::module_poisoned() {
+            crate::println!("unloading a module that panicked");
+        } else if let Some(mut m) = self.lock() {
+            m.unload();
+        }
+        // Unlocked, as removing the oids waits for handlers that may lock it
+        #[cfg(not(feature = "mock"))]
+        {
+            let ctx = self.sysctl.lock().take();
+            drop(ctx);
+        }
+    }
+}
+
+/// The `ModuleEvents::SYSCTL_NAME` of the module, once loaded. Each module
+/// links its own copy of this crate, so there is one per module
+#[cfg(not(feature = "mock"))]
+static SYSCTL_NAME: AtomicPtr<c_char> = AtomicPtr::new(ptr::null_mut());
+
+/// The name of the module's node under `hw.rustmod`, if it has one
+#[cfg(not(feature = "mock"))]
+pub(crate) fn sysctl_name() -> Option<&'static CStr> {
+    let p = SYSCTL_NAME.load(Ordering::Acquire);
+    (!p.is_null()).then(|| unsafe { CStr::from_ptr(p) })
+}
+
+/// Add `hw.rustmod`, which modules share, and `hw.rustmod.<name>` to `ctx`
+#[cfg(not(feature = "mock"))]
+pub(crate) fn module_node(
+    ctx: &mut Context,
+    name: &CStr,
+) -> Result<Node, Errno> {
+    let rustmod = ctx.add_node(Node::hw(), c"rustmod", c"Rust modules")?;
+    ctx.add_node(rustmod, name, c"Module parameters and statistics")
 }
 
 impl<T> Clone for SharedModule<T> {
     fn clone(&self) -> Self {
         SharedModule {
             inner: self.inner.clone(),
+            #[cfg(not(feature = "mock"))]
+            sysctl: self.sysctl.clone(),
         }
     }
 }
@@ -0,0 +1,97 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The Internet checksum (RFC 1071) of IP, TCP and UDP
+//!
+//! All 16-bit values are as stored in the headers, in network byte
+//! order; the one's complement sum comes out the same either way round as
+//! long as everything is in the same order.
+
+use super::Mbuf;
+use core::net::Ipv4Addr;
+use libc::c_int;
+
+/// Checksum of `len` bytes at `offset` in the chain, complemented and
+/// ready to store. Over data that includes its own valid checksum the
+/// result is 0
+///
+/// ## Panics
+/// If the range runs past the end of the chain
+pub fn cksum(m: &Mbuf, offset: usize, len: usize) -> u16 {
+    let end = offset.checked_add(len).unwrap();
+    assert!(end <= m.len(), "checksum range past end of mbuf chain");
+    // in_cksum_skip() counts its length from the start of the chain
+    unsafe {
+        kernel_sys::in_cksum_skip(
+            m.as_ptr(),
+            c_int::try_from(end).unwrap(),
+            offset as c_int,
+        )
+    }
+}
+
+/// Sum of the IPv4 pseudo-header that TCP and UDP checksums cover, for a
+/// segment of `len` bytes of protocol `proto`. Not complemented: it's a
+/// partial sum to add the segment to, or to store in the checksum field
+/// for hardware offload to finish
+pub fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, len: u16) -> u16 {
+    unsafe {
+        kernel_sys::in_pseudo(
+            u32::from_ne_bytes(src.octets()),
+            u32::from_ne_bytes(dst.octets()),
+            u32::from((u16::from(proto) + len).to_be()),
+        )
+    }
+}
+
+/// Checksum of the TCP or UDP segment of `len` bytes at `offset` in the
+/// chain, pseudo-header included, ready to store. Compute it with the
+/// checksum field zeroed; over a received segment the result is 0 if the
+/// segment is intact. UDP sends a computed 0 as 0xffff
+///
+/// ## Panics
+/// If the range runs past the end of the chain, or is longer than an IP
+/// packet can be
+pub fn transport_cksum(
+    m: &Mbuf,
+    offset: usize,
+    len: usize,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    proto: u8,
+) -> u16 {
+    let pseudo = pseudo_header(src, dst, proto, u16::try_from(len).unwrap());
+    let data = !cksum(m, offset, len);
+    let sum = unsafe { kernel_sys::in_addword(data, pseudo) };
+    !sum
+}
+
+/// Update `cksum` for a 16-bit word of the data it covers changing from
+/// `old` to `new`, without summing the data again (RFC 1624). For larger
+/// edits, such as rewriting an address, apply it to each word in turn
+pub fn adjust(cksum: u16, old: u16, new: u16) -> u16 {
+    let sum = u32::from(!cksum) + u32::from(!old) + u32::from(new);
+    let sum = (sum & 0xffff) + (sum >> 16);
+    let sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
@@ -0,0 +1,350 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! dummynet packet schedulers, selected with `ipfw sched N config type`
+//!
+//! dummynet passes each packet bound for a pipe's link to the pipe's
+//! scheduler, and takes them back in the order the scheduler picks as
+//! the link has room. A scheduler is a type implementing `Scheduler`,
+//! registered by its module through a static `Algorithm`:
+//! ```ignore
+//! static RUSTFQ: Algorithm = Algorithm::new::<RustFq>();
+//!
+//! // in the module's load and unload events
+//! RUSTFQ.load()?;
+//! RUSTFQ.unload()?;
+//! ```
+//! with `MODULE_DEPEND(dn_rustfq, dummynet, 3, 3, 3)` in the C glue. Then
+//! `ipfw sched 1 config pipe 1 type rustfq` puts it to use, and for a
+//! multiqueue scheduler `ipfw queue 1 config sched 1 mask all` gives it a
+//! queue per flow.
+//!
+//! dummynet calls schedulers with its lock held, one call at a time, so
+//! they must not sleep, which includes allocating: state lives in memory
+//! dummynet allocates for each instance and queue, and packets wait in
+//! `MbufQueue`s.
+
+use super::{Mbuf, MbufQueue};
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+use core::{fmt, mem};
+use libc::c_int;
+
+/// `DN_MULTIQUEUE`, from `netpfil/ipfw/dn_sched.h`
+const MULTIQUEUE: u32 = 0x01;
+/// `DN_QSIZE_BYTES`, from `netinet/ip_dummynet.h`
+const QSIZE_BYTES: u32 = 0x0008;
+
+/// A packet scheduler. Each pipe using it has an instance, or one per
+/// `sched_mask` flow with a mask set
+pub trait Scheduler: Sized + Send + 'static {
+    /// The name `ipfw sched N config type` selects it by
+    const NAME: &'static CStr;
+    /// A number for the scheduler, unique among those loaded. The base
+    /// system's are below 8
+    const TYPE: u32;
+    /// Whether the scheduler is given each flowset's packets in queues of
+    /// their own, split by the flowset's mask, rather than all together
+    const MULTIQUEUE: bool = false;
+
+    /// The state of a queue, for a multiqueue scheduler
+    type Queue: Default + Send;
+
+    fn new(config: &Config) -> Self;
+
+    /// Take `m`, arriving on `queue` for a multiqueue scheduler, or drop
+    /// it by returning false
+    fn enqueue(&mut self, queue: Option<Queue<Self::Queue>>, m: Mbuf) -> bool;
+
+    /// The next packet for the link, if any
+    fn dequeue(&mut self) -> Option<Mbuf>;
+
+    /// A queue was made for a flow of `flowset`
+    fn new_queue(&mut self, _flowset: &Flowset) -> Self::Queue {
+        Self::Queue::default()
+    }
+
+    /// `queue` is going away, and its state with it; the scheduler must
+    /// forget it
+    fn free_queue(&mut self, _queue: Queue<Self::Queue>) {}
+}
+
+/// The configuration of a scheduler, `struct dn_schk`
+pub struct Config {
+    s: NonNull<kernel_sys::dn_schk>,
+}
+
+impl Config {
+    /// The `N` of `ipfw sched N`
+    pub fn number(&self) -> u32 {
+        unsafe { (*self.s.as_ptr()).sch.sched_nr }
+    }
+
+    /// The pipe's bandwidth in bits per second, 0 for unlimited
+    pub fn bandwidth(&self) -> u32 {
+        unsafe { (*self.s.as_ptr()).link.bandwidth }
+    }
+
+    /// The pipe's delay in milliseconds
+    pub fn delay(&self) -> i32 {
+        unsafe { (*self.s.as_ptr()).link.delay }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Config {{ number: {}, bandwidth: {} }}",
+            self.number(),
+            self.bandwidth()
+        )
+    }
+}
+
+/// The configuration of a flowset, `struct dn_fs`, that a queue was made
+/// for
+#[derive(Copy, Clone, Debug)]
+pub struct Flowset {
+    /// The `N` of `ipfw queue N`
+    pub number: u32,
+    /// Packets, or with `is_bytes` bytes, the queue may hold
+    pub qsize: u32,
+    pub is_bytes: bool,
+    /// The flowset's `weight`, `lmax` and `pri` parameters and one more,
+    /// for the scheduler to interpret
+    pub params: [i32; 4],
+}
+
+impl Flowset {
+    /// ## Safety
+    /// `fs` must be a live flowset
+    unsafe fn from_raw(fs: *const kernel_sys::dn_fsk) -> Self {
+        let fs = unsafe { &(*fs).fs };
+        Flowset {
+            number: fs.fs_nr,
+            qsize: fs.qsize,
+            is_bytes: fs.flags & QSIZE_BYTES != 0,
+            params: fs.par,
+        }
+    }
+
+    /// Whether a queue holding `queued` with `m` added would be over
+    /// `qsize`
+    pub fn is_full(&self, queued: &MbufQueue, m: &Mbuf) -> bool {
+        if self.is_bytes {
+            queued.bytes() + m.len() > self.qsize as usize
+        } else {
+            queued.len() >= self.qsize as usize
+        }
+    }
+}
+
+/// A dummynet queue of a multiqueue scheduler: a handle that stays valid
+/// until the scheduler's `free_queue` is called with it
+pub struct Queue<Q> {
+    q: NonNull<kernel_sys::dn_queue>,
+    _state: PhantomData<*mut Q>,
+}
+
+unsafe impl<Q: Send> Send for Queue<Q> {}
+
+impl<Q> Queue<Q> {
+    pub fn flowset(&self) -> Flowset {
+        unsafe { Flowset::from_raw((*self.q.as_ptr()).fs) }
+    }
+
+    /// The queue's state, made by `Scheduler::new_queue`
+    ///
+    /// ## Safety
+    /// The queue must not be freed yet, and no other reference to its
+    /// state, through a copy of the handle, may be live
+    pub unsafe fn state<'a>(self) -> &'a mut Q {
+        unsafe { &mut *queue_data(self.q.as_ptr()) }
+    }
+}
+
+impl<Q> Clone for Queue<Q> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Q> Copy for Queue<Q> {}
+
+impl<Q> PartialEq for Queue<Q> {
+    fn eq(&self, other: &Self) -> bool {
+        self.q == other.q
+    }
+}
+
+impl<Q> Eq for Queue<Q> {}
+
+impl<Q> fmt::Debug for Queue<Q> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Queue {{ q: {:p} }}", self.q)
+    }
+}
+
+/// A scheduler's `struct dn_alg`, for dummynet to find it by
+pub struct Algorithm(UnsafeCell<kernel_sys::dn_alg>);
+
+// dummynet only touches the reference count and list linkage, under its
+// own lock
+unsafe impl Sync for Algorithm {}
+
+impl Algorithm {
+    pub const fn new<T: Scheduler>() -> Self {
+        // dummynet puts instance and queue data right after its own
+        // structures, only pointer aligned
+        assert!(mem::align_of::<T>() <= mem::align_of::<usize>());
+        assert!(mem::align_of::<T::Queue>() <= mem::align_of::<usize>());
+        let mut alg: kernel_sys::dn_alg = unsafe { mem::zeroed() };
+        alg.type_ = T::TYPE;
+        alg.name = T::NAME.as_ptr();
+        alg.flags = if T::MULTIQUEUE { MULTIQUEUE } else { 0 };
+        alg.si_datalen = mem::size_of::<T>();
+        alg.q_datalen = mem::size_of::<T::Queue>();
+        alg.enqueue = Some(enqueue::<T>);
+        alg.dequeue = Some(dequeue::<T>);
+        alg.new_sched = Some(new_sched::<T>);
+        alg.free_sched = Some(free_sched::<T>);
+        alg.new_queue = Some(new_queue::<T>);
+        alg.free_queue = Some(free_queue::<T>);
+        Algorithm(UnsafeCell::new(alg))
+    }
+
+    /// Register the scheduler, for the module's load event
+    pub fn load(&'static self) -> Result<(), Errno> {
+        self.modevent(kernel_sys::modeventtype_MOD_LOAD)
+    }
+
+    /// Unregister the scheduler, failing with `Errno::Busy` while pipes
+    /// still use it
+    pub fn unload(&'static self) -> Result<(), Errno> {
+        self.modevent(kernel_sys::modeventtype_MOD_UNLOAD)
+    }
+
+    fn modevent(&self, cmd: kernel_sys::modeventtype) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::dn_sched_modevent(
+                ptr::null_mut(),
+                cmd as c_int,
+                self.0.get().cast(),
+            )
+        })
+    }
+}
+
+impl fmt::Debug for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = unsafe { CStr::from_ptr((*self.0.get()).name) };
+        write!(f, "Algorithm {{ name: {:?} }}", name)
+    }
+}
+
+/// The instance data after a `dn_sch_inst`
+fn instance_data<T>(si: *mut kernel_sys::dn_sch_inst) -> *mut T {
+    unsafe { si.add(1).cast() }
+}
+
+/// The queue data after a `dn_queue`
+fn queue_data<Q>(q: *mut kernel_sys::dn_queue) -> *mut Q {
+    unsafe { q.add(1).cast() }
+}
+
+unsafe extern "C" fn new_sched<T: Scheduler>(
+    si: *mut kernel_sys::dn_sch_inst,
+) -> c_int {
+    let config = Config {
+        s: NonNull::new(unsafe { (*si).sched }).unwrap(),
+    };
+    catch_in_module(|| unsafe { instance_data::<T>(si).write(T::new(&config)) })
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+unsafe extern "C" fn free_sched<T: Scheduler>(
+    si: *mut kernel_sys::dn_sch_inst,
+) -> c_int {
+    // Leaked rather than dropped once the module is poisoned, as
+    // everything is
+    let _ = catch_in_module(|| unsafe {
+        ptr::drop_in_place(instance_data::<T>(si))
+    });
+    0
+}
+
+/// Returns 1 if the packet was dropped, and freed
+unsafe extern "C" fn enqueue<T: Scheduler>(
+    si: *mut kernel_sys::dn_sch_inst,
+    q: *mut kernel_sys::dn_queue,
+    m: *mut kernel_sys::mbuf,
+) -> c_int {
+    let t = unsafe { &mut *instance_data::<T>(si) };
+    let queue = NonNull::new(q).map(|q| Queue {
+        q,
+        _state: PhantomData,
+    });
+    let m = unsafe { Mbuf::from_raw(m) };
+    catch_in_module(|| t.enqueue(queue, m)).map_or(1, |ok| !ok as c_int)
+}
+
+unsafe extern "C" fn dequeue<T: Scheduler>(
+    si: *mut kernel_sys::dn_sch_inst,
+) -> *mut kernel_sys::mbuf {
+    let t = unsafe { &mut *instance_data::<T>(si) };
+    catch_in_module(|| t.dequeue())
+        .ok()
+        .flatten()
+        .map_or(ptr::null_mut(), Mbuf::into_raw)
+}
+
+unsafe extern "C" fn new_queue<T: Scheduler>(
+    q: *mut kernel_sys::dn_queue,
+) -> c_int {
+    let t = unsafe { &mut *instance_data::<T>((*q)._si) };
+    let flowset = unsafe { Flowset::from_raw((*q).fs) };
+    catch_in_module(|| unsafe {
+        queue_data::<T::Queue>(q).write(t.new_queue(&flowset))
+    })
+    .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+unsafe extern "C" fn free_queue<T: Scheduler>(
+    q: *mut kernel_sys::dn_queue,
+) -> c_int {
+    let t = unsafe { &mut *instance_data::<T>((*q)._si) };
+    let queue = Queue {
+        q: NonNull::new(q).unwrap(),
+        _state: PhantomData,
+    };
+    let _ = catch_in_module(|| {
+        t.free_queue(queue);
+        unsafe { ptr::drop_in_place(queue_data::<T::Queue>(q)) }
+    });
+    0
+}
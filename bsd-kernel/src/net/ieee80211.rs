@@ -0,0 +1,748 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! 802.11 wireless drivers on `net80211`, see `ieee80211(9)`
+//!
+//! A driver describes its radio with `Com::attach` and net80211 supplies
+//! the rest of the 802.11 stack. Each `ifconfig wlan0 create wlandev
+//! rustwl0` makes a vap, a virtual interface on the radio in one
+//! operating mode, and from then on the stack hands the driver frames to
+//! send, asks it to change channel while scanning, and takes the frames
+//! it receives by `Ic::receive`:
+//! ```ignore
+//! impl Radio for RustWl {
+//!     fn transmit(&self, _: &Ic, frame: Frame) -> Result<(), (Errno, Frame)> {
+//!         self.ring.push(frame)
+//!     }
+//!
+//!     fn set_channel(&self, _: &Ic, channel: Channel) {
+//!         self.tune(channel.freq());
+//!     }
+//! }
+//! ```
+//! with `MODULE_DEPEND(rustwl, wlan, 1, 1, 1)` in the C glue. Frames are
+//! whole 802.11 frames, headers included, in both directions; a driver
+//! completes each one it was given with `Frame::complete` once the
+//! hardware is done with it.
+
+use super::Mbuf;
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::sync::Epoch;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::ptr::{self, NonNull};
+use core::{fmt, mem};
+use libc::{c_char, c_int, c_void};
+
+/// `IEEE80211_C_*` radio capabilities for `Com::attach`, from
+/// `net80211/ieee80211_var.h`
+pub mod caps {
+    /// Station mode
+    pub const STA: u32 = 0x0000_0001;
+    pub const IBSS: u32 = 0x0000_0100;
+    pub const PMGT: u32 = 0x0000_0200;
+    /// Access point mode
+    pub const HOSTAP: u32 = 0x0000_0400;
+    pub const AHDEMO: u32 = 0x0000_0800;
+    pub const SHSLOT: u32 = 0x0000_4000;
+    pub const SHPREAMBLE: u32 = 0x0000_8000;
+    /// Passive monitor mode, every frame heard goes up
+    pub const MONITOR: u32 = 0x0001_0000;
+    pub const MBSS: u32 = 0x0004_0000;
+    pub const WPA1: u32 = 0x0080_0000;
+    pub const WPA2: u32 = 0x0100_0000;
+    pub const WPA: u32 = WPA1 | WPA2;
+    pub const WME: u32 = 0x0400_0000;
+    pub const WDS: u32 = 0x0800_0000;
+    /// The stack may scan in the background while associated
+    pub const BGSCAN: u32 = 0x2000_0000;
+}
+
+/// `IEEE80211_CHAN_*` bits of `Channel::flags`
+pub mod chan {
+    pub const TURBO: u32 = 0x0000_0010;
+    pub const CCK: u32 = 0x0000_0020;
+    pub const OFDM: u32 = 0x0000_0040;
+    pub const GHZ_2: u32 = 0x0000_0080;
+    pub const GHZ_5: u32 = 0x0000_0100;
+    pub const PASSIVE: u32 = 0x0000_0200;
+    pub const DYN: u32 = 0x0000_0400;
+    pub const GFSK: u32 = 0x0000_0800;
+}
+
+/// PHY modes, `IEEE80211_MODE_*`, whose channels a radio supports
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    A,
+    B,
+    G,
+}
+
+impl Mode {
+    fn as_raw(self) -> kernel_sys::ieee80211_phymode {
+        match self {
+            Mode::A => kernel_sys::ieee80211_phymode_IEEE80211_MODE_11A,
+            Mode::B => kernel_sys::ieee80211_phymode_IEEE80211_MODE_11B,
+            Mode::G => kernel_sys::ieee80211_phymode_IEEE80211_MODE_11G,
+        }
+    }
+}
+
+/// Operating modes of a vap, `IEEE80211_M_*`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OpMode {
+    Ibss,
+    Sta,
+    Wds,
+    AhDemo,
+    HostAp,
+    Monitor,
+    Mbss,
+}
+
+impl OpMode {
+    fn from_raw(m: kernel_sys::ieee80211_opmode) -> Option<Self> {
+        Some(match m {
+            kernel_sys::ieee80211_opmode_IEEE80211_M_IBSS => OpMode::Ibss,
+            kernel_sys::ieee80211_opmode_IEEE80211_M_STA => OpMode::Sta,
+            kernel_sys::ieee80211_opmode_IEEE80211_M_WDS => OpMode::Wds,
+            kernel_sys::ieee80211_opmode_IEEE80211_M_AHDEMO => OpMode::AhDemo,
+            kernel_sys::ieee80211_opmode_IEEE80211_M_HOSTAP => OpMode::HostAp,
+            kernel_sys::ieee80211_opmode_IEEE80211_M_MONITOR => OpMode::Monitor,
+            kernel_sys::ieee80211_opmode_IEEE80211_M_MBSS => OpMode::Mbss,
+            _ => return None,
+        })
+    }
+}
+
+/// States of a vap's 802.11 state machine, `IEEE80211_S_*`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    Init,
+    Scan,
+    Auth,
+    Assoc,
+    Capture,
+    Run,
+    /// Channel switch announced
+    Csa,
+    Sleep,
+}
+
+impl State {
+    fn from_raw(s: kernel_sys::ieee80211_state) -> Option<Self> {
+        Some(match s {
+            kernel_sys::ieee80211_state_IEEE80211_S_INIT => State::Init,
+            kernel_sys::ieee80211_state_IEEE80211_S_SCAN => State::Scan,
+            kernel_sys::ieee80211_state_IEEE80211_S_AUTH => State::Auth,
+            kernel_sys::ieee80211_state_IEEE80211_S_ASSOC => State::Assoc,
+            kernel_sys::ieee80211_state_IEEE80211_S_CAPTURE => State::Capture,
+            kernel_sys::ieee80211_state_IEEE80211_S_RUN => State::Run,
+            kernel_sys::ieee80211_state_IEEE80211_S_CSA => State::Csa,
+            kernel_sys::ieee80211_state_IEEE80211_S_SLEEP => State::Sleep,
+            _ => return None,
+        })
+    }
+}
+
+/// A channel, `struct ieee80211_channel`
+#[derive(Copy, Clone)]
+pub struct Channel {
+    c: kernel_sys::ieee80211_channel,
+}
+
+impl Channel {
+    /// Centre frequency in MHz
+    pub fn freq(&self) -> u16 {
+        self.c.ic_freq
+    }
+
+    /// IEEE channel number
+    pub fn number(&self) -> u8 {
+        self.c.ic_ieee
+    }
+
+    /// `chan` bits
+    pub fn flags(&self) -> u32 {
+        self.c.ic_flags
+    }
+
+    pub fn is_2ghz(&self) -> bool {
+        self.flags() & chan::GHZ_2 != 0
+    }
+
+    pub fn is_5ghz(&self) -> bool {
+        self.flags() & chan::GHZ_5 != 0
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Channel {{ number: {}, freq: {}, flags: {:#x} }}",
+            self.number(),
+            self.freq(),
+            self.flags()
+        )
+    }
+}
+
+/// What net80211 asks of a radio. The callbacks run from the stack's
+/// taskqueue or with its lock held, and must not sleep
+pub trait Radio: Send + Sync + 'static {
+    /// Queue `frame` for sending, or hand it back with the reason it
+    /// can't be, such as `Errno::NoBufs` for a full ring
+    fn transmit(&self, ic: &Ic, frame: Frame) -> Result<(), (Errno, Frame)>;
+
+    /// Tune to `channel`, which is now `Ic::current_channel`
+    fn set_channel(&self, ic: &Ic, channel: Channel);
+
+    /// A scan is starting; a radio might stop filtering on its BSSID
+    fn scan_start(&self, _ic: &Ic) {}
+
+    fn scan_end(&self, _ic: &Ic) {}
+
+    /// A vap went up or down; `Ic::is_running` tells whether the radio
+    /// should be powered
+    fn parent(&self, _ic: &Ic) {}
+
+    /// The multicast list or promiscuous mode changed
+    fn update_filter(&self, _ic: &Ic) {}
+
+    /// Refuse vaps in modes the radio can't do beside those it has
+    fn create_vap(&self, _ic: &Ic, _opmode: OpMode) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// `vap` is moving to `state`, before net80211 acts on it. An error
+    /// stops the transition
+    fn new_state(&self, _vap: &Vap, _state: State) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// A radio's `struct ieee80211com`, shared by all its vaps
+#[repr(transparent)]
+pub struct Ic(UnsafeCell<kernel_sys::ieee80211com>);
+
+unsafe impl Sync for Ic {}
+
+impl Ic {
+    /// ## Safety
+    /// `ic` must be attached for as long as the reference is used
+    unsafe fn from_raw<'a>(ic: *mut kernel_sys::ieee80211com) -> &'a Ic {
+        unsafe { &*(ic as *const Ic) }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::ieee80211com {
+        self.0.get()
+    }
+
+    pub fn macaddr(&self) -> [u8; 6] {
+        unsafe { (*self.as_ptr()).ic_macaddr }
+    }
+
+    pub fn current_channel(&self) -> Channel {
+        Channel {
+            c: unsafe { *(*self.as_ptr()).ic_curchan },
+        }
+    }
+
+    /// Whether any vap is up
+    pub fn is_running(&self) -> bool {
+        unsafe { (*self.as_ptr()).ic_nrunning > 0 }
+    }
+
+    /// Pass a received frame up, to the vap of the node that sent it or
+    /// to every vap if it's from no known node. `rssi` is relative to
+    /// `noise`, both in dBm
+    pub fn receive(&self, frame: Mbuf, rssi: i32, noise: i32) {
+        let ic = self.as_ptr();
+        let m = frame.into_raw();
+        Epoch::net().read(|_| unsafe {
+            let ni = if (*m).m_len as usize
+                >= mem::size_of::<kernel_sys::ieee80211_frame_min>()
+            {
+                kernel_sys::ieee80211_find_rxnode(ic, (*m).m_data.cast())
+            } else {
+                ptr::null_mut()
+            };
+            if ni.is_null() {
+                kernel_sys::ieee80211_input_all(ic, m, rssi, noise);
+            } else {
+                // ieee80211_input(), a macro
+                let input = (*(*ni).ni_vap).iv_input.unwrap();
+                input(ni, m, ptr::null(), rssi, noise);
+                kernel_sys::ieee80211_free_node(ni);
+            }
+        });
+    }
+
+    /// Run `f` on each node in the station table, the peers the vaps
+    /// know of
+    pub fn for_each_node<F: FnMut(&Node)>(&self, mut f: F) {
+        unsafe extern "C" fn visit<F: FnMut(&Node)>(
+            arg: *mut c_void,
+            ni: *mut kernel_sys::ieee80211_node,
+        ) {
+            let f = unsafe { &mut *(arg as *mut F) };
+            let node = mem::ManuallyDrop::new(Node {
+                ni: NonNull::new(ni).unwrap(),
+            });
+            f(&node);
+        }
+        unsafe {
+            kernel_sys::ieee80211_iterate_nodes(
+                &mut (*self.as_ptr()).ic_sta,
+                Some(visit::<F>),
+                &mut f as *mut F as *mut c_void,
+            )
+        };
+    }
+}
+
+/// A reference to a node, a peer station or the BSS a vap joined.
+/// Released when dropped
+pub struct Node {
+    ni: NonNull<kernel_sys::ieee80211_node>,
+}
+
+unsafe impl Send for Node {}
+
+impl Node {
+    pub fn as_ptr(&self) -> *mut kernel_sys::ieee80211_node {
+        self.ni.as_ptr()
+    }
+
+    pub fn macaddr(&self) -> [u8; 6] {
+        unsafe { (*self.as_ptr()).ni_macaddr }
+    }
+
+    pub fn bssid(&self) -> [u8; 6] {
+        unsafe { (*self.as_ptr()).ni_bssid }
+    }
+
+    /// Association ID, 0 if not associated
+    pub fn associd(&self) -> u16 {
+        unsafe { (*self.as_ptr()).ni_associd }
+    }
+
+    /// Average RSSI of frames from the node
+    pub fn rssi(&self) -> i8 {
+        let ni = self.as_ptr();
+        unsafe { (*(*ni).ni_ic).ic_node_getrssi.map_or(0, |f| f(ni)) }
+    }
+
+    pub fn channel(&self) -> Channel {
+        Channel {
+            c: unsafe { *(*self.as_ptr()).ni_chan },
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::ieee80211_free_node(self.as_ptr()) };
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Node {{ macaddr: {:02x?}, associd: {} }}",
+            self.macaddr(),
+            self.associd()
+        )
+    }
+}
+
+/// A frame to send and the node it's for. Dropping it frees both without
+/// counting the send as done or failed
+#[derive(Debug)]
+pub struct Frame {
+    node: Node,
+    m: Mbuf,
+}
+
+impl Frame {
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    /// The 802.11 frame, header first
+    pub fn mbuf(&self) -> &Mbuf {
+        &self.m
+    }
+
+    /// Report the send done, successfully or not, freeing the frame
+    pub fn complete(self, ok: bool) {
+        let (ni, m) = self.into_raw();
+        unsafe { kernel_sys::ieee80211_tx_complete(ni, m, !ok as c_int) };
+    }
+
+    fn into_raw(
+        self,
+    ) -> (*mut kernel_sys::ieee80211_node, *mut kernel_sys::mbuf) {
+        let Frame { node, m } = self;
+        let ni = node.as_ptr();
+        mem::forget(node);
+        (ni, m.into_raw())
+    }
+}
+
+/// A vap, given to `Radio::new_state`
+pub struct Vap {
+    vap: NonNull<kernel_sys::ieee80211vap>,
+}
+
+impl Vap {
+    pub fn as_ptr(&self) -> *mut kernel_sys::ieee80211vap {
+        self.vap.as_ptr()
+    }
+
+    pub fn ic(&self) -> &Ic {
+        unsafe { Ic::from_raw((*self.as_ptr()).iv_ic) }
+    }
+
+    pub fn opmode(&self) -> Option<OpMode> {
+        OpMode::from_raw(unsafe { (*self.as_ptr()).iv_opmode })
+    }
+
+    /// The state being left
+    pub fn state(&self) -> Option<State> {
+        State::from_raw(unsafe { (*self.as_ptr()).iv_state })
+    }
+
+    /// The vap's own address
+    pub fn macaddr(&self) -> [u8; 6] {
+        unsafe { (*self.as_ptr()).iv_myaddr }
+    }
+
+    /// The BSSID of the BSS the vap is in, or is joining
+    pub fn bssid(&self) -> [u8; 6] {
+        unsafe {
+            let bss = (*self.as_ptr()).iv_bss;
+            if bss.is_null() {
+                [0; 6]
+            } else {
+                (*bss).ni_bssid
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Vap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Vap {{ opmode: {:?}, state: {:?} }}",
+            self.opmode(),
+            self.state()
+        )
+    }
+}
+
+#[repr(C)]
+struct Inner<T> {
+    ic: Ic,
+    radio: T,
+}
+
+/// Vaps as allocated by `vap_create`, with net80211's own state handler
+/// to chain to
+#[repr(C)]
+struct RawVap {
+    vap: kernel_sys::ieee80211vap,
+    newstate: Option<
+        unsafe extern "C" fn(
+            *mut kernel_sys::ieee80211vap,
+            kernel_sys::ieee80211_state,
+            c_int,
+        ) -> c_int,
+    >,
+}
+
+/// A radio attached to net80211. Detached, destroying its vaps, when
+/// dropped
+pub struct Com<T: Radio> {
+    inner: NonNull<Inner<T>>,
+}
+
+unsafe impl<T: Radio> Send for Com<T> {}
+unsafe impl<T: Radio> Sync for Com<T> {}
+
+impl<T: Radio> Com<T> {
+    /// Attach a radio named `name`, such as the driver's device nameunit,
+    /// with the channels of `modes` allowed
+    pub fn attach(
+        name: &'static CStr,
+        macaddr: [u8; 6],
+        caps: u32,
+        modes: &[Mode],
+        radio: T,
+    ) -> Result<Self, Errno> {
+        // ieee80211com holds the whole channel table, far too much for
+        // the kernel stack
+        let inner =
+            Box::into_raw(Box::<Inner<T>>::new_uninit()).cast::<Inner<T>>();
+        let ic = unsafe {
+            ptr::write_bytes(&raw mut (*inner).ic, 0, 1);
+            (&raw mut (*inner).radio).write(radio);
+            (*inner).ic.as_ptr()
+        };
+        let mut bands = [0u8;
+            (kernel_sys::ieee80211_phymode_IEEE80211_MODE_MAX as usize)
+                .div_ceil(8)];
+        for mode in modes {
+            let m = mode.as_raw() as usize;
+            bands[m / 8] |= 1 << (m % 8);
+        }
+        unsafe {
+            (*ic).ic_softc = inner as *mut c_void;
+            (*ic).ic_name = name.as_ptr();
+            (*ic).ic_phytype = kernel_sys::ieee80211_phytype_IEEE80211_T_OFDM;
+            (*ic).ic_opmode = kernel_sys::ieee80211_opmode_IEEE80211_M_STA;
+            (*ic).ic_caps = caps;
+            (*ic).ic_macaddr = macaddr;
+            let err = kernel_sys::ieee80211_init_channels(
+                ic,
+                ptr::null(),
+                bands.as_ptr(),
+            );
+            if let Err(e) = Errno::result(err) {
+                drop(Box::from_raw(inner));
+                return Err(e);
+            }
+            kernel_sys::ieee80211_ifattach(ic);
+            // after ieee80211_ifattach, which fills in its defaults
+            (*ic).ic_vap_create = Some(vap_create::<T>);
+            (*ic).ic_vap_delete = Some(vap_delete);
+            (*ic).ic_transmit = Some(transmit::<T>);
+            (*ic).ic_raw_xmit = Some(raw_xmit::<T>);
+            (*ic).ic_parent = Some(parent::<T>);
+            (*ic).ic_scan_start = Some(scan_start::<T>);
+            (*ic).ic_scan_end = Some(scan_end::<T>);
+            (*ic).ic_set_channel = Some(set_channel::<T>);
+            (*ic).ic_update_mcast = Some(update_filter::<T>);
+            (*ic).ic_update_promisc = Some(update_filter::<T>);
+        }
+        Ok(Com {
+            inner: NonNull::new(inner).unwrap(),
+        })
+    }
+
+    pub fn ic(&self) -> &Ic {
+        unsafe { &self.inner.as_ref().ic }
+    }
+
+    pub fn radio(&self) -> &T {
+        unsafe { &self.inner.as_ref().radio }
+    }
+}
+
+impl<T: Radio> Drop for Com<T> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::ieee80211_ifdetach(self.ic().as_ptr());
+            drop(Box::from_raw(self.inner.as_ptr()));
+        }
+    }
+}
+
+impl<T: Radio> fmt::Debug for Com<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Com {{ macaddr: {:02x?} }}", self.ic().macaddr())
+    }
+}
+
+/// ## Safety
+/// `ic` must be attached by a `Com<T>`
+unsafe fn inner<'a, T>(ic: *mut kernel_sys::ieee80211com) -> &'a Inner<T> {
+    unsafe { &*(ic as *const Inner<T>) }
+}
+
+unsafe extern "C" fn vap_create<T: Radio>(
+    ic: *mut kernel_sys::ieee80211com,
+    name: *const c_char,
+    unit: c_int,
+    opmode: kernel_sys::ieee80211_opmode,
+    flags: c_int,
+    bssid: *const u8,
+    mac: *const u8,
+) -> *mut kernel_sys::ieee80211vap {
+    let t = unsafe { inner::<T>(ic) };
+    let Some(mode) = OpMode::from_raw(opmode) else {
+        return ptr::null_mut();
+    };
+    if catch_in_module(|| t.radio.create_vap(&t.ic, mode))
+        .and_then(|r| r)
+        .is_err()
+    {
+        return ptr::null_mut();
+    }
+    let raw = Box::into_raw(Box::<RawVap>::new_uninit()).cast::<RawVap>();
+    unsafe {
+        // net80211 expects the vap zeroed
+        ptr::write_bytes(raw, 0, 1);
+        let vap = &raw mut (*raw).vap;
+        let err = kernel_sys::ieee80211_vap_setup(
+            ic, vap, name, unit, opmode, flags, bssid,
+        );
+        if err != 0 {
+            drop(Box::from_raw(raw));
+            return ptr::null_mut();
+        }
+        (*raw).newstate = (*vap).iv_newstate;
+        (*vap).iv_newstate = Some(new_state::<T>);
+        kernel_sys::ieee80211_vap_attach(
+            vap,
+            Some(kernel_sys::ieee80211_media_change),
+            Some(kernel_sys::ieee80211_media_status),
+            mac,
+        );
+        (*ic).ic_opmode = opmode;
+        vap
+    }
+}
+
+unsafe extern "C" fn vap_delete(vap: *mut kernel_sys::ieee80211vap) {
+    unsafe {
+        kernel_sys::ieee80211_vap_detach(vap);
+        drop(Box::from_raw(vap as *mut RawVap));
+    }
+}
+
+unsafe extern "C" fn new_state<T: Radio>(
+    vap: *mut kernel_sys::ieee80211vap,
+    nstate: kernel_sys::ieee80211_state,
+    arg: c_int,
+) -> c_int {
+    let t = unsafe { inner::<T>((*vap).iv_ic) };
+    if let Some(state) = State::from_raw(nstate) {
+        let v = Vap {
+            vap: NonNull::new(vap).unwrap(),
+        };
+        if let Err(e) =
+            catch_in_module(|| t.radio.new_state(&v, state)).and_then(|r| r)
+        {
+            return e.as_raw();
+        }
+    }
+    match unsafe { (*(vap as *mut RawVap)).newstate } {
+        Some(newstate) => unsafe { newstate(vap, nstate, arg) },
+        None => 0,
+    }
+}
+
+/// net80211 points the packet header's `rcvif` at the node a frame is
+/// for
+unsafe fn tx_node(m: *mut kernel_sys::mbuf) -> *mut kernel_sys::ieee80211_node {
+    unsafe {
+        (*m).__bindgen_anon_3
+            .__bindgen_anon_1
+            .__bindgen_anon_1
+            .m_pkthdr
+            .__bindgen_anon_1
+            .rcvif
+            .cast()
+    }
+}
+
+/// `ic_transmit`: on failure net80211 frees the frame and the node
+unsafe extern "C" fn transmit<T: Radio>(
+    ic: *mut kernel_sys::ieee80211com,
+    m: *mut kernel_sys::mbuf,
+) -> c_int {
+    let t = unsafe { inner::<T>(ic) };
+    let frame = Frame {
+        node: Node {
+            ni: NonNull::new(unsafe { tx_node(m) }).unwrap(),
+        },
+        m: unsafe { Mbuf::from_raw(m) },
+    };
+    match catch_in_module(|| t.radio.transmit(&t.ic, frame)) {
+        Ok(Ok(())) => 0,
+        Ok(Err((e, frame))) => {
+            frame.into_raw();
+            e.as_raw()
+        }
+        Err(e) => e.as_raw(),
+    }
+}
+
+/// `ic_raw_xmit`, for management frames and those injected through bpf:
+/// on failure net80211 frees only the node
+unsafe extern "C" fn raw_xmit<T: Radio>(
+    ni: *mut kernel_sys::ieee80211_node,
+    m: *mut kernel_sys::mbuf,
+    _params: *const kernel_sys::ieee80211_bpf_params,
+) -> c_int {
+    let t = unsafe { inner::<T>((*ni).ni_ic) };
+    let frame = Frame {
+        node: Node {
+            ni: NonNull::new(ni).unwrap(),
+        },
+        m: unsafe { Mbuf::from_raw(m) },
+    };
+    match catch_in_module(|| t.radio.transmit(&t.ic, frame)) {
+        Ok(Ok(())) => 0,
+        Ok(Err((e, frame))) => {
+            let (_, m) = frame.into_raw();
+            drop(unsafe { Mbuf::from_raw(m) });
+            e.as_raw()
+        }
+        Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn parent<T: Radio>(ic: *mut kernel_sys::ieee80211com) {
+    let t = unsafe { inner::<T>(ic) };
+    let _ = catch_in_module(|| t.radio.parent(&t.ic));
+}
+
+unsafe extern "C" fn scan_start<T: Radio>(ic: *mut kernel_sys::ieee80211com) {
+    let t = unsafe { inner::<T>(ic) };
+    let _ = catch_in_module(|| t.radio.scan_start(&t.ic));
+}
+
+unsafe extern "C" fn scan_end<T: Radio>(ic: *mut kernel_sys::ieee80211com) {
+    let t = unsafe { inner::<T>(ic) };
+    let _ = catch_in_module(|| t.radio.scan_end(&t.ic));
+}
+
+unsafe extern "C" fn set_channel<T: Radio>(ic: *mut kernel_sys::ieee80211com) {
+    let t = unsafe { inner::<T>(ic) };
+    let channel = t.ic.current_channel();
+    let _ = catch_in_module(|| t.radio.set_channel(&t.ic, channel));
+}
+
+unsafe extern "C" fn update_filter<T: Radio>(
+    ic: *mut kernel_sys::ieee80211com,
+) {
+    let t = unsafe { inner::<T>(ic) };
+    let _ = catch_in_module(|| t.radio.update_filter(&t.ic));
+}
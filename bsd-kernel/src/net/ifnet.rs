@@ -0,0 +1,591 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Network interfaces, see `ifnet(9)`
+//!
+//! A driver implements `Interface` and attaches it as an Ethernet
+//! interface with `Ether::attach`. The stack then hands it each packet to
+//! send, and it passes up those it receives with `Ifnet::input`:
+//! ```rust,ignore
+//! impl Interface for RustNic {
+//!     fn transmit(&self, ifp: &Ifnet, m: Mbuf) -> Result<(), Errno> {
+//!         ifp.bpf_mtap(&m);
+//!         self.ring.push(m)
+//!     }
+//! }
+//!
+//! let ether = Ether::attach(c"rustnic", unit, macaddr, RustNic::new())?;
+//! ether.ifnet().set_link_state(LinkState::Up);
+//! ```
+//! Interfaces made with `ifconfig rustnic0 create` rather than found on a
+//! bus implement `Clonable` too, and are created and destroyed through the
+//! `Cloner` the module attaches on load.
+
+use super::Mbuf;
+use super::vnet::Vnet;
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+use core::{fmt, mem};
+use libc::{c_char, c_int, c_ulong, c_void};
+
+/// `IFF_*` interface flags, from `net/if.h`
+pub mod flags {
+    pub const UP: u32 = 0x1;
+    pub const BROADCAST: u32 = 0x2;
+    pub const LOOPBACK: u32 = 0x8;
+    pub const POINTOPOINT: u32 = 0x10;
+    pub const PROMISC: u32 = 0x100;
+    pub const ALLMULTI: u32 = 0x200;
+    /// Can't hear its own transmissions
+    pub const SIMPLEX: u32 = 0x800;
+    pub const MULTICAST: u32 = 0x8000;
+}
+
+/// `IFCAP_*` capabilities, from `net/if.h`
+pub mod caps {
+    pub const RXCSUM: u32 = 0x0000_0001;
+    pub const TXCSUM: u32 = 0x0000_0002;
+    pub const HWCSUM: u32 = RXCSUM | TXCSUM;
+    pub const VLAN_MTU: u32 = 0x0000_0008;
+    pub const VLAN_HWTAGGING: u32 = 0x0000_0010;
+    pub const JUMBO_MTU: u32 = 0x0000_0020;
+    pub const TSO4: u32 = 0x0000_0100;
+    pub const TSO6: u32 = 0x0000_0200;
+    pub const LRO: u32 = 0x0000_0400;
+    /// Reports link state changes
+    pub const LINKSTATE: u32 = 0x0008_0000;
+}
+
+/// `IFF_DRV_RUNNING`
+const IFF_DRV_RUNNING: c_int = 0x40;
+
+/// `IFT_ETHER`
+const IFT_ETHER: u8 = 0x6;
+
+/// `ETHERMTU`
+pub const ETHERMTU: u32 = 1500;
+
+// The `SIOC*` commands the stack hands drivers, from `sys/sockio.h`
+const IFREQ: usize = mem::size_of::<kernel_sys::ifreq>();
+const SIOCSIFFLAGS: c_ulong = crate::ioctl::iow(b'i', 16, IFREQ);
+const SIOCSIFCAP: c_ulong = crate::ioctl::iow(b'i', 30, IFREQ);
+const SIOCADDMULTI: c_ulong = crate::ioctl::iow(b'i', 49, IFREQ);
+const SIOCDELMULTI: c_ulong = crate::ioctl::iow(b'i', 50, IFREQ);
+const SIOCSIFMTU: c_ulong = crate::ioctl::iow(b'i', 52, IFREQ);
+
+/// Whether the link is up, `LINK_STATE_*`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LinkState {
+    Unknown,
+    Down,
+    Up,
+}
+
+impl LinkState {
+    fn as_raw(self) -> c_int {
+        match self {
+            LinkState::Unknown => 0,
+            LinkState::Down => 1,
+            LinkState::Up => 2,
+        }
+    }
+}
+
+/// The statistics `netstat -i` shows, `IFCOUNTER_*`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Counter {
+    InPackets,
+    InErrors,
+    OutPackets,
+    OutErrors,
+    Collisions,
+    InBytes,
+    OutBytes,
+    InMulticasts,
+    OutMulticasts,
+    InQueueDrops,
+    OutQueueDrops,
+    NoProto,
+}
+
+impl Counter {
+    fn as_raw(self) -> kernel_sys::ift_counter {
+        match self {
+            Counter::InPackets => kernel_sys::ift_counter_IFCOUNTER_IPACKETS,
+            Counter::InErrors => kernel_sys::ift_counter_IFCOUNTER_IERRORS,
+            Counter::OutPackets => kernel_sys::ift_counter_IFCOUNTER_OPACKETS,
+            Counter::OutErrors => kernel_sys::ift_counter_IFCOUNTER_OERRORS,
+            Counter::Collisions => kernel_sys::ift_counter_IFCOUNTER_COLLISIONS,
+            Counter::InBytes => kernel_sys::ift_counter_IFCOUNTER_IBYTES,
+            Counter::OutBytes => kernel_sys::ift_counter_IFCOUNTER_OBYTES,
+            Counter::InMulticasts => kernel_sys::ift_counter_IFCOUNTER_IMCASTS,
+            Counter::OutMulticasts => kernel_sys::ift_counter_IFCOUNTER_OMCASTS,
+            Counter::InQueueDrops => kernel_sys::ift_counter_IFCOUNTER_IQDROPS,
+            Counter::OutQueueDrops => kernel_sys::ift_counter_IFCOUNTER_OQDROPS,
+            Counter::NoProto => kernel_sys::ift_counter_IFCOUNTER_NOPROTO,
+        }
+    }
+}
+
+/// An attached interface, `if_t`, as its callbacks see it
+pub struct Ifnet {
+    ifp: NonNull<kernel_sys::ifnet>,
+}
+
+unsafe impl Send for Ifnet {}
+unsafe impl Sync for Ifnet {}
+
+impl Ifnet {
+    /// ## Safety
+    /// `ifp` must stay attached for as long as the `Ifnet` is used
+    pub(crate) unsafe fn from_raw(ifp: kernel_sys::if_t) -> Option<Self> {
+        NonNull::new(ifp).map(|ifp| Ifnet { ifp })
+    }
+
+    pub fn as_ptr(&self) -> kernel_sys::if_t {
+        self.ifp.as_ptr()
+    }
+
+    /// Its name and unit, as in `rustnic0`
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(kernel_sys::if_name(self.as_ptr())) }
+    }
+
+    /// The network stack the interface is in
+    pub fn vnet(&self) -> Vnet {
+        unsafe { Vnet::from_raw(kernel_sys::if_getvnet(self.as_ptr())) }
+    }
+
+    /// `flags`
+    pub fn flags(&self) -> u32 {
+        unsafe { kernel_sys::if_getflags(self.as_ptr()) as u32 }
+    }
+
+    /// Whether the interface has been configured up
+    pub fn is_up(&self) -> bool {
+        self.flags() & flags::UP != 0
+    }
+
+    /// Whether the driver has marked it running, ready to transmit
+    pub fn is_running(&self) -> bool {
+        let drv = unsafe { kernel_sys::if_getdrvflags(self.as_ptr()) };
+        drv & IFF_DRV_RUNNING != 0
+    }
+
+    pub fn set_running(&self, running: bool) {
+        let (set, clear) = match running {
+            true => (IFF_DRV_RUNNING, 0),
+            false => (0, IFF_DRV_RUNNING),
+        };
+        unsafe { kernel_sys::if_setdrvflagbits(self.as_ptr(), set, clear) };
+    }
+
+    pub fn mtu(&self) -> u32 {
+        unsafe { kernel_sys::if_getmtu(self.as_ptr()) as u32 }
+    }
+
+    pub fn set_mtu(&self, mtu: u32) {
+        unsafe { kernel_sys::if_setmtu(self.as_ptr(), mtu as c_int) };
+    }
+
+    /// Line rate in bits per second
+    pub fn set_baudrate(&self, baudrate: u64) {
+        unsafe { kernel_sys::if_setbaudrate(self.as_ptr(), baudrate) };
+    }
+
+    /// Report a change of link state to the stack and `devd(8)`
+    pub fn set_link_state(&self, state: LinkState) {
+        unsafe {
+            kernel_sys::if_link_state_change(self.as_ptr(), state.as_raw())
+        };
+    }
+
+    /// `caps` the interface supports
+    pub fn capabilities(&self) -> u32 {
+        unsafe { kernel_sys::if_getcapabilities(self.as_ptr()) as u32 }
+    }
+
+    pub fn set_capabilities(&self, caps: u32) {
+        unsafe { kernel_sys::if_setcapabilities(self.as_ptr(), caps as c_int) };
+    }
+
+    /// `caps` enabled, of those supported
+    pub fn capenable(&self) -> u32 {
+        unsafe { kernel_sys::if_getcapenable(self.as_ptr()) as u32 }
+    }
+
+    pub fn set_capenable(&self, caps: u32) {
+        unsafe { kernel_sys::if_setcapenable(self.as_ptr(), caps as c_int) };
+    }
+
+    /// The Ethernet address, once attached
+    pub fn lladdr(&self) -> [u8; 6] {
+        let mut addr = [0; 6];
+        unsafe {
+            let lla = kernel_sys::if_getlladdr(self.as_ptr());
+            ptr::copy_nonoverlapping(lla as *const u8, addr.as_mut_ptr(), 6);
+        }
+        addr
+    }
+
+    pub fn inc_counter(&self, counter: Counter, n: i64) {
+        unsafe {
+            kernel_sys::if_inc_counter(self.as_ptr(), counter.as_raw(), n)
+        };
+    }
+
+    /// Show a packet to `bpf(4)` listeners, such as `tcpdump(1)`
+    pub fn bpf_mtap(&self, m: &Mbuf) {
+        unsafe { kernel_sys::bpf_mtap_if(self.as_ptr(), m.as_ptr()) };
+    }
+
+    /// Pass a received packet up the stack as having arrived here. The
+    /// stack enters the network epoch itself
+    pub fn input(&self, m: Mbuf) {
+        let m = m.into_raw();
+        unsafe {
+            (*m).__bindgen_anon_3
+                .__bindgen_anon_1
+                .__bindgen_anon_1
+                .m_pkthdr
+                .__bindgen_anon_1
+                .rcvif = self.as_ptr();
+            kernel_sys::if_input(self.as_ptr(), m);
+        }
+    }
+}
+
+impl fmt::Debug for Ifnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Ifnet {{ name: {:?}, flags: {:#x} }}",
+            self.name(),
+            self.flags()
+        )
+    }
+}
+
+/// An `ioctl` on the interface that no other `Interface` method handles
+pub struct Ioctl {
+    cmd: c_ulong,
+    data: *mut c_char,
+}
+
+impl Ioctl {
+    pub fn cmd(&self) -> c_ulong {
+        self.cmd
+    }
+
+    /// The argument, already copied in by `ifioctl()`
+    pub fn as_ptr(&self) -> *mut c_char {
+        self.data
+    }
+
+    /// Hand the command to `ether_ioctl()`, which sets addresses and
+    /// fails with `Errno::Inval` for anything it doesn't know
+    pub fn ether_default(&self, ifp: &Ifnet) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::ether_ioctl(ifp.as_ptr(), self.cmd, self.data)
+        })
+    }
+}
+
+impl fmt::Debug for Ioctl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ioctl {{ cmd: {:#x} }}", self.cmd)
+    }
+}
+
+/// What the stack asks of an interface. `transmit` may be called on many
+/// CPUs at once, and none of the callbacks may sleep
+pub trait Interface: Send + Sync + 'static {
+    /// Send `m`, or drop it with the reason it can't be sent, such as
+    /// `Errno::NoBufs` for a full ring
+    fn transmit(&self, ifp: &Ifnet, m: Mbuf) -> Result<(), Errno>;
+
+    /// Free anything queued to send
+    fn qflush(&self, _ifp: &Ifnet) {}
+
+    /// Bring the hardware up, when an address is first set
+    fn init(&self, ifp: &Ifnet) {
+        ifp.set_running(true);
+    }
+
+    /// `Ifnet::flags` changed, such as the interface going up or down or
+    /// into promiscuous mode
+    fn set_flags(&self, ifp: &Ifnet) -> Result<(), Errno> {
+        ifp.set_running(ifp.is_up());
+        Ok(())
+    }
+
+    /// Change the MTU to `mtu`, up to `ETHERMTU` unless jumbo frames are
+    /// supported
+    fn set_mtu(&self, ifp: &Ifnet, mtu: u32) -> Result<(), Errno> {
+        if !(72..=ETHERMTU).contains(&mtu) {
+            return Err(Errno::Inval);
+        }
+        ifp.set_mtu(mtu);
+        Ok(())
+    }
+
+    /// Enable the `caps` requested, of those supported
+    fn set_capenable(&self, ifp: &Ifnet, caps: u32) -> Result<(), Errno> {
+        ifp.set_capenable(caps & ifp.capabilities());
+        Ok(())
+    }
+
+    /// The multicast addresses to receive changed
+    fn update_multicast(&self, _ifp: &Ifnet) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Any other `ioctl`, `ether_ioctl()`'s by default
+    fn ioctl(&self, ifp: &Ifnet, req: &Ioctl) -> Result<(), Errno> {
+        req.ether_default(ifp)
+    }
+}
+
+struct Inner<T> {
+    ifp: Ifnet,
+    iface: T,
+}
+
+/// An Ethernet interface attached with `ether_ifattach()`. Detached and
+/// freed when dropped
+pub struct Ether<T: Interface> {
+    inner: NonNull<Inner<T>>,
+}
+
+unsafe impl<T: Interface> Send for Ether<T> {}
+unsafe impl<T: Interface> Sync for Ether<T> {}
+
+impl<T: Interface> Ether<T> {
+    /// Attach `iface` as `name` and `unit`, such as the driver's device
+    /// name and unit, with Ethernet address `lladdr`
+    pub fn attach(
+        name: &CStr,
+        unit: i32,
+        lladdr: [u8; 6],
+        iface: T,
+    ) -> Result<Self, Errno> {
+        let ifp = unsafe { kernel_sys::if_alloc(IFT_ETHER) };
+        let ifp = NonNull::new(ifp).ok_or(Errno::NoSpc)?;
+        let inner = Box::into_raw(Box::new(Inner {
+            ifp: Ifnet { ifp },
+            iface,
+        }));
+        let ifp = ifp.as_ptr();
+        unsafe {
+            kernel_sys::if_initname(ifp, name.as_ptr(), unit);
+            kernel_sys::if_setsoftc(ifp, inner as *mut c_void);
+            kernel_sys::if_setflags(
+                ifp,
+                (flags::BROADCAST | flags::SIMPLEX | flags::MULTICAST) as c_int,
+            );
+            kernel_sys::if_setinitfn(ifp, Some(init::<T>));
+            kernel_sys::if_setioctlfn(ifp, Some(ioctl::<T>));
+            kernel_sys::if_settransmitfn(ifp, Some(transmit::<T>));
+            kernel_sys::if_setqflushfn(ifp, Some(qflush::<T>));
+            kernel_sys::ether_ifattach(ifp, lladdr.as_ptr());
+        }
+        Ok(Ether {
+            inner: NonNull::new(inner).unwrap(),
+        })
+    }
+
+    pub fn ifnet(&self) -> &Ifnet {
+        unsafe { &self.inner.as_ref().ifp }
+    }
+
+    pub fn iface(&self) -> &T {
+        unsafe { &self.inner.as_ref().iface }
+    }
+
+    /// Give up ownership, for the stack to destroy it later
+    fn into_raw(self) -> kernel_sys::if_t {
+        let ifp = self.ifnet().as_ptr();
+        mem::forget(self);
+        ifp
+    }
+
+    /// ## Safety
+    /// `ifp` must have been attached by an `Ether<T>`, given up with
+    /// `into_raw`
+    unsafe fn from_raw(ifp: kernel_sys::if_t) -> Self {
+        let inner = unsafe { kernel_sys::if_getsoftc(ifp) } as *mut Inner<T>;
+        Ether {
+            inner: NonNull::new(inner).unwrap(),
+        }
+    }
+}
+
+impl<T: Interface> Drop for Ether<T> {
+    fn drop(&mut self) {
+        let ifp = self.ifnet().as_ptr();
+        unsafe {
+            kernel_sys::ether_ifdetach(ifp);
+            kernel_sys::if_free(ifp);
+            drop(Box::from_raw(self.inner.as_ptr()));
+        }
+    }
+}
+
+impl<T: Interface> fmt::Debug for Ether<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ether {{ ifnet: {:?} }}", self.ifnet())
+    }
+}
+
+/// ## Safety
+/// `ifp` must be attached by an `Ether<T>`
+unsafe fn inner<'a, T>(ifp: kernel_sys::if_t) -> &'a Inner<T> {
+    unsafe { &*(kernel_sys::if_getsoftc(ifp) as *const Inner<T>) }
+}
+
+/// `if_init`, given the softc
+unsafe extern "C" fn init<T: Interface>(arg: *mut c_void) {
+    let t = unsafe { &*(arg as *const Inner<T>) };
+    let _ = catch_in_module(|| t.iface.init(&t.ifp));
+}
+
+/// `if_transmit`: the driver frees the packet if it can't send it
+unsafe extern "C" fn transmit<T: Interface>(
+    ifp: kernel_sys::if_t,
+    m: *mut kernel_sys::mbuf,
+) -> c_int {
+    let t = unsafe { inner::<T>(ifp) };
+    let m = unsafe { Mbuf::from_raw(m) };
+    match catch_in_module(|| t.iface.transmit(&t.ifp, m)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn qflush<T: Interface>(ifp: kernel_sys::if_t) {
+    let t = unsafe { inner::<T>(ifp) };
+    let _ = catch_in_module(|| t.iface.qflush(&t.ifp));
+}
+
+unsafe extern "C" fn ioctl<T: Interface>(
+    ifp: kernel_sys::if_t,
+    cmd: c_ulong,
+    data: *mut c_char,
+) -> c_int {
+    let t = unsafe { inner::<T>(ifp) };
+    let ifr = data as *mut kernel_sys::ifreq;
+    let result = catch_in_module(|| match cmd {
+        SIOCSIFFLAGS => t.iface.set_flags(&t.ifp),
+        SIOCSIFMTU => {
+            let mtu = unsafe { (*ifr).ifr_ifru.ifru_mtu };
+            t.iface.set_mtu(&t.ifp, mtu as u32)
+        }
+        SIOCSIFCAP => {
+            // ifr_reqcap
+            let caps = unsafe { (*ifr).ifr_ifru.ifru_cap[0] };
+            t.iface.set_capenable(&t.ifp, caps as u32)
+        }
+        SIOCADDMULTI | SIOCDELMULTI => t.iface.update_multicast(&t.ifp),
+        _ => t.iface.ioctl(&t.ifp, &Ioctl { cmd, data }),
+    });
+    match result {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+/// An interface made by `ifconfig name create`
+pub trait Clonable: Interface + Sized {
+    /// The driver name, that units are named after
+    const NAME: &'static CStr;
+
+    /// Make unit `unit` and choose its Ethernet address
+    fn create(unit: i32) -> Result<(Self, [u8; 6]), Errno>;
+}
+
+/// `T::NAME` registered with `if_clone_simple()`, so that `ifconfig` can
+/// create and destroy its interfaces. Destroys those left when dropped
+pub struct Cloner<T: Clonable> {
+    ifc: NonNull<kernel_sys::if_clone>,
+    _iface: PhantomData<T>,
+}
+
+unsafe impl<T: Clonable> Send for Cloner<T> {}
+unsafe impl<T: Clonable> Sync for Cloner<T> {}
+
+impl<T: Clonable> Cloner<T> {
+    /// Register the cloner, and create `min` interfaces right away, as
+    /// `lo0` is
+    pub fn attach(min: u32) -> Result<Self, Errno> {
+        let ifc = unsafe {
+            kernel_sys::if_clone_simple(
+                T::NAME.as_ptr(),
+                Some(clone_create::<T>),
+                Some(clone_destroy::<T>),
+                min as c_int,
+            )
+        };
+        Ok(Cloner {
+            ifc: NonNull::new(ifc).ok_or(Errno::NoMem)?,
+            _iface: PhantomData,
+        })
+    }
+}
+
+impl<T: Clonable> Drop for Cloner<T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::if_clone_detach(self.ifc.as_ptr()) };
+    }
+}
+
+impl<T: Clonable> fmt::Debug for Cloner<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cloner {{ name: {:?} }}", T::NAME)
+    }
+}
+
+unsafe extern "C" fn clone_create<T: Clonable>(
+    _ifc: *mut kernel_sys::if_clone,
+    unit: c_int,
+    _params: *mut c_char,
+) -> c_int {
+    let result = catch_in_module(|| {
+        let (iface, lladdr) = T::create(unit)?;
+        Ether::attach(T::NAME, unit, lladdr, iface)
+    });
+    match result {
+        Ok(Ok(ether)) => {
+            ether.into_raw();
+            0
+        }
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn clone_destroy<T: Clonable>(ifp: kernel_sys::if_t) {
+    drop(unsafe { Ether::<T>::from_raw(ifp) });
+}
@@ -0,0 +1,529 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::errno::Errno;
+use crate::io::{self, Read, Write};
+use core::marker::PhantomData;
+use core::{fmt, mem, ptr, slice};
+use libc::{c_char, c_int};
+
+/// `M_NOWAIT`: mbufs are allocated without sleeping, failing instead
+const M_NOWAIT: c_int = 0x0001;
+
+/// `MT_DATA`
+const MT_DATA: i16 = 1;
+
+/// `m_flags` bits, from `sys/mbuf.h`
+const M_EXT: u32 = 0x0000_0001;
+const M_PKTHDR: u32 = 0x0000_0002;
+const M_RDONLY: u32 = 0x0000_0008;
+const M_EXTPG: u32 = 0x0000_0100;
+
+/// `MSIZE`
+const MSIZE: usize = 256;
+
+/// `MHLEN`, the data an mbuf with a packet header holds without a
+/// cluster, and the most `Mbuf::pullup` and `Mbuf::prepend` can make
+/// contiguous
+pub const MHLEN: usize = MSIZE
+    - mem::offset_of!(kernel_sys::mbuf, __bindgen_anon_3)
+    - mem::size_of::<kernel_sys::pkthdr>();
+
+/// The most `Mbuf::new` holds in one buffer, `MJUMPAGESIZE`
+pub const MJUMPAGESIZE: usize = kernel_sys::PAGE_SIZE as usize;
+
+/// An owned mbuf chain holding one packet, see `mbuf(9)`. Freed with the
+/// whole chain when dropped
+///
+/// The data is contiguous only within each mbuf of the chain: `pullup`
+/// makes a header contiguous to be read through `data`, while `reader`
+/// and `copy_to` read across the chain and `copy_back` writes across it.
+/// All allocation is without sleeping.
+pub struct Mbuf {
+    m: ptr::NonNull<kernel_sys::mbuf>,
+}
+
+unsafe impl Send for Mbuf {}
+
+impl Mbuf {
+    /// Take ownership of a raw chain
+    ///
+    /// ## Safety
+    /// `m` must be the head of a live chain that nothing else will free
+    pub unsafe fn from_raw(m: *mut kernel_sys::mbuf) -> Self {
+        Mbuf {
+            m: ptr::NonNull::new(m).unwrap(),
+        }
+    }
+
+    /// An empty packet with room for `len` bytes in one buffer: the mbuf
+    /// itself, a cluster or a page-sized jumbo cluster. `None` if `len` is
+    /// over `MJUMPAGESIZE` or no buffers could be had without sleeping
+    pub fn new(len: usize) -> Option<Self> {
+        if len > MJUMPAGESIZE {
+            return None;
+        }
+        let m = unsafe {
+            kernel_sys::m_get2(
+                len as c_int,
+                M_NOWAIT,
+                MT_DATA,
+                M_PKTHDR as c_int,
+            )
+        };
+        ptr::NonNull::new(m).map(|m| Mbuf { m })
+    }
+
+    /// Copy `data` into a new packet, as a driver does with a frame it
+    /// received. `None` if no mbufs could be had without sleeping
+    pub fn copy_from(data: &[u8]) -> Option<Self> {
+        let m = unsafe {
+            kernel_sys::m_devget(
+                data.as_ptr() as *mut libc::c_char,
+                data.len() as libc::c_int,
+                0,
+                ptr::null_mut(),
+                None,
+            )
+        };
+        ptr::NonNull::new(m).map(|m| Mbuf { m })
+    }
+
+    /// Give up ownership of the chain, such as to hand it back to the
+    /// network stack
+    pub fn into_raw(self) -> *mut kernel_sys::mbuf {
+        let m = self.m.as_ptr();
+        mem::forget(self);
+        m
+    }
+
+    /// Raw pointer to the first mbuf
+    pub fn as_ptr(&self) -> *mut kernel_sys::mbuf {
+        self.m.as_ptr()
+    }
+
+    /// Bytes of data in the whole chain
+    pub fn len(&self) -> usize {
+        unsafe { kernel_sys::m_length(self.as_ptr(), ptr::null_mut()) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy the data from `offset` into `buf`, returning how many bytes
+    /// there were to copy
+    pub fn copy_to(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.len().saturating_sub(offset));
+        if n > 0 {
+            unsafe {
+                kernel_sys::m_copydata(
+                    self.as_ptr(),
+                    offset as libc::c_int,
+                    n as libc::c_int,
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                )
+            };
+        }
+        n
+    }
+}
+
+impl Mbuf {
+    fn flags(&self) -> u32 {
+        unsafe { (*self.as_ptr()).m_flags() }
+    }
+
+    fn pkthdr(&self) -> *mut kernel_sys::pkthdr {
+        debug_assert!(self.flags() & M_PKTHDR != 0);
+        unsafe {
+            &raw mut (*self.as_ptr())
+                .__bindgen_anon_3
+                .__bindgen_anon_1
+                .__bindgen_anon_1
+                .m_pkthdr
+        }
+    }
+
+    /// The data in the first mbuf of the chain
+    pub fn data(&self) -> &[u8] {
+        let m = self.as_ptr();
+        unsafe {
+            slice::from_raw_parts((*m).m_data as *const u8, (*m).m_len as usize)
+        }
+    }
+
+    /// The data in the first mbuf, if it's the mbuf's own and so can't be
+    /// shared with another packet. Clusters may be, so write those
+    /// through `copy_back`
+    pub fn data_mut(&mut self) -> Option<&mut [u8]> {
+        if self.flags() & (M_EXT | M_RDONLY | M_EXTPG) != 0 {
+            return None;
+        }
+        let m = self.as_ptr();
+        Some(unsafe {
+            slice::from_raw_parts_mut(
+                (*m).m_data as *mut u8,
+                (*m).m_len as usize,
+            )
+        })
+    }
+
+    /// Space before the data in the first mbuf, counted only when it's the
+    /// mbuf's own, as `M_LEADINGSPACE()` does for writable mbufs
+    fn leading_space(&self) -> usize {
+        let flags = self.flags();
+        if flags & (M_EXT | M_RDONLY | M_EXTPG) != 0 {
+            return 0;
+        }
+        let m = self.as_ptr();
+        // M_START(): m_pktdat follows the packet header, m_dat is where
+        // the header would be
+        let start = unsafe {
+            match flags & M_PKTHDR {
+                0 => (&raw const (*m).__bindgen_anon_3).cast::<u8>(),
+                _ => self.pkthdr().add(1).cast::<u8>(),
+            }
+        };
+        unsafe { (*m).m_data.cast::<u8>().offset_from(start) as usize }
+    }
+
+    /// The mbufs of the chain in turn, as the data each holds
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            m: self.as_ptr(),
+            _chain: PhantomData,
+        }
+    }
+
+    /// Make the first `len` bytes contiguous in the first mbuf, so that a
+    /// header can be read from `data`. Frees the packet and gives `None`
+    /// if it's shorter or `len` is over `MHLEN`
+    pub fn pullup(self, len: usize) -> Option<Self> {
+        let m = self.into_raw();
+        let m = unsafe { kernel_sys::m_pullup(m, len as c_int) };
+        ptr::NonNull::new(m).map(|m| Mbuf { m })
+    }
+
+    /// Add `len` bytes to the front of the packet, in front of the data
+    /// of the first mbuf if there's room there, for `data_mut` to fill in
+    /// with a header. Frees the packet and gives `None` if no mbuf could
+    /// be had
+    ///
+    /// ## Panics
+    /// Panics if `len` is over `MHLEN`
+    pub fn prepend(self, len: usize) -> Option<Self> {
+        assert!(len <= MHLEN, "can't prepend {len} bytes");
+        let this = if self.leading_space() >= len {
+            let m = self.as_ptr();
+            unsafe {
+                (*m).m_data = (*m).m_data.sub(len);
+                (*m).m_len += len as i32;
+            }
+            self
+        } else {
+            let m = unsafe {
+                kernel_sys::m_prepend(self.into_raw(), len as c_int, M_NOWAIT)
+            };
+            Mbuf {
+                m: ptr::NonNull::new(m)?,
+            }
+        };
+        if this.flags() & M_PKTHDR != 0 {
+            unsafe { (*this.pkthdr()).len += len as i32 };
+        }
+        Some(this)
+    }
+
+    /// Add `data` to the end of the packet, in as many mbufs as it takes
+    pub fn append(&mut self, data: &[u8]) -> Result<(), Errno> {
+        let ok = unsafe {
+            kernel_sys::m_append(
+                self.as_ptr(),
+                data.len() as c_int,
+                data.as_ptr() as *const c_char,
+            )
+        };
+        match ok {
+            0 => Err(Errno::NoBufs),
+            _ => Ok(()),
+        }
+    }
+
+    /// Add the packet `tail` to the end of this one
+    pub fn concat(&mut self, tail: Mbuf) {
+        unsafe { kernel_sys::m_catpkt(self.as_ptr(), tail.into_raw()) };
+    }
+
+    /// Trim `n` bytes from the front of the packet, or from the back if
+    /// `n` is negative, as `m_adj()`
+    pub fn adj(&mut self, n: isize) {
+        unsafe { kernel_sys::m_adj(self.as_ptr(), n as c_int) };
+    }
+
+    /// Overwrite the packet from `offset` with `data`, extending it if
+    /// need be
+    pub fn copy_back(
+        &mut self,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), Errno> {
+        unsafe {
+            kernel_sys::m_copyback(
+                self.as_ptr(),
+                offset as c_int,
+                data.len() as c_int,
+                data.as_ptr() as *const c_char,
+            )
+        };
+        // m_copyback() stops short if it can't extend the chain
+        match self.len() >= offset + data.len() {
+            true => Ok(()),
+            false => Err(Errno::NoBufs),
+        }
+    }
+
+    /// Split the packet at `offset`, keeping the front and returning the
+    /// rest as a packet of its own
+    pub fn split(&mut self, offset: usize) -> Option<Mbuf> {
+        let m = unsafe {
+            kernel_sys::m_split(self.as_ptr(), offset as c_int, M_NOWAIT)
+        };
+        ptr::NonNull::new(m).map(|m| Mbuf { m })
+    }
+
+    /// Copy the packet into as few clusters as will hold it, for hardware
+    /// that takes only so many segments. Handed back if that can't be done
+    pub fn defrag(self) -> Result<Self, (Errno, Self)> {
+        let m = unsafe { kernel_sys::m_defrag(self.as_ptr(), M_NOWAIT) };
+        match ptr::NonNull::new(m) {
+            // The old chain was freed
+            Some(m) => {
+                mem::forget(self);
+                Ok(Mbuf { m })
+            }
+            None => Err((Errno::NoBufs, self)),
+        }
+    }
+
+    /// The length the packet header records, which `len` counts afresh
+    pub fn pkt_len(&self) -> usize {
+        unsafe { (*self.pkthdr()).len as usize }
+    }
+
+    /// The flow the packet belongs to, which spreads packets across
+    /// queues and CPUs
+    pub fn flowid(&self) -> u32 {
+        unsafe { (*self.pkthdr()).flowid }
+    }
+
+    pub fn set_flowid(&mut self, flowid: u32) {
+        unsafe { (*self.pkthdr()).flowid = flowid };
+    }
+
+    /// `CSUM_*` checksum offload flags: checksums to compute when sending,
+    /// or those the hardware checked on receipt
+    pub fn csum_flags(&self) -> u32 {
+        unsafe { (*self.pkthdr()).csum_flags }
+    }
+
+    pub fn set_csum_flags(&mut self, flags: u32) {
+        unsafe { (*self.pkthdr()).csum_flags = flags };
+    }
+
+    /// The routing table the packet is looked up in, `setfib(1)`
+    pub fn fib(&self) -> u16 {
+        unsafe { (*self.pkthdr()).fibnum }
+    }
+
+    /// Read the packet from `offset`, such as to parse its headers
+    pub fn reader(&self, offset: usize) -> MbufReader<'_> {
+        MbufReader { m: self, offset }
+    }
+}
+
+/// Appends to the packet
+impl Write for Mbuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The data of each mbuf of a chain, from `Mbuf::segments`
+pub struct Segments<'a> {
+    m: *mut kernel_sys::mbuf,
+    _chain: PhantomData<&'a Mbuf>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let m = ptr::NonNull::new(self.m)?.as_ptr();
+        unsafe {
+            self.m = (*m).__bindgen_anon_1.m_next;
+            Some(slice::from_raw_parts(
+                (*m).m_data as *const u8,
+                (*m).m_len as usize,
+            ))
+        }
+    }
+}
+
+impl fmt::Debug for Segments<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Segments {{ m: {:p} }}", self.m)
+    }
+}
+
+/// Reads a packet across its mbufs, from `Mbuf::reader`
+pub struct MbufReader<'a> {
+    m: &'a Mbuf,
+    offset: usize,
+}
+
+impl MbufReader<'_> {
+    /// Where the next read starts
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl Read for MbufReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.m.copy_to(self.offset, buf);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+impl fmt::Debug for MbufReader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MbufReader {{ offset: {} }}", self.offset)
+    }
+}
+
+impl Drop for Mbuf {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::m_freem(self.as_ptr()) };
+    }
+}
+
+impl fmt::Debug for Mbuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mbuf {{ len: {} }}", self.len())
+    }
+}
+
+/// A FIFO of packets, linked through their `m_nextpkt` so that queueing
+/// never allocates. Frees the packets still in it when dropped
+pub struct MbufQueue {
+    head: *mut kernel_sys::mbuf,
+    tail: *mut kernel_sys::mbuf,
+    len: usize,
+    bytes: usize,
+}
+
+unsafe impl Send for MbufQueue {}
+
+impl MbufQueue {
+    pub const fn new() -> Self {
+        MbufQueue {
+            head: ptr::null_mut(),
+            tail: ptr::null_mut(),
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn push(&mut self, m: Mbuf) {
+        self.bytes += m.len();
+        let m = m.into_raw();
+        unsafe {
+            (*m).__bindgen_anon_2.m_nextpkt = ptr::null_mut();
+            if self.tail.is_null() {
+                self.head = m;
+            } else {
+                (*self.tail).__bindgen_anon_2.m_nextpkt = m;
+            }
+        }
+        self.tail = m;
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<Mbuf> {
+        let m = ptr::NonNull::new(self.head)?;
+        unsafe {
+            self.head = (*m.as_ptr()).__bindgen_anon_2.m_nextpkt;
+            (*m.as_ptr()).__bindgen_anon_2.m_nextpkt = ptr::null_mut();
+        }
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+        }
+        let m = Mbuf { m };
+        self.len -= 1;
+        self.bytes -= m.len();
+        Some(m)
+    }
+
+    /// Packets queued
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes of data in all the packets queued
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Default for MbufQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MbufQueue {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl fmt::Debug for MbufQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MbufQueue {{ len: {}, bytes: {} }}",
+            self.len, self.bytes
+        )
+    }
+}
@@ -0,0 +1,522 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Netgraph node types, see `netgraph(4)` and `netgraph(9)`
+//!
+//! A node type is a type implementing `NgNode`, registered by its module
+//! through a static `NodeType`:
+//! ```rust,ignore
+//! static RUSTTEE: NodeType = NodeType::new::<RustTee>();
+//!
+//! // in the module's load and unload events
+//! RUSTTEE.load()?;
+//! RUSTTEE.unload()?;
+//! ```
+//! with `MODULE_DEPEND(ng_rusttee, netgraph, NG_ABI_VERSION,
+//! NG_ABI_VERSION, NG_ABI_VERSION)` in the C glue. `ngctl mkpeer` then
+//! makes nodes of the type, each with the state `NgNode::constructor`
+//! returns, and connects hooks to them, each with the state
+//! `NgNode::newhook` returns for it. Packets arrive on a hook through
+//! `NgNode::rcvdata` and leave through `Hook::send`.
+//!
+//! Control messages carry a type cookie and a command, and an argument
+//! that `Msg::decode` reads as an `ioctl::Payload`. Messages with the
+//! node type's cookie go to `NgNode::rcvmsg`, which may answer with a
+//! `Response`; netgraph answers generic ones itself. Without a command
+//! list `ngctl msg` can't convert arguments to and from ASCII, so they're
+//! sent in binary, as from `NgSocket(3)`.
+//!
+//! Netgraph may run a node's callbacks on several threads at once, and
+//! they must not sleep.
+
+use super::Mbuf;
+use crate::errno::Errno;
+use crate::ioctl::{Payload, read_payload, write_payload};
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::ptr::{self, NonNull};
+use core::{fmt, mem, slice};
+use libc::{c_char, c_int, c_void};
+
+/// `HK_INVALID`: the hook is being disconnected
+const HK_INVALID: u32 = 0x0001;
+
+/// `NG_NOFLAGS`
+const NG_NOFLAGS: c_int = 0;
+
+/// A node, as its type's callbacks see it
+pub struct Node {
+    node: NonNull<kernel_sys::ng_node>,
+}
+
+impl Node {
+    pub fn as_ptr(&self) -> kernel_sys::node_p {
+        self.node.as_ptr()
+    }
+
+    /// The name `ngctl name` gave the node, empty if none
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.as_ptr()).nd_name.as_ptr()) }
+    }
+
+    /// The node's ID, by which `[id]:` addresses it
+    pub fn id(&self) -> u32 {
+        unsafe { (*self.as_ptr()).nd_ID }
+    }
+
+    /// Hooks connected to the node
+    pub fn num_hooks(&self) -> usize {
+        unsafe { (*self.as_ptr()).nd_numhooks as usize }
+    }
+
+    /// The hook named `name`, if connected
+    pub fn hook(&self, name: &CStr) -> Option<Hook> {
+        let hook =
+            unsafe { kernel_sys::ng_findhook(self.as_ptr(), name.as_ptr()) };
+        NonNull::new(hook).map(|hook| Hook { hook })
+    }
+
+    /// Shut the node down, disconnecting its hooks, as `ngctl shutdown`
+    /// would
+    pub fn remove(&self) {
+        unsafe { kernel_sys::ng_rmnode_self(self.as_ptr()) };
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Node {{ id: {:#x}, name: {:?} }}",
+            self.id(),
+            self.name()
+        )
+    }
+}
+
+/// One end of a connection between two nodes
+pub struct Hook {
+    hook: NonNull<kernel_sys::ng_hook>,
+}
+
+unsafe impl Send for Hook {}
+unsafe impl Sync for Hook {}
+
+impl Hook {
+    pub fn as_ptr(&self) -> kernel_sys::hook_p {
+        self.hook.as_ptr()
+    }
+
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.as_ptr()).hk_name.as_ptr()) }
+    }
+
+    /// Whether the hook is still connected, not being torn down
+    pub fn is_valid(&self) -> bool {
+        unsafe { (*self.as_ptr()).hk_flags as u32 & HK_INVALID == 0 }
+    }
+
+    /// Send `m` to the node at the other end, as `NG_SEND_DATA_ONLY()`.
+    /// The packet is freed if it can't be
+    pub fn send(&self, m: Mbuf) -> Result<(), Errno> {
+        let item =
+            unsafe { kernel_sys::ng_package_data(m.into_raw(), NG_NOFLAGS) };
+        if item.is_null() {
+            return Err(Errno::NoMem);
+        }
+        // Both free the item on failure
+        Errno::result(unsafe {
+            kernel_sys::ng_address_hook(ptr::null_mut(), item, self.as_ptr(), 0)
+        })?;
+        Errno::result(unsafe { kernel_sys::ng_snd_item(item, NG_NOFLAGS) })
+    }
+}
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hook {{ name: {:?} }}", self.name())
+    }
+}
+
+/// A control message, `struct ng_mesg`
+pub struct Msg {
+    msg: NonNull<kernel_sys::ng_mesg>,
+}
+
+impl Msg {
+    fn header(&self) -> &kernel_sys::ng_mesg_ng_msghdr {
+        unsafe { &(*self.msg.as_ptr()).header }
+    }
+
+    /// The cookie of the node type whose command this is
+    pub fn cookie(&self) -> u32 {
+        self.header().typecookie
+    }
+
+    pub fn cmd(&self) -> u32 {
+        self.header().cmd
+    }
+
+    /// The command's name, as `ngctl msg` takes
+    pub fn cmd_str(&self) -> &CStr {
+        let s = &self.header().cmdstr;
+        CStr::from_bytes_until_nul(s).unwrap_or(c"")
+    }
+
+    /// The argument
+    pub fn arg(&self) -> &[u8] {
+        unsafe {
+            let data = self.msg.as_ptr().add(1).cast::<u8>();
+            slice::from_raw_parts(data, self.header().arglen as usize)
+        }
+    }
+
+    /// Read the argument as a `T`, failing with `Errno::Inval` if it's too
+    /// short
+    pub fn decode<T: Payload>(&self) -> Result<T, Errno> {
+        read_payload(self.arg())
+    }
+
+    /// Answer the message with `value` as the argument
+    pub fn respond<T: Payload>(&self, value: &T) -> Result<Response, Errno> {
+        let rsp = Response::new(self, mem::size_of::<T>())?;
+        write_payload(rsp.arg_mut(), value);
+        Ok(rsp)
+    }
+
+    /// Answer the message with no argument
+    pub fn respond_empty(&self) -> Result<Response, Errno> {
+        Response::new(self, 0)
+    }
+}
+
+impl fmt::Debug for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Msg {{ cookie: {}, cmd: {}, arglen: {} }}",
+            self.cookie(),
+            self.cmd(),
+            self.header().arglen
+        )
+    }
+}
+
+/// An answer to a control message, made as `NG_MKRESPONSE()` does
+pub struct Response {
+    rsp: NonNull<kernel_sys::ng_mesg>,
+}
+
+unsafe impl Send for Response {}
+
+impl Response {
+    fn new(msg: &Msg, len: usize) -> Result<Self, Errno> {
+        let rsp = unsafe {
+            kernel_sys::malloc(
+                mem::size_of::<kernel_sys::ng_mesg>() + len,
+                (&raw mut kernel_sys::M_NETGRAPH_MSG).cast(),
+                kernel_sys::M_NOWAIT | kernel_sys::M_ZERO,
+            )
+        };
+        let rsp = NonNull::new(rsp.cast::<kernel_sys::ng_mesg>())
+            .ok_or(Errno::NoMem)?;
+        let from = msg.header();
+        unsafe {
+            let h = &mut (*rsp.as_ptr()).header;
+            h.version = kernel_sys::NG_VERSION as u8;
+            h.arglen = len as u32;
+            h.token = from.token;
+            h.typecookie = from.typecookie;
+            h.cmd = from.cmd;
+            h.cmdstr = from.cmdstr;
+            h.flags |= kernel_sys::NGF_RESP;
+        }
+        Ok(Response { rsp })
+    }
+
+    /// The argument, zeroed to begin with
+    pub fn arg_mut(&self) -> &mut [u8] {
+        unsafe {
+            let rsp = self.rsp.as_ptr();
+            let data = rsp.add(1).cast::<u8>();
+            slice::from_raw_parts_mut(data, (*rsp).header.arglen as usize)
+        }
+    }
+
+    fn into_raw(self) -> *mut kernel_sys::ng_mesg {
+        let rsp = self.rsp.as_ptr();
+        mem::forget(self);
+        rsp
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        free_msg(self.rsp.as_ptr());
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Response {{ rsp: {:p} }}", self.rsp)
+    }
+}
+
+/// `NG_FREE_MSG()`
+fn free_msg(msg: *mut kernel_sys::ng_mesg) {
+    unsafe {
+        kernel_sys::free(
+            msg.cast(),
+            (&raw mut kernel_sys::M_NETGRAPH_MSG).cast(),
+        )
+    };
+}
+
+/// A netgraph node type. Each node has an instance, made when the node
+/// is, and each of its hooks a `Self::Hook`
+pub trait NgNode: Sized + Send + Sync + 'static {
+    /// The name `ngctl mkpeer` makes nodes of the type by
+    const NAME: &'static CStr;
+    /// The type's unique cookie, on the control messages it knows,
+    /// conventionally the time it was written as from `date -u +%s`
+    const COOKIE: u32;
+
+    /// The state of a hook
+    type Hook: Send + Sync;
+
+    fn constructor(node: &Node) -> Result<Self, Errno>;
+
+    /// A hook named `name` is being connected. An error refuses it
+    fn newhook(&self, node: &Node, name: &CStr) -> Result<Self::Hook, Errno>;
+
+    /// Take a packet arriving on `hook`, to send on, keep or drop
+    fn rcvdata(
+        &self,
+        hook: &Hook,
+        state: &Self::Hook,
+        m: Mbuf,
+    ) -> Result<(), Errno>;
+
+    /// Act on a control message with the type's cookie, answering it if
+    /// need be
+    fn rcvmsg(
+        &self,
+        _node: &Node,
+        _msg: &Msg,
+    ) -> Result<Option<Response>, Errno> {
+        Err(Errno::Inval)
+    }
+
+    /// `hook` was disconnected, `state` is dropped next. By default the
+    /// node shuts down with its last hook
+    fn disconnect(&self, node: &Node, _hook: &Hook, _state: &Self::Hook) {
+        if node.num_hooks() == 0 {
+            node.remove();
+        }
+    }
+
+    /// The node is shutting down, its hooks disconnected, and is dropped
+    /// next
+    fn shutdown(&self, _node: &Node) {}
+}
+
+/// A node type's `struct ng_type`, for netgraph to find it by
+pub struct NodeType(UnsafeCell<kernel_sys::ng_type>);
+
+// netgraph only touches the reference count and list linkage, under its
+// own lock
+unsafe impl Sync for NodeType {}
+
+impl NodeType {
+    pub const fn new<T: NgNode>() -> Self {
+        let mut ty: kernel_sys::ng_type = unsafe { mem::zeroed() };
+        ty.version = kernel_sys::NG_ABI_VERSION;
+        ty.name = T::NAME.as_ptr();
+        ty.constructor = Some(constructor::<T>);
+        ty.rcvmsg = Some(rcvmsg::<T>);
+        ty.shutdown = Some(shutdown::<T>);
+        ty.newhook = Some(newhook::<T>);
+        ty.rcvdata = Some(rcvdata::<T>);
+        ty.disconnect = Some(disconnect::<T>);
+        NodeType(UnsafeCell::new(ty))
+    }
+
+    /// Register the node type, for the module's load event
+    pub fn load(&'static self) -> Result<(), Errno> {
+        Errno::result(unsafe { kernel_sys::ng_newtype(self.0.get()) })
+    }
+
+    /// Unregister the node type, failing with `Errno::Busy` while nodes of
+    /// it remain
+    pub fn unload(&'static self) -> Result<(), Errno> {
+        Errno::result(unsafe { kernel_sys::ng_rmtype(self.0.get()) })
+    }
+}
+
+impl fmt::Debug for NodeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = unsafe { CStr::from_ptr((*self.0.get()).name) };
+        write!(f, "NodeType {{ name: {:?} }}", name)
+    }
+}
+
+fn node(node: kernel_sys::node_p) -> Node {
+    Node {
+        node: NonNull::new(node).unwrap(),
+    }
+}
+
+fn hook(hook: kernel_sys::hook_p) -> Hook {
+    Hook {
+        hook: NonNull::new(hook).unwrap(),
+    }
+}
+
+/// ## Safety
+/// `node` must have been constructed as a `T`, and not shut down
+unsafe fn instance<'a, T>(node: kernel_sys::node_p) -> &'a T {
+    unsafe { &*((*node).nd_private as *const T) }
+}
+
+/// ## Safety
+/// `hook` must have been accepted by `newhook::<T>`, and not disconnected
+unsafe fn hook_state<'a, T: NgNode>(hook: kernel_sys::hook_p) -> &'a T::Hook {
+    unsafe { &*((*hook).hk_private as *const T::Hook) }
+}
+
+unsafe extern "C" fn constructor<T: NgNode>(n: kernel_sys::node_p) -> c_int {
+    // netgraph drops its reference to the node on failure
+    match catch_in_module(|| T::constructor(&node(n))) {
+        Ok(Ok(t)) => {
+            unsafe { (*n).nd_private = Box::into_raw(Box::new(t)).cast() };
+            0
+        }
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn shutdown<T: NgNode>(n: kernel_sys::node_p) -> c_int {
+    let t = unsafe { (*n).nd_private as *mut T };
+    unsafe { (*n).nd_private = ptr::null_mut() };
+    let _ = catch_in_module(|| {
+        let t = unsafe { Box::from_raw(t) };
+        t.shutdown(&node(n));
+    });
+    // NG_NODE_UNREF()
+    unsafe { kernel_sys::ng_unref_node(n) };
+    0
+}
+
+unsafe extern "C" fn newhook<T: NgNode>(
+    n: kernel_sys::node_p,
+    h: kernel_sys::hook_p,
+    name: *const c_char,
+) -> c_int {
+    let t = unsafe { instance::<T>(n) };
+    let name = unsafe { CStr::from_ptr(name) };
+    match catch_in_module(|| t.newhook(&node(n), name)) {
+        Ok(Ok(state)) => {
+            let state = Box::into_raw(Box::new(state));
+            unsafe { (*h).hk_private = state as *mut c_void };
+            0
+        }
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn rcvdata<T: NgNode>(
+    h: kernel_sys::hook_p,
+    item: kernel_sys::item_p,
+) -> c_int {
+    // NGI_GET_M(), then NG_FREE_ITEM()
+    let m = unsafe {
+        let m = (*item).body.da_m;
+        (*item).body.da_m = ptr::null_mut();
+        kernel_sys::ng_free_item(item);
+        Mbuf::from_raw(m)
+    };
+    let t = unsafe { instance::<T>((*h).hk_node) };
+    let state = unsafe { hook_state::<T>(h) };
+    match catch_in_module(|| t.rcvdata(&hook(h), state, m)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn rcvmsg<T: NgNode>(
+    n: kernel_sys::node_p,
+    item: kernel_sys::item_p,
+    _lasthook: kernel_sys::hook_p,
+) -> c_int {
+    let t = unsafe { instance::<T>(n) };
+    // NGI_GET_MSG()
+    let msg = unsafe {
+        let msg = (*item).body.msg.msg_msg;
+        (*item).body.msg.msg_msg = ptr::null_mut();
+        Msg {
+            msg: NonNull::new(msg).unwrap(),
+        }
+    };
+    let result = match msg.cookie() == T::COOKIE {
+        true => catch_in_module(|| t.rcvmsg(&node(n), &msg)).and_then(|r| r),
+        false => Err(Errno::Inval),
+    };
+    let error = match result {
+        // NG_RESPOND_MSG()
+        Ok(Some(rsp)) => unsafe {
+            let dest = (*item).body.msg.msg_retaddr;
+            (*item).body.msg.msg_retaddr = 0;
+            (*item).body.msg.msg_msg = rsp.into_raw();
+            match kernel_sys::ng_address_ID(n, item, dest, 0) {
+                0 => {
+                    kernel_sys::ng_snd_item(item, kernel_sys::NG_QUEUE as c_int)
+                }
+                e => e,
+            }
+        },
+        Ok(None) => {
+            unsafe { kernel_sys::ng_free_item(item) };
+            0
+        }
+        Err(e) => {
+            unsafe { kernel_sys::ng_free_item(item) };
+            e.as_raw()
+        }
+    };
+    free_msg(msg.msg.as_ptr());
+    error
+}
+
+unsafe extern "C" fn disconnect<T: NgNode>(h: kernel_sys::hook_p) -> c_int {
+    let n = unsafe { (*h).hk_node };
+    let t = unsafe { instance::<T>(n) };
+    let state = unsafe { (*h).hk_private as *mut T::Hook };
+    unsafe { (*h).hk_private = ptr::null_mut() };
+    let _ = catch_in_module(|| {
+        let state = unsafe { Box::from_raw(state) };
+        t.disconnect(&node(n), &hook(h), &state);
+    });
+    0
+}
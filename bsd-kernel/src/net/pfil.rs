@@ -0,0 +1,236 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Packet filter hooks, see `pfil(9)`
+//!
+//! A module registers a `Filter` as a hook with `Hook::add`, then links it
+//! into the heads of the protocols it wants to see, in either direction.
+//! Every packet passing through those heads is then handed to it inline,
+//! and it decides whether the packet goes on:
+//! ```rust,ignore
+//! impl Filter for DropTelnet {
+//!     fn check(&self, m: Mbuf, _dir: Dir, _ifp: &Ifnet) -> Verdict {
+//!         let Some(m) = m.pullup(IP_TCP_LEN) else {
+//!             return Verdict::Dropped;
+//!         };
+//!         match dport(m.data()) {
+//!             23 => Verdict::Dropped,
+//!             _ => Verdict::Pass(m),
+//!         }
+//!     }
+//! }
+//!
+//! let hook = Hook::add(c"rustfilter", c"telnet", Head::Inet, dir::IN, DropTelnet)?;
+//! hook.link(Head::Inet, dir::IN)?;
+//! ```
+//! `pfilctl(8)` lists the heads and hooks, and can link and unlink them
+//! too. Heads are per vnet, and `link` links into the current one.
+
+use super::Mbuf;
+use super::ifnet::Ifnet;
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::sync::Epoch;
+use alloc::boxed::Box;
+use core::ffi::CStr;
+use core::fmt;
+use core::ptr::{self, NonNull};
+use libc::{c_int, c_void};
+
+/// `PFIL_IN` and `PFIL_OUT`, the directions a hook sees packets in
+pub mod dir {
+    pub const IN: i32 = 0x0000_0001;
+    pub const OUT: i32 = 0x0000_0002;
+    pub const BOTH: i32 = IN | OUT;
+}
+
+/// `PFIL_HEADPTR`: the link args name the head by pointer
+const PFIL_HEADPTR: c_int = 0x0000_0010;
+
+/// `PFIL_HOOKPTR`: the link args name the hook by pointer
+const PFIL_HOOKPTR: c_int = 0x0000_0020;
+
+/// The direction a packet is going in
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Dir {
+    In,
+    Out,
+}
+
+/// The heads the network stack provides, by the packets passing through
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Head {
+    /// IPv4, `PFIL_INET_NAME`
+    Inet,
+    /// IPv6, `PFIL_INET6_NAME`
+    Inet6,
+    /// Ethernet frames, `PFIL_ETHER_NAME`
+    Ethernet,
+}
+
+impl Head {
+    fn name(self) -> &'static CStr {
+        match self {
+            Head::Inet => c"inet",
+            Head::Inet6 => c"inet6",
+            Head::Ethernet => c"ethernet",
+        }
+    }
+
+    fn kind(self) -> kernel_sys::pfil_types {
+        match self {
+            Head::Inet => kernel_sys::pfil_types_PFIL_TYPE_IP4,
+            Head::Inet6 => kernel_sys::pfil_types_PFIL_TYPE_IP6,
+            Head::Ethernet => kernel_sys::pfil_types_PFIL_TYPE_ETHERNET,
+        }
+    }
+}
+
+/// What a filter did with a packet
+#[derive(Debug)]
+pub enum Verdict {
+    /// Let it go on, possibly changed or in a new chain
+    Pass(Mbuf),
+    /// Dropped, counted by the stack as filtered
+    Dropped,
+    /// Kept by the filter, to be sent on its own later
+    Consumed,
+}
+
+/// A packet filter. `check` runs in the network epoch on any CPU, for
+/// every packet through the heads its hook is linked into, and must not
+/// sleep
+pub trait Filter: Send + Sync + 'static {
+    /// Decide on `m`, going `dir` through `ifp`
+    fn check(&self, m: Mbuf, dir: Dir, ifp: &Ifnet) -> Verdict;
+}
+
+/// A filter added with `pfil_add_hook()`. Removed from every head it is
+/// linked into when dropped
+pub struct Hook<T: Filter> {
+    hook: kernel_sys::pfil_hook_t,
+    filter: NonNull<T>,
+}
+
+unsafe impl<T: Filter> Send for Hook<T> {}
+unsafe impl<T: Filter> Sync for Hook<T> {}
+
+impl<T: Filter> Hook<T> {
+    /// Add `filter` as hook `rule` of `module` for packets of `head`'s
+    /// type, going in the `dir` directions. `pfilctl(8)` shows it as
+    /// `module:rule`
+    pub fn add(
+        module: &'static CStr,
+        rule: &'static CStr,
+        head: Head,
+        dir: i32,
+        filter: T,
+    ) -> Result<Self, Errno> {
+        let filter = Box::into_raw(Box::new(filter));
+        let mut pa: kernel_sys::pfil_hook_args = unsafe { core::mem::zeroed() };
+        pa.pa_version = kernel_sys::PFIL_VERSION as c_int;
+        pa.pa_flags = dir;
+        pa.pa_type = head.kind();
+        pa.pa_mbuf_chk = Some(check::<T>);
+        pa.pa_ruleset = filter as *mut c_void;
+        pa.pa_modname = module.as_ptr();
+        pa.pa_rulname = rule.as_ptr();
+        let hook = unsafe { kernel_sys::pfil_add_hook(&mut pa) };
+        if hook.is_null() {
+            drop(unsafe { Box::from_raw(filter) });
+            return Err(Errno::NoMem);
+        }
+        Ok(Hook {
+            hook,
+            filter: NonNull::new(filter).unwrap(),
+        })
+    }
+
+    /// Link the hook into `head` of the current vnet, after the hooks
+    /// already there, to see packets going in the `dir` directions
+    pub fn link(&self, head: Head, dir: i32) -> Result<(), Errno> {
+        let mut pa: kernel_sys::pfil_link_args = unsafe { core::mem::zeroed() };
+        pa.pa_version = kernel_sys::PFIL_VERSION as c_int;
+        pa.pa_flags = dir | PFIL_HOOKPTR;
+        pa.__bindgen_anon_1.pa_headname = head.name().as_ptr();
+        pa.__bindgen_anon_2.pa_hook = self.hook;
+        debug_assert_eq!(pa.pa_flags & PFIL_HEADPTR, 0);
+        Errno::result(unsafe { kernel_sys::pfil_link(&mut pa) })
+    }
+
+    pub fn filter(&self) -> &T {
+        unsafe { self.filter.as_ref() }
+    }
+}
+
+impl<T: Filter> Drop for Hook<T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::pfil_remove_hook(self.hook) };
+        // Packets already in the hook may still be using the filter
+        Epoch::net().wait();
+        drop(unsafe { Box::from_raw(self.filter.as_ptr()) });
+    }
+}
+
+impl<T: Filter> fmt::Debug for Hook<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hook {{ hook: {:p} }}", self.hook)
+    }
+}
+
+/// `pa_mbuf_chk`: the filter frees or keeps the packets it doesn't pass,
+/// and says when it passes another chain than it was given
+unsafe extern "C" fn check<T: Filter>(
+    mp: *mut *mut kernel_sys::mbuf,
+    ifp: kernel_sys::if_t,
+    flags: c_int,
+    ruleset: *mut c_void,
+    _inp: *mut kernel_sys::inpcb,
+) -> kernel_sys::pfil_return_t {
+    let filter = unsafe { &*(ruleset as *const T) };
+    let orig = unsafe { *mp };
+    let m = unsafe { Mbuf::from_raw(orig) };
+    let dir = match flags & dir::IN {
+        0 => Dir::Out,
+        _ => Dir::In,
+    };
+    let ifp = unsafe { Ifnet::from_raw(ifp) }.unwrap();
+    let verdict = catch_in_module(|| filter.check(m, dir, &ifp));
+    let (m, ret) = match verdict {
+        Ok(Verdict::Pass(m)) => {
+            let m = m.into_raw();
+            match m == orig {
+                true => (m, kernel_sys::pfil_return_t_PFIL_PASS),
+                false => (m, kernel_sys::pfil_return_t_PFIL_REALLOCED),
+            }
+        }
+        Ok(Verdict::Dropped) | Err(_) => {
+            (ptr::null_mut(), kernel_sys::pfil_return_t_PFIL_DROPPED)
+        }
+        Ok(Verdict::Consumed) => {
+            (ptr::null_mut(), kernel_sys::pfil_return_t_PFIL_CONSUMED)
+        }
+    };
+    unsafe { *mp = m };
+    ret
+}
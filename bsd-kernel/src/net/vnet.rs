@@ -0,0 +1,316 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Virtualized network stacks, see `vnet(9)`
+//!
+//! On kernels with `options VIMAGE`, as GENERIC is, each vnet jail has a
+//! network stack of its own, and the thread's current vnet picks which
+//! one interfaces, pfil heads and per-vnet variables belong to. Code run
+//! from outside the stack, such as from a callout or taskqueue, sets the
+//! vnet it works on first:
+//! ```rust,ignore
+//! let _vnet = ifp.vnet().set();
+//! RUSTFILTER_PACKETS.get().fetch_add(1, Ordering::Relaxed);
+//! ```
+//! Per-vnet variables are statics defined with `vnet_define!`, which the
+//! kernel copies into each vnet as it's made. Module state that needs
+//! setting up in each vnet, such as a pfil hook linked into its heads, is
+//! set up by a `VnetInit` registered on load, which runs in every vnet
+//! there is and every one made later.
+//!
+//! The `vimage` feature builds for `VIMAGE` kernels. Without it there is
+//! only the one stack: setting the vnet does nothing, and each variable
+//! and `VnetInit` has one instance.
+
+use crate::module::{Order, Subsystem};
+use crate::panic::catch_in_module;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::{fmt, mem};
+use libc::c_void;
+
+/// A network stack, `struct vnet`
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Vnet {
+    vnet: *mut kernel_sys::vnet,
+}
+
+unsafe impl Send for Vnet {}
+unsafe impl Sync for Vnet {}
+
+impl Vnet {
+    /// The host's stack, `vnet0`
+    pub fn vnet0() -> Vnet {
+        #[cfg(feature = "vimage")]
+        let vnet = unsafe { kernel_sys::vnet0 };
+        #[cfg(not(feature = "vimage"))]
+        let vnet = core::ptr::null_mut();
+        Vnet { vnet }
+    }
+
+    /// The thread's vnet, `curvnet`, if one is set
+    pub fn current() -> Option<Vnet> {
+        #[cfg(feature = "vimage")]
+        let vnet = unsafe { (*crate::arch::curthread()).td_vnet };
+        #[cfg(not(feature = "vimage"))]
+        let vnet = core::ptr::null_mut();
+        match cfg!(feature = "vimage") && vnet.is_null() {
+            true => None,
+            false => Some(Vnet { vnet }),
+        }
+    }
+
+    /// ## Safety
+    /// `vnet` must outlive the `Vnet`, as an interface's or a jail's does
+    /// theirs
+    pub unsafe fn from_raw(vnet: *mut kernel_sys::vnet) -> Vnet {
+        Vnet { vnet }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::vnet {
+        self.vnet
+    }
+
+    /// Make this the thread's vnet until the guard is dropped, as
+    /// `CURVNET_SET()` and `CURVNET_RESTORE()`
+    pub fn set(&self) -> CurVnet {
+        #[cfg(feature = "vimage")]
+        let saved = unsafe {
+            let td = crate::arch::curthread();
+            mem::replace(&mut (*td).td_vnet, self.vnet)
+        };
+        #[cfg(not(feature = "vimage"))]
+        let saved = core::ptr::null_mut();
+        CurVnet {
+            saved,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Run `f` with this as the thread's vnet
+    pub fn with<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _vnet = self.set();
+        f()
+    }
+}
+
+impl fmt::Debug for Vnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Vnet {{ vnet: {:p} }}", self.vnet)
+    }
+}
+
+/// The thread's vnet, set by `Vnet::set` and restored when dropped
+pub struct CurVnet {
+    saved: *mut kernel_sys::vnet,
+    // Restored on the thread that set it
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl Drop for CurVnet {
+    fn drop(&mut self) {
+        #[cfg(feature = "vimage")]
+        unsafe {
+            (*crate::arch::curthread()).td_vnet = self.saved
+        };
+    }
+}
+
+impl fmt::Debug for CurVnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CurVnet {{ saved: {:p} }}", self.saved)
+    }
+}
+
+/// A per-vnet variable, `VNET_DEFINE()`, defined with `vnet_define!`
+///
+/// The static is the template each vnet's instance is copied from, so the
+/// value is copied bit for bit and never dropped; shared between the
+/// threads working in a vnet, it's changed through atomics.
+#[repr(transparent)]
+pub struct VnetVar<T: Sync> {
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Sync> Sync for VnetVar<T> {}
+
+impl<T: Sync> VnetVar<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        assert!(!mem::needs_drop::<T>(), "per-vnet values aren't dropped");
+        VnetVar {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// The instance in `vnet`, as `VNET_VNET()`
+    pub fn get_in(&'static self, vnet: Vnet) -> &'static T {
+        #[cfg(feature = "vimage")]
+        unsafe {
+            let base = (*vnet.as_ptr()).vnet_data_base;
+            &*((base + self.value.get() as usize) as *const T)
+        }
+        #[cfg(not(feature = "vimage"))]
+        {
+            let _ = vnet;
+            unsafe { &*self.value.get() }
+        }
+    }
+
+    /// The instance in the thread's vnet, as `VNET()`
+    ///
+    /// ## Panics
+    /// Panics if no vnet is set
+    pub fn get(&'static self) -> &'static T {
+        self.get_in(Vnet::current().expect("no current vnet"))
+    }
+}
+
+impl<T: Sync> fmt::Debug for VnetVar<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VnetVar {{ template: {:p} }}", self.value.get())
+    }
+}
+
+/// Define a per-vnet variable, as `VNET_DEFINE()`
+///
+/// ```rust,ignore
+/// bsd_kernel::vnet_define!(static RUSTFILTER_PACKETS: AtomicU64 = AtomicU64::new(0));
+/// ```
+///
+/// The kernel linker finds the variables of a module in its `set_vnet`
+/// section, and makes room for them in each vnet.
+#[macro_export]
+macro_rules! vnet_define {
+    ($(#[$m:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $(#[$m])*
+        #[unsafe(link_section = "set_vnet")]
+        #[used]
+        $vis static $name: $crate::net::vnet::VnetVar<$t> =
+            $crate::net::vnet::VnetVar::new($init);
+    };
+}
+
+/// Setting up and tearing down a module's state in each vnet, as a pair
+/// of `VNET_SYSINIT()` and `VNET_SYSUNINIT()`
+///
+/// The functions run with the vnet set, `init` in the order of
+/// `subsystem` and `order` among the kernel's own as each vnet is made,
+/// `uninit` in reverse as each is destroyed.
+pub struct VnetInit {
+    #[cfg(feature = "vimage")]
+    init: UnsafeCell<kernel_sys::vnet_sysinit>,
+    #[cfg(feature = "vimage")]
+    uninit: UnsafeCell<kernel_sys::vnet_sysinit>,
+    init_fn: fn(),
+    uninit_fn: fn(),
+}
+
+// The kernel only touches the list linkage, under its own lock
+unsafe impl Sync for VnetInit {}
+
+impl VnetInit {
+    /// `subsystem` must be after `Subsystem::Vnet`, up to
+    /// `Subsystem::VnetDone`, such as `Subsystem::ProtoFirewall`
+    pub const fn new(
+        subsystem: Subsystem,
+        order: Order,
+        init: fn(),
+        uninit: fn(),
+    ) -> Self {
+        let subsystem = subsystem.as_raw();
+        let order = order.as_raw();
+        assert!(
+            subsystem > kernel_sys::sysinit_sub_id_SI_SUB_VNET
+                && subsystem <= kernel_sys::sysinit_sub_id_SI_SUB_VNET_DONE
+        );
+        #[cfg(not(feature = "vimage"))]
+        let _ = order;
+        VnetInit {
+            #[cfg(feature = "vimage")]
+            init: sysinit(subsystem, order, init),
+            #[cfg(feature = "vimage")]
+            uninit: sysinit(subsystem, order, uninit),
+            init_fn: init,
+            uninit_fn: uninit,
+        }
+    }
+
+    /// Run `init` in each vnet there is, and each made from now on, for
+    /// the module's load event
+    pub fn register(&'static self) {
+        #[cfg(feature = "vimage")]
+        unsafe {
+            kernel_sys::vnet_register_sysinit(self.init.get().cast());
+            kernel_sys::vnet_register_sysuninit(self.uninit.get().cast());
+        }
+        #[cfg(not(feature = "vimage"))]
+        unsafe {
+            run(self.init_fn as *const c_void)
+        };
+    }
+
+    /// Run `uninit` in each vnet there is, and no longer in new ones, for
+    /// the module's unload event
+    pub fn deregister(&'static self) {
+        #[cfg(feature = "vimage")]
+        unsafe {
+            kernel_sys::vnet_deregister_sysinit(self.init.get().cast());
+            kernel_sys::vnet_deregister_sysuninit(self.uninit.get().cast());
+        }
+        #[cfg(not(feature = "vimage"))]
+        unsafe {
+            run(self.uninit_fn as *const c_void)
+        };
+    }
+}
+
+impl fmt::Debug for VnetInit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "VnetInit {{ init: {:p}, uninit: {:p} }}",
+            self.init_fn, self.uninit_fn
+        )
+    }
+}
+
+#[cfg(feature = "vimage")]
+const fn sysinit(
+    subsystem: kernel_sys::sysinit_sub_id,
+    order: kernel_sys::sysinit_elem_order,
+    f: fn(),
+) -> UnsafeCell<kernel_sys::vnet_sysinit> {
+    let mut vs: kernel_sys::vnet_sysinit = unsafe { mem::zeroed() };
+    vs.subsystem = subsystem;
+    vs.order = order;
+    vs.func = Some(run);
+    vs.arg = f as *const c_void;
+    UnsafeCell::new(vs)
+}
+
+/// The `sysinit_cfunc_t` of a `VnetInit`, given its function
+unsafe extern "C" fn run(arg: *const c_void) {
+    let f: fn() = unsafe { mem::transmute(arg) };
+    let _ = catch_in_module(f);
+}
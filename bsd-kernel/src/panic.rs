@@ -0,0 +1,239 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Keeping panics from crossing into the kernel
+//!
+//! Kernel modules are built with `panic = "abort"`, so a panic in the
+//! kernel never returns through the `extern "C"` trampolines behind
+//! character devices and GEOM classes. It ends in the panic handler,
+//! which calls `handle`. That poisons the module and prints the panic and
+//! a backtrace on the console. With the `kernel-panic` feature it then
+//! panics the kernel, dropping into the debugger or dumping core as
+//! configured. Otherwise the thread that panicked sleeps for good on
+//! `rpanic`, and the module refuses to quiesce, as unloading it would pull
+//! the code from under the thread. Where it can't sleep safely, in an
+//! interrupt filter, in a critical section or holding a lock, the kernel
+//! panics anyway rather than wedge the CPU or the threads waiting for the
+//! lock.
+//!
+//! A poisoned module refuses every later call into it with `Errno::NxIo`
+//! without running any Rust code, as the panic may have left state shared
+//! between objects half updated. Unloading skips `ModuleEvents::unload`
+//! but still tears the module down, destroying its devices once the
+//! threads already inside them have left.
+//!
+//! # What a caller sees
+//!
+//! For a character device, in the kernel:
+//!
+//! - The thread whose `read(2)`, `write(2)` or `ioctl(2)` panicked never
+//!   returns to userspace. It shows as sleeping on `rpanic` and can't be
+//!   killed, unless the kernel panicked instead.
+//! - Every later `open(2)`, `read(2)`, `write(2)`, `ioctl(2)` and so on,
+//!   on that device or any other of the module, fails with `ENXIO`.
+//! - `kldunload` fails with `EBUSY` while the parked thread exists.
+//!
+//! GEOM `start` and `done` run in the `g_down` and `g_up` threads, which
+//! may not sleep, so a panic there always panics the kernel.
+//!
+//! # In the mock
+//!
+//! Panics unwind only where there is an unwinder. The mock has the host's,
+//! and there `catch_at_boundary` catches a panic at the trampoline,
+//! poisons the object the trampoline belongs to as well as the module, and
+//! returns `Errno::Io`, so the panicking call fails with `EIO` and later
+//! ones with `ENXIO`. That `EIO` never happens in the kernel; tests that
+//! check it are checking the poisoning, not what a kernel caller sees.
+
+use crate::errno::Errno;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(not(feature = "mock"))]
+use crate::io::FmtBuf;
+#[cfg(not(feature = "mock"))]
+use crate::stack::Stack;
+#[cfg(not(feature = "mock"))]
+use core::fmt::Write;
+#[cfg(not(feature = "mock"))]
+use core::panic::PanicInfo;
+
+/// Set once anything in the module has panicked. Each module links its
+/// own copy of this crate, so there is one per module
+static MODULE: Poison = Poison::new();
+
+/// Whether anything in the module has panicked
+pub fn module_poisoned() -> bool {
+    MODULE.is_poisoned()
+}
+
+/// Refuse all further calls into the module, for panic handlers
+pub fn poison_module() {
+    MODULE.poison();
+}
+
+/// Threads asleep for good after panicking, which the module can't be
+/// unloaded under
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of threads that panicked and are asleep in the module
+pub fn parked() -> usize {
+    PARKED.load(Ordering::Relaxed)
+}
+
+/// Handle a panic that reached the module's panic handler: poison the
+/// module, print the panic and a backtrace, and panic the kernel or park
+/// the thread
+#[cfg(not(feature = "mock"))]
+pub fn handle(info: &PanicInfo) -> ! {
+    poison_module();
+    // Formatted on the stack, as the allocator may be what panicked
+    let mut msg = FmtBuf::<256>::new();
+    let _ = write!(msg, "{}", info);
+    let _ = writeln!(Console, "rust panic: {}", msg);
+    let _ = write!(Console, "{}", Stack::capture());
+    if cfg!(feature = "kernel-panic") || !can_sleep() {
+        unsafe { kernel_sys::panic(c"rust: %s".as_ptr(), msg.as_ptr()) }
+    }
+    park()
+}
+
+/// Whether the current thread may sleep for good: outside critical
+/// sections and interrupt handlers, and holding no locks
+#[cfg(not(feature = "mock"))]
+fn can_sleep() -> bool {
+    let td = unsafe { &*crate::arch::curthread() };
+    td.td_critnest == 0 && td.td_no_sleeping == 0 && td.td_locks == 0
+}
+
+/// Sleep for good, off the CPU
+#[cfg(not(feature = "mock"))]
+fn park() -> ! {
+    PARKED.fetch_add(1, Ordering::Relaxed);
+    loop {
+        unsafe {
+            kernel_sys::pause_sbt(
+                c"rpanic".as_ptr(),
+                3600 * kernel_sys::SBT_1S,
+                0,
+                0,
+            )
+        };
+    }
+}
+
+/// The console and message buffer, through `printf(9)` from a stack
+/// buffer
+#[cfg(not(feature = "mock"))]
+struct Console;
+
+#[cfg(not(feature = "mock"))]
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut buf = FmtBuf::<128>::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            buf.clear();
+            let _ = buf.write_str(rest);
+            if buf.is_empty() {
+                break;
+            }
+            rest = &rest[buf.len()..];
+            unsafe { kernel_sys::printf(c"%s".as_ptr(), buf.as_ptr()) };
+        }
+        Ok(())
+    }
+}
+
+/// Set once code run for an object has panicked
+#[derive(Default)]
+pub struct Poison(AtomicBool);
+
+impl Poison {
+    pub const fn new() -> Self {
+        Poison(AtomicBool::new(false))
+    }
+
+    /// Whether code run for the object has panicked
+    pub fn is_poisoned(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Refuse all further calls on the object
+    pub fn poison(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+impl fmt::Debug for Poison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Poison {{ poisoned: {:?} }}", self.is_poisoned())
+    }
+}
+
+/// Run `f` on behalf of the object guarded by `poison`, failing with
+/// `Errno::NxIo` if it or the module is already poisoned
+///
+/// In the mock, a panic in `f` poisons both and fails with `Errno::Io`.
+/// In the kernel a panic never returns here: the panic handler poisons
+/// the module and parks the thread or panics the kernel, see the module
+/// docs
+pub fn catch_at_boundary<R>(
+    poison: &Poison,
+    f: impl FnOnce() -> R,
+) -> Result<R, Errno> {
+    if poison.is_poisoned() {
+        return Err(Errno::NxIo);
+    }
+    let result = catch_in_module(f);
+    if let Err(Errno::Io) = result {
+        poison.poison();
+    }
+    result
+}
+
+/// Run `f` for an entry point not tied to one object, such as a sysctl
+/// handler, failing with `Errno::NxIo` if the module is already
+/// poisoned. As with `catch_at_boundary`, only the mock turns a panic in
+/// `f` into `Errno::Io`
+pub fn catch_in_module<R>(f: impl FnOnce() -> R) -> Result<R, Errno> {
+    if MODULE.is_poisoned() {
+        return Err(Errno::NxIo);
+    }
+    catch(f).ok_or_else(|| {
+        MODULE.poison();
+        Errno::Io
+    })
+}
+
+#[cfg(feature = "mock")]
+fn catch<R>(f: impl FnOnce() -> R) -> Option<R> {
+    // Whatever `f` had borrowed is poisoned along with the object
+    std::panic::catch_unwind(core::panic::AssertUnwindSafe(f)).ok()
+}
+
+// Without an unwinder a panic ends in `handle`, which doesn't return
+#[cfg(not(feature = "mock"))]
+fn catch<R>(f: impl FnOnce() -> R) -> Option<R> {
+    Some(f())
+}
@@ -0,0 +1,380 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! PCI device drivers, see `pci(9)`
+//!
+//! A PCI driver is a `bus::Driver` declared on the `pci` bus, with
+//! `driver_module!(<driver>, pci, <type>)`. Its probe matches the device
+//! against a table of IDs, and attach maps the BARs holding its registers
+//! and allocates its interrupt vectors:
+//! ```rust,ignore
+//! static IDS: &[PciId] = &[
+//!     PciId::new(0x8086, 0x10d3, c"Intel 82574L"),
+//! ];
+//!
+//! fn probe(dev: Device) -> Result<i32, Errno> {
+//!     let id = PciDevice::new(dev)?.lookup(IDS).ok_or(Errno::NxIo)?;
+//!     dev.set_desc(id.desc);
+//!     Ok(priority::DEFAULT)
+//! }
+//!
+//! fn attach(dev: Device) -> Result<Self, Errno> {
+//!     let pci = PciDevice::new(dev)?;
+//!     pci.enable_busmaster()?;
+//!     let regs = pci.map_bar(0)?;
+//!     let msix = pci.alloc_msix(1)?;
+//!     ...
+//! }
+//! ```
+//! The methods are those of `pci_if.m`, called on the bus the device is
+//! on, as the `pci_*` inlines of `dev/pci/pcivar.h` do.
+
+use crate::bus::{self, Device, Resource};
+use crate::errno::Errno;
+use core::ffi::CStr;
+use core::ops::RangeInclusive;
+use core::{fmt, mem};
+use libc::c_int;
+
+/// A device ID a driver handles, as in its probe's table
+#[derive(Copy, Clone, Debug)]
+pub struct PciId {
+    pub vendor: u16,
+    pub device: u16,
+    /// The subsystem vendor and device, or any if `None`
+    pub subsystem: Option<(u16, u16)>,
+    /// What `Device::set_desc` describes the device as
+    pub desc: &'static CStr,
+}
+
+impl PciId {
+    /// Device `device` of `vendor`, whatever its subsystem
+    pub const fn new(vendor: u16, device: u16, desc: &'static CStr) -> Self {
+        PciId {
+            vendor,
+            device,
+            subsystem: None,
+            desc,
+        }
+    }
+
+    /// Device `device` of `vendor` in subsystem `subdevice` of
+    /// `subvendor`, for boards a driver treats differently
+    pub const fn with_subsystem(
+        vendor: u16,
+        device: u16,
+        subvendor: u16,
+        subdevice: u16,
+        desc: &'static CStr,
+    ) -> Self {
+        PciId {
+            vendor,
+            device,
+            subsystem: Some((subvendor, subdevice)),
+            desc,
+        }
+    }
+}
+
+/// The register of BAR `n` in config space, `PCIR_BAR()`, which is also
+/// its resource ID
+pub const fn bar_rid(n: usize) -> i32 {
+    kernel_sys::PCIR_BARS as i32 + n as i32 * 4
+}
+
+/// Call the `pci_if.m` method `$desc` describes on the bus below `$pci`,
+/// with `$pci`'s device and `$arg`s
+macro_rules! pci_call {
+    ($pci:expr, $desc:ident, $ty:ident $(, $arg:expr)*) => {{
+        let bus = $pci.bus;
+        let f: kernel_sys::$ty = unsafe {
+            mem::transmute(bus::method(bus, &raw mut kernel_sys::$desc))
+        };
+        unsafe { f.unwrap()(bus.as_ptr(), $pci.dev.as_ptr() $(, $arg)*) }
+    }};
+}
+
+/// A device on a PCI bus
+#[derive(Copy, Clone)]
+pub struct PciDevice {
+    dev: Device,
+    bus: Device,
+}
+
+impl PciDevice {
+    /// `dev`, failing with `Errno::NxIo` unless its parent is a PCI bus
+    pub fn new(dev: Device) -> Result<Self, Errno> {
+        let bus = dev.parent().ok_or(Errno::NxIo)?;
+        let pci = PciDevice { dev, bus };
+        // Other buses don't know the ivar
+        pci.ivar(kernel_sys::pci_device_ivars_PCI_IVAR_VENDOR)
+            .map_err(|_| Errno::NxIo)?;
+        Ok(pci)
+    }
+
+    pub fn device(&self) -> Device {
+        self.dev
+    }
+
+    fn ivar(
+        &self,
+        index: kernel_sys::pci_device_ivars,
+    ) -> Result<usize, Errno> {
+        self.dev.read_ivar(index as i32)
+    }
+
+    // The bus keeps these for every child, so reading them can't fail
+    fn id(&self, index: kernel_sys::pci_device_ivars) -> u16 {
+        self.ivar(index).unwrap_or(0xffff) as u16
+    }
+
+    pub fn vendor(&self) -> u16 {
+        self.id(kernel_sys::pci_device_ivars_PCI_IVAR_VENDOR)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.id(kernel_sys::pci_device_ivars_PCI_IVAR_DEVICE)
+    }
+
+    pub fn subvendor(&self) -> u16 {
+        self.id(kernel_sys::pci_device_ivars_PCI_IVAR_SUBVENDOR)
+    }
+
+    pub fn subdevice(&self) -> u16 {
+        self.id(kernel_sys::pci_device_ivars_PCI_IVAR_SUBDEVICE)
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.id(kernel_sys::pci_device_ivars_PCI_IVAR_REVID) as u8
+    }
+
+    /// The class, subclass and programming interface, as in the
+    /// `PCIC_*`, `PCIS_*` and `PCIP_*` constants
+    pub fn class(&self) -> (u8, u8, u8) {
+        (
+            self.id(kernel_sys::pci_device_ivars_PCI_IVAR_CLASS) as u8,
+            self.id(kernel_sys::pci_device_ivars_PCI_IVAR_SUBCLASS) as u8,
+            self.id(kernel_sys::pci_device_ivars_PCI_IVAR_PROGIF) as u8,
+        )
+    }
+
+    /// The first entry of `ids` matching the device, if any
+    pub fn lookup<'a>(&self, ids: &'a [PciId]) -> Option<&'a PciId> {
+        let (vendor, device) = (self.vendor(), self.device_id());
+        let subsystem = (self.subvendor(), self.subdevice());
+        ids.iter().find(|id| {
+            id.vendor == vendor
+                && id.device == device
+                && id.subsystem.is_none_or(|s| s == subsystem)
+        })
+    }
+
+    fn read_config(&self, reg: u32, width: c_int) -> u32 {
+        pci_call!(
+            self,
+            pci_read_config_desc,
+            pci_read_config_t,
+            reg as c_int,
+            width
+        )
+    }
+
+    fn write_config(&self, reg: u32, val: u32, width: c_int) {
+        pci_call!(
+            self,
+            pci_write_config_desc,
+            pci_write_config_t,
+            reg as c_int,
+            val,
+            width
+        )
+    }
+
+    /// The byte at `reg` of config space
+    pub fn read_config_1(&self, reg: u32) -> u8 {
+        self.read_config(reg, 1) as u8
+    }
+
+    /// The 16 bits at `reg` of config space, which must be aligned
+    pub fn read_config_2(&self, reg: u32) -> u16 {
+        self.read_config(reg, 2) as u16
+    }
+
+    /// The 32 bits at `reg` of config space, which must be aligned
+    pub fn read_config_4(&self, reg: u32) -> u32 {
+        self.read_config(reg, 4)
+    }
+
+    pub fn write_config_1(&self, reg: u32, val: u8) {
+        self.write_config(reg, val.into(), 1);
+    }
+
+    pub fn write_config_2(&self, reg: u32, val: u16) {
+        self.write_config(reg, val.into(), 2);
+    }
+
+    pub fn write_config_4(&self, reg: u32, val: u32) {
+        self.write_config(reg, val, 4);
+    }
+
+    /// Let the device master the bus, as DMA needs
+    pub fn enable_busmaster(&self) -> Result<(), Errno> {
+        Errno::result(pci_call!(
+            self,
+            pci_enable_busmaster_desc,
+            pci_enable_busmaster_t
+        ))
+    }
+
+    pub fn disable_busmaster(&self) -> Result<(), Errno> {
+        Errno::result(pci_call!(
+            self,
+            pci_disable_busmaster_desc,
+            pci_disable_busmaster_t
+        ))
+    }
+
+    /// Where capability `cap`, one of the `PCIY_*` constants, starts in
+    /// config space, if the device has it
+    pub fn find_cap(&self, cap: i32) -> Option<u32> {
+        let mut reg = 0;
+        let error =
+            pci_call!(self, pci_find_cap_desc, pci_find_cap_t, cap, &mut reg);
+        (error == 0).then_some(reg as u32)
+    }
+
+    /// Where PCIe extended capability `cap`, one of the `PCIZ_*`
+    /// constants, starts in config space, if the device has it
+    pub fn find_extcap(&self, cap: i32) -> Option<u32> {
+        let mut reg = 0;
+        let error = pci_call!(
+            self,
+            pci_find_extcap_desc,
+            pci_find_extcap_t,
+            cap,
+            &mut reg
+        );
+        (error == 0).then_some(reg as u32)
+    }
+
+    /// Map BAR `n`, memory or I/O ports as the BAR says, with
+    /// accesses checked against its size
+    pub fn map_bar(&self, n: usize) -> Result<Resource, Errno> {
+        if n > kernel_sys::PCIR_MAX_BAR_0 as usize {
+            return Err(Errno::Inval);
+        }
+        let rid = bar_rid(n);
+        let bar = self.read_config_4(rid as u32) as i32;
+        if bar & kernel_sys::PCIM_BAR_SPACE == kernel_sys::PCIM_BAR_IO_SPACE {
+            Resource::io_ports(self.dev, rid)
+        } else {
+            Resource::memory(self.dev, rid)
+        }
+    }
+
+    /// How many MSI messages the device supports
+    pub fn msi_count(&self) -> usize {
+        pci_call!(self, pci_msi_count_desc, pci_msi_count_t) as usize
+    }
+
+    /// How many MSI-X messages the device supports
+    pub fn msix_count(&self) -> usize {
+        pci_call!(self, pci_msix_count_desc, pci_msix_count_t) as usize
+    }
+
+    /// Allocate up to `count` MSI messages, a power of two. The system
+    /// may grant fewer
+    pub fn alloc_msi(&self, count: usize) -> Result<Msi, Errno> {
+        let mut n = count as c_int;
+        Errno::result(pci_call!(
+            self,
+            pci_alloc_msi_desc,
+            pci_alloc_msi_t,
+            &mut n
+        ))?;
+        Ok(Msi {
+            pci: *self,
+            count: n as usize,
+        })
+    }
+
+    /// Allocate up to `count` MSI-X messages. The system may grant fewer
+    pub fn alloc_msix(&self, count: usize) -> Result<Msi, Errno> {
+        let mut n = count as c_int;
+        Errno::result(pci_call!(
+            self,
+            pci_alloc_msix_desc,
+            pci_alloc_msix_t,
+            &mut n
+        ))?;
+        Ok(Msi {
+            pci: *self,
+            count: n as usize,
+        })
+    }
+}
+
+impl fmt::Debug for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PciDevice {{ dev: {:?}, id: {:04x}:{:04x} }}",
+            self.dev.nameunit(),
+            self.vendor(),
+            self.device_id()
+        )
+    }
+}
+
+/// A device's MSI or MSI-X messages, released when dropped
+///
+/// Each message is an IRQ resource, whose IDs `rids` gives. Those
+/// resources must be released first.
+pub struct Msi {
+    pci: PciDevice,
+    count: usize,
+}
+
+impl Msi {
+    /// How many messages were granted
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The resource IDs of the messages' IRQs
+    pub fn rids(&self) -> RangeInclusive<i32> {
+        1..=self.count as i32
+    }
+}
+
+impl Drop for Msi {
+    fn drop(&mut self) {
+        pci_call!(self.pci, pci_release_msi_desc, pci_release_msi_t);
+    }
+}
+
+impl fmt::Debug for Msi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Msi {{ count: {} }}", self.count)
+    }
+}
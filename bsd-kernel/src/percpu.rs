@@ -0,0 +1,132 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Per-CPU data, as `DPCPU_DEFINE` gives C code
+//!
+//! A `PerCpu` keeps a value for each CPU, each on cache lines of its own,
+//! so CPUs updating theirs don't contend. It suits caches of free items
+//! and statistics richer than a `Counter`. A thread uses the value of the
+//! CPU it is running on with `with_current`, which keeps it there, and
+//! interrupt filters from it:
+//!
+//! ```rust,ignore
+//! let stats = PerCpu::new(|_| Stats::default());
+//! // In the I/O path
+//! stats.with_current(|s| s.record(bp));
+//! // To report, each CPU adds its own
+//! let bytes = AtomicU64::new(0);
+//! stats.for_each(|_, s| {
+//!     bytes.fetch_add(s.bytes, Ordering::Relaxed);
+//! });
+//! ```
+//!
+//! The values are in `malloc(9)` memory, as modules can't add to the
+//! kernel's DPCPU area once it is laid out, and are indexed by `curcpu`.
+
+use crate::sched::SpinlockSection;
+use crate::smp;
+use alloc::boxed::Box;
+use core::cell::{Cell, UnsafeCell};
+use core::fmt;
+
+/// A value on cache lines of its own, `CACHE_LINE_SIZE` from
+/// `machine/param.h`
+#[cfg_attr(target_arch = "aarch64", repr(align(128)))]
+#[cfg_attr(not(target_arch = "aarch64"), repr(align(64)))]
+struct Slot<T> {
+    value: UnsafeCell<T>,
+    busy: Cell<bool>,
+}
+
+/// A `T` for each CPU
+pub struct PerCpu<T> {
+    slots: Box<[Slot<T>]>,
+}
+
+// Each value is only used on its own CPU, with interrupts disabled, or
+// through `&mut self`, as a Mutex's value is used by one thread at a time
+unsafe impl<T: Send> Send for PerCpu<T> {}
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// A value for each CPU ID up to `mp_maxid`, made by `init` from the
+    /// ID. IDs may be sparse, and those of absent CPUs are made but unused
+    pub fn new(mut init: impl FnMut(usize) -> T) -> Self {
+        let slots = (0..=smp::max_cpu_id())
+            .map(|cpu| Slot {
+                value: UnsafeCell::new(init(cpu)),
+                busy: Cell::new(false),
+            })
+            .collect();
+        PerCpu { slots }
+    }
+
+    /// Run `f` on the current CPU's value, in a `SpinlockSection` so the
+    /// thread stays on the CPU and its interrupt filters wait. `f` must be
+    /// short, and must not sleep
+    ///
+    /// ## Panics
+    /// If `f` uses the same `PerCpu` again
+    pub fn with_current<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let section = SpinlockSection::enter();
+        let slot = &self.slots[section.cpu()];
+        // Nothing else on this CPU can run until the section ends, but
+        // `f` itself
+        assert!(!slot.busy.replace(true), "PerCpu used reentrantly");
+        let r = f(unsafe { &mut *slot.value.get() });
+        slot.busy.set(false);
+        r
+    }
+
+    /// Run `f` on every present CPU at once, each with its own value, in
+    /// an `smp_rendezvous(9)` that spins every CPU with interrupts
+    /// disabled until the last is done, so `f` may take spin mutexes but
+    /// no others
+    pub fn for_each<F>(&self, f: F)
+    where
+        T: Send,
+        F: Fn(usize, &mut T) + Sync,
+    {
+        smp::rendezvous(|| {
+            let cpu = smp::current_cpu();
+            self.with_current(|value| f(cpu, value));
+        });
+    }
+
+    /// Each CPU ID's value, for when nothing else can be using them
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        self.slots.iter_mut().map(|s| s.value.get_mut()).enumerate()
+    }
+}
+
+impl<T: Default> Default for PerCpu<T> {
+    fn default() -> Self {
+        PerCpu::new(|_| T::default())
+    }
+}
+
+impl<T> fmt::Debug for PerCpu<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PerCpu {{ cpus: {} }}", self.slots.len())
+    }
+}
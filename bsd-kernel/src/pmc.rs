@@ -0,0 +1,133 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Software events for `hwpmc(4)`
+//!
+//! A module counts the things it cares about, such as cache hits or
+//! stalled queues, as software PMC events: `pmcstat -S` samples them
+//! and records the callchain at each, alongside the hardware events,
+//! and `pmccontrol -L` lists them among the `SOFT` class. Events cost a
+//! single load each time they fire while nothing samples them:
+//! ```ignore
+//! static STALLS: SoftEvent = SoftEvent::new(c"RUSTFIFO_QUEUE_READ.stall");
+//!
+//! // in the module's load and unload events
+//! STALLS.register();
+//! STALLS.unregister();
+//!
+//! STALLS.fire();
+//! ```
+//! The kernel needs `options HWPMC_HOOKS`, as `GENERIC` has.
+
+use crate::sched::SpinlockSection;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::{fmt, mem, ptr};
+
+/// A software event, as `PMC_SOFT_DEFINE` declares in C
+pub struct SoftEvent(UnsafeCell<kernel_sys::pmc_soft>);
+
+// hwpmc sets `ps_running` and the event code, under its own locks
+unsafe impl Sync for SoftEvent {}
+
+impl SoftEvent {
+    /// An event named like `PMC_SOFT_DEFINE`'s, `PROV_MOD_FUNC.NAME`,
+    /// which must fit in `PMC_NAME_MAX`
+    pub const fn new(name: &'static CStr) -> Self {
+        let mut ps: kernel_sys::pmc_soft = unsafe { mem::zeroed() };
+        let name = name.to_bytes();
+        let len = ps.ps_ev.pm_ev_name.len();
+        assert!(name.len() < len, "event name too long");
+        let mut i = 0;
+        while i < name.len() {
+            ps.ps_ev.pm_ev_name[i] = name[i] as libc::c_char;
+            i += 1;
+        }
+        SoftEvent(UnsafeCell::new(ps))
+    }
+
+    /// Make the event known to hwpmc, for the module's load event
+    pub fn register(&'static self) {
+        unsafe { kernel_sys::pmc_soft_ev_register(self.0.get()) };
+    }
+
+    /// Withdraw the event, stopping any sampling of it
+    pub fn unregister(&'static self) {
+        unsafe { kernel_sys::pmc_soft_ev_deregister(self.0.get()) };
+    }
+
+    /// Whether anything is sampling the event
+    pub fn is_running(&self) -> bool {
+        unsafe {
+            ptr::read_volatile(&raw const (*self.0.get()).ps_running) != 0
+        }
+    }
+
+    /// Count an occurrence of the event, with the caller's callchain
+    /// for samples, as `PMC_SOFT_CALL`
+    #[inline(always)]
+    pub fn fire(&self) {
+        if self.is_running() {
+            self.sample();
+        }
+    }
+
+    #[inline(always)]
+    fn sample(&self) {
+        let section = SpinlockSection::enter();
+        let cpu = section.cpu();
+        unsafe {
+            let tf = &raw mut kernel_sys::pmc_tf[cpu];
+            crate::arch::fake_trapframe(&mut *tf);
+            let mut ks = kernel_sys::pmckern_soft {
+                pm_ev: (*self.0.get()).ps_ev.pm_ev_code,
+                pm_cpu: cpu as libc::c_int,
+                pm_tf: tf,
+            };
+            // PMC_CALL_HOOK_UNLOCKED()
+            if let Some(hook) =
+                ptr::read_volatile(&raw const kernel_sys::pmc_hook)
+            {
+                hook(
+                    crate::arch::curthread(),
+                    kernel_sys::PMC_FN_SOFT_SAMPLING,
+                    &mut ks as *mut _ as *mut libc::c_void,
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SoftEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = unsafe {
+            CStr::from_ptr((*self.0.get()).ps_ev.pm_ev_name.as_ptr())
+        };
+        write!(
+            f,
+            "SoftEvent {{ name: {:?}, running: {} }}",
+            name,
+            self.is_running()
+        )
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Processes, `struct proc`
+//!
+//! Code handed a process, such as a `pseudofs` node's fill function, is
+//! given a `&Proc` that the kernel holds, so it stays put but may be
+//! exiting. Most of what it says is read with its lock held:
+//!
+//! ```rust,ignore
+//! let p = proc.lock();
+//! writeln!(sb, "{:?} {} uid {}", p.comm(), proc.pid(), p.cred().ruid())?;
+//! drop(p);
+//! // Sleeps, so without the lock
+//! proc.args(sb)?;
+//! ```
+
+use crate::cred::{Credential, CurThread};
+use crate::errno::Errno;
+use crate::sbuf::SbufRef;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::fmt;
+use core::ptr;
+
+/// What stage of its life a process is at, `p_state`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    /// Being forked
+    New,
+    Normal,
+    /// Exited, and waiting to be reaped by its parent
+    Zombie,
+}
+
+/// A process, held by whoever lent it out
+#[repr(transparent)]
+pub struct Proc(UnsafeCell<kernel_sys::proc_>);
+
+impl Proc {
+    /// Borrow the process at `p`
+    ///
+    /// ## Safety
+    ///
+    /// `p` must be valid and held, by `PHOLD` or otherwise, for `'a`
+    pub unsafe fn from_raw<'a>(p: *mut kernel_sys::proc_) -> &'a Self {
+        unsafe { &*p.cast() }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::proc_ {
+        self.0.get()
+    }
+
+    /// The process ID, which doesn't change
+    pub fn pid(&self) -> i32 {
+        unsafe { (*self.as_ptr()).p_pid }
+    }
+
+    /// Take the process lock, `PROC_LOCK`, for what it protects
+    pub fn lock(&self) -> ProcGuard<'_> {
+        let mtx = unsafe { &raw mut (*self.as_ptr()).p_mtx.mtx_lock };
+        unsafe { kernel_sys::_mtx_lock_flags(mtx, 0, ptr::null(), 0) };
+        ProcGuard { proc: self }
+    }
+
+    /// Append the process's arguments, each followed by a NUL as in
+    /// `kern.proc.args`, if the current thread may see the process.
+    /// Kernel processes and those exiting have none. Sleeps, so the lock
+    /// must not be held
+    pub fn args(&self, sb: &mut SbufRef) -> Result<(), Errno> {
+        let td = CurThread::get();
+        let p = self.lock();
+        p.can_see(&td)?;
+        let args = unsafe { (*self.as_ptr()).p_args };
+        if !args.is_null() {
+            // Cached at exec when short enough, and kept until the next
+            let args = unsafe {
+                let len = (*args).ar_length as usize;
+                let data = &raw const (*args).ar_args;
+                core::slice::from_raw_parts(data.cast::<u8>(), len)
+            };
+            return sb.cat(args);
+        }
+        let flags = (kernel_sys::P_SYSTEM | kernel_sys::P_WEXIT) as i32;
+        if p.flags() & flags != 0 {
+            return Ok(());
+        }
+        drop(p);
+        // Reads them from the process's memory
+        Errno::result(unsafe {
+            kernel_sys::proc_getargv(td.as_ptr(), self.as_ptr(), sb.as_ptr())
+        })
+    }
+}
+
+impl fmt::Debug for Proc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Proc {{ pid: {} }}", self.pid())
+    }
+}
+
+/// A process with its lock held, released on drop. It must not be held
+/// across anything that sleeps
+pub struct ProcGuard<'a> {
+    proc: &'a Proc,
+}
+
+impl ProcGuard<'_> {
+    fn raw(&self) -> *mut kernel_sys::proc_ {
+        self.proc.as_ptr()
+    }
+
+    /// The name of the program it runs, `p_comm`
+    pub fn comm(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.raw()).p_comm.as_ptr()) }
+    }
+
+    /// Its parent's process ID, or 0 for the first process
+    pub fn ppid(&self) -> i32 {
+        let parent = unsafe { (*self.raw()).p_pptr };
+        if parent.is_null() {
+            0
+        } else {
+            unsafe { (*parent).p_pid }
+        }
+    }
+
+    pub fn state(&self) -> State {
+        match unsafe { (*self.raw()).p_state } {
+            kernel_sys::p_states_PRS_NEW => State::New,
+            kernel_sys::p_states_PRS_ZOMBIE => State::Zombie,
+            _ => State::Normal,
+        }
+    }
+
+    /// The `P_*` flags of `sys/proc.h`
+    pub fn flags(&self) -> i32 {
+        unsafe { (*self.raw()).p_flag }
+    }
+
+    /// How many threads it has
+    pub fn threads(&self) -> i32 {
+        unsafe { (*self.raw()).p_numthreads }
+    }
+
+    /// The credentials it runs with. Its threads each have their own
+    /// reference, and pick up changes when next they enter the kernel
+    pub fn cred(&self) -> &Credential {
+        unsafe { Credential::from_raw((*self.raw()).p_ucred) }
+    }
+
+    /// `p_cansee`: whether `td` may see the process, failing with
+    /// `Errno::Srch` or another error if not
+    pub fn can_see(&self, td: &CurThread) -> Result<(), Errno> {
+        Errno::result(unsafe { kernel_sys::p_cansee(td.as_ptr(), self.raw()) })
+    }
+}
+
+impl core::ops::Deref for ProcGuard<'_> {
+    type Target = Proc;
+
+    fn deref(&self) -> &Proc {
+        self.proc
+    }
+}
+
+impl Drop for ProcGuard<'_> {
+    fn drop(&mut self) {
+        let mtx = unsafe { &raw mut (*self.raw()).p_mtx.mtx_lock };
+        unsafe { kernel_sys::_mtx_unlock_flags(mtx, 0, ptr::null(), 0) };
+    }
+}
+
+impl fmt::Debug for ProcGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ProcGuard {{ pid: {}, comm: {:?}, cred: {:?} }}",
+            self.pid(),
+            self.comm(),
+            self.cred()
+        )
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Synthetic filesystems on `pseudofs(9)`, like `procfs(4)` and
+//! `linprocfs(4)`
+//!
+//! A `PseudoFs` is a tree of files whose contents are made up by a
+//! closure each time they are read. Under a process directory, files
+//! exist once for each process the reader can see, named by pid, and
+//! their closures are passed the process:
+//! ```ignore
+//! let fs = PseudoFs::new(c"procinfo");
+//! fs.register()?;
+//! let root = fs.root();
+//! root.file(c"hello", |_td, _p, sb| sb.cat(b"hello\n"))?;
+//! let pid = root.proc_dir()?;
+//! pid.file(c"cmdline", |_td, p, sb| p.unwrap().args(sb))?;
+//! ```
+//! The tree is made by `register` and torn down by `unregister`, which
+//! fails with `Errno::Busy` while the filesystem is mounted. Nodes can be
+//! added in between, while it is mounted or not. Files are read-only, and
+//! their owner is that of the process they belong to, or root.
+//!
+//! A module using this must depend on `pseudofs`, which provides it:
+//! `MODULE_DEPEND(name, pseudofs, 1, 1, 1);` in its C file.
+
+use crate::cred::CurThread;
+use crate::errno::Errno;
+use crate::panic::{Poison, catch_at_boundary};
+use crate::proc::Proc;
+use crate::sbuf::SbufRef;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::{fmt, mem, ptr};
+use libc::{c_int, c_void};
+
+/// The closure of a file or link
+type Fill = dyn Fn(&CurThread, Option<&Proc>, &mut SbufRef) -> Result<(), Errno>
+    + Send
+    + Sync;
+
+/// A registered `pseudofs` filesystem type
+///
+/// The kernel keeps pointers to the tables inside, so it is only ever
+/// handed out boxed.
+#[repr(C)]
+pub struct PseudoFs {
+    // First, so callbacks can get from a vfsconf back to the PseudoFs
+    conf: UnsafeCell<kernel_sys::vfsconf>,
+    ops: UnsafeCell<kernel_sys::vfsops>,
+    info: UnsafeCell<kernel_sys::pfs_info>,
+    poison: Poison,
+}
+
+unsafe impl Send for PseudoFs {}
+unsafe impl Sync for PseudoFs {}
+
+impl PseudoFs {
+    /// Create the filesystem type `name`, not yet known to the kernel
+    pub fn new(name: &'static CStr) -> Box<Self> {
+        let mut ops: kernel_sys::vfsops = unsafe { mem::zeroed() };
+        ops.vfs_cmount = Some(kernel_sys::pfs_cmount);
+        ops.vfs_init = Some(vfs_init);
+        ops.vfs_mount = Some(vfs_mount);
+        ops.vfs_root = Some(kernel_sys::pfs_root);
+        ops.vfs_statfs = Some(kernel_sys::pfs_statfs);
+        ops.vfs_uninit = Some(vfs_uninit);
+        ops.vfs_unmount = Some(kernel_sys::pfs_unmount);
+        let fs = Box::new(PseudoFs {
+            conf: UnsafeCell::new(unsafe { mem::zeroed() }),
+            ops: UnsafeCell::new(ops),
+            info: UnsafeCell::new(unsafe { mem::zeroed() }),
+            poison: Poison::new(),
+        });
+        let conf = unsafe { &mut *fs.conf.get() };
+        let info = unsafe { &mut *fs.info.get() };
+        let name = name.to_bytes();
+        assert!(
+            name.len() < conf.vfc_name.len().min(info.pi_name.len()),
+            "filesystem name too long"
+        );
+        for (c, &b) in conf.vfc_name.iter_mut().zip(name) {
+            *c = b as _;
+        }
+        for (c, &b) in info.pi_name.iter_mut().zip(name) {
+            *c = b as _;
+        }
+        conf.vfc_version = kernel_sys::VFS_VERSION as _;
+        conf.vfc_vfsops = fs.ops.get();
+        conf.vfc_flags = kernel_sys::VFCF_SYNTHETIC as _;
+        // Called with the tree, which is built after instead
+        info.pi_init = Some(pfs_noop);
+        info.pi_uninit = Some(pfs_noop);
+        fs
+    }
+
+    /// Make the type available to `mount(8)`, with an empty root
+    /// directory. Call from the module's load event
+    pub fn register(&self) -> Result<(), Errno> {
+        Errno::result(unsafe { kernel_sys::vfs_register(self.conf.get()) })
+    }
+
+    /// Withdraw the type and destroy the tree. Fails with `Errno::Busy`,
+    /// leaving both, while the filesystem is mounted
+    pub fn unregister(&self) -> Result<(), Errno> {
+        Errno::result(unsafe { kernel_sys::vfs_unregister(self.conf.get()) })
+    }
+
+    /// The root directory, once registered
+    pub fn root(&self) -> Dir<'_> {
+        let pn = unsafe { (*self.info.get()).pi_root };
+        assert!(!pn.is_null(), "pseudofs not registered");
+        Dir { pn, fs: self }
+    }
+
+    /// Whether a closure has panicked, after which new mounts and reads
+    /// are refused with `Errno::NxIo`
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    /// Raw pointer to the underlying vfsconf
+    pub fn as_ptr(&self) -> *mut kernel_sys::vfsconf {
+        self.conf.get()
+    }
+
+    /// ## Safety
+    /// `vfc` must be the vfsconf of a `PseudoFs`
+    unsafe fn from_conf<'a>(vfc: *mut kernel_sys::vfsconf) -> &'a Self {
+        unsafe { &*(vfc as *const Self) }
+    }
+
+    /// ## Safety
+    /// `pi` must be the pfs_info of a `PseudoFs`
+    unsafe fn from_info<'a>(pi: *mut kernel_sys::pfs_info) -> &'a Self {
+        let offset = mem::offset_of!(Self, info);
+        unsafe { &*((pi as *mut u8).sub(offset) as *const Self) }
+    }
+}
+
+impl fmt::Debug for PseudoFs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name =
+            unsafe { CStr::from_ptr((*self.conf.get()).vfc_name.as_ptr()) };
+        write!(f, "PseudoFs {{ name: {:?} }}", name)
+    }
+}
+
+/// A directory of a `PseudoFs`, to add nodes to
+#[derive(Copy, Clone)]
+pub struct Dir<'a> {
+    pn: *mut kernel_sys::pfs_node,
+    fs: &'a PseudoFs,
+}
+
+impl<'a> Dir<'a> {
+    /// Add the directory `name`
+    pub fn dir(&self, name: &CStr) -> Result<Dir<'a>, Errno> {
+        self.add_dir(name, 0)
+    }
+
+    /// Add the process directory, listed as one directory for each
+    /// process the reader can see, named by its pid. What is added to it
+    /// is added to each. There can be one in a directory, not within
+    /// another
+    pub fn proc_dir(&self) -> Result<Dir<'a>, Errno> {
+        self.add_dir(c"pid", kernel_sys::PFS_PROCDEP as c_int)
+    }
+
+    /// Add the file `name`, whose contents `fill` makes each time it is
+    /// read. It is passed the thread reading, the process when under a
+    /// process directory, and the buffer to fill, which `pseudofs` sizes
+    /// to what is being read: running out of room stops `fill` early but
+    /// isn't an error
+    pub fn file<F>(&self, name: &CStr, fill: F) -> Result<(), Errno>
+    where
+        F: Fn(&CurThread, Option<&Proc>, &mut SbufRef) -> Result<(), Errno>
+            + Send
+            + Sync
+            + 'static,
+    {
+        check_name(name)?;
+        let mut pn = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::pfs_create_file(
+                self.pn,
+                &mut pn,
+                name.as_ptr(),
+                Some(pfs_fill),
+                None,
+                None,
+                Some(pfs_destroy),
+                kernel_sys::PFS_RD as c_int,
+            )
+        })?;
+        unsafe { set_fill(pn, Box::new(fill)) };
+        Ok(())
+    }
+
+    /// Add the symbolic link `name`, whose target `target` writes each
+    /// time it is followed
+    pub fn link<F>(&self, name: &CStr, target: F) -> Result<(), Errno>
+    where
+        F: Fn(&CurThread, Option<&Proc>, &mut SbufRef) -> Result<(), Errno>
+            + Send
+            + Sync
+            + 'static,
+    {
+        check_name(name)?;
+        let mut pn = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::pfs_create_link(
+                self.pn,
+                &mut pn,
+                name.as_ptr(),
+                Some(pfs_fill),
+                None,
+                None,
+                Some(pfs_destroy),
+                0,
+            )
+        })?;
+        unsafe { set_fill(pn, Box::new(target)) };
+        Ok(())
+    }
+
+    /// Raw pointer to the underlying pfs_node
+    pub fn as_ptr(&self) -> *mut kernel_sys::pfs_node {
+        self.pn
+    }
+
+    fn add_dir(&self, name: &CStr, flags: c_int) -> Result<Dir<'a>, Errno> {
+        check_name(name)?;
+        let mut pn = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::pfs_create_dir(
+                self.pn,
+                &mut pn,
+                name.as_ptr(),
+                None,
+                None,
+                None,
+                flags,
+            )
+        })?;
+        Ok(Dir { pn, fs: self.fs })
+    }
+}
+
+impl fmt::Debug for Dir<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = unsafe { CStr::from_ptr((*self.pn).pn_name.as_ptr()) };
+        write!(f, "Dir {{ name: {:?} }}", name)
+    }
+}
+
+fn check_name(name: &CStr) -> Result<(), Errno> {
+    match name.to_bytes().len() {
+        0 => Err(Errno::Inval),
+        n if n >= kernel_sys::PFS_NAMELEN as usize => Err(Errno::NameTooLong),
+        _ => Ok(()),
+    }
+}
+
+/// The node's `pn_data`, which holds its closure
+///
+/// ## Safety
+/// `pn` must be a live node
+unsafe fn data<'a>(pn: *mut kernel_sys::pfs_node) -> &'a AtomicPtr<c_void> {
+    unsafe { AtomicPtr::from_ptr(&raw mut (*pn).pn_data) }
+}
+
+/// Give the node made just now its closure. It is already in the tree, so
+/// a read that gets there first finds none and the node empty
+///
+/// ## Safety
+/// `pn` must be a node made with `pfs_fill` and `pfs_destroy`
+unsafe fn set_fill(pn: *mut kernel_sys::pfs_node, fill: Box<Fill>) {
+    let fill = Box::into_raw(Box::new(fill)) as *mut c_void;
+    unsafe { data(pn) }.store(fill, Ordering::Release);
+}
+
+unsafe extern "C" fn pfs_fill(
+    td: *mut kernel_sys::thread,
+    p: *mut kernel_sys::proc_,
+    pn: *mut kernel_sys::pfs_node,
+    sb: *mut kernel_sys::sbuf,
+    _uio: *mut kernel_sys::uio,
+) -> c_int {
+    let fs = unsafe { PseudoFs::from_info((*pn).pn_info) };
+    let fill = unsafe { data(pn) }.load(Ordering::Acquire) as *const Box<Fill>;
+    if fill.is_null() {
+        return 0;
+    }
+    // Held, and unlocked, by pseudofs for the call
+    let p = (!p.is_null()).then(|| unsafe { Proc::from_raw(p) });
+    let td = unsafe { CurThread::from_raw(td) };
+    let mut sb = unsafe { SbufRef::from_raw(sb) };
+    match catch_at_boundary(&fs.poison, || unsafe { (*fill)(&td, p, &mut sb) })
+    {
+        Ok(Ok(())) => 0,
+        // The buffer ran out, but holds all that is being read
+        Ok(Err(_)) if sb.error().is_err() => 0,
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn pfs_destroy(pn: *mut kernel_sys::pfs_node) -> c_int {
+    let fill = unsafe { data(pn) }.swap(ptr::null_mut(), Ordering::Acquire);
+    if !fill.is_null() {
+        drop(unsafe { Box::from_raw(fill as *mut Box<Fill>) });
+    }
+    0
+}
+
+unsafe extern "C" fn pfs_noop(
+    _pi: *mut kernel_sys::pfs_info,
+    _vfc: *mut kernel_sys::vfsconf,
+) -> c_int {
+    0
+}
+
+unsafe extern "C" fn vfs_init(vfc: *mut kernel_sys::vfsconf) -> c_int {
+    let fs = unsafe { PseudoFs::from_conf(vfc) };
+    unsafe { kernel_sys::pfs_init(fs.info.get(), vfc) }
+}
+
+unsafe extern "C" fn vfs_uninit(vfc: *mut kernel_sys::vfsconf) -> c_int {
+    let fs = unsafe { PseudoFs::from_conf(vfc) };
+    unsafe { kernel_sys::pfs_uninit(fs.info.get(), vfc) }
+}
+
+unsafe extern "C" fn vfs_mount(mp: *mut kernel_sys::mount) -> c_int {
+    let fs = unsafe { PseudoFs::from_conf((*mp).mnt_vfc) };
+    if fs.is_poisoned() {
+        return Errno::NxIo.as_raw();
+    }
+    unsafe { kernel_sys::pfs_mount(fs.info.get(), mp) }
+}
@@ -0,0 +1,97 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The kernel's random number generator, see `random(9)`
+//!
+//! Random bytes come from `arc4random(9)`, which is seeded from the
+//! `random(4)` entropy device and suits keys, nonces and IDs alike:
+//!
+//! ```rust,ignore
+//! let mut nonce = [0u8; 12];
+//! random::fill_bytes(&mut nonce);
+//! let id = random::u64();
+//! ```
+//!
+//! With the `rand_core` feature, `KernelRng` is a `rand_core::RngCore` and
+//! `CryptoRng`, for crates that take a generator.
+
+/// Fill `buf` with random bytes
+pub fn fill_bytes(buf: &mut [u8]) {
+    unsafe { kernel_sys::arc4random_buf(buf.as_mut_ptr().cast(), buf.len()) };
+}
+
+/// A random `u32`
+pub fn u32() -> u32 {
+    unsafe { kernel_sys::arc4random() }
+}
+
+/// A random `u64`
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill_bytes(&mut bytes);
+    u64::from_ne_bytes(bytes)
+}
+
+/// Whether `random(4)` has gathered enough entropy to be seeded. Early in
+/// boot it may not have, and `arc4random` is then seeded weakly
+pub fn is_seeded() -> bool {
+    unsafe { kernel_sys::is_random_seeded() }
+}
+
+/// `read_random`: fill `buf` straight from the entropy device, returning
+/// how many bytes were read. None are until it is seeded
+pub fn read_random(buf: &mut [u8]) -> usize {
+    let len = buf.len().min(u32::MAX as usize) as u32;
+    let read = unsafe { kernel_sys::read_random(buf.as_mut_ptr().cast(), len) };
+    read as usize
+}
+
+/// The kernel's generator, as a `rand_core` one
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KernelRng;
+
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for KernelRng {
+    fn next_u32(&mut self) -> u32 {
+        u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        fill_bytes(dest);
+    }
+
+    fn try_fill_bytes(
+        &mut self,
+        dest: &mut [u8],
+    ) -> Result<(), rand_core::Error> {
+        fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::CryptoRng for KernelRng {}
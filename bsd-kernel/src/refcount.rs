@@ -0,0 +1,335 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Reference counts, `refcount(9)`, and shared ownership built on them
+//!
+//! `Refcount` counts as the kernel's inline `refcount_acquire` and
+//! `refcount_release` do, in the same `u_int`, so it also works on the
+//! counts of kernel structures through `Refcount::from_ptr`. A count that
+//! overflows or is released below zero saturates: it stops changing and
+//! what it counts leaks, rather than being freed while still in use.
+//!
+//! `KArc` is an `Arc` in `malloc(9)` memory with such a count, for state a
+//! device's callbacks and a kernel thread share. `KArc::into_raw` turns a
+//! reference into a pointer for C to hold, such as a cdev's `si_drv1` or a
+//! callback's argument:
+//!
+//! ```rust,ignore
+//! let state = KArc::new(State::new());
+//! let worker = state.clone();
+//! kthread::spawn(c"rustworker", move |stop| worker.run(stop))?;
+//! ```
+//!
+//! The `atomic(9)` operations are `core::sync::atomic`'s, which compile to
+//! the same instructions: `atomic_load_acq_int` is `load(Acquire)`,
+//! `atomic_fetchadd_int` is `fetch_add(_, Relaxed)`, `atomic_cmpset_int`
+//! is `compare_exchange`, and `atomic_thread_fence_rel` is
+//! `fence(Release)`.
+
+use crate::allocator::{KernelAllocator, Wait};
+use alloc::boxed::Box;
+use core::alloc::AllocError;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering, fence};
+use core::{fmt, mem};
+
+/// from `sys/refcount.h`
+/// ```c,ignore
+/// #define REFCOUNT_SATURATED(val)    (((val) & (1U << 31)) != 0)
+/// #define REFCOUNT_SATURATION_VALUE  (3U << 30)
+/// ```
+const SATURATED: u32 = 1 << 31;
+const SATURATION_VALUE: u32 = 3 << 30;
+
+/// A reference count, as a `volatile u_int` managed by `refcount(9)`
+#[repr(transparent)]
+pub struct Refcount(AtomicU32);
+
+impl Refcount {
+    /// `refcount_init`: a count of `count`
+    pub const fn new(count: u32) -> Self {
+        Refcount(AtomicU32::new(count))
+    }
+
+    /// The count at `ptr`, such as a kernel structure's
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be aligned and valid for `'a`, and only be changed
+    /// atomically meanwhile
+    pub unsafe fn from_ptr<'a>(ptr: *mut u32) -> &'a Refcount {
+        unsafe { &*ptr.cast() }
+    }
+
+    /// `refcount_load`: the count, which may change as soon as it is read
+    pub fn load(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Whether the count overflowed or was released too often, and so no
+    /// longer changes
+    pub fn is_saturated(&self) -> bool {
+        self.load() & SATURATED != 0
+    }
+
+    /// `refcount_acquire`: take a reference, from one already held
+    pub fn acquire(&self) {
+        let old = self.0.fetch_add(1, Ordering::Relaxed);
+        if old & SATURATED != 0 {
+            self.saturate();
+        }
+    }
+
+    /// `refcount_acquire_if_not_zero`: take a reference unless the last
+    /// one is gone, as when looking up what may be being destroyed
+    pub fn acquire_if_not_zero(&self) -> bool {
+        let mut old = self.0.load(Ordering::Relaxed);
+        loop {
+            if old == 0 {
+                return false;
+            }
+            if old & SATURATED != 0 {
+                return true;
+            }
+            match self.0.compare_exchange_weak(
+                old,
+                old + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(v) => old = v,
+            }
+        }
+    }
+
+    /// `refcount_release`: drop a reference, returning whether it was the
+    /// last, whose holder destroys what was counted
+    pub fn release(&self) -> bool {
+        // Earlier accesses by this holder happen before the destruction
+        let old = self.0.fetch_sub(1, Ordering::Release);
+        if old == 0 || old & SATURATED != 0 {
+            self.saturate();
+            return false;
+        }
+        if old > 1 {
+            return false;
+        }
+        // And so do all other holders' accesses
+        fence(Ordering::Acquire);
+        true
+    }
+
+    /// `refcount_release_if_not_last`: drop a reference unless it is the
+    /// last, for a holder that must destroy what was counted elsewhere
+    pub fn release_if_not_last(&self) -> bool {
+        let mut old = self.0.load(Ordering::Relaxed);
+        loop {
+            if old <= 1 {
+                return false;
+            }
+            if old & SATURATED != 0 {
+                return true;
+            }
+            match self.0.compare_exchange_weak(
+                old,
+                old - 1,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(v) => old = v,
+            }
+        }
+    }
+
+    // As `_refcount_update_saturated` without INVARIANTS, which panics
+    // with them: pin the count far from both limits
+    #[cold]
+    fn saturate(&self) {
+        self.0.store(SATURATION_VALUE, Ordering::Relaxed);
+    }
+
+    pub fn as_ptr(&self) -> *mut u32 {
+        self.0.as_ptr()
+    }
+}
+
+impl fmt::Debug for Refcount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Refcount {{ count: {} }}", self.load())
+    }
+}
+
+struct Inner<T> {
+    refs: Refcount,
+    value: T,
+}
+
+/// A `T` in `malloc(9)` memory, dropped with the last reference to it
+pub struct KArc<T> {
+    inner: NonNull<Inner<T>>,
+    _value: PhantomData<Inner<T>>,
+}
+
+// As Arc: references on other threads share the value and may drop it
+unsafe impl<T: Send + Sync> Send for KArc<T> {}
+unsafe impl<T: Send + Sync> Sync for KArc<T> {}
+
+impl<T> KArc<T> {
+    /// Share `value`, sleeping for memory if needed
+    pub fn new(value: T) -> Self {
+        Self::with_wait(value, Wait::WaitOk).unwrap()
+    }
+
+    /// Share `value`, failing rather than sleeping for memory
+    pub fn try_new(value: T) -> Result<Self, AllocError> {
+        Self::with_wait(value, Wait::NoWait)
+    }
+
+    fn with_wait(value: T, wait: Wait) -> Result<Self, AllocError> {
+        let inner = Inner {
+            refs: Refcount::new(1),
+            value,
+        };
+        let b = Box::try_new_in(inner, KernelAllocator::new().wait(wait))?;
+        let (ptr, _) = Box::into_raw_with_allocator(b);
+        Ok(KArc {
+            inner: unsafe { NonNull::new_unchecked(ptr) },
+            _value: PhantomData,
+        })
+    }
+
+    /// The number of references, which may change as soon as it is read
+    pub fn count(this: &Self) -> u32 {
+        this.inner().refs.load()
+    }
+
+    /// The value, if this is the only reference to it
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if this.inner().refs.load() == 1 {
+            // Synchronize with the release of the other references
+            fence(Ordering::Acquire);
+            Some(unsafe { &mut (*this.inner.as_ptr()).value })
+        } else {
+            None
+        }
+    }
+
+    /// The value, if this is the only reference to it
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        let refs = &this.inner().refs.0;
+        if refs
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(this);
+        }
+        let ptr = this.inner.as_ptr();
+        mem::forget(this);
+        let b = unsafe { Box::from_raw_in(ptr, KernelAllocator::new()) };
+        Ok(b.value)
+    }
+
+    /// Whether both are references to the same value
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.inner == other.inner
+    }
+
+    /// The reference as a pointer to the value, for C to hold until it
+    /// is passed to `from_raw`
+    pub fn into_raw(this: Self) -> *const T {
+        let ptr = Self::as_ptr(&this);
+        mem::forget(this);
+        ptr
+    }
+
+    /// A reference back from `into_raw`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be from `KArc::<T>::into_raw`, and passed here once for
+    /// each time it was returned from there
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let offset = mem::offset_of!(Inner<T>, value);
+        let inner = unsafe { ptr.byte_sub(offset) } as *mut Inner<T>;
+        KArc {
+            inner: unsafe { NonNull::new_unchecked(inner) },
+            _value: PhantomData,
+        }
+    }
+
+    /// A new reference from a pointer C holds, which keeps its own
+    ///
+    /// # Safety
+    ///
+    /// As for `from_raw`, and the reference C holds must not be dropped
+    /// meanwhile
+    pub unsafe fn clone_raw(ptr: *const T) -> Self {
+        let this = mem::ManuallyDrop::new(unsafe { Self::from_raw(ptr) });
+        KArc::clone(&this)
+    }
+
+    pub fn as_ptr(this: &Self) -> *const T {
+        unsafe { &raw const (*this.inner.as_ptr()).value }
+    }
+
+    fn inner(&self) -> &Inner<T> {
+        unsafe { self.inner.as_ref() }
+    }
+}
+
+impl<T> Clone for KArc<T> {
+    fn clone(&self) -> Self {
+        self.inner().refs.acquire();
+        KArc {
+            inner: self.inner,
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for KArc<T> {
+    fn drop(&mut self) {
+        if self.inner().refs.release() {
+            let ptr = self.inner.as_ptr();
+            drop(unsafe { Box::from_raw_in(ptr, KernelAllocator::new()) });
+        }
+    }
+}
+
+impl<T> Deref for KArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for KArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `sbuf(9)` string buffers
+//!
+//! GEOM's `dumpconf` and the kernel's other reporting interfaces hand over
+//! an sbuf to add to, which `SbufRef` borrows; `Sbuf` owns one of its own.
+//! Both take `write!`, and escape text for XML with `xml`:
+//!
+//! ```rust,ignore
+//! let mut sb = Sbuf::new();
+//! write!(sb, "<name>")?;
+//! write!(sb.xml(), "{}", pp.name())?;
+//! write!(sb, "</name>")?;
+//! let text: &CStr = sb.finish()?;
+//! ```
+//!
+//! A fixed-length sbuf that runs out of room keeps what fit and records
+//! `Errno::NoMem`, which later appends, `len` and `finish` report.
+
+use crate::errno::Errno;
+use core::ffi::CStr;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use libc::{c_int, c_void};
+
+/// An sbuf someone else owns, such as the one `dumpconf` fills
+pub struct SbufRef<'a> {
+    sb: ptr::NonNull<kernel_sys::sbuf>,
+    _sb: PhantomData<&'a mut kernel_sys::sbuf>,
+}
+
+impl<'a> SbufRef<'a> {
+    /// ## Safety
+    /// `sb` must be a valid, unfinished sbuf, not used otherwise for `'a`
+    pub unsafe fn from_raw(sb: *mut kernel_sys::sbuf) -> SbufRef<'a> {
+        SbufRef {
+            sb: ptr::NonNull::new(sb).unwrap(),
+            _sb: PhantomData,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::sbuf {
+        self.sb.as_ptr()
+    }
+
+    /// Append `bytes`
+    pub fn cat(&mut self, bytes: &[u8]) -> Result<(), Errno> {
+        let ret = unsafe {
+            kernel_sys::sbuf_bcat(
+                self.as_ptr(),
+                bytes.as_ptr() as *const c_void,
+                bytes.len(),
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            _ => self.error().and(Err(Errno::NoMem)),
+        }
+    }
+
+    /// Append one byte
+    pub fn push(&mut self, byte: u8) -> Result<(), Errno> {
+        match unsafe { kernel_sys::sbuf_putc(self.as_ptr(), byte.into()) } {
+            0 => Ok(()),
+            _ => self.error().and(Err(Errno::NoMem)),
+        }
+    }
+
+    /// Append `text` escaped for XML, as `kern.geom.confxml` needs
+    pub fn cat_xml(&mut self, text: &str) -> Result<(), Errno> {
+        for part in text.split_inclusive(['&', '<', '>', '"', '\'']) {
+            let (text, entity): (_, &[u8]) = match part.as_bytes() {
+                [text @ .., b'&'] => (text, b"&amp;"),
+                [text @ .., b'<'] => (text, b"&lt;"),
+                [text @ .., b'>'] => (text, b"&gt;"),
+                [text @ .., b'"'] => (text, b"&quot;"),
+                [text @ .., b'\''] => (text, b"&apos;"),
+                text => (text, b""),
+            };
+            self.cat(text)?;
+            self.cat(entity)?;
+        }
+        Ok(())
+    }
+
+    /// Formatted output written to this escapes for XML
+    pub fn xml(&mut self) -> Xml<'_, 'a> {
+        Xml(self)
+    }
+
+    /// Bytes added so far, or the error that stopped them
+    pub fn len(&self) -> Result<usize, Errno> {
+        match unsafe { kernel_sys::sbuf_len(self.as_ptr()) } {
+            n if n >= 0 => Ok(n as usize),
+            _ => self.error().and(Err(Errno::NoMem)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Ok(0)
+    }
+
+    /// The error, such as running out of room, that appends stopped at
+    pub fn error(&self) -> Result<(), Errno> {
+        Errno::result(unsafe { kernel_sys::sbuf_error(self.as_ptr()) })
+    }
+
+    /// Empty the buffer and forget any error
+    pub fn clear(&mut self) {
+        unsafe { kernel_sys::sbuf_clear(self.as_ptr()) };
+    }
+}
+
+impl fmt::Write for SbufRef<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.cat(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Debug for SbufRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SbufRef {{ sb: {:?} }}", self.sb)
+    }
+}
+
+/// `fmt::Write` adaptor from `SbufRef::xml`
+pub struct Xml<'a, 'b>(&'a mut SbufRef<'b>);
+
+impl fmt::Write for Xml<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.cat_xml(s).map_err(|_| fmt::Error)
+    }
+}
+
+/// An sbuf of our own, deleted on drop
+pub struct Sbuf(SbufRef<'static>);
+
+// Nothing else has the sbuf
+unsafe impl Send for Sbuf {}
+
+impl Sbuf {
+    /// A buffer that grows as text is added
+    pub fn new() -> Self {
+        Self::with_flags(0, kernel_sys::SBUF_AUTOEXTEND)
+    }
+
+    /// A buffer of `len` bytes, NUL included, that fails appends once full
+    pub fn with_capacity(len: usize) -> Self {
+        Self::with_flags(len, kernel_sys::SBUF_FIXEDLEN)
+    }
+
+    fn with_flags(len: usize, flags: u32) -> Self {
+        let len = c_int::try_from(len).expect("sbuf too large");
+        let sb = unsafe {
+            kernel_sys::sbuf_new(
+                ptr::null_mut(),
+                ptr::null_mut(),
+                len,
+                flags as c_int,
+            )
+        };
+        // Allowed to sleep, sbuf_new(9) can't fail
+        Sbuf(unsafe { SbufRef::from_raw(sb) })
+    }
+
+    /// NUL-terminate the text and return it, or the error that cut it
+    /// short. It must be cleared before more is added
+    pub fn finish(&mut self) -> Result<&CStr, Errno> {
+        Errno::result(unsafe { kernel_sys::sbuf_finish(self.as_ptr()) })?;
+        Ok(unsafe { CStr::from_ptr(kernel_sys::sbuf_data(self.as_ptr())) })
+    }
+}
+
+impl Default for Sbuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for Sbuf {
+    type Target = SbufRef<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Sbuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl fmt::Write for Sbuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s)
+    }
+}
+
+impl Drop for Sbuf {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::sbuf_delete(self.as_ptr()) };
+    }
+}
+
+impl fmt::Debug for Sbuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sbuf {{ sb: {:?} }}", self.0.sb)
+    }
+}
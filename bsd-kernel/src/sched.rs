@@ -0,0 +1,209 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Keeping the current thread on its CPU, for short per-CPU work
+//!
+//! A `CriticalSection` defers preemption, so the thread neither migrates
+//! nor is switched out until the guard is dropped; interrupts still run.
+//! A `SpinlockSection` also disables interrupts, as holding a spin mutex
+//! does. Neither may be held across anything that sleeps.
+//!
+//! For longer work, `Pinned` lets the thread be preempted and sleep but
+//! always resume on the same CPU, and `Bound` first moves it to a chosen
+//! CPU. The guards are tied to the thread that took them, and nest when
+//! dropped in the reverse order they were taken: an inner `Bound` moves
+//! the thread back to the outer one's CPU. A pinned thread can't be
+//! moved, so `Bound::bind` refuses while a `Pinned` is held.
+
+use crate::arch::curthread;
+use crate::cpuset::CpuSet;
+use crate::errno::Errno;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{Ordering, compiler_fence};
+
+/// RAII guard for `critical_enter(9)`: until dropped the thread is not
+/// preempted, so it stays on the CPU it entered on
+#[must_use]
+pub struct CriticalSection {
+    // Must be left on the thread that entered it
+    _thread: PhantomData<*mut ()>,
+}
+
+impl CriticalSection {
+    pub fn enter() -> Self {
+        unsafe { kernel_sys::critical_enter_KBI() };
+        CriticalSection {
+            _thread: PhantomData,
+        }
+    }
+
+    /// The CPU the section is running on, which can't change while it is
+    /// held
+    pub fn cpu(&self) -> usize {
+        crate::smp::current_cpu()
+    }
+}
+
+impl Drop for CriticalSection {
+    fn drop(&mut self) {
+        // May switch away at once if a preemption was deferred
+        unsafe { kernel_sys::critical_exit_KBI() };
+    }
+}
+
+/// Run `f` in a critical section
+pub fn critical<R>(f: impl FnOnce() -> R) -> R {
+    let _section = CriticalSection::enter();
+    f()
+}
+
+/// RAII guard for `spinlock_enter()`: a critical section with interrupts
+/// disabled as well, for state shared with filter interrupt handlers on
+/// the same CPU
+#[must_use]
+pub struct SpinlockSection {
+    _thread: PhantomData<*mut ()>,
+}
+
+impl SpinlockSection {
+    pub fn enter() -> Self {
+        unsafe { kernel_sys::spinlock_enter() };
+        SpinlockSection {
+            _thread: PhantomData,
+        }
+    }
+
+    /// The CPU the section is running on, which can't change while it is
+    /// held
+    pub fn cpu(&self) -> usize {
+        crate::smp::current_cpu()
+    }
+}
+
+impl Drop for SpinlockSection {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::spinlock_exit() };
+    }
+}
+
+/// RAII guard for `sched_pin(9)`: until dropped the thread may still be
+/// preempted, and may sleep, but always resumes on the CPU it pinned on
+#[must_use]
+pub struct Pinned {
+    _thread: PhantomData<*mut ()>,
+}
+
+impl Pinned {
+    pub fn pin() -> Self {
+        // The count is only touched by its own thread, so ordering against
+        // interrupts on this CPU is all that is needed
+        unsafe { (*curthread()).td_pinned += 1 };
+        compiler_fence(Ordering::SeqCst);
+        Pinned {
+            _thread: PhantomData,
+        }
+    }
+
+    /// The CPU the thread is pinned to
+    pub fn cpu(&self) -> usize {
+        crate::smp::current_cpu()
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        compiler_fence(Ordering::SeqCst);
+        unsafe { (*curthread()).td_pinned -= 1 };
+    }
+}
+
+/// RAII guard for `sched_bind(9)`: moves the thread to a CPU and keeps
+/// it there until dropped, when it may migrate again
+#[must_use]
+pub struct Bound {
+    cpu: usize,
+    // The binding of an enclosing Bound, put back on drop
+    outer: Option<usize>,
+    _thread: PhantomData<*mut ()>,
+}
+
+impl Bound {
+    /// Move to `cpu`, switching away first if running elsewhere, so this
+    /// may sleep. Fails with `Errno::Inval` if there is no such CPU, and
+    /// with `Errno::Busy` if the thread is pinned
+    pub fn bind(cpu: usize) -> Result<Self, Errno> {
+        if !CpuSet::all().is_set(cpu) {
+            return Err(Errno::Inval);
+        }
+        with_thread_lock(|td| unsafe {
+            if (*td).td_pinned != 0 {
+                return Err(Errno::Busy);
+            }
+            // A bound thread runs on the CPU it is bound to
+            let outer = (kernel_sys::sched_is_bound(td) != 0)
+                .then(crate::smp::current_cpu);
+            kernel_sys::sched_bind(td, cpu as libc::c_int);
+            Ok(Bound {
+                cpu,
+                outer,
+                _thread: PhantomData,
+            })
+        })
+    }
+
+    /// The CPU the thread is bound to
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+}
+
+impl Drop for Bound {
+    fn drop(&mut self) {
+        with_thread_lock(|td| unsafe {
+            match self.outer {
+                // May switch back to the outer CPU, as binding did
+                Some(cpu) => kernel_sys::sched_bind(td, cpu as libc::c_int),
+                None => kernel_sys::sched_unbind(td),
+            }
+        });
+    }
+}
+
+/// Run `f` on the current thread with its thread lock held
+fn with_thread_lock<R>(f: impl FnOnce(*mut kernel_sys::thread) -> R) -> R {
+    let td = curthread();
+    unsafe {
+        kernel_sys::thread_lock_flags_(td, 0, ptr::null(), 0);
+        let r = f(td);
+        // The lock may have changed hands while switching CPUs
+        let lock = (*td).td_lock;
+        kernel_sys::_mtx_unlock_spin_flags(
+            &raw mut (*lock).mtx_lock,
+            0,
+            ptr::null(),
+            0,
+        );
+        r
+    }
+}
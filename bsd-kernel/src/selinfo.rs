@@ -0,0 +1,85 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Wrapper around `struct selinfo`, the record of threads waiting in
+//! `poll(2)` or `select(2)` for a device to become ready
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::{fmt, mem};
+
+/// The set of threads polling an object, see `selrecord(9)`
+pub struct SelInfo {
+    // Boxed so the selinfo keeps a stable address when the owner moves
+    si: Box<UnsafeCell<kernel_sys::selinfo>>,
+}
+
+unsafe impl Send for SelInfo {}
+unsafe impl Sync for SelInfo {}
+
+impl SelInfo {
+    /// Create an empty record
+    pub fn new() -> Self {
+        SelInfo {
+            si: Box::new(UnsafeCell::new(unsafe { mem::zeroed() })),
+        }
+    }
+
+    /// Record that thread `td`, currently in its poll routine, is waiting
+    /// for this object. Call it when reporting that the object is not
+    /// ready
+    ///
+    /// ## Safety
+    /// `td` must be the thread passed to the poll routine
+    pub unsafe fn record(&self, td: *mut kernel_sys::thread) {
+        unsafe { kernel_sys::selrecord(td, self.si.get()) };
+    }
+
+    /// Wake the threads recorded by `record`
+    pub fn wakeup(&self) {
+        unsafe { kernel_sys::selwakeup(self.si.get()) };
+    }
+
+    /// Raw pointer to the underlying `selinfo`
+    pub fn as_ptr(&self) -> *mut kernel_sys::selinfo {
+        self.si.get()
+    }
+}
+
+impl Default for SelInfo {
+    fn default() -> Self {
+        SelInfo::new()
+    }
+}
+
+impl Drop for SelInfo {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::seldrain(self.si.get()) };
+    }
+}
+
+impl fmt::Debug for SelInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SelInfo {{ si: {:?} }}", self.si.get())
+    }
+}
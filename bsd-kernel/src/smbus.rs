@@ -0,0 +1,285 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! SMBus devices, see `smbus(4)`
+//!
+//! Drivers for chips on an SMBus, such as battery gauges and temperature
+//! sensors, attach to `smbus`, whose parent is the host controller that
+//! carries out the transfers. A driver is a `bus::Driver` whose C file
+//! declares it with `DRIVER_MODULE(<driver>, smbus, ...)` and
+//! `MODULE_DEPEND(<driver>, smbus, SMBUS_MINVER, SMBUS_PREFVER,
+//! SMBUS_MAXVER)`; `Smbus` then reaches the chip at its address.
+//!
+//! Transfers are made on a `Bus`, which holds the bus for the caller
+//! until dropped, so a sequence like selecting a register and reading it
+//! isn't interleaved with other drivers' transfers.
+//!
+//! ```ignore
+//! let smbus = Smbus::new(dev)?;
+//! let bus = smbus.request(Wait::Sleep)?;
+//! let temp = bus.read_word(0x05)?;
+//! ```
+
+use crate::bus::{self, Device};
+use crate::errno::Errno;
+use core::{fmt, mem};
+use libc::{c_char, c_int};
+
+/// Longest block `Bus::write_block` and `Bus::read_block` transfer
+pub const MAX_BLOCK: usize = 32;
+
+/// How `Smbus::request` waits for a bus other drivers are using
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Wait {
+    /// Fail with `Errno::Again`
+    No,
+    Sleep,
+    /// Sleep, failing with `Errno::Intr` if a signal arrives
+    Interruptible,
+}
+
+/// A chip on an SMBus
+#[derive(Copy, Clone)]
+pub struct Smbus {
+    dev: Device,
+    bus: Device,
+    controller: Device,
+    addr: u8,
+}
+
+impl Smbus {
+    /// The chip `dev`, a child of `smbus`, at the address its hints or
+    /// the driver that added it gave
+    pub fn new(dev: Device) -> Result<Self, Errno> {
+        let index = kernel_sys::smbus_ivars_SMBUS_IVAR_ADDR as i32;
+        let addr = dev.read_ivar(index)? as u8;
+        Smbus::with_addr(dev, addr)
+    }
+
+    /// The chip at `addr` on `dev`'s bus. Addresses are 8-bit, as
+    /// throughout `smbus(4)`: the 7-bit address shifted left by one
+    pub fn with_addr(dev: Device, addr: u8) -> Result<Self, Errno> {
+        let bus = dev.parent().ok_or(Errno::NxIo)?;
+        let controller = bus.parent().ok_or(Errno::NxIo)?;
+        Ok(Smbus {
+            dev,
+            bus,
+            controller,
+            addr,
+        })
+    }
+
+    pub fn addr(&self) -> u8 {
+        self.addr
+    }
+
+    /// Acquire the bus for a sequence of transfers
+    pub fn request(&self, wait: Wait) -> Result<Bus<'_>, Errno> {
+        let how = match wait {
+            Wait::No => kernel_sys::SMB_DONTWAIT,
+            Wait::Sleep => kernel_sys::SMB_WAIT,
+            Wait::Interruptible => kernel_sys::SMB_WAIT | kernel_sys::SMB_INTR,
+        };
+        smb_result(unsafe {
+            kernel_sys::smbus_request_bus(
+                self.bus.as_ptr(),
+                self.dev.as_ptr(),
+                how,
+            )
+        })?;
+        Ok(Bus { smbus: self })
+    }
+}
+
+impl fmt::Debug for Smbus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Smbus {{ dev: {:?}, addr: {:#04x} }}",
+            self.dev.nameunit(),
+            self.addr
+        )
+    }
+}
+
+/// Map the `SMB_E*` codes methods return to an errno
+fn smb_result(error: c_int) -> Result<(), Errno> {
+    Errno::result(unsafe { kernel_sys::smbus_error(error) })
+}
+
+/// Call the `smbus_if.m` method `$desc` describes on the controller below
+/// `$smbus`, with `$smbus`'s address and `$arg`s
+macro_rules! smbus_call {
+    ($smbus:expr, $desc:ident, $ty:ident $(, $arg:expr)*) => {{
+        let controller = $smbus.controller;
+        let f: kernel_sys::$ty = unsafe {
+            mem::transmute(bus::method(
+                controller,
+                &raw mut kernel_sys::$desc,
+            ))
+        };
+        let f = f.ok_or(Errno::NoDev)?;
+        smb_result(unsafe {
+            f(controller.as_ptr(), $smbus.addr $(, $arg)*)
+        })
+    }};
+}
+
+/// The bus, held by one driver
+pub struct Bus<'a> {
+    smbus: &'a Smbus,
+}
+
+impl Bus<'_> {
+    /// Quick command: the address alone, with the read/write bit set
+    /// from `read`
+    pub fn quick(&self, read: bool) -> Result<(), Errno> {
+        let how = match read {
+            true => kernel_sys::SMB_QREAD,
+            false => kernel_sys::SMB_QWRITE,
+        };
+        smbus_call!(self.smbus, smbus_quick_desc, smbus_quick_t, how)
+    }
+
+    /// Send Byte
+    pub fn send_byte(&self, byte: u8) -> Result<(), Errno> {
+        smbus_call!(self.smbus, smbus_sendb_desc, smbus_sendb_t, byte as c_char)
+    }
+
+    /// Receive Byte
+    pub fn recv_byte(&self) -> Result<u8, Errno> {
+        let mut byte: c_char = 0;
+        smbus_call!(self.smbus, smbus_recvb_desc, smbus_recvb_t, &mut byte)?;
+        Ok(byte as u8)
+    }
+
+    /// Write Byte: `byte` to register `cmd`
+    pub fn write_byte(&self, cmd: u8, byte: u8) -> Result<(), Errno> {
+        smbus_call!(
+            self.smbus,
+            smbus_writeb_desc,
+            smbus_writeb_t,
+            cmd as c_char,
+            byte as c_char
+        )
+    }
+
+    /// Read Byte: register `cmd`
+    pub fn read_byte(&self, cmd: u8) -> Result<u8, Errno> {
+        let mut byte: c_char = 0;
+        smbus_call!(
+            self.smbus,
+            smbus_readb_desc,
+            smbus_readb_t,
+            cmd as c_char,
+            &mut byte
+        )?;
+        Ok(byte as u8)
+    }
+
+    /// Write Word: `word` to register `cmd`, low byte first on the wire
+    pub fn write_word(&self, cmd: u8, word: u16) -> Result<(), Errno> {
+        smbus_call!(
+            self.smbus,
+            smbus_writew_desc,
+            smbus_writew_t,
+            cmd as c_char,
+            word as i16
+        )
+    }
+
+    /// Read Word: register `cmd`
+    pub fn read_word(&self, cmd: u8) -> Result<u16, Errno> {
+        let mut word = 0i16;
+        smbus_call!(
+            self.smbus,
+            smbus_readw_desc,
+            smbus_readw_t,
+            cmd as c_char,
+            &mut word
+        )?;
+        Ok(word as u16)
+    }
+
+    /// Process Call: write `word` to register `cmd` and read the reply
+    pub fn process_call(&self, cmd: u8, word: u16) -> Result<u16, Errno> {
+        let mut reply = 0i16;
+        smbus_call!(
+            self.smbus,
+            smbus_pcall_desc,
+            smbus_pcall_t,
+            cmd as c_char,
+            word as i16,
+            &mut reply
+        )?;
+        Ok(reply as u16)
+    }
+
+    /// Block Write: up to `MAX_BLOCK` bytes to register `cmd`
+    pub fn write_block(&self, cmd: u8, data: &[u8]) -> Result<(), Errno> {
+        if data.len() > MAX_BLOCK {
+            return Err(Errno::Inval);
+        }
+        smbus_call!(
+            self.smbus,
+            smbus_bwrite_desc,
+            smbus_bwrite_t,
+            cmd as c_char,
+            data.len() as u8,
+            data.as_ptr() as *mut c_char
+        )
+    }
+
+    /// Block Read: from register `cmd` into `buf`, returning the length
+    /// the chip sent. Blocks too long for `buf` fail with
+    /// `Errno::Overflow`, or are cut short by some controllers
+    pub fn read_block(&self, cmd: u8, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut count = buf.len().min(MAX_BLOCK) as u8;
+        smbus_call!(
+            self.smbus,
+            smbus_bread_desc,
+            smbus_bread_t,
+            cmd as c_char,
+            &mut count,
+            buf.as_mut_ptr() as *mut c_char
+        )?;
+        Ok(count as usize)
+    }
+}
+
+impl Drop for Bus<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::smbus_release_bus(
+                self.smbus.bus.as_ptr(),
+                self.smbus.dev.as_ptr(),
+            )
+        };
+    }
+}
+
+impl fmt::Debug for Bus<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bus {{ smbus: {:?} }}", self.smbus)
+    }
+}
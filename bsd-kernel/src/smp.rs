@@ -0,0 +1,156 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Cross-CPU rendezvous, wrapping `smp_rendezvous(9)`
+//!
+//! The rendezvous functions run the supplied closures on several CPUs at
+//! once, with all participating CPUs spinning with interrupts disabled
+//! between phases. The closures must therefore be short and must not
+//! sleep, allocate with `M_WAITOK` or acquire sleepable locks.
+
+use crate::cpuset::CpuSet;
+use libc::c_void;
+
+/// The ID of the CPU the calling thread is running on (`curcpu`). Unless
+/// the thread is pinned or in a critical section, it may have migrated by
+/// the time the value is used
+pub fn current_cpu() -> usize {
+    crate::arch::curcpu() as usize
+}
+
+/// The number of CPUs in the system (`mp_ncpus`)
+pub fn ncpus() -> usize {
+    unsafe { kernel_sys::mp_ncpus as usize }
+}
+
+/// The highest valid CPU ID (`mp_maxid`). CPU IDs may be sparse
+pub fn max_cpu_id() -> usize {
+    unsafe { kernel_sys::mp_maxid as usize }
+}
+
+unsafe extern "C" fn rendezvous_thunk<F>(arg: *mut c_void)
+where
+    F: Fn() + Sync,
+{
+    let f = unsafe { &*(arg as *const F) };
+    f();
+}
+
+struct Phases<S, A, T> {
+    setup: S,
+    action: A,
+    teardown: T,
+}
+
+unsafe extern "C" fn setup_thunk<S, A, T>(arg: *mut c_void)
+where
+    S: Fn() + Sync,
+{
+    let p = unsafe { &*(arg as *const Phases<S, A, T>) };
+    (p.setup)();
+}
+
+unsafe extern "C" fn action_thunk<S, A, T>(arg: *mut c_void)
+where
+    A: Fn() + Sync,
+{
+    let p = unsafe { &*(arg as *const Phases<S, A, T>) };
+    (p.action)();
+}
+
+unsafe extern "C" fn teardown_thunk<S, A, T>(arg: *mut c_void)
+where
+    T: Fn() + Sync,
+{
+    let p = unsafe { &*(arg as *const Phases<S, A, T>) };
+    (p.teardown)();
+}
+
+/// Run `action` simultaneously on every CPU, returning once all CPUs
+/// have completed it
+pub fn rendezvous<A>(action: A)
+where
+    A: Fn() + Sync,
+{
+    unsafe {
+        kernel_sys::smp_rendezvous(
+            None,
+            Some(rendezvous_thunk::<A>),
+            None,
+            &raw const action as *mut c_void,
+        );
+    }
+}
+
+/// Run `action` simultaneously on each CPU in `cpus`, returning once they
+/// have all completed it. CPUs in the set that are not present in the
+/// system are ignored
+pub fn rendezvous_cpus<A>(cpus: &CpuSet, action: A)
+where
+    A: Fn() + Sync,
+{
+    if !cpus.intersects(&CpuSet::all()) {
+        return;
+    }
+    unsafe {
+        kernel_sys::smp_rendezvous_cpus(
+            *cpus.as_ptr(),
+            None,
+            Some(rendezvous_thunk::<A>),
+            None,
+            &raw const action as *mut c_void,
+        );
+    }
+}
+
+/// Run a three-phase rendezvous on each CPU in `cpus`: every CPU runs
+/// `setup`, then waits for the others before running `action`, then waits
+/// again before running `teardown`
+pub fn rendezvous_phases<S, A, T>(
+    cpus: &CpuSet,
+    setup: S,
+    action: A,
+    teardown: T,
+) where
+    S: Fn() + Sync,
+    A: Fn() + Sync,
+    T: Fn() + Sync,
+{
+    if !cpus.intersects(&CpuSet::all()) {
+        return;
+    }
+    let phases = Phases {
+        setup,
+        action,
+        teardown,
+    };
+    unsafe {
+        kernel_sys::smp_rendezvous_cpus(
+            *cpus.as_ptr(),
+            Some(setup_thunk::<S, A, T>),
+            Some(action_thunk::<S, A, T>),
+            Some(teardown_thunk::<S, A, T>),
+            &raw const phases as *mut c_void,
+        );
+    }
+}
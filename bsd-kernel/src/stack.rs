@@ -0,0 +1,104 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Kernel stack traces, see `stack(9)`
+//!
+//! A `Stack` is saved in place, without allocating, so it can be taken
+//! anywhere, such as when a reference is acquired in case it leaks, and
+//! logged later. The kernel needs `options STACK` or `DDB`, as `GENERIC`
+//! has.
+
+use core::{fmt, mem};
+use libc::{c_char, c_long};
+
+/// A saved stack trace, up to `STACK_MAX` frames of it
+#[derive(Copy, Clone)]
+pub struct Stack {
+    st: kernel_sys::stack,
+}
+
+impl Stack {
+    /// The current thread's stack, from the caller up
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let mut st: kernel_sys::stack = unsafe { mem::zeroed() };
+        unsafe { kernel_sys::stack_save(&mut st) };
+        Stack { st }
+    }
+
+    /// The return addresses, innermost first
+    pub fn pcs(&self) -> &[kernel_sys::vm_offset_t] {
+        &self.st.pcs[..self.st.depth as usize]
+    }
+
+    pub fn depth(&self) -> usize {
+        self.st.depth as usize
+    }
+
+    /// Print the trace on the console, as `stack_print`. May sleep, to
+    /// look up symbols
+    pub fn print(&self) {
+        unsafe { kernel_sys::stack_print(&self.st) };
+    }
+}
+
+/// One frame per line, with the symbol it's in, as `stack_sbuf_print`.
+/// Symbols are looked up without sleeping, so they show as `??` if the
+/// kernel linker is busy
+impl fmt::Display for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut name = [0 as c_char; 64];
+        for (i, &pc) in self.pcs().iter().enumerate() {
+            let mut offset: c_long = 0;
+            let error = unsafe {
+                kernel_sys::linker_search_symbol_name_flags(
+                    pc as kernel_sys::caddr_t,
+                    name.as_mut_ptr(),
+                    name.len() as u32,
+                    &mut offset,
+                    kernel_sys::M_NOWAIT,
+                )
+            };
+            if error == 0 {
+                let name = unsafe { core::ffi::CStr::from_ptr(name.as_ptr()) };
+                writeln!(
+                    f,
+                    "#{} {:#x} at {}+{:#x}",
+                    i,
+                    pc,
+                    name.to_str().unwrap_or("??"),
+                    offset
+                )?;
+            } else {
+                writeln!(f, "#{} {:#x} at ??", i, pc)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stack {{ pcs: {:x?} }}", self.pcs())
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Super I/O chip functions, see `superio(4)`
+//!
+//! `superio` finds the Super I/O chip on the LPC bus and adds a child for
+//! each logical device it knows of and has enabled: hardware monitor,
+//! watchdog or GPIO. A driver for one of them is a `bus::Driver` whose C
+//! file declares it with `DRIVER_MODULE(<driver>, superio, ...)` and
+//! `MODULE_DEPEND(<driver>, superio, 1, 1, 1)`, and whose probe matches
+//! the child's `vendor` and `function`:
+//!
+//! ```ignore
+//! fn probe(dev: Device) -> Result<i32, Errno> {
+//!     let sio = SuperIo::new(dev);
+//!     if sio.vendor() != Some(Vendor::Nuvoton)
+//!         || sio.function() != Some(Function::Watchdog)
+//!     {
+//!         return Err(Errno::NxIo);
+//!     }
+//!     dev.set_desc(c"Nuvoton watchdog");
+//!     Ok(priority::SPECIFIC)
+//! }
+//! ```
+//!
+//! The logical device's configuration registers, from 0x30 up, are read
+//! and written with `SuperIo::read` and `write`; its function's own
+//! registers are usually ports from `iobase`, for `bus::Resource`.
+
+use crate::bus::Device;
+use crate::errno::Errno;
+use core::fmt;
+use libc::c_int;
+
+/// Chip vendors `superio` recognises
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Vendor {
+    Ite,
+    Nuvoton,
+    Fintek,
+}
+
+impl Vendor {
+    fn from_raw(v: kernel_sys::superio_vendor_t) -> Option<Self> {
+        match v {
+            kernel_sys::superio_vendor_SUPERIO_VENDOR_ITE => Some(Vendor::Ite),
+            kernel_sys::superio_vendor_SUPERIO_VENDOR_NUVOTON => {
+                Some(Vendor::Nuvoton)
+            }
+            kernel_sys::superio_vendor_SUPERIO_VENDOR_FINTEK => {
+                Some(Vendor::Fintek)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What a logical device does
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Function {
+    HardwareMonitor,
+    Watchdog,
+    Gpio,
+}
+
+impl Function {
+    fn from_raw(t: kernel_sys::superio_dev_type_t) -> Option<Self> {
+        match t {
+            kernel_sys::superio_dev_type_SUPERIO_DEV_HWM => {
+                Some(Function::HardwareMonitor)
+            }
+            kernel_sys::superio_dev_type_SUPERIO_DEV_WDT => {
+                Some(Function::Watchdog)
+            }
+            kernel_sys::superio_dev_type_SUPERIO_DEV_GPIO => {
+                Some(Function::Gpio)
+            }
+            _ => None,
+        }
+    }
+
+    fn as_raw(self) -> kernel_sys::superio_dev_type_t {
+        match self {
+            Function::HardwareMonitor => {
+                kernel_sys::superio_dev_type_SUPERIO_DEV_HWM
+            }
+            Function::Watchdog => kernel_sys::superio_dev_type_SUPERIO_DEV_WDT,
+            Function::Gpio => kernel_sys::superio_dev_type_SUPERIO_DEV_GPIO,
+        }
+    }
+}
+
+/// A logical device of the chip, a child of `superio`
+#[derive(Copy, Clone)]
+pub struct SuperIo {
+    dev: Device,
+}
+
+impl SuperIo {
+    pub fn new(dev: Device) -> Self {
+        SuperIo { dev }
+    }
+
+    pub fn device(&self) -> Device {
+        self.dev
+    }
+
+    pub fn vendor(&self) -> Option<Vendor> {
+        Vendor::from_raw(unsafe {
+            kernel_sys::superio_vendor(self.dev.as_ptr())
+        })
+    }
+
+    /// The chip's ID, as in its registers 0x20 and 0x21
+    pub fn chip_id(&self) -> u16 {
+        unsafe { kernel_sys::superio_devid(self.dev.as_ptr()) }
+    }
+
+    pub fn chip_revision(&self) -> u8 {
+        unsafe { kernel_sys::superio_revid(self.dev.as_ptr()) }
+    }
+
+    fn ivar(&self, ivar: kernel_sys::superio_ivars) -> Result<usize, Errno> {
+        self.dev.read_ivar(ivar as i32)
+    }
+
+    /// Logical device number
+    pub fn ldn(&self) -> u8 {
+        self.ivar(kernel_sys::superio_ivars_SUPERIO_IVAR_LDN)
+            .map_or(0, |v| v as u8)
+    }
+
+    pub fn function(&self) -> Option<Function> {
+        let t = self
+            .ivar(kernel_sys::superio_ivars_SUPERIO_IVAR_TYPE)
+            .ok()?;
+        Function::from_raw(t as kernel_sys::superio_dev_type_t)
+    }
+
+    /// The first I/O port of the function's registers, 0 if it has none
+    pub fn iobase(&self) -> u16 {
+        self.ivar(kernel_sys::superio_ivars_SUPERIO_IVAR_IOBASE)
+            .map_or(0, |v| v as u16)
+    }
+
+    /// The second range of ports, for functions with two
+    pub fn iobase2(&self) -> u16 {
+        self.ivar(kernel_sys::superio_ivars_SUPERIO_IVAR_IOBASE2)
+            .map_or(0, |v| v as u16)
+    }
+
+    pub fn irq(&self) -> u16 {
+        self.ivar(kernel_sys::superio_ivars_SUPERIO_IVAR_IRQ)
+            .map_or(0, |v| v as u16)
+    }
+
+    /// Read configuration register `reg` of the logical device
+    pub fn read(&self, reg: u8) -> u8 {
+        unsafe { kernel_sys::superio_read(self.dev.as_ptr(), reg) }
+    }
+
+    /// Write configuration register `reg` of the logical device
+    pub fn write(&self, reg: u8, value: u8) {
+        unsafe { kernel_sys::superio_write(self.dev.as_ptr(), reg, value) }
+    }
+
+    /// Whether the `mask` bits of the activation register, 0x30, are set
+    pub fn is_enabled(&self, mask: u8) -> bool {
+        unsafe { kernel_sys::superio_dev_enabled(self.dev.as_ptr(), mask) }
+    }
+
+    pub fn enable(&self, mask: u8) {
+        unsafe { kernel_sys::superio_dev_enable(self.dev.as_ptr(), mask) }
+    }
+
+    pub fn disable(&self, mask: u8) {
+        unsafe { kernel_sys::superio_dev_disable(self.dev.as_ptr(), mask) }
+    }
+
+    /// Another logical device of the same chip with `function`, and
+    /// number `ldn` if given, such as the GPIO a watchdog's pin is on
+    pub fn find(&self, function: Function, ldn: Option<u8>) -> Option<Device> {
+        let superio = self.dev.parent()?;
+        unsafe {
+            Device::from_raw(kernel_sys::superio_find_dev(
+                superio.as_ptr(),
+                function.as_raw(),
+                ldn.map_or(-1, c_int::from),
+            ))
+        }
+    }
+}
+
+impl fmt::Debug for SuperIo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SuperIo {{ vendor: {:?}, ldn: {:#x}, function: {:?} }}",
+            self.vendor(),
+            self.ldn(),
+            self.function()
+        )
+    }
+}
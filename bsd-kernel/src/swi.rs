@@ -0,0 +1,117 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Software interrupts, see `swi(9)`
+//!
+//! A `Swi` runs a closure in a software interrupt thread soon after
+//! `Swi::schedule`, at a priority above every ordinary kernel thread and
+//! taskqueue, for a driver's deferred work that must not wait behind
+//! them, as the network stack's and ttys' does. `schedule` may be called
+//! from anywhere, including filter interrupt handlers, and requests
+//! made while the closure is pending run it only once. The closure runs
+//! in an interrupt thread, so it may take default mutexes but not sleep.
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::ffi::CStr;
+use core::{fmt, ptr};
+use libc::c_void;
+
+/// Software interrupt priorities, `SWI_*`, most urgent first
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(i32)]
+pub enum SwiPriority {
+    Tty = kernel_sys::SWI_TTY,
+    Net = kernel_sys::SWI_NET,
+    CamBio = kernel_sys::SWI_CAMBIO,
+    Vm = kernel_sys::SWI_VM,
+    Clock = kernel_sys::SWI_CLOCK,
+    /// That of `taskqueue_fast`
+    TqFast = kernel_sys::SWI_TQ_FAST,
+    /// That of `taskqueue_swi`
+    Tq = kernel_sys::SWI_TQ,
+}
+
+/// A software interrupt handler with its own interrupt thread. Removed
+/// when dropped, after waiting for a run in progress
+pub struct Swi<F: Fn() + Send + Sync + 'static> {
+    ie: *mut kernel_sys::intr_event,
+    cookie: *mut c_void,
+    // Boxed as the kernel holds a pointer to it
+    f: Box<F>,
+}
+
+unsafe impl<F: Fn() + Send + Sync + 'static> Send for Swi<F> {}
+unsafe impl<F: Fn() + Send + Sync + 'static> Sync for Swi<F> {}
+
+impl<F: Fn() + Send + Sync + 'static> Swi<F> {
+    /// Create the interrupt thread, `swi<pri>: name` in `ps(1)`, to run
+    /// `f`
+    pub fn new(name: &CStr, pri: SwiPriority, f: F) -> Result<Self, Errno> {
+        let f = Box::new(f);
+        let mut ie = ptr::null_mut();
+        let mut cookie = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::swi_add(
+                &mut ie,
+                name.as_ptr(),
+                Some(run::<F>),
+                &*f as *const F as *mut c_void,
+                pri as i32,
+                kernel_sys::intr_type_INTR_MPSAFE,
+                &mut cookie,
+            )
+        })?;
+        Ok(Swi { ie, cookie, f })
+    }
+
+    /// Have the closure run soon
+    pub fn schedule(&self) {
+        unsafe { kernel_sys::swi_sched(self.cookie, 0) };
+    }
+
+    pub fn handler(&self) -> &F {
+        &self.f
+    }
+}
+
+impl<F: Fn() + Send + Sync + 'static> Drop for Swi<F> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::swi_remove(self.cookie);
+            kernel_sys::intr_event_destroy(self.ie);
+        }
+    }
+}
+
+impl<F: Fn() + Send + Sync + 'static> fmt::Debug for Swi<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Swi {{ cookie: {:p} }}", self.cookie)
+    }
+}
+
+unsafe extern "C" fn run<F: Fn() + Send + Sync + 'static>(arg: *mut c_void) {
+    let f = unsafe { &*(arg as *const F) };
+    let _ = catch_in_module(f);
+}
@@ -0,0 +1,503 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Multi-producer, single-consumer FIFO channels
+//!
+//! These follow the shape of `std::sync::mpsc`: `channel` creates an
+//! unbounded channel whose senders never wait for room, and
+//! `sync_channel` a bounded one whose senders sleep while the channel is
+//! full. Each message is boxed before the channel's mutex is taken, so
+//! nothing allocates, or sleeps for memory, with it held.
+//!
+//! `try_send` and `try_recv` never sleep, allocating with `M_NOWAIT`, so
+//! callouts and other code that mustn't sleep can use them; a message
+//! that can't be allocated fails as `TrySendError::Full`. `send`, `recv`
+//! and `recv_timeout` may sleep. The mutex is a default one, so neither
+//! end may be used from interrupt filters or while holding a spin mutex.
+
+use super::{Condvar, Mutex};
+use crate::allocator::{KernelAllocator, Wait};
+use crate::time::{duration_to_sbt, getsbinuptime};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt;
+use core::ptr;
+use core::time::Duration;
+
+/// A queued message, allocated before it is queued
+struct Node<T> {
+    value: T,
+    next: Option<Box<Node<T>, KernelAllocator>>,
+}
+
+/// FIFO of nodes, which links and unlinks them without allocating
+struct Queue<T> {
+    head: Option<Box<Node<T>, KernelAllocator>>,
+    tail: *mut Node<T>,
+    len: usize,
+}
+
+impl<T> Queue<T> {
+    fn new() -> Self {
+        Queue {
+            head: None,
+            tail: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    fn push_back(&mut self, mut node: Box<Node<T>, KernelAllocator>) {
+        node.next = None;
+        let raw: *mut Node<T> = &mut *node;
+        match self.tail.is_null() {
+            true => self.head = Some(node),
+            false => unsafe { (*self.tail).next = Some(node) },
+        }
+        self.tail = raw;
+        self.len += 1;
+    }
+
+    /// Unlink the first node, leaving it to the caller to free, which
+    /// can wait until the lock is dropped
+    fn pop_front(&mut self) -> Option<Box<Node<T>, KernelAllocator>> {
+        let mut node = self.head.take()?;
+        self.head = node.next.take();
+        if self.head.is_none() {
+            self.tail = ptr::null_mut();
+        }
+        self.len -= 1;
+        Some(node)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // One at a time, as dropping the head would recurse down the list
+        while self.pop_front().is_some() {}
+    }
+}
+
+struct State<T> {
+    queue: Queue<T>,
+    senders: usize,
+    receiver: bool,
+}
+
+// The raw tail points into nodes the queue owns
+unsafe impl<T: Send> Send for State<T> {}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    bound: Option<usize>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn new(bound: Option<usize>) -> Arc<Self> {
+        Arc::new(Shared {
+            state: Mutex::new(
+                c"chan",
+                State {
+                    queue: Queue::new(),
+                    senders: 1,
+                    receiver: true,
+                },
+            ),
+            bound,
+            not_empty: Condvar::new(c"chanrecv"),
+            not_full: Condvar::new(c"chansend"),
+        })
+    }
+
+    /// Queue `t`, allocating its node as `wait` says before locking.
+    /// With `block`, sleep while a bounded channel is full
+    fn send(
+        &self,
+        t: T,
+        block: bool,
+        wait: Wait,
+    ) -> Result<(), TrySendError<T>> {
+        let alloc = KernelAllocator::new().wait(wait);
+        let mut node = match Box::try_new_uninit_in(alloc) {
+            Ok(node) => node,
+            Err(_) => return Err(TrySendError::Full(t)),
+        };
+        node.write(Node {
+            value: t,
+            next: None,
+        });
+        let node = unsafe { node.assume_init() };
+        let mut state = self.state.lock();
+        loop {
+            if !state.receiver {
+                drop(state);
+                return Err(TrySendError::Disconnected(node.value));
+            }
+            match self.bound {
+                Some(bound) if state.queue.len() >= bound => {
+                    if !block {
+                        drop(state);
+                        return Err(TrySendError::Full(node.value));
+                    }
+                    state = self.not_full.wait(state);
+                }
+                _ => break,
+            }
+        }
+        state.queue.push_back(node);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    fn add_sender(&self) {
+        self.state.lock().senders += 1;
+    }
+
+    fn drop_sender(&self) {
+        let mut state = self.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.not_empty.notify_all();
+        }
+    }
+}
+
+/// Create an unbounded channel, returning the sending and receiving
+/// halves. The `Sender` may be cloned to create more producers
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Shared::new(None);
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Create a channel holding at most `bound` messages, returning the
+/// sending and receiving halves
+///
+/// ## Panics
+/// Panics if `bound` is zero
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    assert!(bound > 0, "sync_channel bound must be non-zero");
+    let shared = Shared::new(Some(bound));
+    (
+        SyncSender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// The sending half of an unbounded channel
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message, which may sleep to allocate it. Fails, handing the
+    /// message back, only if the `Receiver` has been dropped
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.shared
+            .send(t, false, Wait::WaitOk)
+            .map_err(|e| SendError(e.into_inner()))
+    }
+
+    /// Send a message without sleeping, failing with `TrySendError::Full`
+    /// if it can't be allocated
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        self.shared.send(t, false, Wait::NoWait)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.add_sender();
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sender {{ .. }}")
+    }
+}
+
+/// The sending half of a bounded channel
+pub struct SyncSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> SyncSender<T> {
+    /// Send a message, sleeping while the channel is full. Fails, handing
+    /// the message back, if the `Receiver` has been dropped
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.shared
+            .send(t, true, Wait::WaitOk)
+            .map_err(|e| SendError(e.into_inner()))
+    }
+
+    /// Send a message if there is room for it and it can be allocated,
+    /// without sleeping
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        self.shared.send(t, false, Wait::NoWait)
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.add_sender();
+        SyncSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        self.shared.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for SyncSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SyncSender {{ .. }}")
+    }
+}
+
+/// The receiving half of a channel
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Take the next message, sleeping until one arrives. Fails once the
+    /// channel is empty and every sender has been dropped
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock();
+        loop {
+            if let Some(node) = state.queue.pop_front() {
+                shared.not_full.notify_one();
+                return Ok(node.value);
+            }
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+            state = shared.not_empty.wait(state);
+        }
+    }
+
+    /// Take the next message if there is one, without sleeping
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock();
+        match state.queue.pop_front() {
+            Some(node) => {
+                shared.not_full.notify_one();
+                Ok(node.value)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Take the next message, sleeping for at most `timeout` for one to
+    /// arrive. A zero `timeout` only checks for one
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<T, RecvTimeoutError> {
+        let shared = &*self.shared;
+        let deadline = getsbinuptime().saturating_add(duration_to_sbt(timeout));
+        let mut state = shared.state.lock();
+        loop {
+            if let Some(node) = state.queue.pop_front() {
+                shared.not_full.notify_one();
+                return Ok(node.value);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let remaining = deadline - getsbinuptime();
+            if remaining <= 0 {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = shared.not_empty.wait_timeout_sbt(state, remaining).0;
+        }
+    }
+
+    /// An iterator that takes messages with `recv` until the channel is
+    /// disconnected
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.receiver = false;
+        self.shared.not_full.notify_all();
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Receiver {{ .. }}")
+    }
+}
+
+/// Iterator over the messages of a `Receiver`, see `Receiver::iter`
+#[derive(Debug)]
+pub struct Iter<'a, T> {
+    rx: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+/// The message could not be sent because the receiver is gone
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct SendError<T>(pub T);
+
+/// Reasons a message could not be sent by `SyncSender::try_send`
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum TrySendError<T> {
+    /// The channel is full
+    Full(T),
+    /// The receiver is gone
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    /// The message that failed to send
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(t) | TrySendError::Disconnected(t) => t,
+        }
+    }
+}
+
+/// Every sender is gone and the channel is empty
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RecvError;
+
+/// Reasons `Receiver::try_recv` returned no message
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TryRecvError {
+    /// The channel is currently empty
+    Empty,
+    /// Every sender is gone and the channel is empty
+    Disconnected,
+}
+
+/// Reasons `Receiver::recv_timeout` returned no message
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecvTimeoutError {
+    /// No message arrived before the timeout
+    Timeout,
+    /// Every sender is gone and the channel is empty
+    Disconnected,
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SendError {{ .. }}")
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Full(..)"),
+            TrySendError::Disconnected(_) => write!(f, "Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Disconnected(_) => {
+                write!(f, "sending on a closed channel")
+            }
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "receiving on a closed channel")
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "receiving on an empty channel"),
+            TryRecvError::Disconnected => {
+                write!(f, "receiving on a closed channel")
+            }
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => {
+                write!(f, "timed out waiting on channel")
+            }
+            RecvTimeoutError::Disconnected => {
+                write!(f, "channel is empty and sending half is closed")
+            }
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::mutex::MutexGuard;
+use crate::errno::Errno;
+use crate::time::duration_to_sbt;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::time::Duration;
+use core::{fmt, mem};
+
+/// A condition variable wrapping `condvar(9)`, used together with a
+/// `Mutex` to sleep until some condition on the protected data holds
+///
+/// The name is the wait message shown for sleeping threads by `ps(1)`
+/// and `top(1)`.
+pub struct Condvar {
+    cv: Box<UnsafeCell<kernel_sys::cv>>,
+}
+
+unsafe impl Send for Condvar {}
+unsafe impl Sync for Condvar {}
+
+/// Whether a timed wait on a `Condvar` returned because the timeout
+/// elapsed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// True if the wait ended because the timeout elapsed
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Condvar {
+    /// Create a new condition variable with wait message `name`
+    pub fn new(name: &'static CStr) -> Self {
+        let cv: Box<UnsafeCell<kernel_sys::cv>> =
+            Box::new(UnsafeCell::new(unsafe { mem::zeroed() }));
+        unsafe { kernel_sys::cv_init(cv.get(), name.as_ptr()) };
+        Condvar { cv }
+    }
+
+    /// Atomically release the lock held by `guard` and sleep until woken
+    /// by `notify_one` or `notify_all`, then reacquire the lock. Spurious
+    /// wakeups are possible, so callers should wait in a loop
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        unsafe {
+            kernel_sys::_cv_wait(self.cv.get(), guard.mutex().lock_object())
+        };
+        guard
+    }
+
+    /// Like `wait`, but the sleep may be interrupted by a signal, in which
+    /// case `Errno::Intr` or `Errno::Restart` is returned along with the
+    /// reacquired guard
+    pub fn wait_sig<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+    ) -> (MutexGuard<'a, T>, Result<(), Errno>) {
+        let ret = unsafe {
+            kernel_sys::_cv_wait_sig(self.cv.get(), guard.mutex().lock_object())
+        };
+        (guard, Errno::result(ret))
+    }
+
+    /// Like `wait`, but give up once `timeout` has elapsed. A zero
+    /// `timeout` times out without sleeping
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        if timeout.is_zero() {
+            return (guard, WaitTimeoutResult(true));
+        }
+        // A timeout below one sbt would truncate to 0, which means forever
+        self.wait_timeout_sbt(guard, duration_to_sbt(timeout).max(1))
+    }
+
+    /// Like `wait_timeout`, with the timeout in sbintime units. A timeout
+    /// that isn't positive times out without sleeping
+    pub(crate) fn wait_timeout_sbt<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        sbt: kernel_sys::sbintime_t,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        if sbt <= 0 {
+            return (guard, WaitTimeoutResult(true));
+        }
+        let ret = unsafe {
+            kernel_sys::_cv_timedwait_sbt(
+                self.cv.get(),
+                guard.mutex().lock_object(),
+                sbt,
+                0,
+                0,
+            )
+        };
+        (guard, WaitTimeoutResult(ret == kernel_sys::EWOULDBLOCK))
+    }
+
+    /// Wait until `condition` returns false for the protected data
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake up one thread waiting on this condition variable
+    pub fn notify_one(&self) {
+        unsafe { kernel_sys::cv_signal(self.cv.get()) };
+    }
+
+    /// Wake up all threads waiting on this condition variable
+    pub fn notify_all(&self) {
+        unsafe { kernel_sys::cv_broadcastpri(self.cv.get(), 0) };
+    }
+}
+
+impl Drop for Condvar {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::cv_destroy(self.cv.get()) };
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Condvar {{ cv: {:?} }}", self.cv.get())
+    }
+}
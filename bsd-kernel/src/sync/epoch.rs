@@ -0,0 +1,290 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `epoch(9)` read sections and deferred reclamation
+//!
+//! Readers run in an epoch section with `Epoch::read`, which takes no lock
+//! and never waits for a writer, so it suits data consulted on every I/O
+//! while seldom changing. Writers serialize among themselves with a lock
+//! of their own, unlink what they replace, and hand it to `Epoch::retire`,
+//! which drops it once every section that might still see it has ended.
+//! `EpochCell` does that for a single pointer:
+//!
+//! ```rust,ignore
+//! let units = EpochCell::new(Epoch::global(), Vec::new());
+//!
+//! // I/O path
+//! let present = Epoch::global().read(|g| units.get(g).contains(&unit));
+//!
+//! // configuration, under the writers' lock
+//! let _w = writers.lock();
+//! let mut next = Epoch::global().read(|g| units.get(g).clone());
+//! next.push(unit);
+//! units.replace(next);
+//! ```
+//!
+//! The epochs are preemptible ones, `EPOCH_PREEMPT`: sections may take
+//! default mutexes, but not sleep. The kernel has few epochs, so modules
+//! usually share `Epoch::global()` rather than allocate their own.
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::{fmt, mem, ptr};
+
+/// A preemptible epoch. Dropping one allocated by `new` waits for what
+/// was retired to it to be dropped, so it may sleep
+#[repr(transparent)]
+pub struct Epoch {
+    epoch: kernel_sys::epoch_t,
+}
+
+unsafe impl Send for Epoch {}
+unsafe impl Sync for Epoch {}
+
+impl Epoch {
+    /// Allocate an epoch named `name`, failing with `Errno::NoMem` when
+    /// the kernel has none left
+    pub fn new(name: &'static CStr) -> Result<Self, Errno> {
+        let epoch = unsafe {
+            kernel_sys::epoch_alloc(name.as_ptr(), kernel_sys::EPOCH_PREEMPT)
+        };
+        if epoch.is_null() {
+            return Err(Errno::NoMem);
+        }
+        Ok(Epoch { epoch })
+    }
+
+    /// The kernel's general purpose epoch, `global_epoch_preempt`
+    pub fn global() -> &'static Epoch {
+        unsafe { &*(&raw const kernel_sys::global_epoch_preempt).cast() }
+    }
+
+    /// The network stack's epoch, `net_epoch_preempt`, which its
+    /// interfaces' input paths run in
+    pub fn net() -> &'static Epoch {
+        unsafe { &*(&raw const kernel_sys::net_epoch_preempt).cast() }
+    }
+
+    /// Run `f` in a section of this epoch. Sections may nest
+    pub fn read<R>(&self, f: impl FnOnce(&EpochGuard<'_>) -> R) -> R {
+        // The kernel links the tracker into a per-CPU list for the length
+        // of the section, so it stays put on this stack frame
+        let mut et: kernel_sys::epoch_tracker = unsafe { mem::zeroed() };
+        unsafe { kernel_sys::_epoch_enter_preempt(self.epoch, &mut et) };
+        let guard = EpochGuard {
+            epoch: self,
+            _not_send: PhantomData,
+        };
+        let r = f(&guard);
+        unsafe { kernel_sys::epoch_exit_preempt(self.epoch, &mut et) };
+        r
+    }
+
+    /// Whether the current thread is in a section of this epoch
+    pub fn in_section(&self) -> bool {
+        unsafe { kernel_sys::in_epoch(self.epoch) != 0 }
+    }
+
+    /// Sleep until every section in progress has ended, for writers
+    /// that free what they unlinked themselves
+    pub fn wait(&self) {
+        unsafe { kernel_sys::epoch_wait_preempt(self.epoch) };
+    }
+
+    /// Drop `value` once every section in progress has ended, from a
+    /// kernel thread that runs with others' callbacks, so its `Drop` must
+    /// not sleep
+    ///
+    /// The drop runs the module's code: a module that retires to an epoch
+    /// it didn't allocate calls `drain` before it unloads
+    pub fn retire<T: Send + 'static>(&self, value: EpochBox<T>) {
+        let node = Box::into_raw(value.0);
+        unsafe {
+            kernel_sys::epoch_call(
+                self.epoch,
+                Some(reclaim::<T>),
+                &raw mut (*node).ctx,
+            );
+        }
+    }
+
+    /// Sleep until everything retired so far has been dropped
+    pub fn drain(&self) {
+        unsafe { kernel_sys::epoch_drain_callbacks(self.epoch) };
+    }
+}
+
+impl Drop for Epoch {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::epoch_drain_callbacks(self.epoch);
+            kernel_sys::epoch_free(self.epoch);
+        }
+    }
+}
+
+impl fmt::Debug for Epoch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Epoch {{ epoch: {:p} }}", self.epoch)
+    }
+}
+
+/// Proof of being in a section of `epoch`, which what's read with it
+/// can't outlive
+pub struct EpochGuard<'a> {
+    epoch: &'a Epoch,
+    // The section belongs to the thread that entered it
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl<'a> EpochGuard<'a> {
+    pub fn epoch(&self) -> &'a Epoch {
+        self.epoch
+    }
+}
+
+impl fmt::Debug for EpochGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EpochGuard {{ epoch: {:p} }}", self.epoch.epoch)
+    }
+}
+
+// The kernel finds the value from the context it's given
+#[repr(C)]
+struct Node<T> {
+    ctx: kernel_sys::epoch_context,
+    value: T,
+}
+
+/// A heap allocated `T` with room for the kernel's bookkeeping, so
+/// `Epoch::retire` needs no allocation of its own
+pub struct EpochBox<T>(Box<Node<T>>);
+
+impl<T> EpochBox<T> {
+    pub fn new(value: T) -> Self {
+        EpochBox(Box::new(Node {
+            ctx: unsafe { mem::zeroed() },
+            value,
+        }))
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.value
+    }
+}
+
+impl<T> Deref for EpochBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0.value
+    }
+}
+
+impl<T> DerefMut for EpochBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0.value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for EpochBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EpochBox {{ value: {:?} }}", self.0.value)
+    }
+}
+
+unsafe extern "C" fn reclaim<T>(ctx: kernel_sys::epoch_context_t) {
+    let node = unsafe { Box::from_raw(ctx as *mut Node<T>) };
+    let _ = catch_in_module(|| drop(node));
+}
+
+/// A `T` that readers in sections of an epoch share and writers replace
+/// whole, retiring the old one to the epoch
+///
+/// Writers must serialize among themselves, or one's replacement may be
+/// lost to another's. Dropping the cell waits for the values it retired to
+/// be dropped, so it may sleep.
+pub struct EpochCell<'e, T: Send + Sync + 'static> {
+    epoch: &'e Epoch,
+    node: AtomicPtr<Node<T>>,
+    _value: PhantomData<Box<Node<T>>>,
+}
+
+impl<'e, T: Send + Sync + 'static> EpochCell<'e, T> {
+    pub fn new(epoch: &'e Epoch, value: T) -> Self {
+        EpochCell {
+            epoch,
+            node: AtomicPtr::new(Box::into_raw(EpochBox::new(value).0)),
+            _value: PhantomData,
+        }
+    }
+
+    /// The current value, for the rest of `guard`'s section
+    ///
+    /// ## Panics
+    /// If `guard` is for a section of another epoch than the cell's
+    pub fn get<'g>(&'g self, guard: &'g EpochGuard<'_>) -> &'g T {
+        assert!(
+            guard.epoch.epoch == self.epoch.epoch,
+            "EpochCell read in another epoch"
+        );
+        unsafe { &(*self.node.load(Ordering::Acquire)).value }
+    }
+
+    /// The current value, which no reader can see when the cell is
+    /// borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut (*self.node.load(Ordering::Relaxed)).value }
+    }
+
+    /// Make `value` the current value, and retire the old one
+    pub fn replace(&self, value: T) {
+        let new = Box::into_raw(EpochBox::new(value).0);
+        let old = self.node.swap(new, Ordering::AcqRel);
+        self.epoch.retire(EpochBox(unsafe { Box::from_raw(old) }));
+    }
+
+    pub fn epoch(&self) -> &'e Epoch {
+        self.epoch
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for EpochCell<'_, T> {
+    fn drop(&mut self) {
+        let node = mem::replace(self.node.get_mut(), ptr::null_mut());
+        drop(unsafe { Box::from_raw(node) });
+        self.epoch.drain();
+    }
+}
+
+impl<T: Send + Sync + fmt::Debug + 'static> fmt::Debug for EpochCell<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.epoch
+            .read(|g| write!(f, "EpochCell {{ value: {:?} }}", self.get(g)))
+    }
+}
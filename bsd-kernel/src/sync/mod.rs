@@ -0,0 +1,60 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Synchronization primitives built on the kernel's locking facilities,
+//! and `Once`, `OnceLock` and `Lazy` for initializing `static`s on first use
+//!
+//! Which lock fits depends on where it is taken and what its holders do:
+//!
+//! - `SpinMutex`: from filter interrupt handlers too (`FilterSafe`), for
+//!   short sections that block on nothing else
+//! - `Mutex` and `RwLock`: from threads, interrupt threads, `bio`
+//!   completion and callouts; holders may block on other such locks, but
+//!   must not sleep
+//! - `SxLock`: from threads only, and holders may sleep (`Sleepable`),
+//!   e.g. in `uiomove(9)`
+//!
+//! Data read far more often than it's changed may instead be read in
+//! `Epoch` sections, which readers enter without a lock.
+
+pub use self::channel::{channel, sync_channel};
+pub use self::condvar::{Condvar, WaitTimeoutResult};
+pub use self::epoch::{Epoch, EpochBox, EpochCell, EpochGuard};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::once::{Lazy, Once, OnceLock};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::sleep::{
+    pause, sleep, sleep_sig, sleep_with, sleep_with_sig, wakeup, wakeup_one,
+};
+pub use self::spin::{FilterSafe, SpinMutex, SpinMutexGuard};
+pub use self::sx::{Sleepable, SxLock, SxReadGuard, SxWriteGuard};
+
+pub mod channel;
+mod condvar;
+mod epoch;
+mod mutex;
+mod once;
+mod rwlock;
+pub mod sleep;
+mod spin;
+mod sx;
@@ -0,0 +1,220 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::{CStr, c_char};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::{fmt, mem, ptr};
+
+/// A mutual exclusion lock protecting a `T`, wrapping `mutex(9)` with a
+/// default (sleep-capable) mutex
+///
+/// The name is what `witness(4)` and lock profiling report for the lock.
+/// Unlike `std::sync::Mutex` there is no poisoning: a panic in the kernel
+/// doesn't unwind, so a guard can't be dropped half-way through an update.
+pub struct Mutex<T: ?Sized> {
+    // Boxed so the lock keeps a stable address when the Mutex is moved
+    mtx: Box<UnsafeCell<kernel_sys::mtx>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Create a new mutex named `name` protecting `data`
+    pub fn new(name: &'static CStr, data: T) -> Self {
+        Self::init(name, ptr::null(), data)
+    }
+
+    /// Like `new`, but of the lock type `kind` for `witness(4)`, which
+    /// checks lock order by type: per-instance names such as `"rustfifo0"`
+    /// then share one order. Holding two locks of a type at once is
+    /// reported as taking a duplicate
+    pub fn with_type(
+        name: &'static CStr,
+        kind: &'static CStr,
+        data: T,
+    ) -> Self {
+        Self::init(name, kind.as_ptr(), data)
+    }
+
+    fn init(name: &'static CStr, kind: *const c_char, data: T) -> Self {
+        let mtx: Box<UnsafeCell<kernel_sys::mtx>> =
+            Box::new(UnsafeCell::new(unsafe { mem::zeroed() }));
+        unsafe {
+            kernel_sys::_mtx_init(
+                &raw mut (*mtx.get()).mtx_lock,
+                name.as_ptr(),
+                kind,
+                kernel_sys::MTX_DEF | kernel_sys::MTX_NEW,
+            );
+        }
+        Mutex {
+            mtx,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the mutex, returning the protected data
+    pub fn into_inner(self) -> T {
+        let this = mem::ManuallyDrop::new(self);
+        unsafe {
+            kernel_sys::_mtx_destroy(&raw mut (*this.mtx.get()).mtx_lock);
+            let mtx = ptr::read(&this.mtx);
+            drop(mtx);
+            ptr::read(&this.data).into_inner()
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub(crate) fn raw(&self) -> *mut kernel_sys::mtx {
+        self.mtx.get()
+    }
+
+    /// Pointer to the data, for code the kernel calls with the lock held
+    #[cfg(not(feature = "mock"))]
+    pub(crate) fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    /// Pointer to the embedded `lock_object`, for primitives like
+    /// condition variables that release and reacquire the lock
+    pub(crate) fn lock_object(&self) -> *mut kernel_sys::lock_object {
+        unsafe { &raw mut (*self.raw()).lock_object }
+    }
+
+    /// Acquire the lock, sleeping until it is available
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        unsafe {
+            kernel_sys::_mtx_lock_flags(
+                &raw mut (*self.raw()).mtx_lock,
+                0,
+                ptr::null(),
+                0,
+            );
+        }
+        MutexGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempt to acquire the lock without sleeping
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let ret = unsafe {
+            kernel_sys::_mtx_trylock_flags_(
+                &raw mut (*self.raw()).mtx_lock,
+                0,
+                ptr::null(),
+                0,
+            )
+        };
+        if ret != 0 {
+            Some(MutexGuard {
+                lock: self,
+                _not_send: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The name the lock was created with
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.mtx.get()).lock_object.lo_name) }
+    }
+
+    /// Mutable access to the data without locking, which is safe because
+    /// the mutable borrow guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        unsafe {
+            kernel_sys::_mtx_unlock_flags(
+                &raw mut (*self.raw()).mtx_lock,
+                0,
+                ptr::null(),
+                0,
+            );
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::_mtx_destroy(&raw mut (*self.raw()).mtx_lock) };
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mutex {{ mtx: {:?}, .. }}", self.raw())
+    }
+}
+
+/// RAII guard for a locked `Mutex`; the lock is released on drop
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a Mutex<T>,
+    // Only the owning thread may unlock
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    pub(crate) fn mutex(&self) -> &'a Mutex<T> {
+        self.lock
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
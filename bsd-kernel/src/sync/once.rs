@@ -0,0 +1,251 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, Ordering};
+use libc::c_void;
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+const POISONED: u8 = 3;
+
+/// Runs a piece of initialization exactly once, like `std::sync::Once`
+///
+/// Callers that arrive while another is running the initializer sleep
+/// until it is done, so the initializer may itself sleep, e.g. to
+/// allocate. Only once it has completed is `call_once` safe where sleeping
+/// isn't.
+///
+/// An initializer that panics and unwinds, as in the mock, poisons the
+/// `Once`, and waiters and later callers panic in turn. In the kernel a
+/// panic doesn't unwind: the thread parks and the `Once` stays running,
+/// so threads waiting for it sleep for good as well. The module is
+/// poisoned by then and refuses new calls, so only threads already inside
+/// it can be left waiting.
+pub struct Once {
+    state: AtomicU8,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Run `f` if no call has yet, otherwise wait for the one that did to
+    /// finish. `f` must not use the same `Once`, which would never finish
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if !self.is_completed() {
+            self.call_once_slow(f);
+        }
+    }
+
+    #[cold]
+    fn call_once_slow<F: FnOnce()>(&self, f: F) {
+        let chan = &raw const self.state as *const c_void;
+        loop {
+            match self.state.compare_exchange(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let mut finish = Finish {
+                        state: &self.state,
+                        to: POISONED,
+                    };
+                    f();
+                    finish.to = COMPLETE;
+                    return;
+                }
+                Err(COMPLETE) => return,
+                Err(POISONED) => panic!("Once poisoned by a panic"),
+                Err(_) => unsafe {
+                    // The sleep queue lock is the interlock: `wakeup` takes
+                    // it too, so the state can't complete between the
+                    // check and the sleep without waking it
+                    kernel_sys::sleepq_lock(chan);
+                    if self.state.load(Ordering::Acquire) != RUNNING {
+                        kernel_sys::sleepq_release(chan);
+                        continue;
+                    }
+                    kernel_sys::sleepq_add(
+                        chan,
+                        ptr::null_mut(),
+                        c"once".as_ptr(),
+                        kernel_sys::SLEEPQ_SLEEP as _,
+                        0,
+                    );
+                    kernel_sys::sleepq_wait(chan, 0);
+                },
+            }
+        }
+    }
+}
+
+/// Settles a running `Once` and wakes its waiters, poisoning it if the
+/// initializer unwinds
+struct Finish<'a> {
+    state: &'a AtomicU8,
+    to: u8,
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        self.state.store(self.to, Ordering::Release);
+        unsafe { kernel_sys::wakeup(self.state as *const _ as *const c_void) };
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Once::new()
+    }
+}
+
+impl fmt::Debug for Once {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Once {{ completed: {} }}", self.is_completed())
+    }
+}
+
+/// A value set at most once, like `std::sync::OnceLock`. Usable in a
+/// `static`
+pub struct OnceLock<T> {
+    once: Once,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for OnceLock<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    pub const fn new() -> Self {
+        OnceLock {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The value, if it has been set
+    pub fn get(&self) -> Option<&T> {
+        self.once
+            .is_completed()
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Set the value, or hand `value` back if it was already set
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// The value, set with `f` first if it hasn't been. Sleeps while
+    /// another caller's `f` is running, see `Once::call_once`
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        self.once.call_once(|| unsafe {
+            (*self.value.get()).write(f());
+        });
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        OnceLock::new()
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OnceLock {{ value: {:?} }}", self.get())
+    }
+}
+
+/// A value computed on first use, like `std::sync::LazyLock`, for
+/// `static`s that need allocation or other runtime setup:
+/// ```rust,ignore
+/// static MODULE: Lazy<SharedModule<Hello>> =
+///     Lazy::new(|| SharedModule::new(Hello::new()));
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceLock<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+// `init` is only touched by the one caller that runs it
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            cell: OnceLock::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// The value, computing it first if this is the first use
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.cell.get_or_init(|| {
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy initializer used twice")()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Lazy {{ value: {:?} }}", self.cell.get())
+    }
+}
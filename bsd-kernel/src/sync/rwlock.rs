@@ -0,0 +1,223 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::{fmt, mem, ptr};
+
+/// A reader-writer lock protecting a `T`, wrapping `rwlock(9)`
+///
+/// Any number of readers, or one writer, hold it at a time. Like a default
+/// `Mutex` its holders may block on other locks but must not sleep, so it
+/// suits data read often from paths that can't sleep, such as `bio`
+/// completion. There is no poisoning, as for `Mutex`.
+pub struct RwLock<T: ?Sized> {
+    // Boxed so the lock keeps a stable address when the RwLock is moved
+    rw: Box<UnsafeCell<kernel_sys::rwlock>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Create a new reader-writer lock named `name` protecting `data`
+    pub fn new(name: &'static CStr, data: T) -> Self {
+        let rw: Box<UnsafeCell<kernel_sys::rwlock>> =
+            Box::new(UnsafeCell::new(unsafe { mem::zeroed() }));
+        unsafe {
+            kernel_sys::_rw_init_flags(
+                &raw mut (*rw.get()).rw_lock,
+                name.as_ptr(),
+                kernel_sys::RW_NEW,
+            );
+        }
+        RwLock {
+            rw,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the lock, returning the protected data
+    pub fn into_inner(self) -> T {
+        let this = mem::ManuallyDrop::new(self);
+        unsafe {
+            kernel_sys::_rw_destroy(this.word());
+            let rw = ptr::read(&this.rw);
+            drop(rw);
+            ptr::read(&this.data).into_inner()
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn word(&self) -> *mut usize {
+        unsafe { &raw mut (*self.rw.get()).rw_lock }
+    }
+
+    /// Acquire the lock shared, blocking while a writer holds it
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        unsafe { kernel_sys::__rw_rlock(self.word(), ptr::null(), 0) };
+        RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempt to acquire the lock shared without blocking
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let ret =
+            unsafe { kernel_sys::__rw_try_rlock(self.word(), ptr::null(), 0) };
+        (ret != 0).then(|| RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Acquire the lock exclusive, blocking while anyone else holds it
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        unsafe { kernel_sys::_rw_wlock_cookie(self.word(), ptr::null(), 0) };
+        RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempt to acquire the lock exclusive without blocking
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let ret =
+            unsafe { kernel_sys::__rw_try_wlock(self.word(), ptr::null(), 0) };
+        (ret != 0).then(|| RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// The name the lock was created with
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.rw.get()).lock_object.lo_name) }
+    }
+
+    /// Mutable access to the data without locking, which is safe because
+    /// the mutable borrow guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::_rw_destroy(self.word()) };
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RwLock {{ rw: {:?}, .. }}", self.rw.get())
+    }
+}
+
+/// RAII guard for a `RwLock` held shared; the lock is released on drop
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    // Only the owning thread may unlock
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::_rw_runlock_cookie(self.lock.word(), ptr::null(), 0);
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// RAII guard for a `RwLock` held exclusive; the lock is released on drop
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    // Only the owning thread may unlock
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    /// Let readers in without releasing the lock, so that nothing can
+    /// change between the write and the reads that follow it
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        let lock = mem::ManuallyDrop::new(self).lock;
+        unsafe {
+            kernel_sys::_rw_downgrade_cookie(lock.word(), ptr::null(), 0);
+        }
+        RwLockReadGuard {
+            lock,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::_rw_wunlock_cookie(self.lock.word(), ptr::null(), 0);
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
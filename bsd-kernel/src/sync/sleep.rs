@@ -0,0 +1,133 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! `sleep(9)` and `wakeup(9)`: sleeping on, and waking, any address
+//!
+//! A `Condvar` is usually the better fit, but sleeping on an address
+//! needs no extra state, and is what C code sharing the address expects.
+//! Wakeups may be spurious, so sleepers recheck their condition in a loop,
+//! and without an interlock the wakeup may come before the sleep:
+//!
+//! ```rust,ignore
+//! let mut ready = state.lock();
+//! while !ready.done {
+//!     let (guard, res) = sync::sleep_with_sig(&*state, ready, c"wait", None);
+//!     ready = guard;
+//!     res?;
+//! }
+//! // elsewhere, with the lock held or just after setting `done`
+//! sync::wakeup(&*state);
+//! ```
+
+use super::mutex::MutexGuard;
+use crate::errno::Errno;
+use crate::time::duration_to_sbt;
+use core::ffi::{CStr, c_int, c_void};
+use core::ptr;
+use core::time::Duration;
+
+fn chan<C: ?Sized>(chan: &C) -> *const c_void {
+    chan as *const C as *const c_void
+}
+
+fn sbt(timeout: Option<Duration>) -> kernel_sys::sbintime_t {
+    // Zero would sleep forever
+    timeout.map_or(0, |t| duration_to_sbt(t).max(1))
+}
+
+unsafe fn sleep_raw(
+    chan: *const c_void,
+    lock: *mut kernel_sys::lock_object,
+    pri: c_int,
+    wmesg: &'static CStr,
+    timeout: Option<Duration>,
+) -> Result<(), Errno> {
+    let ret = unsafe {
+        kernel_sys::_sleep(chan, lock, pri, wmesg.as_ptr(), sbt(timeout), 0, 0)
+    };
+    Errno::result(ret)
+}
+
+/// Sleep until `chan` is woken, or `timeout` passes with
+/// `Errno::Again`. `wmesg` is what `ps(1)` shows the thread waiting on
+pub fn sleep<C: ?Sized>(
+    chan: &C,
+    wmesg: &'static CStr,
+    timeout: Option<Duration>,
+) -> Result<(), Errno> {
+    unsafe { sleep_raw(self::chan(chan), ptr::null_mut(), 0, wmesg, timeout) }
+}
+
+/// Like `sleep`, but a signal interrupts it with `Errno::Intr` or
+/// `Errno::Restart`, as a read from userland should be
+pub fn sleep_sig<C: ?Sized>(
+    chan: &C,
+    wmesg: &'static CStr,
+    timeout: Option<Duration>,
+) -> Result<(), Errno> {
+    let chan = self::chan(chan);
+    let pri = kernel_sys::PCATCH;
+    unsafe { sleep_raw(chan, ptr::null_mut(), pri, wmesg, timeout) }
+}
+
+/// Like `sleep`, but the lock `guard` holds is released for the sleep and
+/// reacquired after it, so a wakeup made with the lock held can't be
+/// missed, see `msleep(9)`
+pub fn sleep_with<'a, C: ?Sized, T: ?Sized>(
+    chan: &C,
+    guard: MutexGuard<'a, T>,
+    wmesg: &'static CStr,
+    timeout: Option<Duration>,
+) -> (MutexGuard<'a, T>, Result<(), Errno>) {
+    let lock = guard.mutex().lock_object();
+    let res = unsafe { sleep_raw(self::chan(chan), lock, 0, wmesg, timeout) };
+    (guard, res)
+}
+
+/// `sleep_with` and `sleep_sig` together
+pub fn sleep_with_sig<'a, C: ?Sized, T: ?Sized>(
+    chan: &C,
+    guard: MutexGuard<'a, T>,
+    wmesg: &'static CStr,
+    timeout: Option<Duration>,
+) -> (MutexGuard<'a, T>, Result<(), Errno>) {
+    let lock = guard.mutex().lock_object();
+    let pri = kernel_sys::PCATCH;
+    let res = unsafe { sleep_raw(self::chan(chan), lock, pri, wmesg, timeout) };
+    (guard, res)
+}
+
+/// Wake every thread sleeping on `chan`
+pub fn wakeup<C: ?Sized>(chan: &C) {
+    unsafe { kernel_sys::wakeup(self::chan(chan)) };
+}
+
+/// Wake the highest priority thread sleeping on `chan`
+pub fn wakeup_one<C: ?Sized>(chan: &C) {
+    unsafe { kernel_sys::wakeup_one(self::chan(chan)) };
+}
+
+/// Sleep for `duration`, without being woken early, see `pause(9)`
+pub fn pause(wmesg: &'static CStr, duration: Duration) {
+    let sbt = duration_to_sbt(duration).max(1);
+    unsafe { kernel_sys::pause_sbt(wmesg.as_ptr(), sbt, 0, 0) };
+}
@@ -0,0 +1,192 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::{CStr, c_char};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize};
+use core::{fmt, mem, ptr};
+
+/// Marks what may be used from a filter interrupt handler, which runs
+/// with interrupts disabled on whatever thread was interrupted: never a
+/// sleep-capable `Mutex`, only spin locks and atomics
+///
+/// ## Safety
+/// Every operation on the type must be safe with interrupts disabled,
+/// and must never sleep or take a default mutex
+pub unsafe trait FilterSafe: Sync {}
+
+unsafe impl<T: ?Sized + Send> FilterSafe for SpinMutex<T> {}
+unsafe impl FilterSafe for AtomicBool {}
+unsafe impl FilterSafe for AtomicI32 {}
+unsafe impl FilterSafe for AtomicU32 {}
+unsafe impl FilterSafe for AtomicUsize {}
+
+/// A mutual exclusion lock protecting a `T`, wrapping `mutex(9)` with a
+/// spin mutex (`MTX_SPIN`)
+///
+/// Holding it disables interrupts on the CPU, so state it protects can
+/// be shared with filter interrupt handlers. Sections must be short, and
+/// must not sleep or take a default `Mutex`.
+pub struct SpinMutex<T: ?Sized> {
+    // Boxed so the lock keeps a stable address when the mutex is moved
+    mtx: Box<UnsafeCell<kernel_sys::mtx>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinMutex<T> {}
+
+impl<T> SpinMutex<T> {
+    /// Create a new spin mutex named `name` protecting `data`
+    pub fn new(name: &'static CStr, data: T) -> Self {
+        Self::init(name, ptr::null(), data)
+    }
+
+    /// Like `new`, but of the lock type `kind` for `witness(4)`, which
+    /// checks lock order by type: per-instance names such as `"rustfifo0"`
+    /// then share one order. Holding two locks of a type at once is
+    /// reported as taking a duplicate
+    pub fn with_type(
+        name: &'static CStr,
+        kind: &'static CStr,
+        data: T,
+    ) -> Self {
+        Self::init(name, kind.as_ptr(), data)
+    }
+
+    fn init(name: &'static CStr, kind: *const c_char, data: T) -> Self {
+        let mtx: Box<UnsafeCell<kernel_sys::mtx>> =
+            Box::new(UnsafeCell::new(unsafe { mem::zeroed() }));
+        unsafe {
+            kernel_sys::_mtx_init(
+                &raw mut (*mtx.get()).mtx_lock,
+                name.as_ptr(),
+                kind,
+                kernel_sys::MTX_SPIN | kernel_sys::MTX_NEW,
+            );
+        }
+        SpinMutex {
+            mtx,
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinMutex<T> {
+    fn word(&self) -> *mut usize {
+        unsafe { &raw mut (*self.mtx.get()).mtx_lock }
+    }
+
+    /// Acquire the lock, spinning with interrupts disabled until it is
+    /// available
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        unsafe {
+            kernel_sys::_mtx_lock_spin_flags(self.word(), 0, ptr::null(), 0);
+        }
+        SpinMutexGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempt to acquire the lock without spinning
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        let ret = unsafe {
+            kernel_sys::_mtx_trylock_spin_flags(self.word(), 0, ptr::null(), 0)
+        };
+        (ret != 0).then(|| SpinMutexGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// The name the lock was created with
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.mtx.get()).lock_object.lo_name) }
+    }
+
+    /// Mutable access to the data without locking, which is safe because
+    /// the mutable borrow guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for SpinMutex<T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::_mtx_destroy(self.word()) };
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SpinMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SpinMutex {{ mtx: {:?}, .. }}", self.mtx.get())
+    }
+}
+
+/// RAII guard for a locked `SpinMutex`; the lock is released, and
+/// interrupts restored, on drop
+pub struct SpinMutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a SpinMutex<T>,
+    // Interrupts were disabled on the locking CPU, so only the owning
+    // thread may unlock
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for SpinMutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::_mtx_unlock_spin_flags(
+                self.lock.word(),
+                0,
+                ptr::null(),
+                0,
+            );
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use crate::errno::Errno;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::{fmt, mem, ptr};
+
+/// Marks locks that may be held while sleeping: across `uiomove(9)`,
+/// `malloc(9)` with `M_WAITOK`, `Condvar` waits and the like
+///
+/// Of the locks here only `SxLock` qualifies. A `Mutex` or `RwLock` may be
+/// held while blocking on another lock of the same kinds, never while
+/// sleeping, and a `SpinMutex` (`FilterSafe`) only for short sections
+/// that block on nothing but other spin mutexes. A sleepable lock can't be
+/// taken with a default mutex or reader-writer lock held.
+///
+/// ## Safety
+/// The lock must stay correct when its holder sleeps
+pub unsafe trait Sleepable {}
+
+unsafe impl<T: ?Sized + Send> Sleepable for SxLock<T> {}
+
+/// A shared/exclusive lock protecting a `T`, wrapping `sx(9)`
+///
+/// Like `RwLock` it has any number of readers or one writer, but its
+/// holders may sleep, so it suits state held across copies to and from
+/// userland, such as a character device's buffers. It can't be taken
+/// from paths that mustn't sleep, such as `bio` completion or callouts.
+pub struct SxLock<T: ?Sized> {
+    // Boxed so the lock keeps a stable address when the SxLock is moved
+    sx: Box<UnsafeCell<kernel_sys::sx>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SxLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for SxLock<T> {}
+
+impl<T> SxLock<T> {
+    /// Create a new shared/exclusive lock named `name` protecting `data`
+    pub fn new(name: &'static CStr, data: T) -> Self {
+        let sx: Box<UnsafeCell<kernel_sys::sx>> =
+            Box::new(UnsafeCell::new(unsafe { mem::zeroed() }));
+        unsafe {
+            kernel_sys::sx_init_flags(
+                sx.get(),
+                name.as_ptr(),
+                kernel_sys::SX_NEW,
+            );
+        }
+        SxLock {
+            sx,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the lock, returning the protected data
+    pub fn into_inner(self) -> T {
+        let this = mem::ManuallyDrop::new(self);
+        unsafe {
+            kernel_sys::sx_destroy(this.sx.get());
+            let sx = ptr::read(&this.sx);
+            drop(sx);
+            ptr::read(&this.data).into_inner()
+        }
+    }
+}
+
+impl<T: ?Sized> SxLock<T> {
+    /// Acquire the lock shared, sleeping while a writer holds it
+    pub fn read(&self) -> SxReadGuard<'_, T> {
+        unsafe { kernel_sys::_sx_slock(self.sx.get(), 0, ptr::null(), 0) };
+        SxReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Like `read`, but the sleep may be interrupted by a signal, in which
+    /// case `Errno::Intr` or `Errno::Restart` is returned
+    pub fn read_sig(&self) -> Result<SxReadGuard<'_, T>, Errno> {
+        let ret = unsafe {
+            kernel_sys::_sx_slock(
+                self.sx.get(),
+                kernel_sys::SX_INTERRUPTIBLE,
+                ptr::null(),
+                0,
+            )
+        };
+        Errno::result(ret).map(|()| SxReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Attempt to acquire the lock shared without sleeping
+    pub fn try_read(&self) -> Option<SxReadGuard<'_, T>> {
+        let ret =
+            unsafe { kernel_sys::sx_try_slock_(self.sx.get(), ptr::null(), 0) };
+        (ret != 0).then(|| SxReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Acquire the lock exclusive, sleeping while anyone else holds it
+    pub fn write(&self) -> SxWriteGuard<'_, T> {
+        unsafe { kernel_sys::_sx_xlock(self.sx.get(), 0, ptr::null(), 0) };
+        SxWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Like `write`, but the sleep may be interrupted by a signal, in which
+    /// case `Errno::Intr` or `Errno::Restart` is returned
+    pub fn write_sig(&self) -> Result<SxWriteGuard<'_, T>, Errno> {
+        let ret = unsafe {
+            kernel_sys::_sx_xlock(
+                self.sx.get(),
+                kernel_sys::SX_INTERRUPTIBLE,
+                ptr::null(),
+                0,
+            )
+        };
+        Errno::result(ret).map(|()| SxWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Attempt to acquire the lock exclusive without sleeping
+    pub fn try_write(&self) -> Option<SxWriteGuard<'_, T>> {
+        let ret =
+            unsafe { kernel_sys::sx_try_xlock_(self.sx.get(), ptr::null(), 0) };
+        (ret != 0).then(|| SxWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// The name the lock was created with
+    pub fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr((*self.sx.get()).lock_object.lo_name) }
+    }
+
+    /// Mutable access to the data without locking, which is safe because
+    /// the mutable borrow guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for SxLock<T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::sx_destroy(self.sx.get()) };
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SxLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SxLock {{ sx: {:?}, .. }}", self.sx.get())
+    }
+}
+
+/// RAII guard for an `SxLock` held shared; the lock is released on drop
+pub struct SxReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a SxLock<T>,
+    // Only the owning thread may unlock
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for SxReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for SxReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SxReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::_sx_sunlock(self.lock.sx.get(), ptr::null(), 0) };
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SxReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// RAII guard for an `SxLock` held exclusive; the lock is released on drop
+pub struct SxWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a SxLock<T>,
+    // Only the owning thread may unlock
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for SxWriteGuard<'_, T> {}
+
+impl<'a, T: ?Sized> SxWriteGuard<'a, T> {
+    /// Let readers in without releasing the lock, so that nothing can
+    /// change between the write and the reads that follow it
+    pub fn downgrade(self) -> SxReadGuard<'a, T> {
+        let lock = mem::ManuallyDrop::new(self).lock;
+        unsafe { kernel_sys::sx_downgrade_(lock.sx.get(), ptr::null(), 0) };
+        SxReadGuard {
+            lock,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for SxWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SxWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SxWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::_sx_xunlock(self.lock.sx.get(), ptr::null(), 0) };
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SxWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,775 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Dynamic sysctl nodes, see `sysctl_add_oid(9)`
+//!
+//! A `Context` owns the oids added through it, together with the closures
+//! backing them, and removes them all when dropped. Integers and bools are
+//! added with `add_u64`, computed on each read, or `add_atomic`, shared
+//! with the module and writable. Values that don't fit
+//! a single integer are added with `add_proc`, whose closure streams the
+//! data through a `Request`; `add_string` and `add_struct` build on it
+//! for values shared with the rest of the module. The `sysctl!` macro
+//! declares a whole subtree at once.
+
+use crate::counter::Counter;
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::sync::Mutex;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::sync::atomic::{
+    AtomicBool, AtomicI32, AtomicI64, AtomicU32, AtomicU64, Ordering,
+};
+use core::{fmt, mem, ptr, slice};
+use libc::{c_int, c_void};
+
+/// A list of sysctl children that new oids can be added to
+#[derive(Copy, Clone)]
+pub struct Node {
+    list: ptr::NonNull<kernel_sys::sysctl_oid_list>,
+}
+
+unsafe impl Send for Node {}
+unsafe impl Sync for Node {}
+
+impl Node {
+    /// Wrap a children list, such as one declared with `SYSCTL_NODE` in C
+    ///
+    /// ## Safety
+    /// `list` must be valid for as long as the node is used
+    pub unsafe fn from_raw(list: *mut kernel_sys::sysctl_oid_list) -> Self {
+        Node {
+            list: ptr::NonNull::new(list).unwrap(),
+        }
+    }
+
+    /// The `kern` tree
+    pub fn kern() -> Self {
+        unsafe { Node::from_raw(&raw mut kernel_sys::sysctl__kern_children) }
+    }
+
+    /// The `debug` tree
+    pub fn debug() -> Self {
+        unsafe { Node::from_raw(&raw mut kernel_sys::sysctl__debug_children) }
+    }
+
+    /// The `hw` tree
+    pub fn hw() -> Self {
+        unsafe { Node::from_raw(&raw mut kernel_sys::sysctl__hw_children) }
+    }
+
+    /// The `dev` tree
+    pub fn dev() -> Self {
+        unsafe { Node::from_raw(&raw mut kernel_sys::sysctl__dev_children) }
+    }
+
+    /// Raw pointer to the children list
+    pub fn as_ptr(&self) -> *mut kernel_sys::sysctl_oid_list {
+        self.list.as_ptr()
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Node {{ list: {:?} }}", self.list.as_ptr())
+    }
+}
+
+type Handler = unsafe extern "C" fn(
+    *mut kernel_sys::sysctl_oid,
+    *mut c_void,
+    kernel_sys::intmax_t,
+    *mut kernel_sys::sysctl_req,
+) -> c_int;
+
+type U64Fn = Box<dyn Fn() -> u64 + Send + Sync>;
+type ProcFn = Box<dyn Fn(&mut Request) -> Result<(), Errno> + Send + Sync>;
+
+/// Declare a function adding a sysctl subtree to a `Context`, the way C
+/// modules list `SYSCTL_*` declarations
+///
+/// ```rust,ignore
+/// sysctl! {
+///     /// Adds the FIFO's oids
+///     pub fn fifo_sysctls {
+///         u64 capacity: "Bytes buffered" = || CAPACITY as u64;
+///         atomic(rw) debug: "Log every transfer" = DEBUG.clone();
+///         counter(rw) errors: "Failed transfers" = ERRORS.clone();
+///         node stats: "Statistics" {
+///             u64 reads: "Reads served" = || READS.load(Ordering::Relaxed);
+///         }
+///         string(rw) name: "Device name" = NAME.clone(), max = 32;
+///         opaque(rd) layout: "Ring layout" = LAYOUT.clone();
+///         proc(text, rd) log: "Recent events" = |req| dump_log(req);
+///     }
+/// }
+/// ```
+///
+/// This defines `fn fifo_sysctls(ctx: &mut Context, parent: Node) ->
+/// Result<(), Errno>`, adding each entry under `parent` with the
+/// `Context` method of the same kind: `add_node`, `add_u64`,
+/// `add_atomic`, `add_counter`, `add_string`, `add_struct` and `add_proc`. Access is `rd` or `rw`.
+/// Dropping the `Context` unregisters the lot, as with oids added by
+/// hand, and `ModuleEvents::sysctl` is a natural place to call it from.
+#[macro_export]
+macro_rules! sysctl {
+    ($(#[$meta:meta])* $vis:vis fn $name:ident { $($body:tt)* }) => {
+        $(#[$meta])*
+        $vis fn $name(
+            ctx: &mut $crate::sysctl::Context,
+            parent: $crate::sysctl::Node,
+        ) -> ::core::result::Result<(), $crate::errno::Errno> {
+            $crate::sysctl!(@entries ctx, parent, $($body)*);
+            Ok(())
+        }
+    };
+
+    (@entries $ctx:ident, $parent:ident,) => {};
+    (@entries $ctx:ident, $parent:ident,
+        node $name:ident : $descr:literal { $($inner:tt)* } $($rest:tt)*
+    ) => {
+        {
+            let node = $ctx.add_node(
+                $parent,
+                $crate::cstr!(stringify!($name)),
+                $crate::cstr!($descr),
+            )?;
+            $crate::sysctl!(@entries $ctx, node, $($inner)*);
+        }
+        $crate::sysctl!(@entries $ctx, $parent, $($rest)*);
+    };
+    (@entries $ctx:ident, $parent:ident,
+        u64 $name:ident : $descr:literal = $f:expr; $($rest:tt)*
+    ) => {
+        $ctx.add_u64(
+            $parent,
+            $crate::cstr!(stringify!($name)),
+            $crate::cstr!($descr),
+            $f,
+        )?;
+        $crate::sysctl!(@entries $ctx, $parent, $($rest)*);
+    };
+    (@entries $ctx:ident, $parent:ident,
+        atomic($access:ident) $name:ident : $descr:literal = $value:expr;
+        $($rest:tt)*
+    ) => {
+        $ctx.add_atomic(
+            $parent,
+            $crate::cstr!(stringify!($name)),
+            $crate::cstr!($descr),
+            $value,
+            $crate::sysctl!(@writable $access),
+        )?;
+        $crate::sysctl!(@entries $ctx, $parent, $($rest)*);
+    };
+    (@entries $ctx:ident, $parent:ident,
+        counter($access:ident) $name:ident : $descr:literal = $value:expr;
+        $($rest:tt)*
+    ) => {
+        $ctx.add_counter(
+            $parent,
+            $crate::cstr!(stringify!($name)),
+            $crate::cstr!($descr),
+            $value,
+            $crate::sysctl!(@writable $access),
+        )?;
+        $crate::sysctl!(@entries $ctx, $parent, $($rest)*);
+    };
+    (@entries $ctx:ident, $parent:ident,
+        string($access:ident) $name:ident : $descr:literal = $value:expr
+        $(, max = $max:expr)?; $($rest:tt)*
+    ) => {
+        $ctx.add_string(
+            $parent,
+            $crate::cstr!(stringify!($name)),
+            $crate::cstr!($descr),
+            $value,
+            $crate::sysctl!(@writable $access),
+            None $(.or(Some($max)))?,
+        )?;
+        $crate::sysctl!(@entries $ctx, $parent, $($rest)*);
+    };
+    (@entries $ctx:ident, $parent:ident,
+        opaque($access:ident) $name:ident : $descr:literal = $value:expr;
+        $($rest:tt)*
+    ) => {
+        $ctx.add_struct(
+            $parent,
+            $crate::cstr!(stringify!($name)),
+            $crate::cstr!($descr),
+            $value,
+            $crate::sysctl!(@writable $access),
+        )?;
+        $crate::sysctl!(@entries $ctx, $parent, $($rest)*);
+    };
+    (@entries $ctx:ident, $parent:ident,
+        proc($format:ident, $access:ident) $name:ident : $descr:literal
+        = $f:expr; $($rest:tt)*
+    ) => {
+        $ctx.add_proc(
+            $parent,
+            $crate::cstr!(stringify!($name)),
+            $crate::sysctl!(@format $format),
+            $crate::sysctl!(@writable $access),
+            $crate::cstr!($descr),
+            $f,
+        )?;
+        $crate::sysctl!(@entries $ctx, $parent, $($rest)*);
+    };
+
+    (@writable rd) => { false };
+    (@writable rw) => { true };
+    (@format text) => { $crate::sysctl::Format::Text };
+    (@format opaque) => { $crate::sysctl::Format::Opaque };
+}
+
+/// Longest string `add_string` accepts without a `max_len`
+pub const STRING_MAX: usize = 64 * 1024;
+
+/// How `sysctl(8)` shows the data of an oid added with `add_proc`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// Printed as a string
+    Text,
+    /// Shown only with `-x` or `-b`, for tables and binary stats
+    Opaque,
+}
+
+/// An atomic that `Context::add_atomic` exports as a leaf of the matching
+/// sysctl type: `AtomicI32` as an `int`, `AtomicU32` as a `u_int`,
+/// `AtomicI64` and `AtomicU64` as 64-bit integers and `AtomicBool` as a
+/// `bool`
+pub trait Atomic: Send + Sync + 'static + sealed::Sealed {}
+
+mod sealed {
+    use super::Handler;
+    use core::ffi::CStr;
+    use libc::c_int;
+
+    pub trait Sealed {
+        type Value: Copy;
+        const KIND: c_int;
+        const FMT: &'static CStr;
+        const HANDLE: Handler;
+        fn get(&self) -> Self::Value;
+        fn set(&self, value: Self::Value);
+    }
+}
+
+macro_rules! atomic_leaf {
+    ($atomic:ty, $value:ty, $kind:ident, $fmt:literal, $handle:ident) => {
+        impl sealed::Sealed for $atomic {
+            type Value = $value;
+            const KIND: c_int = kernel_sys::$kind;
+            const FMT: &'static CStr = $fmt;
+            const HANDLE: Handler = kernel_sys::$handle;
+            fn get(&self) -> $value {
+                self.load(Ordering::Relaxed)
+            }
+            fn set(&self, value: $value) {
+                self.store(value, Ordering::Relaxed)
+            }
+        }
+        impl Atomic for $atomic {}
+    };
+}
+
+atomic_leaf!(AtomicI32, i32, CTLTYPE_INT, c"I", sysctl_handle_int);
+atomic_leaf!(AtomicU32, u32, CTLTYPE_UINT, c"IU", sysctl_handle_int);
+atomic_leaf!(AtomicI64, i64, CTLTYPE_S64, c"Q", sysctl_handle_64);
+atomic_leaf!(AtomicU64, u64, CTLTYPE_U64, c"QU", sysctl_handle_64);
+atomic_leaf!(AtomicBool, bool, CTLTYPE_U8, c"CU", sysctl_handle_bool);
+
+/// A read or write of an oid added with `add_proc`
+///
+/// Reads copy data out in as many `write_old` calls as convenient; if the
+/// caller's buffer is too small the rest is still counted, so that a
+/// caller asking for the size first learns how much to allocate. A write
+/// supplies `new_len()` bytes to take in with `read_new`.
+pub struct Request {
+    req: ptr::NonNull<kernel_sys::sysctl_req>,
+}
+
+impl Request {
+    fn raw(&self) -> &kernel_sys::sysctl_req {
+        unsafe { self.req.as_ref() }
+    }
+
+    /// Whether the caller only wants to know how big the data is, and has
+    /// no buffer for it (`SYSCTL_OUT` still counts what's written)
+    pub fn is_size_query(&self) -> bool {
+        self.raw().oldptr.is_null()
+    }
+
+    /// Copy `data` out to the caller, see `SYSCTL_OUT(9)`. Fails with
+    /// `Errno::NoMem` once the caller's buffer is full, which the handler
+    /// should return once it has counted everything
+    pub fn write_old(&mut self, data: &[u8]) -> Result<(), Errno> {
+        let req = self.req.as_ptr();
+        let f = self.raw().oldfunc.unwrap();
+        let err = unsafe { f(req, data.as_ptr() as *const c_void, data.len()) };
+        Errno::result(err)
+    }
+
+    /// Wire the caller's buffer so that `write_old` won't fault, which it
+    /// may otherwise do and sleep; needed before writing out with a
+    /// non-sleepable lock held. `len` is how much will be written, with 0
+    /// meaning all of it
+    pub fn wire_old(&mut self, len: usize) -> Result<(), Errno> {
+        let err = unsafe {
+            kernel_sys::sysctl_wire_old_buffer(self.req.as_ptr(), len)
+        };
+        Errno::result(err)
+    }
+
+    /// Whether this is a write, carrying new data
+    pub fn has_new(&self) -> bool {
+        !self.raw().newptr.is_null()
+    }
+
+    /// How many bytes of new data are left to read
+    pub fn new_len(&self) -> usize {
+        let req = self.raw();
+        req.newlen - req.newidx
+    }
+
+    /// Fill `buf` with the next bytes of new data, see `SYSCTL_IN(9)`.
+    /// Fails with `Errno::Inval` if fewer than `buf.len()` are left
+    pub fn read_new(&mut self, buf: &mut [u8]) -> Result<(), Errno> {
+        let req = self.req.as_ptr();
+        let f = self.raw().newfunc.unwrap();
+        let err = unsafe { f(req, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        Errno::result(err)
+    }
+}
+
+/// A `repr(C)` struct exported whole with `Context::add_struct`
+///
+/// ## Safety
+/// The type must have no padding, pointers or references, and every bit
+/// pattern must be a valid value, as data written in is taken as is
+pub unsafe trait Opaque: Copy + Send + 'static {
+    /// Changed whenever the layout does, so that readers can tell which
+    /// layout they were given and writes made for another are refused
+    const VERSION: u32;
+}
+
+/// What precedes the struct in the data of an `add_struct` oid, in the
+/// host's byte order
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct OpaqueHeader {
+    /// `Opaque::VERSION` of the layout that follows
+    pub version: u32,
+    /// Size of the struct that follows
+    pub len: u32,
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe {
+        slice::from_raw_parts(
+            value as *const T as *const u8,
+            mem::size_of::<T>(),
+        )
+    }
+}
+
+/// View `value` as bytes to be overwritten
+///
+/// ## Safety
+/// Any bytes written must make a valid `T`
+unsafe fn bytes_mut<T: Copy>(value: &mut T) -> &mut [u8] {
+    unsafe {
+        slice::from_raw_parts_mut(
+            value as *mut T as *mut u8,
+            mem::size_of::<T>(),
+        )
+    }
+}
+
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request {{ req: {:?} }}", self.req.as_ptr())
+    }
+}
+
+/// A set of dynamically added oids, removed when the context is dropped
+pub struct Context {
+    // Boxed so the list head keeps a stable address when the owner moves
+    ctx: Box<UnsafeCell<kernel_sys::sysctl_ctx_list>>,
+    // State the handlers point at, released only after the oids are gone
+    handlers: Vec<Box<dyn Send + Sync>>,
+}
+
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+impl Context {
+    /// Create an empty context
+    pub fn new() -> Self {
+        let ctx = Box::new(UnsafeCell::new(unsafe { mem::zeroed() }));
+        unsafe { kernel_sys::sysctl_ctx_init(ctx.get()) };
+        Context {
+            ctx,
+            handlers: Vec::new(),
+        }
+    }
+
+    fn add_oid(
+        &mut self,
+        parent: Node,
+        name: &CStr,
+        kind: c_int,
+        handler: Option<(Handler, *mut c_void)>,
+        fmt: &CStr,
+        descr: &CStr,
+    ) -> Result<*mut kernel_sys::sysctl_oid, Errno> {
+        let (handler, arg1) = match handler {
+            Some((h, arg1)) => (Some(h), arg1),
+            None => (None, ptr::null_mut()),
+        };
+        // The name and description are copied by the kernel
+        let oid = unsafe {
+            kernel_sys::sysctl_add_oid(
+                self.ctx.get(),
+                parent.as_ptr(),
+                kernel_sys::OID_AUTO,
+                name.as_ptr(),
+                kind | kernel_sys::CTLFLAG_MPSAFE,
+                arg1,
+                0,
+                handler,
+                fmt.as_ptr(),
+                descr.as_ptr(),
+                ptr::null(),
+            )
+        };
+        // sysctl_add_oid() only fails when the name is already taken
+        if oid.is_null() {
+            Err(Errno::Exist)
+        } else {
+            Ok(oid)
+        }
+    }
+
+    /// Add a node named `name` under `parent`, returning its children
+    ///
+    /// Fails with `Errno::Exist` if `parent` already has a leaf of that
+    /// name. Adding an existing node returns it again
+    pub fn add_node(
+        &mut self,
+        parent: Node,
+        name: &CStr,
+        descr: &CStr,
+    ) -> Result<Node, Errno> {
+        let kind = kernel_sys::CTLTYPE_NODE | kernel_sys::CTLFLAG_RD as c_int;
+        let oid = self.add_oid(parent, name, kind, None, c"N", descr)?;
+        Ok(unsafe { Node::from_raw(&raw mut (*oid).oid_children) })
+    }
+
+    /// Add a read-only 64-bit value under `parent`, computed by `f` each
+    /// time it is read
+    pub fn add_u64<F>(
+        &mut self,
+        parent: Node,
+        name: &CStr,
+        descr: &CStr,
+        f: F,
+    ) -> Result<(), Errno>
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        let f: Box<U64Fn> = Box::new(Box::new(f));
+        let arg1 = &raw const *f as *mut c_void;
+        let kind = kernel_sys::CTLTYPE_U64 | kernel_sys::CTLFLAG_RD as c_int;
+        self.add_oid(
+            parent,
+            name,
+            kind,
+            Some((u64_handler, arg1)),
+            c"QU",
+            descr,
+        )?;
+        self.handlers.push(f);
+        Ok(())
+    }
+
+    /// Add a 64-bit leaf under `parent` showing the sum of `counter`, as
+    /// `SYSCTL_ADD_COUNTER_U64`. If `writable`, writing any value zeroes it
+    pub fn add_counter(
+        &mut self,
+        parent: Node,
+        name: &CStr,
+        descr: &CStr,
+        counter: Arc<Counter>,
+        writable: bool,
+    ) -> Result<(), Errno> {
+        let arg1 = counter.as_ptr() as *mut c_void;
+        let access = if writable {
+            kernel_sys::CTLFLAG_RW
+        } else {
+            kernel_sys::CTLFLAG_RD
+        };
+        let kind = kernel_sys::CTLTYPE_U64
+            | kernel_sys::CTLFLAG_STATS
+            | access as c_int;
+        self.add_oid(
+            parent,
+            name,
+            kind,
+            Some((kernel_sys::sysctl_handle_counter_u64, arg1)),
+            c"QU",
+            descr,
+        )?;
+        self.handlers.push(Box::new(counter));
+        Ok(())
+    }
+
+    /// Add a leaf under `parent` backed by `value`, which the module reads
+    /// whenever it needs the setting or updates as it counts. If
+    /// `writable`, writes store to it
+    pub fn add_atomic<A: Atomic>(
+        &mut self,
+        parent: Node,
+        name: &CStr,
+        descr: &CStr,
+        value: Arc<A>,
+        writable: bool,
+    ) -> Result<(), Errno> {
+        let arg1 = Arc::as_ptr(&value) as *mut c_void;
+        let access = if writable {
+            kernel_sys::CTLFLAG_RW
+        } else {
+            kernel_sys::CTLFLAG_RD
+        };
+        let kind = A::KIND | access as c_int;
+        self.add_oid(
+            parent,
+            name,
+            kind,
+            Some((atomic_handler::<A>, arg1)),
+            A::FMT,
+            descr,
+        )?;
+        self.handlers.push(Box::new(value));
+        Ok(())
+    }
+
+    /// Add an oid under `parent` whose reads and, if `writable`, writes are
+    /// handled by `f`, for values larger than an integer such as tables,
+    /// logs or binary stats
+    pub fn add_proc<F>(
+        &mut self,
+        parent: Node,
+        name: &CStr,
+        format: Format,
+        writable: bool,
+        descr: &CStr,
+        f: F,
+    ) -> Result<(), Errno>
+    where
+        F: Fn(&mut Request) -> Result<(), Errno> + Send + Sync + 'static,
+    {
+        let f: Box<ProcFn> = Box::new(Box::new(f));
+        let arg1 = &raw const *f as *mut c_void;
+        let (kind, fmt) = match format {
+            Format::Text => (kernel_sys::CTLTYPE_STRING, c"A"),
+            Format::Opaque => (kernel_sys::CTLTYPE_OPAQUE, c"S"),
+        };
+        let access = if writable {
+            kernel_sys::CTLFLAG_RW
+        } else {
+            kernel_sys::CTLFLAG_RD
+        };
+        let kind = kind | access as c_int;
+        self.add_oid(
+            parent,
+            name,
+            kind,
+            Some((proc_handler, arg1)),
+            fmt,
+            descr,
+        )?;
+        self.handlers.push(f);
+        Ok(())
+    }
+
+    /// Add a string under `parent` backed by `value`, which the module
+    /// reads whenever it needs the setting. If `writable`, writes replace
+    /// it, up to `max_len` bytes if given, as for a fixed-size buffer, or
+    /// up to `STRING_MAX` if not. Writes that are too long or have NULs or
+    /// invalid UTF-8 in them fail with `Errno::Inval`
+    pub fn add_string(
+        &mut self,
+        parent: Node,
+        name: &CStr,
+        descr: &CStr,
+        value: Arc<Mutex<String>>,
+        writable: bool,
+        max_len: Option<usize>,
+    ) -> Result<(), Errno> {
+        let f = move |req: &mut Request| {
+            // Copied so the lock isn't held while faulting on user memory
+            let mut old = value.lock().clone().into_bytes();
+            old.push(0);
+            req.write_old(&old)?;
+            if !req.has_new() {
+                return Ok(());
+            }
+            // Checked before allocating, allowing for a trailing NUL
+            if req.new_len() > max_len.unwrap_or(STRING_MAX) + 1 {
+                return Err(Errno::Inval);
+            }
+            let mut new = vec![0; req.new_len()];
+            req.read_new(&mut new)?;
+            // sysctl(8) sends no NUL, but other callers may
+            if new.last() == Some(&0) {
+                new.pop();
+            }
+            if new.len() > max_len.unwrap_or(STRING_MAX) || new.contains(&0) {
+                return Err(Errno::Inval);
+            }
+            *value.lock() = String::from_utf8(new).map_err(|_| Errno::Inval)?;
+            Ok(())
+        };
+        self.add_proc(parent, name, Format::Text, writable, descr, f)
+    }
+
+    /// Add a struct under `parent` backed by `value`, read and, if
+    /// `writable`, written whole behind an `OpaqueHeader`. Writes for
+    /// another version or size of `T` fail with `Errno::Inval`
+    pub fn add_struct<T: Opaque>(
+        &mut self,
+        parent: Node,
+        name: &CStr,
+        descr: &CStr,
+        value: Arc<Mutex<T>>,
+        writable: bool,
+    ) -> Result<(), Errno> {
+        let header = OpaqueHeader {
+            version: T::VERSION,
+            len: mem::size_of::<T>() as u32,
+        };
+        let f = move |req: &mut Request| {
+            let old = *value.lock();
+            req.write_old(as_bytes(&header))?;
+            req.write_old(as_bytes(&old))?;
+            if !req.has_new() {
+                return Ok(());
+            }
+            if req.new_len() != mem::size_of_val(&header) + mem::size_of::<T>()
+            {
+                return Err(Errno::Inval);
+            }
+            let mut got = OpaqueHeader { version: 0, len: 0 };
+            req.read_new(unsafe { bytes_mut(&mut got) })?;
+            if got != header {
+                return Err(Errno::Inval);
+            }
+            let mut new = old;
+            req.read_new(unsafe { bytes_mut(&mut new) })?;
+            *value.lock() = new;
+            Ok(())
+        };
+        self.add_proc(parent, name, Format::Opaque, writable, descr, f)
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context::new()
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        // Waits for running handlers, after which `handlers` can go
+        unsafe { kernel_sys::sysctl_ctx_free(self.ctx.get()) };
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Context {{ handlers: {} }}", self.handlers.len())
+    }
+}
+
+unsafe extern "C" fn u64_handler(
+    oidp: *mut kernel_sys::sysctl_oid,
+    arg1: *mut c_void,
+    _arg2: kernel_sys::intmax_t,
+    req: *mut kernel_sys::sysctl_req,
+) -> c_int {
+    let f = unsafe { &*(arg1 as *const U64Fn) };
+    let mut value = match catch_in_module(f) {
+        Ok(value) => value,
+        Err(e) => return e.as_raw(),
+    };
+    unsafe {
+        kernel_sys::sysctl_handle_64(
+            oidp,
+            &raw mut value as *mut c_void,
+            0,
+            req,
+        )
+    }
+}
+
+unsafe extern "C" fn atomic_handler<A: Atomic>(
+    oidp: *mut kernel_sys::sysctl_oid,
+    arg1: *mut c_void,
+    _arg2: kernel_sys::intmax_t,
+    req: *mut kernel_sys::sysctl_req,
+) -> c_int {
+    let atomic = unsafe { &*(arg1 as *const A) };
+    // Handled on a copy, stored back only if the request wrote one
+    let mut value = atomic.get();
+    let error =
+        unsafe { A::HANDLE(oidp, &raw mut value as *mut c_void, 0, req) };
+    if error == 0 && !unsafe { (*req).newptr }.is_null() {
+        atomic.set(value);
+    }
+    error
+}
+
+unsafe extern "C" fn proc_handler(
+    _oidp: *mut kernel_sys::sysctl_oid,
+    arg1: *mut c_void,
+    _arg2: kernel_sys::intmax_t,
+    req: *mut kernel_sys::sysctl_req,
+) -> c_int {
+    let f = unsafe { &*(arg1 as *const ProcFn) };
+    let mut req = Request {
+        req: ptr::NonNull::new(req).unwrap(),
+    };
+    match catch_in_module(|| f(&mut req)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) | Err(e) => e.as_raw(),
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Messages with a priority, through `log(9)`
+//!
+//! `println!` writes to the terminal of whichever process is running, and
+//! a bare `log!` to the module's own ring. What an administrator should
+//! see goes to `log(9)` instead, with a priority `syslog.conf(5)` routes
+//! it by, and lands in the kernel message buffer `dmesg(8)` shows. Each
+//! message is a line:
+//!
+//! ```rust,ignore
+//! log!(LOG_WARNING, "{}: {} bytes short", pp.name(), short);
+//! device_log!(dev, LOG_ERR, "reset timed out");
+//! uprintf!("rmd: no unit {}\n", unit);
+//! ```
+//!
+//! Paths that may fail over and over, such as bio completion, keep a
+//! `RateLimit`, so that a bad disk can't flood the console:
+//!
+//! ```rust,ignore
+//! static READ_ERRORS: RateLimit = RateLimit::new(5);
+//!
+//! if READ_ERRORS.check() {
+//!     let skipped = READ_ERRORS.take_suppressed();
+//!     log!(LOG_ERR, "read error {} ({} not logged)", err, skipped);
+//! }
+//! ```
+
+use crate::io::{FmtBuf, KernelDebugWriter};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use libc::c_int;
+
+#[cfg(not(feature = "mock"))]
+use crate::bus::Device;
+
+/// Longest message, in bytes. Longer ones are cut short
+pub const MESSAGE_MAX: usize = 256;
+
+/// How urgent a message is, from `syslog(3)`
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Priority {
+    /// The system is unusable
+    Emerg = kernel_sys::LOG_EMERG as isize,
+    /// Must be acted on at once
+    Alert = kernel_sys::LOG_ALERT as isize,
+    Crit = kernel_sys::LOG_CRIT as isize,
+    Err = kernel_sys::LOG_ERR as isize,
+    Warning = kernel_sys::LOG_WARNING as isize,
+    /// Normal, but worth noting
+    Notice = kernel_sys::LOG_NOTICE as isize,
+    Info = kernel_sys::LOG_INFO as isize,
+    Debug = kernel_sys::LOG_DEBUG as isize,
+}
+
+pub const LOG_EMERG: Priority = Priority::Emerg;
+pub const LOG_ALERT: Priority = Priority::Alert;
+pub const LOG_CRIT: Priority = Priority::Crit;
+pub const LOG_ERR: Priority = Priority::Err;
+pub const LOG_WARNING: Priority = Priority::Warning;
+pub const LOG_NOTICE: Priority = Priority::Notice;
+pub const LOG_INFO: Priority = Priority::Info;
+pub const LOG_DEBUG: Priority = Priority::Debug;
+
+impl Priority {
+    pub fn as_raw(self) -> c_int {
+        self as c_int
+    }
+}
+
+/// Formatted on the stack, so that logging doesn't allocate
+fn line(args: fmt::Arguments) -> FmtBuf<MESSAGE_MAX> {
+    let mut buf = FmtBuf::new();
+    let _ = buf.write_fmt(args);
+    buf
+}
+
+/// Send a line to `log(9)` at priority `pri`; use `log!(LOG_*, ...)`
+pub fn log(pri: Priority, args: fmt::Arguments) {
+    let buf = line(args);
+    unsafe { kernel_sys::log(pri.as_raw(), c"%s\n".as_ptr(), buf.as_ptr()) };
+}
+
+/// Send a line to `log(9)` prefixed with the name and unit of `dev`, as
+/// `device_log(9)`; use `device_log!`
+#[cfg(not(feature = "mock"))]
+pub fn device_log(dev: &Device, pri: Priority, args: fmt::Arguments) {
+    let buf = line(args);
+    unsafe {
+        kernel_sys::device_log(
+            dev.as_ptr(),
+            pri.as_raw(),
+            c"%s\n".as_ptr(),
+            buf.as_ptr(),
+        )
+    };
+}
+
+/// Print to the controlling terminal of the current process, if it has
+/// one, as `uprintf(9)`; use `uprintf!`
+pub fn uprintf(args: fmt::Arguments) {
+    let _ = KernelDebugWriter {}.write_fmt(args);
+}
+
+/// Send a line to `log(9)` prefixed with a device's name and unit:
+/// `device_log!(dev, LOG_ERR, "timed out")`
+#[cfg(not(feature = "mock"))]
+#[macro_export]
+macro_rules! device_log {
+    ($dev:expr, $pri:ident, $($arg:tt)+) => {
+        $crate::syslog::device_log(
+            &$dev,
+            $crate::syslog::$pri,
+            format_args!($($arg)+),
+        )
+    };
+}
+
+/// Print to the controlling terminal of the current process, formatted
+/// like `print!`
+#[macro_export]
+macro_rules! uprintf {
+    ($($arg:tt)+) => {
+        $crate::syslog::uprintf(format_args!($($arg)+))
+    };
+}
+
+/// Lets through up to a number of events a second and drops the rest,
+/// with `ppsratecheck(9)`
+///
+/// Never blocks: an event racing another's check is dropped too.
+pub struct RateLimit {
+    max: c_int,
+    busy: AtomicBool,
+    last: UnsafeCell<kernel_sys::timeval>,
+    count: UnsafeCell<c_int>,
+    suppressed: AtomicU32,
+}
+
+// The cells are only touched by whoever set `busy`
+unsafe impl Sync for RateLimit {}
+
+impl RateLimit {
+    /// Up to `max` events a second, or any number if negative
+    pub const fn new(max: i32) -> Self {
+        RateLimit {
+            max,
+            busy: AtomicBool::new(false),
+            last: UnsafeCell::new(unsafe { mem::zeroed() }),
+            count: UnsafeCell::new(0),
+            suppressed: AtomicU32::new(0),
+        }
+    }
+
+    /// Whether an event now is within the limit
+    pub fn check(&self) -> bool {
+        let ok = !self.busy.swap(true, Ordering::Acquire) && {
+            let ret = unsafe {
+                kernel_sys::eventratecheck(
+                    self.last.get(),
+                    self.count.get(),
+                    self.max,
+                )
+            };
+            self.busy.store(false, Ordering::Release);
+            ret != 0
+        };
+        if !ok {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+        }
+        ok
+    }
+
+    /// Events dropped since last asked, to report with the next one let
+    /// through
+    pub fn take_suppressed(&self) -> u32 {
+        self.suppressed.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "RateLimit {{ max: {}, suppressed: {} }}",
+            self.max,
+            self.suppressed.load(Ordering::Relaxed),
+        )
+    }
+}
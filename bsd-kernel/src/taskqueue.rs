@@ -0,0 +1,304 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! Deferred work on kernel threads, see `taskqueue(9)`
+//!
+//! A `TaskQueue` is either one of the system's shared queues or a private
+//! one with kernel threads of its own. `TaskQueue::enqueue` runs a closure
+//! once, soon, allocating it, so it is for contexts that may sleep. A
+//! `Task` is allocated up front and may be enqueued from anywhere a
+//! default mutex may be taken, `bio` completion and interrupt threads
+//! included; enqueueing it again before it runs runs it only once.
+//!
+//! ```rust,ignore
+//! let tq = TaskQueue::new(c"rustfifo", 1)?;
+//! let flush = Task::new(&tq, move || ring.flush());
+//! // from the bio completion
+//! flush.enqueue();
+//! ```
+//!
+//! Nothing queued outlives what queued it: dropping a `Task` waits for it
+//! to finish, and dropping a `TaskQueue` for the closures enqueued on it,
+//! so module state holding them drains on unload.
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::sync;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use core::{fmt, mem, ptr};
+use libc::{c_int, c_void};
+
+struct Inner {
+    // The taskqueue's enqueue function is handed a pointer to this field,
+    // so it must not move once the queue is created
+    tq: UnsafeCell<*mut kernel_sys::taskqueue>,
+    owned: bool,
+    // Closures enqueued and not yet run
+    live: AtomicUsize,
+}
+
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+impl Inner {
+    fn shared(tq: *mut kernel_sys::taskqueue) -> Arc<Self> {
+        Arc::new(Inner {
+            tq: UnsafeCell::new(tq),
+            owned: false,
+            live: AtomicUsize::new(0),
+        })
+    }
+
+    fn taskqueue(&self) -> *mut kernel_sys::taskqueue {
+        unsafe { *self.tq.get() }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // Last of the queue and its tasks, so nothing is queued on it
+        if self.owned && !self.taskqueue().is_null() {
+            unsafe { kernel_sys::taskqueue_free(self.taskqueue()) };
+        }
+    }
+}
+
+/// A queue of deferred work and the threads running it
+pub struct TaskQueue {
+    inner: Arc<Inner>,
+}
+
+impl TaskQueue {
+    /// Create a private queue, served by `threads` kernel threads named
+    /// after `name`, for work that may sleep for long
+    pub fn new(name: &'static CStr, threads: usize) -> Result<Self, Errno> {
+        let threads =
+            c_int::try_from(threads.max(1)).map_err(|_| Errno::Inval)?;
+        // Freed by Inner's drop from here on, failures included
+        let inner = Arc::new(Inner {
+            tq: UnsafeCell::new(ptr::null_mut()),
+            owned: true,
+            live: AtomicUsize::new(0),
+        });
+        let tqp = inner.tq.get();
+        unsafe {
+            let tq = kernel_sys::taskqueue_create(
+                name.as_ptr(),
+                kernel_sys::M_WAITOK,
+                Some(kernel_sys::taskqueue_thread_enqueue),
+                tqp as *mut c_void,
+            );
+            if tq.is_null() {
+                return Err(Errno::NoMem);
+            }
+            *tqp = tq;
+            Errno::result(kernel_sys::taskqueue_start_threads(
+                tqp,
+                threads,
+                kernel_sys::PWAIT,
+                c"%s".as_ptr(),
+                name.as_ptr(),
+            ))?;
+        }
+        Ok(TaskQueue { inner })
+    }
+
+    /// The shared `taskqueue_thread`, whose work may sleep, but briefly,
+    /// as it holds up every other user of the queue
+    pub fn thread() -> Self {
+        TaskQueue {
+            inner: Inner::shared(unsafe { kernel_sys::taskqueue_thread }),
+        }
+    }
+
+    /// The shared `taskqueue_swi`, run from a software interrupt: its work
+    /// may take default mutexes, but never sleep
+    pub fn swi() -> Self {
+        TaskQueue {
+            inner: Inner::shared(unsafe { kernel_sys::taskqueue_swi }),
+        }
+    }
+
+    /// Run `f` once on the queue, soon. Allocates, so the caller must be
+    /// able to sleep; elsewhere enqueue a `Task`
+    pub fn enqueue<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.inner.live.fetch_add(1, Ordering::AcqRel);
+        let job = Box::into_raw(Box::new(Job {
+            ktask: unsafe { mem::zeroed() },
+            f: Box::new(f),
+            live: &raw const self.inner.live,
+        }));
+        unsafe {
+            // TASK_INIT(&job->ktask, 0, run_job, job)
+            (*job).ktask.ta_func = Some(run_job);
+            (*job).ktask.ta_context = job as *mut c_void;
+            kernel_sys::taskqueue_enqueue(
+                self.inner.taskqueue(),
+                &raw mut (*job).ktask,
+            );
+        }
+    }
+
+    /// Wait for everything enqueued on a private queue so far to run. On a
+    /// shared queue only the closures enqueued through this handle are
+    /// waited for
+    pub fn drain(&self) {
+        if self.inner.owned {
+            unsafe { kernel_sys::taskqueue_drain_all(self.inner.taskqueue()) };
+        }
+        // Jobs wake nobody in particular, so this polls
+        while self.inner.live.load(Ordering::Acquire) != 0 {
+            let _ = sync::sleep(
+                &self.inner.live,
+                c"tqdrain",
+                Some(Duration::from_millis(1)),
+            );
+        }
+    }
+}
+
+impl Drop for TaskQueue {
+    fn drop(&mut self) {
+        // Jobs don't keep the queue alive, so this must outlast them
+        self.drain();
+    }
+}
+
+impl fmt::Debug for TaskQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TaskQueue {{ tq: {:?} }}", self.inner.taskqueue())
+    }
+}
+
+struct Job {
+    ktask: kernel_sys::task,
+    f: Box<dyn FnOnce() + Send>,
+    live: *const AtomicUsize,
+}
+
+unsafe extern "C" fn run_job(context: *mut c_void, _pending: c_int) {
+    let job = unsafe { Box::from_raw(context as *mut Job) };
+    let Job { f, live, .. } = *job;
+    let _ = catch_in_module(f);
+    // Last use: the queue may be dropped as soon as this is seen
+    unsafe { (*live).fetch_sub(1, Ordering::AcqRel) };
+}
+
+struct TaskInner {
+    ktask: UnsafeCell<kernel_sys::task>,
+    f: Box<dyn Fn() + Send + Sync>,
+}
+
+/// Work allocated once and run on a queue each time it is enqueued
+///
+/// Enqueueing doesn't allocate or sleep. Dropping the task cancels it if
+/// it is pending and waits for it if it is running.
+pub struct Task {
+    // Boxed as the queue holds a pointer to it
+    inner: Box<TaskInner>,
+    queue: Arc<Inner>,
+}
+
+unsafe impl Send for Task {}
+unsafe impl Sync for Task {}
+
+impl Task {
+    /// A task running `f` on `queue`
+    pub fn new<F>(queue: &TaskQueue, f: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let inner = Box::new(TaskInner {
+            ktask: UnsafeCell::new(unsafe { mem::zeroed() }),
+            f: Box::new(f),
+        });
+        unsafe {
+            // TASK_INIT(&inner->ktask, 0, run_task, inner)
+            let kt = &mut *inner.ktask.get();
+            kt.ta_func = Some(run_task);
+            kt.ta_context = &*inner as *const TaskInner as *mut c_void;
+        }
+        Task {
+            inner,
+            queue: queue.inner.clone(),
+        }
+    }
+
+    /// Have the task run soon, once however often this is called before
+    /// it starts
+    pub fn enqueue(&self) {
+        unsafe {
+            kernel_sys::taskqueue_enqueue(
+                self.queue.taskqueue(),
+                self.inner.ktask.get(),
+            );
+        }
+    }
+
+    /// Take the task off the queue if it hasn't started, returning whether
+    /// it was pending. Fails with `Errno::Busy` while it runs
+    pub fn cancel(&self) -> Result<bool, Errno> {
+        let mut pending = 0;
+        let ret = unsafe {
+            kernel_sys::taskqueue_cancel(
+                self.queue.taskqueue(),
+                self.inner.ktask.get(),
+                &mut pending,
+            )
+        };
+        Errno::result(ret).map(|()| pending != 0)
+    }
+
+    /// Wait until the task is neither pending nor running
+    pub fn drain(&self) {
+        unsafe {
+            kernel_sys::taskqueue_drain(
+                self.queue.taskqueue(),
+                self.inner.ktask.get(),
+            );
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let _ = self.cancel();
+        self.drain();
+    }
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pending = unsafe { (*self.inner.ktask.get()).ta_pending };
+        write!(f, "Task {{ pending: {} }}", pending)
+    }
+}
+
+unsafe extern "C" fn run_task(context: *mut c_void, _pending: c_int) {
+    let inner = unsafe { &*(context as *const TaskInner) };
+    let _ = catch_in_module(|| (inner.f)());
+}
@@ -0,0 +1,282 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Kernel clocks, and conversions between `core::time::Duration` and
+//! kernel time values
+//!
+//! `Instant` reads the uptime clock, which is monotonic and stops while
+//! suspended, and `SystemTime` the wall clock, which `settimeofday(2)` and
+//! NTP may step:
+//!
+//! ```rust,ignore
+//! let start = Instant::now();
+//! // ...
+//! println!("took {:?}", start.elapsed());
+//! let deadline = start + Duration::from_secs(1);
+//! ```
+//!
+//! The `coarse` variants read the time as of the last tick, which is
+//! cheaper than reading the timecounter and good enough for timestamps.
+
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::time::Duration;
+
+/// Convert a `Duration` to an `sbintime_t` (32.32 fixed-point seconds),
+/// saturating at the largest representable value
+pub fn duration_to_sbt(d: Duration) -> kernel_sys::sbintime_t {
+    let secs = match i64::try_from(d.as_secs()) {
+        Ok(s) if s < (1 << 31) => s,
+        _ => return kernel_sys::sbintime_t::MAX,
+    };
+    let frac = (u64::from(d.subsec_nanos()) << 32) / 1_000_000_000;
+    (secs << 32) + frac as i64
+}
+
+/// Convert a non-negative `sbintime_t` to a `Duration`. Negative values
+/// are clamped to zero
+pub fn sbt_to_duration(sbt: kernel_sys::sbintime_t) -> Duration {
+    if sbt <= 0 {
+        return Duration::ZERO;
+    }
+    let secs = (sbt >> 32) as u64;
+    let nanos = ((sbt & 0xffff_ffff) as u64 * 1_000_000_000) >> 32;
+    Duration::new(secs, nanos as u32)
+}
+
+/// The time since boot as an `sbintime_t`, read with `getbinuptime(9)`:
+/// cheap, but only accurate to within one tick
+pub fn getsbinuptime() -> kernel_sys::sbintime_t {
+    let mut bt = kernel_sys::bintime::default();
+    unsafe { kernel_sys::getbinuptime(&mut bt) };
+    (bt.sec << 32) + (bt.frac >> 32) as i64
+}
+
+/// The time since boot as an `sbintime_t`, read with `binuptime(9)`:
+/// precise, at the cost of reading the timecounter
+pub fn sbinuptime() -> kernel_sys::sbintime_t {
+    let mut bt = kernel_sys::bintime::default();
+    unsafe { kernel_sys::binuptime(&mut bt) };
+    (bt.sec << 32) + (bt.frac >> 32) as i64
+}
+
+/// Convert a `bintime` to a `Duration`. Negative times are clamped to zero
+pub fn bintime_to_duration(bt: &kernel_sys::bintime) -> Duration {
+    if bt.sec < 0 {
+        return Duration::ZERO;
+    }
+    let nanos = ((bt.frac >> 32) * 1_000_000_000) >> 32;
+    Duration::new(bt.sec as u64, nanos as u32)
+}
+
+/// Convert a `Duration` to a `bintime`
+pub fn duration_to_bintime(d: Duration) -> kernel_sys::bintime {
+    let frac = (u64::from(d.subsec_nanos()) << 32) / 1_000_000_000;
+    kernel_sys::bintime {
+        sec: d.as_secs().min(i64::MAX as u64) as i64,
+        frac: frac << 32,
+    }
+}
+
+/// The clock interrupt frequency, `hz`, which `ticks` count at
+pub fn hz() -> u32 {
+    unsafe { kernel_sys::hz as u32 }
+}
+
+/// The number of clock ticks since boot, which wraps
+#[cfg(not(feature = "mock"))]
+pub fn ticks() -> i32 {
+    unsafe { core::ptr::read_volatile(&raw const kernel_sys::ticks) }
+}
+
+/// Convert a `Duration` to a number of ticks, rounding up so a timeout is
+/// never shorter than asked, and saturating at `i32::MAX`
+pub fn duration_to_ticks(d: Duration) -> i32 {
+    let hz = u128::from(hz());
+    let ticks = (d.as_nanos() * hz).div_ceil(1_000_000_000);
+    ticks.min(i32::MAX as u128) as i32
+}
+
+/// Convert a number of ticks to a `Duration`. Negative counts are clamped
+/// to zero
+pub fn ticks_to_duration(ticks: i32) -> Duration {
+    let nanos = u64::try_from(ticks).unwrap_or(0) * 1_000_000_000;
+    Duration::from_nanos(nanos / u64::from(hz()))
+}
+
+/// A reading of the uptime clock, which only ever goes forwards
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(kernel_sys::sbintime_t);
+
+impl Instant {
+    /// The current time, read precisely with `binuptime(9)`
+    pub fn now() -> Self {
+        Instant(sbinuptime())
+    }
+
+    /// The time as of the last tick, read with `getbinuptime(9)`
+    pub fn coarse() -> Self {
+        Instant(getsbinuptime())
+    }
+
+    /// The instant an `sbintime_t` uptime names, as callouts take
+    pub fn from_sbt(sbt: kernel_sys::sbintime_t) -> Self {
+        Instant(sbt)
+    }
+
+    pub fn as_sbt(self) -> kernel_sys::sbintime_t {
+        self.0
+    }
+
+    /// How long ago this was, zero for instants in the future
+    pub fn elapsed(self) -> Duration {
+        Instant::now().saturating_duration_since(self)
+    }
+
+    /// The time from `earlier` to this, zero if `earlier` is later
+    pub fn saturating_duration_since(self, earlier: Instant) -> Duration {
+        sbt_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// The time from `earlier` to this, `None` if `earlier` is later
+    pub fn checked_duration_since(self, earlier: Instant) -> Option<Duration> {
+        (self >= earlier).then(|| sbt_to_duration(self.0 - earlier.0))
+    }
+
+    pub fn checked_add(self, d: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_sbt(d)).map(Instant)
+    }
+
+    pub fn checked_sub(self, d: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration_to_sbt(d)).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// ## Panics
+    ///
+    /// If the result overflows
+    fn add(self, d: Duration) -> Instant {
+        self.checked_add(d)
+            .expect("overflow adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, d: Duration) {
+        *self = *self + d;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    /// ## Panics
+    ///
+    /// If the result overflows
+    fn sub(self, d: Duration) -> Instant {
+        self.checked_sub(d)
+            .expect("overflow subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, d: Duration) {
+        *self = *self - d;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    /// The time from `earlier` to this, zero if `earlier` is later
+    fn sub(self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Instant {{ {:?} }}", sbt_to_duration(self.0))
+    }
+}
+
+/// A reading of the wall clock, the time since the Unix epoch as the
+/// system believes it to be. It may be stepped backwards
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SystemTime(Duration);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::ZERO);
+
+    /// The current time, read precisely with `nanotime(9)`
+    pub fn now() -> Self {
+        let mut ts = kernel_sys::timespec::default();
+        unsafe { kernel_sys::nanotime(&mut ts) };
+        Self::from_timespec(&ts)
+    }
+
+    /// The time as of the last tick, read with `getnanotime(9)`
+    pub fn coarse() -> Self {
+        let mut ts = kernel_sys::timespec::default();
+        unsafe { kernel_sys::getnanotime(&mut ts) };
+        Self::from_timespec(&ts)
+    }
+
+    fn from_timespec(ts: &kernel_sys::timespec) -> Self {
+        let secs = u64::try_from(ts.tv_sec).unwrap_or(0);
+        SystemTime(Duration::new(secs, ts.tv_nsec as u32))
+    }
+
+    /// The time since the epoch
+    pub fn since_epoch(self) -> Duration {
+        self.0
+    }
+
+    /// The time from `earlier` to this, `None` if the clock has been set
+    /// back past `earlier` meanwhile
+    pub fn duration_since(self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// As a `timespec`, as `struct stat` and GEOM's configuration take
+    pub fn to_timespec(self) -> kernel_sys::timespec {
+        kernel_sys::timespec {
+            tv_sec: self.0.as_secs() as _,
+            tv_nsec: self.0.subsec_nanos() as _,
+        }
+    }
+}
+
+impl fmt::Debug for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SystemTime {{ {}.{:09} }}",
+            self.0.as_secs(),
+            self.0.subsec_nanos()
+        )
+    }
+}
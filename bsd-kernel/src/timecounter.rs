@@ -0,0 +1,165 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Timecounters, see `timecounters(4)`
+//!
+//! A timecounter is a free running hardware counter the kernel reads to
+//! keep time between clock ticks. A driver describes its counter with
+//! `Info` and hands it to `register`, after which the kernel switches to
+//! it if its quality beats the current one's; `kern.timecounter.hardware`
+//! overrides the choice.
+//!
+//! The kernel has no way to take a timecounter back, so a registered
+//! counter lives until reboot and its module must refuse to unload, by
+//! returning `Errno::Busy` for `ModuleEvent::Unload`.
+//!
+//! ```ignore
+//! struct Mmio(*const u32);
+//!
+//! impl Counter for Mmio {
+//!     fn read(&self) -> u32 {
+//!         unsafe { self.0.read_volatile() }
+//!     }
+//! }
+//!
+//! let info = Info {
+//!     name: c"rusttimer",
+//!     bits: 32,
+//!     frequency: 24_000_000,
+//!     quality: 1000,
+//!     flags: 0,
+//! };
+//! timecounter::register(info, Mmio(regs));
+//! ```
+
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::{fmt, mem};
+
+/// `TC_FLAGS_*`
+pub mod flags {
+    /// Stops in C2 sleep, which the kernel then avoids
+    pub const C2STOP: u32 = 1;
+    /// Keeps counting across suspend and resume
+    pub const SUSPEND_SAFE: u32 = 2;
+}
+
+/// How a counter presents itself
+#[derive(Copy, Clone, Debug)]
+pub struct Info {
+    pub name: &'static CStr,
+    /// Width of the counter, at most 32 bits; it must not wrap twice
+    /// between clock ticks
+    pub bits: u32,
+    /// Counting frequency in Hz
+    pub frequency: u64,
+    /// Higher is preferred; below 0 the counter is only used if chosen
+    /// by name. The TSC's is 800 at best, the HPET's 950 and the i8254's 0
+    pub quality: i32,
+    /// `flags` bits
+    pub flags: u32,
+}
+
+/// The hardware side of a counter
+pub trait Counter: Send + Sync + 'static {
+    /// Read the counter. Called at any time, including from interrupt
+    /// handlers and with interrupts disabled, so it must not sleep or
+    /// take locks
+    fn read(&self) -> u32;
+}
+
+#[repr(C)]
+pub struct Timecounter<T> {
+    tc: UnsafeCell<kernel_sys::timecounter>,
+    counter: T,
+}
+
+unsafe impl<T: Counter> Sync for Timecounter<T> {}
+
+/// Register `counter` with the kernel for the rest of its uptime
+pub fn register<T: Counter>(info: Info, counter: T) -> &'static Timecounter<T> {
+    let mut tc: kernel_sys::timecounter = unsafe { mem::zeroed() };
+    tc.tc_get_timecount = Some(get_timecount::<T>);
+    tc.tc_counter_mask = match info.bits {
+        32.. => u32::MAX,
+        bits => (1 << bits) - 1,
+    };
+    tc.tc_frequency = info.frequency;
+    tc.tc_name = info.name.as_ptr();
+    tc.tc_quality = info.quality;
+    tc.tc_flags = info.flags;
+    let tc = Box::leak(Box::new(Timecounter {
+        tc: UnsafeCell::new(tc),
+        counter,
+    }));
+    unsafe {
+        (*tc.tc.get()).tc_priv = (tc as *mut Timecounter<T>).cast();
+        kernel_sys::tc_init(tc.tc.get());
+    }
+    tc
+}
+
+impl<T: Counter> Timecounter<T> {
+    pub fn counter(&self) -> &T {
+        &self.counter
+    }
+
+    pub fn frequency(&self) -> u64 {
+        unsafe { (*self.tc.get()).tc_frequency }
+    }
+
+    pub fn quality(&self) -> i32 {
+        unsafe { (*self.tc.get()).tc_quality }
+    }
+
+    /// Whether the kernel is keeping time with this counter
+    pub fn is_current(&self) -> bool {
+        unsafe { kernel_sys::timecounter == self.tc.get() }
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::timecounter {
+        self.tc.get()
+    }
+}
+
+impl<T: Counter> fmt::Debug for Timecounter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = unsafe { CStr::from_ptr((*self.tc.get()).tc_name) };
+        write!(
+            f,
+            "Timecounter {{ name: {:?}, frequency: {}, quality: {} }}",
+            name,
+            self.frequency(),
+            self.quality()
+        )
+    }
+}
+
+unsafe extern "C" fn get_timecount<T: Counter>(
+    tc: *mut kernel_sys::timecounter,
+) -> libc::c_uint {
+    let tc = unsafe { &*((*tc).tc_priv as *const Timecounter<T>) };
+    catch_in_module(|| tc.counter.read()).unwrap_or(0)
+}
@@ -0,0 +1,604 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Terminal devices, see `tty(9)`
+//!
+//! A serial-style driver implements `TtyDevice` and creates a `Tty`,
+//! which publishes `/dev/tty<name>` with the line discipline in front
+//! of it: the kernel handles line editing, echo, signals and `termios`,
+//! and calls the driver to move characters, apply settings and drive
+//! the modem lines.
+//!
+//! Every `TtyDevice` method runs with the tty's mutex held, given as a
+//! `TtyGuard`, and must not sleep. Output is pulled from the line
+//! discipline in `outwakeup`, or later, when the hardware has room:
+//!
+//! ```rust,ignore
+//! impl TtyDevice for Port {
+//!     fn outwakeup(&self, tty: &mut TtyGuard) {
+//!         let mut buf = [0; 64];
+//!         while self.tx_room() >= buf.len() {
+//!             let n = tty.getc(&mut buf);
+//!             if n == 0 {
+//!                 break;
+//!             }
+//!             self.transmit(&buf[..n]);
+//!         }
+//!     }
+//! }
+//!
+//! // From the receive interrupt
+//! let mut tty = port.lock();
+//! for &c in received {
+//!     tty.rint(c, RxError::NONE);
+//! }
+//! tty.rint_done();
+//! ```
+
+use crate::cred::CurThread;
+use crate::errno::Errno;
+use crate::ioctl::{self, IoctlRequest};
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use core::marker::PhantomData;
+use core::{fmt, mem, ops, ptr, slice};
+use libc::{c_char, c_int, c_ulong, c_void};
+
+/// Flags of a tty's `ttydevsw`, which choose the devices made for it
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TtyFlags(u32);
+
+impl TtyFlags {
+    pub const NONE: Self = TtyFlags(0);
+    /// Also make the `.init` and `.lock` devices, which hold the settings
+    /// a port starts with and those it may not change, as serial ports do
+    pub const INIT_LOCK: Self = TtyFlags(kernel_sys::TF_INITLOCK);
+    /// Also make the `cua` callout device, which opens without waiting
+    /// for carrier, for dialing out
+    pub const CALLOUT: Self = TtyFlags(kernel_sys::TF_CALLOUT);
+    /// Name the device `name` rather than `tty<name>`
+    pub const NO_PREFIX: Self = TtyFlags(kernel_sys::TF_NOPREFIX);
+
+    pub const fn union(self, other: Self) -> Self {
+        TtyFlags(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags as `tsw_flags`
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+impl ops::BitOr for TtyFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// Modem control and status lines, see `sys/serial.h`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Modem(c_int);
+
+impl Modem {
+    pub const NONE: Self = Modem(0);
+    /// Data terminal ready, an output
+    pub const DTR: Self = Modem(kernel_sys::SER_DTR as c_int);
+    /// Request to send, an output
+    pub const RTS: Self = Modem(kernel_sys::SER_RTS as c_int);
+    /// Clear to send, an input
+    pub const CTS: Self = Modem(kernel_sys::SER_CTS as c_int);
+    /// Data carrier detect, an input
+    pub const DCD: Self = Modem(kernel_sys::SER_DCD as c_int);
+    /// Ring indicator, an input
+    pub const RI: Self = Modem(kernel_sys::SER_RI as c_int);
+    /// Data set ready, an input
+    pub const DSR: Self = Modem(kernel_sys::SER_DSR as c_int);
+
+    pub const fn from_raw(bits: c_int) -> Self {
+        Modem(bits)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Modem(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn bits(self) -> c_int {
+        self.0
+    }
+}
+
+impl ops::BitOr for Modem {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// Errors seen receiving a character, for `TtyGuard::rint`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RxError(c_int);
+
+impl RxError {
+    pub const NONE: Self = RxError(0);
+    pub const FRAMING: Self = RxError(kernel_sys::TRE_FRAMING as c_int);
+    pub const PARITY: Self = RxError(kernel_sys::TRE_PARITY as c_int);
+    /// Characters were lost before this one
+    pub const OVERRUN: Self = RxError(kernel_sys::TRE_OVERRUN as c_int);
+    /// A break was received in place of a character
+    pub const BREAK: Self = RxError(kernel_sys::TRE_BREAK as c_int);
+}
+
+impl ops::BitOr for RxError {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        RxError(self.0 | other.0)
+    }
+}
+
+/// Parity checked and generated on the line
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Line settings passed to `TtyDevice::param`, see `termios(4)`
+#[repr(transparent)]
+pub struct Termios(kernel_sys::termios);
+
+impl Termios {
+    unsafe fn from_raw<'a>(t: *mut kernel_sys::termios) -> &'a mut Self {
+        unsafe { &mut *(t as *mut Termios) }
+    }
+
+    /// Raw pointer to the underlying `struct termios`
+    pub fn as_ptr(&self) -> *const kernel_sys::termios {
+        &self.0
+    }
+
+    /// The receive speed, in bits per second
+    pub fn ispeed(&self) -> u32 {
+        self.0.c_ispeed
+    }
+
+    /// The transmit speed, in bits per second
+    pub fn ospeed(&self) -> u32 {
+        self.0.c_ospeed
+    }
+
+    /// Replace the speeds with the nearest the hardware can do, which
+    /// `tcgetattr(3)` then reports
+    pub fn set_speed(&mut self, ispeed: u32, ospeed: u32) {
+        self.0.c_ispeed = ispeed;
+        self.0.c_ospeed = ospeed;
+    }
+
+    /// Bits per character, 5 to 8
+    pub fn char_bits(&self) -> u8 {
+        match self.0.c_cflag & kernel_sys::CSIZE {
+            kernel_sys::CS5 => 5,
+            kernel_sys::CS6 => 6,
+            kernel_sys::CS7 => 7,
+            _ => 8,
+        }
+    }
+
+    /// Whether two stop bits are sent rather than one
+    pub fn two_stop_bits(&self) -> bool {
+        self.0.c_cflag & kernel_sys::CSTOPB != 0
+    }
+
+    pub fn parity(&self) -> Parity {
+        match self.0.c_cflag & (kernel_sys::PARENB | kernel_sys::PARODD) {
+            f if f & kernel_sys::PARENB == 0 => Parity::None,
+            f if f & kernel_sys::PARODD == 0 => Parity::Even,
+            _ => Parity::Odd,
+        }
+    }
+
+    /// Whether RTS/CTS hardware flow control is on
+    pub fn rtscts(&self) -> bool {
+        self.0.c_cflag & kernel_sys::CRTSCTS == kernel_sys::CRTSCTS
+    }
+
+    /// Whether the line ignores the modem status lines, `CLOCAL`
+    pub fn is_local(&self) -> bool {
+        self.0.c_cflag & kernel_sys::CLOCAL != 0
+    }
+
+    /// The raw control flags, `c_cflag`
+    pub fn cflag(&self) -> u32 {
+        self.0.c_cflag
+    }
+
+    /// Replace the control flags, such as to clear those the hardware
+    /// can't do
+    pub fn set_cflag(&mut self, cflag: u32) {
+        self.0.c_cflag = cflag;
+    }
+}
+
+impl fmt::Debug for Termios {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Termios {{ ispeed: {}, ospeed: {}, cflag: {:#x} }}",
+            self.ispeed(),
+            self.ospeed(),
+            self.cflag()
+        )
+    }
+}
+
+/// A terminal driver's entry points, called with the tty locked
+pub trait TtyDevice: Send + Sync + 'static {
+    /// Which devices are made for the tty
+    const FLAGS: TtyFlags = TtyFlags::NONE;
+
+    /// The device is being opened, before the line discipline is. An
+    /// error fails the `open(2)`
+    fn open(&self, _tty: &mut TtyGuard) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// The last descriptor for the device was closed
+    fn close(&self, _tty: &mut TtyGuard) {}
+
+    /// The line discipline has output, for `TtyGuard::getc`. Send what
+    /// the hardware has room for, and the rest once it has drained
+    fn outwakeup(&self, tty: &mut TtyGuard);
+
+    /// Input was read and the line discipline has room for more, so a
+    /// driver that stopped receiving can start again
+    fn inwakeup(&self, _tty: &mut TtyGuard) {}
+
+    /// Handle an `ioctl(2)` the tty layer didn't. Unknown commands fail
+    /// with `Errno::NoIoctl`, as the default does for all, which leaves
+    /// the line discipline to handle them
+    fn ioctl(
+        &self,
+        _tty: &mut TtyGuard,
+        _req: &mut IoctlRequest,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Err(Errno::NoIoctl)
+    }
+
+    /// Apply new line settings, after adjusting what the hardware can't
+    /// do, or fail with `Errno::Inval` to refuse them
+    fn param(
+        &self,
+        _tty: &mut TtyGuard,
+        _termios: &mut Termios,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Raise the lines in `on`, lower those in `off`, and return the state
+    /// of all of them; both are empty to only ask. The default reports a
+    /// carrier, so opens don't wait for one
+    fn modem(&self, _tty: &mut TtyGuard, _on: Modem, _off: Modem) -> Modem {
+        Modem::DCD
+    }
+}
+
+/// The tty, with its mutex held
+///
+/// Given to `TtyDevice` methods, or taken with `Tty::lock` by the rest of
+/// the driver, such as its receive interrupt, to hand the line
+/// discipline input. It's a mutex, so nothing may sleep while holding it.
+pub struct TtyGuard<'a> {
+    tp: ptr::NonNull<kernel_sys::tty>,
+    unlock: bool,
+    _tty: PhantomData<&'a kernel_sys::tty>,
+}
+
+impl TtyGuard<'_> {
+    /// ## Safety
+    /// `tp` is locked, by the kernel for the duration of a callback
+    unsafe fn locked(tp: *mut kernel_sys::tty) -> Self {
+        TtyGuard {
+            tp: ptr::NonNull::new(tp).unwrap(),
+            unlock: false,
+            _tty: PhantomData,
+        }
+    }
+
+    fn flags(&self) -> u32 {
+        unsafe { self.tp.as_ref().t_flags }
+    }
+
+    /// Raw pointer to the underlying `struct tty`
+    pub fn as_ptr(&self) -> *mut kernel_sys::tty {
+        self.tp.as_ptr()
+    }
+
+    /// Whether the device is open
+    pub fn is_opened(&self) -> bool {
+        self.flags() & kernel_sys::TF_OPENED != 0
+    }
+
+    /// Whether the tty is being destroyed, after which input is ignored
+    pub fn is_gone(&self) -> bool {
+        self.flags() & kernel_sys::TF_GONE != 0
+    }
+
+    /// Hand the line discipline a received character. Returns `false`
+    /// when its input queue is full and the character was dropped
+    pub fn rint(&mut self, c: u8, error: RxError) -> bool {
+        unsafe {
+            kernel_sys::ttydisc_rint(self.as_ptr(), c as c_char, error.0) == 0
+        }
+    }
+
+    /// Whether the line discipline does no processing of input, so it
+    /// can be passed on in bulk with `rint_bypass`
+    pub fn can_bypass(&self) -> bool {
+        self.flags() & kernel_sys::TF_BYPASS != 0
+    }
+
+    /// Hand the line discipline characters it won't process, when
+    /// `can_bypass`. Returns how many it took
+    pub fn rint_bypass(&mut self, buf: &[u8]) -> usize {
+        unsafe {
+            kernel_sys::ttydisc_rint_bypass(
+                self.as_ptr(),
+                buf.as_ptr() as *const c_void,
+                buf.len(),
+            )
+        }
+    }
+
+    /// Wake readers once a batch of input has been handed over
+    pub fn rint_done(&mut self) {
+        unsafe { kernel_sys::ttydisc_rint_done(self.as_ptr()) };
+    }
+
+    /// How many characters the line discipline can take now
+    pub fn rint_poll(&mut self) -> usize {
+        unsafe { kernel_sys::ttydisc_rint_poll(self.as_ptr()) }
+    }
+
+    /// Take output to send into `buf`. Returns how many bytes were
+    /// taken, zero once there's nothing more
+    pub fn getc(&mut self, buf: &mut [u8]) -> usize {
+        unsafe {
+            kernel_sys::ttydisc_getc(
+                self.as_ptr(),
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+            )
+        }
+    }
+
+    /// How many bytes of output are waiting
+    pub fn getc_poll(&mut self) -> usize {
+        unsafe { kernel_sys::ttydisc_getc_poll(self.as_ptr()) }
+    }
+
+    /// Report a change of carrier, which hangs up the session when lost
+    /// unless the line `is_local`
+    pub fn carrier(&mut self, on: bool) {
+        unsafe { kernel_sys::ttydisc_modem(self.as_ptr(), c_int::from(on)) };
+    }
+}
+
+impl Drop for TtyGuard<'_> {
+    fn drop(&mut self) {
+        if self.unlock {
+            unsafe {
+                let mtx = &raw mut (*self.tp.as_ref().t_mtx).mtx_lock;
+                kernel_sys::_mtx_unlock_flags(mtx, 0, ptr::null(), 0);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TtyGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TtyGuard {{ tp: {:?} }}", self.tp)
+    }
+}
+
+/// Freed by `tsw_free` once the kernel is done with the tty, which may
+/// be after the `Tty` is dropped
+struct Shared<T> {
+    tsw: kernel_sys::ttydevsw,
+    device: T,
+}
+
+/// A terminal device, which goes away when dropped
+pub struct Tty<T: TtyDevice> {
+    tp: ptr::NonNull<kernel_sys::tty>,
+    shared: *const Shared<T>,
+}
+
+unsafe impl<T: TtyDevice> Send for Tty<T> {}
+unsafe impl<T: TtyDevice> Sync for Tty<T> {}
+
+impl<T: TtyDevice> Tty<T> {
+    /// Make the tty for `device`, with a device named after `name` as
+    /// `TtyDevice::FLAGS` says, `/dev/ttyU0` for a `name` of `U0`
+    pub fn new(name: &str, device: T) -> Result<Self, Errno> {
+        let name = CString::new(name).map_err(|_| Errno::Inval)?;
+        let mut tsw: kernel_sys::ttydevsw = unsafe { mem::zeroed() };
+        tsw.tsw_flags = T::FLAGS.bits();
+        tsw.tsw_open = Some(tty_open::<T>);
+        tsw.tsw_close = Some(tty_close::<T>);
+        tsw.tsw_outwakeup = Some(tty_outwakeup::<T>);
+        tsw.tsw_inwakeup = Some(tty_inwakeup::<T>);
+        tsw.tsw_ioctl = Some(tty_ioctl::<T>);
+        tsw.tsw_param = Some(tty_param::<T>);
+        tsw.tsw_modem = Some(tty_modem::<T>);
+        tsw.tsw_free = Some(tty_free::<T>);
+        let shared = Box::into_raw(Box::new(Shared { tsw, device }));
+        let tp = unsafe {
+            kernel_sys::tty_alloc(&raw mut (*shared).tsw, shared as *mut c_void)
+        };
+        let tty = Tty {
+            tp: ptr::NonNull::new(tp).unwrap(),
+            shared,
+        };
+        let error = unsafe {
+            kernel_sys::tty_makedevf(
+                tp,
+                ptr::null_mut(),
+                0,
+                c"%s".as_ptr(),
+                name.as_ptr(),
+            )
+        };
+        // On failure, dropping `tty` releases it, and `tsw_free` `shared`
+        Errno::result(error).map(|()| tty)
+    }
+
+    pub fn device(&self) -> &T {
+        unsafe { &(*self.shared).device }
+    }
+
+    /// Raw pointer to the underlying `struct tty`
+    pub fn as_ptr(&self) -> *mut kernel_sys::tty {
+        self.tp.as_ptr()
+    }
+
+    /// Take the tty's mutex, to hand the line discipline input or take
+    /// output from outside a `TtyDevice` method
+    pub fn lock(&self) -> TtyGuard<'_> {
+        unsafe {
+            let mtx = &raw mut (*self.tp.as_ref().t_mtx).mtx_lock;
+            kernel_sys::_mtx_lock_flags(mtx, 0, ptr::null(), 0);
+        }
+        TtyGuard {
+            tp: self.tp,
+            unlock: true,
+            _tty: PhantomData,
+        }
+    }
+}
+
+impl<T: TtyDevice> Drop for Tty<T> {
+    /// Hangs up the tty and removes its devices. `T` is dropped once the
+    /// last descriptor open on them is closed
+    fn drop(&mut self) {
+        let guard = self.lock();
+        // Unlocks the tty itself
+        mem::forget(guard);
+        unsafe { kernel_sys::tty_rel_gone(self.tp.as_ptr()) };
+    }
+}
+
+impl<T: TtyDevice> fmt::Debug for Tty<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tty {{ tp: {:?} }}", self.tp)
+    }
+}
+
+unsafe fn device<'a, T: TtyDevice>(tp: *mut kernel_sys::tty) -> &'a T {
+    unsafe { &(*((*tp).t_devswsoftc as *const Shared<T>)).device }
+}
+
+unsafe extern "C" fn tty_open<T: TtyDevice>(tp: *mut kernel_sys::tty) -> c_int {
+    let (t, mut tty) = unsafe { (device::<T>(tp), TtyGuard::locked(tp)) };
+    catch_in_module(|| t.open(&mut tty))
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+unsafe extern "C" fn tty_close<T: TtyDevice>(tp: *mut kernel_sys::tty) {
+    let (t, mut tty) = unsafe { (device::<T>(tp), TtyGuard::locked(tp)) };
+    let _ = catch_in_module(|| t.close(&mut tty));
+}
+
+unsafe extern "C" fn tty_outwakeup<T: TtyDevice>(tp: *mut kernel_sys::tty) {
+    let (t, mut tty) = unsafe { (device::<T>(tp), TtyGuard::locked(tp)) };
+    let _ = catch_in_module(|| t.outwakeup(&mut tty));
+}
+
+unsafe extern "C" fn tty_inwakeup<T: TtyDevice>(tp: *mut kernel_sys::tty) {
+    let (t, mut tty) = unsafe { (device::<T>(tp), TtyGuard::locked(tp)) };
+    let _ = catch_in_module(|| t.inwakeup(&mut tty));
+}
+
+unsafe extern "C" fn tty_ioctl<T: TtyDevice>(
+    tp: *mut kernel_sys::tty,
+    cmd: c_ulong,
+    data: kernel_sys::caddr_t,
+    td: *mut kernel_sys::thread,
+) -> c_int {
+    let (t, mut tty) = unsafe { (device::<T>(tp), TtyGuard::locked(tp)) };
+    // The kernel has copied the argument in and copies it out after
+    let len = ioctl::param_len(cmd);
+    let data: &mut [u8] = if len == 0 || data.is_null() {
+        &mut []
+    } else {
+        unsafe { slice::from_raw_parts_mut(data as *mut u8, len) }
+    };
+    let mut req = if ioctl::is_compat32(td) {
+        IoctlRequest::new_compat32(cmd, data)
+    } else {
+        IoctlRequest::new(cmd, data)
+    };
+    let td = unsafe { CurThread::from_raw(td) };
+    catch_in_module(|| t.ioctl(&mut tty, &mut req, &td))
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+unsafe extern "C" fn tty_param<T: TtyDevice>(
+    tp: *mut kernel_sys::tty,
+    termios: *mut kernel_sys::termios,
+) -> c_int {
+    let (t, mut tty) = unsafe { (device::<T>(tp), TtyGuard::locked(tp)) };
+    let termios = unsafe { Termios::from_raw(termios) };
+    catch_in_module(|| t.param(&mut tty, termios))
+        .and_then(|r| r)
+        .map_or_else(|e| e.as_raw(), |()| 0)
+}
+
+unsafe extern "C" fn tty_modem<T: TtyDevice>(
+    tp: *mut kernel_sys::tty,
+    sigon: c_int,
+    sigoff: c_int,
+) -> c_int {
+    let (t, mut tty) = unsafe { (device::<T>(tp), TtyGuard::locked(tp)) };
+    let (on, off) = (Modem::from_raw(sigon), Modem::from_raw(sigoff));
+    catch_in_module(|| t.modem(&mut tty, on, off)).map_or(0, Modem::bits)
+}
+
+unsafe extern "C" fn tty_free<T: TtyDevice>(softc: *mut c_void) {
+    drop(unsafe { Box::from_raw(softc as *mut Shared<T>) });
+}
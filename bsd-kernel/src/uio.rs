@@ -25,27 +25,79 @@
 
 //! This module provides wrapper structs around `kernel_sys::uio` that
 //! implement `crate::io::Read` and `crate::io::Write`.
+//!
+//! What those do with the transfer's offset depends on the `Offsets` the
+//! wrapper was made with, which the device glue takes from
+//! `CharacterDevice::OFFSETS`. Seekable transfers also implement
+//! `crate::io::Seek`, to move `uio_offset` before reading or writing the
+//! device; a vectored read or write treats its buffers as consecutive
+//! regions of the device, as `read_at` and `write_at` would.
+//!
+//! Fixed-size structures move whole with `UioReader::read_obj` and
+//! `UioWriter::write_obj`:
+//! ```rust,ignore
+//! fn write(
+//!     &mut self,
+//!     uio: &mut UioReader,
+//!     _td: &CurThread,
+//! ) -> Result<(), Errno> {
+//!     let cmd: [u32; 2] = uio.read_obj()?;
+//!     self.run(cmd)
+//! }
+//! ```
 
-use crate::io::{self, Read, Write};
-use alloc::format;
+use crate::errno::Errno;
+use crate::io::{
+    self, AsBytes, FromBytes, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write,
+};
+use core::mem::{self, MaybeUninit};
 use core::prelude::v1::*;
 use core::{fmt, isize, ptr};
 use libc::c_void;
 
+/// How `Read` and `Write` treat `uio_offset`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Offsets {
+    /// The buffer passed in is the whole device, and the offset selects
+    /// where in it the transfer starts, as for a device backed by memory
+    #[default]
+    Seekable,
+    /// The offset is ignored and each call moves the next bytes of the
+    /// stream, as for FIFOs and terminals
+    Stream,
+}
+
 /// Wrapper around the kernel device driver I/O interfaces providing
 /// methods to read data from userland to the kernel
 ///
 /// https://nixdoc.net/man-pages/FreeBSD/man9/uio.9.html
 pub struct UioReader {
     uio: ptr::NonNull<kernel_sys::uio>,
+    offsets: Offsets,
 }
 
 impl UioReader {
     /// Create a new UioReader instance from a kernel uio pointer.
+    ///
+    /// ## Panics
+    /// Panics if the supplied uio pointer is null, or isn't a `UIO_WRITE`
     pub fn new(uio: *mut kernel_sys::uio) -> Self {
-        UioReader {
-            uio: ptr::NonNull::new(uio).unwrap(),
-        }
+        UioReader::with_offsets(uio, Offsets::Seekable)
+    }
+
+    /// Create a UioReader whose `read` treats the offset as `offsets` says
+    ///
+    /// ## Panics
+    /// Panics if the supplied uio pointer is null, or isn't a `UIO_WRITE`
+    pub fn with_offsets(uio: *mut kernel_sys::uio, offsets: Offsets) -> Self {
+        let uio = ptr::NonNull::new(uio).unwrap();
+        check_direction(uio, kernel_sys::uio_rw_UIO_WRITE);
+        UioReader { uio, offsets }
+    }
+
+    /// How `read` treats the offset
+    pub fn offsets(&self) -> Offsets {
+        self.offsets
     }
 
     /// The remaining number of bytes to process, updated after transfer.
@@ -57,12 +109,78 @@ impl UioReader {
     pub fn offset(&self) -> i64 {
         unsafe { self.uio.as_ref().uio_offset }
     }
+
+    /// Fill `buf` with the next bytes from userland, disregarding the
+    /// offset, as `read` does under `Offsets::Stream`. Fails with the
+    /// errno from `uiomove(9)`, such as `Errno::Fault` for a bad address,
+    /// ready to return from `CharacterDevice::write`
+    pub fn read_stream(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        uiomove_stream(buf.as_mut_ptr() as *mut c_void, buf.len(), self.uio)
+    }
+
+    /// Fill `buf`, which holds the device contents starting at `offset`,
+    /// from the transfer's current offset on. Moves nothing if the
+    /// transfer's offset lies outside the region, so a device made of
+    /// several regions can offer each one in turn
+    pub fn read_at(
+        &mut self,
+        buf: &mut [u8],
+        offset: i64,
+    ) -> Result<usize, Errno> {
+        let Some(start) = region_start(self.offset(), offset, buf.len()) else {
+            return Ok(0);
+        };
+        let buf = &mut buf[start..];
+        uiomove_stream(buf.as_mut_ptr() as *mut c_void, buf.len(), self.uio)
+    }
+
+    /// Consume the rest of the transfer without copying it, as a sink
+    /// like `/dev/null` does
+    pub fn discard(&mut self) {
+        unsafe { self.uio.as_mut().uio_resid = 0 };
+    }
+
+    /// Take a `T` from the next bytes from userland, disregarding the
+    /// offset like `read_stream`. Fails with `Errno::Inval`, taking
+    /// nothing, if fewer than `size_of::<T>()` bytes are left
+    pub fn read_obj<T: FromBytes>(&mut self) -> Result<T, Errno> {
+        let size = mem::size_of::<T>();
+        if self.residual() < size as isize {
+            return Err(Errno::Inval);
+        }
+        let mut obj = MaybeUninit::<T>::uninit();
+        uiomove_stream(obj.as_mut_ptr() as *mut c_void, size, self.uio)?;
+        // Every byte was moved, as the residual covered them
+        Ok(unsafe { obj.assume_init() })
+    }
 }
 
 impl Read for UioReader {
+    fn read_vectored(
+        &mut self,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> io::Result<usize> {
+        let mut total = 0;
+        let mut region = 0;
+        for buf in bufs {
+            if self.residual() <= 0 {
+                break;
+            }
+            total += match self.offsets {
+                Offsets::Stream => self.read_stream(buf)?,
+                Offsets::Seekable => self.read_at(buf, region)?,
+            };
+            region += buf.len() as i64;
+        }
+        Ok(total)
+    }
+
     // A reader is implemented for reading data from userland to kernel.
     // That is, for d_write callback.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offsets == Offsets::Stream {
+            return self.read_stream(buf).map_err(io::Error::from);
+        }
         let len: i32 = buf.len().try_into().map_err(|_| {
             io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -92,17 +210,28 @@ impl Read for UioReader {
                     )
                 })
             }
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("uiomove_frombuf failed with return code {}", ret),
+            _ => Err(io::Error::from_errno(
+                Errno::from_raw(ret).unwrap_or(Errno::Io),
+                "uiomove_frombuf failed",
             )),
         }
     }
 }
 
+impl Seek for UioReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        seek(self.uio, self.offsets, pos).map_err(io::Error::from)
+    }
+}
+
 impl fmt::Debug for UioReader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "UioReader {{ uio: {:?} }}", self.uio.as_ptr())
+        write!(
+            f,
+            "UioReader {{ uio: {:?}, offsets: {:?} }}",
+            self.uio.as_ptr(),
+            self.offsets
+        )
     }
 }
 
@@ -112,17 +241,31 @@ impl fmt::Debug for UioReader {
 /// https://nixdoc.net/man-pages/FreeBSD/man9/uio.9.html
 pub struct UioWriter {
     uio: ptr::NonNull<kernel_sys::uio>,
+    offsets: Offsets,
 }
 
 impl UioWriter {
     /// Create a new UioWriter
     ///
     /// ## Panics
-    /// Panics if the supplied uio pointer is null
+    /// Panics if the supplied uio pointer is null, or isn't a `UIO_READ`
     pub fn new(uio: *mut kernel_sys::uio) -> Self {
-        UioWriter {
-            uio: ptr::NonNull::new(uio).unwrap(),
-        }
+        UioWriter::with_offsets(uio, Offsets::Seekable)
+    }
+
+    /// Create a UioWriter whose `write` treats the offset as `offsets` says
+    ///
+    /// ## Panics
+    /// Panics if the supplied uio pointer is null, or isn't a `UIO_READ`
+    pub fn with_offsets(uio: *mut kernel_sys::uio, offsets: Offsets) -> Self {
+        let uio = ptr::NonNull::new(uio).unwrap();
+        check_direction(uio, kernel_sys::uio_rw_UIO_READ);
+        UioWriter { uio, offsets }
+    }
+
+    /// How `write` treats the offset
+    pub fn offsets(&self) -> Offsets {
+        self.offsets
     }
 
     /// The remaining number of bytes to process, updated after transfer.
@@ -134,10 +277,184 @@ impl UioWriter {
     pub fn offset(&self) -> i64 {
         unsafe { self.uio.as_ref().uio_offset }
     }
+
+    /// Send `buf` to userland, disregarding the offset; the counterpart of
+    /// `UioReader::read_stream`
+    pub fn write_stream(&mut self, buf: &[u8]) -> Result<usize, Errno> {
+        let p = buf.as_ptr() as *const c_void as *mut c_void;
+        uiomove_stream(p, buf.len(), self.uio)
+    }
+
+    /// Send the part of `buf`, which holds the device contents starting at
+    /// `offset`, from the transfer's current offset on; the counterpart of
+    /// `UioReader::read_at`
+    pub fn write_at(
+        &mut self,
+        buf: &[u8],
+        offset: i64,
+    ) -> Result<usize, Errno> {
+        let Some(start) = region_start(self.offset(), offset, buf.len()) else {
+            return Ok(0);
+        };
+        let buf = &buf[start..];
+        let p = buf.as_ptr() as *const c_void as *mut c_void;
+        uiomove_stream(p, buf.len(), self.uio)
+    }
+
+    /// Send `obj` to userland, disregarding the offset like
+    /// `write_stream`. Fails with `Errno::Inval`, sending nothing, if the
+    /// caller asked for fewer than `size_of::<T>()` bytes
+    pub fn write_obj<T: AsBytes>(&mut self, obj: &T) -> Result<(), Errno> {
+        let bytes = obj.as_bytes();
+        if self.residual() < bytes.len() as isize {
+            return Err(Errno::Inval);
+        }
+        self.write_stream(bytes).map(|_| ())
+    }
+}
+
+/// Most user pages `write_mapped` wires and maps at once
+#[cfg(not(feature = "mock"))]
+const MAP_PAGES: usize = 64;
+
+/// from `vm/vm.h`, which defines it with a cast bindgen skips
+#[cfg(not(feature = "mock"))]
+const VM_PROT_WRITE: kernel_sys::vm_prot_t = 0x02;
+
+#[cfg(not(feature = "mock"))]
+impl UioWriter {
+    /// Let `f` fill the caller's buffer in place instead of copying into it
+    /// with `uiomove(9)`, disregarding the offset like `write_stream`
+    ///
+    /// User buffers are held with `vm_fault_quick_hold_pages(9)` and mapped
+    /// into kernel address space `MAP_PAGES` pages at a time, so `f` may be
+    /// called several times per iovec. `f` returns how much of its slice it
+    /// filled; a short fill ends the transfer. Returns the total filled.
+    ///
+    /// Holding the pages can sleep, as `uiomove(9)` can.
+    pub fn write_mapped<F>(&mut self, mut f: F) -> Result<usize, Errno>
+    where
+        F: FnMut(&mut [u8]) -> usize,
+    {
+        let uio = unsafe { self.uio.as_mut() };
+        let mut total = 0;
+        while uio.uio_resid > 0 && uio.uio_iovcnt > 0 {
+            let iov = unsafe { &mut *uio.uio_iov };
+            if iov.iov_len == 0 {
+                uio.uio_iov = unsafe { uio.uio_iov.add(1) };
+                uio.uio_iovcnt -= 1;
+                continue;
+            }
+            let base = iov.iov_base as usize;
+            let mut len = iov.iov_len.min(uio.uio_resid as usize);
+            let n = match uio.uio_segflg {
+                kernel_sys::uio_seg_UIO_SYSSPACE => f(unsafe {
+                    core::slice::from_raw_parts_mut(base as *mut u8, len)
+                }),
+                kernel_sys::uio_seg_UIO_USERSPACE => {
+                    let page = kernel_sys::PAGE_SIZE as usize;
+                    let first = base & (page - 1);
+                    len = len.min(MAP_PAGES * page - first);
+                    unsafe { with_user_pages(uio.uio_td, base, len, &mut f)? }
+                }
+                _ => return Err(Errno::Inval),
+            };
+            let n = n.min(len);
+            iov.iov_base = (base + n) as *mut c_void;
+            iov.iov_len -= n;
+            uio.uio_resid -= n as isize;
+            uio.uio_offset += n as i64;
+            total += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+}
+
+/// User pages held and mapped at `kva` for the length of a `write_mapped`
+/// call to `f`
+#[cfg(not(feature = "mock"))]
+struct Mapping {
+    pages: [kernel_sys::vm_page_t; MAP_PAGES],
+    held: i32,
+    kva: kernel_sys::vm_offset_t,
+}
+
+#[cfg(not(feature = "mock"))]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let size = self.held as usize * kernel_sys::PAGE_SIZE as usize;
+        unsafe {
+            if self.kva != 0 {
+                kernel_sys::pmap_qremove(self.kva, self.held);
+                kernel_sys::kva_free(self.kva, size as _);
+            }
+            kernel_sys::vm_page_unhold_pages(
+                self.pages.as_mut_ptr(),
+                self.held,
+            );
+        }
+    }
+}
+
+/// Hold the `len` bytes of `td`'s address space at `addr` for writing, map
+/// them into kernel address space and hand them to `f`
+///
+/// `vm_fault_quick_hold_pages` marks pages held for writing dirty, so
+/// nothing is lost when `f` writes through the kernel mapping.
+#[cfg(not(feature = "mock"))]
+unsafe fn with_user_pages<F>(
+    td: *mut kernel_sys::thread,
+    addr: usize,
+    len: usize,
+    f: &mut F,
+) -> Result<usize, Errno>
+where
+    F: FnMut(&mut [u8]) -> usize,
+{
+    let mut m = Mapping {
+        pages: [ptr::null_mut(); MAP_PAGES],
+        held: 0,
+        kva: 0,
+    };
+    let held = unsafe {
+        let map = &raw mut (*(*(*td).td_proc).p_vmspace).vm_map;
+        kernel_sys::vm_fault_quick_hold_pages(
+            map,
+            addr as kernel_sys::vm_offset_t,
+            len as _,
+            VM_PROT_WRITE,
+            m.pages.as_mut_ptr(),
+            MAP_PAGES as i32,
+        )
+    };
+    if held < 0 {
+        return Err(Errno::Fault);
+    }
+    m.held = held;
+    let size = held as usize * kernel_sys::PAGE_SIZE as usize;
+    m.kva = unsafe { kernel_sys::kva_alloc(size as _) };
+    if m.kva == 0 {
+        return Err(Errno::NoMem);
+    }
+    unsafe { kernel_sys::pmap_qenter(m.kva, m.pages.as_mut_ptr(), held) };
+    let first = addr & (kernel_sys::PAGE_SIZE as usize - 1);
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(
+            (m.kva as usize + first) as *mut u8,
+            len,
+        )
+    };
+    Ok(f(buf))
 }
 
 impl Write for UioWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.offsets == Offsets::Stream {
+            return self.write_stream(buf).map_err(io::Error::from);
+        }
         let len: i32 = buf.len().try_into().map_err(|_| {
             io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -167,21 +484,100 @@ impl Write for UioWriter {
                     )
                 })
             }
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("uiomove_frombuf failed with return code {}", ret),
+            _ => Err(io::Error::from_errno(
+                Errno::from_raw(ret).unwrap_or(Errno::Io),
+                "uiomove_frombuf failed",
             )),
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut total = 0;
+        let mut region = 0;
+        for buf in bufs {
+            if self.residual() <= 0 {
+                break;
+            }
+            total += match self.offsets {
+                Offsets::Stream => self.write_stream(buf)?,
+                Offsets::Seekable => self.write_at(buf, region)?,
+            };
+            region += buf.len() as i64;
+        }
+        Ok(total)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         // XXX What do we do here?
         Ok(())
     }
 }
 
+impl Seek for UioWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        seek(self.uio, self.offsets, pos).map_err(io::Error::from)
+    }
+}
+
 impl fmt::Debug for UioWriter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "UioWriter {{ uio: {:?} }}", self.uio.as_ptr())
+        write!(
+            f,
+            "UioWriter {{ uio: {:?}, offsets: {:?} }}",
+            self.uio.as_ptr(),
+            self.offsets
+        )
+    }
+}
+
+/// Move up to `len` bytes between `p` and `uio` with `uiomove(9)`,
+/// returning how many were moved
+fn uiomove_stream(
+    p: *mut c_void,
+    len: usize,
+    mut uio: ptr::NonNull<kernel_sys::uio>,
+) -> Result<usize, Errno> {
+    let resid =
+        |uio: ptr::NonNull<kernel_sys::uio>| unsafe { uio.as_ref().uio_resid };
+    // uiomove() stops at the residual count anyway
+    let len = len.min(resid(uio).max(0) as usize);
+    let n: i32 = len.try_into().map_err(|_| Errno::Inval)?;
+    let orig_resid = resid(uio);
+    Errno::result(unsafe { kernel_sys::uiomove(p, n, uio.as_mut()) })?;
+    Ok((orig_resid - resid(uio)) as usize)
+}
+
+/// Panic unless `uio` moves data the way `rw` says, as a reader or writer
+/// the other way round would copy in the wrong direction
+fn check_direction(uio: ptr::NonNull<kernel_sys::uio>, rw: kernel_sys::uio_rw) {
+    let actual = unsafe { uio.as_ref().uio_rw };
+    assert!(actual == rw, "uio_rw is {}, expected {}", actual, rw);
+}
+
+/// Move the transfer's offset to `pos`. There's no end to seek from, as
+/// the uio doesn't know how big the device is
+fn seek(
+    mut uio: ptr::NonNull<kernel_sys::uio>,
+    offsets: Offsets,
+    pos: SeekFrom,
+) -> Result<u64, Errno> {
+    if offsets == Offsets::Stream {
+        return Err(Errno::SPipe);
     }
+    let uio = unsafe { uio.as_mut() };
+    let offset = match pos {
+        SeekFrom::Start(n) => i64::try_from(n).ok(),
+        SeekFrom::Current(d) => uio.uio_offset.checked_add(d),
+        SeekFrom::End(_) => None,
+    };
+    let offset = offset.filter(|&o| o >= 0).ok_or(Errno::Inval)?;
+    uio.uio_offset = offset;
+    Ok(offset as u64)
+}
+
+/// Where in a region of `len` bytes at device offset `offset` a transfer
+/// at `at` starts, if it starts inside it
+fn region_start(at: i64, offset: i64, len: usize) -> Option<usize> {
+    let start = usize::try_from(at.checked_sub(offset)?).ok()?;
+    (start < len).then_some(start)
 }
@@ -0,0 +1,267 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Zones of fixed-size items from `uma(9)`, for types allocated at a high
+//! rate, such as per-bio contexts or packet descriptors
+//!
+//! Items stay constructed while the zone caches them: `Default` builds
+//! one when the zone takes in fresh memory and it is dropped only when
+//! that memory is given back. `ZoneItem::ctor` and `dtor` run as each is
+//! handed out and freed, to do what has to happen every time:
+//!
+//! ```rust,ignore
+//! #[derive(Default)]
+//! struct Ctx {
+//!     retries: u32,
+//!     scratch: Vec<u8>,
+//! }
+//!
+//! impl ZoneItem for Ctx {
+//!     fn ctor(&mut self) -> Result<(), Errno> {
+//!         self.retries = 0;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let zone = UmaZone::<Ctx>::new(c"rmd ctx")?;
+//! zone.set_max(1024);
+//! let mut ctx = zone.alloc(Wait::NoWait)?;
+//! ctx.retries += 1;
+//! ```
+//!
+//! Every `ZoneBox` borrows its zone, so the zone, destroyed when dropped,
+//! can't go away with items out; dropping it with the rest of the module's
+//! state tears it down on unload.
+
+use crate::allocator::Wait;
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use core::alloc::AllocError;
+use core::ffi::CStr;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use libc::{c_int, c_void};
+
+/// A type a zone holds
+pub trait ZoneItem: Default + Send + 'static {
+    /// Ready an item being handed out, failing the allocation on error
+    fn ctor(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Tidy an item being freed, before the zone caches it for reuse
+    fn dtor(&mut self) {}
+}
+
+/// A `uma(9)` zone of `T`s
+pub struct UmaZone<T: ZoneItem> {
+    zone: kernel_sys::uma_zone_t,
+    _item: PhantomData<T>,
+}
+
+// Items are handed between threads through the zone's caches
+unsafe impl<T: ZoneItem> Send for UmaZone<T> {}
+unsafe impl<T: ZoneItem> Sync for UmaZone<T> {}
+
+impl<T: ZoneItem> UmaZone<T> {
+    /// Create a zone `vmstat -z` lists as `name`
+    pub fn new(name: &'static CStr) -> Result<Self, Errno> {
+        let zone = unsafe {
+            kernel_sys::uma_zcreate(
+                name.as_ptr(),
+                mem::size_of::<T>().max(1),
+                Some(ctor::<T>),
+                Some(dtor::<T>),
+                Some(init::<T>),
+                Some(fini::<T>),
+                (mem::align_of::<T>() - 1) as c_int,
+                0,
+            )
+        };
+        if zone.is_null() {
+            return Err(Errno::NoMem);
+        }
+        Ok(UmaZone {
+            zone,
+            _item: PhantomData,
+        })
+    }
+
+    /// Take an item from the zone, sleeping for memory or not as `wait`
+    /// says, and for a free item if the zone is at its limit
+    pub fn alloc(&self, wait: Wait) -> Result<ZoneBox<'_, T>, AllocError> {
+        let flags = match wait {
+            Wait::WaitOk => kernel_sys::M_WAITOK,
+            Wait::NoWait => kernel_sys::M_NOWAIT,
+        };
+        let item = unsafe {
+            kernel_sys::uma_zalloc_arg(self.zone, ptr::null_mut(), flags)
+        };
+        match NonNull::new(item as *mut T) {
+            Some(item) => Ok(ZoneBox { zone: self, item }),
+            None => Err(AllocError),
+        }
+    }
+
+    /// Limit the zone to about `items` items out at once, returning the
+    /// limit the zone rounded it to
+    pub fn set_max(&self, items: usize) -> usize {
+        let items = c_int::try_from(items).unwrap_or(c_int::MAX);
+        unsafe { kernel_sys::uma_zone_set_max(self.zone, items) as usize }
+    }
+
+    /// The zone's limit, 0 for none
+    pub fn max(&self) -> usize {
+        unsafe { kernel_sys::uma_zone_get_max(self.zone) as usize }
+    }
+
+    /// The number of items out
+    pub fn count(&self) -> usize {
+        unsafe { kernel_sys::uma_zone_get_cur(self.zone) as usize }
+    }
+
+    /// Have the kernel log `warning` when the zone hits its limit
+    pub fn set_warning(&self, warning: &'static CStr) {
+        unsafe { kernel_sys::uma_zone_set_warning(self.zone, warning.as_ptr()) }
+    }
+}
+
+impl<T: ZoneItem> Drop for UmaZone<T> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::uma_zdestroy(self.zone) };
+    }
+}
+
+impl<T: ZoneItem> fmt::Debug for UmaZone<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "UmaZone {{ count: {}, max: {} }}",
+            self.count(),
+            self.max()
+        )
+    }
+}
+
+/// An item out of a zone, which goes back to it when dropped
+pub struct ZoneBox<'a, T: ZoneItem> {
+    zone: &'a UmaZone<T>,
+    item: NonNull<T>,
+}
+
+unsafe impl<T: ZoneItem + Sync> Sync for ZoneBox<'_, T> {}
+unsafe impl<T: ZoneItem> Send for ZoneBox<'_, T> {}
+
+impl<'a, T: ZoneItem> ZoneBox<'a, T> {
+    /// The item, to keep somewhere only a pointer fits, like `bio_caller1`
+    pub fn into_raw(b: Self) -> *mut T {
+        let item = b.item.as_ptr();
+        mem::forget(b);
+        item
+    }
+
+    /// An item back from `into_raw`
+    ///
+    /// # Safety
+    ///
+    /// `item` must have come from `into_raw` on an item of `zone`, and
+    /// not been taken back already
+    pub unsafe fn from_raw(zone: &'a UmaZone<T>, item: *mut T) -> Self {
+        ZoneBox {
+            zone,
+            item: unsafe { NonNull::new_unchecked(item) },
+        }
+    }
+}
+
+impl<T: ZoneItem> Deref for ZoneBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.item.as_ref() }
+    }
+}
+
+impl<T: ZoneItem> DerefMut for ZoneBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.item.as_mut() }
+    }
+}
+
+impl<T: ZoneItem> Drop for ZoneBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::uma_zfree_arg(
+                self.zone.zone,
+                self.item.as_ptr() as *mut c_void,
+                ptr::null_mut(),
+            )
+        };
+    }
+}
+
+impl<T: ZoneItem + fmt::Debug> fmt::Debug for ZoneBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+unsafe extern "C" fn init<T: ZoneItem>(
+    mem: *mut c_void,
+    _size: c_int,
+    _flags: c_int,
+) -> c_int {
+    match catch_in_module(|| unsafe { (mem as *mut T).write(T::default()) }) {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn fini<T: ZoneItem>(mem: *mut c_void, _size: c_int) {
+    let _ = catch_in_module(|| unsafe { ptr::drop_in_place(mem as *mut T) });
+}
+
+unsafe extern "C" fn ctor<T: ZoneItem>(
+    mem: *mut c_void,
+    _size: c_int,
+    _arg: *mut c_void,
+    _flags: c_int,
+) -> c_int {
+    let item = unsafe { &mut *(mem as *mut T) };
+    match catch_in_module(|| item.ctor()).and_then(|r| r) {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
+
+unsafe extern "C" fn dtor<T: ZoneItem>(
+    mem: *mut c_void,
+    _size: c_int,
+    _arg: *mut c_void,
+) {
+    let item = unsafe { &mut *(mem as *mut T) };
+    let _ = catch_in_module(|| item.dtor());
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! Unit numbers, `unr(9)`
+//!
+//! A `UnitAllocator` hands out the lowest free number of a range, so the
+//! units of cloned devices or a class's instances stay dense and are
+//! reused once freed. Each number comes as a `Unit`, which frees it when
+//! dropped:
+//! ```ignore
+//! static UNITS: Lazy<UnitAllocator> = Lazy::new(|| UnitAllocator::new(0..=255));
+//!
+//! let unit = UNITS.alloc().ok_or(Errno::NoSpc)?;
+//! let name = format!("rustclone{}", unit);
+//! ```
+//! Allocating and freeing may sleep.
+
+use core::ops::RangeInclusive;
+use core::{fmt, mem, ptr};
+use libc::c_int;
+
+/// A range of unit numbers, some in use
+pub struct UnitAllocator {
+    uh: ptr::NonNull<kernel_sys::unrhdr>,
+}
+
+// The header takes its own lock
+unsafe impl Send for UnitAllocator {}
+unsafe impl Sync for UnitAllocator {}
+
+impl UnitAllocator {
+    /// Hand out the numbers in `range`, which must be no higher than
+    /// `i32::MAX`
+    pub fn new(range: RangeInclusive<u32>) -> Self {
+        let (low, high) = range.into_inner();
+        let low = c_int::try_from(low).expect("unit numbers are ints");
+        let high = c_int::try_from(high).expect("unit numbers are ints");
+        assert!(low <= high, "empty unit range");
+        // Without a mutex of ours, the header uses the shared unitmtx
+        let uh = unsafe { kernel_sys::new_unrhdr(low, high, ptr::null_mut()) };
+        UnitAllocator {
+            uh: ptr::NonNull::new(uh).unwrap(),
+        }
+    }
+
+    /// The lowest free number, if any are left
+    pub fn alloc(&self) -> Option<Unit<'_>> {
+        let n = unsafe { kernel_sys::alloc_unr(self.uh.as_ptr()) };
+        self.unit(n)
+    }
+
+    /// Number `n`, if it is in range and free, such as for a device the
+    /// user asked for by unit
+    pub fn alloc_specific(&self, n: u32) -> Option<Unit<'_>> {
+        let n = unsafe { kernel_sys::alloc_unr_specific(self.uh.as_ptr(), n) };
+        self.unit(n)
+    }
+
+    fn unit(&self, n: c_int) -> Option<Unit<'_>> {
+        let n = u32::try_from(n).ok()?;
+        Some(Unit { units: self, n })
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::unrhdr {
+        self.uh.as_ptr()
+    }
+}
+
+impl Drop for UnitAllocator {
+    fn drop(&mut self) {
+        // Numbers kept with `Unit::into_raw` may still be out
+        unsafe {
+            kernel_sys::clear_unrhdr(self.uh.as_ptr());
+            kernel_sys::delete_unrhdr(self.uh.as_ptr());
+        }
+    }
+}
+
+impl fmt::Debug for UnitAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UnitAllocator {{ uh: {:?} }}", self.uh.as_ptr())
+    }
+}
+
+/// A unit number in use, freed when dropped
+pub struct Unit<'a> {
+    units: &'a UnitAllocator,
+    n: u32,
+}
+
+impl<'a> Unit<'a> {
+    pub fn get(&self) -> u32 {
+        self.n
+    }
+
+    /// Keep the number in use without the guard, such as while a device
+    /// stored elsewhere holds it
+    pub fn into_raw(self) -> u32 {
+        let n = self.n;
+        mem::forget(self);
+        n
+    }
+
+    /// ## Safety
+    /// `n` must have come from `into_raw` on a unit of `units`, and not
+    /// been taken back already.
+    pub unsafe fn from_raw(units: &'a UnitAllocator, n: u32) -> Self {
+        Unit { units, n }
+    }
+}
+
+impl Drop for Unit<'_> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::free_unr(self.units.uh.as_ptr(), self.n) };
+    }
+}
+
+impl fmt::Debug for Unit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unit {{ n: {} }}", self.n)
+    }
+}
+
+impl fmt::Display for Unit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.n)
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Descriptors for a USB device, as a gadget presents them to the host
+//!
+//! A `Gadget` describes the device, its configurations, interfaces and
+//! endpoints, and `Gadget::build` lays that out as the descriptors the
+//! host reads while enumerating, with string indices assigned in order
+//! of appearance. Endpoints without a `max_packet` get the largest the
+//! speed allows for their type.
+
+use crate::errno::Errno;
+use alloc::vec::Vec;
+
+/// Descriptor types, `UDESC_*` in `dev/usb/usb.h`
+pub mod kind {
+    pub const DEVICE: u8 = 0x01;
+    pub const CONFIG: u8 = 0x02;
+    pub const STRING: u8 = 0x03;
+    pub const INTERFACE: u8 = 0x04;
+    pub const ENDPOINT: u8 = 0x05;
+    pub const DEVICE_QUALIFIER: u8 = 0x06;
+}
+
+/// Class codes, `UDCLASS_*` and `UICLASS_*` in `dev/usb/usb.h`
+pub mod class {
+    /// In a device descriptor, each interface gives its own class
+    pub const IN_INTERFACE: u8 = 0x00;
+    pub const CDC: u8 = 0x02;
+    pub const HID: u8 = 0x03;
+    pub const MASS_STORAGE: u8 = 0x08;
+    pub const CDC_DATA: u8 = 0x0a;
+    pub const VENDOR: u8 = 0xff;
+}
+
+/// `bmAttributes` of a configuration, `UC_*`
+pub mod attributes {
+    /// Must always be set
+    pub const BUS_POWERED: u8 = 0x80;
+    pub const SELF_POWERED: u8 = 0x40;
+    pub const REMOTE_WAKEUP: u8 = 0x20;
+}
+
+/// `bcdUSB`, and which packet sizes apply
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Speed {
+    Full,
+    High,
+}
+
+/// `UE_CONTROL` and so on
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TransferType {
+    Control = 0,
+    Isochronous = 1,
+    Bulk = 2,
+    Interrupt = 3,
+}
+
+/// The direction bit of an endpoint address, `UE_DIR_IN`
+pub const DIR_IN: u8 = 0x80;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Endpoint {
+    /// The endpoint number with `DIR_IN` for endpoints the host reads
+    pub address: u8,
+    pub kind: TransferType,
+    /// `None` for the speed's largest
+    pub max_packet: Option<u16>,
+    /// Polling interval, in frames at full speed and as an exponent of
+    /// microframes at high speed
+    pub interval: u8,
+}
+
+impl Endpoint {
+    /// Bulk endpoint `n` that the host reads from
+    pub const fn bulk_in(n: u8) -> Self {
+        Endpoint {
+            address: n | DIR_IN,
+            kind: TransferType::Bulk,
+            max_packet: None,
+            interval: 0,
+        }
+    }
+
+    /// Bulk endpoint `n` that the host writes to
+    pub const fn bulk_out(n: u8) -> Self {
+        Endpoint {
+            address: n,
+            kind: TransferType::Bulk,
+            max_packet: None,
+            interval: 0,
+        }
+    }
+
+    /// Interrupt endpoint `n` that the host polls every `interval`
+    pub const fn interrupt_in(n: u8, max_packet: u16, interval: u8) -> Self {
+        Endpoint {
+            address: n | DIR_IN,
+            kind: TransferType::Interrupt,
+            max_packet: Some(max_packet),
+            interval,
+        }
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & DIR_IN != 0
+    }
+
+    fn max_packet(&self, speed: Speed) -> u16 {
+        self.max_packet.unwrap_or(match (self.kind, speed) {
+            (TransferType::Bulk, Speed::High) => 512,
+            (
+                TransferType::Interrupt | TransferType::Isochronous,
+                Speed::High,
+            ) => 1024,
+            (TransferType::Isochronous, Speed::Full) => 1023,
+            _ => 64,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Interface {
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub name: Option<&'static str>,
+    /// Class-specific descriptors, such as CDC's functional ones, placed
+    /// after the interface descriptor
+    pub extra: Vec<u8>,
+    pub endpoints: Vec<Endpoint>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Configuration {
+    /// `attributes::*`, with `BUS_POWERED` added
+    pub attributes: u8,
+    /// Current drawn from the bus, in mA
+    pub max_power: u16,
+    pub name: Option<&'static str>,
+    pub interfaces: Vec<Interface>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Gadget {
+    pub vendor: u16,
+    pub product: u16,
+    /// `bcdDevice`
+    pub release: u16,
+    /// `class::IN_INTERFACE` unless the device as a whole has a class
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub manufacturer: Option<&'static str>,
+    pub product_name: Option<&'static str>,
+    pub serial: Option<&'static str>,
+    pub configurations: Vec<Configuration>,
+}
+
+/// `wLANGID` of the one language, US English
+const LANGID: u16 = 0x0409;
+
+/// The control endpoint's packet size
+const CONTROL_PACKET: u8 = 64;
+
+/// A gadget's descriptors for one speed
+#[derive(Clone, Debug)]
+pub struct Descriptors {
+    pub device: [u8; 18],
+    pub qualifier: [u8; 10],
+    configs: Vec<Vec<u8>>,
+    /// Index 0 holds the language IDs
+    strings: Vec<Vec<u8>>,
+}
+
+impl Descriptors {
+    /// Configuration descriptor `index`, with those of its interfaces and
+    /// endpoints following
+    pub fn config(&self, index: u8) -> Option<&[u8]> {
+        self.configs.get(index as usize).map(Vec::as_slice)
+    }
+
+    /// String descriptor `index`, in whichever language is asked for
+    pub fn string(&self, index: u8) -> Option<&[u8]> {
+        self.strings.get(index as usize).map(Vec::as_slice)
+    }
+
+    /// The descriptor a `GET_DESCRIPTOR` request for `kind` and `index`
+    /// returns, as sent
+    pub fn get(&self, kind: u8, index: u8) -> Option<&[u8]> {
+        match kind {
+            kind::DEVICE if index == 0 => Some(&self.device),
+            kind::DEVICE_QUALIFIER if index == 0 => Some(&self.qualifier),
+            kind::CONFIG => self.config(index),
+            kind::STRING => self.string(index),
+            _ => None,
+        }
+    }
+}
+
+/// String descriptors, assigning indices as they are added
+struct Strings(Vec<Vec<u8>>);
+
+impl Strings {
+    fn add(&mut self, s: Option<&str>) -> Result<u8, Errno> {
+        let Some(s) = s else {
+            return Ok(0);
+        };
+        let mut d = Vec::from([0, kind::STRING]);
+        for u in s.encode_utf16() {
+            d.extend_from_slice(&u.to_le_bytes());
+        }
+        d[0] = u8::try_from(d.len()).map_err(|_| Errno::NameTooLong)?;
+        let i = u8::try_from(self.0.len()).map_err(|_| Errno::TooBig)?;
+        self.0.push(d);
+        Ok(i)
+    }
+}
+
+impl Gadget {
+    /// Lay out the descriptors for `speed`, failing with `Errno::Inval`
+    /// for a gadget the host couldn't enumerate
+    pub fn build(&self, speed: Speed) -> Result<Descriptors, Errno> {
+        let configs = u8::try_from(self.configurations.len())
+            .map_err(|_| Errno::Inval)?;
+        if configs == 0 {
+            return Err(Errno::Inval);
+        }
+        let mut strings = Strings(Vec::from([Vec::from([
+            4,
+            kind::STRING,
+            LANGID as u8,
+            (LANGID >> 8) as u8,
+        ])]));
+        let manufacturer = strings.add(self.manufacturer)?;
+        let product = strings.add(self.product_name)?;
+        let serial = strings.add(self.serial)?;
+
+        let bcd_usb: u16 = match speed {
+            Speed::Full => 0x0110,
+            Speed::High => 0x0200,
+        };
+        let [usb_lo, usb_hi] = bcd_usb.to_le_bytes();
+        let [vid_lo, vid_hi] = self.vendor.to_le_bytes();
+        let [pid_lo, pid_hi] = self.product.to_le_bytes();
+        let [rel_lo, rel_hi] = self.release.to_le_bytes();
+        let device = [
+            18,
+            kind::DEVICE,
+            usb_lo,
+            usb_hi,
+            self.class,
+            self.subclass,
+            self.protocol,
+            CONTROL_PACKET,
+            vid_lo,
+            vid_hi,
+            pid_lo,
+            pid_hi,
+            rel_lo,
+            rel_hi,
+            manufacturer,
+            product,
+            serial,
+            configs,
+        ];
+        let qualifier = [
+            10,
+            kind::DEVICE_QUALIFIER,
+            0x00,
+            0x02,
+            self.class,
+            self.subclass,
+            self.protocol,
+            CONTROL_PACKET,
+            configs,
+            0,
+        ];
+
+        let mut descs = Vec::new();
+        for (c, config) in self.configurations.iter().enumerate() {
+            let count = u8::try_from(config.interfaces.len())
+                .map_err(|_| Errno::Inval)?;
+            let mut d = Vec::from([
+                9,
+                kind::CONFIG,
+                0,
+                0,
+                count,
+                c as u8 + 1,
+                strings.add(config.name)?,
+                config.attributes | attributes::BUS_POWERED,
+                (config.max_power / 2).min(0xff) as u8,
+            ]);
+            let mut seen = Vec::new();
+            for (i, iface) in config.interfaces.iter().enumerate() {
+                let endpoints = u8::try_from(iface.endpoints.len())
+                    .map_err(|_| Errno::Inval)?;
+                d.extend_from_slice(&[
+                    9,
+                    kind::INTERFACE,
+                    i as u8,
+                    0,
+                    endpoints,
+                    iface.class,
+                    iface.subclass,
+                    iface.protocol,
+                    strings.add(iface.name)?,
+                ]);
+                d.extend_from_slice(&iface.extra);
+                for ep in &iface.endpoints {
+                    // Endpoint 0 is the control pipe
+                    if ep.address & !DIR_IN == 0 || seen.contains(&ep.address) {
+                        return Err(Errno::Inval);
+                    }
+                    seen.push(ep.address);
+                    let [mp_lo, mp_hi] = ep.max_packet(speed).to_le_bytes();
+                    d.extend_from_slice(&[
+                        7,
+                        kind::ENDPOINT,
+                        ep.address,
+                        ep.kind as u8,
+                        mp_lo,
+                        mp_hi,
+                        ep.interval,
+                    ]);
+                }
+            }
+            let total = u16::try_from(d.len()).map_err(|_| Errno::Inval)?;
+            d[2..4].copy_from_slice(&total.to_le_bytes());
+            descs.push(d);
+        }
+        Ok(Descriptors {
+            device,
+            qualifier,
+            configs: descs,
+            strings: strings.0,
+        })
+    }
+}
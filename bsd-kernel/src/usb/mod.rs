@@ -0,0 +1,124 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! USB device mode
+//!
+//! With a controller in device mode, such as the `dwc_otg` or `musb`
+//! ones on many boards, the machine is the USB device and a host
+//! enumerates it. A module presents itself with a `descriptor::Gadget`,
+//! installed as the device-mode template by `template::install` in place
+//! of `usb_template(4)`. Once the host configures the gadget, `uhub`
+//! attaches drivers to its interfaces as it would on the host side; a
+//! `bus::Driver` declared for `uhub` in C claims them, checking
+//! `UsbInterface::is_device_mode`, and moves data through
+//! `transfer::Pipe`s on the interface's endpoints.
+
+pub mod descriptor;
+#[cfg(not(feature = "mock"))]
+pub mod template;
+#[cfg(not(feature = "mock"))]
+pub mod transfer;
+
+#[cfg(not(feature = "mock"))]
+use crate::bus::Device;
+#[cfg(not(feature = "mock"))]
+use crate::errno::Errno;
+
+/// `usb_error_t` as an `Errno`
+#[cfg(not(feature = "mock"))]
+pub(crate) fn result(err: kernel_sys::usb_error_t) -> Result<(), Errno> {
+    match err {
+        kernel_sys::usb_error_t_USB_ERR_NORMAL_COMPLETION => Ok(()),
+        kernel_sys::usb_error_t_USB_ERR_NOMEM => Err(Errno::NoMem),
+        kernel_sys::usb_error_t_USB_ERR_INVAL => Err(Errno::Inval),
+        kernel_sys::usb_error_t_USB_ERR_NO_PIPE => Err(Errno::NxIo),
+        kernel_sys::usb_error_t_USB_ERR_TIMEOUT => Err(Errno::TimedOut),
+        _ => Err(Errno::Io),
+    }
+}
+
+/// The interface of a `uhub` child, from its `usb_attach_arg`
+#[cfg(not(feature = "mock"))]
+#[derive(Copy, Clone, Debug)]
+pub struct UsbInterface {
+    uaa: *const kernel_sys::usb_attach_arg,
+}
+
+#[cfg(not(feature = "mock"))]
+unsafe impl Send for UsbInterface {}
+#[cfg(not(feature = "mock"))]
+unsafe impl Sync for UsbInterface {}
+
+#[cfg(not(feature = "mock"))]
+impl UsbInterface {
+    /// ## Safety
+    /// `dev` must be a child of `uhub`
+    pub unsafe fn new(dev: Device) -> Self {
+        let uaa = unsafe { kernel_sys::device_get_ivars(dev.as_ptr()) };
+        UsbInterface {
+            uaa: uaa as *const _,
+        }
+    }
+
+    fn info(&self) -> &kernel_sys::usbd_lookup_info {
+        unsafe { &(*self.uaa).info }
+    }
+
+    /// Whether the machine is the device, and the interface one of its
+    /// gadget's
+    pub fn is_device_mode(&self) -> bool {
+        let mode = unsafe { (*self.uaa).usb_mode };
+        mode == kernel_sys::usb_hc_mode_USB_MODE_DEVICE
+    }
+
+    pub fn vendor(&self) -> u16 {
+        self.info().idVendor
+    }
+
+    pub fn product(&self) -> u16 {
+        self.info().idProduct
+    }
+
+    /// Class, subclass and protocol
+    pub fn class(&self) -> (u8, u8, u8) {
+        let info = self.info();
+        (
+            info.bInterfaceClass,
+            info.bInterfaceSubClass,
+            info.bInterfaceProtocol,
+        )
+    }
+
+    /// `bInterfaceNumber`
+    pub fn number(&self) -> u8 {
+        self.info().bIfaceNum
+    }
+
+    pub(crate) fn index(&self) -> u8 {
+        self.info().bIfaceIndex
+    }
+
+    pub(crate) fn udev(&self) -> *mut kernel_sys::usb_device {
+        unsafe { (*self.uaa).device }
+    }
+}
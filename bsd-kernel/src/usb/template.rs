@@ -0,0 +1,156 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! A gadget as the device-mode template
+//!
+//! `usb_template(4)` answers the host's `GET_DESCRIPTOR` requests through
+//! hooks in the USB stack, with the built-in template `hw.usb.template`
+//! selects; `install` points them at a `Gadget` instead. Only one template
+//! can be installed, so `usb_template` must not be loaded with it.
+//! Endpoint addresses are used as given and must be ones the controller
+//! has, as `usb_template` would otherwise pick them.
+//!
+//! The template applies to devices enumerated after it's installed, so
+//! install it before loading the controller driver, or re-enumerate
+//! by setting `hw.usb.template`. Likewise, drop the `Template` only once
+//! no host can be enumerating the device, as its descriptors go with it.
+
+use super::descriptor::{Descriptors, Gadget, Speed};
+use crate::errno::Errno;
+use alloc::boxed::Box;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// `bmRequestType` of a standard device-to-host request, `UT_READ_DEVICE`
+const UT_READ_DEVICE: u8 = 0x80;
+
+/// `UR_GET_DESCRIPTOR`
+const UR_GET_DESCRIPTOR: u8 = 0x06;
+
+struct Installed {
+    full: Descriptors,
+    high: Descriptors,
+    get_desc: kernel_sys::usb_handle_req_t,
+    setup: kernel_sys::usb_temp_setup_by_index_t,
+    unsetup: kernel_sys::usb_temp_unsetup_t,
+}
+
+/// The one installed template, for the hooks
+static INSTALLED: AtomicPtr<Installed> = AtomicPtr::new(ptr::null_mut());
+
+/// The installed gadget, until dropped
+#[derive(Debug)]
+#[must_use = "the template is removed when dropped"]
+pub struct Template {
+    _priv: (),
+}
+
+unsafe extern "C" fn get_desc(
+    udev: *mut kernel_sys::usb_device,
+    req: *mut kernel_sys::usb_device_request,
+    data: *mut *const libc::c_void,
+    len: *mut u16,
+) -> kernel_sys::usb_error_t {
+    let Some(t) = (unsafe { INSTALLED.load(Ordering::Acquire).as_ref() })
+    else {
+        return kernel_sys::usb_error_t_USB_ERR_INVAL;
+    };
+    let req = unsafe { &*req };
+    if req.bmRequestType != UT_READ_DEVICE || req.bRequest != UR_GET_DESCRIPTOR
+    {
+        return kernel_sys::usb_error_t_USB_ERR_STALLED;
+    }
+    let descs = match unsafe { kernel_sys::usbd_get_speed(udev) } {
+        kernel_sys::usb_dev_speed_USB_SPEED_HIGH => &t.high,
+        _ => &t.full,
+    };
+    // wValue holds the index in its low byte and the type in its high one
+    match descs.get(req.wValue[1], req.wValue[0]) {
+        Some(d) => {
+            unsafe {
+                *data = d.as_ptr() as *const _;
+                *len = d.len() as u16;
+            }
+            kernel_sys::usb_error_t_USB_ERR_NORMAL_COMPLETION
+        }
+        None => kernel_sys::usb_error_t_USB_ERR_STALLED,
+    }
+}
+
+/// Every `hw.usb.template` index gets the gadget
+unsafe extern "C" fn setup(
+    _udev: *mut kernel_sys::usb_device,
+    _index: u16,
+) -> kernel_sys::usb_error_t {
+    match INSTALLED.load(Ordering::Acquire).is_null() {
+        true => kernel_sys::usb_error_t_USB_ERR_INVAL,
+        false => kernel_sys::usb_error_t_USB_ERR_NORMAL_COMPLETION,
+    }
+}
+
+/// The descriptors belong to the template, not the device
+unsafe extern "C" fn unsetup(_udev: *mut kernel_sys::usb_device) {}
+
+/// Present `gadget` to hosts, failing with `Errno::Busy` if there already
+/// is an installed gadget
+pub fn install(gadget: &Gadget) -> Result<Template, Errno> {
+    let t = Box::into_raw(Box::new(Installed {
+        full: gadget.build(Speed::Full)?,
+        high: gadget.build(Speed::High)?,
+        get_desc: unsafe { kernel_sys::usb_temp_get_desc_p },
+        setup: unsafe { kernel_sys::usb_temp_setup_by_index_p },
+        unsetup: unsafe { kernel_sys::usb_temp_unsetup_p },
+    }));
+    if INSTALLED
+        .compare_exchange(
+            ptr::null_mut(),
+            t,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+    {
+        drop(unsafe { Box::from_raw(t) });
+        return Err(Errno::Busy);
+    }
+    unsafe {
+        kernel_sys::usb_temp_get_desc_p = Some(get_desc);
+        kernel_sys::usb_temp_setup_by_index_p = Some(setup);
+        kernel_sys::usb_temp_unsetup_p = Some(unsetup);
+    }
+    Ok(Template { _priv: () })
+}
+
+impl Drop for Template {
+    /// Restore the hooks found at install
+    fn drop(&mut self) {
+        let t = INSTALLED.load(Ordering::Acquire);
+        unsafe {
+            kernel_sys::usb_temp_get_desc_p = (*t).get_desc;
+            kernel_sys::usb_temp_setup_by_index_p = (*t).setup;
+            kernel_sys::usb_temp_unsetup_p = (*t).unsetup;
+        }
+        INSTALLED.store(ptr::null_mut(), Ordering::Release);
+        drop(unsafe { Box::from_raw(t) });
+    }
+}
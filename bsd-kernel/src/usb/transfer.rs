@@ -0,0 +1,219 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Transfers on a gadget's bulk and interrupt endpoints
+//!
+//! A `Pipe` keeps one transfer queued on an endpoint. For an endpoint the
+//! host reads, its `Handler` fills the next packet whenever the last one
+//! has gone; for one the host writes, it is handed each packet as it
+//! arrives. Handlers run in the USB stack's callback thread with the
+//! pipe's lock held, and must not sleep.
+
+use super::UsbInterface;
+use super::descriptor::{DIR_IN, TransferType};
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use crate::sync::Mutex;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ptr;
+
+/// `USB_ST_*`, the state a transfer's callback sees
+const USB_ST_SETUP: u8 = 0;
+const USB_ST_TRANSFERRED: u8 = 1;
+
+/// Moves data through a `Pipe`
+pub trait Handler: Send + Sync + 'static {
+    /// The host is ready for more: fill `buf` and return how much to send.
+    /// Returning 0 leaves the pipe idle until `Pipe::start`
+    fn send(&self, _buf: &mut [u8]) -> usize {
+        0
+    }
+
+    /// `data` arrived from the host
+    fn received(&self, _data: &[u8]) {}
+}
+
+/// Which endpoint a pipe is for
+#[derive(Copy, Clone, Debug)]
+pub struct Endpoint {
+    /// As in the gadget's descriptors, with `DIR_IN` for ones the host
+    /// reads
+    pub address: u8,
+    pub kind: TransferType,
+    /// The largest transfer, at least a packet
+    pub bufsize: usize,
+}
+
+struct Shared<T> {
+    handler: T,
+    /// Bounce buffer for the DMA one, used under the pipe's lock
+    buf: UnsafeCell<Vec<u8>>,
+    out: bool,
+}
+
+/// A transfer on one endpoint of an interface
+pub struct Pipe<T: Handler> {
+    xfer: *mut kernel_sys::usb_xfer,
+    shared: Box<Shared<T>>,
+    lock: Mutex<()>,
+}
+
+unsafe impl<T: Handler> Send for Pipe<T> {}
+unsafe impl<T: Handler> Sync for Pipe<T> {}
+
+unsafe extern "C" fn callback<T: Handler>(
+    xfer: *mut kernel_sys::usb_xfer,
+    error: kernel_sys::usb_error_t,
+) {
+    let shared =
+        unsafe { &*(kernel_sys::usbd_xfer_softc(xfer) as *const Shared<T>) };
+    // The pipe's lock is held, so the buffer is ours
+    let buf = unsafe { &mut *shared.buf.get() };
+    let pc = unsafe { kernel_sys::usbd_xfer_get_frame(xfer, 0) };
+    let _ = catch_in_module(|| {
+        let state = unsafe { kernel_sys::usbd_xfer_state(xfer) };
+        if state != USB_ST_SETUP && state != USB_ST_TRANSFERRED {
+            // Cancelled by Pipe::stop, or stalled: clear it and go again
+            if error == kernel_sys::usb_error_t_USB_ERR_CANCELLED {
+                return;
+            }
+            unsafe { kernel_sys::usbd_xfer_set_stall(xfer) };
+        }
+        if shared.out {
+            if state == USB_ST_TRANSFERRED {
+                let mut actlen = 0;
+                unsafe {
+                    kernel_sys::usbd_xfer_status(
+                        xfer,
+                        &mut actlen,
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                    );
+                    kernel_sys::usbd_copy_out(
+                        pc,
+                        0,
+                        buf.as_mut_ptr() as *mut _,
+                        actlen as u32,
+                    );
+                }
+                shared.handler.received(&buf[..actlen as usize]);
+            }
+            let len = unsafe { kernel_sys::usbd_xfer_max_len(xfer) };
+            unsafe { kernel_sys::usbd_xfer_set_frame_len(xfer, 0, len) };
+        } else {
+            let n = shared.handler.send(buf).min(buf.len());
+            if n == 0 {
+                return;
+            }
+            unsafe {
+                kernel_sys::usbd_copy_in(
+                    pc,
+                    0,
+                    buf.as_ptr() as *const _,
+                    n as u32,
+                );
+                kernel_sys::usbd_xfer_set_frame_len(xfer, 0, n as u32);
+            }
+        }
+        unsafe { kernel_sys::usbd_transfer_submit(xfer) };
+    });
+}
+
+impl<T: Handler> Pipe<T> {
+    /// A pipe on `endpoint` of `iface`, stopped until `start`
+    pub fn new(
+        iface: &UsbInterface,
+        endpoint: Endpoint,
+        handler: T,
+    ) -> Result<Self, Errno> {
+        let mut flags: kernel_sys::usb_xfer_flags =
+            unsafe { core::mem::zeroed() };
+        // Pass short packets up rather than fail the transfer
+        flags.set_short_xfer_ok(1);
+        flags.set_pipe_bof(1);
+        let config = kernel_sys::usb_config {
+            type_: endpoint.kind as u8,
+            endpoint: endpoint.address & !DIR_IN,
+            direction: endpoint.address & DIR_IN,
+            bufsize: endpoint.bufsize as u32,
+            frames: 1,
+            flags,
+            callback: Some(callback::<T>),
+            usb_mode: kernel_sys::usb_hc_mode_USB_MODE_DEVICE,
+            ..unsafe { core::mem::zeroed() }
+        };
+        let shared = Box::new(Shared {
+            handler,
+            buf: UnsafeCell::new(alloc::vec![0; endpoint.bufsize]),
+            out: endpoint.address & DIR_IN == 0,
+        });
+        let lock = Mutex::new(c"usbpipe", ());
+        let index = iface.index();
+        let mut xfer = ptr::null_mut();
+        super::result(unsafe {
+            kernel_sys::usbd_transfer_setup(
+                iface.udev(),
+                &index,
+                &mut xfer,
+                &config,
+                1,
+                &*shared as *const Shared<T> as *mut _,
+                lock.raw(),
+            )
+        })?;
+        Ok(Pipe { xfer, shared, lock })
+    }
+
+    pub fn handler(&self) -> &T {
+        &self.shared.handler
+    }
+
+    /// Queue a transfer: ask the handler for data, or wait for the host's
+    pub fn start(&self) {
+        let _guard = self.lock.lock();
+        unsafe { kernel_sys::usbd_transfer_start(self.xfer) };
+    }
+
+    /// Cancel the queued transfer
+    pub fn stop(&self) {
+        let _guard = self.lock.lock();
+        unsafe { kernel_sys::usbd_transfer_stop(self.xfer) };
+    }
+}
+
+impl<T: Handler> Drop for Pipe<T> {
+    /// Waits for the callback to finish, so it must not run under the
+    /// pipe's lock
+    fn drop(&mut self) {
+        unsafe { kernel_sys::usbd_transfer_unsetup(&mut self.xfer, 1) };
+    }
+}
+
+impl<T: Handler> core::fmt::Debug for Pipe<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Pipe {{ xfer: {:?} }}", self.xfer)
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Userland memory, reached only through `copyin(9)` and `copyout(9)`
+//!
+//! Ioctl arguments that point at more data and GEOM ctlreq parameters
+//! carry raw user addresses. Wrapped in `UserPtr`, `UserSlice` or
+//! `UserCStr`, they are read and written with the copy routines, which
+//! turn a bad address into `Errno::Fault` rather than a kernel fault, and
+//! check sizes before anything is copied:
+//!
+//! ```rust,ignore
+//! let req: Request = cmd.payload();
+//! let name = UserCStr::new(req.name as usize).read_to_cstring(MAXPATHLEN)?;
+//! let data = UserSlice::new(req.buf as usize, req.len).read_to_vec(MAX_IO)?;
+//! UserPtr::<u32>::new(req.status as usize).write(&0)?;
+//! ```
+//!
+//! The addresses are in the address space of the thread that made the
+//! request, so are only good while handling it.
+
+use crate::errno::Errno;
+use crate::io::{AsBytes, FromBytes};
+use alloc::ffi::CString;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{self, MaybeUninit};
+use libc::c_void;
+
+/// The end of `len` bytes at `addr`, or `Errno::Fault` if that wraps
+fn end(addr: usize, len: usize) -> Result<usize, Errno> {
+    addr.checked_add(len).ok_or(Errno::Fault)
+}
+
+/// Copy `len` bytes in from user address `addr` to `dst`
+fn copyin(addr: usize, dst: *mut u8, len: usize) -> Result<(), Errno> {
+    end(addr, len)?;
+    Errno::result(unsafe {
+        kernel_sys::copyin(addr as *const c_void, dst as *mut c_void, len)
+    })
+}
+
+/// Copy `src` out to user address `addr`
+fn copyout(src: &[u8], addr: usize) -> Result<(), Errno> {
+    end(addr, src.len())?;
+    Errno::result(unsafe {
+        kernel_sys::copyout(
+            src.as_ptr() as *const c_void,
+            addr as *mut c_void,
+            src.len(),
+        )
+    })
+}
+
+/// The user address of a `T`
+pub struct UserPtr<T> {
+    addr: usize,
+    _t: PhantomData<*mut T>,
+}
+
+// Only an address, good for whichever thread handles the request
+unsafe impl<T> Send for UserPtr<T> {}
+unsafe impl<T> Sync for UserPtr<T> {}
+
+impl<T> UserPtr<T> {
+    pub const fn new(addr: usize) -> Self {
+        UserPtr {
+            addr,
+            _t: PhantomData,
+        }
+    }
+
+    pub const fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub const fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    /// The address of the `T` `count` places on, as in an array
+    pub fn offset(self, count: usize) -> Result<Self, Errno> {
+        let offset = count.checked_mul(mem::size_of::<T>());
+        let addr = offset.and_then(|o| self.addr.checked_add(o));
+        addr.map(Self::new).ok_or(Errno::Fault)
+    }
+
+    /// The `T` there
+    pub fn read(&self) -> Result<T, Errno>
+    where
+        T: FromBytes,
+    {
+        let mut value = MaybeUninit::<T>::uninit();
+        let size = mem::size_of::<T>();
+        copyin(self.addr, value.as_mut_ptr() as *mut u8, size)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Store `value` there
+    pub fn write(&self, value: &T) -> Result<(), Errno>
+    where
+        T: AsBytes,
+    {
+        copyout(value.as_bytes(), self.addr)
+    }
+}
+
+impl UserPtr<u32> {
+    /// The word there, with `fueword32(9)`, which is cheaper than
+    /// `copyin(9)` for one word
+    pub fn fetch(&self) -> Result<u32, Errno> {
+        let mut val = 0;
+        let ret = unsafe {
+            kernel_sys::fueword32(self.addr as *const c_void, &mut val)
+        };
+        if ret == -1 {
+            return Err(Errno::Fault);
+        }
+        Ok(val as u32)
+    }
+
+    /// Store `word` there, with `suword32(9)`
+    pub fn store(&self, word: u32) -> Result<(), Errno> {
+        let ret = unsafe {
+            kernel_sys::suword32(self.addr as *mut c_void, word as i32)
+        };
+        if ret == -1 {
+            return Err(Errno::Fault);
+        }
+        Ok(())
+    }
+}
+
+impl UserPtr<u64> {
+    /// The word there, with `fueword64(9)`
+    pub fn fetch(&self) -> Result<u64, Errno> {
+        let mut val = 0;
+        let ret = unsafe {
+            kernel_sys::fueword64(self.addr as *const c_void, &mut val)
+        };
+        if ret == -1 {
+            return Err(Errno::Fault);
+        }
+        Ok(val as u64)
+    }
+
+    /// Store `word` there, with `suword64(9)`
+    pub fn store(&self, word: u64) -> Result<(), Errno> {
+        let ret = unsafe {
+            kernel_sys::suword64(self.addr as *mut c_void, word as i64)
+        };
+        if ret == -1 {
+            return Err(Errno::Fault);
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> fmt::Debug for UserPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UserPtr {{ addr: {:#x} }}", self.addr)
+    }
+}
+
+/// `len` bytes of user memory at an address
+#[derive(Copy, Clone)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub const fn new(addr: usize, len: usize) -> Self {
+        UserSlice { addr, len }
+    }
+
+    pub const fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Fill `buf` from the start of the slice, or fail with
+    /// `Errno::Inval` if the slice is shorter
+    pub fn read(&self, buf: &mut [u8]) -> Result<(), Errno> {
+        if buf.len() > self.len {
+            return Err(Errno::Inval);
+        }
+        copyin(self.addr, buf.as_mut_ptr(), buf.len())
+    }
+
+    /// The whole slice, or `Errno::TooBig` if it is over `max` bytes, as
+    /// the length came from userland
+    pub fn read_to_vec(&self, max: usize) -> Result<Vec<u8>, Errno> {
+        if self.len > max {
+            return Err(Errno::TooBig);
+        }
+        let mut buf = Vec::with_capacity(self.len);
+        copyin(self.addr, buf.as_mut_ptr(), self.len)?;
+        unsafe { buf.set_len(self.len) };
+        Ok(buf)
+    }
+
+    /// Copy `data` to the start of the slice, or fail with
+    /// `Errno::Inval` if it doesn't fit
+    pub fn write(&self, data: &[u8]) -> Result<(), Errno> {
+        if data.len() > self.len {
+            return Err(Errno::Inval);
+        }
+        copyout(data, self.addr)
+    }
+
+    /// The byte at `offset`, with `fubyte(9)`
+    pub fn read_byte(&self, offset: usize) -> Result<u8, Errno> {
+        let addr = self.byte(offset)?;
+        match unsafe { kernel_sys::fubyte(addr as *const c_void) } {
+            -1 => Err(Errno::Fault),
+            b => Ok(b as u8),
+        }
+    }
+
+    /// Store `byte` at `offset`, with `subyte(9)`
+    pub fn write_byte(&self, offset: usize, byte: u8) -> Result<(), Errno> {
+        let addr = self.byte(offset)?;
+        match unsafe { kernel_sys::subyte(addr as *mut c_void, byte.into()) } {
+            -1 => Err(Errno::Fault),
+            _ => Ok(()),
+        }
+    }
+
+    fn byte(&self, offset: usize) -> Result<usize, Errno> {
+        if offset >= self.len {
+            return Err(Errno::Inval);
+        }
+        end(self.addr, offset)
+    }
+}
+
+impl fmt::Debug for UserSlice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "UserSlice {{ addr: {:#x}, len: {} }}",
+            self.addr, self.len
+        )
+    }
+}
+
+/// A NUL-terminated string in user memory
+#[derive(Copy, Clone)]
+pub struct UserCStr {
+    addr: usize,
+}
+
+impl UserCStr {
+    pub const fn new(addr: usize) -> Self {
+        UserCStr { addr }
+    }
+
+    pub const fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Copy the string, NUL included, into `buf`, with `copyinstr(9)`.
+    /// Fails with `Errno::NameTooLong` if it doesn't fit
+    pub fn read<'a>(&self, buf: &'a mut [u8]) -> Result<&'a CStr, Errno> {
+        let mut copied = 0;
+        Errno::result(unsafe {
+            kernel_sys::copyinstr(
+                self.addr as *const c_void,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+                &mut copied,
+            )
+        })?;
+        CStr::from_bytes_with_nul(&buf[..copied]).map_err(|_| Errno::Fault)
+    }
+
+    /// The string, of at most `max` bytes NUL included, or
+    /// `Errno::NameTooLong` if it's longer
+    pub fn read_to_cstring(&self, max: usize) -> Result<CString, Errno> {
+        let mut buf = vec![0; max];
+        let len = self.read(&mut buf)?.count_bytes();
+        buf.truncate(len);
+        // No NULs inside, as copyinstr stopped at the first
+        Ok(unsafe { CString::from_vec_unchecked(buf) })
+    }
+}
+
+impl fmt::Debug for UserCStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UserCStr {{ addr: {:#x} }}", self.addr)
+    }
+}
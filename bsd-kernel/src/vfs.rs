@@ -0,0 +1,840 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Read-only filesystems, see `vfs(9)` and `VOP_LOOKUP(9)`
+//!
+//! A `Filesystem` is a type `mount(8)` can name with `-t`, whose `mount`
+//! makes a `Volume` from the options it was given. The volume names its
+//! files by inode number and answers for them; the glue keeps a vnode
+//! for each in use, found again by number, and turns the kernel's vnode
+//! operations into calls on the volume:
+//! ```ignore
+//! impl Volume for Image {
+//!     fn root(&self) -> u64 { ROOT }
+//!     fn getattr(&self, ino: u64) -> Result<Attr, Errno> { ... }
+//!     fn lookup(&self, dir: u64, name: &[u8]) -> Result<u64, Errno> { ... }
+//!     ...
+//! }
+//!
+//! let vfs = Vfs::new(ImageFs);
+//! vfs.register()?;
+//! ```
+//! Files can be read, mapped and run; anything that would change the
+//! volume fails with `Errno::RoFs`, and mounts are always read-only.
+//! `Vfs::unregister` fails with `Errno::Busy` while any volume is
+//! mounted. Every call runs in the thread of the process doing the I/O,
+//! and may sleep.
+
+use crate::arch::curthread;
+use crate::errno::Errno;
+use crate::panic::{Poison, catch_at_boundary};
+use crate::uio::UioWriter;
+use alloc::boxed::Box;
+use alloc::vec;
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::{fmt, mem, ptr, slice};
+use libc::{c_int, c_void};
+
+/// A filesystem type, registered with `Vfs`
+pub trait Filesystem: Sized + Send + Sync + 'static {
+    /// The type's name, as given to `mount -t`. At most 15 bytes
+    const NAME: &'static CStr;
+
+    /// A mounted instance
+    type Volume: Volume;
+
+    /// Mount a volume as `opts` describe it, such as from the `from`
+    /// option, which `mount(8)` fills with its special argument. Runs
+    /// with the mounting thread's credentials
+    fn mount(&self, opts: &Options) -> Result<Self::Volume, Errno>;
+}
+
+/// A mounted filesystem, whose files are named by inode number
+pub trait Volume: Send + Sync + 'static {
+    /// The root directory's number
+    fn root(&self) -> u64;
+
+    /// Describe file `ino`
+    fn getattr(&self, ino: u64) -> Result<Attr, Errno>;
+
+    /// The number of `name` in directory `dir`. Never asked for `.` or
+    /// `..`
+    fn lookup(&self, dir: u64, name: &[u8]) -> Result<u64, Errno>;
+
+    /// The directory `dir` is in. The root is its own parent
+    fn parent(&self, dir: u64) -> Result<u64, Errno>;
+
+    /// Entry `index` of directory `dir`, counting from 0 and leaving out
+    /// `.` and `..`, or `None` past the last
+    fn readdir(
+        &self,
+        dir: u64,
+        index: u64,
+    ) -> Result<Option<DirEntry<'_>>, Errno>;
+
+    /// Read a regular file's contents, or a symlink's target, from
+    /// `offset` into `buf`, returning how much was read, which is short
+    /// only at the end
+    fn read(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Errno>;
+
+    /// Totals for `statfs(2)`
+    fn statfs(&self) -> Statfs {
+        Statfs::default()
+    }
+}
+
+/// The kinds of file a `Volume` can hold
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+}
+
+impl FileType {
+    fn vtype(self) -> kernel_sys::vtype {
+        match self {
+            FileType::Regular => kernel_sys::vtype_VREG,
+            FileType::Directory => kernel_sys::vtype_VDIR,
+            FileType::Symlink => kernel_sys::vtype_VLNK,
+        }
+    }
+
+    fn dtype(self) -> u8 {
+        (match self {
+            FileType::Regular => kernel_sys::DT_REG,
+            FileType::Directory => kernel_sys::DT_DIR,
+            FileType::Symlink => kernel_sys::DT_LNK,
+        }) as u8
+    }
+}
+
+/// What `stat(2)` reports of a file
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Attr {
+    pub kind: FileType,
+    /// Permission bits, such as `0o755`
+    pub mode: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Length in bytes; a symlink's is its target's
+    pub size: u64,
+    /// Last modified, in seconds since the epoch, also given as the
+    /// access and change times
+    pub mtime: i64,
+}
+
+/// An entry `Volume::readdir` lists
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry<'a> {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: &'a [u8],
+}
+
+/// A volume's size, in 512-byte blocks, and number of files
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Statfs {
+    pub blocks: u64,
+    pub files: u64,
+}
+
+/// The options a mount was asked for, see `vfs_getopt(9)`
+pub struct Options<'a> {
+    opts: *mut kernel_sys::vfsoptlist,
+    _mount: PhantomData<&'a ()>,
+}
+
+impl Options<'_> {
+    /// The value of option `name`, if given
+    pub fn get(&self, name: &CStr) -> Option<&[u8]> {
+        let mut buf = ptr::null_mut();
+        let mut len = 0;
+        let error = unsafe {
+            kernel_sys::vfs_getopt(self.opts, name.as_ptr(), &mut buf, &mut len)
+        };
+        if error != 0 {
+            return None;
+        }
+        if buf.is_null() {
+            return Some(&[]);
+        }
+        Some(unsafe { slice::from_raw_parts(buf as *const u8, len as usize) })
+    }
+
+    /// The value of option `name` as a string, if given as one
+    pub fn get_str(&self, name: &CStr) -> Option<&CStr> {
+        CStr::from_bytes_with_nul(self.get(name)?).ok()
+    }
+
+    /// Whether option `name`, such as `ro`, was given, with or without a
+    /// value
+    pub fn has(&self, name: &CStr) -> bool {
+        self.get(name).is_some()
+    }
+}
+
+impl fmt::Debug for Options<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Options {{ .. }}")
+    }
+}
+
+/// A registered filesystem type backed by a `Filesystem` implementation
+///
+/// The kernel keeps pointers to the tables inside, so it is only ever
+/// handed out boxed.
+#[repr(C)]
+pub struct Vfs<T: Filesystem> {
+    // First, so callbacks can get from a mount's vfsconf back to the Vfs
+    conf: UnsafeCell<kernel_sys::vfsconf>,
+    ops: UnsafeCell<kernel_sys::vfsops>,
+    vnops: UnsafeCell<kernel_sys::vop_vector>,
+    inner: T,
+    poison: Poison,
+}
+
+unsafe impl<T: Filesystem> Send for Vfs<T> {}
+unsafe impl<T: Filesystem> Sync for Vfs<T> {}
+
+impl<T: Filesystem> Vfs<T> {
+    /// Create the filesystem type, not yet known to the kernel
+    pub fn new(inner: T) -> Box<Self> {
+        let mut ops: kernel_sys::vfsops = unsafe { mem::zeroed() };
+        ops.vfs_mount = Some(vfs_mount::<T>);
+        ops.vfs_unmount = Some(vfs_unmount::<T>);
+        ops.vfs_root = Some(vfs_root::<T>);
+        ops.vfs_statfs = Some(vfs_statfs::<T>);
+        ops.vfs_vget = Some(vfs_vget::<T>);
+        let mut vnops: kernel_sys::vop_vector = unsafe { mem::zeroed() };
+        vnops.vop_default = &raw mut kernel_sys::default_vnodeops;
+        vnops.vop_lookup = Some(vop_lookup::<T>);
+        vnops.vop_access = Some(vop_access::<T>);
+        vnops.vop_getattr = Some(vop_getattr::<T>);
+        vnops.vop_setattr = Some(vop_setattr);
+        vnops.vop_open = Some(vop_open::<T>);
+        vnops.vop_read = Some(vop_read::<T>);
+        vnops.vop_readdir = Some(vop_readdir::<T>);
+        vnops.vop_readlink = Some(vop_readlink::<T>);
+        vnops.vop_bmap = Some(vop_bmap);
+        vnops.vop_reclaim = Some(vop_reclaim);
+        let vfs = Box::new(Vfs {
+            conf: UnsafeCell::new(unsafe { mem::zeroed() }),
+            ops: UnsafeCell::new(ops),
+            vnops: UnsafeCell::new(vnops),
+            inner,
+            poison: Poison::new(),
+        });
+        let conf = unsafe { &mut *vfs.conf.get() };
+        conf.vfc_version = kernel_sys::VFS_VERSION as _;
+        let name = T::NAME.to_bytes();
+        assert!(name.len() < conf.vfc_name.len(), "filesystem name too long");
+        for (c, &b) in conf.vfc_name.iter_mut().zip(name) {
+            *c = b as _;
+        }
+        conf.vfc_vfsops = vfs.ops.get();
+        conf.vfc_flags = kernel_sys::VFCF_READONLY as _;
+        // Fills in the operations left out from the defaults
+        unsafe { kernel_sys::vfs_vector_op_register(vfs.vnops.get()) };
+        vfs
+    }
+
+    /// Make the type available to `mount(8)`. Call from the module's load
+    /// event
+    pub fn register(&self) -> Result<(), Errno> {
+        Errno::result(unsafe { kernel_sys::vfs_register(self.conf.get()) })
+    }
+
+    /// Withdraw the type. Fails with `Errno::Busy`, leaving it
+    /// registered, while any volume is mounted
+    pub fn unregister(&self) -> Result<(), Errno> {
+        Errno::result(unsafe { kernel_sys::vfs_unregister(self.conf.get()) })
+    }
+
+    /// Whether a callback has panicked, after which new mounts and calls
+    /// into mounted volumes are refused with `Errno::NxIo`
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    /// Raw pointer to the underlying vfsconf
+    pub fn as_ptr(&self) -> *mut kernel_sys::vfsconf {
+        self.conf.get()
+    }
+
+    /// ## Safety
+    /// `mp` must be a mount of this type
+    unsafe fn from_mount<'a>(mp: *mut kernel_sys::mount) -> &'a Self {
+        unsafe { &*((*mp).mnt_vfc as *const Self) }
+    }
+
+    /// Run `f` on behalf of a call into mount `mp`, refusing it if the
+    /// type is poisoned
+    ///
+    /// ## Safety
+    /// `mp` must be a mount of this type
+    unsafe fn call(
+        mp: *mut kernel_sys::mount,
+        f: impl FnOnce(&Self) -> Result<(), Errno>,
+    ) -> c_int {
+        let vfs = unsafe { Self::from_mount(mp) };
+        match catch_at_boundary(&vfs.poison, || f(vfs)) {
+            Ok(Ok(())) => 0,
+            Ok(Err(e)) | Err(e) => e.as_raw(),
+        }
+    }
+
+    /// The vnode for file `ino` of mount `mp`, locked as `flags` ask,
+    /// from those in use or made anew
+    ///
+    /// ## Safety
+    /// `mp` must be a mount of this type, with its volume in place
+    unsafe fn vget(
+        &self,
+        mp: *mut kernel_sys::mount,
+        ino: u64,
+        flags: c_int,
+        vpp: *mut *mut kernel_sys::vnode,
+    ) -> Result<(), Errno> {
+        let td = curthread();
+        let mut wanted = ino;
+        let key = &raw mut wanted as *mut c_void;
+        // Only the low bits hash, so the comparison tells files apart
+        let hash = ino as u32;
+        unsafe { *vpp = ptr::null_mut() };
+        Errno::result(unsafe {
+            kernel_sys::vfs_hash_get(mp, hash, flags, td, vpp, Some(same), key)
+        })?;
+        if unsafe { !(*vpp).is_null() } {
+            return Ok(());
+        }
+        let volume = unsafe { volume::<T>(mp) };
+        let attr = volume.getattr(ino)?;
+        let mut vp = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::getnewvnode(
+                T::NAME.as_ptr(),
+                mp,
+                self.vnops.get(),
+                &mut vp,
+            )
+        })?;
+        let node = Box::into_raw(Box::new(Node { ino }));
+        unsafe {
+            (*vp).v_data = node as *mut c_void;
+            (*vp).v_type = attr.kind.vtype() as _;
+            if ino == volume.root() {
+                (*vp).v_vflag |= kernel_sys::VV_ROOT as _;
+            }
+            kernel_sys::_vn_lock(
+                vp,
+                (kernel_sys::LK_EXCLUSIVE | kernel_sys::LK_RETRY) as c_int,
+                c"vfs.rs".as_ptr(),
+                line!() as c_int,
+            );
+        }
+        if let Err(e) = Errno::result(unsafe { kernel_sys::insmntque(vp, mp) })
+        {
+            // The vnode is gone, and its v_data cleared for reclaim to skip
+            drop(unsafe { Box::from_raw(node) });
+            return Err(e);
+        }
+        // Loses to a racing thread that made the same file's vnode first,
+        // whose vnode is returned instead and this one reclaimed
+        Errno::result(unsafe {
+            kernel_sys::vfs_hash_insert(
+                vp,
+                hash,
+                flags,
+                td,
+                vpp,
+                Some(same),
+                key,
+            )
+        })?;
+        unsafe {
+            if (*vpp).is_null() {
+                *vpp = vp;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Filesystem> Deref for Vfs<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Filesystem + fmt::Debug> fmt::Debug for Vfs<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Vfs {{ name: {:?}, inner: {:?} }}", T::NAME, self.inner)
+    }
+}
+
+/// What a vnode's `v_data` points to
+struct Node {
+    ino: u64,
+}
+
+/// ## Safety
+/// `vp` must be a live vnode of a `Vfs`
+unsafe fn ino(vp: *mut kernel_sys::vnode) -> u64 {
+    unsafe { (*((*vp).v_data as *const Node)).ino }
+}
+
+/// ## Safety
+/// `mp` must be a mounted `Vfs<T>`
+unsafe fn volume<'a, T: Filesystem>(
+    mp: *mut kernel_sys::mount,
+) -> &'a T::Volume {
+    unsafe { &*((*mp).mnt_data as *const T::Volume) }
+}
+
+/// Tells `vfs_hash_get` whether `vp` is the file `arg` points to
+unsafe extern "C" fn same(
+    vp: *mut kernel_sys::vnode,
+    arg: *mut c_void,
+) -> c_int {
+    unsafe { (ino(vp) != *(arg as *const u64)) as c_int }
+}
+
+unsafe extern "C" fn vfs_mount<T: Filesystem>(
+    mp: *mut kernel_sys::mount,
+) -> c_int {
+    let flags = unsafe { (*mp).mnt_flag };
+    if flags & kernel_sys::MNT_UPDATE as u64 != 0 {
+        // Nothing to change, but neither can it be made writable
+        return match flags & kernel_sys::MNT_RDONLY as u64 {
+            0 => Errno::RoFs.as_raw(),
+            _ => 0,
+        };
+    }
+    unsafe {
+        Vfs::<T>::call(mp, |vfs| {
+            let opts = Options {
+                opts: (*mp).mnt_optnew,
+                _mount: PhantomData,
+            };
+            let volume = vfs.mount(&opts)?;
+            (*mp).mnt_data = Box::into_raw(Box::new(volume)) as *mut c_void;
+            let mtx = &raw mut (*mp).mnt_mtx.mtx_lock;
+            kernel_sys::_mtx_lock_flags(mtx, 0, ptr::null(), 0);
+            (*mp).mnt_flag |=
+                (kernel_sys::MNT_RDONLY | kernel_sys::MNT_LOCAL) as u64;
+            kernel_sys::_mtx_unlock_flags(mtx, 0, ptr::null(), 0);
+            kernel_sys::vfs_getnewfsid(mp);
+            let from = opts.get_str(c"from").unwrap_or(T::NAME);
+            kernel_sys::vfs_mountedfrom(mp, from.as_ptr());
+            Ok(())
+        })
+    }
+}
+
+unsafe extern "C" fn vfs_unmount<T: Filesystem>(
+    mp: *mut kernel_sys::mount,
+    mntflags: c_int,
+) -> c_int {
+    let flags = match mntflags as u64 & kernel_sys::MNT_FORCE as u64 {
+        0 => 0,
+        _ => kernel_sys::FORCECLOSE as c_int,
+    };
+    // Reclaims every vnode, so nothing refers to the volume after
+    let error = unsafe { kernel_sys::vflush(mp, 0, flags, curthread()) };
+    if error != 0 {
+        return error;
+    }
+    unsafe {
+        drop(Box::from_raw((*mp).mnt_data as *mut T::Volume));
+        (*mp).mnt_data = ptr::null_mut();
+    }
+    0
+}
+
+unsafe extern "C" fn vfs_root<T: Filesystem>(
+    mp: *mut kernel_sys::mount,
+    flags: c_int,
+    vpp: *mut *mut kernel_sys::vnode,
+) -> c_int {
+    unsafe {
+        Vfs::<T>::call(mp, |vfs| {
+            vfs.vget(mp, volume::<T>(mp).root(), flags, vpp)
+        })
+    }
+}
+
+unsafe extern "C" fn vfs_vget<T: Filesystem>(
+    mp: *mut kernel_sys::mount,
+    ino: kernel_sys::ino_t,
+    flags: c_int,
+    vpp: *mut *mut kernel_sys::vnode,
+) -> c_int {
+    unsafe { Vfs::<T>::call(mp, |vfs| vfs.vget(mp, ino, flags, vpp)) }
+}
+
+unsafe extern "C" fn vfs_statfs<T: Filesystem>(
+    mp: *mut kernel_sys::mount,
+    sbp: *mut kernel_sys::statfs,
+) -> c_int {
+    unsafe {
+        Vfs::<T>::call(mp, |_| {
+            let totals = volume::<T>(mp).statfs();
+            let sb = &mut *sbp;
+            sb.f_bsize = 512;
+            sb.f_iosize = kernel_sys::PAGE_SIZE as _;
+            sb.f_blocks = totals.blocks;
+            sb.f_bfree = 0;
+            sb.f_bavail = 0;
+            sb.f_files = totals.files;
+            sb.f_ffree = 0;
+            Ok(())
+        })
+    }
+}
+
+unsafe extern "C" fn vop_lookup<T: Filesystem>(
+    ap: *mut kernel_sys::vop_lookup_args,
+) -> c_int {
+    let ap = unsafe { &mut *ap };
+    let dvp = ap.a_dvp;
+    let cnp = unsafe { &*ap.a_cnp };
+    let mp = unsafe { (*dvp).v_mount };
+    unsafe { *ap.a_vpp = ptr::null_mut() };
+    unsafe {
+        Vfs::<T>::call(mp, |vfs| {
+            if (*dvp).v_type as u32 != kernel_sys::vtype_VDIR as u32 {
+                return Err(Errno::NotDir);
+            }
+            let volume = volume::<T>(mp);
+            let dir = ino(dvp);
+            access(volume, dvp, kernel_sys::VEXEC as _, cnp.cn_cred)?;
+            let last = cnp.cn_flags as u64 & kernel_sys::ISLASTCN as u64 != 0;
+            if last && cnp.cn_nameiop as u64 != kernel_sys::LOOKUP as u64 {
+                return Err(Errno::RoFs);
+            }
+            let name = slice::from_raw_parts(
+                cnp.cn_nameptr as *const u8,
+                cnp.cn_namelen as usize,
+            );
+            match name {
+                b"." => {
+                    kernel_sys::vref(dvp);
+                    *ap.a_vpp = dvp;
+                    Ok(())
+                }
+                // Unlocks the directory while the parent is locked
+                b".." => Errno::result(kernel_sys::vn_vget_ino(
+                    dvp,
+                    volume.parent(dir)?,
+                    cnp.cn_lkflags,
+                    ap.a_vpp,
+                )),
+                _ => {
+                    let ino = volume.lookup(dir, name)?;
+                    vfs.vget(mp, ino, cnp.cn_lkflags, ap.a_vpp)
+                }
+            }
+        })
+    }
+}
+
+/// Check `accmode` against file `vp`'s owner and mode, refusing writes
+///
+/// ## Safety
+/// `vp` must be a live vnode of `volume`
+unsafe fn access<V: Volume>(
+    volume: &V,
+    vp: *mut kernel_sys::vnode,
+    accmode: kernel_sys::accmode_t,
+    cred: *mut kernel_sys::ucred,
+) -> Result<(), Errno> {
+    if accmode
+        & (kernel_sys::VWRITE | kernel_sys::VAPPEND) as kernel_sys::accmode_t
+        != 0
+    {
+        return Err(Errno::RoFs);
+    }
+    let attr = volume.getattr(unsafe { ino(vp) })?;
+    Errno::result(unsafe {
+        kernel_sys::vaccess(
+            (*vp).v_type as _,
+            attr.mode as _,
+            attr.uid,
+            attr.gid,
+            accmode,
+            cred,
+        )
+    })
+}
+
+unsafe extern "C" fn vop_access<T: Filesystem>(
+    ap: *mut kernel_sys::vop_access_args,
+) -> c_int {
+    let ap = unsafe { &*ap };
+    let mp = unsafe { (*ap.a_vp).v_mount };
+    unsafe {
+        Vfs::<T>::call(mp, |_| {
+            access(volume::<T>(mp), ap.a_vp, ap.a_accmode, ap.a_cred)
+        })
+    }
+}
+
+unsafe extern "C" fn vop_getattr<T: Filesystem>(
+    ap: *mut kernel_sys::vop_getattr_args,
+) -> c_int {
+    let ap = unsafe { &*ap };
+    let vp = ap.a_vp;
+    let mp = unsafe { (*vp).v_mount };
+    unsafe {
+        Vfs::<T>::call(mp, |_| {
+            let ino = ino(vp);
+            let attr = volume::<T>(mp).getattr(ino)?;
+            let time = kernel_sys::timespec {
+                tv_sec: attr.mtime as _,
+                tv_nsec: 0,
+            };
+            let va = &mut *ap.a_vap;
+            *va = mem::zeroed();
+            va.va_type = (*vp).v_type as _;
+            va.va_mode = attr.mode as _;
+            va.va_nlink = attr.nlink as _;
+            va.va_uid = attr.uid;
+            va.va_gid = attr.gid;
+            va.va_fsid = (*mp).mnt_stat.f_fsid.val[0] as _;
+            va.va_fileid = ino;
+            va.va_size = attr.size;
+            va.va_blocksize = kernel_sys::PAGE_SIZE as _;
+            va.va_atime = time;
+            va.va_mtime = time;
+            va.va_ctime = time;
+            va.va_birthtime = time;
+            // NODEV, which bindgen leaves out
+            va.va_rdev = !0;
+            va.va_bytes = attr.size;
+            Ok(())
+        })
+    }
+}
+
+unsafe extern "C" fn vop_setattr(
+    _ap: *mut kernel_sys::vop_setattr_args,
+) -> c_int {
+    Errno::RoFs.as_raw()
+}
+
+unsafe extern "C" fn vop_open<T: Filesystem>(
+    ap: *mut kernel_sys::vop_open_args,
+) -> c_int {
+    let ap = unsafe { &*ap };
+    let vp = ap.a_vp;
+    let mp = unsafe { (*vp).v_mount };
+    unsafe {
+        Vfs::<T>::call(mp, |_| {
+            if (*vp).v_type as u32 != kernel_sys::vtype_VREG as u32 {
+                return Ok(());
+            }
+            // For mmap(2) and exec, whose pages are read through vop_read
+            // as vop_bmap says there are no blocks to go to
+            let size = volume::<T>(mp).getattr(ino(vp))?.size;
+            Errno::result(kernel_sys::vnode_create_vobject(
+                vp, size as _, ap.a_td,
+            ))
+        })
+    }
+}
+
+/// Send file `ino` from the transfer's offset on, as far as it wants
+/// and the file goes
+fn copy_out<V: Volume>(
+    volume: &V,
+    ino: u64,
+    uio: *mut kernel_sys::uio,
+) -> Result<(), Errno> {
+    let mut uio = UioWriter::new(uio);
+    let offset = u64::try_from(uio.offset()).map_err(|_| Errno::Inval)?;
+    let want = uio.residual().max(0) as usize;
+    let mut buf = vec![0; want.min(kernel_sys::MAXBSIZE as usize)];
+    let mut done = 0;
+    while done < want {
+        let n = (want - done).min(buf.len());
+        let got = volume.read(ino, offset + done as u64, &mut buf[..n])?;
+        uio.write_stream(&buf[..got])?;
+        done += got;
+        if got < n {
+            break;
+        }
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn vop_read<T: Filesystem>(
+    ap: *mut kernel_sys::vop_read_args,
+) -> c_int {
+    let ap = unsafe { &*ap };
+    let vp = ap.a_vp;
+    let mp = unsafe { (*vp).v_mount };
+    unsafe {
+        Vfs::<T>::call(mp, |_| match (*vp).v_type as u32 {
+            t if t == kernel_sys::vtype_VREG as u32 => {
+                copy_out(volume::<T>(mp), ino(vp), ap.a_uio)
+            }
+            t if t == kernel_sys::vtype_VDIR as u32 => Err(Errno::IsDir),
+            _ => Err(Errno::Inval),
+        })
+    }
+}
+
+unsafe extern "C" fn vop_readlink<T: Filesystem>(
+    ap: *mut kernel_sys::vop_readlink_args,
+) -> c_int {
+    let ap = unsafe { &*ap };
+    let vp = ap.a_vp;
+    let mp = unsafe { (*vp).v_mount };
+    unsafe {
+        Vfs::<T>::call(mp, |_| {
+            if (*vp).v_type as u32 != kernel_sys::vtype_VLNK as u32 {
+                return Err(Errno::Inval);
+            }
+            copy_out(volume::<T>(mp), ino(vp), ap.a_uio)
+        })
+    }
+}
+
+/// Offset of `d_name` in a `struct dirent`
+const DIRENT_NAME: usize = mem::offset_of!(kernel_sys::dirent, d_name);
+
+/// List directory entries from the transfer's offset, which counts
+/// entries rather than bytes, `.` and `..` first
+unsafe extern "C" fn vop_readdir<T: Filesystem>(
+    ap: *mut kernel_sys::vop_readdir_args,
+) -> c_int {
+    let ap = unsafe { &*ap };
+    let vp = ap.a_vp;
+    let mp = unsafe { (*vp).v_mount };
+    unsafe {
+        Vfs::<T>::call(mp, |_| {
+            if (*vp).v_type as u32 != kernel_sys::vtype_VDIR as u32 {
+                return Err(Errno::NotDir);
+            }
+            // Only NFS asks for cookies, and volumes can't be exported
+            if !ap.a_ncookies.is_null() {
+                return Err(Errno::OpNotSupp);
+            }
+            let volume = volume::<T>(mp);
+            let dir = ino(vp);
+            let uio = ap.a_uio;
+            let mut index =
+                u64::try_from((*uio).uio_offset).map_err(|_| Errno::Inval)?;
+            let mut out = UioWriter::new(uio);
+            let mut sent = false;
+            let mut eof = false;
+            loop {
+                let entry = match index {
+                    0 => DirEntry {
+                        ino: dir,
+                        kind: FileType::Directory,
+                        name: b".",
+                    },
+                    1 => DirEntry {
+                        ino: volume.parent(dir)?,
+                        kind: FileType::Directory,
+                        name: b"..",
+                    },
+                    _ => match volume.readdir(dir, index - 2)? {
+                        Some(entry) => entry,
+                        None => {
+                            eof = true;
+                            break;
+                        }
+                    },
+                };
+                let mut d: kernel_sys::dirent = mem::zeroed();
+                if entry.name.len() >= d.d_name.len() {
+                    return Err(Errno::NameTooLong);
+                }
+                // GENERIC_DIRSIZ: the name and its NUL, 8-byte aligned
+                let reclen = (DIRENT_NAME + entry.name.len() + 1 + 7) & !7;
+                if out.residual() < reclen as isize {
+                    break;
+                }
+                d.d_fileno = entry.ino;
+                d.d_off = (index + 1) as _;
+                d.d_reclen = reclen as u16;
+                d.d_type = entry.kind.dtype();
+                d.d_namlen = entry.name.len() as u16;
+                for (c, &b) in d.d_name.iter_mut().zip(entry.name) {
+                    *c = b as _;
+                }
+                let bytes =
+                    slice::from_raw_parts(&raw const d as *const u8, reclen);
+                out.write_stream(bytes)?;
+                sent = true;
+                index += 1;
+                (*uio).uio_offset = index as _;
+            }
+            if !sent && !eof {
+                // Too small a buffer for even one entry
+                return Err(Errno::Inval);
+            }
+            if !ap.a_eofflag.is_null() {
+                *ap.a_eofflag = eof as c_int;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// There are no blocks behind files, so the pager falls back to reading
+/// pages in with `vop_read`
+unsafe extern "C" fn vop_bmap(_ap: *mut kernel_sys::vop_bmap_args) -> c_int {
+    Errno::OpNotSupp.as_raw()
+}
+
+/// Free the node, whatever state the type is in, as the vnode is going
+unsafe extern "C" fn vop_reclaim(
+    ap: *mut kernel_sys::vop_reclaim_args,
+) -> c_int {
+    let vp = unsafe { (*ap).a_vp };
+    let node = unsafe { (*vp).v_data as *mut Node };
+    // Null only when insmntque failed, before the vnode was hashed
+    if !node.is_null() {
+        unsafe {
+            kernel_sys::vfs_hash_remove(vp);
+            drop(Box::from_raw(node));
+            (*vp).v_data = ptr::null_mut();
+        }
+    }
+    0
+}
@@ -0,0 +1,575 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Memory shared between the kernel and userland through `mmap(2)`
+//!
+//! A `SharedBuffer` is a run of wired pages in a VM object, which the
+//! kernel keeps mapped. A character device hands it to userland from
+//! `CharacterDevice::mmap`, after which both sides see the same bytes
+//! without copying:
+//! ```ignore
+//! fn mmap(&mut self, offset: u64, _size: usize, _prot: Prot)
+//!     -> Result<(&SharedBuffer, u64), Errno>
+//! {
+//!     Ok((&self.ring, offset))
+//! }
+//! ```
+//! Each mapping holds its own reference to the object, so the pages stay
+//! valid for a process that keeps them mapped after the buffer, and the
+//! device, are gone.
+//!
+//! A `SwapObject` is pageable memory backed by swap, for more data than
+//! should stay resident, copied in and out a page at a time:
+//! ```ignore
+//! let store = SwapObject::new(1 << 30)?;
+//! store.write(offset, &block)?;
+//! ```
+//!
+//! A `ContigBuffer` is physically contiguous memory for hardware to reach
+//! by bus address, within the alignment, boundary and address range the
+//! device's DMA engine can cope with:
+//! ```ignore
+//! let within = ContigConstraints::new().below(0xffff_ffff).boundary(0x1_0000);
+//! let ring = ContigBuffer::new([Desc::default(); 256], &within, Wait::WaitOk)?;
+//! regs.write_4(RING_BASE, ring.phys() as u32);
+//! ```
+
+use crate::allocator::Wait;
+use crate::errno::Errno;
+use core::ops::{Deref, DerefMut};
+use core::{fmt, mem, ops, ptr};
+
+// From `vm/vm.h`, whose casts bindgen doesn't follow
+const VM_PROT_READ: kernel_sys::vm_prot_t = 0x01;
+const VM_PROT_WRITE: kernel_sys::vm_prot_t = 0x02;
+const VM_PROT_EXECUTE: kernel_sys::vm_prot_t = 0x04;
+
+/// Access to mapped memory, as `PROT_READ` and so on
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Prot(kernel_sys::vm_prot_t);
+
+impl Prot {
+    pub const NONE: Self = Prot(0);
+    pub const READ: Self = Prot(VM_PROT_READ);
+    pub const WRITE: Self = Prot(VM_PROT_WRITE);
+    pub const EXECUTE: Self = Prot(VM_PROT_EXECUTE);
+
+    pub const fn from_bits(bits: kernel_sys::vm_prot_t) -> Self {
+        Prot(bits)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The protection as a `vm_prot_t`
+    pub const fn bits(self) -> kernel_sys::vm_prot_t {
+        self.0
+    }
+}
+
+impl ops::BitOr for Prot {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Prot(self.0 | other.0)
+    }
+}
+
+fn kernel_map() -> kernel_sys::vm_map_t {
+    &raw mut kernel_sys::kernel_map_store
+}
+
+/// Zeroed pages that userland can map, also mapped into the kernel
+pub struct SharedBuffer {
+    object: ptr::NonNull<kernel_sys::vm_object>,
+    /// Where the kernel's mapping starts
+    addr: kernel_sys::vm_offset_t,
+    len: usize,
+}
+
+unsafe impl Send for SharedBuffer {}
+unsafe impl Sync for SharedBuffer {}
+
+impl SharedBuffer {
+    /// Allocate and wire at least `len` bytes, rounded up to whole pages.
+    /// Sleeps
+    pub fn new(len: usize) -> Result<Self, Errno> {
+        let page = kernel_sys::PAGE_SIZE as usize;
+        let len = len.checked_next_multiple_of(page).ok_or(Errno::NoMem)?;
+        if len == 0 {
+            return Err(Errno::Inval);
+        }
+        let object = unsafe {
+            kernel_sys::vm_object_allocate(
+                kernel_sys::obj_type_OBJT_PHYS as kernel_sys::objtype_t,
+                (len / page) as kernel_sys::vm_pindex_t,
+            )
+        };
+        let object = ptr::NonNull::new(object).ok_or(Errno::NoMem)?;
+        // One reference for the kernel's mapping, which it consumes, and
+        // one for us to hand on to `mmap`
+        unsafe { kernel_sys::vm_object_reference(object.as_ptr()) };
+        let mut addr = 0;
+        let rv = unsafe {
+            kernel_sys::vm_map_find(
+                kernel_map(),
+                object.as_ptr(),
+                0,
+                &mut addr,
+                len,
+                0,
+                kernel_sys::VMFS_ANY_SPACE,
+                VM_PROT_READ | VM_PROT_WRITE,
+                VM_PROT_READ | VM_PROT_WRITE,
+                0,
+            )
+        };
+        if rv != kernel_sys::KERN_SUCCESS {
+            unsafe {
+                kernel_sys::vm_object_deallocate(object.as_ptr());
+                kernel_sys::vm_object_deallocate(object.as_ptr());
+            }
+            return Err(Errno::NoMem);
+        }
+        let buf = SharedBuffer { object, addr, len };
+        // Fault every page in now, so the kernel can use them anywhere
+        let rv = unsafe {
+            kernel_sys::vm_map_wire(
+                kernel_map(),
+                addr,
+                addr + len,
+                kernel_sys::VM_MAP_WIRE_SYSTEM
+                    | kernel_sys::VM_MAP_WIRE_NOHOLES,
+            )
+        };
+        if rv != kernel_sys::KERN_SUCCESS {
+            return Err(Errno::NoMem);
+        }
+        Ok(buf)
+    }
+
+    /// Length in bytes, a whole number of pages
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The kernel's view of the buffer. Userland may change it at any
+    /// time, so reads through it can see torn or changing values
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr as *mut u8
+    }
+
+    /// Copy out `buf.len()` bytes at `offset`
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Errno> {
+        self.check(offset, buf.len())?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                self.as_ptr().add(offset),
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        Ok(())
+    }
+
+    /// Copy `data` in at `offset`
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<(), Errno> {
+        self.check(offset, data.len())?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.as_ptr().add(offset),
+                data.len(),
+            )
+        };
+        Ok(())
+    }
+
+    fn check(&self, offset: usize, len: usize) -> Result<(), Errno> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(Errno::Inval),
+        }
+    }
+
+    /// A new reference to the object, for the `size` bytes at `offset`
+    /// that an `mmap(2)` asked for, or `Errno::Inval` if they don't fit
+    pub(crate) fn map(
+        &self,
+        offset: u64,
+        size: usize,
+    ) -> Result<*mut kernel_sys::vm_object, Errno> {
+        let offset = usize::try_from(offset).map_err(|_| Errno::Inval)?;
+        self.check(offset, size)?;
+        unsafe { kernel_sys::vm_object_reference(self.object.as_ptr()) };
+        Ok(self.object.as_ptr())
+    }
+
+    /// Raw pointer to the underlying VM object
+    pub fn as_object(&self) -> *mut kernel_sys::vm_object {
+        self.object.as_ptr()
+    }
+}
+
+impl Drop for SharedBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            // Unwires the pages and drops the mapping's reference
+            kernel_sys::vm_map_remove(
+                kernel_map(),
+                self.addr,
+                self.addr + self.len,
+            );
+            kernel_sys::vm_object_deallocate(self.object.as_ptr());
+        }
+    }
+}
+
+impl fmt::Debug for SharedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SharedBuffer {{ addr: {:#x}, len: {} }}",
+            self.addr, self.len
+        )
+    }
+}
+
+/// Pageable anonymous memory backed by swap, which the kernel reads and
+/// writes a page at a time without keeping it mapped
+pub struct SwapObject {
+    object: ptr::NonNull<kernel_sys::vm_object>,
+    len: u64,
+}
+
+unsafe impl Send for SwapObject {}
+unsafe impl Sync for SwapObject {}
+
+impl SwapObject {
+    /// Reserve `len` bytes of swap, charged to the calling thread's
+    /// credentials, reading as zeroes until written. Sleeps
+    pub fn new(len: u64) -> Result<Self, Errno> {
+        if len == 0 {
+            return Err(Errno::Inval);
+        }
+        let object = unsafe {
+            kernel_sys::vm_pager_allocate(
+                kernel_sys::obj_type_OBJT_SWAP as kernel_sys::objtype_t,
+                ptr::null_mut(),
+                len as kernel_sys::vm_ooffset_t,
+                VM_PROT_READ | VM_PROT_WRITE,
+                0,
+                (*crate::arch::curthread()).td_ucred,
+            )
+        };
+        let object = ptr::NonNull::new(object).ok_or(Errno::NoMem)?;
+        Ok(SwapObject { object, len })
+    }
+
+    /// Length in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy out `buf.len()` bytes at `offset`, paging in as needed
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        self.pages(offset, buf.len(), false, |m, off, at, n| unsafe {
+            kernel_sys::physcopyout(
+                (*m).phys_addr + off as kernel_sys::vm_paddr_t,
+                buf.as_mut_ptr().add(at) as *mut _,
+                n,
+            );
+        })
+    }
+
+    /// Copy `data` in at `offset`, leaving the pages dirty for the
+    /// pagedaemon to send to swap when memory runs short
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<(), Errno> {
+        self.pages(offset, data.len(), true, |m, off, at, n| unsafe {
+            kernel_sys::physcopyin(
+                data.as_ptr().add(at) as *mut _,
+                (*m).phys_addr + off as kernel_sys::vm_paddr_t,
+                n,
+            );
+            kernel_sys::vm_page_dirty_KBI(m);
+        })
+    }
+
+    /// Busy each valid page under `len` bytes at `offset` in turn, shared
+    /// or, to `write`, exclusive, for `f` to copy `n` bytes at `off`
+    /// within it, `at` bytes into the transfer
+    fn pages(
+        &self,
+        offset: u64,
+        len: usize,
+        write: bool,
+        mut f: impl FnMut(kernel_sys::vm_page_t, usize, usize, usize),
+    ) -> Result<(), Errno> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.len => {}
+            _ => return Err(Errno::Inval),
+        }
+        let page = kernel_sys::PAGE_SIZE as u64;
+        let flags = if write {
+            kernel_sys::VM_ALLOC_NORMAL
+        } else {
+            kernel_sys::VM_ALLOC_NORMAL | kernel_sys::VM_ALLOC_SBUSY
+        };
+        let mut at = 0;
+        while at < len {
+            let pos = offset + at as u64;
+            let off = (pos % page) as usize;
+            let n = (len - at).min(page as usize - off);
+            let mut m = ptr::null_mut();
+            let rv = unsafe {
+                kernel_sys::vm_page_grab_valid_unlocked(
+                    &mut m,
+                    self.object.as_ptr(),
+                    (pos / page) as kernel_sys::vm_pindex_t,
+                    flags as i32,
+                )
+            };
+            if rv != kernel_sys::VM_PAGER_OK as i32 {
+                return Err(Errno::Io);
+            }
+            f(m, off, at, n);
+            unsafe {
+                if write {
+                    kernel_sys::vm_page_xunbusy_hard(m);
+                } else {
+                    kernel_sys::vm_page_sunbusy(m);
+                }
+            }
+            at += n;
+        }
+        Ok(())
+    }
+
+    /// Raw pointer to the underlying VM object
+    pub fn as_object(&self) -> *mut kernel_sys::vm_object {
+        self.object.as_ptr()
+    }
+}
+
+impl Drop for SwapObject {
+    fn drop(&mut self) {
+        // Frees the pages and the swap behind them, and the reservation
+        unsafe { kernel_sys::vm_object_deallocate(self.object.as_ptr()) };
+    }
+}
+
+impl fmt::Debug for SwapObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SwapObject {{ len: {} }}", self.len)
+    }
+}
+
+/// Where `ContigBuffer`s may be placed, for a DMA engine's limits
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ContigConstraints {
+    low: kernel_sys::vm_paddr_t,
+    high: kernel_sys::vm_paddr_t,
+    alignment: u64,
+    boundary: u64,
+    memattr: kernel_sys::vm_memattr_t,
+}
+
+impl ContigConstraints {
+    /// Anywhere in physical memory, page aligned, and cached as usual
+    pub const fn new() -> Self {
+        ContigConstraints {
+            low: 0,
+            high: kernel_sys::vm_paddr_t::MAX,
+            alignment: kernel_sys::PAGE_SIZE as u64,
+            boundary: 0,
+            memattr: kernel_sys::VM_MEMATTR_DEFAULT as _,
+        }
+    }
+
+    /// At or above physical address `low`
+    pub const fn above(self, low: kernel_sys::vm_paddr_t) -> Self {
+        ContigConstraints { low, ..self }
+    }
+
+    /// Ending at or below physical address `high`, as for a device that
+    /// only drives 32 address lines
+    pub const fn below(self, high: kernel_sys::vm_paddr_t) -> Self {
+        ContigConstraints { high, ..self }
+    }
+
+    /// Starting at a multiple of `alignment`, a power of two
+    pub const fn align(self, alignment: u64) -> Self {
+        ContigConstraints { alignment, ..self }
+    }
+
+    /// Not crossing a multiple of `boundary`, a power of two, or 0 for
+    /// no such limit
+    pub const fn boundary(self, boundary: u64) -> Self {
+        ContigConstraints { boundary, ..self }
+    }
+
+    /// Mapped uncached, for memory the device reads and writes behind
+    /// the CPU's back on platforms without coherent DMA
+    pub const fn uncacheable(self) -> Self {
+        ContigConstraints {
+            memattr: kernel_sys::VM_MEMATTR_UNCACHEABLE as _,
+            ..self
+        }
+    }
+
+    fn check(&self, size: usize) -> Result<(), Errno> {
+        let boundary_fits = self.boundary == 0
+            || (self.boundary.is_power_of_two()
+                && size as u64 <= self.boundary);
+        if self.alignment.is_power_of_two()
+            && boundary_fits
+            && self.low < self.high
+        {
+            Ok(())
+        } else {
+            Err(Errno::Inval)
+        }
+    }
+}
+
+impl Default for ContigConstraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A `T` in wired, physically contiguous memory, from
+/// `kmem_alloc_contig(9)`, with the bus address to hand a device
+///
+/// What the device writes should be read through `as_ptr` with
+/// `read_volatile`, as references assume only Rust changes the memory.
+pub struct ContigBuffer<T> {
+    ptr: ptr::NonNull<T>,
+    phys: kernel_sys::vm_paddr_t,
+}
+
+unsafe impl<T: Send> Send for ContigBuffer<T> {}
+unsafe impl<T: Sync> Sync for ContigBuffer<T> {}
+
+impl<T> ContigBuffer<T> {
+    /// Move `value` into memory placed as `constraints` say, sleeping for
+    /// it or not as `wait` says
+    pub fn new(
+        value: T,
+        constraints: &ContigConstraints,
+        wait: Wait,
+    ) -> Result<Self, Errno> {
+        let size = Self::size();
+        constraints.check(size)?;
+        let alignment = constraints.alignment.max(mem::align_of::<T>() as u64);
+        let flags = match wait {
+            Wait::WaitOk => kernel_sys::M_WAITOK,
+            Wait::NoWait => kernel_sys::M_NOWAIT,
+        };
+        let addr = unsafe {
+            kernel_sys::kmem_alloc_contig(
+                size as kernel_sys::vm_size_t,
+                flags | kernel_sys::M_ZERO,
+                constraints.low,
+                constraints.high,
+                alignment as libc::c_ulong,
+                constraints.boundary,
+                constraints.memattr,
+            )
+        };
+        let ptr = ptr::NonNull::new(addr as *mut T).ok_or(Errno::NoMem)?;
+        unsafe { ptr.as_ptr().write(value) };
+        let phys = unsafe { kernel_sys::pmap_kextract(addr as _) };
+        Ok(ContigBuffer { ptr, phys })
+    }
+
+    fn size() -> usize {
+        mem::size_of::<T>().max(1)
+    }
+
+    /// The physical address the buffer starts at, for the device
+    pub fn phys(&self) -> kernel_sys::vm_paddr_t {
+        self.phys
+    }
+
+    /// The kernel's mapping of the buffer
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    /// Length in bytes; the allocation itself is whole pages
+    pub fn len(&self) -> usize {
+        mem::size_of::<T>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Deref for ContigBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for ContigBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for ContigBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            kernel_sys::kmem_free(
+                self.ptr.as_ptr() as *mut libc::c_void,
+                Self::size() as kernel_sys::vm_size_t,
+            );
+        }
+    }
+}
+
+impl<T> fmt::Debug for ContigBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ContigBuffer {{ addr: {:p}, phys: {:#x}, len: {} }}",
+            self.ptr,
+            self.phys,
+            self.len()
+        )
+    }
+}
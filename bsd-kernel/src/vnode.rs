@@ -0,0 +1,255 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Files opened by the kernel itself, see `vnode(9)` and `VOP_READ(9)`
+//!
+//! A `File` is a regular file the kernel holds open by path, rather than
+//! through a process's descriptor, and reads and writes at explicit
+//! offsets, as `md(4)` backs a disk with an image:
+//! ```ignore
+//! let image = File::open(c"/var/images/disk0", true)?;
+//! let size = image.size()?;
+//! image.read_at(0, &mut label)?;
+//! ```
+//! It is opened with the credentials of the calling thread, which are kept
+//! for its I/O whichever thread does it. Everything here sleeps, so it is
+//! for threads such as an `Executor`'s, not GEOM's `start`.
+
+use crate::arch::curthread;
+use crate::errno::Errno;
+use core::ffi::CStr;
+use core::{fmt, mem, ptr};
+use libc::{c_int, c_void};
+
+/// A regular file held open by the kernel, closed when dropped
+pub struct File {
+    vp: ptr::NonNull<kernel_sys::vnode>,
+    /// The opener's credentials, held for as long as the file
+    cred: ptr::NonNull<kernel_sys::ucred>,
+    /// `FREAD` and `FWRITE`, as `vn_close` wants them back
+    flags: c_int,
+}
+
+// The vnode lock serializes I/O, and nothing else changes after open
+unsafe impl Send for File {}
+unsafe impl Sync for File {}
+
+impl File {
+    /// Open the regular file at `path`, following symlinks, for reading
+    /// and, if `write`, writing. Fails with `Errno::Inval` for anything
+    /// but a regular file
+    pub fn open(path: &CStr, write: bool) -> Result<Self, Errno> {
+        let mut flags = kernel_sys::FREAD as c_int;
+        if write {
+            flags |= kernel_sys::FWRITE as c_int;
+        }
+        // NDINIT(&nd, LOOKUP, FOLLOW, UIO_SYSSPACE, path)
+        let mut nd: kernel_sys::nameidata = unsafe { mem::zeroed() };
+        nd.ni_cnd.cn_nameiop = kernel_sys::LOOKUP as _;
+        nd.ni_cnd.cn_flags = kernel_sys::FOLLOW as _;
+        nd.ni_segflg = kernel_sys::uio_seg_UIO_SYSSPACE;
+        nd.ni_dirp = path.as_ptr();
+        nd.ni_dirfd = kernel_sys::AT_FDCWD;
+        nd.ni_rightsneeded = &raw const kernel_sys::cap_no_rights as _;
+        nd.ni_filecaps.fc_nioctls = -1;
+        nd.ni_debugflags = kernel_sys::NAMEI_DBG_INITED as _;
+        Errno::result(unsafe {
+            kernel_sys::vn_open(&mut nd, &mut flags, 0, ptr::null_mut())
+        })?;
+        // NDFREE_PNBUF(&nd)
+        unsafe {
+            kernel_sys::uma_zfree_arg(
+                kernel_sys::namei_zone,
+                nd.ni_cnd.cn_pnbuf as *mut c_void,
+                ptr::null_mut(),
+            )
+        };
+        // Locked and referenced, which the file takes over
+        let vp = nd.ni_vp;
+        let cred = unsafe { kernel_sys::crhold((*curthread()).td_ucred) };
+        let file = File {
+            vp: ptr::NonNull::new(vp).unwrap(),
+            cred: ptr::NonNull::new(cred).unwrap(),
+            flags,
+        };
+        let regular = unsafe { (*vp).v_type == kernel_sys::vtype_VREG };
+        file.unlock();
+        if !regular {
+            return Err(Errno::Inval);
+        }
+        Ok(file)
+    }
+
+    /// Raw pointer to the underlying vnode
+    pub fn as_ptr(&self) -> *mut kernel_sys::vnode {
+        self.vp.as_ptr()
+    }
+
+    /// Whether the file was opened for writing
+    pub fn is_writable(&self) -> bool {
+        self.flags & kernel_sys::FWRITE as c_int != 0
+    }
+
+    fn lock(&self, flags: c_int) {
+        unsafe {
+            kernel_sys::_vn_lock(
+                self.as_ptr(),
+                flags | kernel_sys::LK_RETRY as c_int,
+                c"vnode.rs".as_ptr(),
+                line!() as c_int,
+            )
+        };
+    }
+
+    fn unlock(&self) {
+        let mut a: kernel_sys::vop_unlock_args = unsafe { mem::zeroed() };
+        a.a_gen.a_desc = &raw mut kernel_sys::vop_unlock_desc;
+        a.a_vp = self.as_ptr();
+        unsafe { kernel_sys::VOP_UNLOCK_AP(&mut a) };
+    }
+
+    /// The file's length in bytes
+    pub fn size(&self) -> Result<u64, Errno> {
+        let mut va: kernel_sys::vattr = unsafe { mem::zeroed() };
+        let mut a: kernel_sys::vop_getattr_args = unsafe { mem::zeroed() };
+        a.a_gen.a_desc = &raw mut kernel_sys::vop_getattr_desc;
+        a.a_vp = self.as_ptr();
+        a.a_vap = &mut va;
+        a.a_cred = self.cred.as_ptr();
+        self.lock(kernel_sys::LK_SHARED as c_int);
+        let res = Errno::result(unsafe { kernel_sys::VOP_GETATTR_AP(&mut a) });
+        self.unlock();
+        res.map(|()| va.va_size)
+    }
+
+    /// Read into `buf` from `offset`, returning how much was read, which
+    /// is short only at the end of the file
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let base = buf.as_mut_ptr();
+        self.rdwr(kernel_sys::uio_rw_UIO_READ, offset, base, buf.len())
+    }
+
+    /// Write all of `data` at `offset`, extending the file if need be.
+    /// Fails with `Errno::BadF` unless opened for writing
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), Errno> {
+        if !self.is_writable() {
+            return Err(Errno::BadF);
+        }
+        let base = data.as_ptr() as *mut u8;
+        let n =
+            self.rdwr(kernel_sys::uio_rw_UIO_WRITE, offset, base, data.len())?;
+        if n < data.len() {
+            return Err(Errno::Io);
+        }
+        Ok(())
+    }
+
+    /// Transfer `len` bytes at `base`, in pieces `vn_rdwr` can count
+    fn rdwr(
+        &self,
+        rw: kernel_sys::uio_rw,
+        offset: u64,
+        base: *mut u8,
+        len: usize,
+    ) -> Result<usize, Errno> {
+        const MAX: usize = 1 << 30;
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(MAX);
+            let off = offset
+                .checked_add(done as u64)
+                .and_then(|o| i64::try_from(o).ok())
+                .ok_or(Errno::FBig)?;
+            let mut resid: isize = 0;
+            Errno::result(unsafe {
+                kernel_sys::vn_rdwr(
+                    rw,
+                    self.as_ptr(),
+                    base.add(done) as *mut c_void,
+                    n as c_int,
+                    off,
+                    kernel_sys::uio_seg_UIO_SYSSPACE,
+                    0,
+                    self.cred.as_ptr(),
+                    ptr::null_mut(),
+                    &mut resid,
+                    curthread(),
+                )
+            })?;
+            let moved = n - resid as usize;
+            done += moved;
+            if moved < n {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Write the file's dirty data and metadata out to its storage, and
+    /// wait for it to get there
+    pub fn sync(&self) -> Result<(), Errno> {
+        let mut mp = ptr::null_mut();
+        Errno::result(unsafe {
+            kernel_sys::vn_start_write(
+                self.as_ptr(),
+                &mut mp,
+                kernel_sys::V_WAIT as c_int,
+            )
+        })?;
+        let mut a: kernel_sys::vop_fsync_args = unsafe { mem::zeroed() };
+        a.a_gen.a_desc = &raw mut kernel_sys::vop_fsync_desc;
+        a.a_vp = self.as_ptr();
+        a.a_waitfor = kernel_sys::MNT_WAIT as c_int;
+        a.a_td = curthread();
+        self.lock(kernel_sys::LK_EXCLUSIVE as c_int);
+        let res = Errno::result(unsafe { kernel_sys::VOP_FSYNC_AP(&mut a) });
+        self.unlock();
+        unsafe { kernel_sys::vn_finished_write(mp) };
+        res
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::vn_close(
+                self.as_ptr(),
+                self.flags,
+                self.cred.as_ptr(),
+                curthread(),
+            );
+            kernel_sys::crfree(self.cred.as_ptr());
+        }
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "File {{ vp: {:?}, writable: {} }}",
+            self.vp.as_ptr(),
+            self.is_writable()
+        )
+    }
+}
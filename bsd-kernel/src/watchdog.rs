@@ -0,0 +1,222 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Hardware watchdogs, see `watchdog(9)`
+//!
+//! A driver for watchdog hardware registers a `Watchdog`, whose closure
+//! `watchdogd(8)`, or the kernel itself while dumping, calls to arm or
+//! pat the timer, and to disarm it:
+//!
+//! ```rust,ignore
+//! let wd = Watchdog::register(move |cmd| match cmd {
+//!     Some(timeout) => regs.arm(timeout.as_duration()).is_ok(),
+//!     None => {
+//!         regs.disarm();
+//!         true
+//!     }
+//! });
+//! ```
+//!
+//! Code that keeps a CPU busy long enough to starve `watchdogd(8)`, such
+//! as writing out a crash dump, pats every registered watchdog instead
+//! with `pat` or `pat_last`.
+
+use crate::errno::Errno;
+use crate::panic::catch_in_module;
+use alloc::boxed::Box;
+use core::ffi::CStr;
+use core::fmt;
+use core::time::Duration;
+use libc::{c_int, c_uint, c_void};
+
+/// A watchdog timeout, which `watchdog(4)` takes as a power of two
+/// nanoseconds, from `2^1` to `2^255`
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timeout(u8);
+
+impl Timeout {
+    /// About a millisecond
+    pub const MS_1: Timeout = Timeout(kernel_sys::WD_TO_1MS as u8);
+    /// About 125 milliseconds
+    pub const MS_125: Timeout = Timeout(kernel_sys::WD_TO_125MS as u8);
+    /// About 250 milliseconds
+    pub const MS_250: Timeout = Timeout(kernel_sys::WD_TO_250MS as u8);
+    /// About half a second
+    pub const MS_500: Timeout = Timeout(kernel_sys::WD_TO_500MS as u8);
+    /// About a second
+    pub const SEC_1: Timeout = Timeout(kernel_sys::WD_TO_1SEC as u8);
+    /// About 2 seconds
+    pub const SEC_2: Timeout = Timeout(kernel_sys::WD_TO_2SEC as u8);
+    /// About 4 seconds
+    pub const SEC_4: Timeout = Timeout(kernel_sys::WD_TO_4SEC as u8);
+    /// About 8 seconds
+    pub const SEC_8: Timeout = Timeout(kernel_sys::WD_TO_8SEC as u8);
+    /// About 16 seconds, `watchdogd(8)`'s default
+    pub const SEC_16: Timeout = Timeout(kernel_sys::WD_TO_16SEC as u8);
+    /// About 32 seconds
+    pub const SEC_32: Timeout = Timeout(kernel_sys::WD_TO_32SEC as u8);
+    /// About 64 seconds
+    pub const SEC_64: Timeout = Timeout(kernel_sys::WD_TO_64SEC as u8);
+    /// About 128 seconds
+    pub const SEC_128: Timeout = Timeout(kernel_sys::WD_TO_128SEC as u8);
+
+    /// The timeout in the `WD_INTERVAL` bits of a command, `None` for
+    /// `WD_TO_NEVER`
+    pub fn from_raw(cmd: c_uint) -> Option<Timeout> {
+        match cmd & kernel_sys::WD_INTERVAL {
+            0 => None,
+            n => Some(Timeout(n as u8)),
+        }
+    }
+
+    pub fn as_raw(self) -> c_uint {
+        c_uint::from(self.0)
+    }
+
+    /// The shortest timeout of at least `d`, `None` if that is longer
+    /// than a `Duration` can hold
+    pub fn from_duration(d: Duration) -> Option<Timeout> {
+        let ns = d.as_nanos().max(1);
+        let shift = ns.next_power_of_two().trailing_zeros();
+        (shift < 64 + 30).then(|| Timeout(shift.max(1) as u8))
+    }
+
+    /// The timeout's length, saturating at `Duration::MAX`
+    pub fn as_duration(self) -> Duration {
+        match 1u128.checked_shl(self.0.into()) {
+            Some(ns) if ns < u128::from(u64::MAX) * 1_000_000_000 => {
+                Duration::new(
+                    (ns / 1_000_000_000) as u64,
+                    (ns % 1_000_000_000) as u32,
+                )
+            }
+            _ => Duration::MAX,
+        }
+    }
+}
+
+impl fmt::Debug for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timeout {{ 2^{}ns }}", self.0)
+    }
+}
+
+type WatchdogFn = Box<dyn Fn(Option<Timeout>) -> bool + Send + Sync>;
+
+const WATCHDOG_LIST: &CStr = c"watchdog_list";
+
+/// A watchdog timer's registration on `watchdog_list`
+///
+/// The closure is called with `Some` timeout to arm the hardware, or
+/// re-arm it if it already is, which pats it. It returns whether the
+/// hardware can time out after that long; when it can't, it should
+/// leave the timer as it was so another watchdog may take the command.
+/// `None` asks for the timer to be disarmed. The closure must not sleep,
+/// as the kernel pats from the dump path with interrupts disabled.
+pub struct Watchdog {
+    tag: kernel_sys::eventhandler_tag,
+    // The kernel holds a pointer to the inner box until deregistered
+    _f: Box<WatchdogFn>,
+}
+
+unsafe impl Send for Watchdog {}
+unsafe impl Sync for Watchdog {}
+
+impl Watchdog {
+    pub fn register<F>(f: F) -> Self
+    where
+        F: Fn(Option<Timeout>) -> bool + Send + Sync + 'static,
+    {
+        let f: Box<WatchdogFn> = Box::new(Box::new(f));
+        let tag = unsafe {
+            kernel_sys::eventhandler_register(
+                core::ptr::null_mut(),
+                WATCHDOG_LIST.as_ptr(),
+                watchdog_handler as *mut c_void,
+                &raw const *f as *mut c_void,
+                kernel_sys::EVENTHANDLER_PRI_ANY,
+            )
+        };
+        Watchdog { tag, _f: f }
+    }
+}
+
+impl Drop for Watchdog {
+    /// Deregisters, leaving the hardware as the closure last set it. A
+    /// driver disarms the timer itself before detaching
+    fn drop(&mut self) {
+        unsafe {
+            let list =
+                kernel_sys::eventhandler_find_list(WATCHDOG_LIST.as_ptr());
+            debug_assert!(!list.is_null());
+            if !list.is_null() {
+                kernel_sys::eventhandler_deregister(list, self.tag);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Watchdog {{ tag: {:?} }}", self.tag)
+    }
+}
+
+unsafe extern "C" fn watchdog_handler(
+    arg: *mut c_void,
+    cmd: c_uint,
+    error: *mut c_int,
+) {
+    let f = unsafe { &*(arg as *const WatchdogFn) };
+    let timeout = Timeout::from_raw(cmd);
+    if let Ok(true) = catch_in_module(|| f(timeout)) {
+        if timeout.is_some() {
+            unsafe { *error = 0 };
+        }
+    }
+}
+
+/// Arm, or pat, every registered watchdog with `timeout`, or disarm them
+/// all with `None`, as `watchdogd(8)` would. Fails with `OpNotSupp` when
+/// no watchdog can time out after that long
+pub fn pat(timeout: Option<Timeout>) -> Result<(), Errno> {
+    let cmd = timeout.map_or(kernel_sys::WD_TO_NEVER, Timeout::as_raw);
+    match unsafe { kernel_sys::wdog_kern_pat(cmd) } {
+        0 => Ok(()),
+        e => Err(Errno::from_raw(e).unwrap_or(Errno::OpNotSupp)),
+    }
+}
+
+/// Pat every registered watchdog with the timeout they were last armed
+/// with, to keep them from firing through long work in the kernel
+pub fn pat_last() -> Result<(), Errno> {
+    match unsafe { kernel_sys::wdog_kern_pat(kernel_sys::WD_LASTVAL) } {
+        0 => Ok(()),
+        e => Err(Errno::from_raw(e).unwrap_or(Errno::OpNotSupp)),
+    }
+}
+
+/// The timeout watchdogs were last armed with, `None` if disarmed
+pub fn last_timeout() -> Option<Timeout> {
+    Timeout::from_raw(unsafe { kernel_sys::wdog_kern_last_timeout() })
+}
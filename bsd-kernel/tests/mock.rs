@@ -0,0 +1,1380 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Host tests against the userspace mock of kernel-sys
+//!
+//! Run with `cargo test -p bsd-kernel --features mock` for the host target,
+//! see the README.
+
+use bsd_kernel::allocator::{
+    KBox, KString, KVec, KernelAllocator, MallocType, Wait,
+};
+use bsd_kernel::character_device::{CDev, CharacterDevice, DeviceFlags};
+use bsd_kernel::checksum::{
+    Crc32c, Fletcher2, Fletcher4, crc32c, fletcher2, fletcher4,
+};
+use bsd_kernel::counter::Counter;
+use bsd_kernel::cred::{CurThread, Privilege};
+use bsd_kernel::devctl::{Event, EventBuf};
+use bsd_kernel::errno::Errno;
+use bsd_kernel::export::{Api, ApiHeader, import};
+use bsd_kernel::hid::descriptor::{Kind, ReportDescriptor, Usage};
+use bsd_kernel::io::{
+    self, FmtBuf, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write,
+};
+use bsd_kernel::ioctl::{Compat32, IoctlEnum, IoctlRequest, Payload};
+use bsd_kernel::kernel_sys::mock::cred::{self, Cred};
+use bsd_kernel::kernel_sys::mock::{dev, devctl, sysctl, uiomove::MockUio};
+use bsd_kernel::kstr::KernelStr;
+use bsd_kernel::log::{self, LINE_MAX, LINES, LogDevice, Reader};
+use bsd_kernel::module::{Abi, BUILT_FOR, SharedModule, check_abi};
+use bsd_kernel::random;
+use bsd_kernel::refcount::{KArc, Refcount};
+use bsd_kernel::sbuf::Sbuf;
+use bsd_kernel::sync::{
+    self, Condvar, Epoch, EpochBox, EpochCell, Lazy, Mutex, Once, OnceLock,
+    RwLock, SpinMutex, SxLock, sync_channel,
+};
+use bsd_kernel::sysctl::{Context as Sysctls, Format, Node};
+use bsd_kernel::syslog::{LOG_ERR, LOG_WARNING, Priority, RateLimit};
+use bsd_kernel::time::{self, Instant, SystemTime};
+use bsd_kernel::uio::{Offsets, UioReader, UioWriter};
+use bsd_kernel::uma::{UmaZone, ZoneItem};
+use bsd_kernel::unr::{Unit, UnitAllocator};
+use bsd_kernel::usb::descriptor::{
+    Configuration, Endpoint, Gadget, Interface, Speed, class, kind,
+};
+use bsd_kernel::user::{UserCStr, UserPtr, UserSlice};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn uio_writer_honours_offset() {
+    let mut buf = [0u8; 8];
+    let mut uio = MockUio::read(&mut buf, 2);
+    let n = UioWriter::new(uio.as_ptr()).write(b"hello").unwrap();
+    assert_eq!(n, 3);
+    assert_eq!(uio.offset(), 5);
+    assert_eq!(uio.resid(), 5);
+    assert_eq!(&buf[..3], b"llo");
+}
+
+#[test]
+fn uio_stream_ignores_offset() {
+    let mut buf = [0u8; 8];
+    let mut uio = MockUio::read(&mut buf, 2);
+    let mut w = UioWriter::with_offsets(uio.as_ptr(), Offsets::Stream);
+    assert_eq!(w.write(b"hello").unwrap(), 5);
+    assert_eq!(uio.offset(), 7);
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test]
+fn uio_write_at_serves_region() {
+    let mut buf = [0u8; 8];
+    let mut uio = MockUio::read(&mut buf, 6);
+    let mut w = UioWriter::new(uio.as_ptr());
+    assert_eq!(w.write_at(b"abcd", 0).unwrap(), 0);
+    assert_eq!(w.write_at(b"efgh", 4).unwrap(), 2);
+    assert_eq!(w.write_at(b"ijkl", 8).unwrap(), 4);
+    assert_eq!(uio.offset(), 12);
+    assert_eq!(&buf[..6], b"ghijkl");
+}
+
+#[test]
+fn uio_seek_vectored_and_objects() {
+    let mut buf = [0u8; 8];
+    let mut uio = MockUio::read(&mut buf, 0);
+    let mut w = UioWriter::new(uio.as_ptr());
+    assert_eq!(w.seek(SeekFrom::Start(3)).unwrap(), 3);
+    let regions = [IoSlice::new(b"abcd"), IoSlice::new(b"efgh")];
+    assert_eq!(w.write_vectored(&regions).unwrap(), 5);
+    assert!(w.seek(SeekFrom::Current(-9)).is_err());
+    assert_eq!(&buf[..5], b"defgh");
+
+    let data = [1u8, 0, 0, 0, 2, 0, 0, 0, 9];
+    let mut uio = MockUio::write(&data, 0);
+    let mut r = UioReader::with_offsets(uio.as_ptr(), Offsets::Stream);
+    assert_eq!(r.read_obj::<[u32; 2]>().unwrap(), [1, 2]);
+    assert_eq!(r.read_obj::<u32>(), Err(Errno::Inval));
+    assert_eq!(
+        r.seek(SeekFrom::Start(0)).unwrap_err().errno(),
+        Some(Errno::SPipe)
+    );
+    let (mut a, mut b) = ([0u8; 0], [0u8; 4]);
+    let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+    assert_eq!(r.read_vectored(&mut bufs).unwrap(), 1);
+    assert_eq!(b[0], 9);
+}
+
+#[test]
+fn uio_reader_short_request() {
+    let data = b"from";
+    let mut uio = MockUio::write(data, 0);
+    let mut buf = [0u8; 16];
+    let n = UioReader::new(uio.as_ptr()).read(&mut buf).unwrap();
+    assert_eq!(n, 4);
+    assert_eq!(&buf[..4], b"from");
+}
+
+#[test]
+fn nowait_allocations_fail_instead_of_aborting() {
+    use std::fmt::Write as _;
+
+    static M_TEST: MallocType = MallocType::new(c"test");
+    let b = KBox::try_new_in([7u8; 64], &M_TEST).unwrap();
+    assert_eq!(KBox::into_inner(b)[63], 7);
+    let mut v = KVec::try_with_capacity(2).unwrap();
+    v.try_push(1u64).unwrap();
+    v.try_push(2).unwrap();
+    v.try_insert(0, 0).unwrap();
+    assert_eq!(v.as_slice(), &[0, 1, 2]);
+    assert_eq!((v.remove(1), v.pop()), (1, Some(2)));
+    assert!(KVec::<u8>::try_with_capacity(isize::MAX as usize / 2).is_err());
+    assert_eq!(M_TEST.name(), c"test");
+
+    let alloc = KernelAllocator::with_type(&M_TEST).wait(Wait::NoWait);
+    let mut v = KVec::new_with(alloc);
+    v.try_extend_from_slice(b"sect").unwrap();
+    let err = v.try_reserve(isize::MAX as usize).map_err(Errno::from);
+    assert_eq!((v.as_slice(), err), (&b"sect"[..], Err(Errno::NoMem)));
+
+    let mut s = KString::try_from_str("rmd").unwrap();
+    write!(s, "{}", 7).unwrap();
+    s.try_push('\u{e9}').unwrap();
+    assert_eq!((s.as_str(), s.len()), ("rmd7\u{e9}", 6));
+    assert!(s.try_reserve(isize::MAX as usize).is_err());
+    assert_eq!(format!("{:?}", s), "\"rmd7\u{e9}\"");
+}
+
+#[derive(Default)]
+struct Ctx {
+    built: u32,
+    handed_out: u32,
+    scratch: Vec<u8>,
+}
+
+impl ZoneItem for Ctx {
+    fn ctor(&mut self) -> Result<(), Errno> {
+        self.handed_out += 1;
+        Ok(())
+    }
+
+    fn dtor(&mut self) {
+        self.scratch.clear();
+    }
+}
+
+#[test]
+fn uma_zone_reuses_constructed_items() {
+    let zone = UmaZone::<Ctx>::new(c"test ctx").unwrap();
+    assert_eq!(zone.set_max(1), 1);
+    let mut ctx = zone.alloc(Wait::NoWait).unwrap();
+    ctx.built += 1;
+    ctx.scratch.extend_from_slice(b"bio");
+    assert!(zone.alloc(Wait::NoWait).is_err());
+    assert_eq!(zone.count(), 1);
+    drop(ctx);
+    let ctx = zone.alloc(Wait::NoWait).unwrap();
+    assert_eq!((ctx.built, ctx.handed_out), (1, 2));
+    assert!(ctx.scratch.is_empty());
+}
+
+#[test]
+fn mutex_with_type_keeps_instance_name() {
+    let m = Mutex::with_type(c"rustfifo0", c"rustfifo", 1u32);
+    assert_eq!(m.name(), c"rustfifo0");
+    *m.lock() += 1;
+    assert_eq!(m.into_inner(), 2);
+    let s = SpinMutex::with_type(c"rustfifo0 intr", c"rustfifo intr", ());
+    assert_eq!(s.name(), c"rustfifo0 intr");
+}
+
+#[test]
+fn rwlock_shares_readers_and_excludes_writers() {
+    let lock = RwLock::new(c"test", 1u32);
+    let r1 = lock.read();
+    let r2 = lock.try_read().unwrap();
+    assert!(lock.try_write().is_none());
+    assert_eq!(*r1 + *r2, 2);
+    drop((r1, r2));
+    let mut w = lock.write();
+    *w = 5;
+    assert!(lock.try_read().is_none());
+    let r = w.downgrade();
+    assert!(lock.try_write().is_none());
+    assert_eq!(*lock.try_read().unwrap(), 5);
+    drop(r);
+    assert_eq!(lock.into_inner(), 5);
+}
+
+#[test]
+fn sx_lock_between_threads() {
+    let lock = Arc::new(SxLock::new(c"test", Vec::new()));
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let lock = lock.clone();
+            thread::spawn(move || lock.write_sig().unwrap().push(i))
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    let mut got = lock.read().clone();
+    got.sort();
+    assert_eq!(got, [0, 1, 2, 3]);
+    let w = lock.try_write().unwrap();
+    assert!(lock.try_read().is_none());
+    let r = w.downgrade();
+    assert!(lock.try_read().is_some());
+    drop(r);
+    assert_eq!(lock.name(), c"test");
+}
+
+#[test]
+fn epoch_cell_drops_replaced_values_after_readers() {
+    struct Counted(u32, Arc<AtomicUsize>);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    let drops = Arc::new(AtomicUsize::new(0));
+    let epoch = Epoch::new(c"test").unwrap();
+    let cell = EpochCell::new(&epoch, Counted(1, drops.clone()));
+    epoch.read(|g| {
+        assert!(epoch.in_section());
+        let old = cell.get(g);
+        cell.replace(Counted(2, drops.clone()));
+        assert_eq!(old.0, 1);
+        assert_eq!(cell.get(g).0, 2);
+    });
+    assert!(!epoch.in_section());
+    epoch.drain();
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+    epoch.retire(EpochBox::new(Counted(3, drops.clone())));
+    drop(cell);
+    assert_eq!(drops.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn refcount_saturates_instead_of_wrapping() {
+    let r = Refcount::new(1);
+    r.acquire();
+    assert!(r.release_if_not_last());
+    assert!(!r.release_if_not_last());
+    assert!(r.release());
+    assert!(!r.acquire_if_not_zero());
+    assert!(!r.release());
+    assert!(r.is_saturated());
+    r.acquire();
+    assert!(!r.release());
+    assert!(r.is_saturated());
+}
+
+#[test]
+fn karc_drops_value_with_last_reference() {
+    let drops = Arc::new(AtomicUsize::new(0));
+    struct Counted(Arc<AtomicUsize>);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    let mut a = KArc::new(Counted(drops.clone()));
+    assert!(KArc::get_mut(&mut a).is_some());
+    let raw = KArc::into_raw(a.clone());
+    assert_eq!(KArc::count(&a), 2);
+    assert!(KArc::get_mut(&mut a).is_none());
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let b = a.clone();
+            thread::spawn(move || drop(b))
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    let b = unsafe { KArc::from_raw(raw) };
+    assert!(KArc::ptr_eq(&a, &b));
+    let Err(a) = KArc::try_unwrap(a) else {
+        panic!("unwrapped a shared KArc");
+    };
+    drop(b);
+    assert_eq!(drops.load(Ordering::Relaxed), 0);
+    let value = KArc::try_unwrap(a).ok().unwrap();
+    drop(value);
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn condvar_wakes_waiter() {
+    let pair = Arc::new((Mutex::new(c"test", false), Condvar::new(c"test")));
+    let other = pair.clone();
+    let t = thread::spawn(move || {
+        *other.0.lock() = true;
+        other.1.notify_one();
+    });
+    let (lock, cv) = &*pair;
+    let guard = cv.wait_while(lock.lock(), |ready| !*ready);
+    assert!(*guard);
+    drop(guard);
+    t.join().unwrap();
+}
+
+#[test]
+fn sleep_times_out_and_drops_interlock() {
+    let chan = 0u8;
+    let timeout = Some(Duration::from_millis(1));
+    assert_eq!(sync::sleep(&chan, c"test", timeout), Err(Errno::Again));
+    let lock = Arc::new(Mutex::new(c"test", 0));
+    let other = lock.clone();
+    let guard = lock.lock();
+    let t = thread::spawn(move || *other.lock() += 1);
+    let mut guard = Some(guard);
+    while *guard.as_deref().unwrap() == 0 {
+        let (g, res) =
+            sync::sleep_with(&*lock, guard.take().unwrap(), c"test", timeout);
+        assert_eq!(res, Err(Errno::Again));
+        guard = Some(g);
+    }
+    sync::wakeup(&*lock);
+    drop(guard);
+    t.join().unwrap();
+}
+
+#[test]
+fn sync_channel_between_threads() {
+    let (tx, rx) = sync_channel(1);
+    let t = thread::spawn(move || {
+        for i in 0..16 {
+            tx.send(i).unwrap();
+        }
+    });
+    let got: Vec<i32> = rx.iter().collect();
+    assert_eq!(got, (0..16).collect::<Vec<_>>());
+    t.join().unwrap();
+    assert!(rx.recv_timeout(Duration::from_millis(1)).is_err());
+}
+
+#[test]
+fn zero_timeouts_do_not_sleep() {
+    let (tx, rx) = sync::channel();
+    assert_eq!(
+        rx.recv_timeout(Duration::ZERO),
+        Err(sync::channel::RecvTimeoutError::Timeout)
+    );
+    tx.try_send(7).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::ZERO), Ok(7));
+
+    let lock = Mutex::new(c"test", ());
+    let cv = Condvar::new(c"test");
+    let (guard, res) = cv.wait_timeout(lock.lock(), Duration::ZERO);
+    assert!(res.timed_out());
+    let (_guard, res) = cv.wait_timeout(guard, Duration::from_nanos(1));
+    assert!(res.timed_out());
+}
+
+#[derive(Default)]
+struct Echo {
+    data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, IoctlEnum)]
+#[ioctl(group = b'e')]
+enum EchoIoctl {
+    #[ioctl(io = 1)]
+    Clear,
+    #[ioctl(ior = 2)]
+    Len(u32),
+    #[ioctl(iowr = 3)]
+    Xor([u8; 4]),
+    #[ioctl(ior = 4, compat32)]
+    Stat(EchoStat),
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Compat32)]
+struct EchoStat {
+    id: u32,
+    len: usize,
+    sum: u64,
+}
+
+unsafe impl Payload for EchoStat {}
+
+impl CharacterDevice for Echo {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &mut IoctlRequest,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let mut cmd = req.decode::<EchoIoctl>()?;
+        match cmd {
+            EchoIoctl::Clear => self.data.clear(),
+            EchoIoctl::Len(ref mut len) => *len = self.data.len() as u32,
+            EchoIoctl::Xor(ref mut key) => {
+                for (i, b) in self.data.iter_mut().enumerate() {
+                    *b ^= key[i % 4];
+                }
+                key.reverse();
+            }
+            EchoIoctl::Stat(ref mut stat) => {
+                *stat = EchoStat {
+                    id: 0xec,
+                    len: self.data.len(),
+                    sum: self.data.iter().map(|&b| b as u64).sum(),
+                }
+            }
+        }
+        req.reply(&cmd);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let _ = uio.write(&self.data);
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let mut buf = vec![0u8; uio.residual() as usize];
+        if let Ok(n) = uio.read(&mut buf) {
+            self.data = buf[..n].to_vec();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn character_device_echo() {
+    let cdev =
+        CDev::new_with_delegate("mockecho", SharedModule::new(Echo::default()))
+            .unwrap();
+    assert!(dev::exists("mockecho"));
+    dev::open("mockecho", 0).unwrap();
+    assert_eq!(dev::write("mockecho", b"hi rust", 0, 0), Ok(7));
+    let mut buf = [0u8; 16];
+    assert_eq!(dev::read("mockecho", &mut buf, 0, 0), Ok(7));
+    assert_eq!(&buf[..7], b"hi rust");
+    assert_eq!(dev::read("mockecho", &mut buf, 3, 0), Ok(4));
+    assert_eq!(&buf[..4], b"rust");
+    dev::close("mockecho", 0).unwrap();
+    drop(cdev);
+    assert!(!dev::exists("mockecho"));
+}
+
+#[test]
+fn character_device_counts_operations() {
+    let cdev = CDev::new_with_delegate(
+        "mockstats",
+        SharedModule::new(Echo::default()),
+    )
+    .unwrap();
+    dev::open("mockstats", 0).unwrap();
+    assert_eq!(dev::write("mockstats", b"hi rust", 0, 0), Ok(7));
+    let mut buf = [0u8; 16];
+    assert_eq!(dev::read("mockstats", &mut buf, 0, 0), Ok(7));
+    assert_eq!(dev::read("mockstats", &mut buf, 3, 0), Ok(4));
+    let err = dev::ioctl("mockstats", 0, &mut []);
+    assert_eq!(err, Err(Errno::NotTy.as_raw()));
+    dev::close("mockstats", 0).unwrap();
+    let stats = cdev.stats();
+    assert_eq!(stats.opens.fetch(), 1);
+    assert_eq!(stats.writes.fetch(), 1);
+    assert_eq!(stats.bytes_written.fetch(), 7);
+    assert_eq!(stats.reads.fetch(), 2);
+    assert_eq!(stats.bytes_read.fetch(), 11);
+    assert_eq!(stats.ioctls.fetch(), 1);
+    assert_eq!(stats.errors.fetch(), 1);
+    assert_eq!(stats.in_flight.fetch(), 0);
+    drop(cdev);
+}
+
+#[test]
+fn character_device_ioctl() {
+    assert_eq!(EchoIoctl::CLEAR, 0x2000_6501);
+    assert_eq!(EchoIoctl::LEN, 0x4004_6502);
+    assert_eq!(EchoIoctl::XOR, 0xc004_6503);
+    assert_eq!(EchoIoctl::Len(0).cmd(), EchoIoctl::LEN);
+    let cdev = CDev::new_with_delegate(
+        "mockioctl",
+        SharedModule::new(Echo::default()),
+    )
+    .unwrap();
+    assert_eq!(dev::write("mockioctl", b"abcd", 0, 0), Ok(4));
+    let mut len = [0xffu8; 4];
+    dev::ioctl("mockioctl", EchoIoctl::LEN, &mut len).unwrap();
+    assert_eq!(u32::from_ne_bytes(len), 4);
+    let mut key = [0, 1, 2, 3];
+    dev::ioctl("mockioctl", EchoIoctl::XOR, &mut key).unwrap();
+    assert_eq!(key, [3, 2, 1, 0]);
+    let mut buf = [0u8; 4];
+    assert_eq!(dev::read("mockioctl", &mut buf, 0, 0), Ok(4));
+    assert_eq!(&buf, b"acag");
+    dev::ioctl("mockioctl", EchoIoctl::CLEAR, &mut []).unwrap();
+    dev::ioctl("mockioctl", EchoIoctl::LEN, &mut len).unwrap();
+    assert_eq!(u32::from_ne_bytes(len), 0);
+    // The size is part of the command, so a mismatched one is unknown
+    let wrong = bsd_kernel::ioctl::ior(b'e', 2, 8);
+    let err = dev::ioctl("mockioctl", wrong, &mut [0; 8]);
+    assert_eq!(err, Err(Errno::NotTy.as_raw()));
+    drop(cdev);
+}
+
+#[test]
+fn character_device_ioctl_compat32() {
+    assert_eq!(size_of::<EchoStat>(), 24);
+    assert_eq!(size_of::<EchoStat32>(), 16);
+    assert_eq!(EchoIoctl::STAT, 0x4018_6504);
+    assert_eq!(EchoIoctl::STAT32, 0x4010_6504);
+    let stat = EchoStat {
+        id: 1,
+        len: 2,
+        sum: 3,
+    };
+    assert_eq!(EchoStat::from_abi32(stat.to_abi32()), stat);
+    let cdev = CDev::new_with_delegate(
+        "mockioctl32",
+        SharedModule::new(Echo::default()),
+    )
+    .unwrap();
+    assert_eq!(dev::write("mockioctl32", b"abc", 0, 0), Ok(3));
+    let mut out = [0xffu8; 16];
+    dev::ioctl32("mockioctl32", EchoIoctl::STAT32, &mut out).unwrap();
+    assert_eq!(out[..4], 0xecu32.to_ne_bytes());
+    assert_eq!(out[4..8], 3u32.to_ne_bytes());
+    assert_eq!(out[8..], (97u64 + 98 + 99).to_ne_bytes());
+    // Only 32-bit callers get the 32-bit layout
+    let err = dev::ioctl("mockioctl32", EchoIoctl::STAT32, &mut out);
+    assert_eq!(err, Err(Errno::NotTy.as_raw()));
+    let mut out = [0u8; 24];
+    dev::ioctl("mockioctl32", EchoIoctl::STAT, &mut out).unwrap();
+    assert_eq!(out[8..16], 3usize.to_ne_bytes());
+    // Commands not marked compat32 are shared
+    let mut len = [0u8; 4];
+    dev::ioctl32("mockioctl32", EchoIoctl::LEN, &mut len).unwrap();
+    assert_eq!(u32::from_ne_bytes(len), 3);
+    drop(cdev);
+}
+
+/// Counts calls to `close`, tracking every close if `TRACK`
+struct Closes<const TRACK: bool>(Arc<AtomicUsize>);
+
+impl<const TRACK: bool> CharacterDevice for Closes<TRACK> {
+    const FLAGS: DeviceFlags = if TRACK {
+        DeviceFlags::TRACK_CLOSE
+    } else {
+        DeviceFlags::NONE
+    };
+
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        _uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+#[test]
+fn character_device_track_close() {
+    let last = Arc::new(AtomicUsize::new(0));
+    let every = Arc::new(AtomicUsize::new(0));
+    let m = SharedModule::new(Closes::<false>(last.clone()));
+    let last_dev = CDev::new_with_delegate("mocklastclose", m).unwrap();
+    let m = SharedModule::new(Closes::<true>(every.clone()));
+    let every_dev = CDev::new_with_delegate("mocktrackclose", m).unwrap();
+    for name in ["mocklastclose", "mocktrackclose"] {
+        dev::open(name, 0).unwrap();
+        dev::open(name, 0).unwrap();
+        dev::close(name, 0).unwrap();
+        dev::close(name, 0).unwrap();
+    }
+    assert_eq!(last.load(Ordering::Relaxed), 1);
+    assert_eq!(every.load(Ordering::Relaxed), 2);
+    drop(last_dev);
+    drop(every_dev);
+}
+
+/// A device only one descriptor may have open at a time
+#[derive(Default)]
+struct Exclusive(bool);
+
+impl CharacterDevice for Exclusive {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        if self.0 {
+            return Err(Errno::Busy);
+        }
+        self.0 = true;
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        self.0 = false;
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        _uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+#[test]
+fn character_device_open_error_reaches_caller() {
+    let m = SharedModule::new(Exclusive::default());
+    let cdev = CDev::new_with_delegate("mockexcl", m).unwrap();
+    dev::open("mockexcl", 0).unwrap();
+    assert_eq!(dev::open("mockexcl", 0), Err(Errno::Busy.as_raw()));
+    drop(cdev);
+}
+
+/// A device any user may read, but only root outside a jail may write,
+/// recording who last opened it
+#[derive(Default)]
+struct Guarded {
+    opener: Option<(u32, bool)>,
+}
+
+impl CharacterDevice for Guarded {
+    fn open(&mut self, td: &CurThread) -> Result<(), Errno> {
+        self.opener = Some((td.cred().uid(), td.cred().is_jailed()));
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        _uio: &mut UioReader,
+        td: &CurThread,
+    ) -> Result<(), Errno> {
+        td.priv_check(Privilege::DRIVER)
+    }
+}
+
+#[test]
+fn character_device_checks_caller_privilege() {
+    let m = SharedModule::new(Guarded::default());
+    let cdev = CDev::new_with_delegate("mockguard", m.clone()).unwrap();
+    let user = Cred {
+        uid: 1001,
+        gid: 1001,
+        jailed: false,
+    };
+    let jailed_root = Cred {
+        jailed: true,
+        ..Cred::ROOT
+    };
+    for (cred, allowed) in
+        [(Cred::ROOT, true), (user, false), (jailed_root, false)]
+    {
+        cred::set_cred(cred);
+        dev::open("mockguard", 0).unwrap();
+        assert_eq!(m.lock().unwrap().opener, Some((cred.uid, cred.jailed)));
+        assert_eq!(dev::read("mockguard", &mut [0; 4], 0, 0), Ok(0));
+        let wrote = dev::write("mockguard", b"hi", 0, 0);
+        assert_eq!(wrote.is_ok(), allowed);
+        if !allowed {
+            assert_eq!(wrote, Err(Errno::Perm.as_raw()));
+        }
+        dev::close("mockguard", 0).unwrap();
+    }
+    cred::set_cred(Cred::ROOT);
+    drop(cdev);
+}
+
+/// A four-byte pipe, to exercise blocking writes
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    writer: Option<Waker>,
+}
+
+impl CharacterDevice for Pipe {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.buf.len() < 4 {
+            Poll::Ready(())
+        } else {
+            self.writer = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let n = uio.write_stream(self.buf.make_contiguous()).unwrap_or(0);
+        self.buf.drain(..n);
+        if let Some(w) = self.writer.take() {
+            w.wake();
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let mut buf = vec![0u8; 4 - self.buf.len()];
+        if let Ok(n) = uio.read_stream(&mut buf) {
+            self.buf.extend(&buf[..n]);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn character_device_blocking_write() {
+    use bsd_kernel::kernel_sys::{EAGAIN, O_NONBLOCK, POLLOUT};
+
+    let cdev =
+        CDev::new_with_delegate("mockpipe", SharedModule::new(Pipe::default()))
+            .unwrap();
+    assert_eq!(dev::write("mockpipe", b"abcdef", 0, O_NONBLOCK), Ok(4));
+    assert_eq!(dev::poll("mockpipe", POLLOUT), Ok(0));
+    assert_eq!(dev::write("mockpipe", b"x", 0, O_NONBLOCK), Err(EAGAIN));
+
+    let writer = thread::spawn(|| dev::write("mockpipe", b"ghijkl", 0, 0));
+    let mut got = Vec::new();
+    let mut buf = [0u8; 16];
+    while got.len() < 10 {
+        let n = dev::read("mockpipe", &mut buf, 0, 0).unwrap();
+        got.extend_from_slice(&buf[..n]);
+        thread::yield_now();
+    }
+    assert_eq!(writer.join().unwrap(), Ok(6));
+    assert_eq!(got, b"abcdghijkl");
+    assert_eq!(dev::poll("mockpipe", POLLOUT), Ok(POLLOUT));
+    drop(cdev);
+}
+
+#[test]
+fn checksums_match_reference_values() {
+    assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    let mut crc = Crc32c::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.finish(), 0xe306_9283);
+
+    let words: Vec<u8> = [1u32, 2, 3, 4]
+        .iter()
+        .flat_map(|w| w.to_ne_bytes())
+        .collect();
+    assert_eq!(fletcher4(&words), [10, 20, 35, 56]);
+
+    // Split off word boundaries, the sums are those of the whole
+    let data: Vec<u8> = (0..=255u8).cycle().take(203).collect();
+    for split in [1, 3, 7, 16, 17, 100] {
+        let (head, tail) = data.split_at(split);
+        let mut sum = Fletcher2::new();
+        sum.update(head);
+        sum.update(tail);
+        assert_eq!(sum.finish(), fletcher2(&data));
+        let mut sum = Fletcher4::new();
+        for piece in [head, &tail[..tail.len() / 2], &tail[tail.len() / 2..]] {
+            sum.update(piece);
+        }
+        assert_eq!(sum.finish(), fletcher4(&data));
+    }
+    // A partial last word is padded out with zeros
+    let mut padded = data.clone();
+    padded.resize(204, 0);
+    assert_eq!(fletcher4(&data), fletcher4(&padded));
+    padded.resize(208, 0);
+    assert_eq!(fletcher2(&data), fletcher2(&padded));
+}
+
+#[test]
+fn random_fills_whole_buffers() {
+    let (mut a, mut b) = ([0u8; 64], [0u8; 64]);
+    random::fill_bytes(&mut a);
+    random::fill_bytes(&mut b);
+    assert_ne!(a, b);
+    assert!(random::is_seeded());
+    assert_eq!(random::read_random(&mut a), a.len());
+    assert_ne!(random::u64(), random::u64());
+}
+
+#[test]
+fn devctl_event_quotes_values() {
+    Event::new("RUST", "fifo", "OVERFLOW")
+        .with("cdev", "rustfifo")
+        .with("bytes", 42)
+        .with("reason", r#"ring "full""#)
+        .notify();
+    Event::new("RUST", "fifo", "CREATE").notify();
+    // The last pair doesn't fit, and goes whole
+    EventBuf::<32>::new(c"RUST", c"rmd", c"ERROR")
+        .with("error", "EIO")
+        .with("note", "")
+        .with("offset", 1u64 << 40)
+        .notify();
+    assert!(bsd_kernel::devctl::is_listening());
+    assert_eq!(
+        devctl::take_events(),
+        [
+            r#"!system=RUST subsystem=fifo type=OVERFLOW cdev=rustfifo bytes=42 reason="ring \"full\"""#,
+            "!system=RUST subsystem=fifo type=CREATE",
+            r#"!system=RUST subsystem=rmd type=ERROR error=EIO note="""#,
+        ]
+    );
+}
+
+#[test]
+fn kernel_str_copies_into_name_fields() {
+    const NAME: &KernelStr = KernelStr::from_cstr(c"rustfifo");
+    let mut buf = [0x7f; 16];
+    NAME.copy_to(&mut buf).unwrap();
+    assert_eq!(KernelStr::from_buf(&buf), Some(NAME));
+    assert!(buf[8..].iter().all(|&c| c == 0));
+    let mut short = [0; 8];
+    assert_eq!(NAME.copy_to(&mut short), Err(Errno::NameTooLong));
+    assert_eq!(short, [0; 8]);
+
+    let odd = KernelStr::from_bytes_with_nul(b"caf\xc3\xa9 \xff\0").unwrap();
+    assert_eq!(odd.to_string(), "caf\u{e9} \u{fffd}");
+    assert_eq!(bsd_kernel::cstr!("rust"), c"rust");
+}
+
+#[test]
+fn fmt_buf_truncates_at_char_boundary() {
+    use std::fmt::Write as _;
+    let mut buf = FmtBuf::<8>::new();
+    assert!(write!(buf, "{}", 42).is_ok());
+    assert!(write!(buf, "caf\u{e9}s").is_err());
+    assert_eq!(buf.as_str(), "42caf\u{e9}");
+    assert_eq!(buf.as_cstr(), c"42caf\u{e9}");
+}
+
+#[test]
+fn io_error_carries_errno() {
+    let e = io::Error::from_errno(Errno::Fault, "uiomove failed");
+    assert_eq!(e.to_string(), "Other: uiomove failed: Bad address (14)");
+    assert_eq!(Errno::from(e), Errno::Fault);
+    let e = io::Error::new(io::ErrorKind::WouldBlock, "empty");
+    assert_eq!(e.errno(), None);
+    assert_eq!(Errno::from(e), Errno::Again);
+}
+
+#[test]
+fn lazy_initializes_once_across_threads() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static VALUE: Lazy<Vec<u32>> = Lazy::new(|| {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(20));
+        vec![1, 2, 3]
+    });
+    let threads: Vec<_> = (0..4)
+        .map(|_| thread::spawn(|| VALUE.iter().sum::<u32>()))
+        .collect();
+    for t in threads {
+        assert_eq!(t.join().unwrap(), 6);
+    }
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+
+    let cell = OnceLock::new();
+    assert_eq!(cell.get(), None);
+    assert_eq!(cell.set(1), Ok(()));
+    assert_eq!(cell.set(2), Err(2));
+    assert_eq!(cell.get_or_init(|| 3), &1);
+
+    let once = Once::new();
+    let panicked =
+        std::panic::catch_unwind(|| once.call_once(|| panic!("init failed")));
+    assert!(panicked.is_err());
+    assert!(!once.is_completed());
+    assert!(std::panic::catch_unwind(|| once.call_once(|| ())).is_err());
+}
+
+#[test]
+fn spin_mutex_excludes_threads() {
+    let counter = Arc::new(SpinMutex::new(c"counter", 0u32));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    *counter.lock() += 1;
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(*counter.lock(), 4000);
+    let guard = counter.lock();
+    assert!(counter.try_lock().is_none());
+    drop(guard);
+}
+
+#[test]
+fn module_abi_accepts_versions() {
+    assert!(check_abi(Abi::Exact).is_ok());
+    assert!(!Abi::Exact.accepts(BUILT_FOR + 1));
+    assert!(Abi::Branch.accepts(BUILT_FOR + 1));
+    assert!(!Abi::Branch.accepts(BUILT_FOR - 1));
+    assert!(!Abi::Branch.accepts(BUILT_FOR + 100_000));
+    assert!(Abi::Range(BUILT_FOR - 10, BUILT_FOR + 10).accepts(BUILT_FOR));
+    assert!(!Abi::Range(0, BUILT_FOR - 1).accepts(BUILT_FOR));
+    assert_eq!(check_abi(Abi::Range(0, BUILT_FOR - 1)), Err(Errno::NoExec));
+}
+
+#[test]
+fn log_keeps_newest_lines() {
+    // The only test writing to the log, which the whole binary shares
+    for i in 0..LINES + 10 {
+        bsd_kernel::log!("line {}", i);
+    }
+    let mut reader = Reader::oldest();
+    let mut buf = [0u8; LINE_MAX];
+    let len = reader.next_line(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"line 10");
+    let mut count = 1;
+    while reader.next_line(&mut buf).is_some() {
+        count += 1;
+    }
+    assert_eq!(count, LINES);
+    log::write_line(&[b'x'; LINE_MAX + 8]);
+    assert_eq!(reader.next_line(&mut buf), Some(LINE_MAX));
+
+    let cdev =
+        CDev::new_with_delegate("mocklog", SharedModule::new(LogDevice::new()))
+            .unwrap();
+    dev::open("mocklog", 0).unwrap();
+    let mut out = vec![0u8; 64 * 1024];
+    let n = dev::read("mocklog", &mut out, 0, 0).unwrap();
+    let text = String::from_utf8(out[..n].to_vec()).unwrap();
+    assert!(text.starts_with("line 11\nline 12\n"));
+    assert!(text.ends_with(&format!("{}\n", "x".repeat(LINE_MAX))));
+    assert_eq!(text.lines().count(), LINES);
+    // Caught up, so the next read is end of file
+    assert_eq!(dev::read("mocklog", &mut out, 0, 0), Ok(0));
+    let err = dev::write("mocklog", b"no", 0, 0);
+    assert_eq!(err, Err(Errno::NoDev.as_raw()));
+    dev::close("mocklog", 0).unwrap();
+    drop(cdev);
+
+    let mut ctx = Sysctls::new();
+    ctx.add_proc(
+        Node::debug(),
+        c"mocklog",
+        Format::Text,
+        false,
+        c"Log",
+        log::sysctl_dump,
+    )
+    .unwrap();
+    let dump = sysctl::read("debug.mocklog").unwrap();
+    assert_eq!(dump.strip_suffix(b"\0").unwrap(), text.as_bytes());
+}
+
+#[repr(C)]
+struct CounterApi {
+    header: ApiHeader,
+    get: extern "C" fn() -> u32,
+}
+
+unsafe impl Api for CounterApi {
+    const MAJOR: u16 = 1;
+    const MINOR: u16 = 1;
+}
+
+extern "C" fn get_seven() -> u32 {
+    7
+}
+
+bsd_kernel::export_api! {
+    mock_counter_api: CounterApi = CounterApi {
+        header: ApiHeader::new::<CounterApi>(1, 1),
+        get: get_seven,
+    };
+}
+
+#[test]
+fn export_api_checks_versions() {
+    let api = import(&mock_counter_api).unwrap();
+    assert_eq!((api.get)(), 7);
+    assert!(api.header().supports(1, 0));
+    assert!(!api.header().supports(2, 0));
+
+    static OLDER: CounterApi = CounterApi {
+        header: ApiHeader::new::<CounterApi>(1, 0),
+        get: get_seven,
+    };
+    static NEWER_MAJOR: CounterApi = CounterApi {
+        header: ApiHeader::new::<CounterApi>(2, 0),
+        get: get_seven,
+    };
+    assert_eq!(import(&OLDER).err(), Some(Errno::ProcUnavail));
+    assert_eq!(import(&NEWER_MAJOR).err(), Some(Errno::ProgMismatch));
+}
+
+/// A boot protocol mouse with a wheel, reports numbered 2
+const MOUSE_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x85, 0x02, 0x09, 0x01, 0xa1, 0x00,
+    0x05, 0x09, 0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03,
+    0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81, 0x01, 0x05, 0x01,
+    0x09, 0x30, 0x09, 0x31, 0x09, 0x38, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08,
+    0x95, 0x03, 0x81, 0x06, 0xc0, 0xc0,
+];
+
+#[test]
+fn hid_descriptor_locates_fields() {
+    let rd = ReportDescriptor::parse(MOUSE_DESCRIPTOR).unwrap();
+    assert!(rd.has_report_ids());
+    assert_eq!(rd.report_len(Kind::Input, 2), 5);
+    assert_eq!(rd.max_report_len(Kind::Input), 5);
+    assert_eq!(rd.report_len(Kind::Output, 2), 0);
+
+    let b3 = rd.locate(Usage::button(3), Kind::Input, 0).unwrap();
+    assert_eq!((b3.pos, b3.size), (2, 1));
+    let x = rd.locate(Usage::X, Kind::Input, 0).unwrap();
+    let wheel = rd.locate(Usage::WHEEL, Kind::Input, 0).unwrap();
+    assert_eq!((x.pos, wheel.pos), (8, 24));
+    assert!(x.is_relative() && x.is_signed());
+    assert!(rd.locate(Usage::button(4), Kind::Input, 0).is_none());
+
+    let mut report = [2, 0b101, 0xfe, 3, 0x80];
+    assert_eq!(b3.get(&report), Some(1));
+    assert_eq!(x.value(&report), Some(-2));
+    assert_eq!(wheel.value(&report), Some(-128));
+    assert_eq!(x.get(&[1, 0, 0, 0, 0]), None);
+    assert_eq!(x.get(&report[..2]), None);
+
+    x.set(&mut report, 5).unwrap();
+    b3.set(&mut report, 0).unwrap();
+    assert_eq!(report, [2, 0b001, 5, 3, 0x80]);
+
+    // Truncated item, and a pop without a push
+    assert_eq!(ReportDescriptor::parse(&[0x05]).err(), Some(Errno::Inval));
+    assert_eq!(ReportDescriptor::parse(&[0xb4]).err(), Some(Errno::Inval));
+}
+
+#[test]
+fn usb_gadget_builds_descriptors() {
+    let gadget = Gadget {
+        vendor: 0x1209,
+        product: 0x0001,
+        release: 0x0100,
+        manufacturer: Some("FreeBSD"),
+        product_name: Some("Loop"),
+        configurations: vec![Configuration {
+            max_power: 100,
+            interfaces: vec![Interface {
+                class: class::VENDOR,
+                endpoints: vec![
+                    Endpoint::bulk_in(1),
+                    Endpoint::bulk_out(2),
+                    Endpoint::interrupt_in(3, 8, 10),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let full = gadget.build(Speed::Full).unwrap();
+    let high = gadget.build(Speed::High).unwrap();
+    assert_eq!(&full.device[..4], &[18, kind::DEVICE, 0x10, 0x01]);
+    assert_eq!(&high.device[..4], &[18, kind::DEVICE, 0x00, 0x02]);
+    // Manufacturer and product strings, no serial, one configuration
+    assert_eq!(&full.device[8..], &[0x09, 0x12, 1, 0, 0, 1, 1, 2, 0, 1]);
+
+    let config = high.get(kind::CONFIG, 0).unwrap();
+    assert_eq!(config.len(), 9 + 9 + 3 * 7);
+    assert_eq!(&config[2..4], &(config.len() as u16).to_le_bytes());
+    assert_eq!(config[7..9], [0x80, 50]);
+    // bulk in at 512 bytes, interrupt at the 8 given
+    assert_eq!(&config[18..25], &[7, kind::ENDPOINT, 0x81, 2, 0, 2, 0]);
+    assert_eq!(&config[32..39], &[7, kind::ENDPOINT, 0x83, 3, 8, 0, 10]);
+    assert_eq!(&full.config(0).unwrap()[22..24], &[64, 0]);
+
+    assert_eq!(full.get(kind::STRING, 0), Some(&[4, 3, 0x09, 0x04][..]));
+    assert_eq!(
+        full.get(kind::STRING, 2),
+        Some(&[10, 3, b'L', 0, b'o', 0, b'o', 0, b'p', 0][..])
+    );
+    assert_eq!(full.get(kind::STRING, 3), None);
+    assert_eq!(full.get(kind::CONFIG, 1), None);
+
+    let mut twice = gadget.clone();
+    twice.configurations[0].interfaces[0]
+        .endpoints
+        .push(Endpoint::bulk_in(1));
+    assert_eq!(twice.build(Speed::Full).err(), Some(Errno::Inval));
+    assert_eq!(
+        Gadget::default().build(Speed::Full).err(),
+        Some(Errno::Inval)
+    );
+}
+
+#[test]
+fn unit_allocator_reuses_lowest_free() {
+    let units = UnitAllocator::new(1..=3);
+    let one = units.alloc().unwrap();
+    let two = units.alloc().unwrap();
+    assert_eq!((one.get(), two.get()), (1, 2));
+    let three = units.alloc_specific(3).unwrap().into_raw();
+    assert!(units.alloc().is_none());
+    assert!(units.alloc_specific(2).is_none());
+    assert!(units.alloc_specific(4).is_none());
+    drop(one);
+    assert_eq!(units.alloc().unwrap().get(), 1);
+    drop(unsafe { Unit::from_raw(&units, three) });
+    assert_eq!(
+        units.alloc_specific(3).map(|u| u.to_string()).as_deref(),
+        Some("3")
+    );
+    drop(two);
+}
+
+#[test]
+fn user_copies_check_sizes_and_faults() {
+    let mut word = 7u32;
+    let ptr = UserPtr::<u32>::new(&raw mut word as usize);
+    assert_eq!((ptr.read(), ptr.fetch()), (Ok(7), Ok(7)));
+    ptr.write(&8).unwrap();
+    ptr.store(ptr.fetch().unwrap() + 1).unwrap();
+    assert_eq!(word, 9);
+    assert_eq!(UserPtr::<u64>::new(0).read(), Err(Errno::Fault));
+    assert_eq!(UserPtr::<u32>::new(0).fetch(), Err(Errno::Fault));
+
+    let mut bytes = *b"rust";
+    let slice = UserSlice::new(bytes.as_mut_ptr() as usize, bytes.len());
+    assert_eq!(slice.read_to_vec(4).as_deref(), Ok(&b"rust"[..]));
+    assert_eq!(slice.read_to_vec(3), Err(Errno::TooBig));
+    assert_eq!(slice.write(b"bsd!!"), Err(Errno::Inval));
+    slice.write(b"bsd").unwrap();
+    slice.write_byte(3, b'!').unwrap();
+    assert_eq!(slice.read_byte(0), Ok(b'b'));
+    assert_eq!(slice.read_byte(4), Err(Errno::Inval));
+    assert_eq!(&bytes, b"bsd!");
+    assert_eq!(
+        UserSlice::new(usize::MAX, 2).write(b"no"),
+        Err(Errno::Fault)
+    );
+
+    let name = UserCStr::new(c"geom0".as_ptr() as usize);
+    assert_eq!(name.read_to_cstring(6).as_deref(), Ok(c"geom0"));
+    assert_eq!(name.read_to_cstring(5), Err(Errno::NameTooLong));
+    assert_eq!(UserCStr::new(0).read(&mut [0; 8]), Err(Errno::Fault));
+}
+
+#[test]
+fn sbuf_formats_escapes_and_reports_overflow() {
+    use std::fmt::Write;
+
+    let mut sb = Sbuf::new();
+    write!(sb, "<name>").unwrap();
+    let name = "a<b & 'c'";
+    write!(sb.xml(), "{name}").unwrap();
+    sb.push(b'<').unwrap();
+    sb.cat(b"/name>").unwrap();
+    assert_eq!(sb.len(), Ok(39));
+    assert_eq!(sb.finish(), Ok(c"<name>a&lt;b &amp; &apos;c&apos;</name>"));
+
+    let mut fixed = Sbuf::with_capacity(4);
+    assert!(fixed.is_empty());
+    assert!(write!(fixed, "{}", 12345).is_err());
+    assert_eq!(fixed.cat(b"6"), Err(Errno::NoMem));
+    assert_eq!(fixed.len(), Err(Errno::NoMem));
+    assert_eq!(fixed.finish(), Err(Errno::NoMem));
+    fixed.clear();
+    fixed.cat(b"ok").unwrap();
+    assert_eq!(fixed.finish(), Ok(c"ok"));
+}
+
+#[test]
+fn rate_limit_drops_and_counts_excess() {
+    let limit = RateLimit::new(2);
+    let passed = (0..5).filter(|_| limit.check()).count();
+    assert_eq!((passed, limit.take_suppressed()), (2, 3));
+    assert_eq!(limit.take_suppressed(), 0);
+    assert!(LOG_ERR < LOG_WARNING);
+    assert_eq!(Priority::Warning.as_raw(), 4);
+    bsd_kernel::log!(LOG_WARNING, "{} of 5 logged", passed);
+}
+
+#[test]
+fn time_converts_and_orders_instants() {
+    let d = Duration::new(3, 250_000_000);
+    assert_eq!(time::bintime_to_duration(&time::duration_to_bintime(d)), d);
+    assert_eq!(time::hz(), 1000);
+    assert_eq!(time::duration_to_ticks(Duration::from_micros(1500)), 2);
+    assert_eq!(time::duration_to_ticks(Duration::MAX), i32::MAX);
+    assert_eq!(time::ticks_to_duration(-1), Duration::ZERO);
+    assert_eq!(time::ticks_to_duration(250), Duration::from_millis(250));
+
+    let start = Instant::now();
+    let later = start + Duration::from_secs(1);
+    assert!(later > start);
+    assert_eq!(later - start, Duration::from_secs(1));
+    assert_eq!(start - later, Duration::ZERO);
+    assert_eq!(start.checked_duration_since(later), None);
+    assert!(Instant::coarse() >= start);
+
+    let wall = SystemTime::now();
+    assert!(wall.since_epoch() > Duration::from_secs(1_600_000_000));
+    assert_eq!(wall.duration_since(wall), Some(Duration::ZERO));
+    assert_eq!(
+        wall.to_timespec().tv_nsec,
+        wall.since_epoch().subsec_nanos().into()
+    );
+}
+
+#[test]
+fn sysctl_handlers_read_and_write() {
+    let enabled = Arc::new(AtomicBool::new(false));
+    let name = Arc::new(Mutex::new(c"mockname", String::from("rmd")));
+    let errors = Arc::new(Counter::new());
+    errors.add(3);
+    let mut ctx = Sysctls::new();
+    let node = ctx.add_node(Node::hw(), c"mocksysctl", c"Test").unwrap();
+    ctx.add_u64(node, c"answer", c"Answer", || 42).unwrap();
+    let atomic = enabled.clone();
+    ctx.add_atomic(node, c"enabled", c"Enabled", atomic, true)
+        .unwrap();
+    ctx.add_string(node, c"name", c"Name", name.clone(), true, Some(4))
+        .unwrap();
+    ctx.add_counter(node, c"errors", c"Errors", errors.clone(), true)
+        .unwrap();
+    let taken = ctx.add_u64(node, c"answer", c"Again", || 0);
+    assert_eq!(taken, Err(Errno::Exist));
+
+    assert_eq!(
+        sysctl::read("hw.mocksysctl.answer"),
+        Ok(42u64.to_ne_bytes().to_vec())
+    );
+    let err = sysctl::write("hw.mocksysctl.answer", &[0; 8]);
+    assert_eq!(err, Err(Errno::Perm.as_raw()));
+    assert_eq!(sysctl::read("hw.mocksysctl"), Err(Errno::IsDir.as_raw()));
+
+    sysctl::write("hw.mocksysctl.enabled", &[1]).unwrap();
+    assert!(enabled.load(Ordering::Relaxed));
+    assert_eq!(sysctl::read("hw.mocksysctl.enabled"), Ok(vec![1]));
+
+    assert_eq!(sysctl::read("hw.mocksysctl.name"), Ok(b"rmd\0".to_vec()));
+    sysctl::write("hw.mocksysctl.name", b"md").unwrap();
+    assert_eq!(*name.lock(), "md");
+    let err = sysctl::write("hw.mocksysctl.name", b"toolong");
+    assert_eq!(err, Err(Errno::Inval.as_raw()));
+
+    let sum = sysctl::read("hw.mocksysctl.errors").unwrap();
+    assert_eq!(sum, 3u64.to_ne_bytes());
+    sysctl::write("hw.mocksysctl.errors", &0u64.to_ne_bytes()).unwrap();
+    assert_eq!(errors.fetch(), 0);
+
+    // A second context shares the node, which outlives the first
+    let mut other = Sysctls::new();
+    let shared = other.add_node(Node::hw(), c"mocksysctl", c"Test").unwrap();
+    other.add_u64(shared, c"other", c"Other", || 7).unwrap();
+    drop(ctx);
+    assert!(!sysctl::exists("hw.mocksysctl.answer"));
+    assert!(sysctl::exists("hw.mocksysctl.other"));
+    drop(other);
+    assert_eq!(sysctl::read("hw.mocksysctl"), Err(Errno::NoEnt.as_raw()));
+}
+
+#[test]
+fn console_output_is_captured() {
+    let ((), out) = dev::capture(|| {
+        bsd_kernel::println!("{} + {}", 1, 2);
+        bsd_kernel::uprintf!("no newline");
+        bsd_kernel::syslog::log(Priority::Err, format_args!("failed"));
+    });
+    assert_eq!(out, "1 + 2\nno newline<3>failed\n");
+    let (n, nested) =
+        dev::capture(|| dev::capture(|| bsd_kernel::println!("x")).1.len());
+    assert_eq!((n, nested.as_str()), (2, ""));
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Host tests of panic poisoning, in their own binary as a panic poisons
+//! the whole module
+
+use bsd_kernel::character_device::{CDev, CharacterDevice};
+use bsd_kernel::cred::CurThread;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::io::Read;
+use bsd_kernel::kernel_sys::mock::dev;
+use bsd_kernel::module::{ModuleEvents, SharedModule};
+use bsd_kernel::panic::{catch_in_module, module_poisoned};
+use bsd_kernel::uio::{UioReader, UioWriter};
+
+/// A device that panics on a write of nothing
+struct Fragile;
+
+impl CharacterDevice for Fragile {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        _uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        assert!(uio.residual() > 0, "empty write");
+        let _ = uio.read(&mut [0u8; 16]);
+        Ok(())
+    }
+}
+
+impl ModuleEvents for Fragile {
+    fn load(&mut self) {}
+
+    fn unload(&mut self) {
+        unreachable!("unload of a module that panicked");
+    }
+}
+
+#[test]
+fn panic_poisons_device_and_module() {
+    let fragile =
+        CDev::new_with_delegate("mockfragile", SharedModule::new(Fragile))
+            .unwrap();
+    let other =
+        CDev::new_with_delegate("mockother", SharedModule::new(Fragile))
+            .unwrap();
+    let mut buf = [0u8; 4];
+    assert_eq!(dev::write("mockfragile", b"ok", 0, 0), Ok(2));
+    assert!(!fragile.is_poisoned());
+    assert!(!module_poisoned());
+
+    // Only the mock unwinds: in the kernel this write never returns, as
+    // its thread parks on `rpanic` instead, and the calls below are what
+    // every other thread sees
+    let err = dev::write("mockfragile", b"", 0, 0);
+    assert_eq!(err, Err(Errno::Io.as_raw()));
+    assert!(fragile.is_poisoned());
+    assert!(module_poisoned());
+    let err = dev::read("mockfragile", &mut buf, 0, 0);
+    assert_eq!(err, Err(Errno::NxIo.as_raw()));
+
+    // The rest of the module refuses too, without being poisoned itself
+    assert!(!other.is_poisoned());
+    let err = dev::write("mockother", b"ok", 0, 0);
+    assert_eq!(err, Err(Errno::NxIo.as_raw()));
+    assert_eq!(catch_in_module(|| 1), Err(Errno::NxIo));
+
+    SharedModule::new(Fragile).unload();
+    drop(fragile);
+    drop(other);
+    assert!(!dev::exists("mockfragile"));
+}
@@ -1,20 +1,43 @@
 #!/usr/bin/env sh
+#
+# Usage: ./build.sh [module-dir]
+#
+# Builds the module crate in module-dir (default: the hello example at the
+# top level) and links it into a .ko with the module's Makefile.
+# Builds for the running kernel's architecture unless MACHINE_ARCH is set
+# to amd64, aarch64 or riscv64.
 
-OBJECTDIR=target/objects
 CURDIR=`pwd`
-MODULE_NAME="bsd_rust"
+
+if [ -n "$1" ]; then
+	MODULE_DIR="${1%/}"
+	PACKAGE=`sed -n 's/^name = "\(.*\)"/\1/p' "${MODULE_DIR}/Cargo.toml" | head -n 1`
+else
+	MODULE_DIR="."
+	PACKAGE="bsd-rust"
+fi
+MODULE_NAME=`echo "${PACKAGE}" | tr - _`
+
+case "${MACHINE_ARCH:-`uname -p`}" in
+amd64)		TARGET="x86_64-kernel-freebsd" ;;
+aarch64)	TARGET="aarch64-kernel-freebsd" ;;
+riscv64*)	TARGET="riscv64-kernel-freebsd" ;;
+*)		echo "Unsupported architecture" >&2; exit 1 ;;
+esac
+
+OBJECTDIR="${MODULE_DIR}/target/objects"
 
 if [ -d "${OBJECTDIR}" ]; then
 	rm -rf "${OBJECTDIR}"
 fi
 
-mkdir "${OBJECTDIR}"
+mkdir -p "${OBJECTDIR}"
 
 
 
-make clean && \
-	cargo build && \
+make -C "${MODULE_DIR}" clean && \
+	cargo build -p "${PACKAGE}" --target "${CURDIR}/${TARGET}.json" && \
 	cd "${OBJECTDIR}" && \
-	ar -xv "${CURDIR}/target/x86_64-kernel-freebsd/debug/lib${MODULE_NAME}.a" && \
+	ar -xv "${CURDIR}/target/${TARGET}/debug/lib${MODULE_NAME}.a" && \
 	cd "${CURDIR}" && \
-	make OBJECTDIR="${OBJECTDIR}"
+	make -C "${MODULE_DIR}" OBJECTDIR=target/objects
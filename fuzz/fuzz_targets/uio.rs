@@ -0,0 +1,147 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Drive `UioReader` and `UioWriter` with arbitrary offsets, residuals and
+//! buffer sizes, checking each transfer against `uiomove_frombuf(9)`:
+//! a request at offset `o` against a buffer of `len` bytes moves
+//! `min(resid, len - o)` bytes from `buf[o..]`, nothing at or past the end,
+//! and fails for a negative offset.
+
+#![no_main]
+
+use bsd_kernel::io::{Read, Write};
+use bsd_kernel::kernel_sys::{
+    iovec, uio, uio_rw_UIO_READ, uio_rw_UIO_WRITE, uio_seg_UIO_SYSSPACE,
+};
+use bsd_kernel::uio::{UioReader, UioWriter};
+use libfuzzer_sys::fuzz_target;
+use std::ptr;
+
+/// Consume bytes from the front of the input, padding with zeroes
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut b = [0u8; N];
+        let n = N.min(self.0.len());
+        b[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        b
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take())
+    }
+
+    /// Mostly offsets near the buffer, sometimes anything at all
+    fn offset(&mut self) -> i64 {
+        match self.u8() % 4 {
+            0 => i64::from_le_bytes(self.take()),
+            1 => -i64::from(self.u16()),
+            _ => i64::from(self.u16() % 1024),
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = Input(data);
+    let to_user = input.u8() & 1 != 0;
+    let offset = input.offset();
+    let buf_len = usize::from(input.u16() % 2048);
+    let nsegs = usize::from(input.u8() % 4) + 1;
+    let seg_lens: Vec<usize> =
+        (0..nsegs).map(|_| usize::from(input.u16() % 512)).collect();
+    let resid: usize = seg_lens.iter().sum();
+
+    // Device memory, and the caller's segments laid end to end
+    let mut buf: Vec<u8> = (0..buf_len).map(|i| i as u8).collect();
+    let mut user: Vec<u8> = (0..resid).map(|i| !(i as u8)).collect();
+    let orig_buf = buf.clone();
+    let orig_user = user.clone();
+
+    let mut iovs: Vec<iovec> = Vec::with_capacity(nsegs);
+    let mut base = user.as_mut_ptr();
+    for &len in &seg_lens {
+        iovs.push(iovec {
+            iov_base: base as *mut _,
+            iov_len: len,
+        });
+        base = unsafe { base.add(len) };
+    }
+    let mut u = uio {
+        uio_iov: iovs.as_mut_ptr(),
+        uio_iovcnt: nsegs as i32,
+        uio_offset: offset,
+        uio_resid: resid as isize,
+        uio_segflg: uio_seg_UIO_SYSSPACE,
+        uio_rw: if to_user {
+            uio_rw_UIO_READ
+        } else {
+            uio_rw_UIO_WRITE
+        },
+        uio_td: ptr::null_mut(),
+    };
+
+    let res = if to_user {
+        let mut w = UioWriter::new(&raw mut u);
+        let res = w.write(&buf);
+        assert_eq!(w.residual(), u.uio_resid);
+        res
+    } else {
+        let mut r = UioReader::new(&raw mut u);
+        let res = r.read(&mut buf);
+        assert_eq!(r.residual(), u.uio_resid);
+        res
+    };
+
+    if offset < 0 {
+        assert!(res.is_err(), "negative offset accepted");
+        assert_eq!(u.uio_resid, resid as isize);
+        assert_eq!(buf, orig_buf);
+        assert_eq!(user, orig_user);
+        return;
+    }
+    let n = res.expect("transfer failed");
+    // Offsets past the end move nothing
+    let start = usize::try_from(offset).unwrap_or(usize::MAX).min(buf_len);
+    let expected = (buf_len - start).min(resid);
+    assert_eq!(n, expected);
+    assert_eq!(u.uio_resid, (resid - n) as isize);
+    assert_eq!(u.uio_offset, offset + n as i64);
+
+    // Exactly the expected span moved, and nothing else changed
+    if to_user {
+        assert_eq!(buf, orig_buf);
+        assert_eq!(user[..n], orig_buf[start..start + n]);
+        assert_eq!(user[n..], orig_user[n..]);
+    } else {
+        assert_eq!(user, orig_user);
+        assert_eq!(buf[..start], orig_buf[..start]);
+        assert_eq!(buf[start..start + n], orig_user[..n]);
+        assert_eq!(buf[start + n..], orig_buf[start + n..]);
+    }
+});
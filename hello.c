@@ -23,21 +23,7 @@
 //
 // Based on public domain code by Johannes Lundberg
 
-#include <sys/param.h>
-#include <sys/module.h>
-#include <sys/kernel.h>
-#include <sys/systm.h>
-#include <sys/types.h>
-#include <sys/conf.h>
-#include <sys/uio.h>
-#include <sys/malloc.h>
-
-extern int module_event(struct module *, int, void *);
-
-static moduledata_t module_data = {
-    "hello",        /* module name */
-     module_event,  /* event handler */
-     NULL           /* extra data */
-};
-
-DECLARE_MODULE(hello, module_data, SI_SUB_DRIVERS, SI_ORDER_MIDDLE);
+/*
+ * The module is declared by #[kernel_module] in module-hello/src/module.rs;
+ * this file only gives bsd.kmod.mk a source to build.
+ */
@@ -28,11 +28,121 @@ extern crate bindgen;
 use bindgen::{
     Builder, Formatter, MacroTypeVariation::Signed, RustEdition, RustTarget,
 };
-use std::path::PathBuf;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 const FILEPATH: &str = "src/bindings.rs";
 
+/// kobj interfaces whose generated `*_if.h` headers `sys/bus.h`, the hid,
+/// mmc and pci headers include, and whose method descriptions the smbus
+/// and pci wrappers look up
+const INTERFACES: &[&str] = &[
+    "kern/device_if.m",
+    "kern/bus_if.m",
+    "dev/hid/hid_if.m",
+    "dev/mmc/mmcbr_if.m",
+    "dev/pci/pci_if.m",
+    "dev/smbus/smbus_if.m",
+];
+
+/// Generate the interface headers in `dir`, as the kernel build does
+fn make_interfaces(dir: &Path) {
+    for m in INTERFACES {
+        let status = Command::new("awk")
+            .args(["-f", "/usr/src/sys/tools/makeobjops.awk"])
+            .arg(format!("/usr/src/sys/{m}"))
+            .arg("-h")
+            .current_dir(dir)
+            .status()
+            .expect("Unable to run awk");
+        assert!(status.success(), "Unable to generate the header for {m}");
+    }
+}
+
+/// Generate `vnode_if.h`, which `sys/vnode.h` includes, and the headers
+/// it includes in turn, in `dir`, as the kernel build does
+fn make_vnode_if(dir: &Path) {
+    for header in ["-h", "-p", "-q"] {
+        let status = Command::new("awk")
+            .args(["-f", "/usr/src/sys/tools/vnode_if.awk"])
+            .arg("/usr/src/sys/kern/vnode_if.src")
+            .arg(header)
+            .current_dir(dir)
+            .status()
+            .expect("Unable to run awk");
+        assert!(status.success(), "Unable to generate vnode_if.h");
+    }
+}
+
+/// The kernel's own flags for each architecture, from `sys/conf/kern.mk`
+fn arch_args(arch: &str) -> &'static [&'static str] {
+    match arch {
+        "x86_64" => &[
+            "-mcmodel=kernel",
+            "-mno-red-zone",
+            "-mno-mmx",
+            "-mno-sse",
+            "-msoft-float",
+            "-mno-aes",
+            "-mno-avx",
+        ],
+        // Modules are shared objects here, rather than amd64's relocatable
+        // ones, and x18 holds the pcpu pointer
+        "aarch64" => &[
+            "--target=aarch64-unknown-freebsd",
+            "-mgeneral-regs-only",
+            "-ffixed-x18",
+            "-fPIC",
+        ],
+        "riscv64" => &[
+            "--target=riscv64-unknown-freebsd",
+            "-march=rv64imac",
+            "-mabi=lp64",
+            "-mcmodel=medany",
+            "-fPIC",
+        ],
+        _ => panic!("No kernel flags for {arch}"),
+    }
+}
+
+/// Point `machine/` (and amd64's `x86/`) in `dir` at the target's headers,
+/// as the kernel build does, so bindings can be generated for another
+/// architecture than the host's
+fn link_machine(dir: &Path, arch: &str) {
+    let machine = match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "riscv64" => "riscv",
+        _ => panic!("No machine headers for {arch}"),
+    };
+    let mut links = vec![("machine", machine)];
+    if arch == "x86_64" {
+        links.push(("x86", "x86"));
+    }
+    for (name, target) in links {
+        let link = dir.join(name);
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(
+            format!("/usr/src/sys/{target}/include"),
+            &link,
+        )
+        .expect("Unable to link the machine headers");
+    }
+}
+
 fn main() {
+    // The mock layer replaces the bindings, so no kernel sources needed
+    if env::var_os("CARGO_FEATURE_MOCK").is_some() {
+        return;
+    }
+
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    make_interfaces(&out_dir);
+    make_vnode_if(&out_dir);
+    link_machine(&out_dir, &arch);
+
     let bindings = Builder::default()
         .formatter(Formatter::Rustfmt)
         .rust_target(RustTarget::nightly())
@@ -49,19 +159,20 @@ fn main() {
         .clang_arg("-Werror")
         .clang_arg("-D_KERNEL")
         .clang_arg("-DKLD_MODULE")
+        // dummynet.ko is built with its AQM support, which changes the
+        // layout of its structures
+        .clang_arg("-DNEW_AQM")
         .clang_arg("-nostdinc")
         .clang_arg("-I.")
+        .clang_arg(format!("-I{}", out_dir.display()))
         .clang_arg("-I/usr/src/sys")
+        // zstd's headers want libc ones, which the kernel build maps here
+        .clang_arg("-I/usr/src/sys/contrib/zstd/lib/freebsd")
         .clang_arg("-I/usr/include")
         .clang_arg("-fno-common")
         .clang_arg("-fno-omit-frame-pointer")
         .clang_arg("-mno-omit-leaf-frame-pointer")
         .clang_arg("-MD")
-        .clang_arg("-mcmodel=kernel")
-        .clang_arg("-mno-red-zone")
-        .clang_arg("-mno-mmx")
-        .clang_arg("-mno-sse")
-        .clang_arg("-msoft-float")
         .clang_arg("-fno-asynchronous-unwind-tables")
         .clang_arg("-ffreestanding")
         .clang_arg("-fwrapv")
@@ -82,9 +193,12 @@ fn main() {
         .clang_arg("-Wno-unknown-pragmas")
         // .clang_arg("-Wno-error-tautological-compare")
         // .clang_arg("-Wno-error-empty-body")
-        .clang_arg("-mno-aes")
-        .clang_arg("-mno-avx")
         .clang_arg("-std=iso9899:1999")
+        .clang_args(arch_args(&arch))
+        // ktr(4) is only declared for kernels with `options KTR`
+        .clang_args(env::var_os("CARGO_FEATURE_KTR").map(|_| "-DKTR"))
+        // As is most of vnet(9), for kernels with `options VIMAGE`
+        .clang_args(env::var_os("CARGO_FEATURE_VIMAGE").map(|_| "-DVIMAGE"))
         .generate()
         .expect("Unable to generate binding");
 
@@ -23,7 +23,8 @@
 //
 // Based on public domain code by Johannes Lundberg
 
-#![no_std]
+#![cfg_attr(not(feature = "mock"), no_std)]
+#![cfg_attr(feature = "mock", feature(c_variadic))]
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
@@ -31,6 +32,12 @@
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::missing_safety_doc)]
 
+#[cfg(not(feature = "mock"))]
 pub use bindings::*;
+#[cfg(feature = "mock")]
+pub use mock::*;
 
+#[cfg(not(feature = "mock"))]
 mod bindings;
+#[cfg(feature = "mock")]
+pub mod mock;
@@ -0,0 +1,127 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! User memory is the test process's own, so the copies are plain memory
+//! copies; a null address stands in for one that faults.
+
+use super::{EFAULT, ENAMETOOLONG};
+use libc::{c_int, c_void};
+use std::ptr;
+
+pub unsafe fn copyin(
+    udaddr: *const c_void,
+    kaddr: *mut c_void,
+    len: usize,
+) -> c_int {
+    if udaddr.is_null() {
+        return EFAULT;
+    }
+    unsafe { ptr::copy(udaddr as *const u8, kaddr as *mut u8, len) };
+    0
+}
+
+pub unsafe fn copyout(
+    kaddr: *const c_void,
+    udaddr: *mut c_void,
+    len: usize,
+) -> c_int {
+    if udaddr.is_null() {
+        return EFAULT;
+    }
+    unsafe { ptr::copy(kaddr as *const u8, udaddr as *mut u8, len) };
+    0
+}
+
+pub unsafe fn copyinstr(
+    udaddr: *const c_void,
+    kaddr: *mut c_void,
+    len: usize,
+    lencopied: *mut usize,
+) -> c_int {
+    if udaddr.is_null() {
+        return EFAULT;
+    }
+    let (src, dst) = (udaddr as *const u8, kaddr as *mut u8);
+    let mut n = 0;
+    let mut ret = ENAMETOOLONG;
+    while n < len {
+        let c = unsafe { src.add(n).read() };
+        unsafe { dst.add(n).write(c) };
+        n += 1;
+        if c == 0 {
+            ret = 0;
+            break;
+        }
+    }
+    if !lencopied.is_null() {
+        unsafe { lencopied.write(n) };
+    }
+    ret
+}
+
+pub unsafe fn fubyte(base: *const c_void) -> c_int {
+    if base.is_null() {
+        return -1;
+    }
+    c_int::from(unsafe { (base as *const u8).read() })
+}
+
+pub unsafe fn subyte(base: *mut c_void, byte: c_int) -> c_int {
+    if base.is_null() {
+        return -1;
+    }
+    unsafe { (base as *mut u8).write(byte as u8) };
+    0
+}
+
+pub unsafe fn fueword32(base: *const c_void, val: *mut i32) -> c_int {
+    if base.is_null() {
+        return -1;
+    }
+    unsafe { val.write((base as *const i32).read_unaligned()) };
+    0
+}
+
+pub unsafe fn fueword64(base: *const c_void, val: *mut i64) -> c_int {
+    if base.is_null() {
+        return -1;
+    }
+    unsafe { val.write((base as *const i64).read_unaligned()) };
+    0
+}
+
+pub unsafe fn suword32(base: *mut c_void, word: i32) -> c_int {
+    if base.is_null() {
+        return -1;
+    }
+    unsafe { (base as *mut i32).write_unaligned(word) };
+    0
+}
+
+pub unsafe fn suword64(base: *mut c_void, word: i64) -> c_int {
+    if base.is_null() {
+        return -1;
+    }
+    unsafe { (base as *mut i64).write_unaligned(word) };
+    0
+}
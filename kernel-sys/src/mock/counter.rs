@@ -0,0 +1,46 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `counter(9)` with a single slot, which `bsd_kernel::counter` updates
+//! atomically in place of the per-CPU add
+
+use libc::c_int;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub type counter_u64_t = *mut u64;
+
+pub unsafe fn counter_u64_alloc(_flags: c_int) -> counter_u64_t {
+    Box::into_raw(Box::new(AtomicU64::new(0))) as counter_u64_t
+}
+
+pub unsafe fn counter_u64_free(c: counter_u64_t) {
+    drop(unsafe { Box::from_raw(c as *mut AtomicU64) });
+}
+
+pub unsafe fn counter_u64_zero(c: counter_u64_t) {
+    unsafe { AtomicU64::from_ptr(c) }.store(0, Ordering::Relaxed);
+}
+
+pub unsafe fn counter_u64_fetch(c: counter_u64_t) -> u64 {
+    unsafe { AtomicU64::from_ptr(c) }.load(Ordering::Relaxed)
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Credentials and `priv(9)`, for the thread `mock::dev` calls devices on
+//!
+//! Calls run as root outside any jail, unless a test sets other
+//! credentials with `set_cred`. `priv_check_cred` grants every privilege
+//! to root and none to other users or to jails.
+
+use super::{EPERM, gid_t, proc_, sysentvec, thread, u_int, ucred, uid_t};
+use libc::c_int;
+use std::cell::Cell;
+use std::ptr;
+
+/// The credentials devices are called with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Cred {
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub jailed: bool,
+}
+
+impl Cred {
+    pub const ROOT: Cred = Cred {
+        uid: 0,
+        gid: 0,
+        jailed: false,
+    };
+}
+
+thread_local! {
+    static CRED: Cell<Cred> = const { Cell::new(Cred::ROOT) };
+}
+
+/// Call devices from this test thread with `cred` from now on
+pub fn set_cred(cred: Cred) {
+    CRED.with(|c| c.set(cred));
+}
+
+/// Stands in for the prison of jailed credentials, only ever compared
+static mut PRISON: u8 = 0;
+
+/// Run `f` on a thread with the test's credentials, in a process whose
+/// ABI has `sv_flags`
+pub(crate) fn with_thread<R>(
+    sv_flags: u_int,
+    f: impl FnOnce(*mut thread) -> R,
+) -> R {
+    let cred = CRED.with(Cell::get);
+    let mut cr = ucred {
+        cr_uid: cred.uid,
+        cr_ruid: cred.uid,
+        cr_gid: cred.gid,
+        cr_rgid: cred.gid,
+        cr_svuid: cred.uid,
+        cr_svgid: cred.gid,
+        cr_ngroups: 0,
+        cr_groups: ptr::null_mut(),
+        cr_prison: if cred.jailed {
+            (&raw mut PRISON).cast()
+        } else {
+            ptr::null_mut()
+        },
+    };
+    let mut sysent = sysentvec { sv_flags };
+    let mut p = proc_ {
+        p_pid: std::process::id() as i32,
+        p_sysent: &mut sysent,
+    };
+    let mut td = thread {
+        td_tid: 100000,
+        td_ucred: &mut cr,
+        td_proc: &mut p,
+    };
+    f(&mut td)
+}
+
+pub unsafe fn jailed(cred: *mut ucred) -> c_int {
+    unsafe { (!(*cred).cr_prison.is_null()) as c_int }
+}
+
+pub unsafe fn groupmember(gid: gid_t, cred: *const ucred) -> bool {
+    unsafe { (*cred).cr_gid == gid }
+}
+
+pub unsafe fn priv_check_cred(cred: *mut ucred, _priv: c_int) -> c_int {
+    let granted = unsafe { (*cred).cr_uid == 0 && jailed(cred) == 0 };
+    if granted { 0 } else { EPERM }
+}
+
+pub unsafe fn priv_check(td: *mut thread, priv_: c_int) -> c_int {
+    unsafe { priv_check_cred((*td).td_ucred, priv_) }
+}
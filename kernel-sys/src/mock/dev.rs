@@ -0,0 +1,306 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Character devices, `selrecord(9)` and console output
+//!
+//! `make_dev_s` records each device in a table by name, and the functions
+//! here call into its switch the way the devfs layer would, so a test can
+//! open, read, write and poll a device created by the code under test.
+
+use super::cred::with_thread;
+use super::uiomove::MockUio;
+use super::{
+    D_TRACKCLOSE, EEXIST, ENODEV, ENXIO, MAKEDEV_CHECKNAME, SV_ILP32, caddr_t,
+    cdev, cdevsw, make_dev_args, off_t, selinfo, thread, u_int, u_long,
+};
+use libc::{c_char, c_int, c_void};
+use std::cell::RefCell;
+use std::ffi::{CStr, VaListImpl};
+use std::ptr;
+use std::sync::Mutex;
+
+struct Device {
+    name: String,
+    cdev: *mut cdev,
+    opens: usize,
+}
+
+unsafe impl Send for Device {}
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static CONSOLE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, returning what it printed to the console on this thread with
+/// `printf`, `uprintf` or `log` instead of printing it
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, String) {
+    let outer = CONSOLE.replace(Some(String::new()));
+    let r = f();
+    let out = CONSOLE.replace(outer).unwrap_or_default();
+    (r, out)
+}
+
+fn console(s: &str) {
+    CONSOLE.with_borrow_mut(|c| match c {
+        Some(c) => c.push_str(s),
+        None => print!("{}", s),
+    })
+}
+
+/// Expand a printf(9) format, handling the common conversions
+unsafe fn format(fmt: *const c_char, args: &mut VaListImpl<'_>) -> String {
+    let fmt = unsafe { CStr::from_ptr(fmt) }.to_bytes();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < fmt.len() {
+        if fmt[i] != b'%' {
+            let end = fmt[i..].iter().position(|&b| b == b'%');
+            let end = end.map_or(fmt.len(), |n| i + n);
+            out.push_str(&String::from_utf8_lossy(&fmt[i..end]));
+            i = end;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        let mut long = false;
+        while i < fmt.len() && matches!(fmt[i], b'l' | b'z' | b'j' | b'q') {
+            long = true;
+            i += 1;
+        }
+        let Some(&conv) = fmt.get(i) else {
+            out.push_str(&String::from_utf8_lossy(&fmt[start..]));
+            break;
+        };
+        i += 1;
+        unsafe {
+            match conv {
+                b'%' => out.push('%'),
+                b's' => {
+                    let s = args.arg::<*const c_char>();
+                    if s.is_null() {
+                        out.push_str("(null)");
+                    } else {
+                        out.push_str(&CStr::from_ptr(s).to_string_lossy());
+                    }
+                }
+                b'c' => out.push(args.arg::<c_int>() as u8 as char),
+                b'd' | b'i' if long => {
+                    out.push_str(&args.arg::<i64>().to_string())
+                }
+                b'd' | b'i' => out.push_str(&args.arg::<c_int>().to_string()),
+                b'u' if long => out.push_str(&args.arg::<u64>().to_string()),
+                b'u' => out.push_str(&args.arg::<u32>().to_string()),
+                b'x' if long => {
+                    out.push_str(&format!("{:x}", args.arg::<u64>()))
+                }
+                b'x' => out.push_str(&format!("{:x}", args.arg::<u32>())),
+                b'p' => {
+                    out.push_str(&format!("{:p}", args.arg::<*const c_void>()))
+                }
+                _ => out.push_str(&String::from_utf8_lossy(&fmt[start..i])),
+            }
+        }
+    }
+    out
+}
+
+pub unsafe extern "C" fn uprintf(fmt: *const c_char, mut args: ...) -> c_int {
+    let s = unsafe { format(fmt, &mut args) };
+    console(&s);
+    s.len() as c_int
+}
+
+pub unsafe extern "C" fn printf(fmt: *const c_char, mut args: ...) -> c_int {
+    let s = unsafe { format(fmt, &mut args) };
+    console(&s);
+    s.len() as c_int
+}
+
+pub unsafe extern "C" fn log(level: c_int, fmt: *const c_char, mut args: ...) {
+    let s = unsafe { format(fmt, &mut args) };
+    console(&format!("<{}>{}", level, s));
+}
+
+pub unsafe fn make_dev_args_init_impl(args: *mut make_dev_args, sz: usize) {
+    unsafe {
+        ptr::write_bytes(args as *mut u8, 0, sz);
+        (*args).mda_size = sz;
+    }
+}
+
+pub unsafe extern "C" fn make_dev_s(
+    args: *mut make_dev_args,
+    cdev: *mut *mut cdev,
+    fmt: *const c_char,
+    mut ap: ...
+) -> c_int {
+    let args = unsafe { &*args };
+    let name = unsafe { format(fmt, &mut ap) };
+    let mut devices = DEVICES.lock().unwrap();
+    if devices.iter().any(|d| d.name == name) {
+        // Without MAKEDEV_CHECKNAME the kernel panics on a duplicate
+        assert!(
+            args.mda_flags & MAKEDEV_CHECKNAME != 0,
+            "make_dev_s: duplicate name {}",
+            name
+        );
+        return EEXIST;
+    }
+    let dev = Box::into_raw(Box::new(super::cdev {
+        si_drv1: args.mda_si_drv1,
+        si_drv2: args.mda_si_drv2,
+        si_devsw: args.mda_devsw,
+    }));
+    devices.push(Device {
+        name,
+        cdev: dev,
+        opens: 0,
+    });
+    unsafe { *cdev = dev };
+    0
+}
+
+pub unsafe fn destroy_dev(dev: *mut cdev) {
+    let mut devices = DEVICES.lock().unwrap();
+    let i = devices
+        .iter()
+        .position(|d| d.cdev == dev)
+        .expect("destroy_dev: unknown device");
+    devices.remove(i);
+    drop(unsafe { Box::from_raw(dev) });
+}
+
+pub unsafe fn selrecord(_selector: *mut thread, _sip: *mut selinfo) {}
+
+pub unsafe fn selwakeup(_sip: *mut selinfo) {}
+
+pub unsafe fn selwakeuppri(_sip: *mut selinfo, _pri: c_int) {}
+
+pub unsafe fn seldrain(_sip: *mut selinfo) {}
+
+fn lookup(name: &str) -> Result<(*mut cdev, &'static cdevsw), c_int> {
+    let devices = DEVICES.lock().unwrap();
+    let d = devices.iter().find(|d| d.name == name).ok_or(ENXIO)?;
+    Ok((d.cdev, unsafe { &*(*d.cdev).si_devsw }))
+}
+
+/// Check whether a device named `name` currently exists
+pub fn exists(name: &str) -> bool {
+    lookup(name).is_ok()
+}
+
+/// Count an open or close of the device named `name`, returning the
+/// number of opens left
+fn count_open(name: &str, delta: isize) -> usize {
+    let mut devices = DEVICES.lock().unwrap();
+    let d = devices.iter_mut().find(|d| d.name == name).unwrap();
+    d.opens = d.opens.saturating_add_signed(delta);
+    d.opens
+}
+
+/// Call the device's `d_open` with `oflags`
+pub fn open(name: &str, oflags: c_int) -> Result<(), c_int> {
+    let (dev, sw) = lookup(name)?;
+    let f = sw.d_open.ok_or(ENODEV)?;
+    errno(with_thread(0, |td| unsafe { f(dev, oflags, 0, td) }))?;
+    count_open(name, 1);
+    Ok(())
+}
+
+/// Close one open of the device. Like devfs, this only calls `d_close` on
+/// the last close, unless the switch has `D_TRACKCLOSE`
+pub fn close(name: &str, fflag: c_int) -> Result<(), c_int> {
+    let (dev, sw) = lookup(name)?;
+    let f = sw.d_close.ok_or(ENODEV)?;
+    let last = count_open(name, -1) == 0;
+    if !last && sw.d_flags & D_TRACKCLOSE as u32 == 0 {
+        return Ok(());
+    }
+    errno(with_thread(0, |td| unsafe { f(dev, fflag, 0, td) }))
+}
+
+/// Read into `buf` from `offset`, returning the number of bytes read
+pub fn read(
+    name: &str,
+    buf: &mut [u8],
+    offset: off_t,
+    ioflag: c_int,
+) -> Result<usize, c_int> {
+    let (dev, sw) = lookup(name)?;
+    let f = sw.d_read.ok_or(ENODEV)?;
+    let mut uio = MockUio::read(buf, offset);
+    errno(with_thread(0, |td| unsafe {
+        (*uio.as_ptr()).uio_td = td;
+        f(dev, uio.as_ptr(), ioflag)
+    }))?;
+    Ok(uio.transferred())
+}
+
+/// Write `data` at `offset`, returning the number of bytes written
+pub fn write(
+    name: &str,
+    data: &[u8],
+    offset: off_t,
+    ioflag: c_int,
+) -> Result<usize, c_int> {
+    let (dev, sw) = lookup(name)?;
+    let f = sw.d_write.ok_or(ENODEV)?;
+    let mut uio = MockUio::write(data, offset);
+    errno(with_thread(0, |td| unsafe {
+        (*uio.as_ptr()).uio_td = td;
+        f(dev, uio.as_ptr(), ioflag)
+    }))?;
+    Ok(uio.transferred())
+}
+
+/// Call the device's `d_ioctl` with `data` as the kernel copy of the
+/// argument, as the kernel passes it after copying it in
+pub fn ioctl(name: &str, cmd: u_long, data: &mut [u8]) -> Result<(), c_int> {
+    let (dev, sw) = lookup(name)?;
+    let f = sw.d_ioctl.ok_or(ENODEV)?;
+    let data = data.as_mut_ptr() as caddr_t;
+    errno(with_thread(0, |td| unsafe { f(dev, cmd, data, 0, td) }))
+}
+
+/// `ioctl`, as called by a 32-bit process on a 64-bit kernel
+pub fn ioctl32(name: &str, cmd: u_long, data: &mut [u8]) -> Result<(), c_int> {
+    let (dev, sw) = lookup(name)?;
+    let f = sw.d_ioctl.ok_or(ENODEV)?;
+    let data = data.as_mut_ptr() as caddr_t;
+    errno(with_thread(SV_ILP32 as u_int, |td| unsafe {
+        f(dev, cmd, data, 0, td)
+    }))
+}
+
+/// Call the device's `d_poll`, returning the events that are ready
+pub fn poll(name: &str, events: c_int) -> Result<c_int, c_int> {
+    let (dev, sw) = lookup(name)?;
+    let f = sw.d_poll.ok_or(ENODEV)?;
+    Ok(with_thread(0, |td| unsafe { f(dev, events, td) }))
+}
+
+fn errno(ret: c_int) -> Result<(), c_int> {
+    if ret == 0 { Ok(()) } else { Err(ret) }
+}
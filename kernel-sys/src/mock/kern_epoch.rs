@@ -0,0 +1,131 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `epoch(9)` as a count of the threads in a section
+//!
+//! Callbacks queue up until `epoch_drain_callbacks`, which runs them once
+//! no thread is in a section, so a test sees when values are dropped.
+//! `epoch_wait_preempt` spins until then.
+
+use libc::{c_char, c_int, c_void};
+use std::cell::RefCell;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+pub const EPOCH_PREEMPT: i32 = 1;
+
+pub struct epoch {
+    readers: AtomicUsize,
+    pending: Mutex<Vec<Pending>>,
+}
+pub type epoch_t = *mut epoch;
+
+#[repr(C)]
+pub struct epoch_tracker {
+    _p: [u64; 8],
+}
+
+#[repr(C)]
+pub struct epoch_context {
+    pub data: [*mut c_void; 2],
+}
+pub type epoch_context_t = *mut epoch_context;
+pub type epoch_callback_t = Option<unsafe extern "C" fn(arg1: epoch_context_t)>;
+
+struct Pending(epoch_callback_t, epoch_context_t);
+
+// The callback's value is Send, as Epoch::retire requires
+unsafe impl Send for Pending {}
+
+impl epoch {
+    const fn new() -> Self {
+        epoch {
+            readers: AtomicUsize::new(0),
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+static GLOBAL: epoch = epoch::new();
+static NET: epoch = epoch::new();
+pub static mut global_epoch_preempt: epoch_t =
+    &GLOBAL as *const epoch as epoch_t;
+pub static mut net_epoch_preempt: epoch_t = &NET as *const epoch as epoch_t;
+
+thread_local! {
+    /// The epochs the thread is in sections of, innermost last
+    static SECTIONS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+pub unsafe fn epoch_alloc(_name: *const c_char, _flags: c_int) -> epoch_t {
+    Box::into_raw(Box::new(epoch::new()))
+}
+
+pub unsafe fn epoch_free(epoch: epoch_t) {
+    let e = unsafe { Box::from_raw(epoch) };
+    assert_eq!(e.readers.load(Ordering::Acquire), 0, "freeing epoch in use");
+    assert!(
+        e.pending.lock().unwrap().is_empty(),
+        "freeing undrained epoch"
+    );
+}
+
+pub unsafe fn _epoch_enter_preempt(epoch: epoch_t, _et: *mut epoch_tracker) {
+    unsafe { &*epoch }.readers.fetch_add(1, Ordering::AcqRel);
+    SECTIONS.with(|s| s.borrow_mut().push(epoch as usize));
+}
+
+pub unsafe fn epoch_exit_preempt(epoch: epoch_t, _et: *mut epoch_tracker) {
+    let last = SECTIONS.with(|s| s.borrow_mut().pop());
+    assert_eq!(last, Some(epoch as usize), "exiting epoch not entered");
+    unsafe { &*epoch }.readers.fetch_sub(1, Ordering::AcqRel);
+}
+
+pub unsafe fn in_epoch(epoch: epoch_t) -> c_int {
+    SECTIONS.with(|s| s.borrow().contains(&(epoch as usize))) as c_int
+}
+
+pub unsafe fn epoch_wait_preempt(epoch: epoch_t) {
+    while unsafe { &*epoch }.readers.load(Ordering::Acquire) != 0 {
+        thread::yield_now();
+    }
+}
+
+pub unsafe fn epoch_call(
+    epoch: epoch_t,
+    callback: epoch_callback_t,
+    ctx: epoch_context_t,
+) {
+    let pending = &unsafe { &*epoch }.pending;
+    pending.lock().unwrap().push(Pending(callback, ctx));
+}
+
+pub unsafe fn epoch_drain_callbacks(epoch: epoch_t) {
+    unsafe { epoch_wait_preempt(epoch) };
+    let pending =
+        std::mem::take(&mut *unsafe { &*epoch }.pending.lock().unwrap());
+    for Pending(callback, ctx) in pending {
+        unsafe { callback.unwrap()(ctx) };
+    }
+}
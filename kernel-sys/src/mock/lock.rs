@@ -0,0 +1,488 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `mutex(9)`, `rwlock(9)`, `sx(9)` and `condvar(9)` as spinning locks
+//!
+//! A mutex word is 0 when unowned and 1 when held. Reader-writer and sx
+//! lock words count readers, or are `WRITER` when held exclusively. A
+//! condition variable reuses `cv_waiters` as a wakeup sequence number:
+//! waiters spin until it changes, so every signal is effectively a
+//! broadcast, which the interface permits as a spurious wakeup. `sleep(9)`
+//! queues aren't kept at all: a sleep drops its interlock, if any, and
+//! waits out its timeout, or just yields without one. The `sleepqueue(9)`
+//! functions share one lock and one wakeup sequence number across all
+//! channels, so every `wakeup` wakes every waiter on a sleep queue.
+
+use super::{
+    C_ABSOLUTE, EWOULDBLOCK, bintime, binuptime, cv, lock_object, mtx, rwlock,
+    sbintime_t, sx,
+};
+use libc::{c_char, c_int, c_void};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+fn word<'a>(c: *mut usize) -> &'a AtomicUsize {
+    unsafe { AtomicUsize::from_ptr(c) }
+}
+
+pub unsafe fn _mtx_init(
+    c: *mut usize,
+    name: *const c_char,
+    _type_: *const c_char,
+    _opts: c_int,
+) {
+    // Recover the mutex from its lock word, like the kernel's mtxlock2mtx
+    let m = unsafe { (c as *mut u8).sub(core::mem::offset_of!(mtx, mtx_lock)) }
+        as *mut mtx;
+    unsafe { (*m).lock_object.lo_name = name };
+    word(c).store(0, Ordering::Release);
+}
+
+pub unsafe fn _mtx_destroy(c: *mut usize) {
+    assert_eq!(word(c).load(Ordering::Acquire), 0, "destroying owned mutex");
+}
+
+pub unsafe fn _mtx_lock_flags(
+    c: *mut usize,
+    _opts: c_int,
+    _file: *const c_char,
+    _line: c_int,
+) {
+    while word(c)
+        .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        thread::yield_now();
+    }
+}
+
+pub unsafe fn _mtx_trylock_flags_(
+    c: *mut usize,
+    _opts: c_int,
+    _file: *const c_char,
+    _line: c_int,
+) -> c_int {
+    word(c)
+        .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok() as c_int
+}
+
+pub unsafe fn _mtx_unlock_flags(
+    c: *mut usize,
+    _opts: c_int,
+    _file: *const c_char,
+    _line: c_int,
+) {
+    let prev = word(c).swap(0, Ordering::Release);
+    assert_eq!(prev, 1, "unlocking unowned mutex");
+}
+
+/// Interrupts aren't modelled, so spin mutexes are plain mutexes
+pub unsafe fn _mtx_lock_spin_flags(
+    c: *mut usize,
+    opts: c_int,
+    file: *const c_char,
+    line: c_int,
+) {
+    unsafe { _mtx_lock_flags(c, opts, file, line) }
+}
+
+pub unsafe fn _mtx_trylock_spin_flags(
+    c: *mut usize,
+    opts: c_int,
+    file: *const c_char,
+    line: c_int,
+) -> c_int {
+    unsafe { _mtx_trylock_flags_(c, opts, file, line) }
+}
+
+pub unsafe fn _mtx_unlock_spin_flags(
+    c: *mut usize,
+    opts: c_int,
+    file: *const c_char,
+    line: c_int,
+) {
+    unsafe { _mtx_unlock_flags(c, opts, file, line) }
+}
+
+/// Lock word of a reader-writer or sx lock held exclusively
+const WRITER: usize = usize::MAX;
+
+fn try_shared(c: *mut usize) -> bool {
+    let w = word(c);
+    let n = w.load(Ordering::Relaxed);
+    n != WRITER
+        && w.compare_exchange(n, n + 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+}
+
+fn try_exclusive(c: *mut usize) -> bool {
+    word(c)
+        .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+}
+
+fn release_shared(c: *mut usize) {
+    let prev = word(c).fetch_sub(1, Ordering::Release);
+    assert!(prev != 0 && prev != WRITER, "unlocking unowned shared lock");
+}
+
+fn release_exclusive(c: *mut usize) {
+    let prev = word(c).swap(0, Ordering::Release);
+    assert_eq!(prev, WRITER, "unlocking unowned exclusive lock");
+}
+
+fn downgrade(c: *mut usize) {
+    let prev = word(c).swap(1, Ordering::Release);
+    assert_eq!(prev, WRITER, "downgrading unowned lock");
+}
+
+fn spin_until(mut f: impl FnMut() -> bool) {
+    while !f() {
+        thread::yield_now();
+    }
+}
+
+pub unsafe fn _rw_init_flags(c: *mut usize, name: *const c_char, _opts: c_int) {
+    let rw =
+        unsafe { (c as *mut u8).sub(core::mem::offset_of!(rwlock, rw_lock)) }
+            as *mut rwlock;
+    unsafe { (*rw).lock_object.lo_name = name };
+    word(c).store(0, Ordering::Release);
+}
+
+pub unsafe fn _rw_destroy(c: *mut usize) {
+    assert_eq!(
+        word(c).load(Ordering::Acquire),
+        0,
+        "destroying owned rwlock"
+    );
+}
+
+pub unsafe fn __rw_rlock(c: *mut usize, _file: *const c_char, _line: c_int) {
+    spin_until(|| try_shared(c));
+}
+
+pub unsafe fn __rw_try_rlock(
+    c: *mut usize,
+    _file: *const c_char,
+    _line: c_int,
+) -> c_int {
+    try_shared(c) as c_int
+}
+
+pub unsafe fn _rw_runlock_cookie(
+    c: *mut usize,
+    _file: *const c_char,
+    _line: c_int,
+) {
+    release_shared(c);
+}
+
+pub unsafe fn _rw_wlock_cookie(
+    c: *mut usize,
+    _file: *const c_char,
+    _line: c_int,
+) {
+    spin_until(|| try_exclusive(c));
+}
+
+pub unsafe fn __rw_try_wlock(
+    c: *mut usize,
+    _file: *const c_char,
+    _line: c_int,
+) -> c_int {
+    try_exclusive(c) as c_int
+}
+
+pub unsafe fn _rw_wunlock_cookie(
+    c: *mut usize,
+    _file: *const c_char,
+    _line: c_int,
+) {
+    release_exclusive(c);
+}
+
+pub unsafe fn _rw_downgrade_cookie(
+    c: *mut usize,
+    _file: *const c_char,
+    _line: c_int,
+) {
+    downgrade(c);
+}
+
+fn sx_word(sx: *mut sx) -> *mut usize {
+    unsafe { &raw mut (*sx).sx_lock }
+}
+
+pub unsafe fn sx_init_flags(sx: *mut sx, desc: *const c_char, _opts: c_int) {
+    unsafe { (*sx).lock_object.lo_name = desc };
+    word(sx_word(sx)).store(0, Ordering::Release);
+}
+
+pub unsafe fn sx_destroy(sx: *mut sx) {
+    let n = word(sx_word(sx)).load(Ordering::Acquire);
+    assert_eq!(n, 0, "destroying owned sx lock");
+}
+
+/// Signals aren't modelled, so interruptible locking always succeeds
+pub unsafe fn _sx_slock(
+    sx: *mut sx,
+    _opts: c_int,
+    _file: *const c_char,
+    _line: c_int,
+) -> c_int {
+    spin_until(|| try_shared(sx_word(sx)));
+    0
+}
+
+pub unsafe fn sx_try_slock_(
+    sx: *mut sx,
+    _file: *const c_char,
+    _line: c_int,
+) -> c_int {
+    try_shared(sx_word(sx)) as c_int
+}
+
+pub unsafe fn _sx_sunlock(sx: *mut sx, _file: *const c_char, _line: c_int) {
+    release_shared(sx_word(sx));
+}
+
+pub unsafe fn _sx_xlock(
+    sx: *mut sx,
+    _opts: c_int,
+    _file: *const c_char,
+    _line: c_int,
+) -> c_int {
+    spin_until(|| try_exclusive(sx_word(sx)));
+    0
+}
+
+pub unsafe fn sx_try_xlock_(
+    sx: *mut sx,
+    _file: *const c_char,
+    _line: c_int,
+) -> c_int {
+    try_exclusive(sx_word(sx)) as c_int
+}
+
+pub unsafe fn _sx_xunlock(sx: *mut sx, _file: *const c_char, _line: c_int) {
+    release_exclusive(sx_word(sx));
+}
+
+pub unsafe fn sx_downgrade_(sx: *mut sx, _file: *const c_char, _line: c_int) {
+    downgrade(sx_word(sx));
+}
+
+fn lock_word(lock: *mut lock_object) -> *mut usize {
+    // Only mutexes are passed as the interlock
+    unsafe { &raw mut (*(lock as *mut mtx)).mtx_lock }
+}
+
+fn seq<'a>(cvp: *mut cv) -> &'a AtomicI32 {
+    unsafe { AtomicI32::from_ptr(&raw mut (*cvp).cv_waiters) }
+}
+
+fn uptime() -> sbintime_t {
+    let mut bt = bintime::default();
+    unsafe { binuptime(&mut bt) };
+    (bt.sec << 32) + (bt.frac >> 32) as i64
+}
+
+/// Release `lock`, spin until `cvp` is signalled or `deadline` passes,
+/// then reacquire it
+unsafe fn wait(
+    cvp: *mut cv,
+    lock: *mut lock_object,
+    deadline: Option<sbintime_t>,
+) -> c_int {
+    let c = lock_word(lock);
+    let start = seq(cvp).load(Ordering::Acquire);
+    unsafe { _mtx_unlock_flags(c, 0, core::ptr::null(), 0) };
+    let mut ret = 0;
+    while seq(cvp).load(Ordering::Acquire) == start {
+        if deadline.is_some_and(|d| uptime() >= d) {
+            ret = EWOULDBLOCK;
+            break;
+        }
+        thread::yield_now();
+    }
+    unsafe { _mtx_lock_flags(c, 0, core::ptr::null(), 0) };
+    ret
+}
+
+pub unsafe fn cv_init(cvp: *mut cv, desc: *const c_char) {
+    unsafe {
+        (*cvp).cv_description = desc;
+        (*cvp).cv_waiters = 0;
+    }
+}
+
+pub unsafe fn cv_destroy(_cvp: *mut cv) {}
+
+pub unsafe fn _cv_wait(cvp: *mut cv, lock: *mut lock_object) {
+    unsafe { wait(cvp, lock, None) };
+}
+
+pub unsafe fn _cv_wait_unlock(cvp: *mut cv, lock: *mut lock_object) {
+    unsafe {
+        wait(cvp, lock, None);
+        _mtx_unlock_flags(lock_word(lock), 0, core::ptr::null(), 0);
+    }
+}
+
+/// There are no signals to interrupt the sleep
+pub unsafe fn _cv_wait_sig(cvp: *mut cv, lock: *mut lock_object) -> c_int {
+    unsafe { wait(cvp, lock, None) }
+}
+
+pub unsafe fn _cv_timedwait_sbt(
+    cvp: *mut cv,
+    lock: *mut lock_object,
+    sbt: sbintime_t,
+    _pr: sbintime_t,
+    flags: c_int,
+) -> c_int {
+    // As in the kernel, a relative timeout of 0 waits forever
+    let deadline = if flags & C_ABSOLUTE != 0 {
+        Some(sbt)
+    } else if sbt == 0 {
+        None
+    } else {
+        Some(uptime().saturating_add(sbt))
+    };
+    unsafe { wait(cvp, lock, deadline) }
+}
+
+pub unsafe fn _cv_timedwait_sig_sbt(
+    cvp: *mut cv,
+    lock: *mut lock_object,
+    sbt: sbintime_t,
+    pr: sbintime_t,
+    flags: c_int,
+) -> c_int {
+    unsafe { _cv_timedwait_sbt(cvp, lock, sbt, pr, flags) }
+}
+
+pub unsafe fn cv_signal(cvp: *mut cv) {
+    seq(cvp).fetch_add(1, Ordering::AcqRel);
+}
+
+pub unsafe fn cv_broadcastpri(cvp: *mut cv, _pri: c_int) {
+    seq(cvp).fetch_add(1, Ordering::AcqRel);
+}
+
+pub unsafe fn _sleep(
+    _chan: *const c_void,
+    lock: *mut lock_object,
+    _pri: c_int,
+    _wmesg: *const c_char,
+    sbt: sbintime_t,
+    _pr: sbintime_t,
+    _flags: c_int,
+) -> c_int {
+    if !lock.is_null() {
+        unsafe { _mtx_unlock_flags(lock_word(lock), 0, core::ptr::null(), 0) };
+    }
+    // Untimed sleeps return as if woken, spuriously
+    let ret = if sbt > 0 {
+        let nanos = (sbt as u128 * 1_000_000_000) >> 32;
+        thread::sleep(Duration::from_nanos(nanos as u64));
+        EWOULDBLOCK
+    } else {
+        thread::yield_now();
+        0
+    };
+    if !lock.is_null() {
+        unsafe { _mtx_lock_flags(lock_word(lock), 0, core::ptr::null(), 0) };
+    }
+    ret
+}
+
+pub unsafe fn pause_sbt(
+    wmesg: *const c_char,
+    sbt: sbintime_t,
+    pr: sbintime_t,
+    flags: c_int,
+) -> c_int {
+    let chan = &raw const wmesg as *const c_void;
+    unsafe { _sleep(chan, core::ptr::null_mut(), 0, wmesg, sbt, pr, flags) }
+}
+
+/// Held by whoever is between `sleepq_lock` and `sleepq_release`
+static SLEEPQ_LOCK: AtomicBool = AtomicBool::new(false);
+/// Bumped by every `wakeup`
+static SLEEPQ_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// `SLEEPQ_SEQ` as of this thread's `sleepq_add`
+    static SLEEPQ_ADDED: Cell<usize> = const { Cell::new(0) };
+}
+
+pub unsafe fn sleepq_lock(_wchan: *const c_void) {
+    while SLEEPQ_LOCK
+        .compare_exchange_weak(
+            false,
+            true,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .is_err()
+    {
+        std::hint::spin_loop();
+    }
+}
+
+pub unsafe fn sleepq_release(_wchan: *const c_void) {
+    SLEEPQ_LOCK.store(false, Ordering::Release);
+}
+
+pub unsafe fn sleepq_add(
+    _wchan: *const c_void,
+    _lock: *mut lock_object,
+    _wmesg: *const c_char,
+    _flags: c_int,
+    _queue: c_int,
+) {
+    SLEEPQ_ADDED.set(SLEEPQ_SEQ.load(Ordering::Relaxed));
+}
+
+pub unsafe fn sleepq_wait(wchan: *const c_void, _pri: c_int) {
+    let added = SLEEPQ_ADDED.get();
+    unsafe { sleepq_release(wchan) };
+    while SLEEPQ_SEQ.load(Ordering::Acquire) == added {
+        thread::yield_now();
+    }
+}
+
+pub unsafe fn wakeup(chan: *const c_void) {
+    unsafe { sleepq_lock(chan) };
+    SLEEPQ_SEQ.fetch_add(1, Ordering::Release);
+    unsafe { sleepq_release(chan) };
+}
+
+pub unsafe fn wakeup_one(chan: *const c_void) {
+    unsafe { wakeup(chan) };
+}
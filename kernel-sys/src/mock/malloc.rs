@@ -0,0 +1,80 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `malloc(9)` on top of the Rust allocator
+
+use super::{M_NOWAIT, M_ZERO, malloc_type};
+use libc::{c_int, c_void};
+use std::alloc::{self, Layout};
+use std::ptr;
+
+pub static mut M_DEVBUF: [malloc_type; 1] = [malloc_type {
+    ks_next: ptr::null_mut(),
+    ks_version: 0,
+    ks_shortdesc: c"devbuf".as_ptr(),
+    ks_handle: ptr::null_mut(),
+}];
+
+// Each allocation is prefixed with its size so free() can rebuild the
+// layout; the header keeps the kernel's 16-byte alignment
+const HEADER: usize = 16;
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(HEADER + size, HEADER).unwrap()
+}
+
+pub unsafe fn malloc(
+    size: usize,
+    _type_: *mut malloc_type,
+    flags: c_int,
+) -> *mut c_void {
+    let layout = layout(size);
+    let base = unsafe {
+        if flags & M_ZERO != 0 {
+            alloc::alloc_zeroed(layout)
+        } else {
+            alloc::alloc(layout)
+        }
+    };
+    if base.is_null() {
+        if flags & M_NOWAIT != 0 {
+            return ptr::null_mut();
+        }
+        alloc::handle_alloc_error(layout);
+    }
+    unsafe {
+        (base as *mut usize).write(size);
+        base.add(HEADER) as *mut c_void
+    }
+}
+
+pub unsafe fn free(addr: *mut c_void, _type_: *mut malloc_type) {
+    if addr.is_null() {
+        return;
+    }
+    unsafe {
+        let base = (addr as *mut u8).sub(HEADER);
+        let size = (base as *const usize).read();
+        alloc::dealloc(base, layout(size));
+    }
+}
@@ -0,0 +1,519 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Userspace stand-ins for the kernel interfaces used by `bsd-kernel`
+//!
+//! Enabled by the `mock` feature in place of the generated bindings, so
+//! module logic can be unit-tested (and run under Miri) on a development
+//! machine. The types keep the field names of their kernel counterparts
+//! but not their layout, and the functions implement just enough of the
+//! kernel's behaviour for a single process: memory comes from the Rust
+//! allocator, locks spin, counters have a single slot, and devices live
+//! in a table that tests drive through `mock::dev`;
+//! `mock::cred` sets the credentials they are called with,
+//! `mock::uiomove::MockUio` builds requests for testing `uio` consumers
+//! directly, `mock::devctl` keeps the events sent to devd, and
+//! `mock::sysctl` reads and writes oids by name. Console output goes to
+//! stdout unless captured with `mock::dev::capture`.
+
+use libc::{c_char, c_int, c_long, c_uchar, c_uint, c_ulong, c_ushort, c_void};
+
+pub use self::copy::{
+    copyin, copyinstr, copyout, fubyte, fueword32, fueword64, subyte, suword32,
+    suword64,
+};
+pub use self::counter::{
+    counter_u64_alloc, counter_u64_fetch, counter_u64_free, counter_u64_t,
+    counter_u64_zero,
+};
+pub use self::cred::{groupmember, jailed, priv_check, priv_check_cred};
+pub use self::dev::{
+    destroy_dev, log, make_dev_args_init_impl, make_dev_s, printf, seldrain,
+    selrecord, selwakeup, selwakeuppri, uprintf,
+};
+pub use self::devctl::{devctl_notify, devctl_process_running};
+pub use self::kern_epoch::{
+    _epoch_enter_preempt, EPOCH_PREEMPT, epoch, epoch_alloc, epoch_call,
+    epoch_callback_t, epoch_context, epoch_context_t, epoch_drain_callbacks,
+    epoch_exit_preempt, epoch_free, epoch_t, epoch_tracker, epoch_wait_preempt,
+    global_epoch_preempt, in_epoch, net_epoch_preempt,
+};
+pub use self::libkern::calculate_crc32c;
+pub use self::lock::{
+    __rw_rlock, __rw_try_rlock, __rw_try_wlock, _cv_timedwait_sbt,
+    _cv_timedwait_sig_sbt, _cv_wait, _cv_wait_sig, _cv_wait_unlock,
+    _mtx_destroy, _mtx_init, _mtx_lock_flags, _mtx_lock_spin_flags,
+    _mtx_trylock_flags_, _mtx_trylock_spin_flags, _mtx_unlock_flags,
+    _mtx_unlock_spin_flags, _rw_destroy, _rw_downgrade_cookie, _rw_init_flags,
+    _rw_runlock_cookie, _rw_wlock_cookie, _rw_wunlock_cookie, _sleep,
+    _sx_slock, _sx_sunlock, _sx_xlock, _sx_xunlock, cv_broadcastpri,
+    cv_destroy, cv_init, cv_signal, pause_sbt, sx_destroy, sx_downgrade_,
+    sleepq_add, sleepq_lock, sleepq_release, sleepq_wait, sx_init_flags,
+    sx_try_slock_, sx_try_xlock_, wakeup, wakeup_one,
+};
+pub use self::malloc::{M_DEVBUF, free, malloc};
+pub use self::random::{
+    arc4random, arc4random_buf, is_random_seeded, read_random,
+};
+pub use self::subr_sbuf::{
+    SBUF_AUTOEXTEND, SBUF_FIXEDLEN, SBUF_NOWAIT, sbuf, sbuf_bcat, sbuf_clear,
+    sbuf_data, sbuf_delete, sbuf_error, sbuf_finish, sbuf_len, sbuf_new,
+    sbuf_putc,
+};
+pub use self::sysctl::{
+    CTLFLAG_MPSAFE, CTLFLAG_RD, CTLFLAG_RW, CTLFLAG_STATS, CTLFLAG_WR,
+    CTLTYPE, CTLTYPE_INT, CTLTYPE_NODE, CTLTYPE_OPAQUE, CTLTYPE_S64,
+    CTLTYPE_STRING, CTLTYPE_U8, CTLTYPE_U64, CTLTYPE_UINT, OID_AUTO,
+    intmax_t, sysctl__debug_children, sysctl__dev_children,
+    sysctl__hw_children, sysctl__kern_children, sysctl_add_oid,
+    sysctl_ctx_free, sysctl_ctx_init, sysctl_ctx_list, sysctl_handle_64,
+    sysctl_handle_bool, sysctl_handle_counter_u64, sysctl_handle_int,
+    sysctl_oid, sysctl_oid_list, sysctl_req, sysctl_wire_old_buffer,
+};
+pub use self::time::{
+    binuptime, eventratecheck, getbinuptime, getnanotime, hz, nanotime,
+};
+pub use self::uiomove::{uiomove, uiomove_frombuf};
+pub use self::uma::{
+    uma_ctor, uma_dtor, uma_fini, uma_init, uma_zalloc_arg, uma_zcreate,
+    uma_zdestroy, uma_zfree_arg, uma_zone, uma_zone_get_cur, uma_zone_get_max,
+    uma_zone_set_max, uma_zone_set_warning, uma_zone_t,
+};
+pub use self::unr::{
+    alloc_unr, alloc_unr_specific, clear_unrhdr, delete_unrhdr, free_unr,
+    new_unrhdr, unrhdr,
+};
+
+mod copy;
+mod counter;
+pub mod cred;
+pub mod dev;
+pub mod devctl;
+mod kern_epoch;
+mod libkern;
+mod lock;
+mod malloc;
+mod random;
+mod subr_sbuf;
+pub mod sysctl;
+mod time;
+pub mod uiomove;
+mod uma;
+mod unr;
+
+pub type u_int = c_uint;
+pub type u_long = c_ulong;
+pub type u_char = c_uchar;
+pub type u_short = c_ushort;
+pub type uid_t = u32;
+pub type gid_t = u32;
+pub type off_t = i64;
+pub type ssize_t = i64;
+pub type lwpid_t = i32;
+pub type pid_t = i32;
+pub type time_t = i64;
+pub type sbintime_t = i64;
+pub type suseconds_t = i64;
+pub type caddr_t = *mut c_char;
+
+pub type modeventtype = c_uint;
+pub const modeventtype_MOD_LOAD: modeventtype = 0;
+pub const modeventtype_MOD_UNLOAD: modeventtype = 1;
+pub const modeventtype_MOD_SHUTDOWN: modeventtype = 2;
+pub const modeventtype_MOD_QUIESCE: modeventtype = 3;
+pub use self::modeventtype as modeventtype_t;
+
+#[repr(C)]
+pub struct module {
+    _unused: [u8; 0],
+}
+pub type module_t = *mut module;
+pub type modeventhand_t = Option<
+    unsafe extern "C" fn(
+        arg1: module_t,
+        arg2: c_int,
+        arg3: *mut c_void,
+    ) -> c_int,
+>;
+
+#[repr(C)]
+pub struct malloc_type {
+    pub ks_next: *mut malloc_type,
+    pub ks_version: u_long,
+    pub ks_shortdesc: *const c_char,
+    pub ks_handle: *mut c_void,
+}
+
+pub const __FreeBSD_version: i32 = 1500000;
+pub static mut osreldate: c_int = __FreeBSD_version;
+
+pub const M_NOWAIT: i32 = 1;
+pub const M_WAITOK: i32 = 2;
+pub const M_ZERO: i32 = 256;
+pub const M_VERSION: u32 = 2020110501;
+
+#[repr(C)]
+pub struct thread {
+    pub td_tid: lwpid_t,
+    pub td_ucred: *mut ucred,
+    pub td_proc: *mut proc_,
+}
+#[repr(C)]
+pub struct proc_ {
+    pub p_pid: pid_t,
+    pub p_sysent: *mut sysentvec,
+}
+#[repr(C)]
+pub struct sysentvec {
+    pub sv_flags: u_int,
+}
+pub const SV_ILP32: i32 = 0x100;
+#[repr(C)]
+pub struct ucred {
+    pub cr_uid: uid_t,
+    pub cr_ruid: uid_t,
+    pub cr_gid: gid_t,
+    pub cr_rgid: gid_t,
+    pub cr_svuid: uid_t,
+    pub cr_svgid: gid_t,
+    pub cr_ngroups: c_int,
+    pub cr_groups: *mut gid_t,
+    pub cr_prison: *mut prison,
+}
+#[repr(C)]
+pub struct prison {
+    _unused: [u8; 0],
+}
+pub const PRIV_IO: u32 = 12;
+pub const PRIV_DRIVER: u32 = 14;
+pub const PRIV_SYSCTL_WRITE: u32 = 241;
+pub const PRIV_NET_SETIFMTU: u32 = 402;
+pub const PRIV_NET_SETIFFLAGS: u32 = 403;
+pub const PRIV_NET_IFCREATE: u32 = 415;
+#[repr(C)]
+pub struct file {
+    _unused: [u8; 0],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct iovec {
+    pub iov_base: *mut c_void,
+    pub iov_len: usize,
+}
+pub type uio_rw = c_uint;
+pub const uio_rw_UIO_READ: uio_rw = 0;
+pub const uio_rw_UIO_WRITE: uio_rw = 1;
+pub type uio_seg = c_uint;
+pub const uio_seg_UIO_USERSPACE: uio_seg = 0;
+pub const uio_seg_UIO_SYSSPACE: uio_seg = 1;
+pub const uio_seg_UIO_NOCOPY: uio_seg = 2;
+#[repr(C)]
+#[derive(Debug)]
+pub struct uio {
+    pub uio_iov: *mut iovec,
+    pub uio_iovcnt: c_int,
+    pub uio_offset: off_t,
+    pub uio_resid: isize,
+    pub uio_segflg: uio_seg,
+    pub uio_rw: uio_rw,
+    pub uio_td: *mut thread,
+}
+pub const IOSIZE_MAX: isize = isize::MAX;
+
+#[repr(C)]
+pub struct lock_object {
+    pub lo_name: *const c_char,
+    pub lo_flags: u_int,
+    pub lo_data: u_int,
+    pub lo_witness: *mut c_void,
+}
+#[repr(C)]
+pub struct mtx {
+    pub lock_object: lock_object,
+    pub mtx_lock: usize,
+}
+pub const MTX_DEF: i32 = 0;
+pub const MTX_SPIN: i32 = 1;
+pub const MTX_QUIET: i32 = 2;
+pub const MTX_RECURSE: i32 = 4;
+pub const MTX_NOWITNESS: i32 = 8;
+pub const MTX_DUPOK: i32 = 16;
+pub const MTX_NOPROFILE: i32 = 32;
+pub const MTX_NEW: i32 = 64;
+#[repr(C)]
+pub struct rwlock {
+    pub lock_object: lock_object,
+    pub rw_lock: usize,
+}
+pub const RW_DUPOK: i32 = 1;
+pub const RW_NEW: i32 = 32;
+#[repr(C)]
+pub struct sx {
+    pub lock_object: lock_object,
+    pub sx_lock: usize,
+}
+pub const SX_DUPOK: i32 = 1;
+pub const SX_NEW: i32 = 64;
+pub const SX_INTERRUPTIBLE: i32 = 64;
+#[repr(C)]
+pub struct cv {
+    pub cv_description: *const c_char,
+    pub cv_waiters: c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bintime {
+    pub sec: time_t,
+    pub frac: u64,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct timeval {
+    pub tv_sec: time_t,
+    pub tv_usec: suseconds_t,
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct timespec {
+    pub tv_sec: time_t,
+    pub tv_nsec: c_long,
+}
+pub const SBT_1S: i64 = 1 << 32;
+pub const SBT_1MS: i64 = 4294967;
+pub const SBT_1US: i64 = 4294;
+pub const SBT_1NS: i64 = 4;
+pub const C_DIRECT_EXEC: i32 = 1;
+pub const C_HARDCLOCK: i32 = 256;
+pub const C_ABSOLUTE: i32 = 512;
+pub const PCATCH: i32 = 256;
+pub const SLEEPQ_SLEEP: i32 = 0;
+
+#[repr(C)]
+pub struct cdev {
+    pub si_drv1: *mut c_void,
+    pub si_drv2: *mut c_void,
+    pub si_devsw: *mut cdevsw,
+}
+pub type d_open_t =
+    Option<unsafe extern "C" fn(*mut cdev, c_int, c_int, *mut thread) -> c_int>;
+pub type d_fdopen_t = Option<
+    unsafe extern "C" fn(*mut cdev, c_int, *mut thread, *mut file) -> c_int,
+>;
+pub type d_close_t =
+    Option<unsafe extern "C" fn(*mut cdev, c_int, c_int, *mut thread) -> c_int>;
+pub type d_read_t =
+    Option<unsafe extern "C" fn(*mut cdev, *mut uio, c_int) -> c_int>;
+pub type d_write_t =
+    Option<unsafe extern "C" fn(*mut cdev, *mut uio, c_int) -> c_int>;
+pub type d_ioctl_t = Option<
+    unsafe extern "C" fn(
+        *mut cdev,
+        u_long,
+        caddr_t,
+        c_int,
+        *mut thread,
+    ) -> c_int,
+>;
+pub type d_poll_t =
+    Option<unsafe extern "C" fn(*mut cdev, c_int, *mut thread) -> c_int>;
+#[repr(C)]
+pub struct cdevsw {
+    pub d_version: c_int,
+    pub d_flags: u_int,
+    pub d_name: *const c_char,
+    pub d_open: d_open_t,
+    pub d_fdopen: d_fdopen_t,
+    pub d_close: d_close_t,
+    pub d_read: d_read_t,
+    pub d_write: d_write_t,
+    pub d_ioctl: d_ioctl_t,
+    pub d_poll: d_poll_t,
+    pub d_mmap: *mut c_void,
+    pub d_strategy: *mut c_void,
+    pub d_dump: *mut c_void,
+    pub d_kqfilter: *mut c_void,
+    pub d_purge: *mut c_void,
+    pub d_mmap_single: *mut c_void,
+}
+#[repr(C)]
+pub struct make_dev_args {
+    pub mda_size: usize,
+    pub mda_flags: c_int,
+    pub mda_devsw: *mut cdevsw,
+    pub mda_cr: *mut ucred,
+    pub mda_uid: uid_t,
+    pub mda_gid: gid_t,
+    pub mda_mode: c_int,
+    pub mda_unit: c_int,
+    pub mda_si_drv1: *mut c_void,
+    pub mda_si_drv2: *mut c_void,
+}
+pub const D_VERSION: i32 = 0x17122009;
+pub const D_TRACKCLOSE: i32 = 0x00080000;
+pub const D_MMAP_ANON: i32 = 0x00100000;
+pub const D_NEEDGIANT: i32 = 0x00400000;
+pub const MAKEDEV_REF: i32 = 1;
+pub const MAKEDEV_WHTOUT: i32 = 2;
+pub const MAKEDEV_NOWAIT: i32 = 4;
+pub const MAKEDEV_WAITOK: i32 = 8;
+pub const MAKEDEV_ETERNAL: i32 = 16;
+pub const MAKEDEV_CHECKNAME: i32 = 32;
+pub const UID_ROOT: i32 = 0;
+pub const GID_WHEEL: i32 = 0;
+
+#[repr(C)]
+pub struct knlist {
+    pub kl_list: [*mut c_void; 1],
+    pub kl_lockarg: *mut c_void,
+}
+#[repr(C)]
+pub struct selinfo {
+    pub si_tdlist: [*mut c_void; 2],
+    pub si_note: knlist,
+    pub si_mtx: *mut mtx,
+}
+pub const POLLIN: i32 = 1;
+pub const POLLPRI: i32 = 2;
+pub const POLLOUT: i32 = 4;
+pub const POLLERR: i32 = 8;
+pub const POLLHUP: i32 = 16;
+pub const POLLNVAL: i32 = 32;
+pub const POLLRDNORM: i32 = 64;
+pub const POLLWRNORM: i32 = 4;
+pub const O_NONBLOCK: i32 = 4;
+pub const IO_NDELAY: i32 = 4;
+
+pub const LOG_EMERG: u32 = 0;
+pub const LOG_ALERT: u32 = 1;
+pub const LOG_CRIT: u32 = 2;
+pub const LOG_ERR: u32 = 3;
+pub const LOG_WARNING: u32 = 4;
+pub const LOG_NOTICE: u32 = 5;
+pub const LOG_INFO: u32 = 6;
+pub const LOG_DEBUG: u32 = 7;
+
+pub const EPERM: i32 = 1;
+pub const ENOENT: i32 = 2;
+pub const ESRCH: i32 = 3;
+pub const EINTR: i32 = 4;
+pub const EIO: i32 = 5;
+pub const ENXIO: i32 = 6;
+pub const E2BIG: i32 = 7;
+pub const ENOEXEC: i32 = 8;
+pub const EBADF: i32 = 9;
+pub const ECHILD: i32 = 10;
+pub const EDEADLK: i32 = 11;
+pub const ENOMEM: i32 = 12;
+pub const EACCES: i32 = 13;
+pub const EFAULT: i32 = 14;
+pub const ENOTBLK: i32 = 15;
+pub const EBUSY: i32 = 16;
+pub const EEXIST: i32 = 17;
+pub const EXDEV: i32 = 18;
+pub const ENODEV: i32 = 19;
+pub const ENOTDIR: i32 = 20;
+pub const EISDIR: i32 = 21;
+pub const EINVAL: i32 = 22;
+pub const ENFILE: i32 = 23;
+pub const EMFILE: i32 = 24;
+pub const ENOTTY: i32 = 25;
+pub const ETXTBSY: i32 = 26;
+pub const EFBIG: i32 = 27;
+pub const ENOSPC: i32 = 28;
+pub const ESPIPE: i32 = 29;
+pub const EROFS: i32 = 30;
+pub const EMLINK: i32 = 31;
+pub const EPIPE: i32 = 32;
+pub const EDOM: i32 = 33;
+pub const ERANGE: i32 = 34;
+pub const EAGAIN: i32 = 35;
+pub const EINPROGRESS: i32 = 36;
+pub const EALREADY: i32 = 37;
+pub const ENOTSOCK: i32 = 38;
+pub const EDESTADDRREQ: i32 = 39;
+pub const EMSGSIZE: i32 = 40;
+pub const EPROTOTYPE: i32 = 41;
+pub const ENOPROTOOPT: i32 = 42;
+pub const EPROTONOSUPPORT: i32 = 43;
+pub const ESOCKTNOSUPPORT: i32 = 44;
+pub const EOPNOTSUPP: i32 = 45;
+pub const EPFNOSUPPORT: i32 = 46;
+pub const EAFNOSUPPORT: i32 = 47;
+pub const EADDRINUSE: i32 = 48;
+pub const EADDRNOTAVAIL: i32 = 49;
+pub const ENETDOWN: i32 = 50;
+pub const ENETUNREACH: i32 = 51;
+pub const ENETRESET: i32 = 52;
+pub const ECONNABORTED: i32 = 53;
+pub const ECONNRESET: i32 = 54;
+pub const ENOBUFS: i32 = 55;
+pub const EISCONN: i32 = 56;
+pub const ENOTCONN: i32 = 57;
+pub const ESHUTDOWN: i32 = 58;
+pub const ETOOMANYREFS: i32 = 59;
+pub const ETIMEDOUT: i32 = 60;
+pub const ECONNREFUSED: i32 = 61;
+pub const ELOOP: i32 = 62;
+pub const ENAMETOOLONG: i32 = 63;
+pub const EHOSTDOWN: i32 = 64;
+pub const EHOSTUNREACH: i32 = 65;
+pub const ENOTEMPTY: i32 = 66;
+pub const EPROCLIM: i32 = 67;
+pub const EUSERS: i32 = 68;
+pub const EDQUOT: i32 = 69;
+pub const ESTALE: i32 = 70;
+pub const EREMOTE: i32 = 71;
+pub const EBADRPC: i32 = 72;
+pub const ERPCMISMATCH: i32 = 73;
+pub const EPROGUNAVAIL: i32 = 74;
+pub const EPROGMISMATCH: i32 = 75;
+pub const EPROCUNAVAIL: i32 = 76;
+pub const ENOLCK: i32 = 77;
+pub const ENOSYS: i32 = 78;
+pub const EFTYPE: i32 = 79;
+pub const EAUTH: i32 = 80;
+pub const ENEEDAUTH: i32 = 81;
+pub const EIDRM: i32 = 82;
+pub const ENOMSG: i32 = 83;
+pub const EOVERFLOW: i32 = 84;
+pub const ECANCELED: i32 = 85;
+pub const EILSEQ: i32 = 86;
+pub const ENOATTR: i32 = 87;
+pub const EDOOFUS: i32 = 88;
+pub const EBADMSG: i32 = 89;
+pub const EMULTIHOP: i32 = 90;
+pub const ENOLINK: i32 = 91;
+pub const EPROTO: i32 = 92;
+pub const ENOTCAPABLE: i32 = 93;
+pub const ECAPMODE: i32 = 94;
+pub const ENOTRECOVERABLE: i32 = 95;
+pub const EOWNERDEAD: i32 = 96;
+pub const EINTEGRITY: i32 = 97;
+pub const ERESTART: i32 = -1;
+pub const EJUSTRETURN: i32 = -2;
+pub const ENOIOCTL: i32 = -3;
+pub const EDIRIOCTL: i32 = -4;
+pub const ERELOOKUP: i32 = -5;
+pub const EWOULDBLOCK: i32 = 35;
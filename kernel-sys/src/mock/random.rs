@@ -0,0 +1,50 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `random(9)`, from the host's `/dev/urandom`
+
+use libc::{c_uint, c_void};
+use std::fs::File;
+use std::io::Read;
+
+pub unsafe fn arc4random_buf(ptr: *mut c_void, len: usize) {
+    let buf = unsafe { std::slice::from_raw_parts_mut(ptr.cast::<u8>(), len) };
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(buf))
+        .unwrap();
+}
+
+pub unsafe fn arc4random() -> u32 {
+    let mut bytes = [0u8; 4];
+    unsafe { arc4random_buf(bytes.as_mut_ptr().cast(), bytes.len()) };
+    u32::from_ne_bytes(bytes)
+}
+
+pub unsafe fn is_random_seeded() -> bool {
+    true
+}
+
+pub unsafe fn read_random(ptr: *mut c_void, len: c_uint) -> c_uint {
+    unsafe { arc4random_buf(ptr, len as usize) };
+    len
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `sbuf(9)` over a `Vec`, which a fixed-length sbuf doesn't grow past
+
+use super::{ENOMEM, ssize_t};
+use libc::{c_char, c_int, c_void};
+use std::slice;
+
+pub const SBUF_FIXEDLEN: u32 = 0;
+pub const SBUF_AUTOEXTEND: u32 = 1;
+pub const SBUF_NOWAIT: u32 = 8;
+
+pub struct sbuf {
+    s_buf: Vec<u8>,
+    s_size: usize,
+    s_flags: c_int,
+    s_error: c_int,
+}
+
+pub unsafe fn sbuf_new(
+    s: *mut sbuf,
+    buf: *mut c_char,
+    length: c_int,
+    flags: c_int,
+) -> *mut sbuf {
+    assert!(s.is_null() && buf.is_null(), "sbuf_new: only allocates");
+    Box::into_raw(Box::new(sbuf {
+        s_buf: Vec::with_capacity(length as usize),
+        s_size: length as usize,
+        s_flags: flags,
+        s_error: 0,
+    }))
+}
+
+pub unsafe fn sbuf_delete(s: *mut sbuf) {
+    drop(unsafe { Box::from_raw(s) });
+}
+
+pub unsafe fn sbuf_bcat(s: *mut sbuf, buf: *const c_void, len: usize) -> c_int {
+    let s = unsafe { &mut *s };
+    if s.s_error != 0 {
+        return -1;
+    }
+    let bytes = unsafe { slice::from_raw_parts(buf as *const u8, len) };
+    let room = match s.s_flags as u32 & SBUF_AUTOEXTEND {
+        0 => s.s_size.saturating_sub(s.s_buf.len() + 1),
+        _ => len,
+    };
+    // As the kernel does, keep what fits
+    s.s_buf.extend_from_slice(&bytes[..len.min(room)]);
+    if room < len {
+        s.s_error = ENOMEM;
+        return -1;
+    }
+    0
+}
+
+pub unsafe fn sbuf_putc(s: *mut sbuf, c: c_int) -> c_int {
+    let byte = c as u8;
+    unsafe { sbuf_bcat(s, &raw const byte as *const c_void, 1) }
+}
+
+pub unsafe fn sbuf_clear(s: *mut sbuf) {
+    let s = unsafe { &mut *s };
+    s.s_buf.clear();
+    s.s_error = 0;
+}
+
+pub unsafe fn sbuf_error(s: *const sbuf) -> c_int {
+    unsafe { &*s }.s_error
+}
+
+pub unsafe fn sbuf_len(s: *mut sbuf) -> ssize_t {
+    let s = unsafe { &*s };
+    match s.s_error {
+        0 => s.s_buf.len() as ssize_t,
+        _ => -1,
+    }
+}
+
+pub unsafe fn sbuf_finish(s: *mut sbuf) -> c_int {
+    unsafe { &*s }.s_error
+}
+
+pub unsafe fn sbuf_data(s: *mut sbuf) -> *mut c_char {
+    let s = unsafe { &mut *s };
+    // The NUL is past the end, so appending again overwrites it
+    s.s_buf.reserve(1);
+    let end = s.s_buf.len();
+    unsafe { s.s_buf.as_mut_ptr().add(end).write(0) };
+    s.s_buf.as_mut_ptr() as *mut c_char
+}
@@ -0,0 +1,410 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The sysctl tree, for reading and writing oids by name from tests
+//!
+//! `sysctl_add_oid` and `sysctl_ctx_free` keep the tree as the kernel
+//! does, sharing nodes between contexts, and `read` and `write` find an
+//! oid by its dotted name and run its handler with a request that copies
+//! to and from a `Vec` the way `sysctl(3)` copies to user memory.
+
+use super::counter::{counter_u64_fetch, counter_u64_t, counter_u64_zero};
+use super::{EINVAL, EISDIR, ENOENT, ENOMEM, EPERM};
+use libc::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::sync::RwLock;
+
+pub type intmax_t = i64;
+
+pub const OID_AUTO: c_int = -1;
+pub const CTLTYPE: c_int = 0xf;
+pub const CTLTYPE_NODE: c_int = 1;
+pub const CTLTYPE_INT: c_int = 2;
+pub const CTLTYPE_STRING: c_int = 3;
+pub const CTLTYPE_S64: c_int = 4;
+pub const CTLTYPE_OPAQUE: c_int = 5;
+pub const CTLTYPE_UINT: c_int = 6;
+pub const CTLTYPE_U64: c_int = 9;
+pub const CTLTYPE_U8: c_int = 0xa;
+pub const CTLFLAG_RD: u32 = 0x80000000;
+pub const CTLFLAG_WR: u32 = 0x40000000;
+pub const CTLFLAG_RW: u32 = CTLFLAG_RD | CTLFLAG_WR;
+pub const CTLFLAG_MPSAFE: c_int = 0x00040000;
+pub const CTLFLAG_STATS: c_int = 0x00002000;
+
+pub type sysctl_handler_t = unsafe extern "C" fn(
+    *mut sysctl_oid,
+    *mut c_void,
+    intmax_t,
+    *mut sysctl_req,
+) -> c_int;
+
+pub struct sysctl_oid_list {
+    oids: Vec<*mut sysctl_oid>,
+}
+
+impl sysctl_oid_list {
+    const fn new() -> Self {
+        sysctl_oid_list { oids: Vec::new() }
+    }
+}
+
+pub struct sysctl_oid {
+    pub oid_children: sysctl_oid_list,
+    oid_parent: *mut sysctl_oid_list,
+    oid_name: CString,
+    oid_kind: c_int,
+    oid_arg1: *mut c_void,
+    oid_arg2: intmax_t,
+    oid_handler: Option<sysctl_handler_t>,
+    oid_refcnt: u32,
+}
+
+/// The oids added through a context, in order; null until
+/// `sysctl_ctx_init`, so that a zeroed list is valid
+pub struct sysctl_ctx_list {
+    entries: *mut Vec<*mut sysctl_oid>,
+}
+
+pub struct sysctl_req {
+    pub oldptr: *mut c_void,
+    pub oldlen: usize,
+    pub oldidx: usize,
+    pub oldfunc: Option<
+        unsafe extern "C" fn(*mut sysctl_req, *const c_void, usize) -> c_int,
+    >,
+    pub newptr: *const c_void,
+    pub newlen: usize,
+    pub newidx: usize,
+    pub newfunc: Option<
+        unsafe extern "C" fn(*mut sysctl_req, *mut c_void, usize) -> c_int,
+    >,
+}
+
+pub static mut sysctl__kern_children: sysctl_oid_list = sysctl_oid_list::new();
+pub static mut sysctl__debug_children: sysctl_oid_list = sysctl_oid_list::new();
+pub static mut sysctl__hw_children: sysctl_oid_list = sysctl_oid_list::new();
+pub static mut sysctl__dev_children: sysctl_oid_list = sysctl_oid_list::new();
+
+/// Held for writing while the tree changes and for reading while a handler
+/// runs, so that freeing a context waits for its handlers, as `sysctllock`
+static TREE: RwLock<()> = RwLock::new(());
+
+pub unsafe fn sysctl_ctx_init(clist: *mut sysctl_ctx_list) -> c_int {
+    unsafe { (*clist).entries = Box::into_raw(Box::new(Vec::new())) };
+    0
+}
+
+pub unsafe fn sysctl_ctx_free(clist: *mut sysctl_ctx_list) -> c_int {
+    let _tree = TREE.write().unwrap();
+    let entries = unsafe { Box::from_raw((*clist).entries) };
+    unsafe { (*clist).entries = ptr::null_mut() };
+    // Children were added after their parents, so go last to first
+    for &oid in entries.iter().rev() {
+        unsafe {
+            (*oid).oid_refcnt -= 1;
+            if (*oid).oid_refcnt == 0 {
+                (*(*oid).oid_parent).oids.retain(|&o| o != oid);
+                drop(Box::from_raw(oid));
+            }
+        }
+    }
+    0
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn sysctl_add_oid(
+    clist: *mut sysctl_ctx_list,
+    parent: *mut sysctl_oid_list,
+    _nbr: c_int,
+    name: *const c_char,
+    kind: c_int,
+    arg1: *mut c_void,
+    arg2: intmax_t,
+    handler: Option<sysctl_handler_t>,
+    _fmt: *const c_char,
+    _descr: *const c_char,
+    _label: *const c_char,
+) -> *mut sysctl_oid {
+    let _tree = TREE.write().unwrap();
+    let name = unsafe { CStr::from_ptr(name) };
+    let list = unsafe { &mut *parent };
+    let found = list
+        .oids
+        .iter()
+        .copied()
+        .find(|&o| unsafe { (*o).oid_name.as_c_str() } == name);
+    let oid = match found {
+        // Adding a node again shares it, anything else is a collision
+        Some(o) => unsafe {
+            if (*o).oid_kind & CTLTYPE != CTLTYPE_NODE
+                || kind & CTLTYPE != CTLTYPE_NODE
+                || (*o).oid_handler.is_some()
+            {
+                return ptr::null_mut();
+            }
+            (*o).oid_refcnt += 1;
+            o
+        },
+        None => {
+            let oid = Box::into_raw(Box::new(sysctl_oid {
+                oid_children: sysctl_oid_list::new(),
+                oid_parent: parent,
+                oid_name: name.into(),
+                oid_kind: kind,
+                oid_arg1: arg1,
+                oid_arg2: arg2,
+                oid_handler: handler,
+                oid_refcnt: 1,
+            }));
+            list.oids.push(oid);
+            oid
+        }
+    };
+    if !clist.is_null() {
+        unsafe { (*(*clist).entries).push(oid) };
+    }
+    oid
+}
+
+pub unsafe fn sysctl_wire_old_buffer(
+    _req: *mut sysctl_req,
+    _len: usize,
+) -> c_int {
+    0
+}
+
+unsafe fn sysctl_out(
+    req: *mut sysctl_req,
+    p: *const c_void,
+    l: usize,
+) -> c_int {
+    unsafe { (*req).oldfunc.unwrap()(req, p, l) }
+}
+
+unsafe fn sysctl_in(req: *mut sysctl_req, p: *mut c_void, l: usize) -> c_int {
+    unsafe { (*req).newfunc.unwrap()(req, p, l) }
+}
+
+pub unsafe extern "C" fn sysctl_handle_int(
+    _oidp: *mut sysctl_oid,
+    arg1: *mut c_void,
+    arg2: intmax_t,
+    req: *mut sysctl_req,
+) -> c_int {
+    let tmp = if arg1.is_null() {
+        arg2 as c_int
+    } else {
+        unsafe { *(arg1 as *const c_int) }
+    };
+    let error = unsafe { sysctl_out(req, (&raw const tmp).cast(), 4) };
+    if error != 0 || unsafe { (*req).newptr }.is_null() {
+        return error;
+    }
+    if arg1.is_null() {
+        return EPERM;
+    }
+    unsafe { sysctl_in(req, arg1, 4) }
+}
+
+pub unsafe extern "C" fn sysctl_handle_64(
+    _oidp: *mut sysctl_oid,
+    arg1: *mut c_void,
+    _arg2: intmax_t,
+    req: *mut sysctl_req,
+) -> c_int {
+    let tmp = unsafe { *(arg1 as *const i64) };
+    let error = unsafe { sysctl_out(req, (&raw const tmp).cast(), 8) };
+    if error != 0 || unsafe { (*req).newptr }.is_null() {
+        return error;
+    }
+    unsafe { sysctl_in(req, arg1, 8) }
+}
+
+pub unsafe extern "C" fn sysctl_handle_bool(
+    _oidp: *mut sysctl_oid,
+    arg1: *mut c_void,
+    arg2: intmax_t,
+    req: *mut sysctl_req,
+) -> c_int {
+    let mut tmp = if arg1.is_null() {
+        (arg2 != 0) as u8
+    } else {
+        unsafe { *(arg1 as *const bool) as u8 }
+    };
+    let error = unsafe { sysctl_out(req, (&raw const tmp).cast(), 1) };
+    if error != 0 || unsafe { (*req).newptr }.is_null() {
+        return error;
+    }
+    if arg1.is_null() {
+        return EPERM;
+    }
+    let error = unsafe { sysctl_in(req, (&raw mut tmp).cast(), 1) };
+    if error == 0 {
+        unsafe { *(arg1 as *mut bool) = tmp != 0 };
+    }
+    error
+}
+
+pub unsafe extern "C" fn sysctl_handle_counter_u64(
+    _oidp: *mut sysctl_oid,
+    arg1: *mut c_void,
+    _arg2: intmax_t,
+    req: *mut sysctl_req,
+) -> c_int {
+    let counter = unsafe { *(arg1 as *const counter_u64_t) };
+    let mut out = unsafe { counter_u64_fetch(counter) };
+    let error = unsafe { sysctl_out(req, (&raw const out).cast(), 8) };
+    if error != 0 || unsafe { (*req).newptr }.is_null() {
+        return error;
+    }
+    // Any write zeroes the counter
+    let error = unsafe { sysctl_in(req, (&raw mut out).cast(), 8) };
+    if error == 0 {
+        unsafe { counter_u64_zero(counter) };
+    }
+    error
+}
+
+/// Copy out as `sysctl_old_user`: the whole length is counted even when
+/// it doesn't fit, or there is nowhere to put it
+unsafe extern "C" fn old_vec(
+    req: *mut sysctl_req,
+    p: *const c_void,
+    l: usize,
+) -> c_int {
+    let req = unsafe { &mut *req };
+    let mut i = l;
+    if !req.oldptr.is_null() {
+        i = l.min(req.oldlen.saturating_sub(req.oldidx));
+        unsafe {
+            let dst = (req.oldptr as *mut u8).add(req.oldidx);
+            ptr::copy_nonoverlapping(p as *const u8, dst, i);
+        }
+    }
+    req.oldidx += l;
+    if !req.oldptr.is_null() && i < l {
+        return ENOMEM;
+    }
+    0
+}
+
+/// Copy in as `sysctl_new_user`
+unsafe extern "C" fn new_vec(
+    req: *mut sysctl_req,
+    p: *mut c_void,
+    l: usize,
+) -> c_int {
+    let req = unsafe { &mut *req };
+    if req.newptr.is_null() {
+        return 0;
+    }
+    if req.newlen - req.newidx < l {
+        return EINVAL;
+    }
+    unsafe {
+        let src = (req.newptr as *const u8).add(req.newidx);
+        ptr::copy_nonoverlapping(src, p as *mut u8, l);
+    }
+    req.newidx += l;
+    0
+}
+
+unsafe fn lookup(name: &str) -> Option<*mut sysctl_oid> {
+    let mut parts = name.split('.');
+    let mut list = match parts.next()? {
+        "kern" => &raw mut sysctl__kern_children,
+        "debug" => &raw mut sysctl__debug_children,
+        "hw" => &raw mut sysctl__hw_children,
+        "dev" => &raw mut sysctl__dev_children,
+        _ => return None,
+    };
+    let mut oid = None;
+    for part in parts {
+        let o = unsafe { &(*list).oids }.iter().copied().find(
+            |&o| unsafe { (*o).oid_name.to_bytes() } == part.as_bytes(),
+        )?;
+        list = unsafe { &raw mut (*o).oid_children };
+        oid = Some(o);
+    }
+    oid
+}
+
+/// Run the handler of the oid named `name`, copying out to `old` and in
+/// from `new`, returning the length of the old value
+fn request(
+    name: &str,
+    old: Option<&mut [u8]>,
+    new: Option<&[u8]>,
+) -> Result<usize, c_int> {
+    let _tree = TREE.read().unwrap();
+    let oid = unsafe { lookup(name) }.ok_or(ENOENT)?;
+    let oid = unsafe { &*oid };
+    let handler = oid.oid_handler.ok_or(EISDIR)?;
+    if new.is_some() && oid.oid_kind as u32 & CTLFLAG_WR == 0 {
+        return Err(EPERM);
+    }
+    let (oldptr, oldlen) = old.map_or((ptr::null_mut(), 0), |b| {
+        (b.as_mut_ptr() as *mut c_void, b.len())
+    });
+    let (newptr, newlen) = new
+        .map_or((ptr::null(), 0), |b| (b.as_ptr() as *const c_void, b.len()));
+    let mut req = sysctl_req {
+        oldptr,
+        oldlen,
+        oldidx: 0,
+        oldfunc: Some(old_vec),
+        newptr,
+        newlen,
+        newidx: 0,
+        newfunc: Some(new_vec),
+    };
+    let oidp = oid as *const sysctl_oid as *mut sysctl_oid;
+    let error = unsafe { handler(oidp, oid.oid_arg1, oid.oid_arg2, &mut req) };
+    if error != 0 {
+        return Err(error);
+    }
+    Ok(req.oldidx)
+}
+
+/// Check whether an oid named `name`, such as `debug.mymod.count`, exists
+pub fn exists(name: &str) -> bool {
+    let _tree = TREE.read().unwrap();
+    unsafe { lookup(name) }.is_some()
+}
+
+/// Read the value of `name` as `sysctl(8)` does, asking for its size
+/// first and then reading into a buffer of that size
+pub fn read(name: &str) -> Result<Vec<u8>, c_int> {
+    let len = request(name, None, None)?;
+    let mut buf = vec![0; len];
+    let len = request(name, Some(&mut buf), None)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Write `new` to `name`, without reading the old value
+pub fn write(name: &str, new: &[u8]) -> Result<(), c_int> {
+    request(name, None, Some(new)).map(drop)
+}
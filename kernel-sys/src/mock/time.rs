@@ -0,0 +1,84 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The uptime clock, measured from the first time it is read, with `ticks`
+//! counted in milliseconds, and the wall clock, read from the host
+
+use super::{bintime, timespec, timeval};
+use libc::c_int;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime};
+
+#[allow(non_upper_case_globals)]
+pub static mut hz: c_int = 1000;
+
+static BOOT: OnceLock<Instant> = OnceLock::new();
+
+pub unsafe fn binuptime(bt: *mut bintime) {
+    let up = BOOT.get_or_init(Instant::now).elapsed();
+    let frac = (u64::from(up.subsec_nanos()) << 32) / 1_000_000_000;
+    unsafe {
+        *bt = bintime {
+            sec: up.as_secs() as i64,
+            frac: frac << 32,
+        };
+    }
+}
+
+pub unsafe fn getbinuptime(bt: *mut bintime) {
+    unsafe { binuptime(bt) };
+}
+
+pub unsafe fn nanotime(ts: *mut timespec) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    unsafe {
+        *ts = timespec {
+            tv_sec: now.as_secs() as i64,
+            tv_nsec: now.subsec_nanos().into(),
+        };
+    }
+}
+
+pub unsafe fn getnanotime(ts: *mut timespec) {
+    unsafe { nanotime(ts) };
+}
+
+pub unsafe fn eventratecheck(
+    lasttime: *mut timeval,
+    cureps: *mut c_int,
+    maxeps: c_int,
+) -> c_int {
+    const HZ: i64 = 1000;
+    // Counted from 1, as a zero time means never
+    let now = BOOT.get_or_init(Instant::now).elapsed().as_millis() as i64 + 1;
+    let (last, cur) = unsafe { (&mut *lasttime, &mut *cureps) };
+    if last.tv_sec == 0 || now - last.tv_sec >= HZ {
+        last.tv_sec = now;
+        *cur = 1;
+        return c_int::from(maxeps != 0);
+    }
+    *cur += 1;
+    c_int::from(maxeps < 0 || *cur <= maxeps)
+}
@@ -0,0 +1,168 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! `uio(9)` transfers, following `sys/kern/subr_uio.c`
+//!
+//! Every segment type is copied with a plain memory copy, since user and
+//! kernel addresses are the same in a test process.
+
+use super::{
+    EINVAL, IOSIZE_MAX, iovec, off_t, uio, uio_rw, uio_rw_UIO_READ,
+    uio_rw_UIO_WRITE, uio_seg_UIO_NOCOPY, uio_seg_UIO_SYSSPACE,
+};
+use libc::{c_int, c_void};
+use std::marker::PhantomData;
+use std::ptr;
+
+pub unsafe fn uiomove(cp: *mut c_void, n: c_int, uio: *mut uio) -> c_int {
+    let uio = unsafe { &mut *uio };
+    assert!(
+        uio.uio_rw == uio_rw_UIO_READ || uio.uio_rw == uio_rw_UIO_WRITE,
+        "uiomove: mode"
+    );
+    let mut cp = cp as *mut u8;
+    let mut n = n as usize;
+    while n > 0 && uio.uio_resid != 0 {
+        // The kernel trusts uio_resid to cover the iovecs and would walk
+        // off the end of the array
+        assert!(uio.uio_iovcnt > 0, "uiomove: uio_resid exceeds iovecs");
+        let iov = unsafe { &mut *uio.uio_iov };
+        let mut cnt = iov.iov_len;
+        if cnt == 0 {
+            uio.uio_iov = unsafe { uio.uio_iov.add(1) };
+            uio.uio_iovcnt -= 1;
+            continue;
+        }
+        if cnt > n {
+            cnt = n;
+        }
+        if uio.uio_segflg != uio_seg_UIO_NOCOPY {
+            let base = iov.iov_base as *mut u8;
+            unsafe {
+                if uio.uio_rw == uio_rw_UIO_READ {
+                    ptr::copy(cp, base, cnt);
+                } else {
+                    ptr::copy(base, cp, cnt);
+                }
+            }
+        }
+        iov.iov_base = unsafe { (iov.iov_base as *mut u8).add(cnt) } as _;
+        iov.iov_len -= cnt;
+        uio.uio_resid -= cnt as isize;
+        uio.uio_offset += cnt as off_t;
+        cp = unsafe { cp.add(cnt) };
+        n -= cnt;
+    }
+    0
+}
+
+pub unsafe fn uiomove_frombuf(
+    buf: *mut c_void,
+    buflen: c_int,
+    uio: *mut uio,
+) -> c_int {
+    let (offset, resid) = unsafe { ((*uio).uio_offset, (*uio).uio_resid) };
+    let Ok(offset) = usize::try_from(offset) else {
+        return EINVAL;
+    };
+    if resid < 0 {
+        return EINVAL;
+    }
+    if buflen <= 0 || offset >= buflen as usize {
+        return 0;
+    }
+    let n = buflen as usize - offset;
+    if n > IOSIZE_MAX as usize {
+        return EINVAL;
+    }
+    unsafe {
+        uiomove((buf as *mut u8).add(offset) as *mut c_void, n as c_int, uio)
+    }
+}
+
+/// A single-segment system-space `uio` over a caller's buffer, as a
+/// device routine would receive it
+pub struct MockUio<'a> {
+    uio: uio,
+    iov: iovec,
+    start: *mut c_void,
+    _buf: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> MockUio<'a> {
+    fn new(rw: uio_rw, base: *mut u8, len: usize, offset: off_t) -> Self {
+        MockUio {
+            uio: uio {
+                uio_iov: ptr::null_mut(),
+                uio_iovcnt: 1,
+                uio_offset: offset,
+                uio_resid: len as isize,
+                uio_segflg: uio_seg_UIO_SYSSPACE,
+                uio_rw: rw,
+                uio_td: ptr::null_mut(),
+            },
+            iov: iovec {
+                iov_base: base as *mut c_void,
+                iov_len: len,
+            },
+            start: base as *mut c_void,
+            _buf: PhantomData,
+        }
+    }
+
+    /// A `UIO_READ` request, which the device fills from `offset`
+    pub fn read(buf: &'a mut [u8], offset: off_t) -> Self {
+        Self::new(uio_rw_UIO_READ, buf.as_mut_ptr(), buf.len(), offset)
+    }
+
+    /// A `UIO_WRITE` request carrying `data` to the device at `offset`
+    pub fn write(data: &'a [u8], offset: off_t) -> Self {
+        // Never written through: UIO_WRITE only copies out of the iovec
+        let base = data.as_ptr() as *mut u8;
+        Self::new(uio_rw_UIO_WRITE, base, data.len(), offset)
+    }
+
+    /// Bytes transferred so far
+    pub fn transferred(&self) -> usize {
+        self.iov.iov_base as usize - self.start as usize
+    }
+
+    /// The residual count left by the transfer
+    pub fn resid(&self) -> isize {
+        self.uio.uio_resid
+    }
+
+    /// The file offset after the transfer
+    pub fn offset(&self) -> off_t {
+        self.uio.uio_offset
+    }
+
+    /// Pointer to pass to a device routine
+    pub fn as_ptr(&mut self) -> *mut uio {
+        // Derived afresh from this borrow, past the segment if uiomove
+        // stepped over it while empty
+        let stepped = self.uio.uio_iovcnt == 0;
+        self.uio.uio_iov = (&raw mut self.iov).wrapping_add(stepped as usize);
+        &raw mut self.uio
+    }
+}
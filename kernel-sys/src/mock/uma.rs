@@ -0,0 +1,175 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::M_NOWAIT;
+use libc::{c_char, c_int, c_void};
+use std::alloc::{self, Layout};
+use std::sync::Mutex;
+
+pub type uma_ctor = Option<
+    unsafe extern "C" fn(
+        mem: *mut c_void,
+        size: c_int,
+        arg: *mut c_void,
+        flags: c_int,
+    ) -> c_int,
+>;
+pub type uma_dtor = Option<
+    unsafe extern "C" fn(mem: *mut c_void, size: c_int, arg: *mut c_void),
+>;
+pub type uma_init = Option<
+    unsafe extern "C" fn(mem: *mut c_void, size: c_int, flags: c_int) -> c_int,
+>;
+pub type uma_fini = Option<unsafe extern "C" fn(mem: *mut c_void, size: c_int)>;
+
+// Freed items are cached, still initialized, as the kernel's are
+pub struct uma_zone {
+    layout: Layout,
+    ctor: uma_ctor,
+    dtor: uma_dtor,
+    init: uma_init,
+    fini: uma_fini,
+    state: Mutex<State>,
+}
+pub type uma_zone_t = *mut uma_zone;
+
+struct State {
+    cached: Vec<usize>,
+    cur: c_int,
+    max: c_int,
+}
+
+pub unsafe fn uma_zcreate(
+    _name: *const c_char,
+    size: usize,
+    ctor: uma_ctor,
+    dtor: uma_dtor,
+    uminit: uma_init,
+    fini: uma_fini,
+    align: c_int,
+    _flags: u32,
+) -> uma_zone_t {
+    let layout = Layout::from_size_align(size, align as usize + 1).unwrap();
+    Box::into_raw(Box::new(uma_zone {
+        layout,
+        ctor,
+        dtor,
+        init: uminit,
+        fini,
+        state: Mutex::new(State {
+            cached: Vec::new(),
+            cur: 0,
+            max: 0,
+        }),
+    }))
+}
+
+pub unsafe fn uma_zdestroy(zone: uma_zone_t) {
+    let zone = unsafe { Box::from_raw(zone) };
+    let state = zone.state.into_inner().unwrap();
+    assert_eq!(state.cur, 0, "uma_zdestroy: items still allocated");
+    for item in state.cached {
+        let item = item as *mut c_void;
+        if let Some(fini) = zone.fini {
+            unsafe { fini(item, zone.layout.size() as c_int) };
+        }
+        unsafe { alloc::dealloc(item as *mut u8, zone.layout) };
+    }
+}
+
+pub unsafe fn uma_zalloc_arg(
+    zone: uma_zone_t,
+    arg: *mut c_void,
+    flags: c_int,
+) -> *mut c_void {
+    let zone = unsafe { &*zone };
+    let size = zone.layout.size() as c_int;
+    let cached = {
+        let mut state = zone.state.lock().unwrap();
+        if state.max > 0 && state.cur >= state.max {
+            // The kernel would sleep for an item to be freed, forever here
+            assert!(flags & M_NOWAIT != 0, "uma_zalloc_arg: zone is full");
+            return std::ptr::null_mut();
+        }
+        state.cur += 1;
+        state.cached.pop()
+    };
+    let item = match cached {
+        Some(item) => item as *mut c_void,
+        None => {
+            let item = unsafe { alloc::alloc(zone.layout) } as *mut c_void;
+            let failed = match zone.init {
+                Some(init) => (unsafe { init(item, size, flags) }) != 0,
+                None => false,
+            };
+            if failed {
+                unsafe { alloc::dealloc(item as *mut u8, zone.layout) };
+                zone.state.lock().unwrap().cur -= 1;
+                return std::ptr::null_mut();
+            }
+            item
+        }
+    };
+    if let Some(ctor) = zone.ctor {
+        if (unsafe { ctor(item, size, arg, flags) }) != 0 {
+            let mut state = zone.state.lock().unwrap();
+            state.cur -= 1;
+            state.cached.push(item as usize);
+            return std::ptr::null_mut();
+        }
+    }
+    item
+}
+
+pub unsafe fn uma_zfree_arg(
+    zone: uma_zone_t,
+    item: *mut c_void,
+    arg: *mut c_void,
+) {
+    if item.is_null() {
+        return;
+    }
+    let zone = unsafe { &*zone };
+    if let Some(dtor) = zone.dtor {
+        unsafe { dtor(item, zone.layout.size() as c_int, arg) };
+    }
+    let mut state = zone.state.lock().unwrap();
+    state.cur -= 1;
+    state.cached.push(item as usize);
+}
+
+pub unsafe fn uma_zone_set_max(zone: uma_zone_t, nitems: c_int) -> c_int {
+    unsafe { &*zone }.state.lock().unwrap().max = nitems;
+    nitems
+}
+
+pub unsafe fn uma_zone_get_max(zone: uma_zone_t) -> c_int {
+    unsafe { &*zone }.state.lock().unwrap().max
+}
+
+pub unsafe fn uma_zone_get_cur(zone: uma_zone_t) -> c_int {
+    unsafe { &*zone }.state.lock().unwrap().cur
+}
+
+pub unsafe fn uma_zone_set_warning(_zone: uma_zone_t, _warning: *const c_char) {
+}
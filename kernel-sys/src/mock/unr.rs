@@ -0,0 +1,86 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//! `unr(9)` over a set of the numbers in use
+
+use libc::{c_int, c_uint};
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+pub struct unrhdr {
+    low: c_uint,
+    high: c_uint,
+    used: Mutex<BTreeSet<c_uint>>,
+}
+
+pub unsafe fn new_unrhdr(
+    low: c_int,
+    high: c_int,
+    _mutex: *mut super::mtx,
+) -> *mut unrhdr {
+    assert!(0 <= low && low <= high, "new_unrhdr: bad range");
+    Box::into_raw(Box::new(unrhdr {
+        low: low as c_uint,
+        high: high as c_uint,
+        used: Mutex::new(BTreeSet::new()),
+    }))
+}
+
+pub unsafe fn clear_unrhdr(uh: *mut unrhdr) {
+    unsafe { &*uh }.used.lock().unwrap().clear();
+}
+
+pub unsafe fn delete_unrhdr(uh: *mut unrhdr) {
+    let uh = unsafe { Box::from_raw(uh) };
+    assert!(
+        uh.used.lock().unwrap().is_empty(),
+        "unrhdr has units in use"
+    );
+}
+
+pub unsafe fn alloc_unr(uh: *mut unrhdr) -> c_int {
+    let uh = unsafe { &*uh };
+    let mut used = uh.used.lock().unwrap();
+    match (uh.low..=uh.high).find(|n| !used.contains(n)) {
+        Some(n) => {
+            used.insert(n);
+            n as c_int
+        }
+        None => -1,
+    }
+}
+
+pub unsafe fn alloc_unr_specific(uh: *mut unrhdr, item: c_uint) -> c_int {
+    let uh = unsafe { &*uh };
+    if !(uh.low..=uh.high).contains(&item) {
+        return -1;
+    }
+    match uh.used.lock().unwrap().insert(item) {
+        true => item as c_int,
+        false => -1,
+    }
+}
+
+pub unsafe fn free_unr(uh: *mut unrhdr, item: c_uint) {
+    let freed = unsafe { &*uh }.used.lock().unwrap().remove(&item);
+    assert!(freed, "free_unr: unit {} not allocated", item);
+}
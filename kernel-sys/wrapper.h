@@ -27,11 +27,119 @@
 #include <sys/types.h>
 #include <sys/module.h>
 #include <sys/systm.h>  /* uprintf */
+#include <sys/syslog.h> /* log priorities */
 #include <sys/kernel.h> /* types used in module initialization */
 #include <sys/conf.h>   /* cdevsw struct */
 #include <sys/uio.h>    /* uio struct */
 #include <sys/malloc.h>
 #include <sys/kthread.h>
+#include <sys/linker.h>
 #include <sys/unistd.h>
 #include <sys/lock.h>
 #include <sys/mutex.h>
+#include <sys/rwlock.h>
+#include <sys/errno.h>
+#include <sys/cpuset.h>
+#include <sys/smp.h>
+#ifdef __amd64__
+#include <machine/clock.h> /* tsc_freq */
+#endif
+#include <sys/proc.h>
+#include <sys/sched.h>   /* sched_bind */
+#include <sys/sysent.h>  /* SV_ILP32 */
+#include <sys/priv.h>
+#include <sys/random.h>
+#include <sys/jail.h>
+#include <sys/osd.h>
+#include <sys/watchdog.h>
+#include <sys/serial.h>
+#include <sys/tty.h>
+#include <sys/interrupt.h>
+#include <sys/buf_ring.h>
+#include <sys/selinfo.h>
+#include <sys/event.h>
+#include <sys/pmckern.h>
+#include <sys/stack.h>
+#include <sys/ktr.h>
+#include <sys/sbuf.h>
+#include <sys/disk.h>     /* struct diocskerneldump_arg */
+#include <sys/kerneldump.h>
+#include <sys/alq.h>
+#include <sys/timeet.h>
+#include <sys/timetc.h>
+#include <sys/efi.h>
+#include <sys/poll.h>
+#include <sys/fcntl.h>
+#include <sys/capsicum.h> /* cap_no_rights */
+#include <sys/vnode.h>
+#include <sys/namei.h>
+#include <sys/mount.h>
+#include <sys/dirent.h>
+#include <fs/pseudofs/pseudofs.h>
+#include <sys/callout.h>
+#include <sys/taskqueue.h>
+#include <sys/condvar.h>
+#include <sys/sleepqueue.h>
+#include <sys/sx.h>
+#include <sys/bio.h>
+#include <sys/sysctl.h>
+#include <sys/eventhandler.h>
+#include <sys/counter.h>
+#include <sys/devctl.h>
+#include <sys/devicestat.h>
+#include <sys/bus.h>
+#include <machine/bus.h> /* bus_dma(9) */
+#include <sys/mbuf.h>
+#include <netinet/in.h>
+#include <sys/epoch.h>
+#include <net/if.h>
+#include <net/if_var.h>
+#include <net/vnet.h>
+#include <net/if_clone.h>
+#include <net/bpf.h>
+#include <net/pfil.h>
+#include <netgraph/ng_message.h>
+#include <netgraph/netgraph.h>
+#include <net/if_media.h>
+#include <net/ethernet.h>
+#include <net80211/ieee80211_var.h>
+#include <netinet/ip_var.h>
+#include <netinet/ip_fw.h>
+#include <netinet/ip_dummynet.h>
+#include <netpfil/ipfw/ip_fw_private.h>
+#include <netpfil/ipfw/dn_heap.h>
+#include <netpfil/ipfw/ip_dn_private.h>
+#include <netpfil/ipfw/dn_aqm.h>
+#include <netpfil/ipfw/dn_sched.h>
+#include <vm/vm.h>
+#include <vm/pmap.h>
+#include <vm/vm_extern.h> /* vm_fault_quick_hold_pages, kva_alloc */
+#include <vm/vm_map.h>    /* struct vmspace */
+#include <vm/vm_page.h>
+#include <vm/vm_param.h>
+#include <vm/vm_object.h>
+#include <vm/vm_pager.h>
+#include <vm/vm_kern.h>   /* kernel_map */
+#include <vm/uma.h>
+#include <ddb/ddb.h>
+#include <dev/hid/hid.h>
+#include <dev/hid/hidbus.h>
+#include <dev/mmc/bridge.h>
+#include <dev/mmc/mmcreg.h>
+#include <dev/mmc/mmcbrvar.h>
+#include <dev/pci/pcireg.h>
+#include <dev/pci/pcivar.h>
+#include <dev/smbus/smbconf.h>
+#include "smbus_if.h"
+#include <dev/superio/superio.h>
+#include <dev/usb/usb.h>
+#include <dev/usb/usbdi.h>
+#include <dev/usb/usb_core.h>
+#include <dev/usb/usb_dynamic.h> /* device-mode template hooks */
+#include <geom/geom.h>
+#include <geom/geom_disk.h>
+#include <opencrypto/xform_auth.h>
+#include <opencrypto/cryptodev.h>
+#include <contrib/zlib/zlib.h>
+#include <contrib/zstd/lib/zstd.h>
+#include <contrib/zstd/lib/zstd_errors.h>
@@ -0,0 +1,697 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The tree of files in a cpio or tar image
+//!
+//! Both formats are a run of headers, each followed by a file's contents.
+//! `newc` cpio headers (`cpio -H newc`, as Linux initramfs images use) are
+//! 110 bytes of hex, then the NUL-terminated path, each padded to four
+//! bytes; the last is named `TRAILER!!!`. Tar headers are 512-byte blocks
+//! of octal fields, `ustar` or older, with contents padded to 512 bytes,
+//! and end at a block of zeros. Paths too long for a tar header come from
+//! a pax extended header or a GNU long name entry before it.
+//!
+//! Only regular files, directories and symlinks are kept, and hard links
+//! to regular files. Directories a path passes through that the image
+//! doesn't list are made up, and paths leading out of the root with `..`
+//! are skipped. Contents are left in the image, to be read as wanted.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::vfs::FileType;
+use bsd_kernel::vnode::File;
+
+/// The root directory's inode number; the others follow it
+pub const ROOT: u64 = 2;
+
+/// Longest path accepted in a header, `PATH_MAX`
+const PATH_MAX: u64 = 1024;
+
+/// Longest path component, `NAME_MAX`
+const NAME_MAX: usize = 255;
+
+/// Largest pax extended header read; bigger ones, holding more than
+/// paths, are skipped
+const PAX_MAX: u64 = 64 << 10;
+
+/// Where an image's bytes are
+pub enum Source {
+    /// In memory that lasts, such as a file the loader preloaded
+    Memory(&'static [u8]),
+    /// In a regular file, read as needed
+    File(File),
+}
+
+impl Source {
+    /// Length in bytes
+    pub fn len(&self) -> Result<u64, Errno> {
+        match self {
+            Source::Memory(m) => Ok(m.len() as u64),
+            Source::File(f) => f.size(),
+        }
+    }
+
+    /// Read into `buf` from `offset`, short only at the end
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        match self {
+            Source::Memory(m) => {
+                let start = usize::try_from(offset).unwrap_or(usize::MAX);
+                let from = m.get(start..).unwrap_or(&[]);
+                let n = from.len().min(buf.len());
+                buf[..n].copy_from_slice(&from[..n]);
+                Ok(n)
+            }
+            Source::File(f) => f.read_at(offset, buf),
+        }
+    }
+
+    /// Fill `buf` from `offset`, failing with `Errno::Inval` if the image
+    /// ends first
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        match self.read_at(offset, buf)? {
+            n if n == buf.len() => Ok(()),
+            _ => Err(Errno::Inval),
+        }
+    }
+
+    /// Read `len` bytes at `offset` into a new buffer, for paths and link
+    /// targets, failing with `Errno::NameTooLong` past `max`
+    fn read_vec(
+        &self,
+        offset: u64,
+        len: u64,
+        max: u64,
+    ) -> Result<Vec<u8>, Errno> {
+        if len > max {
+            return Err(Errno::NameTooLong);
+        }
+        let mut buf = vec![0; len as usize];
+        self.read_exact_at(offset, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// What a file holds
+enum Data {
+    /// The bytes at this offset in the image
+    Image(u64),
+    /// These bytes, for tar symlinks, whose targets are in the header
+    Inline(Box<[u8]>),
+}
+
+/// A file in the tree
+pub struct Node {
+    pub kind: FileType,
+    /// Permission bits
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub nlink: u32,
+    pub size: u64,
+    data: Data,
+    /// The directory this is in, itself for the root
+    pub parent: u64,
+    /// A directory's entries, sorted by name
+    pub children: Vec<(Box<[u8]>, u64)>,
+}
+
+impl Node {
+    fn new(kind: FileType, header: &Header) -> Self {
+        Node {
+            kind,
+            mode: header.mode as u16 & 0o7777,
+            uid: header.uid,
+            gid: header.gid,
+            mtime: header.mtime,
+            nlink: 1,
+            size: 0,
+            data: Data::Image(0),
+            parent: ROOT,
+            children: Vec::new(),
+        }
+    }
+
+    /// A directory the image doesn't list, but a path passes through
+    fn implied() -> Self {
+        Node {
+            kind: FileType::Directory,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            nlink: 1,
+            size: 0,
+            data: Data::Image(0),
+            parent: ROOT,
+            children: Vec::new(),
+        }
+    }
+}
+
+/// The fields of either format's headers that are kept
+#[derive(Default)]
+struct Header {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+}
+
+/// A parsed image, and the source its contents are read from
+pub struct Archive {
+    source: Source,
+    len: u64,
+    nodes: Vec<Node>,
+}
+
+impl Archive {
+    /// Read the headers of the cpio or tar image in `source`. Fails with
+    /// `Errno::FType` if it is neither, and `Errno::Inval` if it is cut
+    /// short or malformed
+    pub fn new(source: Source) -> Result<Self, Errno> {
+        let len = source.len()?;
+        let mut tree = Tree::new();
+        let mut magic = [0; 6];
+        source
+            .read_exact_at(0, &mut magic)
+            .map_err(|_| Errno::FType)?;
+        if &magic == b"070701" || &magic == b"070702" {
+            read_cpio(&source, &mut tree)?;
+        } else {
+            read_tar(&source, &mut tree)?;
+        }
+        Ok(Archive {
+            source,
+            len,
+            nodes: tree.finish(),
+        })
+    }
+
+    /// Length of the image in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// How many files the image held, counting any a later entry replaced
+    pub fn files(&self) -> u64 {
+        self.nodes.len() as u64
+    }
+
+    /// File `ino`
+    pub fn node(&self, ino: u64) -> Result<&Node, Errno> {
+        ino.checked_sub(ROOT)
+            .and_then(|i| self.nodes.get(usize::try_from(i).ok()?))
+            .ok_or(Errno::NoEnt)
+    }
+
+    /// The number of `name` in directory `dir`
+    pub fn lookup(&self, dir: u64, name: &[u8]) -> Result<u64, Errno> {
+        let dir = self.node(dir)?;
+        if dir.kind != FileType::Directory {
+            return Err(Errno::NotDir);
+        }
+        let i = dir
+            .children
+            .binary_search_by(|(n, _)| (**n).cmp(name))
+            .map_err(|_| Errno::NoEnt)?;
+        Ok(dir.children[i].1)
+    }
+
+    /// Read the contents of file `ino` from `offset`
+    pub fn read(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Errno> {
+        let node = self.node(ino)?;
+        if node.kind == FileType::Directory {
+            return Err(Errno::IsDir);
+        }
+        let left = node.size.saturating_sub(offset);
+        let n = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+        if n == 0 {
+            return Ok(0);
+        }
+        let buf = &mut buf[..n];
+        match &node.data {
+            Data::Image(start) => {
+                self.source.read_exact_at(start + offset, buf)?
+            }
+            Data::Inline(bytes) => {
+                buf.copy_from_slice(&bytes[offset as usize..][..n])
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// The tree as it is built
+struct Tree {
+    nodes: Vec<Node>,
+    /// Each node's entries, looked up by name while building
+    entries: Vec<BTreeMap<Box<[u8]>, u64>>,
+}
+
+impl Tree {
+    fn new() -> Self {
+        Tree {
+            nodes: vec![Node::implied()],
+            entries: vec![BTreeMap::new()],
+        }
+    }
+
+    fn index(ino: u64) -> usize {
+        (ino - ROOT) as usize
+    }
+
+    fn add(&mut self, node: Node) -> u64 {
+        self.nodes.push(node);
+        self.entries.push(BTreeMap::new());
+        ROOT + self.nodes.len() as u64 - 1
+    }
+
+    /// The components of `path`, or `None` for a path with a `..` or a
+    /// component too long
+    fn split(path: &[u8]) -> Option<Vec<&[u8]>> {
+        let parts: Vec<&[u8]> = path
+            .split(|&b| b == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .collect();
+        if parts.iter().any(|c| *c == b".." || c.len() > NAME_MAX) {
+            return None;
+        }
+        Some(parts)
+    }
+
+    /// The file at `path`, if there is one
+    fn find(&self, path: &[u8]) -> Option<u64> {
+        let mut ino = ROOT;
+        for name in Self::split(path)? {
+            ino = *self.entries[Self::index(ino)].get(name)?;
+        }
+        Some(ino)
+    }
+
+    /// Enter `node` at `path`, making any directories missing on the way,
+    /// and return its number. A directory listed again keeps its entries
+    /// and takes the new attributes; anything else replaces what was
+    /// there. Returns `None`, leaving the tree alone, for a path that
+    /// can't be entered
+    fn insert(&mut self, path: &[u8], node: Node) -> Option<u64> {
+        let parts = Self::split(path)?;
+        let Some((last, dirs)) = parts.split_last() else {
+            // The root itself, as `.` or `./`
+            if node.kind == FileType::Directory {
+                self.update(ROOT, node);
+            }
+            return Some(ROOT);
+        };
+        let mut dir = ROOT;
+        for name in dirs {
+            dir = match self.entries[Self::index(dir)].get(*name) {
+                Some(&ino) => ino,
+                None => {
+                    let ino = self.add(Node::implied());
+                    self.link(dir, name, ino);
+                    ino
+                }
+            };
+            if self.nodes[Self::index(dir)].kind != FileType::Directory {
+                return None;
+            }
+        }
+        if let Some(&old) = self.entries[Self::index(dir)].get(*last) {
+            let old_node = &self.nodes[Self::index(old)];
+            if old_node.kind == FileType::Directory
+                && node.kind == FileType::Directory
+            {
+                self.update(old, node);
+                return Some(old);
+            }
+        }
+        let ino = self.add(node);
+        self.link(dir, last, ino);
+        Some(ino)
+    }
+
+    /// Give directory `ino` the attributes of `node`
+    fn update(&mut self, ino: u64, node: Node) {
+        let dir = &mut self.nodes[Self::index(ino)];
+        dir.mode = node.mode;
+        dir.uid = node.uid;
+        dir.gid = node.gid;
+        dir.mtime = node.mtime;
+    }
+
+    /// Enter `ino` in `dir` as `name`
+    fn link(&mut self, dir: u64, name: &[u8], ino: u64) {
+        self.nodes[Self::index(ino)].parent = dir;
+        let entries = &mut self.entries[Self::index(dir)];
+        if let Some(old) = entries.insert(name.into(), ino) {
+            let old = &mut self.nodes[Self::index(old)];
+            old.nlink = old.nlink.saturating_sub(1);
+        }
+    }
+
+    /// Enter a hard link to regular file `ino` at `path`
+    fn hard_link(&mut self, path: &[u8], ino: u64) {
+        let Some(parts) = Self::split(path) else {
+            return;
+        };
+        let Some((last, dirs)) = parts.split_last() else {
+            return;
+        };
+        let mut dir = ROOT;
+        for name in dirs {
+            match self.entries[Self::index(dir)].get(*name) {
+                Some(&d)
+                    if self.nodes[Self::index(d)].kind
+                        == FileType::Directory =>
+                {
+                    dir = d
+                }
+                _ => return,
+            }
+        }
+        let entries = &mut self.entries[Self::index(dir)];
+        match entries.insert((*last).into(), ino) {
+            Some(old) if old == ino => return,
+            Some(old) => {
+                let old = &mut self.nodes[Self::index(old)];
+                old.nlink = old.nlink.saturating_sub(1);
+            }
+            None => {}
+        }
+        self.nodes[Self::index(ino)].nlink += 1;
+    }
+
+    /// The finished nodes, with sorted entries and directories' link
+    /// counts
+    fn finish(self) -> Vec<Node> {
+        let mut nodes = self.nodes;
+        for (i, entries) in self.entries.into_iter().enumerate() {
+            if nodes[i].kind != FileType::Directory {
+                continue;
+            }
+            let subdirs = entries
+                .values()
+                .filter(|&&ino| {
+                    nodes[Self::index(ino)].kind == FileType::Directory
+                })
+                .count();
+            nodes[i].nlink = 2 + subdirs as u32;
+            nodes[i].children = entries.into_iter().collect();
+        }
+        nodes
+    }
+}
+
+/// Round `n` up to a multiple of `align`, a power of two
+fn align(n: u64, align: u64) -> Result<u64, Errno> {
+    n.checked_add(align - 1)
+        .map(|n| n & !(align - 1))
+        .ok_or(Errno::Inval)
+}
+
+/// File type bits of a mode, from `sys/stat.h`
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Length of a `newc` cpio header
+const CPIO_HEADER: usize = 110;
+
+/// The eight hex digits of field `n` of a `newc` cpio header
+fn hex(header: &[u8; CPIO_HEADER], n: usize) -> Result<u32, Errno> {
+    let field = &header[6 + 8 * n..][..8];
+    let s = core::str::from_utf8(field).map_err(|_| Errno::Inval)?;
+    u32::from_str_radix(s, 16).map_err(|_| Errno::Inval)
+}
+
+fn read_cpio(source: &Source, tree: &mut Tree) -> Result<(), Errno> {
+    // Hard linked files share an inode number in the image, and only the
+    // last of their entries carries the contents
+    let mut links: BTreeMap<u32, u64> = BTreeMap::new();
+    let mut offset = 0;
+    loop {
+        let mut raw = [0; CPIO_HEADER];
+        source.read_exact_at(offset, &mut raw)?;
+        if &raw[..6] != b"070701" && &raw[..6] != b"070702" {
+            return Err(Errno::Inval);
+        }
+        let cpio_ino = hex(&raw, 0)?;
+        let header = Header {
+            mode: hex(&raw, 1)?,
+            uid: hex(&raw, 2)?,
+            gid: hex(&raw, 3)?,
+            mtime: hex(&raw, 5)?.into(),
+        };
+        let nlink = hex(&raw, 4)?;
+        let size = u64::from(hex(&raw, 6)?);
+        let namesize = u64::from(hex(&raw, 11)?);
+        let mut path = source.read_vec(
+            offset + CPIO_HEADER as u64,
+            namesize,
+            PATH_MAX + 1,
+        )?;
+        if path.pop() != Some(0) {
+            return Err(Errno::Inval);
+        }
+        let data = align(offset + CPIO_HEADER as u64 + namesize, 4)?;
+        if path == b"TRAILER!!!" {
+            return Ok(());
+        }
+        offset = align(data.checked_add(size).ok_or(Errno::Inval)?, 4)?;
+        let kind = match header.mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFREG => FileType::Regular,
+            S_IFLNK => FileType::Symlink,
+            _ => continue,
+        };
+        if kind == FileType::Regular && nlink > 1 {
+            if let Some(&ino) = links.get(&cpio_ino) {
+                tree.hard_link(&path, ino);
+                if size > 0 {
+                    let node = &mut tree.nodes[Tree::index(ino)];
+                    node.size = size;
+                    node.data = Data::Image(data);
+                }
+                continue;
+            }
+        }
+        let mut node = Node::new(kind, &header);
+        if kind != FileType::Directory {
+            node.size = size;
+            node.data = Data::Image(data);
+        }
+        let ino = tree.insert(&path, node);
+        if let (Some(ino), true) = (ino, kind == FileType::Regular && nlink > 1)
+        {
+            links.insert(cpio_ino, ino);
+        }
+    }
+}
+
+/// Tar's block size
+const BLOCK: u64 = 512;
+
+/// Field `at..at + len` of a tar header, up to its first NUL
+fn field(header: &[u8; BLOCK as usize], at: usize, len: usize) -> &[u8] {
+    let f = &header[at..at + len];
+    let end = f.iter().position(|&b| b == 0).unwrap_or(len);
+    &f[..end]
+}
+
+/// A numeric tar field: octal digits, or for values too big for them,
+/// big-endian binary flagged by the top bit of the first byte
+fn number(
+    header: &[u8; BLOCK as usize],
+    at: usize,
+    len: usize,
+) -> Result<u64, Errno> {
+    let f = &header[at..at + len];
+    if f[0] & 0x80 != 0 {
+        let mut n = u64::from(f[0] & 0x7f);
+        for &b in &f[1..] {
+            n = n.checked_mul(256).ok_or(Errno::Inval)? | u64::from(b);
+        }
+        return Ok(n);
+    }
+    let mut n: u64 = 0;
+    for &b in f.iter().skip_while(|&&b| b == b' ') {
+        match b {
+            b'0'..=b'7' => {
+                n = n.checked_mul(8).ok_or(Errno::Inval)? + u64::from(b - b'0')
+            }
+            b' ' | 0 => break,
+            _ => return Err(Errno::Inval),
+        }
+    }
+    Ok(n)
+}
+
+/// Whether a tar header's checksum, of its bytes with the checksum field
+/// taken as spaces, matches
+fn checksum_ok(header: &[u8; BLOCK as usize]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                u64::from(b' ')
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum();
+    number(header, 148, 8) == Ok(sum)
+}
+
+/// The `path` and `linkpath` records of a pax extended header, each a
+/// line of `<length> <key>=<value>\n`
+fn pax(records: &[u8], path: &mut Option<Vec<u8>>, link: &mut Option<Vec<u8>>) {
+    let mut rest = records;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let len = core::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok());
+        let Some(record) = len.and_then(|len| rest.get(space + 1..len)) else {
+            return;
+        };
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            *path = Some(value.into());
+        } else if let Some(value) = record.strip_prefix(b"linkpath=") {
+            *link = Some(value.into());
+        }
+        rest = &rest[len.unwrap()..];
+    }
+}
+
+fn read_tar(source: &Source, tree: &mut Tree) -> Result<(), Errno> {
+    // Set by a pax or GNU header for the entry after it
+    let mut long_path = None;
+    let mut long_link = None;
+    let mut offset = 0;
+    loop {
+        let mut raw = [0; BLOCK as usize];
+        match source.read_at(offset, &mut raw)? {
+            // Some writers leave off the closing zero blocks
+            0 if offset > 0 => return Ok(()),
+            n if n < raw.len() => return Err(Errno::Inval),
+            _ => {}
+        }
+        if raw.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        if !checksum_ok(&raw) {
+            // Neither tar nor cpio if it's the first header
+            return Err(if offset == 0 {
+                Errno::FType
+            } else {
+                Errno::Inval
+            });
+        }
+        let header = Header {
+            mode: number(&raw, 100, 8)? as u32,
+            uid: number(&raw, 108, 8)? as u32,
+            gid: number(&raw, 116, 8)? as u32,
+            mtime: number(&raw, 136, 12)? as i64,
+        };
+        let size = number(&raw, 124, 12)?;
+        let typeflag = raw[156];
+        let data = offset + BLOCK;
+        offset = align(data.checked_add(size).ok_or(Errno::Inval)?, BLOCK)?;
+        let path = match long_path.take() {
+            Some(path) => path,
+            None => {
+                let name = field(&raw, 0, 100);
+                let prefix = field(&raw, 345, 155);
+                // GNU tar's `ustar ` headers use the prefix for times
+                if raw[257..263] == *b"ustar\0" && !prefix.is_empty() {
+                    [prefix, b"/", name].concat()
+                } else {
+                    name.into()
+                }
+            }
+        };
+        let link = match long_link.take() {
+            Some(link) => link,
+            None => field(&raw, 157, 100).to_vec(),
+        };
+        let kind = match typeflag {
+            // Old archives mark directories with a trailing slash only
+            b'0' | b'7' | 0 if path.ends_with(b"/") => FileType::Directory,
+            b'0' | b'7' | 0 => FileType::Regular,
+            b'5' => FileType::Directory,
+            b'2' => FileType::Symlink,
+            b'1' => {
+                if let Some(ino) = tree.find(&link) {
+                    if tree.nodes[Tree::index(ino)].kind == FileType::Regular {
+                        tree.hard_link(&path, ino);
+                    }
+                }
+                continue;
+            }
+            b'x' => {
+                if size <= PAX_MAX {
+                    let records = source.read_vec(data, size, PAX_MAX)?;
+                    pax(&records, &mut long_path, &mut long_link);
+                }
+                continue;
+            }
+            b'L' | b'K' => {
+                let mut value = source.read_vec(data, size, PATH_MAX + 1)?;
+                let end =
+                    value.iter().position(|&b| b == 0).unwrap_or(value.len());
+                value.truncate(end);
+                match typeflag {
+                    b'L' => long_path = Some(value),
+                    _ => long_link = Some(value),
+                }
+                continue;
+            }
+            // Global pax headers, devices and FIFOs
+            _ => continue,
+        };
+        let mut node = Node::new(kind, &header);
+        match kind {
+            FileType::Regular => {
+                node.size = size;
+                node.data = Data::Image(data);
+            }
+            FileType::Symlink => {
+                node.size = link.len() as u64;
+                node.data = Data::Inline(link.into());
+            }
+            FileType::Directory => {}
+        }
+        tree.insert(&path, node);
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::archive::{Archive, ROOT, Source};
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::vfs::{
+    Attr, DirEntry, Filesystem, Options, Statfs, Vfs, Volume,
+};
+use bsd_kernel::vnode::File;
+use bsd_kernel::{linker, log};
+use core::ffi::CStr;
+
+static VFS: spin::Mutex<Option<Box<Vfs<ArchiveFs>>>> = spin::Mutex::new(None);
+
+pub fn load() -> Result<(), Errno> {
+    let vfs = Vfs::new(ArchiveFs);
+    vfs.register()?;
+    *VFS.lock() = Some(vfs);
+    Ok(())
+}
+
+pub fn unload() -> Result<(), Errno> {
+    let mut vfs = VFS.lock();
+    if let Some(v) = vfs.as_ref() {
+        // Fails while anything is mounted
+        v.unregister()?;
+    }
+    *vfs = None;
+    Ok(())
+}
+
+pub struct ArchiveFs;
+
+impl Filesystem for ArchiveFs {
+    const NAME: &'static CStr = c"archivefs";
+    type Volume = Archive;
+
+    /// Mount the image `from` names: a file the loader preloaded, as
+    /// `preload:<name>`, or else the path of a regular file
+    fn mount(&self, opts: &Options) -> Result<Archive, Errno> {
+        let from = opts.get_str(c"from").ok_or(Errno::Inval)?;
+        let source = match from.to_bytes().strip_prefix(b"preload:") {
+            Some(name) => {
+                let name = CString::new(name).map_err(|_| Errno::Inval)?;
+                Source::Memory(linker::preloaded(&name).ok_or(Errno::NoEnt)?)
+            }
+            None => Source::File(File::open(from, false)?),
+        };
+        let archive = Archive::new(source)?;
+        log!(
+            LOG_INFO,
+            "archivefs: {:?}: {} files in {} bytes",
+            from,
+            archive.files(),
+            archive.len()
+        );
+        Ok(archive)
+    }
+}
+
+impl Volume for Archive {
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn getattr(&self, ino: u64) -> Result<Attr, Errno> {
+        let node = self.node(ino)?;
+        Ok(Attr {
+            kind: node.kind,
+            mode: node.mode,
+            nlink: node.nlink,
+            uid: node.uid,
+            gid: node.gid,
+            size: node.size,
+            mtime: node.mtime,
+        })
+    }
+
+    fn lookup(&self, dir: u64, name: &[u8]) -> Result<u64, Errno> {
+        Archive::lookup(self, dir, name)
+    }
+
+    fn parent(&self, dir: u64) -> Result<u64, Errno> {
+        Ok(self.node(dir)?.parent)
+    }
+
+    fn readdir(
+        &self,
+        dir: u64,
+        index: u64,
+    ) -> Result<Option<DirEntry<'_>>, Errno> {
+        let children = &self.node(dir)?.children;
+        let Some((name, ino)) =
+            usize::try_from(index).ok().and_then(|i| children.get(i))
+        else {
+            return Ok(None);
+        };
+        Ok(Some(DirEntry {
+            ino: *ino,
+            kind: self.node(*ino)?.kind,
+            name,
+        }))
+    }
+
+    fn read(
+        &self,
+        ino: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Errno> {
+        Archive::read(self, ino, offset, buf)
+    }
+
+    fn statfs(&self) -> Statfs {
+        Statfs {
+            blocks: self.len().div_ceil(512),
+            files: self.files(),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_std]
+
+//! Example filesystem written in Rust: `archivefs` mounts a cpio (`newc`)
+//! or tar (v7, ustar, GNU or pax) archive read-only, like a tiny
+//! initramfs. The image is a regular file:
+//! ```bash,ignore
+//! ./build.sh module-archivefs
+//! sudo make -C module-archivefs load
+//! tar -cf /var/tmp/etc.tar -C /etc .
+//! sudo mount -t archivefs /var/tmp/etc.tar /mnt
+//! ls -l /mnt && cat /mnt/rc.conf
+//! sudo umount /mnt
+//! sudo make -C module-archivefs unload
+//! ```
+//! or one the loader put in memory, named after `preload:`:
+//! ```text,ignore
+//! # /boot/loader.conf
+//! archivefs_load="YES"
+//! initfs_load="YES"
+//! initfs_type="archivefs_image"
+//! initfs_name="/boot/initfs.cpio"
+//! ```
+//! ```bash,ignore
+//! sudo mount -t archivefs preload:/boot/initfs.cpio /mnt
+//! ```
+//! The archive is read once at mount time to build the directory tree,
+//! which is kept in memory; file contents are read from the image as they
+//! are asked for. Directories an archive leaves out are made up, later
+//! entries replace earlier ones of the same name, and device nodes, FIFOs
+//! and the like are skipped. The module can't be unloaded while anything
+//! is mounted.
+
+use bsd_kernel::allocator::KernelAllocator;
+use bsd_kernel::module::{Abi, ModuleEventType, check_abi};
+use core::panic::PanicInfo;
+use libc::{c_int, c_void};
+
+mod archive;
+mod fs;
+extern crate alloc;
+
+bsd_kernel::malloc_type!(static M_ARCHIVEFS = c"archivefs");
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::with_type(&M_ARCHIVEFS);
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    bsd_kernel::panic::handle(info)
+}
+
+/// Main event handler for module events
+#[unsafe(no_mangle)]
+pub extern "C" fn module_event(
+    _module: bsd_kernel::Module,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    let result = match ModuleEventType::from_i32(event) {
+        Some(ModuleEventType::Load) => {
+            check_abi(Abi::Exact).and_then(|()| fs::load())
+        }
+        Some(ModuleEventType::Unload) => fs::unload(),
+        Some(_) => Ok(()),
+        None => Err(bsd_kernel::errno::Errno::OpNotSupp),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use bsd_kernel::debugln;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::kernel_module;
+use bsd_kernel::module::ModuleEvents;
+use bsd_kernel::net::Mbuf;
+use bsd_kernel::net::ifnet::{
+    Clonable, Cloner, Counter, Ifnet, Interface, LinkState,
+};
+use core::ffi::CStr;
+
+#[kernel_module(name = "rustedsc", version = 1)]
+#[derive(Default, Debug)]
+pub struct Edsc {
+    cloner: Option<Cloner<Discard>>,
+}
+
+impl ModuleEvents for Edsc {
+    fn load(&mut self) {
+        debugln!("[edsc.rs] Edsc::load");
+
+        match Cloner::attach(0) {
+            Ok(cloner) => self.cloner = Some(cloner),
+            Err(e) => debugln!("[edsc.rs] Edsc::load: {:?}", e),
+        }
+    }
+
+    fn unload(&mut self) {
+        debugln!("[edsc.rs] Edsc::unload");
+
+        // Destroys the interfaces still there
+        self.cloner = None;
+    }
+}
+
+/// One interface, which has nothing to keep
+#[derive(Debug)]
+pub struct Discard;
+
+impl Interface for Discard {
+    fn transmit(&self, ifp: &Ifnet, m: Mbuf) -> Result<(), Errno> {
+        ifp.bpf_mtap(&m);
+        ifp.inc_counter(Counter::OutPackets, 1);
+        ifp.inc_counter(Counter::OutBytes, m.len() as i64);
+        Ok(())
+    }
+
+    /// Up as soon as it's configured up, there being no link to wait for
+    fn set_flags(&self, ifp: &Ifnet) -> Result<(), Errno> {
+        let up = ifp.is_up();
+        ifp.set_running(up);
+        ifp.set_link_state(match up {
+            true => LinkState::Up,
+            false => LinkState::Down,
+        });
+        Ok(())
+    }
+}
+
+impl Clonable for Discard {
+    const NAME: &'static CStr = c"rustedsc";
+
+    /// A locally administered address with the unit in it, as `edsc(4)`
+    /// makes
+    fn create(unit: i32) -> Result<(Self, [u8; 6]), Errno> {
+        let [a, b, c, d] = (unit as u32).to_be_bytes();
+        Ok((Discard, [0x02, 0x00, a, b, c, d]))
+    }
+}
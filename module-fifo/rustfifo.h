@@ -0,0 +1,57 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+/*
+ * The API the fifo module exports. Consumers declare
+ *
+ *     MODULE_DEPEND(<consumer>, fifo, 1, 1, 1);
+ *
+ * and should check rustfifo_api.header before using anything added after
+ * version 1.0.
+ */
+
+#ifndef _RUSTFIFO_H_
+#define _RUSTFIFO_H_
+
+#include <sys/types.h>
+
+#define	RUSTFIFO_API_MAJOR	1
+#define	RUSTFIFO_API_MINOR	0
+
+struct rust_api_header {
+	uint16_t	major;
+	uint16_t	minor;
+	uint32_t	size;	/* of the whole table */
+};
+
+struct rustfifo_api {
+	struct rust_api_header header;
+	/* Bytes waiting to be read */
+	size_t	(*buffered)(void);
+	/* Queue up to len bytes without blocking; returns how many fit */
+	size_t	(*push)(const void *buf, size_t len);
+};
+
+extern const struct rustfifo_api rustfifo_api;
+
+#endif /* _RUSTFIFO_H_ */
@@ -0,0 +1,68 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The C ABI `fifo` exports to other kernel modules, declared for C
+//! consumers in `rustfifo.h`
+
+use crate::module::MODULE;
+use bsd_kernel::export::{Api, ApiHeader};
+use bsd_kernel::panic::catch_in_module;
+
+/// Version 1.0 of `rustfifo_api`; keep in step with `rustfifo.h` and
+/// `MODULE_VERSION` in `fifo.c`
+#[repr(C)]
+pub struct FifoApi {
+    pub header: ApiHeader,
+    /// Bytes waiting to be read
+    pub buffered: extern "C" fn() -> usize,
+    /// Queue up to `len` bytes from `buf` without blocking, waking readers,
+    /// and return how many fit
+    pub push: unsafe extern "C" fn(buf: *const u8, len: usize) -> usize,
+}
+
+unsafe impl Api for FifoApi {
+    const MAJOR: u16 = 1;
+    const MINOR: u16 = 0;
+}
+
+extern "C" fn buffered() -> usize {
+    catch_in_module(|| MODULE.lock().map_or(0, |m| m.buffered())).unwrap_or(0)
+}
+
+unsafe extern "C" fn push(buf: *const u8, len: usize) -> usize {
+    if buf.is_null() || len == 0 {
+        return 0;
+    }
+    let buf = unsafe { core::slice::from_raw_parts(buf, len) };
+    catch_in_module(|| MODULE.lock().map_or(0, |mut m| m.push(buf)))
+        .unwrap_or(0)
+}
+
+bsd_kernel::export_api! {
+    /// Listed in the Makefile's `EXPORT_SYMS`
+    rustfifo_api: FifoApi = FifoApi {
+        header: ApiHeader::new::<FifoApi>(FifoApi::MAJOR, FifoApi::MINOR),
+        buffered,
+        push,
+    };
+}
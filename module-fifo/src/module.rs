@@ -0,0 +1,272 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::ring::Ring;
+use alloc::boxed::Box;
+use bsd_kernel::character_device::{CDev, CharacterDevice};
+use bsd_kernel::cred::CurThread;
+use bsd_kernel::debugln;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::io::{Read, Write};
+use bsd_kernel::ioctl::{IoctlEnum, IoctlRequest};
+use bsd_kernel::log::{self, LogDevice};
+use bsd_kernel::module::{ModuleEvents, SharedModule};
+use bsd_kernel::sync::Lazy;
+use bsd_kernel::sysctl::{self, Node};
+use bsd_kernel::uio::{Offsets, UioReader, UioWriter};
+use core::ffi::CStr;
+use core::task::{Context, Poll, Waker};
+
+/// Bytes buffered before writers block
+const CAPACITY: usize = 4096;
+
+pub static MODULE: Lazy<SharedModule<Fifo>> =
+    Lazy::new(|| SharedModule::new(Fifo::new()));
+
+#[derive(Debug)]
+pub struct FifoInner {
+    ring: Ring,
+    /// Woken when data arrives
+    reader: Option<Waker>,
+    /// Woken when room is made
+    writer: Option<Waker>,
+    _cdev: Box<CDev<Fifo>>,
+    _log: Option<Box<CDev<LogDevice>>>,
+}
+
+/// Everything lives in `inner`, created on load once `MODULE` is usable
+#[derive(Default, Debug)]
+pub struct Fifo {
+    inner: Option<FifoInner>,
+}
+
+impl Fifo {
+    fn new() -> Self {
+        Fifo { inner: None }
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.inner.as_ref().map_or(0, |inner| {
+            let (a, b) = inner.ring.as_slices();
+            a.len() + b.len()
+        })
+    }
+
+    /// Queue as much of `buf` as fits, waking a blocked reader
+    pub fn push(&mut self, buf: &[u8]) -> usize {
+        let Some(ref mut inner) = self.inner else {
+            return 0;
+        };
+        let (a, b) = inner.ring.spare_mut();
+        let n = a.len().min(buf.len());
+        a[..n].copy_from_slice(&buf[..n]);
+        let m = b.len().min(buf.len() - n);
+        b[..m].copy_from_slice(&buf[n..n + m]);
+        inner.ring.commit(n + m);
+        if n + m > 0 {
+            if let Some(w) = inner.reader.take() {
+                w.wake();
+            }
+        }
+        n + m
+    }
+}
+
+/// The generic file ioctls a FIFO answers
+#[derive(IoctlEnum)]
+#[ioctl(group = b'f')]
+enum FifoIoctl {
+    /// `FIONREAD`: bytes ready to read
+    #[ioctl(ior = 127)]
+    NRead(i32),
+}
+
+bsd_kernel::sysctl! {
+    /// Adds `hw.rustmod.fifo`'s oids
+    fn fifo_sysctls {
+        u64 capacity: "Bytes buffered before writers block" =
+            || CAPACITY as u64;
+        u64 buffered: "Bytes waiting to be read" =
+            || MODULE.lock().map_or(0, |m| m.buffered() as u64);
+        proc(text, rd) log: "Recent opens and closes" = log::sysctl_dump;
+    }
+}
+
+/// Keep `cx`'s waker in `slot`, replacing a stale one
+fn register(slot: &mut Option<Waker>, cx: &Context<'_>) {
+    match slot {
+        Some(w) if w.will_wake(cx.waker()) => (),
+        _ => *slot = Some(cx.waker().clone()),
+    }
+}
+
+impl ModuleEvents for Fifo {
+    const SYSCTL_NAME: Option<&'static CStr> = Some(c"fifo");
+
+    fn sysctl(
+        &mut self,
+        root: Node,
+        ctx: &mut sysctl::Context,
+    ) -> Result<(), Errno> {
+        fifo_sysctls(ctx, root)
+    }
+
+    fn load(&mut self) {
+        debugln!("[module.rs] Fifo::load");
+
+        let m = MODULE.clone();
+        if let Some(cdev) = CDev::new_with_delegate("rustfifo", m) {
+            self.inner = Some(FifoInner {
+                ring: Ring::with_capacity(CAPACITY),
+                reader: None,
+                writer: None,
+                _cdev: cdev,
+                _log: CDev::new_with_delegate(
+                    "rustfifolog",
+                    SharedModule::new(LogDevice::new()),
+                ),
+            });
+        } else {
+            debugln!(
+                "[module.rs] Fifo::load: Failed to create character device"
+            );
+        }
+    }
+
+    fn unload(&mut self) {
+        debugln!("[module.rs] Fifo::unload");
+    }
+}
+
+impl CharacterDevice for Fifo {
+    const OFFSETS: Offsets = Offsets::Stream;
+    const DEVSTAT: bool = true;
+
+    fn open(&mut self, td: &CurThread) -> Result<(), Errno> {
+        bsd_kernel::log!(
+            "open by pid {}, {} bytes buffered",
+            td.pid(),
+            self.buffered()
+        );
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        bsd_kernel::log!("close, {} bytes buffered", self.buffered());
+        Ok(())
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.inner {
+            Some(ref mut inner) if inner.ring.is_empty() => {
+                register(&mut inner.reader, cx);
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        }
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.inner {
+            Some(ref mut inner) if inner.ring.is_full() => {
+                register(&mut inner.writer, cx);
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        }
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &mut IoctlRequest,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let Some(ref inner) = self.inner else {
+            return Err(Errno::NxIo);
+        };
+        let cmd = match req.decode()? {
+            FifoIoctl::NRead(_) => {
+                let (a, b) = inner.ring.as_slices();
+                FifoIoctl::NRead((a.len() + b.len()) as i32)
+            }
+        };
+        req.reply(&cmd);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let Some(ref mut inner) = self.inner else {
+            return Err(Errno::NxIo);
+        };
+        let (a, b) = inner.ring.as_slices();
+        let mut n = match uio.write(a) {
+            Ok(n) => n,
+            Err(e) => {
+                debugln!("{}", e);
+                return Err(Errno::Fault);
+            }
+        };
+        if n == a.len() {
+            n += uio.write(b).unwrap_or(0);
+        }
+        inner.ring.consume(n);
+        if n > 0 {
+            if let Some(w) = inner.writer.take() {
+                w.wake();
+            }
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let Some(ref mut inner) = self.inner else {
+            return Err(Errno::NxIo);
+        };
+        let (a, b) = inner.ring.spare_mut();
+        let mut n = match uio.read(a) {
+            Ok(n) => n,
+            Err(e) => {
+                debugln!("{:?}", e);
+                return Err(Errno::Fault);
+            }
+        };
+        if n == a.len() {
+            n += uio.read(b).unwrap_or(0);
+        }
+        inner.ring.commit(n);
+        if n > 0 {
+            if let Some(w) = inner.reader.take() {
+                w.wake();
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Fixed-size byte ring buffer
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+#[derive(Debug)]
+pub struct Ring {
+    buf: Box<[u8]>,
+    /// Index of the oldest byte
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    /// ## Panics
+    /// If `capacity` is zero
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Ring {
+            buf: vec![0; capacity].into_boxed_slice(),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.buf.len()
+    }
+
+    /// The buffered bytes, oldest first, in up to two pieces
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.head + self.len;
+        if end <= self.buf.len() {
+            (&self.buf[self.head..end], &[])
+        } else {
+            let (wrapped, tail) = self.buf.split_at(self.head);
+            (tail, &wrapped[..end - self.buf.len()])
+        }
+    }
+
+    /// The free space, in the order it will be filled, in up to two pieces
+    pub fn spare_mut(&mut self) -> (&mut [u8], &mut [u8]) {
+        let tail = (self.head + self.len) % self.buf.len();
+        if self.is_full() {
+            (&mut [], &mut [])
+        } else if tail >= self.head {
+            let (front, back) = self.buf.split_at_mut(tail);
+            (back, &mut front[..self.head])
+        } else {
+            (&mut self.buf[tail..self.head], &mut [])
+        }
+    }
+
+    /// Drop the `n` oldest bytes, after copying them out of `as_slices`
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.len);
+        self.head = (self.head + n) % self.buf.len();
+        self.len -= n;
+    }
+
+    /// Keep `n` bytes written into `spare_mut`
+    pub fn commit(&mut self, n: usize) {
+        assert!(n <= self.buf.len() - self.len);
+        self.len += n;
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Per-CPU latency histograms
+
+use bsd_kernel::counter::Counter;
+
+/// Number of buckets. Bucket `b` counts latencies below `1 << b` ns and
+/// at least half that; the last one also takes everything slower
+pub const BUCKETS: usize = 40;
+
+/// A log2 histogram of latencies in nanoseconds
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [Counter; BUCKETS],
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            buckets: core::array::from_fn(|_| Counter::new()),
+        }
+    }
+
+    /// Count one latency. Cheap enough for the I/O path
+    pub fn record(&self, ns: u64) {
+        let b = (u64::BITS - ns.leading_zeros()) as usize;
+        self.buckets[b.min(BUCKETS - 1)].increment();
+    }
+
+    fn snapshot(&self) -> [u64; BUCKETS] {
+        core::array::from_fn(|b| self.buckets[b].fetch())
+    }
+
+    /// Number of latencies recorded
+    pub fn count(&self) -> u64 {
+        self.snapshot().iter().sum()
+    }
+
+    /// Upper bound in ns of the bucket holding the `permille`th
+    /// thousandth of recorded latencies, or 0 if none were recorded
+    pub fn percentile(&self, permille: u64) -> u64 {
+        let buckets = self.snapshot();
+        let total: u64 = buckets.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let target = (total * permille).div_ceil(1000).max(1);
+        let mut seen = 0;
+        for (b, n) in buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return 1 << b;
+            }
+        }
+        1 << (BUCKETS - 1)
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::hist::Histogram;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::geom::{self, Bio, BioCmd, Class, Geom, GeomClass, Provider};
+use bsd_kernel::sysctl::{Context, Node};
+use bsd_kernel::time::{sbinuptime, sbt_to_duration};
+use bsd_kernel::{Module, debugln, kenv};
+use core::ffi::CStr;
+use libc::c_void;
+use spin::Mutex;
+
+/// The loaded class, and the `kern.geom.lat` node its geoms report under.
+/// Fields drop in order, so the geoms' oids are gone before the node
+struct Loaded {
+    class: Box<Class<Lat>>,
+    _sysctl: Context,
+}
+
+static LOADED: Mutex<Option<Loaded>> = Mutex::new(None);
+
+pub fn load(module: Module) -> Result<(), Errno> {
+    let mut sysctl = Context::new();
+    let root = sysctl.add_node(
+        geom::sysctl_node(),
+        c"lat",
+        c"GEOM_LAT latency statistics",
+    )?;
+    let attach = kenv::getenv(c"kern.geom.lat.attach").unwrap_or_default();
+    let class = Class::new(Lat {
+        attach: parse_attach(&attach),
+        root,
+    });
+    // Tastes the existing providers, so the root node must exist first
+    class.load(module)?;
+    *LOADED.lock() = Some(Loaded {
+        class,
+        _sysctl: sysctl,
+    });
+    Ok(())
+}
+
+pub fn unload(module: Module) -> Result<(), Errno> {
+    let mut loaded = LOADED.lock();
+    if let Some(l) = loaded.as_ref() {
+        // On failure, such as a LAT provider still being open, stay loaded
+        l.class.unload(module)?;
+    }
+    *loaded = None;
+    Ok(())
+}
+
+fn parse_attach(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|name| name.trim())
+        .map(|name| name.strip_prefix("/dev/").unwrap_or(name))
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub struct Lat {
+    /// Names of the providers to stack on
+    attach: Vec<String>,
+    root: Node,
+}
+
+pub struct LatSoftc {
+    read: Arc<Histogram>,
+    write: Arc<Histogram>,
+    _sysctl: Context,
+}
+
+impl Lat {
+    /// Publish `kern.geom.lat.<name>.{read,write}.*`
+    fn add_sysctls(
+        &self,
+        name: &str,
+        read: &Arc<Histogram>,
+        write: &Arc<Histogram>,
+    ) -> Result<Context, Errno> {
+        // Keep the node name a single component
+        let node = name.replace(['.', '/'], "_");
+        let node = CString::new(node).map_err(|_| Errno::Inval)?;
+        let mut ctx = Context::new();
+        let dev =
+            ctx.add_node(self.root, &node, c"Latencies of one provider")?;
+        for (dir, hist) in [(c"read", read), (c"write", write)] {
+            let parent = ctx.add_node(dev, dir, c"Latencies in ns")?;
+            let h = hist.clone();
+            ctx.add_u64(parent, c"count", c"Requests completed", move || {
+                h.count()
+            })?;
+            let percentiles: [(&CStr, u64); 4] =
+                [(c"p50", 500), (c"p90", 900), (c"p99", 990), (c"p999", 999)];
+            for (name, permille) in percentiles {
+                let h = hist.clone();
+                ctx.add_u64(
+                    parent,
+                    name,
+                    c"Percentile bound, ns",
+                    move || h.percentile(permille),
+                )?;
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+impl GeomClass for Lat {
+    const NAME: &'static CStr = c"LAT";
+    type Softc = LatSoftc;
+
+    fn taste(class: &Class<Self>, pp: Provider) -> Option<Geom<Self>> {
+        let name = pp.name().to_str().ok()?;
+        if !class.attach.iter().any(|a| a == name) {
+            return None;
+        }
+        let gp_name = CString::new(format!("{}.lat", name)).ok()?;
+        let read = Arc::new(Histogram::new());
+        let write = Arc::new(Histogram::new());
+        let sysctl = match class.add_sysctls(name, &read, &write) {
+            Ok(sysctl) => sysctl,
+            Err(e) => {
+                debugln!("[lat.rs] {}: sysctls: {}", name, e);
+                return None;
+            }
+        };
+        let gp = class.new_geom(
+            &gp_name,
+            LatSoftc {
+                read,
+                write,
+                _sysctl: sysctl,
+            },
+        );
+        let cp = gp.new_consumer();
+        if let Err(e) = cp.attach(pp) {
+            debugln!("[lat.rs] {}: attach: {}", name, e);
+            gp.wither(e);
+            return None;
+        }
+        let lpp = gp.new_provider(&gp_name);
+        lpp.set_mediasize(pp.mediasize());
+        lpp.set_sectorsize(pp.sectorsize());
+        lpp.set_stripesize(pp.stripesize());
+        lpp.set_stripeoffset(pp.stripeoffset());
+        lpp.set_accepts_unmapped(pp.accepts_unmapped());
+        lpp.set_error(None);
+        Some(gp)
+    }
+
+    fn start(&self, gp: Geom<Self>, bio: Bio) {
+        let Some(cp) = gp.consumer() else {
+            return bio.deliver(Err(Errno::NxIo));
+        };
+        let Some(mut cbp) = bio.clone_bio() else {
+            return bio.deliver(Err(Errno::NoMem));
+        };
+        cbp.set_caller1(sbinuptime() as *mut c_void);
+        gp.request(cbp, cp);
+    }
+
+    fn speedup(&self, gp: Geom<Self>, bio: Bio) {
+        self.start(gp, bio);
+    }
+
+    fn done(&self, gp: Geom<Self>, bio: Bio) {
+        let elapsed = sbinuptime() - bio.caller1() as i64;
+        let ns = sbt_to_duration(elapsed).as_nanos() as u64;
+        match bio.cmd() {
+            BioCmd::Read => unsafe { gp.softc() }.read.record(ns),
+            BioCmd::Write => unsafe { gp.softc() }.write.record(ns),
+            _ => (),
+        }
+        bio.std_done();
+    }
+}
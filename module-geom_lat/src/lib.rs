@@ -0,0 +1,82 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_std]
+
+//! Example GEOM class written in Rust: `LAT` stacks a transparent
+//! `<provider>.lat` on each provider named in the `kern.geom.lat.attach`
+//! tunable, and keeps per-CPU histograms of how long reads and writes take
+//! to come back from below. Comparing them with a C class such as `NOP`
+//! on the same disk gives the overhead of the Rust I/O path.
+//!
+//! To build and try it:
+//! ```bash,ignore
+//! ./build.sh module-geom_lat
+//! sudo kenv kern.geom.lat.attach=md0
+//! sudo make -C module-geom_lat load
+//! dd if=/dev/md0.lat of=/dev/null bs=64k
+//! sysctl kern.geom.lat.md0
+//! sudo make -C module-geom_lat unload
+//! ```
+//!
+//! The `p50`, `p90`, `p99` and `p999` values are the upper bounds in ns of
+//! the histogram buckets holding those percentiles.
+
+use bsd_kernel::allocator::KernelAllocator;
+use bsd_kernel::module::{Abi, ModuleEventType, check_abi};
+use core::panic::PanicInfo;
+use libc::{c_int, c_void};
+
+mod hist;
+mod lat;
+
+extern crate alloc;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    bsd_kernel::panic::handle(info)
+}
+
+/// Main event handler for module events
+#[unsafe(no_mangle)]
+pub extern "C" fn module_event(
+    module: bsd_kernel::Module,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    let result = match ModuleEventType::from_i32(event) {
+        Some(ModuleEventType::Load) => {
+            check_abi(Abi::Exact).and_then(|()| lat::load(module))
+        }
+        Some(ModuleEventType::Unload) => lat::unload(module),
+        Some(_) => Ok(()),
+        None => Err(bsd_kernel::errno::Errno::OpNotSupp),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
@@ -0,0 +1,63 @@
+/*
+ * Issue RCAT control requests, which geom(8) has no class library for:
+ *
+ *	rcatctl [-s stripesize] create name provider ...
+ *	rcatctl destroy name
+ *
+ * Build with: cc -o rcatctl rcatctl.c -lgeom
+ */
+
+#include <err.h>
+#include <inttypes.h>
+#include <libgeom.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+static void
+usage(void)
+{
+	fprintf(stderr, "usage: rcatctl [-s stripesize] create name provider ...\n"
+	    "       rcatctl destroy name\n");
+	exit(1);
+}
+
+int
+main(int argc, char **argv)
+{
+	struct gctl_req *req;
+	const char *errstr;
+	char param[16];
+	intmax_t stripesize = 0;
+	int ch, i, nargs;
+
+	while ((ch = getopt(argc, argv, "s:")) != -1) {
+		switch (ch) {
+		case 's':
+			stripesize = strtoimax(optarg, NULL, 0);
+			break;
+		default:
+			usage();
+		}
+	}
+	argc -= optind;
+	argv += optind;
+	if (argc < 2)
+		usage();
+
+	req = gctl_get_handle();
+	gctl_ro_param(req, "class", -1, "RCAT");
+	gctl_ro_param(req, "verb", -1, argv[0]);
+	nargs = argc - 1;
+	gctl_ro_param(req, "nargs", sizeof(nargs), &nargs);
+	gctl_ro_param(req, "stripesize", sizeof(stripesize), &stripesize);
+	for (i = 0; i < nargs; i++) {
+		snprintf(param, sizeof(param), "arg%d", i);
+		gctl_ro_param(req, param, -1, argv[i + 1]);
+	}
+	errstr = gctl_issue(req);
+	if (errstr != NULL)
+		errx(1, "%s", errstr);
+	gctl_free(req);
+	return (0);
+}
@@ -0,0 +1,82 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_std]
+
+//! Example GEOM class written in Rust: `RCAT` joins several providers
+//! into one, either end to end (concatenation) or interleaved in fixed
+//! size chunks (striping). Requests are split across the consumers and
+//! completed once every piece is in. Ordered requests, such as the
+//! flushes filesystems use as write barriers, are kept in place across
+//! the disks.
+//!
+//! Geoms are created and destroyed through control requests. `geom(8)`
+//! only passes class-specific verbs on through a class library, so
+//! `rcatctl.c` issues them directly with `libgeom(3)`:
+//! ```bash,ignore
+//! ./build.sh module-geom_rcat
+//! cc -o rcatctl module-geom_rcat/rcatctl.c -lgeom
+//! sudo make -C module-geom_rcat load
+//! sudo ./rcatctl create both md0 md1              # /dev/rcat/both
+//! sudo ./rcatctl -s 65536 create fast md2 md3     # 64k stripes
+//! sudo ./rcatctl destroy both
+//! sudo make -C module-geom_rcat unload
+//! ```
+
+use bsd_kernel::allocator::KernelAllocator;
+use bsd_kernel::module::{Abi, ModuleEventType, check_abi};
+use core::panic::PanicInfo;
+use libc::{c_int, c_void};
+
+mod rcat;
+
+extern crate alloc;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    bsd_kernel::panic::handle(info)
+}
+
+/// Main event handler for module events
+#[unsafe(no_mangle)]
+pub extern "C" fn module_event(
+    module: bsd_kernel::Module,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    let result = match ModuleEventType::from_i32(event) {
+        Some(ModuleEventType::Load) => {
+            check_abi(Abi::Exact).and_then(|()| rcat::load(module))
+        }
+        Some(ModuleEventType::Unload) => rcat::unload(module),
+        Some(_) => Ok(()),
+        None => Err(bsd_kernel::errno::Errno::OpNotSupp),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::vec::Vec;
+use bsd_kernel::Module;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::geom::{
+    Bio, BioCmd, BioList, Class, Consumer, CtlReq, DirectDispatch, Geom,
+    GeomClass, Provider,
+};
+use core::ffi::CStr;
+use libc::c_void;
+use spin::Mutex;
+
+static CLASS: Mutex<Option<Box<Class<Rcat>>>> = Mutex::new(None);
+
+pub fn load(module: Module) -> Result<(), Errno> {
+    let class = Class::new(Rcat);
+    class.load(module)?;
+    *CLASS.lock() = Some(class);
+    Ok(())
+}
+
+pub fn unload(module: Module) -> Result<(), Errno> {
+    let mut class = CLASS.lock();
+    if let Some(c) = class.as_ref() {
+        // Fails while any RCAT provider is open
+        c.unload(module)?;
+    }
+    *class = None;
+    Ok(())
+}
+
+pub struct Rcat;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Layout {
+    Concat,
+    /// Chunks of this many bytes, dealt round the disks in turn
+    Stripe(i64),
+}
+
+struct Disk {
+    cp: Consumer,
+    /// Where the disk's first byte sits in a concatenation
+    start: i64,
+    size: i64,
+}
+
+pub struct RcatSoftc {
+    layout: Layout,
+    disks: Vec<Disk>,
+    order: Mutex<Order>,
+}
+
+/// Keeps ordered requests in place. The pieces of requests on different
+/// disks could otherwise complete in any order, so an ordered request
+/// waits for everything in flight, and everything after it waits for it
+#[derive(Default)]
+struct Order {
+    /// Clones in flight, plus one for each request still being split
+    inflight: usize,
+    /// Whether an ordered request is in flight
+    barrier: bool,
+    /// Requests waiting their turn, in arrival order
+    held: BioList,
+}
+
+impl Order {
+    fn can_start(&self, bio: &Bio) -> bool {
+        !self.barrier && (!bio.is_ordered() || self.inflight == 0)
+    }
+
+    fn admit(&mut self, bio: &Bio) {
+        self.inflight += 1;
+        self.barrier |= bio.is_ordered();
+    }
+}
+
+/// The part of a request that falls on one disk
+struct Piece {
+    disk: usize,
+    offset: i64,
+    length: i64,
+    /// Offset of the piece within the request's data
+    pos: i64,
+}
+
+impl RcatSoftc {
+    fn mediasize(&self) -> i64 {
+        match self.layout {
+            Layout::Concat => self.disks.iter().map(|d| d.size).sum(),
+            Layout::Stripe(s) => {
+                let smallest = self.disks.iter().map(|d| d.size).min();
+                smallest.unwrap_or(0) / s * s * self.disks.len() as i64
+            }
+        }
+    }
+
+    /// Split `length` bytes at `offset` into per-disk pieces, in order
+    fn pieces(
+        &self,
+        offset: i64,
+        length: i64,
+    ) -> impl Iterator<Item = Piece> + '_ {
+        let end = offset + length;
+        let mut pos = offset;
+        core::iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+            let (disk, doff, avail) = match self.layout {
+                Layout::Concat => {
+                    let i = self
+                        .disks
+                        .iter()
+                        .position(|d| pos < d.start + d.size)?;
+                    let d = &self.disks[i];
+                    (i, pos - d.start, d.start + d.size - pos)
+                }
+                Layout::Stripe(s) => {
+                    let n = self.disks.len() as i64;
+                    let stripe = pos / s;
+                    let doff = stripe / n * s + pos % s;
+                    ((stripe % n) as usize, doff, s - pos % s)
+                }
+            };
+            let piece = Piece {
+                disk,
+                offset: doff,
+                length: avail.min(end - pos),
+                pos: pos - offset,
+            };
+            pos += piece.length;
+            Some(piece)
+        })
+    }
+
+    /// Start `bio` now, or hold it until the requests it must follow
+    /// are done
+    fn submit(&self, gp: Geom<Rcat>, bio: Bio) {
+        {
+            let mut order = self.order.lock();
+            if !order.held.is_empty() || !order.can_start(&bio) {
+                order.held.push_back(bio);
+                return;
+            }
+            order.admit(&bio);
+        }
+        self.dispatch(gp, bio);
+    }
+
+    /// Split an admitted request and send the pieces, then drop the count
+    /// it was admitted with
+    fn dispatch(&self, gp: Geom<Rcat>, bio: Bio) {
+        match self.split(&bio) {
+            Ok(list) if list.is_empty() => bio.deliver(Ok(())),
+            Ok(list) => {
+                self.order.lock().inflight += list.len();
+                self.send(gp, list);
+            }
+            Err(e) => bio.deliver(Err(e)),
+        }
+        self.release(gp);
+    }
+
+    /// Count one clone or split finished. Once nothing is in flight, start
+    /// the held requests up to the next one that must wait again
+    fn release(&self, gp: Geom<Rcat>) {
+        let mut ready = BioList::new();
+        {
+            let mut order = self.order.lock();
+            order.inflight -= 1;
+            if order.inflight > 0 {
+                return;
+            }
+            order.barrier = false;
+            while order.held.front().is_some_and(|bio| order.can_start(bio)) {
+                let bio = order.held.pop_front().unwrap();
+                order.admit(&bio);
+                ready.push_back(bio);
+            }
+        }
+        while let Some(bio) = ready.pop_front() {
+            self.dispatch(gp, bio);
+        }
+    }
+
+    /// Clone `bio` for each disk it touches, with the disk's index in
+    /// `caller2`. Every clone is made before any is sent, so the parent
+    /// can't complete while pieces are still being added
+    fn split(&self, bio: &Bio) -> Result<BioList, Errno> {
+        let mut list = BioList::new();
+        if matches!(bio.cmd(), BioCmd::Flush | BioCmd::Speedup) {
+            for disk in 0..self.disks.len() {
+                let mut cbp = bio.clone_bio().ok_or(Errno::NoMem)?;
+                cbp.set_caller2(disk as *mut c_void);
+                list.push_back(cbp);
+            }
+            return Ok(list);
+        }
+        for piece in self.pieces(bio.offset(), bio.length()) {
+            let mut cbp = bio.clone_bio().ok_or(Errno::NoMem)?;
+            cbp.set_offset(piece.offset);
+            cbp.set_length(piece.length);
+            if let Some(data) = bio.data() {
+                cbp.set_data(unsafe { data.add(piece.pos as usize) });
+            }
+            cbp.set_caller2(piece.disk as *mut c_void);
+            list.push_back(cbp);
+        }
+        Ok(list)
+    }
+
+    /// Send each clone on `list` to the disk stashed in its `caller2`
+    fn send(&self, gp: Geom<Rcat>, mut list: BioList) {
+        while let Some(cbp) = list.pop_front() {
+            let cp = self.disks[cbp.caller2() as usize].cp;
+            gp.request(cbp, cp);
+        }
+    }
+}
+
+impl GeomClass for Rcat {
+    const NAME: &'static CStr = c"RCAT";
+    type Softc = RcatSoftc;
+
+    fn ctlreq(class: &Class<Self>, req: &mut CtlReq, verb: &CStr) {
+        match verb.to_bytes() {
+            b"create" => ctl_create(class, req),
+            b"destroy" => ctl_destroy(class, req),
+            _ => req.error("Unknown verb."),
+        }
+    }
+
+    fn start(&self, gp: Geom<Self>, bio: Bio) {
+        match bio.cmd() {
+            BioCmd::Read | BioCmd::Write | BioCmd::Delete | BioCmd::Flush => {
+                unsafe { gp.softc() }.submit(gp, bio)
+            }
+            _ => bio.deliver(Err(Errno::OpNotSupp)),
+        }
+    }
+
+    fn speedup(&self, gp: Geom<Self>, bio: Bio) {
+        unsafe { gp.softc() }.submit(gp, bio);
+    }
+
+    fn done(&self, gp: Geom<Self>, bio: Bio) {
+        bio.std_done();
+        unsafe { gp.softc() }.release(gp);
+    }
+
+    fn access(
+        &self,
+        gp: Geom<Self>,
+        _pp: Provider,
+        dr: i32,
+        dw: i32,
+        de: i32,
+    ) -> Result<(), Errno> {
+        let disks = &unsafe { gp.softc() }.disks;
+        for (i, d) in disks.iter().enumerate() {
+            if let Err(e) = d.cp.access(dr, dw, de) {
+                for d in &disks[..i] {
+                    let _ = d.cp.access(-dr, -dw, -de);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Nothing sleeps, and no lock is held while bios are sent or delivered
+unsafe impl DirectDispatch for Rcat {}
+
+/// `create <name> <provider> ...`, striped if `stripesize` is non-zero
+fn ctl_create(class: &Class<Rcat>, req: &mut CtlReq) {
+    let Some(nargs) = req.param_int(c"nargs") else {
+        return req.error("No 'nargs' argument.");
+    };
+    if nargs < 2 {
+        return req.error("Too few arguments.");
+    }
+    let Some(name) = req.param_str(c"arg0").map(CString::from) else {
+        return req.error("No 'arg0' argument.");
+    };
+    if class.geoms().any(|gp| gp.name() == name.as_c_str()) {
+        return req.error("Geom already exists.");
+    }
+    let mut pps = Vec::new();
+    for i in 1..nargs {
+        let arg = CString::new(format!("arg{}", i)).unwrap();
+        // Reports a missing provider itself
+        let Some(pp) = req.provider(&arg) else {
+            return;
+        };
+        pps.push(pp);
+    }
+    let sectorsize = pps[0].sectorsize();
+    if pps.iter().any(|pp| pp.sectorsize() != sectorsize) {
+        return req.error("Providers have different sector sizes.");
+    }
+    let layout = match req.param_i64(c"stripesize").unwrap_or(0) {
+        0 => Layout::Concat,
+        s if s > 0 && s % i64::from(sectorsize) == 0 => Layout::Stripe(s),
+        _ => {
+            return req
+                .error("Stripe size must be a multiple of the sector size.");
+        }
+    };
+    let result =
+        class.new_geom_with(&name, |gp| build(gp, &name, layout, &pps));
+    if let Err(e) = result {
+        req.error(&format!("Cannot create {:?}: {}.", name, e));
+    }
+}
+
+fn build(
+    gp: Geom<Rcat>,
+    name: &CStr,
+    layout: Layout,
+    pps: &[Provider],
+) -> Result<RcatSoftc, Errno> {
+    let mut disks = Vec::with_capacity(pps.len());
+    let mut start = 0;
+    for &pp in pps {
+        let cp = gp.new_direct_consumer();
+        cp.attach(pp)?;
+        let size = pp.mediasize() - pp.mediasize() % i64::from(pp.sectorsize());
+        disks.push(Disk { cp, start, size });
+        start += size;
+    }
+    let sc = RcatSoftc {
+        layout,
+        disks,
+        order: Mutex::new(Order::default()),
+    };
+    let pp_name = format!("rcat/{}", name.to_string_lossy());
+    let pp = gp
+        .new_direct_provider(&CString::new(pp_name).map_err(|_| Errno::Inval)?);
+    pp.set_mediasize(sc.mediasize());
+    pp.set_sectorsize(pps[0].sectorsize());
+    if let Layout::Stripe(s) = layout {
+        pp.set_stripesize(s);
+        pp.set_stripeoffset(0);
+    }
+    pp.set_error(None);
+    Ok(sc)
+}
+
+/// `destroy <name>`
+fn ctl_destroy(class: &Class<Rcat>, req: &mut CtlReq) {
+    let Some(name) = req.param_str(c"arg0").map(CString::from) else {
+        return req.error("No 'arg0' argument.");
+    };
+    let Some(gp) = class.geoms().find(|gp| gp.name() == name.as_c_str()) else {
+        return req.error("No such geom.");
+    };
+    if let Err(e) = class.destroy(gp) {
+        req.error(&format!("Cannot destroy {:?}: {}.", name, e));
+    }
+}
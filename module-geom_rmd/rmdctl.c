@@ -0,0 +1,109 @@
+/*
+ * Issue RMD control requests, which geom(8) has no class library for:
+ *
+ *	rmdctl create [-t type] [-s size] [-S sectorsize] [-f file] [name]
+ *	rmdctl destroy name ...
+ *
+ * The type is malloc, the default, vnode or swap, and a vnode disk needs
+ * the file to use. Sizes take the suffixes expand_number(3) does; a vnode
+ * disk left without one is the size of its file. Without a name the next
+ * free rmdN is used.
+ *
+ * Build with: cc -o rmdctl rmdctl.c -lgeom -lutil
+ */
+
+#include <err.h>
+#include <libgeom.h>
+#include <libutil.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+static void
+usage(void)
+{
+	fprintf(stderr, "usage: rmdctl create [-t type] [-s size] "
+	    "[-S sectorsize] [-f file] [name]\n"
+	    "       rmdctl destroy name ...\n");
+	exit(1);
+}
+
+static intmax_t
+number(const char *arg)
+{
+	uint64_t n;
+
+	if (expand_number(arg, &n) != 0 || n > INTMAX_MAX)
+		errx(1, "invalid number: %s", arg);
+	return ((intmax_t)n);
+}
+
+int
+main(int argc, char **argv)
+{
+	struct gctl_req *req;
+	const char *errstr, *file = NULL, *type = NULL, *verb;
+	char param[16];
+	intmax_t sectorsize = -1, size = -1;
+	int ch, i, nargs;
+
+	if (argc < 2)
+		usage();
+	verb = argv[1];
+	argc--;
+	argv++;
+	while ((ch = getopt(argc, argv, "f:s:S:t:")) != -1) {
+		if (strcmp(verb, "create") != 0)
+			usage();
+		switch (ch) {
+		case 'f':
+			file = optarg;
+			break;
+		case 's':
+			size = number(optarg);
+			break;
+		case 'S':
+			sectorsize = number(optarg);
+			break;
+		case 't':
+			type = optarg;
+			break;
+		default:
+			usage();
+		}
+	}
+	argc -= optind;
+	argv += optind;
+	if (strcmp(verb, "create") == 0 ? argc > 1 :
+	    strcmp(verb, "destroy") != 0 || argc < 1)
+		usage();
+
+	req = gctl_get_handle();
+	gctl_ro_param(req, "class", -1, "RMD");
+	gctl_ro_param(req, "verb", -1, verb);
+	nargs = argc;
+	gctl_ro_param(req, "nargs", sizeof(nargs), &nargs);
+	if (type != NULL)
+		gctl_ro_param(req, "type", -1, type);
+	if (file != NULL) {
+		/* The kernel resolves it from its own root, not our cwd */
+		if ((file = realpath(file, NULL)) == NULL)
+			err(1, "realpath");
+		gctl_ro_param(req, "file", -1, file);
+	}
+	if (size != -1)
+		gctl_ro_param(req, "size", sizeof(size), &size);
+	if (sectorsize != -1)
+		gctl_ro_param(req, "sectorsize", sizeof(sectorsize), &sectorsize);
+	for (i = 0; i < nargs; i++) {
+		snprintf(param, sizeof(param), "arg%d", i);
+		gctl_ro_param(req, param, -1, argv[i]);
+	}
+	errstr = gctl_issue(req);
+	if (errstr != NULL)
+		errx(1, "%s", errstr);
+	gctl_free(req);
+	return (0);
+}
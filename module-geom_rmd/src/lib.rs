@@ -0,0 +1,107 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_std]
+
+//! Example GEOM class written in Rust: `RMD` is a memory disk, like
+//! `md(4)`. Loading it creates `/dev/rmd0`, whose contents live in kernel
+//! memory until the module is unloaded. The size and sector size are read
+//! from kernel environment variables at load time:
+//! ```bash,ignore
+//! ./build.sh module-geom_rmd
+//! sudo kenv kern.geom.rmd.size=64m kern.geom.rmd.sectorsize=4096
+//! sudo make -C module-geom_rmd load
+//! sudo newfs /dev/rmd0 && sudo mount /dev/rmd0 /mnt
+//! geom rmd list
+//! vmstat -m | grep rmd
+//! sudo umount /mnt
+//! sudo make -C module-geom_rmd unload
+//! ```
+//! More disks are made with `rmdctl.c`, backed by kernel memory, by a
+//! regular file, whose size they take unless given one, or by swap, which
+//! unlike kernel memory can be paged out:
+//! ```bash,ignore
+//! cc -o rmdctl module-geom_rmd/rmdctl.c -lgeom -lutil
+//! truncate -s 1g /var/tmp/disk.img
+//! sudo ./rmdctl create -t vnode -f /var/tmp/disk.img    # /dev/rmd1
+//! sudo ./rmdctl create -t swap -s 4g -S 4096 scratch    # /dev/scratch
+//! sudo ./rmdctl destroy rmd1 scratch
+//! ```
+//! File and swap disks serve requests on a worker thread, as their I/O
+//! sleeps. Files are opened, and swap reserved, by GEOM's event thread,
+//! so with the kernel's credentials rather than those of `rmdctl`, and
+//! with the topology lock held, which holds up any other GEOM changes
+//! until the file is open. Only malloc disks take `BIO_DELETE`.
+//!
+//! It tells `devd(8)` as `rmd0` is created and destroyed, and of requests
+//! that fail, as events of the `RUST` system and `rmd` subsystem:
+//! ```text,ignore
+//! notify 0 {
+//!     match "system"    "RUST";
+//!     match "subsystem" "rmd";
+//!     match "type"      "MEDIA_ERROR";
+//!     action "logger -t rmd $cmd of $length bytes at $offset failed: $error";
+//! };
+//! ```
+//! With its `ktr` feature, for kernels built with `options KTR`, it traces
+//! each bio in the `KTR_GEOM` class, which `sysctl debug.ktr.mask=0x4000000`
+//! turns on and `ktrdump(8)` prints.
+
+use bsd_kernel::allocator::KernelAllocator;
+use bsd_kernel::module::{Abi, ModuleEventType, check_abi};
+use core::panic::PanicInfo;
+use libc::{c_int, c_void};
+
+mod rmd;
+extern crate alloc;
+
+bsd_kernel::malloc_type!(static M_RMD = c"rmd");
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::with_type(&M_RMD);
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    bsd_kernel::panic::handle(info)
+}
+
+/// Main event handler for module events
+#[unsafe(no_mangle)]
+pub extern "C" fn module_event(
+    module: bsd_kernel::Module,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    let result = match ModuleEventType::from_i32(event) {
+        Some(ModuleEventType::Load) => {
+            check_abi(Abi::Exact).and_then(|()| rmd::load(module))
+        }
+        Some(ModuleEventType::Unload) => rmd::unload(module),
+        Some(_) => Ok(()),
+        None => Err(bsd_kernel::errno::Errno::OpNotSupp),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
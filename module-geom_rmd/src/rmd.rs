@@ -0,0 +1,459 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bsd_kernel::devctl::{Event, EventBuf};
+use bsd_kernel::errno::Errno;
+use bsd_kernel::executor::{Executor, JoinHandle};
+use bsd_kernel::geom::{
+    Attr, Attributes, Bio, BioCmd, BioQueue, Class, Conf, ConfPart, CtlReq,
+    Geom, GeomClass, Provider,
+};
+use bsd_kernel::ktr::KTR_GEOM;
+use bsd_kernel::sync::Mutex;
+use bsd_kernel::vm::SwapObject;
+use bsd_kernel::vnode::File;
+use bsd_kernel::{Module, kenv, ktr, log};
+use core::ffi::CStr;
+use core::slice;
+
+/// Defaults for the `kern.geom.rmd` tunables, and for `create`
+const DEFAULT_SIZE: u64 = 32 << 20;
+const DEFAULT_SECTORSIZE: u32 = 512;
+
+static CLASS: spin::Mutex<Option<Box<Class<Rmd>>>> = spin::Mutex::new(None);
+
+pub fn load(module: Module) -> Result<(), Errno> {
+    let size = kenv::get::<u64>(c"kern.geom.rmd.size").unwrap_or(DEFAULT_SIZE);
+    let sectorsize = kenv::get::<u32>(c"kern.geom.rmd.sectorsize")
+        .unwrap_or(DEFAULT_SECTORSIZE);
+    check_size(size, sectorsize)?;
+    let executor = Executor::new(c"g_rmd", 1)?;
+    let class = Class::new(Rmd {
+        executor,
+        size,
+        sectorsize,
+    });
+    class.load(module)?;
+    *CLASS.lock() = Some(class);
+    Ok(())
+}
+
+pub fn unload(module: Module) -> Result<(), Errno> {
+    let mut class = CLASS.lock();
+    if let Some(c) = class.as_ref() {
+        // Fails while any disk is open
+        c.unload(module)?;
+    }
+    *class = None;
+    Ok(())
+}
+
+/// A size that is a whole, non-zero number of power-of-two sectors
+fn check_size(size: u64, sectorsize: u32) -> Result<(), Errno> {
+    if !sectorsize.is_power_of_two()
+        || size == 0
+        || size % u64::from(sectorsize) != 0
+    {
+        return Err(Errno::Inval);
+    }
+    Ok(())
+}
+
+pub struct Rmd {
+    /// Runs the workers of disks whose I/O sleeps. Dropped with the
+    /// class, after its geoms
+    executor: Executor,
+    /// The malloc disk to create when the class is loaded
+    size: u64,
+    sectorsize: u32,
+}
+
+/// What a disk keeps its contents in, as asked for by `create`
+enum Kind {
+    Malloc,
+    Vnode(CString),
+    Swap,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Malloc => "malloc",
+            Kind::Vnode(_) => "vnode",
+            Kind::Swap => "swap",
+        }
+    }
+}
+
+/// A disk's contents
+enum Store {
+    /// Kernel memory. Copying in and out doesn't sleep, so a default
+    /// mutex, held for one copy at a time, will do
+    Malloc(Mutex<Box<[u8]>>),
+    /// A regular file, opened by path
+    Vnode { file: File, path: CString },
+    /// Pageable memory, which can go out to swap
+    Swap(SwapObject),
+}
+
+impl Store {
+    fn kind(&self) -> &'static str {
+        match self {
+            Store::Malloc(_) => "malloc",
+            Store::Vnode { .. } => "vnode",
+            Store::Swap(_) => "swap",
+        }
+    }
+
+    /// Carry out `bio`. Sleeps, other than for a malloc store
+    fn serve(&self, bio: &mut Bio) -> Result<(), Errno> {
+        let cmd = bio.cmd();
+        if cmd == BioCmd::Flush {
+            return match self {
+                Store::Vnode { file, .. } => file.sync(),
+                _ => Ok(()),
+            };
+        }
+        // GEOM has already checked the request against the media size
+        let offset = u64::try_from(bio.offset()).map_err(|_| Errno::Io)?;
+        let length = usize::try_from(bio.length()).map_err(|_| Errno::Io)?;
+        match cmd {
+            // Only advertised for malloc stores
+            BioCmd::Delete => match self {
+                Store::Malloc(store) => {
+                    region(&mut store.lock(), offset, length)?.fill(0)
+                }
+                _ => return Err(Errno::OpNotSupp),
+            },
+            BioCmd::Read | BioCmd::Write => {
+                // Mapped, as the provider doesn't accept unmapped bios
+                let data = bio.data().ok_or(Errno::Inval)?;
+                let data = unsafe { slice::from_raw_parts_mut(data, length) };
+                let read = cmd == BioCmd::Read;
+                match self {
+                    Store::Malloc(store) => {
+                        let mut store = store.lock();
+                        let disk = region(&mut store, offset, length)?;
+                        if read {
+                            data.copy_from_slice(disk);
+                        } else {
+                            disk.copy_from_slice(data);
+                        }
+                    }
+                    Store::Vnode { file, .. } if read => {
+                        // Past the end of a short file reads as zeroes
+                        let n = file.read_at(offset, data)?;
+                        data[n..].fill(0);
+                    }
+                    Store::Vnode { file, .. } => file.write_at(offset, data)?,
+                    Store::Swap(object) if read => object.read(offset, data)?,
+                    Store::Swap(object) => object.write(offset, data)?,
+                }
+            }
+            _ => return Err(Errno::OpNotSupp),
+        }
+        bio.set_completed(length as i64);
+        Ok(())
+    }
+}
+
+/// The `length` bytes of a malloc store at `offset`
+fn region(
+    store: &mut [u8],
+    offset: u64,
+    length: usize,
+) -> Result<&mut [u8], Errno> {
+    let offset = usize::try_from(offset).map_err(|_| Errno::Io)?;
+    store.get_mut(offset..offset + length).ok_or(Errno::Io)
+}
+
+/// State shared by a disk's I/O path and its worker
+struct Shared {
+    store: Store,
+    /// Requests passed on by `start`, when serving them sleeps
+    bios: BioQueue,
+}
+
+impl Shared {
+    async fn serve(self: Arc<Self>, gp: Geom<Rmd>) {
+        while let Some(mut bio) = self.bios.next().await {
+            let result = self.store.serve(&mut bio);
+            if let Err(e) = result {
+                media_error(gp, &bio, e);
+            }
+            bio.deliver(result);
+        }
+    }
+}
+
+pub struct RmdSoftc {
+    shared: Arc<Shared>,
+    /// Serves `bios`, for all but malloc stores
+    worker: Option<JoinHandle<()>>,
+    attrs: Attributes<RmdSoftc>,
+}
+
+impl Drop for RmdSoftc {
+    fn drop(&mut self) {
+        // The provider is gone, so nothing more will be queued
+        self.shared.bios.close();
+        self.shared.bios.drain();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Tell devd that `bio` failed with `e`
+fn media_error(gp: Geom<Rmd>, bio: &Bio, e: Errno) {
+    // Built on the stack, as `start` mustn't sleep
+    EventBuf::<128>::new(c"RUST", c"rmd", c"MEDIA_ERROR")
+        .with("cdev", gp.name().to_str().unwrap_or("rmd"))
+        .with("cmd", format_args!("{:?}", bio.cmd()))
+        .with("offset", bio.offset())
+        .with("length", bio.length())
+        .with("error", e.as_raw())
+        .notify();
+}
+
+impl GeomClass for Rmd {
+    const NAME: &'static CStr = c"RMD";
+    type Softc = RmdSoftc;
+
+    fn init(class: &Class<Self>) {
+        let (size, sectorsize) = (class.size, class.sectorsize);
+        let result = class.new_geom_with(c"rmd0", |gp| {
+            build(class, gp, Kind::Malloc, Some(size), sectorsize)
+        });
+        if let Err(e) = result {
+            log!(LOG_ERR, "geom_rmd: cannot create rmd0: {}", e);
+        }
+    }
+
+    fn ctlreq(class: &Class<Self>, req: &mut CtlReq, verb: &CStr) {
+        req.dispatch(
+            class,
+            verb,
+            &[(c"create", ctl_create), (c"destroy", ctl_destroy)],
+        );
+    }
+
+    fn start(&self, gp: Geom<Self>, bio: Bio) {
+        ktr!(
+            KTR_GEOM,
+            "rmd_start: bp %p offset %jd length %jd",
+            bio.as_ptr(),
+            bio.offset(),
+            bio.length()
+        );
+        let sc = unsafe { gp.softc() };
+        let Some(mut bio) = sc.attrs.handle(sc, bio) else {
+            return;
+        };
+        if !matches!(sc.shared.store, Store::Malloc(_)) {
+            return sc.shared.bios.push(bio);
+        }
+        let result = sc.shared.store.serve(&mut bio);
+        if let Err(e) = result {
+            media_error(gp, &bio, e);
+        }
+        bio.deliver(result);
+    }
+
+    /// Nothing to open below, and GEOM keeps the counts that stop the
+    /// disk being destroyed while in use
+    fn access(
+        &self,
+        _gp: Geom<Self>,
+        _pp: Provider,
+        _dr: i32,
+        _dw: i32,
+        _de: i32,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// As the default, but announcing the disk's end to devd
+    fn destroy(&self, gp: Geom<Self>) -> Result<(), Errno> {
+        if gp.providers().any(|pp| pp.is_open()) {
+            return Err(Errno::Busy);
+        }
+        gp.wither(Errno::NxIo);
+        Event::new("RUST", "rmd", "DESTROY")
+            .with("cdev", gp.name().to_string_lossy())
+            .notify();
+        Ok(())
+    }
+
+    /// As `md(4)` lists its disks
+    fn dumpconf(&self, gp: Geom<Self>, conf: &mut Conf, part: ConfPart) {
+        let ConfPart::Provider(pp) = part else { return };
+        let store = &unsafe { gp.softc() }.shared.store;
+        conf.element(c"type", store.kind());
+        if let Store::Vnode { path, .. } = store {
+            conf.element(c"file", path.to_string_lossy());
+        }
+        conf.element(c"length", pp.mediasize());
+        conf.element(c"sectorsize", pp.sectorsize());
+    }
+}
+
+/// `create [name]`, taking the next free `rmdN` if no name is given
+fn ctl_create(class: &Class<Rmd>, req: &mut CtlReq) {
+    let kind = req.param_str(c"type").map(CString::from);
+    let file = req.param_str(c"file").map(CString::from);
+    let kind = match (kind.as_deref().map(CStr::to_bytes), file) {
+        (None | Some(b"malloc"), _) => Kind::Malloc,
+        (Some(b"swap"), _) => Kind::Swap,
+        (Some(b"vnode"), Some(file)) => Kind::Vnode(file),
+        (Some(b"vnode"), None) => return req.error("Missing file."),
+        (Some(_), _) => return req.error("Invalid type."),
+    };
+    // A file's size is its own unless given
+    let size = match (req.param_i64(c"size"), &kind) {
+        (Some(n), _) => match u64::try_from(n) {
+            Ok(size) => Some(size),
+            Err(_) => return req.error("Invalid size."),
+        },
+        (None, Kind::Vnode(_)) => None,
+        (None, _) => Some(DEFAULT_SIZE),
+    };
+    let sectorsize = match req.param_i64(c"sectorsize") {
+        Some(n) => match u32::try_from(n) {
+            Ok(s) if s.is_power_of_two() => s,
+            _ => return req.error("Invalid sector size."),
+        },
+        None => DEFAULT_SECTORSIZE,
+    };
+    if size.is_some_and(|size| check_size(size, sectorsize).is_err()) {
+        return req.error("Size must be a whole number of sectors.");
+    }
+    let name = match req.args().next() {
+        Some(name) => CString::from(name),
+        None => (0..)
+            .map(|i| CString::new(format!("rmd{}", i)).unwrap())
+            .find(|name| find(class, name).is_none())
+            .unwrap(),
+    };
+    if find(class, &name).is_some() {
+        return req.error(&format!("Provider {:?} exists.", name));
+    }
+    let result = class
+        .new_geom_with(&name, |gp| build(class, gp, kind, size, sectorsize));
+    if let Err(e) = result {
+        return req.error(&format!("Cannot create {:?}: {}.", name, e));
+    }
+}
+
+/// `destroy <name> ...`
+fn ctl_destroy(class: &Class<Rmd>, req: &mut CtlReq) {
+    let names: Vec<CString> = req.args().map(CString::from).collect();
+    if names.is_empty() {
+        return req.error("Missing device(s).");
+    }
+    for name in names {
+        let Some(gp) = find(class, &name) else {
+            return req.error(&format!("No such geom {:?}.", name));
+        };
+        if let Err(e) = class.destroy(gp) {
+            return req.error(&format!("Cannot destroy {:?}: {}.", name, e));
+        }
+    }
+}
+
+/// The geom named `name`, with or without a leading `/dev/`
+fn find(class: &Class<Rmd>, name: &CStr) -> Option<Geom<Rmd>> {
+    let name = name.to_bytes();
+    let name = name.strip_prefix(b"/dev/").unwrap_or(name);
+    class.geoms().find(|gp| gp.name().to_bytes() == name)
+}
+
+/// Set up a disk of `size` bytes, or for a file with none given, as many
+/// whole sectors as it holds. Files and swap are opened and reserved with
+/// the credentials of the caller, which for a `create` is GEOM's event
+/// thread, running as the kernel
+fn build(
+    class: &Rmd,
+    gp: Geom<Rmd>,
+    kind: Kind,
+    size: Option<u64>,
+    sectorsize: u32,
+) -> Result<RmdSoftc, Errno> {
+    let name = kind.name();
+    let (store, size) = match kind {
+        Kind::Malloc => {
+            let size = size.ok_or(Errno::Inval)?;
+            let len = usize::try_from(size).map_err(|_| Errno::NoMem)?;
+            let mut store = vec![];
+            store.try_reserve_exact(len).map_err(|_| Errno::NoMem)?;
+            store.resize(len, 0);
+            let store = Mutex::new(c"rmd store", store.into_boxed_slice());
+            (Store::Malloc(store), size)
+        }
+        Kind::Vnode(path) => {
+            let file = File::open(&path, true)?;
+            let size = match size {
+                Some(size) => size,
+                None => file.size()? & !(u64::from(sectorsize) - 1),
+            };
+            (Store::Vnode { file, path }, size)
+        }
+        Kind::Swap => {
+            let size = size.ok_or(Errno::Inval)?;
+            (Store::Swap(SwapObject::new(size)?), size)
+        }
+    };
+    check_size(size, sectorsize)?;
+    let shared = Arc::new(Shared {
+        store,
+        bios: BioQueue::new(),
+    });
+    let worker = match shared.store {
+        Store::Malloc(_) => None,
+        _ => Some(class.executor.spawn(shared.clone().serve(gp))),
+    };
+    let sc = RmdSoftc {
+        shared,
+        worker,
+        attrs: Attributes::new().with(c"GEOM::candelete", |sc: &RmdSoftc| {
+            Attr::Int(matches!(sc.shared.store, Store::Malloc(_)) as i32)
+        }),
+    };
+    let pp = gp.new_provider(gp.name());
+    pp.set_mediasize(size as i64);
+    pp.set_sectorsize(sectorsize);
+    pp.set_error(None);
+    Event::new("RUST", "rmd", "CREATE")
+        .with("cdev", gp.name().to_string_lossy())
+        .with("type", name)
+        .with("size", size)
+        .with("sectorsize", sectorsize)
+        .notify();
+    Ok(sc)
+}
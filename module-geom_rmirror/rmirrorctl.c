@@ -0,0 +1,50 @@
+/*
+ * Issue RMIRROR control requests, which geom(8) has no class library for:
+ *
+ *	rmirrorctl create name provider provider ...
+ *	rmirrorctl destroy name
+ *
+ * Build with: cc -o rmirrorctl rmirrorctl.c -lgeom
+ */
+
+#include <err.h>
+#include <libgeom.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static void
+usage(void)
+{
+	fprintf(stderr, "usage: rmirrorctl create name provider provider ...\n"
+	    "       rmirrorctl destroy name\n");
+	exit(1);
+}
+
+int
+main(int argc, char **argv)
+{
+	struct gctl_req *req;
+	const char *errstr;
+	char param[16];
+	int i, nargs;
+
+	argc--;
+	argv++;
+	if (argc < 2)
+		usage();
+
+	req = gctl_get_handle();
+	gctl_ro_param(req, "class", -1, "RMIRROR");
+	gctl_ro_param(req, "verb", -1, argv[0]);
+	nargs = argc - 1;
+	gctl_ro_param(req, "nargs", sizeof(nargs), &nargs);
+	for (i = 0; i < nargs; i++) {
+		snprintf(param, sizeof(param), "arg%d", i);
+		gctl_ro_param(req, param, -1, argv[i + 1]);
+	}
+	errstr = gctl_issue(req);
+	if (errstr != NULL)
+		errx(1, "%s", errstr);
+	gctl_free(req);
+	return (0);
+}
@@ -0,0 +1,81 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_std]
+
+//! Example GEOM class written in Rust: `RMIRROR` keeps the same data on
+//! two or more providers. Writes go to every disk and complete once all
+//! copies are in; reads go to whichever disk has the least outstanding.
+//!
+//! A disk that fails a request or disappears is dropped and the mirror
+//! carries on degraded, retrying failed reads on the remaining disks. It
+//! is not used again, nor resynchronised, until the mirror is recreated.
+//!
+//! Geoms are created and destroyed through control requests, issued
+//! with `rmirrorctl.c` as `geom(8)` needs a class library for them:
+//! ```bash,ignore
+//! ./build.sh module-geom_rmirror
+//! cc -o rmirrorctl module-geom_rmirror/rmirrorctl.c -lgeom
+//! sudo make -C module-geom_rmirror load
+//! sudo ./rmirrorctl create safe md0 md1     # /dev/rmirror/safe
+//! sudo ./rmirrorctl destroy safe
+//! sudo make -C module-geom_rmirror unload
+//! ```
+
+use bsd_kernel::allocator::KernelAllocator;
+use bsd_kernel::module::{Abi, ModuleEventType, check_abi};
+use core::panic::PanicInfo;
+use libc::{c_int, c_void};
+
+mod rmirror;
+
+extern crate alloc;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    bsd_kernel::panic::handle(info)
+}
+
+/// Main event handler for module events
+#[unsafe(no_mangle)]
+pub extern "C" fn module_event(
+    module: bsd_kernel::Module,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    let result = match ModuleEventType::from_i32(event) {
+        Some(ModuleEventType::Load) => {
+            check_abi(Abi::Exact).and_then(|()| rmirror::load(module))
+        }
+        Some(ModuleEventType::Unload) => rmirror::unload(module),
+        Some(_) => Ok(()),
+        None => Err(bsd_kernel::errno::Errno::OpNotSupp),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::vec::Vec;
+use bsd_kernel::Module;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::geom::{
+    Bio, BioCmd, BioList, Class, Consumer, CtlReq, Geom, GeomClass, Provider,
+};
+use bsd_kernel::log;
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use libc::c_void;
+use spin::Mutex;
+
+static CLASS: Mutex<Option<Box<Class<Rmirror>>>> = Mutex::new(None);
+
+pub fn load(module: Module) -> Result<(), Errno> {
+    let class = Class::new(Rmirror);
+    class.load(module)?;
+    *CLASS.lock() = Some(class);
+    Ok(())
+}
+
+pub fn unload(module: Module) -> Result<(), Errno> {
+    let mut class = CLASS.lock();
+    if let Some(c) = class.as_ref() {
+        // Fails while any RMIRROR provider is open
+        c.unload(module)?;
+    }
+    *class = None;
+    Ok(())
+}
+
+pub struct Rmirror;
+
+struct Disk {
+    cp: Consumer,
+    /// Set once the disk fails a request or goes away, after which no
+    /// more requests are sent to it
+    failed: AtomicBool,
+    /// Set once `orphan` has dropped the disk's access counts. Only
+    /// touched with the topology lock held
+    closed: AtomicBool,
+    /// Requests sent and not yet done, for balancing reads
+    pending: AtomicU32,
+}
+
+pub struct RmirrorSoftc {
+    disks: Vec<Disk>,
+}
+
+impl RmirrorSoftc {
+    fn active(&self) -> impl Iterator<Item = usize> + '_ {
+        self.disks
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| !d.failed.load(Ordering::Relaxed))
+            .map(|(i, _)| i)
+    }
+
+    /// The working disk with the fewest requests in flight
+    fn pick(&self) -> Option<usize> {
+        self.active()
+            .min_by_key(|&i| self.disks[i].pending.load(Ordering::Relaxed))
+    }
+
+    fn send(&self, gp: Geom<Rmirror>, mut cbp: Bio, disk: usize) {
+        let d = &self.disks[disk];
+        d.pending.fetch_add(1, Ordering::Relaxed);
+        cbp.set_caller2(disk as *mut c_void);
+        gp.request(cbp, d.cp);
+    }
+
+    fn fail(&self, gp: Geom<Rmirror>, disk: usize, error: Errno) {
+        if !self.disks[disk].failed.swap(true, Ordering::Relaxed) {
+            log!(
+                LOG_WARNING,
+                "GEOM_RMIRROR: {:?}: dropping disk {}: {}",
+                gp.name(),
+                disk,
+                error
+            );
+        }
+    }
+}
+
+impl GeomClass for Rmirror {
+    const NAME: &'static CStr = c"RMIRROR";
+    type Softc = RmirrorSoftc;
+
+    fn ctlreq(class: &Class<Self>, req: &mut CtlReq, verb: &CStr) {
+        match verb.to_bytes() {
+            b"create" => ctl_create(class, req),
+            b"destroy" => ctl_destroy(class, req),
+            _ => req.error("Unknown verb."),
+        }
+    }
+
+    fn start(&self, gp: Geom<Self>, mut bio: Bio) {
+        let sc = unsafe { gp.softc() };
+        match bio.cmd() {
+            BioCmd::Read => {
+                let Some(disk) = sc.pick() else {
+                    return bio.deliver(Err(Errno::NxIo));
+                };
+                let Some(cbp) = bio.clone_bio() else {
+                    return bio.deliver(Err(Errno::NoMem));
+                };
+                sc.send(gp, cbp, disk);
+            }
+            BioCmd::Write
+            | BioCmd::Delete
+            | BioCmd::Flush
+            | BioCmd::Speedup => {
+                let mut list = BioList::new();
+                for disk in sc.active() {
+                    let Some(mut cbp) = bio.clone_bio() else {
+                        drop(list);
+                        return bio.deliver(Err(Errno::NoMem));
+                    };
+                    cbp.set_caller2(disk as *mut c_void);
+                    list.push_back(cbp);
+                }
+                if list.is_empty() {
+                    return bio.deliver(Err(Errno::NxIo));
+                }
+                // Every copy reports no progress in `done`, so the whole
+                // length is counted once rather than once per disk
+                bio.set_completed(bio.length());
+                while let Some(cbp) = list.pop_front() {
+                    let disk = cbp.caller2() as usize;
+                    sc.send(gp, cbp, disk);
+                }
+            }
+            _ => bio.deliver(Err(Errno::OpNotSupp)),
+        }
+    }
+
+    fn speedup(&self, gp: Geom<Self>, bio: Bio) {
+        // Every disk holds the writes, so every disk is asked
+        self.start(gp, bio);
+    }
+
+    fn done(&self, gp: Geom<Self>, mut cbp: Bio) {
+        let sc = unsafe { gp.softc() };
+        let disk = cbp.caller2() as usize;
+        sc.disks[disk].pending.fetch_sub(1, Ordering::Relaxed);
+        // Deletes and flushes may fail for want of support, which is no
+        // reason to give up on a disk
+        match (cbp.cmd(), cbp.error()) {
+            (BioCmd::Read, Some(e)) => {
+                sc.fail(gp, disk, e);
+                if let Some(other) = sc.pick() {
+                    cbp.set_error(None);
+                    return sc.send(gp, cbp, other);
+                }
+            }
+            (BioCmd::Write, Some(e)) => {
+                sc.fail(gp, disk, e);
+                // The write stands as long as some disk may still have it
+                if sc.active().next().is_some() {
+                    cbp.set_error(None);
+                }
+            }
+            _ => {}
+        }
+        if cbp.cmd() != BioCmd::Read {
+            cbp.set_completed(0);
+        }
+        cbp.std_done();
+    }
+
+    fn access(
+        &self,
+        gp: Geom<Self>,
+        _pp: Provider,
+        dr: i32,
+        dw: i32,
+        de: i32,
+    ) -> Result<(), Errno> {
+        let disks = &unsafe { gp.softc() }.disks;
+        let open = |d: &&Disk| !d.closed.load(Ordering::Relaxed);
+        for (i, d) in disks.iter().enumerate().filter(|(_, d)| open(d)) {
+            if let Err(e) = d.cp.access(dr, dw, de) {
+                for d in disks[..i].iter().filter(open) {
+                    let _ = d.cp.access(-dr, -dw, -de);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn orphan(&self, gp: Geom<Self>, cp: Consumer) {
+        let sc = unsafe { gp.softc() };
+        let Some(disk) = sc.disks.iter().position(|d| d.cp == cp) else {
+            return;
+        };
+        let error = cp.provider().and_then(|pp| pp.error());
+        let error = error.unwrap_or(Errno::NxIo);
+        sc.fail(gp, disk, error);
+        // Let go of the provider's access counts so it can be destroyed.
+        // The consumer stays attached, as requests may be on their way to
+        // it, until the mirror itself withers
+        let d = &sc.disks[disk];
+        if !d.closed.swap(true, Ordering::Relaxed) {
+            let (r, w, e) = cp.access_counts();
+            let _ = cp.access(-r, -w, -e);
+        }
+        if sc.disks.iter().all(|d| d.closed.load(Ordering::Relaxed)) {
+            gp.wither(error);
+        }
+    }
+}
+
+/// `create <name> <provider> <provider> ...`
+fn ctl_create(class: &Class<Rmirror>, req: &mut CtlReq) {
+    let Some(nargs) = req.param_int(c"nargs") else {
+        return req.error("No 'nargs' argument.");
+    };
+    if nargs < 3 {
+        return req.error("Too few arguments.");
+    }
+    let Some(name) = req.param_str(c"arg0").map(CString::from) else {
+        return req.error("No 'arg0' argument.");
+    };
+    if class.geoms().any(|gp| gp.name() == name.as_c_str()) {
+        return req.error("Geom already exists.");
+    }
+    let mut pps = Vec::new();
+    for i in 1..nargs {
+        let arg = CString::new(format!("arg{}", i)).unwrap();
+        // Reports a missing provider itself
+        let Some(pp) = req.provider(&arg) else {
+            return;
+        };
+        pps.push(pp);
+    }
+    let sectorsize = pps[0].sectorsize();
+    if pps.iter().any(|pp| pp.sectorsize() != sectorsize) {
+        return req.error("Providers have different sector sizes.");
+    }
+    let result = class.new_geom_with(&name, |gp| build(gp, &name, &pps));
+    if let Err(e) = result {
+        req.error(&format!("Cannot create {:?}: {}.", name, e));
+    }
+}
+
+fn build(
+    gp: Geom<Rmirror>,
+    name: &CStr,
+    pps: &[Provider],
+) -> Result<RmirrorSoftc, Errno> {
+    let mut disks = Vec::with_capacity(pps.len());
+    for &pp in pps {
+        let cp = gp.new_consumer();
+        cp.attach(pp)?;
+        disks.push(Disk {
+            cp,
+            failed: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            pending: AtomicU32::new(0),
+        });
+    }
+    let sectorsize = pps[0].sectorsize();
+    let smallest = pps.iter().map(|pp| pp.mediasize()).min().unwrap_or(0);
+    let pp_name = format!("rmirror/{}", name.to_string_lossy());
+    let pp = gp.new_provider(&CString::new(pp_name).map_err(|_| Errno::Inval)?);
+    pp.set_mediasize(smallest - smallest % i64::from(sectorsize));
+    pp.set_sectorsize(sectorsize);
+    pp.set_error(None);
+    Ok(RmirrorSoftc { disks })
+}
+
+/// `destroy <name>`
+fn ctl_destroy(class: &Class<Rmirror>, req: &mut CtlReq) {
+    let Some(name) = req.param_str(c"arg0").map(CString::from) else {
+        return req.error("No 'arg0' argument.");
+    };
+    let Some(gp) = class.geoms().find(|gp| gp.name() == name.as_c_str()) else {
+        return req.error("No such geom.");
+    };
+    if let Err(e) = class.destroy(gp) {
+        req.error(&format!("Cannot destroy {:?}: {}.", name, e));
+    }
+}
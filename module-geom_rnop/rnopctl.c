@@ -0,0 +1,101 @@
+/*
+ * Issue RNOP control requests, which geom(8) has no class library for:
+ *
+ *	rnopctl [-d delay] [-e error] [-r rfailprob] [-w wfailprob]
+ *	    create provider ...
+ *	rnopctl [-d delay] [-e error] [-r rfailprob] [-w wfailprob]
+ *	    configure name ...
+ *	rnopctl destroy name ...
+ *
+ * The delay is in milliseconds, the failure probabilities in percent and
+ * the error is the errno failed requests get. Options left out of
+ * configure keep their current values.
+ *
+ * Build with: cc -o rnopctl rnopctl.c -lgeom
+ */
+
+#include <err.h>
+#include <inttypes.h>
+#include <libgeom.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+static void
+usage(void)
+{
+	fprintf(stderr, "usage: rnopctl [-d delay] [-e error] [-r rfailprob] "
+	    "[-w wfailprob] create provider ...\n"
+	    "       rnopctl [-d delay] [-e error] [-r rfailprob] "
+	    "[-w wfailprob] configure name ...\n"
+	    "       rnopctl destroy name ...\n");
+	exit(1);
+}
+
+static intmax_t
+number(const char *arg)
+{
+	char *end;
+	intmax_t n;
+
+	n = strtoimax(arg, &end, 0);
+	if (*arg == '\0' || *end != '\0' || n < 0)
+		errx(1, "invalid number: %s", arg);
+	return (n);
+}
+
+int
+main(int argc, char **argv)
+{
+	struct gctl_req *req;
+	const char *errstr;
+	char param[16];
+	intmax_t delay = -1, error = -1, rfailprob = -1, wfailprob = -1;
+	int ch, i, nargs;
+
+	while ((ch = getopt(argc, argv, "d:e:r:w:")) != -1) {
+		switch (ch) {
+		case 'd':
+			delay = number(optarg);
+			break;
+		case 'e':
+			error = number(optarg);
+			break;
+		case 'r':
+			rfailprob = number(optarg);
+			break;
+		case 'w':
+			wfailprob = number(optarg);
+			break;
+		default:
+			usage();
+		}
+	}
+	argc -= optind;
+	argv += optind;
+	if (argc < 2)
+		usage();
+
+	req = gctl_get_handle();
+	gctl_ro_param(req, "class", -1, "RNOP");
+	gctl_ro_param(req, "verb", -1, argv[0]);
+	nargs = argc - 1;
+	gctl_ro_param(req, "nargs", sizeof(nargs), &nargs);
+	if (delay != -1)
+		gctl_ro_param(req, "delay", sizeof(delay), &delay);
+	if (error != -1)
+		gctl_ro_param(req, "error", sizeof(error), &error);
+	if (rfailprob != -1)
+		gctl_ro_param(req, "rfailprob", sizeof(rfailprob), &rfailprob);
+	if (wfailprob != -1)
+		gctl_ro_param(req, "wfailprob", sizeof(wfailprob), &wfailprob);
+	for (i = 0; i < nargs; i++) {
+		snprintf(param, sizeof(param), "arg%d", i);
+		gctl_ro_param(req, param, -1, argv[i + 1]);
+	}
+	errstr = gctl_issue(req);
+	if (errstr != NULL)
+		errx(1, "%s", errstr);
+	gctl_free(req);
+	return (0);
+}
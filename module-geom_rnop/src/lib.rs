@@ -0,0 +1,83 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_std]
+
+//! Example GEOM class written in Rust: `RNOP` stacks a `<provider>.rnop`
+//! on a provider, like `gnop(8)`, and passes I/O through it while failing
+//! a given share of the reads and writes and holding every request back
+//! for a given delay. It lets filesystems and classes stacked above be
+//! tried against a disk that errors or is slow, without one to hand.
+//!
+//! Failed requests are delivered at once with the configured error, and
+//! count towards `kern.geom.rnop.<provider>`. Delays are kept with a
+//! `bsd_kernel::geom::Scheduler`, so requests leave in the order they
+//! came in. As with `RCAT`, `rnopctl.c` issues the control requests:
+//! ```bash,ignore
+//! ./build.sh module-geom_rnop
+//! cc -o rnopctl module-geom_rnop/rnopctl.c -lgeom
+//! sudo make -C module-geom_rnop load
+//! sudo ./rnopctl -r 10 -d 20 create md0         # /dev/md0.rnop
+//! sudo ./rnopctl -w 100 -e 28 configure md0.rnop   # writes fail, ENOSPC
+//! sysctl kern.geom.rnop.md0
+//! sudo sysctl kern.geom.rnop.md0.reads=0          # count afresh
+//! sudo ./rnopctl destroy md0.rnop
+//! sudo make -C module-geom_rnop unload
+//! ```
+
+use bsd_kernel::allocator::KernelAllocator;
+use bsd_kernel::module::{Abi, ModuleEventType, check_abi};
+use core::panic::PanicInfo;
+use libc::{c_int, c_void};
+
+mod rnop;
+extern crate alloc;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    bsd_kernel::panic::handle(info)
+}
+
+/// Main event handler for module events
+#[unsafe(no_mangle)]
+pub extern "C" fn module_event(
+    module: bsd_kernel::Module,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    let result = match ModuleEventType::from_i32(event) {
+        Some(ModuleEventType::Load) => {
+            check_abi(Abi::Exact).and_then(|()| rnop::load(module))
+        }
+        Some(ModuleEventType::Unload) => rnop::unload(module),
+        Some(_) => Ok(()),
+        None => Err(bsd_kernel::errno::Errno::OpNotSupp),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.as_raw(),
+    }
+}
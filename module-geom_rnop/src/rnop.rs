@@ -0,0 +1,390 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bsd_kernel::Module;
+use bsd_kernel::counter::Counter;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::geom::sched::{FLOWS, Flow, Select};
+use bsd_kernel::geom::{
+    self, Bio, BioCmd, Class, Conf, ConfPart, CtlReq, DirectDispatch, Geom,
+    GeomClass, Policy, Provider, Scheduler,
+};
+use bsd_kernel::sysctl::{Context, Node};
+use bsd_kernel::time::sbinuptime;
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+/// The loaded class, and the `kern.geom.rnop` node its geoms report
+/// under. Fields drop in order, so the geoms' oids are gone before the node
+struct Loaded {
+    class: Box<Class<Rnop>>,
+    _sysctl: Context,
+}
+
+static LOADED: Mutex<Option<Loaded>> = Mutex::new(None);
+
+pub fn load(module: Module) -> Result<(), Errno> {
+    let mut sysctl = Context::new();
+    let root = sysctl.add_node(
+        geom::sysctl_node(),
+        c"rnop",
+        c"GEOM_RNOP fault injection",
+    )?;
+    let class = Class::new(Rnop { root });
+    class.load(module)?;
+    *LOADED.lock() = Some(Loaded {
+        class,
+        _sysctl: sysctl,
+    });
+    Ok(())
+}
+
+pub fn unload(module: Module) -> Result<(), Errno> {
+    let mut loaded = LOADED.lock();
+    if let Some(l) = loaded.as_ref() {
+        // Fails while any RNOP provider is open
+        l.class.unload(module)?;
+    }
+    *loaded = None;
+    Ok(())
+}
+
+pub struct Rnop {
+    root: Node,
+}
+
+/// What to inject, as set by `create` and `configure`
+#[derive(Copy, Clone, Debug)]
+struct Faults {
+    /// Percentages of reads and writes to fail
+    read: u32,
+    write: u32,
+    error: Errno,
+}
+
+/// Per-CPU counters, as every bio updates them, each shared with its oid
+#[derive(Default)]
+struct Stats {
+    reads: Arc<Counter>,
+    writes: Arc<Counter>,
+    read_failures: Arc<Counter>,
+    write_failures: Arc<Counter>,
+}
+
+pub struct RnopSoftc {
+    faults: Mutex<Faults>,
+    /// xorshift state the failures are drawn from
+    rng: AtomicU64,
+    stats: Stats,
+    sched: Scheduler<Delay>,
+    _sysctl: Context,
+}
+
+impl RnopSoftc {
+    /// Count `bio` and decide whether to fail it, and with what
+    fn inject(&self, bio: &Bio) -> Option<Errno> {
+        let faults = *self.faults.lock();
+        let stats = &self.stats;
+        let (count, failures, percent) = match bio.cmd() {
+            BioCmd::Read => (&stats.reads, &stats.read_failures, faults.read),
+            BioCmd::Write => {
+                (&stats.writes, &stats.write_failures, faults.write)
+            }
+            _ => return None,
+        };
+        count.increment();
+        if self.roll() >= percent {
+            return None;
+        }
+        failures.increment();
+        Some(faults.error)
+    }
+
+    /// A number from 0 to 99. Racing callers may draw the same one, which
+    /// does no harm here
+    fn roll(&self) -> u32 {
+        let mut x = self.rng.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.store(x, Ordering::Relaxed);
+        (x % 100) as u32
+    }
+}
+
+/// Hold every bio until `delay` after it arrived, sending the earliest
+/// arrival first
+struct Delay {
+    delay: Duration,
+}
+
+impl Policy for Delay {
+    type Flow = ();
+
+    fn select(
+        &mut self,
+        flows: &mut [Flow<()>; FLOWS],
+        now: Duration,
+    ) -> Select {
+        let first = flows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, f)| Some((i, f.head_arrival()? + self.delay)))
+            .min_by_key(|&(_, due)| due);
+        match first {
+            Some((i, due)) if due <= now => Select::Flow(i),
+            Some((_, due)) => Select::After(due - now),
+            None => Select::Idle,
+        }
+    }
+}
+
+impl GeomClass for Rnop {
+    const NAME: &'static CStr = c"RNOP";
+    type Softc = RnopSoftc;
+
+    fn ctlreq(class: &Class<Self>, req: &mut CtlReq, verb: &CStr) {
+        req.dispatch(
+            class,
+            verb,
+            &[
+                (c"create", ctl_create),
+                (c"configure", ctl_configure),
+                (c"destroy", ctl_destroy),
+            ],
+        );
+    }
+
+    fn start(&self, gp: Geom<Self>, bio: Bio) {
+        let sc = unsafe { gp.softc() };
+        match sc.inject(&bio) {
+            Some(e) => bio.deliver(Err(e)),
+            None => sc.sched.enqueue(bio),
+        }
+    }
+
+    fn speedup(&self, gp: Geom<Self>, bio: Bio) {
+        unsafe { gp.softc() }.sched.enqueue(bio);
+    }
+
+    fn done(&self, gp: Geom<Self>, bio: Bio) {
+        unsafe { gp.softc() }.sched.completed(&bio);
+        bio.std_done();
+    }
+
+    /// The settings and counts, as `gnop(8)` lists them
+    fn dumpconf(&self, gp: Geom<Self>, conf: &mut Conf, part: ConfPart) {
+        let ConfPart::Provider(_) = part else { return };
+        let sc = unsafe { gp.softc() };
+        let faults = *sc.faults.lock();
+        conf.element(c"ReadFailProb", faults.read);
+        conf.element(c"WriteFailProb", faults.write);
+        conf.element(c"Error", faults.error.as_raw());
+        let stats = &sc.stats;
+        for (name, count) in [
+            (c"Reads", &stats.reads),
+            (c"Writes", &stats.writes),
+            (c"ReadFailures", &stats.read_failures),
+            (c"WriteFailures", &stats.write_failures),
+        ] {
+            conf.element(name, count.fetch());
+        }
+    }
+}
+
+// Nothing sleeps, and the scheduler sends and delivers without its lock
+unsafe impl DirectDispatch for Rnop {}
+
+/// The settings a `create` or `configure` request carries, over `faults`
+/// and `delay`. Fails with the message to report
+fn settings(
+    req: &CtlReq,
+    faults: &mut Faults,
+    delay: &mut Duration,
+) -> Result<(), &'static str> {
+    let percent = |name| match req.param_i64(name) {
+        Some(p @ 0..=100) => Ok(Some(p as u32)),
+        Some(_) => Err("Probabilities must be from 0 to 100."),
+        None => Ok(None),
+    };
+    let read = percent(c"rfailprob")?;
+    let write = percent(c"wfailprob")?;
+    let error = match req.param_i64(c"error") {
+        Some(n) => {
+            let e = i32::try_from(n).ok().and_then(Errno::from_raw);
+            Some(e.ok_or("Invalid error number.")?)
+        }
+        None => None,
+    };
+    let ms = match req.param_i64(c"delay") {
+        Some(ms) => Some(u64::try_from(ms).map_err(|_| "Invalid delay.")?),
+        None => None,
+    };
+    faults.read = read.unwrap_or(faults.read);
+    faults.write = write.unwrap_or(faults.write);
+    faults.error = error.unwrap_or(faults.error);
+    *delay = ms.map_or(*delay, Duration::from_millis);
+    Ok(())
+}
+
+/// `create <provider> ...`
+fn ctl_create(class: &Class<Rnop>, req: &mut CtlReq) {
+    let mut faults = Faults {
+        read: 0,
+        write: 0,
+        error: Errno::Io,
+    };
+    let mut delay = Duration::ZERO;
+    if let Err(msg) = settings(req, &mut faults, &mut delay) {
+        return req.error(msg);
+    }
+    let Some(nargs) = req.param_int(c"nargs").filter(|&n| n > 0) else {
+        return req.error("Missing device(s).");
+    };
+    for i in 0..nargs {
+        let arg = CString::new(format!("arg{}", i)).unwrap();
+        // Reports a missing provider itself
+        let Some(pp) = req.provider(&arg) else {
+            return;
+        };
+        let name = format!("{}.rnop", pp.name().to_string_lossy());
+        let name = CString::new(name).unwrap();
+        if class.geoms().any(|gp| gp.name() == name.as_c_str()) {
+            return req.error(&format!("Provider {:?} exists.", name));
+        }
+        let result = class
+            .new_geom_with(&name, |gp| build(class, gp, pp, faults, delay));
+        if let Err(e) = result {
+            return req.error(&format!("Cannot create {:?}: {}.", name, e));
+        }
+    }
+}
+
+fn build(
+    class: &Rnop,
+    gp: Geom<Rnop>,
+    pp: Provider,
+    faults: Faults,
+    delay: Duration,
+) -> Result<RnopSoftc, Errno> {
+    let cp = gp.new_direct_consumer();
+    cp.attach(pp)?;
+    let stats = Stats::default();
+    let sysctl = add_sysctls(class.root, pp.name(), &stats)?;
+    let sc = RnopSoftc {
+        faults: Mutex::new(faults),
+        rng: AtomicU64::new(sbinuptime() as u64 | 1),
+        stats,
+        sched: Scheduler::new(Delay { delay }, usize::MAX, move |bio| {
+            gp.request(bio, cp)
+        }),
+        _sysctl: sysctl,
+    };
+    let npp = gp.new_direct_provider(gp.name());
+    npp.set_mediasize(pp.mediasize());
+    npp.set_sectorsize(pp.sectorsize());
+    npp.set_stripesize(pp.stripesize());
+    npp.set_stripeoffset(pp.stripeoffset());
+    npp.set_accepts_unmapped(pp.accepts_unmapped());
+    npp.set_error(None);
+    Ok(sc)
+}
+
+/// Publish `kern.geom.rnop.<provider>.*`
+fn add_sysctls(
+    root: Node,
+    name: &CStr,
+    stats: &Stats,
+) -> Result<Context, Errno> {
+    // Keep the node name a single component
+    let node = name.to_string_lossy().replace(['.', '/'], "_");
+    let node = CString::new(node).map_err(|_| Errno::Inval)?;
+    let mut ctx = Context::new();
+    let dev = ctx.add_node(root, &node, c"Faults injected on one provider")?;
+    let counters = [
+        (c"reads", c"Reads started", &stats.reads),
+        (c"writes", c"Writes started", &stats.writes),
+        (c"read_failures", c"Reads failed", &stats.read_failures),
+        (c"write_failures", c"Writes failed", &stats.write_failures),
+    ];
+    // Writable, so that `sysctl ...=0` starts the counts afresh
+    for (name, descr, counter) in counters {
+        ctx.add_counter(dev, name, descr, counter.clone(), true)?;
+    }
+    Ok(ctx)
+}
+
+/// `configure <name> ...`, changing only the settings given
+fn ctl_configure(class: &Class<Rnop>, req: &mut CtlReq) {
+    let names: Vec<CString> = req.args().map(CString::from).collect();
+    if names.is_empty() {
+        return req.error("Missing device(s).");
+    }
+    for name in names {
+        let Some(gp) = find(class, &name) else {
+            return req.error(&format!("No such geom {:?}.", name));
+        };
+        // `find` passes over reaped geoms
+        let sc = unsafe { gp.softc() };
+        let mut faults = *sc.faults.lock();
+        let mut delay = sc.sched.with_policy(|p| p.delay);
+        if let Err(msg) = settings(req, &mut faults, &mut delay) {
+            return req.error(msg);
+        }
+        *sc.faults.lock() = faults;
+        // Requests already held see the new delay too
+        sc.sched.with_policy(|p| p.delay = delay);
+    }
+}
+
+/// `destroy <name> ...`
+fn ctl_destroy(class: &Class<Rnop>, req: &mut CtlReq) {
+    let names: Vec<CString> = req.args().map(CString::from).collect();
+    if names.is_empty() {
+        return req.error("Missing device(s).");
+    }
+    for name in names {
+        let Some(gp) = find(class, &name) else {
+            return req.error(&format!("No such geom {:?}.", name));
+        };
+        if let Err(e) = class.destroy(gp) {
+            return req.error(&format!("Cannot destroy {:?}: {}.", name, e));
+        }
+    }
+}
+
+/// The geom named `name`, with or without a leading `/dev/`
+fn find(class: &Class<Rnop>, name: &CStr) -> Option<Geom<Rnop>> {
+    let name = name.to_bytes();
+    let name = name.strip_prefix(b"/dev/").unwrap_or(name);
+    // Skipping geoms already reaped, whose softc is gone
+    class
+        .geoms()
+        .find(|gp| gp.has_softc() && gp.name().to_bytes() == name)
+}
@@ -0,0 +1,318 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::toc::{self, Toc};
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bsd_kernel::Module;
+use bsd_kernel::compress::{Algorithm, Decompressor};
+use bsd_kernel::errno::Errno;
+use bsd_kernel::executor::{Executor, JoinHandle, Notify};
+use bsd_kernel::geom::{
+    Attr, Attributes, Bio, BioCmd, BioQueue, Class, Consumer, Geom, GeomClass,
+    Provider,
+};
+use bsd_kernel::log;
+use bsd_kernel::sync;
+use core::ffi::CStr;
+use core::ops::Range;
+use core::slice;
+use spin::Mutex;
+
+/// Compressed bytes fetched from the image at once, unless a single
+/// block is bigger
+const MAX_READ: u64 = 128 * 1024;
+
+static CLASS: Mutex<Option<Box<Class<Ruzip>>>> = Mutex::new(None);
+
+pub fn load(module: Module) -> Result<(), Errno> {
+    let executor = Executor::new(c"g_ruzip", 1)?;
+    let class = Class::new(Ruzip { executor });
+    class.load(module)?;
+    *CLASS.lock() = Some(class);
+    Ok(())
+}
+
+pub fn unload(module: Module) -> Result<(), Errno> {
+    let mut class = CLASS.lock();
+    if let Some(c) = class.as_ref() {
+        // Fails while any RUZIP provider is open
+        c.unload(module)?;
+    }
+    *class = None;
+    Ok(())
+}
+
+pub struct Ruzip {
+    /// Runs the geoms' workers. Dropped with the class, after its geoms
+    executor: Executor,
+}
+
+/// State shared by a geom's I/O path and its worker
+struct Shared {
+    toc: Toc,
+    cp: Consumer,
+    sectorsize: u64,
+    /// Reads passed on by `start`
+    bios: BioQueue,
+    /// The worker's read of the image, once `done`
+    finished: sync::Mutex<Option<Bio>>,
+    done: Notify,
+}
+
+pub struct RuzipSoftc {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+    attrs: Attributes<RuzipSoftc>,
+}
+
+impl Drop for RuzipSoftc {
+    fn drop(&mut self) {
+        // The provider is gone, so nothing more will be queued
+        self.shared.bios.close();
+        self.shared.bios.drain();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Shared {
+    async fn serve(
+        self: Arc<Self>,
+        gp: Geom<Ruzip>,
+        mut decoder: Decompressor,
+    ) {
+        while let Some(mut bio) = self.bios.next().await {
+            let result = self.read(gp, &mut decoder, &mut bio).await;
+            bio.deliver(result);
+        }
+    }
+
+    /// Fill `bio` with the blocks it covers, a few blocks' worth of
+    /// compressed data at a time
+    async fn read(
+        &self,
+        gp: Geom<Ruzip>,
+        decoder: &mut Decompressor,
+        bio: &mut Bio,
+    ) -> Result<(), Errno> {
+        let data = bio.data().ok_or(Errno::OpNotSupp)?;
+        let out =
+            unsafe { slice::from_raw_parts_mut(data, bio.length() as usize) };
+        let blksz = self.toc.blksz();
+        let start = bio.offset() as u64;
+        let end = start + out.len() as u64;
+        // For blocks only partly wanted
+        let mut block = Vec::new();
+        let mut pos = start;
+        while pos < end {
+            let first = pos / blksz;
+            let fits = |last| {
+                let span = self.toc.span(first, last);
+                span.end - span.start <= MAX_READ
+            };
+            let mut last = first;
+            while (last + 1) * blksz < end && fits(last + 1) {
+                last += 1;
+            }
+            let (image, base) =
+                self.read_image(gp, self.toc.span(first, last)).await?;
+            for n in first..=last {
+                let range = self.toc.block(n);
+                let input = &image[(range.start - base) as usize
+                    ..(range.end - base) as usize];
+                let bstart = n * blksz;
+                let to = end.min(bstart + blksz);
+                let dst =
+                    &mut out[(pos - start) as usize..(to - start) as usize];
+                if pos == bstart && to == bstart + blksz {
+                    inflate_block(decoder, input, dst)?;
+                } else {
+                    block.resize(blksz as usize, 0);
+                    inflate_block(decoder, input, &mut block)?;
+                    let skip = (pos - bstart) as usize;
+                    dst.copy_from_slice(&block[skip..skip + dst.len()]);
+                }
+                pos = to;
+            }
+            bio.set_completed((pos - start) as i64);
+        }
+        Ok(())
+    }
+
+    /// Read the sectors holding `range` of the image, returning them with
+    /// the image offset they start at
+    async fn read_image(
+        &self,
+        gp: Geom<Ruzip>,
+        range: Range<u64>,
+    ) -> Result<(Vec<u8>, u64), Errno> {
+        let from = range.start / self.sectorsize * self.sectorsize;
+        let to = range.end.div_ceil(self.sectorsize) * self.sectorsize;
+        let mut buf = vec![0; (to - from) as usize];
+        let mut cbp = Bio::alloc(BioCmd::Read);
+        cbp.set_offset(from as i64);
+        cbp.set_length(buf.len() as i64);
+        cbp.set_data(buf.as_mut_ptr());
+        gp.request(cbp, self.cp);
+        let done = self.done.wait_while(&self.finished, |f| f.is_none());
+        let cbp = done.await.take().unwrap();
+        let result = match cbp.error() {
+            Some(e) => Err(e),
+            None if cbp.completed() != buf.len() as i64 => Err(Errno::Io),
+            None => Ok((buf, from)),
+        };
+        cbp.destroy();
+        result
+    }
+}
+
+/// Inflate one block into `dst`, zero filling what it doesn't cover
+fn inflate_block(
+    decoder: &mut Decompressor,
+    input: &[u8],
+    dst: &mut [u8],
+) -> Result<(), Errno> {
+    let len = if input.is_empty() {
+        0
+    } else {
+        decoder.decompress_all(input, dst)?
+    };
+    dst[len..].fill(0);
+    Ok(())
+}
+
+impl GeomClass for Ruzip {
+    const NAME: &'static CStr = c"RUZIP";
+    type Softc = RuzipSoftc;
+
+    fn taste(class: &Class<Self>, pp: Provider) -> Option<Geom<Self>> {
+        let name = format!("{}.ruzip", pp.name().to_string_lossy());
+        let name = CString::new(name).ok()?;
+        class.new_geom_with(&name, |gp| build(class, gp, pp)).ok()
+    }
+
+    fn start(&self, gp: Geom<Self>, bio: Bio) {
+        let sc = unsafe { gp.softc() };
+        let Some(bio) = sc.attrs.handle(sc, bio) else {
+            return;
+        };
+        match bio.cmd() {
+            BioCmd::Read => sc.shared.bios.push(bio),
+            _ => bio.deliver(Err(Errno::OpNotSupp)),
+        }
+    }
+
+    fn done(&self, gp: Geom<Self>, bio: Bio) {
+        let shared = &unsafe { gp.softc() }.shared;
+        *shared.finished.lock() = Some(bio);
+        shared.done.notify_one();
+    }
+
+    fn access(
+        &self,
+        gp: Geom<Self>,
+        _pp: Provider,
+        dr: i32,
+        dw: i32,
+        de: i32,
+    ) -> Result<(), Errno> {
+        if dw > 0 {
+            return Err(Errno::RoFs);
+        }
+        unsafe { gp.softc() }.shared.cp.access(dr, dw, de)
+    }
+}
+
+fn build(
+    class: &Class<Ruzip>,
+    gp: Geom<Ruzip>,
+    pp: Provider,
+) -> Result<RuzipSoftc, Errno> {
+    let cp = gp.new_consumer();
+    cp.attach(pp)?;
+    cp.access(1, 0, 0)?;
+    let toc = read_toc(cp, pp);
+    let _ = cp.access(-1, 0, 0);
+    let toc = toc?;
+    let decoder = Decompressor::new(Algorithm::Zlib)?;
+
+    let npp = gp.new_provider(gp.name());
+    npp.set_mediasize((toc.nblocks() * toc.blksz()) as i64);
+    npp.set_sectorsize(512);
+    npp.set_stripesize(toc.blksz() as i64);
+    npp.set_stripeoffset(0);
+    npp.set_error(None);
+    log!(
+        LOG_INFO,
+        "GEOM_RUZIP: {:?}: {} blocks of {} bytes",
+        gp.name(),
+        toc.nblocks(),
+        toc.blksz()
+    );
+
+    let shared = Arc::new(Shared {
+        toc,
+        cp,
+        sectorsize: u64::from(pp.sectorsize()),
+        bios: BioQueue::new(),
+        finished: sync::Mutex::new(c"ruzipfin", None),
+        done: Notify::new(c"ruzipdone"),
+    });
+    let worker = class.executor.spawn(shared.clone().serve(gp, decoder));
+    let attrs = Attributes::new()
+        // Read-only, so nothing to trim
+        .with(c"GEOM::candelete", |_| Attr::Int(0))
+        .with(c"RUZIP::blksz", |sc: &RuzipSoftc| {
+            Attr::Int(sc.shared.toc.blksz() as i32)
+        });
+    Ok(RuzipSoftc {
+        shared,
+        worker: Some(worker),
+        attrs,
+    })
+}
+
+/// Read and check the header and block index of the image on `pp`
+fn read_toc(cp: Consumer, pp: Provider) -> Result<Toc, Errno> {
+    let sectorsize = pp.sectorsize() as usize;
+    let mediasize = pp.mediasize() as u64;
+    let len = toc::HEADER_LEN.next_multiple_of(sectorsize);
+    if mediasize < len as u64 {
+        return Err(Errno::Inval);
+    }
+    let header = cp.read_data(0, len)?;
+    let (blksz, nblocks) = toc::parse_header(&header).ok_or(Errno::Inval)?;
+    let len = toc::index_len(nblocks);
+    if mediasize < len.next_multiple_of(sectorsize) as u64 {
+        return Err(Errno::Inval);
+    }
+    let index = cp.read_data(0, len.next_multiple_of(sectorsize))?;
+    let raw = &index[toc::HEADER_LEN..len];
+    Toc::new(blksz, nblocks, raw, mediasize).ok_or(Errno::Inval)
+}
@@ -0,0 +1,127 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The block index of cloop images, as written by `mkuzip(8)`
+//!
+//! An image starts with a 128 byte shell script header that mounts it,
+//! followed by the block size and count as big-endian 32-bit integers.
+//! Then come `nblocks + 1` big-endian 64-bit image offsets: block `n`'s
+//! compressed data runs from offset `n` to offset `n + 1`, and inflates
+//! to `blksz` bytes. Since version 3 an empty block stands for zeros.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Bytes before the table of offsets
+pub const HEADER_LEN: usize = 136;
+
+const MAGIC: &[u8] = b"#!/bin/sh\n#V";
+
+/// Largest block size accepted, as `mkuzip(8)` does
+const MAX_BLKSZ: u32 = 1 << 20;
+
+/// Check the header of a zlib compressed image, returning its block size
+/// and count
+pub fn parse_header(header: &[u8]) -> Option<(u32, u32)> {
+    let header = header.get(..HEADER_LEN)?;
+    let version = header[MAGIC.len()];
+    if !header.starts_with(MAGIC) || !(b'2'..=b'4').contains(&version) {
+        return None;
+    }
+    let blksz = u32::from_be_bytes(header[128..132].try_into().unwrap());
+    let nblocks = u32::from_be_bytes(header[132..136].try_into().unwrap());
+    if blksz == 0 || blksz % 512 != 0 || blksz > MAX_BLKSZ || nblocks == 0 {
+        return None;
+    }
+    Some((blksz, nblocks))
+}
+
+/// Bytes of header and offsets for an image of `nblocks` blocks
+pub fn index_len(nblocks: u32) -> usize {
+    HEADER_LEN + (nblocks as usize + 1) * 8
+}
+
+/// Upper bound on a compressed block, `compressBound()` in zlib
+fn compress_bound(len: u64) -> u64 {
+    len + (len >> 12) + (len >> 14) + (len >> 25) + 13
+}
+
+/// Where each block's compressed data sits in the image
+pub struct Toc {
+    blksz: u64,
+    offsets: Vec<u64>,
+}
+
+impl Toc {
+    /// Decode the `nblocks + 1` offsets in `raw`, checking that they lie
+    /// in order within an image of `mediasize` bytes and that no block
+    /// is bigger than compression could have made it
+    pub fn new(
+        blksz: u32,
+        nblocks: u32,
+        raw: &[u8],
+        mediasize: u64,
+    ) -> Option<Self> {
+        let offsets: Vec<u64> = raw
+            .chunks_exact(8)
+            .take(nblocks as usize + 1)
+            .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+            .collect();
+        if offsets.len() != nblocks as usize + 1 {
+            return None;
+        }
+        let blksz = u64::from(blksz);
+        let first = offsets[0];
+        let last = offsets[nblocks as usize];
+        if first < index_len(nblocks) as u64 || last > mediasize {
+            return None;
+        }
+        let bound = compress_bound(blksz);
+        if offsets
+            .windows(2)
+            .any(|w| w[1] < w[0] || w[1] - w[0] > bound)
+        {
+            return None;
+        }
+        Some(Toc { blksz, offsets })
+    }
+
+    /// Uncompressed size of each block
+    pub fn blksz(&self) -> u64 {
+        self.blksz
+    }
+
+    pub fn nblocks(&self) -> u64 {
+        self.offsets.len() as u64 - 1
+    }
+
+    /// Image bytes holding block `n`, empty for a block of zeros
+    pub fn block(&self, n: u64) -> Range<u64> {
+        self.offsets[n as usize]..self.offsets[n as usize + 1]
+    }
+
+    /// Image bytes holding blocks `first` to `last` inclusive
+    pub fn span(&self, first: u64, last: u64) -> Range<u64> {
+        self.offsets[first as usize]..self.offsets[last as usize + 1]
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The fifo module's exported API, as declared in
+//! `module-fifo/rustfifo.h`, used if that module happens to be loaded
+
+use bsd_kernel::errno::Errno;
+use bsd_kernel::export::{Api, ApiHeader};
+use bsd_kernel::linker;
+
+#[repr(C)]
+pub struct FifoApi {
+    pub header: ApiHeader,
+    pub buffered: extern "C" fn() -> usize,
+    pub push: unsafe extern "C" fn(buf: *const u8, len: usize) -> usize,
+}
+
+unsafe impl Api for FifoApi {
+    const MAJOR: u16 = 1;
+    const MINOR: u16 = 0;
+}
+
+/// The bytes waiting in `/dev/rustfifo`, or `Errno::NoEnt` if the fifo
+/// module isn't loaded
+///
+/// Sleeps on the kernel linker's lock, so not for device methods, which
+/// run with the module's mutex held.
+pub fn buffered() -> Result<usize, Errno> {
+    linker::with_api(c"rustfifo_api", |api: &FifoApi| (api.buffered)())
+}
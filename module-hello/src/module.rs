@@ -23,43 +23,77 @@
 //
 // Based on public domain code by Johannes Lundberg
 
+use crate::fifo;
 use alloc::boxed::Box;
-use alloc::string::{String, ToString};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bsd_kernel::bufcache::BufCache;
 use bsd_kernel::character_device::{CDev, CharacterDevice};
+use bsd_kernel::cred::CurThread;
 use bsd_kernel::debugln;
-use bsd_kernel::io::{Read, Write};
-use bsd_kernel::module::{ModuleEvents, SharedModule};
+use bsd_kernel::errno::Errno;
+use bsd_kernel::io::Write;
+use bsd_kernel::kernel_module;
+use bsd_kernel::module::ModuleEvents;
+use bsd_kernel::sysctl::{Context, Node};
 use bsd_kernel::uio::{UioReader, UioWriter};
-use lazy_static::lazy_static;
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-lazy_static! {
-    // Object created on first access (which is module load callback)
-    pub static ref MODULE:
-        SharedModule<Hello> = SharedModule::new(Hello::new());
-}
+/// Bytes staged at a time by `write`
+const SCRATCH: usize = 4096;
 
 #[derive(Debug)]
 pub struct HelloInner {
-    data: String,
+    data: Vec<u8>,
+    /// Buffers to stage writes in, so that concurrent writers on different
+    /// CPUs don't contend in the allocator
+    scratch: BufCache<SCRATCH>,
     _cdev: Box<CDev<Hello>>,
 }
 
+// Defines MODULE, created on first access (which is module load callback)
+#[kernel_module(name = "hello", version = 1)]
 #[derive(Default, Debug)]
 pub struct Hello {
     // Put everything in an option so that SharedModule<Hello> can be
     // fully initialised before we start doing stuff in module load
     // callback. (we can't for example clone MODULE while in
-    // Hello::new() because of order of initialisation)
+    // Default::default() because of order of initialisation)
     inner: Option<HelloInner>,
-}
-impl Hello {
-    fn new() -> Self {
-        // We can't access MODULE here because it is not initialised yet!
-        Hello { inner: None }
-    }
+    /// `hw.rustmod.hello.read_only`, refusing writes to the message
+    read_only: Arc<AtomicBool>,
 }
 
 impl ModuleEvents for Hello {
+    const SYSCTL_NAME: Option<&'static CStr> = Some(c"hello");
+
+    fn sysctl(
+        &mut self,
+        root: Node,
+        sysctl: &mut Context,
+    ) -> Result<(), Errno> {
+        sysctl.add_u64(root, c"length", c"Length of the message", || {
+            let m = MODULE.lock();
+            let len = m.as_ref().and_then(|m| m.inner.as_ref());
+            len.map_or(0, |inner| inner.data.len() as u64)
+        })?;
+        sysctl.add_u64(
+            root,
+            c"fifo_buffered",
+            c"Bytes waiting in rustfifo, if loaded",
+            || fifo::buffered().unwrap_or(0) as u64,
+        )?;
+        sysctl.add_atomic(
+            root,
+            c"read_only",
+            c"Refuse writes to the message",
+            self.read_only.clone(),
+            true,
+        )
+    }
+
     fn load(&mut self) {
         debugln!("[module.rs] Hello::load");
 
@@ -69,7 +103,8 @@ impl ModuleEvents for Hello {
 
         if let Some(cdev) = CDev::new_with_delegate("rustmodule", m) {
             self.inner = Some(HelloInner {
-                data: "Default hello message\n".to_string(),
+                data: b"Default hello message\n".to_vec(),
+                scratch: BufCache::new(),
                 _cdev: cdev,
             });
         } else {
@@ -85,39 +120,59 @@ impl ModuleEvents for Hello {
 }
 
 impl CharacterDevice for Hello {
-    fn open(&mut self) {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
         // debugln!("[module.rs] Hello::open");
+        Ok(())
     }
-    fn close(&mut self) {
+    fn close(&mut self) -> Result<(), Errno> {
         // debugln!("[module.rs] Hello::close");
+        Ok(())
     }
-    fn read(&mut self, uio: &mut UioWriter) {
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
         // debugln!("[module.rs] Hello::read");
 
         if let Some(ref h) = self.inner {
-            match uio.write(&h.data.as_bytes()) {
+            match uio.write(&h.data) {
                 Ok(_) => (),
                 Err(e) => debugln!("{}", e),
             }
         }
+        Ok(())
     }
-    fn write(&mut self, uio: &mut UioReader) {
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
         // debugln!("[module.rs] Hello::write");
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(Errno::Perm);
+        }
         if let Some(ref mut inner) = self.inner {
             if uio.offset() == 0 {
                 inner.data.clear();
             }
-            match uio.read_to_string(&mut inner.data) {
-                Ok(x) => {
-                    debugln!(
-                        "Read {} bytes. Setting new message to `{}`",
-                        x,
-                        inner.data
-                    )
+            let mut buf = inner.scratch.get();
+            loop {
+                match uio.read_stream(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => inner.data.extend_from_slice(&buf[..n]),
+                    Err(e) => {
+                        debugln!("{:?}", e);
+                        return Err(e);
+                    }
                 }
-                Err(e) => debugln!("{:?}", e),
             }
+            debugln!(
+                "Setting new message to `{}`",
+                String::from_utf8_lossy(&inner.data)
+            );
         }
+        Ok(())
     }
 }
 impl Drop for Hello {
@@ -0,0 +1,70 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#include <sys/param.h>
+#include <sys/bus.h>
+#include <sys/kernel.h>
+#include <sys/module.h>
+#include <sys/systm.h>
+
+#include <dev/hid/hid.h>
+#include <dev/hid/hidbus.h>
+
+#include "opt_hid.h"
+
+/* In Rust, see src/mouse.rs */
+extern int module_event(struct module *, int, void *);
+extern int rusthidmon_probe(device_t);
+extern int rusthidmon_attach(device_t);
+extern int rusthidmon_detach(device_t);
+
+static const struct hid_device_id hidmon_devs[] = {
+	{ HID_TLC(HUP_GENERIC_DESKTOP, HUG_MOUSE) },
+};
+
+/* Match the collection here, where the hidbus macros are */
+static int
+hidmon_probe(device_t dev)
+{
+	int error;
+
+	error = HIDBUS_LOOKUP_DRIVER_INFO(dev, hidmon_devs);
+	if (error != 0)
+		return (error);
+	return (rusthidmon_probe(dev));
+}
+
+static device_method_t hidmon_methods[] = {
+	DEVMETHOD(device_probe,  hidmon_probe),
+	DEVMETHOD(device_attach, rusthidmon_attach),
+	DEVMETHOD(device_detach, rusthidmon_detach),
+	DEVMETHOD_END
+};
+
+/* The softc holds a pointer to the Rust driver state */
+DEFINE_CLASS_0(hidmon, hidmon_driver, hidmon_methods, sizeof(void *));
+DRIVER_MODULE(hidmon, hidbus, hidmon_driver, module_event, NULL);
+MODULE_DEPEND(hidmon, hid, 1, 1, 1);
+MODULE_DEPEND(hidmon, hidbus, 1, 1, 1);
+MODULE_VERSION(hidmon, 1);
+HID_PNP_INFO(hidmon_devs);
@@ -0,0 +1,72 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::boxed::Box;
+use bsd_kernel::character_device::CDev;
+use bsd_kernel::debugln;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::log::{self, LogDevice};
+use bsd_kernel::module::{ModuleEvents, SharedModule};
+use bsd_kernel::sync::Lazy;
+use bsd_kernel::sysctl::{self, Node};
+use core::ffi::CStr;
+
+pub static MODULE: Lazy<SharedModule<Hidmon>> =
+    Lazy::new(|| SharedModule::new(Hidmon::default()));
+
+/// The module's own state; each attached mouse has a `Mouse` of its own
+#[derive(Default, Debug)]
+pub struct Hidmon {
+    _log: Option<Box<CDev<LogDevice>>>,
+}
+
+bsd_kernel::sysctl! {
+    /// Adds `hw.rustmod.hidmon`'s oids
+    fn hidmon_sysctls {
+        proc(text, rd) log: "Recent input reports" = log::sysctl_dump;
+    }
+}
+
+impl ModuleEvents for Hidmon {
+    const SYSCTL_NAME: Option<&'static CStr> = Some(c"hidmon");
+
+    fn sysctl(
+        &mut self,
+        root: Node,
+        ctx: &mut sysctl::Context,
+    ) -> Result<(), Errno> {
+        hidmon_sysctls(ctx, root)
+    }
+
+    fn load(&mut self) {
+        debugln!("[module.rs] Hidmon::load");
+        self._log = CDev::new_with_delegate(
+            "rusthidmonlog",
+            SharedModule::new(LogDevice::new()),
+        );
+    }
+
+    fn unload(&mut self) {
+        debugln!("[module.rs] Hidmon::unload");
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::vec::Vec;
+use bsd_kernel::bus::{Device, Driver, priority};
+use bsd_kernel::errno::Errno;
+use bsd_kernel::hid::descriptor::{Field, Kind, Usage};
+use bsd_kernel::hid::{HidDevice, HidInterrupt};
+
+bsd_kernel::newbus_driver!(
+    Mouse,
+    rusthidmon_probe,
+    rusthidmon_attach,
+    rusthidmon_detach
+);
+
+/// Buttons looked for
+const BUTTONS: u16 = 16;
+
+/// An attached mouse and where its report fields are
+#[derive(Debug)]
+pub struct Mouse {
+    hid: HidDevice,
+    x: Field,
+    y: Field,
+    wheel: Option<Field>,
+    buttons: Vec<Field>,
+}
+
+impl Driver for Mouse {
+    fn probe(dev: Device) -> Result<i32, Errno> {
+        // hidmon.c has matched the collection already
+        dev.set_desc(c"Rust HID mouse monitor");
+        Ok(priority::LOW_PRIORITY)
+    }
+
+    fn attach(dev: Device) -> Result<Self, Errno> {
+        let hid = HidDevice::new(dev);
+        let rd = hid.descriptor()?;
+        let input = |usage| rd.locate(usage, Kind::Input, 0).copied();
+        let (Some(x), Some(y)) = (input(Usage::X), input(Usage::Y)) else {
+            return Err(Errno::NxIo);
+        };
+        let buttons = (1..=BUTTONS)
+            .filter_map(|n| input(Usage::button(n)))
+            .collect::<Vec<_>>();
+        bsd_kernel::log!(
+            "{}: {} buttons{}",
+            name(&dev),
+            buttons.len(),
+            if input(Usage::WHEEL).is_some() {
+                ", wheel"
+            } else {
+                ""
+            }
+        );
+        Ok(Mouse {
+            hid,
+            x,
+            y,
+            wheel: input(Usage::WHEEL),
+            buttons,
+        })
+    }
+
+    fn start(&self, _dev: Device) -> Result<(), Errno> {
+        // Safety: the state stays boxed in the softc until detach
+        unsafe { self.hid.set_interrupt(self) };
+        self.hid.start_interrupts()
+    }
+
+    fn detach(&self, _dev: Device) -> Result<(), Errno> {
+        self.hid.stop_interrupts()
+    }
+}
+
+impl HidInterrupt for Mouse {
+    fn interrupt(&self, report: &[u8]) {
+        // Other collections' reports come through here too
+        let (Some(x), Some(y)) = (self.x.value(report), self.y.value(report))
+        else {
+            return;
+        };
+        let wheel = self.wheel.and_then(|w| w.value(report)).unwrap_or(0);
+        let buttons =
+            self.buttons.iter().enumerate().fold(0u32, |b, (i, f)| {
+                b | ((f.get(report).unwrap_or(0) != 0) as u32) << i
+            });
+        bsd_kernel::log!(
+            "{}: x {} y {} wheel {} buttons {:#x}",
+            name(&self.hid.device()),
+            x,
+            y,
+            wheel,
+            buttons
+        );
+    }
+}
+
+fn name(dev: &Device) -> &str {
+    dev.nameunit().to_str().unwrap_or("?")
+}
@@ -0,0 +1,18 @@
+#!/usr/bin/env sh
+#
+# Usage: module-null/bench.sh [count]
+#
+# Compares /dev/rustzero, which copies zeroes out with uiomove(9), against
+# /dev/rustzeromap, which fills the reader's pages in place, by reading
+# count (default 1024) blocks of each size with dd(1). Load the module
+# first.
+
+COUNT=${1:-1024}
+
+for BS in 4k 64k 1m 8m; do
+	for DEV in rustzero rustzeromap; do
+		printf '%-12s bs=%-4s ' "${DEV}" "${BS}"
+		dd if="/dev/${DEV}" of=/dev/null bs="${BS}" count="${COUNT}" \
+			2>&1 | tail -n 1
+	done
+done
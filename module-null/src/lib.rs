@@ -0,0 +1,78 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_std]
+
+//! Example module registering several minimal character devices:
+//! `/dev/rustnull` discards writes and reads as empty, `/dev/rustzero`
+//! discards writes and reads as an endless run of zeroes, and
+//! `/dev/rustfull` reads like `rustzero` but fails every write with
+//! `ENOSPC`. `/dev/rustzeromap` reads like `rustzero` but writes the
+//! zeroes straight into the reader's mapped pages; `module-null/bench.sh`
+//! times the two. To try it:
+//! ```bash,ignore
+//! ./build.sh module-null
+//! sudo make -C module-null load
+//! head -c 16 /dev/rustzero | hexdump -C
+//! echo "hi rust" > /dev/rustfull
+//! sudo make -C module-null unload
+//! ```
+
+use bsd_kernel::allocator::KernelAllocator;
+use bsd_kernel::module::ModuleEventType;
+use core::panic::PanicInfo;
+use libc::{c_int, c_void};
+use module::MODULE;
+
+mod module;
+
+extern crate alloc;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    bsd_kernel::panic::handle(info)
+}
+
+/// Main event handler for module events
+#[unsafe(no_mangle)]
+pub extern "C" fn module_event(
+    _module: bsd_kernel::Module,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    match ModuleEventType::from_i32(event) {
+        Some(ModuleEventType::Load) => match MODULE.load() {
+            Ok(()) => 0,
+            Err(e) => e.as_raw(),
+        },
+        Some(ModuleEventType::Unload) => {
+            MODULE.unload();
+            MODULE.cleanup();
+            0
+        }
+        _ => 0,
+    }
+}
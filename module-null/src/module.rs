@@ -0,0 +1,204 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::boxed::Box;
+use bsd_kernel::character_device::{CDev, CharacterDevice};
+use bsd_kernel::cred::CurThread;
+use bsd_kernel::debugln;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::module::{ModuleEvents, SharedModule};
+use bsd_kernel::sync::Lazy;
+use bsd_kernel::uio::{UioReader, UioWriter};
+
+pub static MODULE: Lazy<SharedModule<Devices>> =
+    Lazy::new(|| SharedModule::new(Devices::default()));
+
+/// One of each device, created on load
+#[derive(Default, Debug)]
+pub struct Devices {
+    null: Option<Box<CDev<Null>>>,
+    zero: Option<Box<CDev<Zero>>>,
+    zero_map: Option<Box<CDev<ZeroMap>>>,
+    full: Option<Box<CDev<Full>>>,
+}
+
+impl ModuleEvents for Devices {
+    fn load(&mut self) {
+        debugln!("[module.rs] Devices::load");
+
+        self.null =
+            CDev::new_with_delegate("rustnull", SharedModule::new(Null));
+        self.zero =
+            CDev::new_with_delegate("rustzero", SharedModule::new(Zero));
+        self.zero_map =
+            CDev::new_with_delegate("rustzeromap", SharedModule::new(ZeroMap));
+        self.full =
+            CDev::new_with_delegate("rustfull", SharedModule::new(Full));
+        if self.null.is_none()
+            || self.zero.is_none()
+            || self.zero_map.is_none()
+            || self.full.is_none()
+        {
+            debugln!("[module.rs] Devices::load: Failed to create a device");
+        }
+    }
+
+    fn unload(&mut self) {
+        debugln!("[module.rs] Devices::unload");
+    }
+}
+
+/// Fill the rest of `uio` with zeroes
+fn read_zeroes(uio: &mut UioWriter) -> Result<(), Errno> {
+    static ZEROES: [u8; 512] = [0; 512];
+    while uio.residual() > 0 {
+        uio.write_stream(&ZEROES)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Null;
+
+impl CharacterDevice for Null {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Always at end of file
+    fn read(
+        &mut self,
+        _uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        uio.discard();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Zero;
+
+impl CharacterDevice for Zero {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        read_zeroes(uio)
+    }
+
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        uio.discard();
+        Ok(())
+    }
+}
+
+/// `Zero`, but filling the reader's buffer in place rather than copying
+/// zeroes out, to compare the two paths (see `bench.sh`)
+#[derive(Debug)]
+pub struct ZeroMap;
+
+impl CharacterDevice for ZeroMap {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        uio.write_mapped(|buf| {
+            buf.fill(0);
+            buf.len()
+        })?;
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        uio.discard();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Full;
+
+impl CharacterDevice for Full {
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        read_zeroes(uio)
+    }
+
+    fn write(
+        &mut self,
+        _uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        Err(Errno::NoSpc)
+    }
+}
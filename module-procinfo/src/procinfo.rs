@@ -0,0 +1,111 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use alloc::boxed::Box;
+use bsd_kernel::cred::CurThread;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::proc::{Proc, State};
+use bsd_kernel::pseudofs::{Dir, PseudoFs};
+use bsd_kernel::sbuf::SbufRef;
+use core::fmt::{self, Write};
+
+static FS: spin::Mutex<Option<Box<PseudoFs>>> = spin::Mutex::new(None);
+
+pub fn load() -> Result<(), Errno> {
+    let fs = PseudoFs::new(c"procinfo");
+    fs.register()?;
+    if let Err(e) = build(fs.root()) {
+        // Takes what was built with it
+        fs.unregister()?;
+        return Err(e);
+    }
+    *FS.lock() = Some(fs);
+    Ok(())
+}
+
+pub fn unload() -> Result<(), Errno> {
+    let mut fs = FS.lock();
+    if let Some(f) = fs.as_ref() {
+        // Fails while mounted
+        f.unregister()?;
+    }
+    *fs = None;
+    Ok(())
+}
+
+fn build(root: Dir) -> Result<(), Errno> {
+    root.link(c"curproc", |td, _, sb| {
+        write!(sb, "{}", td.pid()).map_err(nomem)
+    })?;
+    let pid = root.proc_dir()?;
+    pid.file(c"cmdline", |_, p, sb| p.ok_or(Errno::Inval)?.args(sb))?;
+    pid.file(c"status", status)?;
+    Ok(())
+}
+
+/// Fill `<pid>/status`, in the style of Linux's
+fn status(
+    _td: &CurThread,
+    p: Option<&Proc>,
+    sb: &mut SbufRef,
+) -> Result<(), Errno> {
+    // The buffer is fixed-length, so doesn't sleep to grow under the lock
+    let p = p.ok_or(Errno::Inval)?.lock();
+    let cred = p.cred();
+    let state = match p.state() {
+        State::New => "new",
+        State::Normal => "normal",
+        State::Zombie => "zombie",
+    };
+    write!(
+        sb,
+        "Name:\t{}\nState:\t{}\nPid:\t{}\nPPid:\t{}\n\
+         Uid:\t{}\t{}\t{}\nGid:\t{}\t{}\t{}\nGroups:\t",
+        p.comm().to_bytes().escape_ascii(),
+        state,
+        p.pid(),
+        p.ppid(),
+        cred.ruid(),
+        cred.uid(),
+        cred.svuid(),
+        cred.rgid(),
+        cred.gid(),
+        cred.svgid(),
+    )
+    .map_err(nomem)?;
+    for gid in cred.groups() {
+        write!(sb, "{} ", gid).map_err(nomem)?;
+    }
+    write!(
+        sb,
+        "\nThreads:\t{}\nJailed:\t{}\n",
+        p.threads(),
+        cred.is_jailed() as u8
+    )
+    .map_err(nomem)
+}
+
+/// The sbuf ran out of room, which is what a write to it fails with
+fn nomem(_: fmt::Error) -> Errno {
+    Errno::NoMem
+}
@@ -0,0 +1,112 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+/*
+ * Usage: ringping [count]
+ *
+ * Sends count (default 1000) echo commands through /dev/rustring's rings,
+ * as many at a time as fit, and checks each response.
+ */
+
+#include <sys/types.h>
+#include <sys/event.h>
+#include <sys/mman.h>
+#include <machine/atomic.h>
+
+#include <err.h>
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#include "rustring.h"
+
+int
+main(int argc, char **argv)
+{
+	struct rustring_shared *sh;
+	struct kevent ev;
+	uint64_t sent, received, count;
+	uint32_t head, tail;
+	int fd, kq;
+
+	count = argc > 1 ? strtoull(argv[1], NULL, 0) : 1000;
+	if ((fd = open("/dev/rustring", O_RDWR)) == -1)
+		err(1, "/dev/rustring");
+	sh = mmap(NULL, RUSTRING_MAPSIZE, PROT_READ | PROT_WRITE, MAP_SHARED,
+	    fd, 0);
+	if (sh == MAP_FAILED)
+		err(1, "mmap");
+	if ((kq = kqueue()) == -1)
+		err(1, "kqueue");
+	EV_SET(&ev, fd, EVFILT_READ, EV_ADD | EV_CLEAR, 0, 0, NULL);
+	if (kevent(kq, &ev, 1, NULL, 0, NULL) == -1)
+		err(1, "kevent");
+
+	sent = received = 0;
+	while (received < count) {
+		/* Queue as many as there is room for, then ring */
+		head = sh->cmd_head;
+		tail = atomic_load_acq_32(&sh->cmd_tail);
+		if (sent < count && head - tail < RUSTRING_SLOTS) {
+			while (sent < count && head - tail < RUSTRING_SLOTS) {
+				struct rustring_cmd *cmd;
+
+				cmd = &sh->cmd[head % RUSTRING_SLOTS];
+				cmd->tag = sent;
+				cmd->op = RUSTRING_OP_ECHO;
+				cmd->arg[0] = ~sent;
+				head++;
+				sent++;
+			}
+			atomic_store_rel_32(&sh->cmd_head, head);
+			if (write(fd, "", 1) == -1)
+				err(1, "write");
+		}
+
+		/* Take what has been answered, or wait for the kernel */
+		head = atomic_load_acq_32(&sh->rsp_head);
+		tail = sh->rsp_tail;
+		if (head == tail) {
+			if (kevent(kq, NULL, 0, &ev, 1, NULL) == -1)
+				err(1, "kevent");
+			continue;
+		}
+		for (; tail != head; tail++) {
+			struct rustring_rsp *rsp;
+
+			rsp = &sh->rsp[tail % RUSTRING_SLOTS];
+			if (rsp->tag != received || rsp->error != 0 ||
+			    rsp->value != ~received)
+				errx(1, "bad response %ju", (uintmax_t)received);
+			received++;
+		}
+		atomic_store_rel_32(&sh->rsp_tail, tail);
+		/* The kernel may have stopped for want of room */
+		if (atomic_load_acq_32(&sh->cmd_tail) != sh->cmd_head &&
+		    write(fd, "", 1) == -1)
+			err(1, "write");
+	}
+	printf("%ju echoes\n", (uintmax_t)received);
+	return (0);
+}
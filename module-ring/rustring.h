@@ -0,0 +1,81 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+/*
+ * The rings /dev/rustring shares with each descriptor open on it.
+ *
+ * Map RUSTRING_MAPSIZE bytes at offset 0 to reach a struct rustring_shared.
+ * Each index counts entries ever queued or taken, wrapping at 2^32, and
+ * entry i lives in slot i % RUSTRING_SLOTS. Userland advances cmd_head and
+ * rsp_tail, the kernel cmd_tail and rsp_head; load the other side's with
+ * atomic_load_acq_32() and store your own with atomic_store_rel_32().
+ *
+ * Fill cmd[cmd_head % RUSTRING_SLOTS], advance cmd_head, and ring the
+ * kernel's doorbell with a write(2) of any one byte. The kernel answers
+ * queued commands in order from a thread of its own, as long as there is
+ * room for their responses; once it stops for lack of room, ring again
+ * after taking some. It rings back by making the descriptor readable:
+ * wait for EVFILT_READ with kevent(2), or for POLLIN, or block in a
+ * read(2), which returns the number of responses waiting as a uint32_t.
+ * Readiness is shared by all descriptors open on the device, so a wakeup
+ * may be for another.
+ */
+
+#ifndef _RUSTRING_H_
+#define _RUSTRING_H_
+
+#include <sys/types.h>
+
+#define	RUSTRING_SLOTS		64
+#define	RUSTRING_MAPSIZE	4096
+
+/* Commands' op */
+#define	RUSTRING_OP_NOP		0	/* value = 0 */
+#define	RUSTRING_OP_ECHO	1	/* value = arg[0] */
+#define	RUSTRING_OP_ADD		2	/* value = arg[0] + arg[1] */
+
+struct rustring_cmd {
+	uint64_t	tag;	/* copied to the response */
+	uint32_t	op;
+	uint32_t	pad;
+	uint64_t	arg[2];
+};
+
+struct rustring_rsp {
+	uint64_t	tag;
+	int32_t		error;	/* 0, or EINVAL for an unknown op */
+	uint32_t	pad;
+	uint64_t	value;
+};
+
+struct rustring_shared {
+	/* Each on a cache line of its own, as each side writes its own */
+	uint32_t	cmd_head __aligned(64);
+	uint32_t	cmd_tail __aligned(64);
+	uint32_t	rsp_head __aligned(64);
+	uint32_t	rsp_tail __aligned(64);
+	struct rustring_cmd cmd[RUSTRING_SLOTS];
+	struct rustring_rsp rsp[RUSTRING_SLOTS];
+};
+
+#endif /* _RUSTRING_H_ */
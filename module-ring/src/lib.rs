@@ -0,0 +1,83 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+#![no_std]
+
+//! Example device exposing a command and response ring to userland
+//!
+//! Each descriptor open on `/dev/rustring` gets a page of its own, which it
+//! maps with `mmap(2)` and which holds two lock-free single-producer rings:
+//! commands from the process, and the kernel's responses to them. Neither
+//! side copies or makes a system call per command. The process rings the
+//! kernel's doorbell with a one-byte `write(2)` once it has queued some,
+//! and a task answers them; the kernel rings back by making the
+//! descriptor readable, which the process waits for with `kevent(2)`.
+//! `rustring.h` lays out the page and spells out the protocol, and
+//! `ringping.c` is a client that pings the kernel through it. To try it:
+//! ```bash,ignore
+//! ./build.sh module-ring
+//! sudo make -C module-ring load
+//! cc -I module-ring -o ringping module-ring/ringping.c
+//! ./ringping 1000
+//! sudo make -C module-ring unload
+//! ```
+
+use bsd_kernel::allocator::KernelAllocator;
+use bsd_kernel::module::ModuleEventType;
+use core::panic::PanicInfo;
+use libc::{c_int, c_void};
+use module::MODULE;
+
+mod module;
+mod ring;
+
+extern crate alloc;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator::new();
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    bsd_kernel::panic::handle(info)
+}
+
+/// Main event handler for module events
+#[unsafe(no_mangle)]
+pub extern "C" fn module_event(
+    _module: bsd_kernel::Module,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    match ModuleEventType::from_i32(event) {
+        Some(ModuleEventType::Load) => match MODULE.load() {
+            Ok(()) => 0,
+            Err(e) => e.as_raw(),
+        },
+        Some(ModuleEventType::Unload) => {
+            MODULE.unload();
+            MODULE.cleanup();
+            0
+        }
+        _ => 0,
+    }
+}
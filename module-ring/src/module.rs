@@ -0,0 +1,218 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::ring::Rings;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use bsd_kernel::character_device::{
+    CDev, CharacterDevice, set_cdevpriv, with_cdevpriv,
+};
+use bsd_kernel::cred::CurThread;
+use bsd_kernel::debugln;
+use bsd_kernel::errno::Errno;
+use bsd_kernel::module::{ModuleEvents, SharedModule};
+use bsd_kernel::sync::{Lazy, Mutex};
+use bsd_kernel::taskqueue::{Task, TaskQueue};
+use bsd_kernel::uio::{Offsets, UioReader, UioWriter};
+use bsd_kernel::vm::{Prot, SharedBuffer};
+use core::fmt;
+use core::task::{Context, Poll, Waker};
+
+pub static MODULE: Lazy<SharedModule<Devices>> =
+    Lazy::new(|| SharedModule::new(Devices::default()));
+
+/// The device, created on load
+#[derive(Default, Debug)]
+pub struct Devices {
+    ring: Option<Box<CDev<RingDevice>>>,
+}
+
+impl ModuleEvents for Devices {
+    fn load(&mut self) {
+        debugln!("[module.rs] Devices::load");
+
+        self.ring = match TaskQueue::new(c"rustring", 1) {
+            Ok(queue) => CDev::new_with_delegate(
+                "rustring",
+                SharedModule::new(RingDevice::new(queue)),
+            ),
+            Err(e) => {
+                debugln!("[module.rs] Devices::load: taskqueue: {}", e);
+                None
+            }
+        };
+        if self.ring.is_none() {
+            debugln!("[module.rs] Devices::load: Failed to create the device");
+        }
+    }
+
+    fn unload(&mut self) {
+        debugln!("[module.rs] Devices::unload");
+    }
+}
+
+/// The kernel's doorbell to userland: the waker the device reports
+/// readiness to `read(2)`, `poll(2)` and `kevent(2)` through, once any
+/// has looked. Kept after ringing, as it's the same each time
+struct Bell(Mutex<Option<Waker>>);
+
+impl Bell {
+    fn ring(&self) {
+        let waker = self.0.lock().clone();
+        if let Some(w) = waker {
+            w.wake();
+        }
+    }
+}
+
+/// What each open descriptor has: its rings, and the task answering
+/// them, which userland's doorbell enqueues
+struct Session {
+    rings: Arc<Rings>,
+    doorbell: Task,
+}
+
+/// The descriptor's state, kept by devfs until its last close
+struct Handle {
+    id: u64,
+    session: Arc<Session>,
+}
+
+pub struct RingDevice {
+    /// Every descriptor's session, for `mmap` to find its rings by. Those
+    /// whose `Handle` is gone are dropped on the next open
+    sessions: BTreeMap<u64, Arc<Session>>,
+    next_id: u64,
+    bell: Arc<Bell>,
+    queue: TaskQueue,
+}
+
+impl RingDevice {
+    fn new(queue: TaskQueue) -> Self {
+        RingDevice {
+            sessions: BTreeMap::new(),
+            next_id: 0,
+            bell: Arc::new(Bell(Mutex::new(c"rustringbell", None))),
+            queue,
+        }
+    }
+
+    /// Sessions whose descriptors are still open
+    fn open_sessions(&self) -> impl Iterator<Item = &Session> {
+        self.sessions
+            .values()
+            .filter(|s| Arc::strong_count(s) > 1)
+            .map(|s| &**s)
+    }
+}
+
+impl fmt::Debug for RingDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RingDevice {{ sessions: {} }}", self.sessions.len())
+    }
+}
+
+impl CharacterDevice for RingDevice {
+    const OFFSETS: Offsets = Offsets::Stream;
+
+    /// Give the descriptor rings of its own
+    fn open(&mut self, _td: &CurThread) -> Result<(), Errno> {
+        self.sessions.retain(|_, s| Arc::strong_count(s) > 1);
+        let rings = Arc::new(Rings::new()?);
+        let doorbell = {
+            let rings = rings.clone();
+            let bell = self.bell.clone();
+            Task::new(&self.queue, move || {
+                if rings.serve() > 0 {
+                    bell.ring();
+                }
+            })
+        };
+        let session = Arc::new(Session { rings, doorbell });
+        self.next_id += 1;
+        let id = self.next_id;
+        set_cdevpriv(Handle {
+            id,
+            session: session.clone(),
+        })?;
+        self.sessions.insert(id, session);
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Ready once any descriptor has responses waiting
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        // Registered before looking, so responses queued after the look
+        // still ring the bell
+        {
+            let mut waker = self.bell.0.lock();
+            match *waker {
+                Some(ref w) if w.will_wake(cx.waker()) => (),
+                _ => *waker = Some(cx.waker().clone()),
+            }
+        }
+        if self.open_sessions().any(|s| s.rings.pending() > 0) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// The number of responses waiting for this descriptor, as a native
+    /// `uint32_t`
+    fn read(
+        &mut self,
+        uio: &mut UioWriter,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        let pending = with_cdevpriv(|h: &Handle| h.session.rings.pending())?;
+        uio.write_stream(&pending.to_ne_bytes())?;
+        Ok(())
+    }
+
+    /// Userland's doorbell: have this descriptor's commands answered
+    fn write(
+        &mut self,
+        uio: &mut UioReader,
+        _td: &CurThread,
+    ) -> Result<(), Errno> {
+        uio.discard();
+        with_cdevpriv(|h: &Handle| h.session.doorbell.enqueue())
+    }
+
+    /// Map this descriptor's rings
+    fn mmap(
+        &mut self,
+        offset: u64,
+        _size: usize,
+        _prot: Prot,
+    ) -> Result<(&SharedBuffer, u64), Errno> {
+        let id = with_cdevpriv(|h: &Handle| h.id)?;
+        let session = self.sessions.get(&id).ok_or(Errno::NxIo)?;
+        Ok((session.rings.buffer(), offset))
+    }
+}
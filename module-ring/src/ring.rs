@@ -0,0 +1,171 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! The rings' layout in the shared page, `struct rustring_shared` in
+//! `rustring.h`, and the kernel's side of them
+//!
+//! The process can write anything anywhere in the page at any time, the
+//! kernel's indices included. Slots are found by masking indices, and
+//! entries are copied out before use, so a process scribbling over its
+//! rings only confuses itself.
+
+use bsd_kernel::errno::Errno;
+use bsd_kernel::vm::SharedBuffer;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::{fmt, mem};
+
+/// Entries in each ring, a power of two
+pub const SLOTS: u32 = 64;
+
+const OP_NOP: u32 = 0;
+const OP_ECHO: u32 = 1;
+const OP_ADD: u32 = 2;
+
+/// `struct rustring_cmd`
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Command {
+    tag: u64,
+    op: u32,
+    _pad: u32,
+    arg: [u64; 2],
+}
+
+/// `struct rustring_rsp`
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Response {
+    tag: u64,
+    error: i32,
+    _pad: u32,
+    value: u64,
+}
+
+impl Command {
+    fn execute(&self) -> Response {
+        let (error, value) = match self.op {
+            OP_NOP => (0, 0),
+            OP_ECHO => (0, self.arg[0]),
+            OP_ADD => (0, self.arg[0].wrapping_add(self.arg[1])),
+            _ => (Errno::Inval.as_raw(), 0),
+        };
+        Response {
+            tag: self.tag,
+            error,
+            _pad: 0,
+            value,
+        }
+    }
+}
+
+/// An index on a cache line of its own
+#[repr(C, align(64))]
+struct Index(AtomicU32);
+
+/// `struct rustring_shared`
+#[repr(C)]
+struct Shared {
+    cmd_head: Index,
+    cmd_tail: Index,
+    rsp_head: Index,
+    rsp_tail: Index,
+    cmd: [UnsafeCell<Command>; SLOTS as usize],
+    rsp: [UnsafeCell<Response>; SLOTS as usize],
+}
+
+const _: () = assert!(mem::size_of::<Shared>() == 3840);
+
+fn slot(index: u32) -> usize {
+    (index % SLOTS) as usize
+}
+
+/// One descriptor's rings, in a page it shares with the process
+pub struct Rings {
+    buf: SharedBuffer,
+}
+
+impl Rings {
+    /// Allocate the page, zeroed, so both rings start empty. Sleeps
+    pub fn new() -> Result<Self, Errno> {
+        Ok(Rings {
+            buf: SharedBuffer::new(mem::size_of::<Shared>())?,
+        })
+    }
+
+    /// The page, for `mmap(2)`
+    pub fn buffer(&self) -> &SharedBuffer {
+        &self.buf
+    }
+
+    fn shared(&self) -> &Shared {
+        // Page aligned, and as long as the buffer
+        unsafe { &*(self.buf.as_ptr() as *const Shared) }
+    }
+
+    /// Responses the process has yet to take
+    pub fn pending(&self) -> u32 {
+        let sh = self.shared();
+        let head = sh.rsp_head.0.load(Ordering::Relaxed);
+        let tail = sh.rsp_tail.0.load(Ordering::Acquire);
+        head.wrapping_sub(tail).min(SLOTS)
+    }
+
+    /// Answer the commands queued so far, in order, for as long as there
+    /// is room for their responses, and return how many were answered.
+    /// Rings whose indices are further apart than they can be are left
+    /// alone. Only one thread may serve the rings at a time
+    pub fn serve(&self) -> u32 {
+        let sh = self.shared();
+        let cmd_head = sh.cmd_head.0.load(Ordering::Acquire);
+        let mut cmd_tail = sh.cmd_tail.0.load(Ordering::Relaxed);
+        let mut rsp_head = sh.rsp_head.0.load(Ordering::Relaxed);
+        let mut answered = 0;
+        while cmd_tail != cmd_head {
+            let rsp_tail = sh.rsp_tail.0.load(Ordering::Acquire);
+            let queued = rsp_head.wrapping_sub(rsp_tail);
+            if cmd_head.wrapping_sub(cmd_tail) > SLOTS || queued >= SLOTS {
+                break;
+            }
+            let cmd =
+                unsafe { ptr::read_volatile(sh.cmd[slot(cmd_tail)].get()) };
+            let rsp = cmd.execute();
+            unsafe { ptr::write_volatile(sh.rsp[slot(rsp_head)].get(), rsp) };
+            rsp_head = rsp_head.wrapping_add(1);
+            cmd_tail = cmd_tail.wrapping_add(1);
+            // The response before its index, and the command slot's
+            // reuse after its copy
+            sh.rsp_head.0.store(rsp_head, Ordering::Release);
+            sh.cmd_tail.0.store(cmd_tail, Ordering::Release);
+            answered += 1;
+        }
+        answered
+    }
+}
+
+impl fmt::Debug for Rings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rings {{ pending: {} }}", self.pending())
+    }
+}